@@ -12,7 +12,7 @@ use iroh_gossip::{
 use iroh_net::{
     derp::{DerpMap, DerpMode},
     key::{PublicKey, SecretKey},
-    magic_endpoint::accept_conn,
+    magic_endpoint::{accept_conn, Connecting},
     MagicEndpoint, NodeAddr,
 };
 use serde::{Deserialize, Serialize};
@@ -201,7 +201,7 @@ async fn endpoint_loop(endpoint: MagicEndpoint, gossip: Gossip) {
         });
     }
 }
-async fn handle_connection(conn: quinn::Connecting, gossip: Gossip) -> anyhow::Result<()> {
+async fn handle_connection(conn: Connecting, gossip: Gossip) -> anyhow::Result<()> {
     let (peer_id, alpn, conn) = accept_conn(conn).await?;
     match alpn.as_bytes() {
         GOSSIP_ALPN => gossip
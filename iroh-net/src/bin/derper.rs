@@ -8,21 +8,27 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{anyhow, bail, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use clap::Parser;
 use futures::{Future, StreamExt};
 use http::response::Builder as ResponseBuilder;
 use hyper::body::Incoming;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
 use iroh_metrics::inc;
-use iroh_net::defaults::{DEFAULT_DERP_STUN_PORT, NA_DERP_HOSTNAME};
+use iroh_net::defaults::{DEFAULT_DERP_STUN_ALT_PORT, DEFAULT_DERP_STUN_PORT, NA_DERP_HOSTNAME};
 use iroh_net::derp;
 use iroh_net::derp::http::{
-    MeshAddrs, ServerBuilder as DerpServerBuilder, TlsAcceptor, TlsConfig as DerpTlsConfig,
+    MeshAddrs, MeshStatus, ServerBuilder as DerpServerBuilder, TlsAcceptor,
+    TlsConfig as DerpTlsConfig,
 };
-use iroh_net::key::SecretKey;
+use iroh_net::derp::{
+    AccessPolicy, AdminHandler, ClientOrigin, ClientRateLimit, ConnectedClient, HttpClient,
+    RestartNotice,
+};
+use iroh_net::key::{PublicKey, SecretKey};
 use iroh_net::stun;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
@@ -180,6 +186,12 @@ struct Config {
     /// The UDP port on which to serve STUN. The listener is bound to the same IP (if any) as
     /// specified in the `addr` field. Defaults to [`DEFAULT_DERP_STUN_PORT`].
     stun_port: u16,
+    /// A second UDP port on which to serve STUN, bound to the same IP as `stun_port`.
+    ///
+    /// Requests on this port are answered from `stun_port`, which lets clients classify how
+    /// their NAT maps and filters traffic.  Defaults to [`DEFAULT_DERP_STUN_ALT_PORT`], not
+    /// served if unset.
+    stun_alt_port: Option<u16>,
     /// Certificate hostname. Defaults to [`NA_DERP_HOSTNAME`].
     hostname: String,
     /// Whether to run a STUN server. It will bind to the same IP as the `addr` field.
@@ -197,6 +209,12 @@ struct Config {
     limits: Option<Limits>,
     /// Mesh network configuration
     mesh: Option<MeshConfig>,
+    /// Access control configuration. If not set, every client may use the derper.
+    access: Option<AccessConfig>,
+    /// How clients are told about the derper shutting down. If not set, the defaults apply.
+    restart: Option<RestartConfig>,
+    /// Admin API configuration. If not set, the admin API is not served.
+    admin: Option<AdminConfig>,
     #[cfg(feature = "metrics")]
     /// Metrics serve address. If not set, metrics are not served.
     metrics_addr: Option<SocketAddr>,
@@ -208,7 +226,196 @@ struct MeshConfig {
     mesh_psk_file: PathBuf,
     /// Comma-separated list of urls to mesh with. Must also include the scheme ('http' or
     /// 'https').
+    #[serde(default)]
     mesh_with: Vec<Url>,
+    /// Path to a file listing more urls to mesh with, one per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored. The file is reloaded when it
+    /// changes, so members can join and leave the mesh without restarting the derper.
+    mesh_with_file: Option<PathBuf>,
+}
+
+/// The members of the mesh, from the config and the `mesh_with_file`.
+struct MeshMembers {
+    mesh_with: Vec<Url>,
+    mesh_with_file: Option<PathBuf>,
+    /// The members as of the last (re)load.
+    current: Option<Vec<Url>>,
+}
+
+impl MeshMembers {
+    fn new(mesh_with: Vec<Url>, mesh_with_file: Option<PathBuf>) -> Self {
+        Self {
+            mesh_with,
+            mesh_with_file,
+            current: None,
+        }
+    }
+
+    fn is_dynamic(&self) -> bool {
+        self.mesh_with_file.is_some()
+    }
+
+    /// Reads the members, returns them if they changed since the last call.
+    async fn reload(&mut self) -> Result<Option<Vec<Url>>> {
+        let mut members = self.mesh_with.clone();
+        if let Some(ref path) = self.mesh_with_file {
+            let raw = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("reading mesh members from {}", path.display()))?;
+            members.extend(parse_mesh_members(&raw)?);
+        }
+        members.sort();
+        members.dedup();
+        if self.current.as_ref() == Some(&members) {
+            return Ok(None);
+        }
+        self.current = Some(members.clone());
+        Ok(Some(members))
+    }
+}
+
+fn parse_mesh_members(raw: &str) -> Result<Vec<Url>> {
+    raw.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .with_context(|| format!("invalid mesh member url: {line}"))
+        })
+        .collect()
+}
+
+/// Decides which clients may use the derper.
+///
+/// Mesh peers presenting the mesh key are always accepted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AccessConfig {
+    /// Path to a file listing the node ids allowed to connect, one per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    AllowListFile(PathBuf),
+    /// Path to a file containing the shared-secret token clients must present; whitespace is
+    /// trimmed.
+    TokenFile(PathBuf),
+    /// URL queried for every connecting client.
+    ///
+    /// The derper sends a `GET` request with the client's node id in the `node_id` query
+    /// parameter, forwarding the client's access token in the `Authorization` header if one was
+    /// presented. The client is accepted if the response status is a success.
+    VerifyUrl(Url),
+}
+
+impl AccessConfig {
+    async fn into_policy(self) -> Result<AccessPolicy> {
+        match self {
+            AccessConfig::AllowListFile(path) => {
+                let raw = tokio::fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("reading allow list {}", path.display()))?;
+                let keys = parse_allow_list(&raw)?;
+                info!("access restricted to {} allow-listed nodes", keys.len());
+                Ok(AccessPolicy::allow_list(keys))
+            }
+            AccessConfig::TokenFile(path) => {
+                let raw = tokio::fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("reading access token {}", path.display()))?;
+                let token = raw.trim();
+                ensure!(!token.is_empty(), "access token file is empty");
+                info!("access restricted to clients with the access token");
+                Ok(AccessPolicy::Token(token.to_string()))
+            }
+            AccessConfig::VerifyUrl(url) => {
+                info!(%url, "access verified through callback");
+                let client = reqwest::Client::new();
+                Ok(AccessPolicy::Callback(Arc::new(move |key, token| {
+                    let client = client.clone();
+                    let url = url.clone();
+                    Box::pin(async move { verify_client(&client, url, key, token).await })
+                })))
+            }
+        }
+    }
+}
+
+/// Parses an allow list file, containing one node id per line.
+fn parse_allow_list(raw: &str) -> Result<Vec<PublicKey>> {
+    raw.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .with_context(|| format!("invalid node id in allow list: {line}"))
+        })
+        .collect()
+}
+
+/// Asks the verification endpoint at `url` whether the client may connect.
+async fn verify_client(
+    client: &reqwest::Client,
+    mut url: Url,
+    key: PublicKey,
+    token: Option<String>,
+) -> Result<bool> {
+    url.query_pairs_mut()
+        .append_pair("node_id", &key.to_string());
+    let mut req = client.get(url).timeout(ACCESS_VERIFY_TIMEOUT);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let res = req.send().await.context("access verification request")?;
+    debug!(node = %key.fmt_short(), status = %res.status(), "access verification");
+    Ok(res.status().is_success())
+}
+
+/// Announced to clients when the derper shuts down, so they reconnect right away instead of
+/// timing out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+struct RestartConfig {
+    /// Clients reconnect after a random delay of up to this many milliseconds.
+    ///
+    /// Defaults to `2000`.
+    reconnect_in_ms: u64,
+    /// For how many milliseconds clients keep trying to reconnect.
+    ///
+    /// Defaults to `5000`.
+    try_for_ms: u64,
+    /// For how many seconds the derper keeps relaying for connected clients before shutting
+    /// down.
+    ///
+    /// Defaults to `10`.
+    drain_timeout_secs: u64,
+    /// Another derper clients should move to. Clients only follow the redirect if it is in
+    /// their derp map.
+    redirect: Option<Url>,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_in_ms: 2000,
+            try_for_ms: 5000,
+            drain_timeout_secs: 10,
+            redirect: None,
+        }
+    }
+}
+
+impl RestartConfig {
+    fn notice(&self) -> RestartNotice {
+        RestartNotice {
+            reconnect_in: Duration::from_millis(self.reconnect_in_ms),
+            try_for: Duration::from_millis(self.try_for_ms),
+            redirect: self.redirect.clone(),
+        }
+    }
+
+    fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -238,12 +445,45 @@ struct TlsConfig {
     captive_portal_port: Option<u16>,
 }
 
+/// Configuration of the admin API, which lists the connected clients and can disconnect or
+/// ban them.
+///
+/// The admin API is served over plain HTTP, it should only listen on a private address.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct AdminConfig {
+    /// Admin API listen address.
+    addr: SocketAddr,
+    /// Path to a file containing the token admin requests must present in an
+    /// `Authorization: Bearer <token>` header; whitespace is trimmed.
+    token_file: PathBuf,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Limits {
     /// Rate limit for accepting new connection. Unlimited if not set.
     accept_conn_limit: Option<f64>,
     /// Burst limit for accepting new connection. Unlimited if not set.
     accept_conn_burst: Option<usize>,
+    /// Rate limit in bytes per second for the packets each client sends. Unlimited if not set.
+    client_bytes_per_second: Option<u32>,
+    /// Burst limit in bytes for the packets each client sends.
+    ///
+    /// Defaults to one second worth of `client_bytes_per_second`, must fit at least the largest
+    /// packet a client can send.
+    client_bytes_burst: Option<u32>,
+}
+
+impl Limits {
+    /// The per client rate limit, if configured.
+    fn client_rate_limit(&self) -> Result<Option<ClientRateLimit>> {
+        let Some(bytes_per_second) = self.client_bytes_per_second else {
+            return Ok(None);
+        };
+        let burst = self.client_bytes_burst.unwrap_or(bytes_per_second);
+        let limit =
+            ClientRateLimit::new(bytes_per_second, burst).context("invalid client limits")?;
+        Ok(Some(limit))
+    }
 }
 
 impl Default for Config {
@@ -252,12 +492,16 @@ impl Default for Config {
             secret_key: SecretKey::generate(),
             addr: "[::]:443".parse().unwrap(),
             stun_port: DEFAULT_DERP_STUN_PORT,
+            stun_alt_port: Some(DEFAULT_DERP_STUN_ALT_PORT),
             hostname: NA_DERP_HOSTNAME.into(),
             enable_stun: true,
             enable_derp: true,
             tls: None,
             limits: None,
             mesh: None,
+            access: None,
+            restart: None,
+            admin: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -346,6 +590,10 @@ pub fn init_metrics_collection(
 const DEV_PORT: u16 = 3340;
 /// Only used when tls is enabled & a captive protal port is not given
 const DEFAULT_CAPTIVE_PORTAL_PORT: u16 = 80;
+/// How often to check the `mesh_with_file` for changes
+const MESH_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for the access verification endpoint to answer
+const ACCESS_VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -400,7 +648,20 @@ async fn run(
         warn!("The address port is 443, which is typically the expected tls port, but you have not supplied any tls configuration.\nIf you meant to run the derper with tls enabled, adjust the config file to include tls configuration.");
     }
 
+    let client_rate_limit = match cfg.limits {
+        Some(ref limits) => limits.client_rate_limit()?,
+        None => None,
+    };
+
+    let restart_config = cfg.restart.unwrap_or_default();
+
+    let access_policy = match cfg.access {
+        Some(access) if cfg.enable_derp => access.into_policy().await?,
+        _ => AccessPolicy::Everyone,
+    };
+
     // set up derp configuration details
+    let mut mesh_members = None;
     let (secret_key, mesh_key, mesh_derpers) = match cfg.enable_derp {
         true => {
             let (mesh_key, mesh_derpers) = if let Some(mesh_config) = cfg.mesh {
@@ -411,10 +672,11 @@ async fn run(
                 hex::decode_to_slice(raw.trim(), &mut mesh_key)
                     .context("invalid mesh-pks content")?;
                 info!("DERP mesh key configured");
-                (
-                    Some(mesh_key),
-                    Some(MeshAddrs::Addrs(mesh_config.mesh_with)),
-                )
+                let mut members =
+                    MeshMembers::new(mesh_config.mesh_with, mesh_config.mesh_with_file);
+                let urls = members.reload().await?.unwrap_or_default();
+                mesh_members = Some(members);
+                (Some(mesh_key), Some(MeshAddrs::Addrs(urls)))
             } else {
                 (None, None)
             };
@@ -426,7 +688,7 @@ async fn run(
     // run stun
     let stun_task = if cfg.enable_stun {
         Some(tokio::task::spawn(async move {
-            serve_stun(addr.ip(), cfg.stun_port).await
+            serve_stun(addr.ip(), cfg.stun_port, cfg.stun_alt_port).await
         }))
     } else {
         None
@@ -460,6 +722,8 @@ async fn run(
     let mut builder = DerpServerBuilder::new(addr)
         .secret_key(secret_key.map(Into::into))
         .mesh_key(mesh_key)
+        .access_policy(access_policy)
+        .client_rate_limit(client_rate_limit)
         .headers(headers)
         .tls_config(tls_config.clone())
         .derp_override(Box::new(derp_disabled_handler))
@@ -477,7 +741,32 @@ async fn run(
             Box::new(serve_no_content_handler),
         );
     }
-    let derp_server = builder.spawn().await?;
+    let mut derp_server = builder.spawn().await?;
+
+    let admin_task = match cfg.admin {
+        Some(admin_config) => match derp_server.admin_handler() {
+            Some(admin) => {
+                let raw = tokio::fs::read_to_string(&admin_config.token_file)
+                    .await
+                    .with_context(|| {
+                        format!("reading admin token {}", admin_config.token_file.display())
+                    })?;
+                let token = raw.trim();
+                ensure!(!token.is_empty(), "admin token file is empty");
+                let service = AdminService {
+                    token: token.into(),
+                    admin,
+                    mesh_status: derp_server.mesh_status(),
+                };
+                Some(serve_admin_service(admin_config.addr, service).await?)
+            }
+            None => {
+                warn!("derp is disabled, not serving the admin api");
+                None
+            }
+        },
+        None => None,
+    };
 
     // captive portal detections must be served over HTTP
     let captive_portal_task = if tls_config.is_some() {
@@ -494,7 +783,30 @@ async fn run(
         }
     }
 
-    tokio::signal::ctrl_c().await?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut mesh_reload = tokio::time::interval(MESH_RELOAD_INTERVAL);
+    loop {
+        tokio::select! {
+            res = &mut shutdown => {
+                res?;
+                break;
+            }
+            _ = mesh_reload.tick(), if mesh_members.as_ref().is_some_and(MeshMembers::is_dynamic) => {
+                let members = mesh_members.as_mut().expect("checked");
+                match members.reload().await {
+                    Ok(Some(urls)) => {
+                        info!("mesh members changed: {urls:?}");
+                        if let Err(err) = derp_server.set_mesh_members(MeshAddrs::Addrs(urls)) {
+                            warn!("unable to update mesh members: {err:?}");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("unable to reload mesh members: {err:?}"),
+                }
+            }
+        }
+    }
     // Shutdown all tasks
     if let Some(task) = stun_task {
         task.abort();
@@ -502,11 +814,34 @@ async fn run(
     if let Some(task) = captive_portal_task {
         task.abort()
     }
-    derp_server.shutdown().await;
+    if let Some(task) = admin_task {
+        task.abort()
+    }
+    info!("shutting down, telling clients to reconnect");
+    derp_server
+        .shutdown_for_restart(restart_config.notice(), restart_config.drain_timeout())
+        .await;
 
     Ok(())
 }
 
+/// Waits for ctrl-c, or on unix also for `SIGTERM`, which is what service managers send
+/// during rolling upgrades.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
 const NO_CONTENT_CHALLENGE_HEADER: &str = "X-Tailscale-Challenge";
 const NO_CONTENT_RESPONSE_HEADER: &str = "X-Tailscale-Response";
 
@@ -600,6 +935,194 @@ impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
     }
 }
 
+async fn serve_admin_service(
+    addr: SocketAddr,
+    service: AdminService,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .context("failed to bind admin api")?;
+    info!("[AdminService]: serving on {}", listener.local_addr()?);
+
+    let task = tokio::spawn(
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer_addr)) => {
+                        debug!("[AdminService] Connection opened from {}", peer_addr);
+                        let service = service.clone();
+                        tokio::task::spawn(async move {
+                            let stream = hyper_util::rt::TokioIo::new(stream);
+                            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                                .serve_connection(stream, service)
+                                .await
+                            {
+                                error!("[AdminService] Failed to serve connection: {:?}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("[AdminService] failed to accept connection: {:#?}", err);
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("admin.service")),
+    );
+    Ok(task)
+}
+
+/// Serves the admin API, see [`AdminConfig`].
+///
+/// All requests must carry the admin token. The routes are:
+/// - `GET /clients`: the connected clients, one per line.
+/// - `GET /bans`: the banned node ids, one per line.
+/// - `GET /mesh`: the health of the mesh, `503 Service Unavailable` if it is partitioned.
+/// - `POST /clients/{node_id}/disconnect`: closes the connection of the client.
+/// - `POST /clients/{node_id}/ban`: closes the connection of the client and refuses it until
+///   unbanned. Bans are lost when the derper restarts.
+/// - `POST /clients/{node_id}/unban`: accepts the client again.
+#[derive(Clone)]
+struct AdminService {
+    token: Arc<str>,
+    admin: AdminHandler<HttpClient>,
+    mesh_status: Option<MeshStatus>,
+}
+
+impl hyper::service::Service<Request<Incoming>> for AdminService {
+    type Response = Response<BytesBody>;
+    type Error = HyperError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            Ok(this
+                .handle(req.method(), req.uri().path(), req.headers())
+                .await)
+        })
+    }
+}
+
+impl AdminService {
+    async fn handle(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Response<BytesBody> {
+        if !self.is_authorized(headers) {
+            return text_response(StatusCode::UNAUTHORIZED, "unauthorized".into());
+        }
+        match self.route(method, path).await {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("admin request {method} {path} failed: {err:#}");
+                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
+            }
+        }
+    }
+
+    async fn route(&self, method: &Method, path: &str) -> Result<Response<BytesBody>> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["clients"]) => {
+                let clients = self.admin.clients().await?;
+                Ok(text_response(
+                    StatusCode::OK,
+                    format_clients(clients, self.mesh_status.as_ref()),
+                ))
+            }
+            (&Method::GET, ["mesh"]) => {
+                let Some(ref status) = self.mesh_status else {
+                    return Ok(text_response(StatusCode::NOT_FOUND, "not meshed".into()));
+                };
+                let health = status.health();
+                let code = if health.is_healthy() {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                Ok(text_response(code, health.to_string()))
+            }
+            (&Method::GET, ["bans"]) => {
+                let mut banned = self.admin.banned().await?;
+                banned.sort();
+                let body = banned.iter().map(|key| format!("{key}\n")).collect();
+                Ok(text_response(StatusCode::OK, body))
+            }
+            (&Method::POST, ["clients", node_id, action]) => {
+                let Ok(key) = node_id.parse::<PublicKey>() else {
+                    return Ok(text_response(
+                        StatusCode::BAD_REQUEST,
+                        "invalid node id".into(),
+                    ));
+                };
+                match *action {
+                    "disconnect" => self.admin.disconnect(key).await?,
+                    "ban" => self.admin.ban(key).await?,
+                    "unban" => self.admin.unban(key).await?,
+                    _ => {
+                        return Ok(text_response(
+                            StatusCode::NOT_FOUND,
+                            "unknown action".into(),
+                        ))
+                    }
+                }
+                info!("admin: {action} {key}");
+                Ok(text_response(StatusCode::OK, format!("{action} {key}\n")))
+            }
+            _ => Ok(text_response(StatusCode::NOT_FOUND, "not found".into())),
+        }
+    }
+
+    /// Whether the request carries the admin token, compared in constant time.
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| derp::is_authorized(value, &self.token))
+    }
+}
+
+/// Lists the clients one per line, longest connected first, clients of the mesh last.
+fn format_clients(mut clients: Vec<ConnectedClient>, mesh_status: Option<&MeshStatus>) -> String {
+    clients.sort_by(|a, b| b.connected_for.cmp(&a.connected_for));
+    let mut out = String::new();
+    for client in clients {
+        let origin = match client.origin {
+            ClientOrigin::Local => "local",
+            ClientOrigin::MeshPeer => "mesh_peer",
+            ClientOrigin::Mesh => "mesh",
+        };
+        out.push_str(&format!("{} origin={origin}", client.node_id));
+        match client.connected_for {
+            Some(age) => out.push_str(&format!(
+                " age={}s bytes_recv={} bytes_sent={} preferred={}",
+                age.as_secs(),
+                client.bytes_recv,
+                client.bytes_sent,
+                client.preferred
+            )),
+            None => {
+                if let Some(url) = mesh_status.and_then(|s| s.member_for(&client.node_id)) {
+                    out.push_str(&format!(" via={url}"));
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<BytesBody> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
+
 fn derp_disabled_handler(
     _r: Request<Incoming>,
     response: ResponseBuilder,
@@ -683,30 +1206,48 @@ fn is_challenge_char(c: char) -> bool {
         || c == '_'
 }
 
-async fn serve_stun(host: IpAddr, port: u16) {
-    match UdpSocket::bind((host, port)).await {
-        Ok(sock) => {
-            let addr = sock.local_addr().expect("socket just bound");
-            info!(%addr, "running STUN server");
-            server_stun_listener(sock)
-                .instrument(debug_span!("stun_server", %addr))
-                .await;
-        }
+async fn serve_stun(host: IpAddr, port: u16, alt_port: Option<u16>) {
+    let sock = match UdpSocket::bind((host, port)).await {
+        Ok(sock) => Arc::new(sock),
         Err(err) => {
             error!("failed to open STUN listener: {:#?}", err);
+            return;
+        }
+    };
+    let addr = sock.local_addr().expect("socket just bound");
+    info!(%addr, "running STUN server");
+    let primary =
+        server_stun_listener(sock.clone(), None).instrument(debug_span!("stun_server", %addr));
+    let Some(alt_port) = alt_port else {
+        return primary.await;
+    };
+    match UdpSocket::bind((host, alt_port)).await {
+        Ok(alt_sock) => {
+            let alt_addr = alt_sock.local_addr().expect("socket just bound");
+            info!(%alt_addr, "running alternate STUN server");
+            let alt = server_stun_listener(Arc::new(alt_sock), Some(sock))
+                .instrument(debug_span!("stun_server", addr = %alt_addr));
+            tokio::join!(primary, alt);
+        }
+        Err(err) => {
+            error!("failed to open alternate STUN listener: {:#?}", err);
+            primary.await;
         }
     }
 }
 
-async fn server_stun_listener(sock: UdpSocket) {
-    let sock = Arc::new(sock);
+/// Answers STUN binding requests received on `sock`.
+///
+/// If `reply_from` is set responses are sent from that socket instead, so clients can tell
+/// whether their NAT lets through packets from a port they never sent to.
+async fn server_stun_listener(sock: Arc<UdpSocket>, reply_from: Option<Arc<UdpSocket>>) {
     let mut buffer = vec![0u8; 64 << 10];
     loop {
         match sock.recv_from(&mut buffer).await {
             Ok((n, src_addr)) => {
                 inc!(StunMetrics, requests);
                 let pkt = buffer[..n].to_vec();
-                let sock = sock.clone();
+                let sock = reply_from.clone().unwrap_or_else(|| sock.clone());
                 tokio::task::spawn(async move {
                     if !stun::is(&pkt) {
                         debug!(%src_addr, "STUN: ignoring non stun packet");
@@ -930,6 +1471,167 @@ mod tests {
             .is_empty());
     }
 
+    #[test]
+    fn test_parse_allow_list() -> Result<()> {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let raw = format!("# enrolled nodes\n{a}\n\n  {b}  \n");
+        assert_eq!(parse_allow_list(&raw)?, vec![a, b]);
+        assert!(parse_allow_list("not a node id").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_config_toml() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            access: AccessConfig,
+        }
+        let cfg: Wrapper = toml::from_str("[access]\nallow_list_file = \"/etc/derper/allowed\"")?;
+        assert_eq!(
+            cfg.access,
+            AccessConfig::AllowListFile("/etc/derper/allowed".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mesh_members() -> Result<()> {
+        let raw =
+            "# region one\nhttps://derp1.example.com/derp\n\n  https://derp2.example.com/derp \n";
+        let members = parse_mesh_members(raw)?;
+        assert_eq!(
+            members,
+            vec![
+                Url::parse("https://derp1.example.com/derp")?,
+                Url::parse("https://derp2.example.com/derp")?,
+            ]
+        );
+        assert!(parse_mesh_members("not a url").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mesh_members_reload() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("mesh");
+        tokio::fs::write(&path, "https://derp2.example.com/derp\n").await?;
+        let static_member = Url::parse("https://derp1.example.com/derp")?;
+        let mut members = MeshMembers::new(vec![static_member.clone()], Some(path.clone()));
+
+        let urls = members.reload().await?.expect("first load");
+        assert_eq!(urls.len(), 2);
+        assert!(members.reload().await?.is_none());
+
+        tokio::fs::write(&path, "").await?;
+        let urls = members.reload().await?.expect("changed");
+        assert_eq!(urls, vec![static_member]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_config_toml() -> Result<()> {
+        let cfg: RestartConfig = toml::from_str("")?;
+        assert_eq!(cfg, RestartConfig::default());
+
+        let cfg: RestartConfig =
+            toml::from_str("reconnect_in_ms = 500\nredirect = \"https://derp2.example.com\"")?;
+        let notice = cfg.notice();
+        assert_eq!(notice.reconnect_in, Duration::from_millis(500));
+        assert_eq!(notice.try_for, Duration::from_secs(5));
+        assert_eq!(notice.redirect, Some("https://derp2.example.com".parse()?));
+        assert_eq!(cfg.drain_timeout(), Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_rate_limit() -> Result<()> {
+        let limits: Limits = toml::from_str("client_bytes_per_second = 1048576")?;
+        let limit = limits.client_rate_limit()?.expect("configured");
+        assert_eq!(limit.bytes_per_second(), 1048576);
+        assert_eq!(limit.bytes_burst(), 1048576);
+
+        let limits: Limits = toml::from_str("client_bytes_per_second = 1024")?;
+        assert!(limits.client_rate_limit().is_err());
+
+        let limits: Limits = toml::from_str("accept_conn_limit = 10.0")?;
+        assert!(limits.client_rate_limit()?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_service() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let derp_server = DerpServerBuilder::new(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let service = AdminService {
+            token: "sekrit".into(),
+            admin: derp_server.admin_handler().expect("derp enabled"),
+            mesh_status: None,
+        };
+        let derper_url: Url = format!("http://{}", derp_server.addr()).parse()?;
+        let secret_key = SecretKey::generate();
+        let key = secret_key.public();
+        let (client, _client_receiver) = ClientBuilder::new(derper_url).build(secret_key);
+        client.connect().await?;
+
+        let mut headers = HeaderMap::new();
+        let res = service.handle(&Method::GET, "/clients", &headers).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = service.handle(&Method::GET, "/mesh", &headers).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        headers.insert(hyper::header::AUTHORIZATION, "Bearer wrong".parse()?);
+        let res = service.handle(&Method::GET, "/clients", &headers).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        headers.insert(hyper::header::AUTHORIZATION, "Bearer sekrit".parse()?);
+        let body = |res: Response<BytesBody>| async move {
+            let bytes = res.into_body().collect().await.unwrap().to_bytes();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+        // the client is registered with the server shortly after connecting
+        let clients = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let res = service.handle(&Method::GET, "/clients", &headers).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                let clients = body(res).await;
+                if !clients.is_empty() {
+                    return clients;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(clients.starts_with(&format!("{key} origin=local age=")));
+        let res = service.handle(&Method::GET, "/mesh", &headers).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = service
+            .handle(&Method::POST, "/clients/not-a-key/ban", &headers)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = service
+            .handle(&Method::POST, &format!("/clients/{key}/ban"), &headers)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = service.handle(&Method::GET, "/clients", &headers).await;
+        assert!(body(res).await.is_empty());
+        let res = service.handle(&Method::GET, "/bans", &headers).await;
+        assert_eq!(body(res).await, format!("{key}\n"));
+
+        let res = service
+            .handle(&Method::POST, &format!("/clients/{key}/unban"), &headers)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = service.handle(&Method::GET, "/bans", &headers).await;
+        assert!(body(res).await.is_empty());
+
+        derp_server.shutdown().await;
+        Ok(())
+    }
+
     #[test]
     fn test_escape_hostname() {
         assert_eq!(
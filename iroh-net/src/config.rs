@@ -28,6 +28,8 @@ pub enum EndpointType {
     Portmapped,
     /// Hard NAT: STUN'ed IPv4 address + local fixed port.
     Stun4LocalPort,
+    /// Endpoint was forwarded manually on the router.
+    Static,
 }
 
 impl Display for EndpointType {
@@ -38,6 +40,7 @@ impl Display for EndpointType {
             EndpointType::Stun => write!(f, "stun"),
             EndpointType::Portmapped => write!(f, "portmap"),
             EndpointType::Stun4LocalPort => write!(f, "stun4localport"),
+            EndpointType::Static => write!(f, "static"),
         }
     }
 }
@@ -12,6 +12,11 @@ pub const EU_DERP_HOSTNAME: &str = "euw1-1.derp.iroh.network.";
 /// STUN port as defined by [RFC 8489](<https://www.rfc-editor.org/rfc/rfc8489#section-18.6>)
 pub const DEFAULT_DERP_STUN_PORT: u16 = 3478;
 
+/// Alternate STUN port of the default derpers, used to classify the NAT.
+///
+/// See [`DerpNode::stun_alt_port`].
+pub const DEFAULT_DERP_STUN_ALT_PORT: u16 = 3479;
+
 /// Get the default [`DerpMap`].
 pub fn default_derp_map() -> DerpMap {
     DerpMap::from_nodes([default_na_derp_node(), default_eu_derp_node()])
@@ -26,6 +31,8 @@ pub fn default_na_derp_node() -> DerpNode {
         url: url.clone(),
         stun_only: false,
         stun_port: DEFAULT_DERP_STUN_PORT,
+        stun_alt_port: Some(DEFAULT_DERP_STUN_ALT_PORT),
+        auth_token: None,
     }
 }
 
@@ -37,5 +44,7 @@ pub fn default_eu_derp_node() -> DerpNode {
         url: url.clone(),
         stun_only: false,
         stun_port: DEFAULT_DERP_STUN_PORT,
+        stun_alt_port: Some(DEFAULT_DERP_STUN_ALT_PORT),
+        auth_token: None,
     }
 }
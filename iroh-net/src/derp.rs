@@ -9,6 +9,7 @@
 //! Based on tailscale/derp/derp.go
 
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
+pub(crate) mod access;
 pub(crate) mod client;
 pub(crate) mod client_conn;
 pub(crate) mod clients;
@@ -19,12 +20,17 @@ mod metrics;
 pub(crate) mod server;
 pub(crate) mod types;
 
+pub use self::access::{is_authorized, AccessCallback, AccessPolicy};
 pub use self::client::{Client as DerpClient, ReceivedMessage};
 pub use self::codec::MAX_PACKET_SIZE;
+pub(crate) use self::codec::{FrameDirection, FrameTap, FrameType};
 pub use self::http::Client as HttpClient;
 pub use self::map::{DerpMap, DerpMode, DerpNode};
 pub use self::metrics::Metrics;
 pub use self::server::{
-    ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, PacketForwarderHandler, Server,
+    AdminHandler, ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer,
+    PacketForwarderHandler, Server,
+};
+pub use self::types::{
+    ClientOrigin, ClientRateLimit, ConnectedClient, MeshKey, PacketForwarder, RestartNotice,
 };
-pub use self::types::{MeshKey, PacketForwarder};
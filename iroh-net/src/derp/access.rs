@@ -0,0 +1,132 @@
+//! Access control for clients connecting to a derp [`super::Server`].
+//!
+//! By default a derp server relays packets for any [`PublicKey`] that completes the handshake.
+//! An [`AccessPolicy`] restricts this to a known set of nodes, to clients presenting a
+//! shared-secret token, or to whatever an external callback decides.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+use crate::key::PublicKey;
+
+/// Scheme used in the `Authorization` header of the HTTP upgrade request to carry the
+/// access token, i.e. `Authorization: Bearer <token>`.
+pub(crate) const AUTH_SCHEME: &str = "Bearer";
+
+/// Callback deciding whether a client may use the derp server.
+///
+/// Receives the [`PublicKey`] the client proved ownership of during the handshake and the
+/// access token it presented, if any. Returning `Ok(false)` or an error rejects the client.
+pub type AccessCallback = Arc<
+    dyn Fn(PublicKey, Option<String>) -> BoxFuture<'static, Result<bool>> + Send + Sync + 'static,
+>;
+
+/// Decides which clients a derp [`super::Server`] accepts.
+///
+/// Mesh peers presenting the server's [`super::MeshKey`] are always accepted, regardless of
+/// the policy.
+#[derive(derive_more::Debug, Clone, Default)]
+pub enum AccessPolicy {
+    /// Accept every client. The default.
+    #[default]
+    Everyone,
+    /// Only accept clients whose [`PublicKey`] is in the set.
+    AllowList(Arc<HashSet<PublicKey>>),
+    /// Only accept clients presenting this shared-secret token.
+    Token(#[debug("<redacted>")] String),
+    /// Ask the [`AccessCallback`] for every client.
+    Callback(#[debug("AccessCallback")] AccessCallback),
+}
+
+impl AccessPolicy {
+    /// Creates an [`AccessPolicy::AllowList`] from the given keys.
+    pub fn allow_list(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        AccessPolicy::AllowList(Arc::new(keys.into_iter().collect()))
+    }
+
+    /// Whether this policy accepts every client.
+    pub fn is_open(&self) -> bool {
+        matches!(self, AccessPolicy::Everyone)
+    }
+
+    /// Checks whether the client with the given `key`, presenting `token`, is accepted.
+    pub async fn is_allowed(&self, key: PublicKey, token: Option<&str>) -> Result<bool> {
+        match self {
+            AccessPolicy::Everyone => Ok(true),
+            AccessPolicy::AllowList(keys) => Ok(keys.contains(&key)),
+            AccessPolicy::Token(expected) => {
+                Ok(token.is_some_and(|token| constant_time_eq(token, expected)))
+            }
+            AccessPolicy::Callback(cb) => cb(key, token.map(ToOwned::to_owned)).await,
+        }
+    }
+}
+
+/// Extracts the access token from the value of an `Authorization` header.
+pub(crate) fn parse_auth_header(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case(AUTH_SCHEME) {
+        return None;
+    }
+    let token = token.trim();
+    (!token.is_empty()).then_some(token)
+}
+
+/// Whether the value of an `Authorization` header carries the `expected` token.
+///
+/// The token is compared in constant time.
+pub fn is_authorized(header: &str, expected: &str) -> bool {
+    parse_auth_header(header).is_some_and(|token| constant_time_eq(token, expected))
+}
+
+/// Compares two strings without short-circuiting on the first mismatching byte.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::key::SecretKey;
+
+    #[tokio::test]
+    async fn test_access_policy() -> Result<()> {
+        let allowed = SecretKey::generate().public();
+        let other = SecretKey::generate().public();
+
+        assert!(AccessPolicy::Everyone.is_allowed(other, None).await?);
+
+        let policy = AccessPolicy::allow_list([allowed]);
+        assert!(policy.is_allowed(allowed, None).await?);
+        assert!(!policy.is_allowed(other, None).await?);
+
+        let policy = AccessPolicy::Token("sekrit".to_string());
+        assert!(policy.is_allowed(other, Some("sekrit")).await?);
+        assert!(!policy.is_allowed(other, Some("sekri")).await?);
+        assert!(!policy.is_allowed(other, None).await?);
+
+        let policy = AccessPolicy::Callback(Arc::new(move |key, _token| {
+            Box::pin(async move { Ok(key == allowed) })
+        }));
+        assert!(policy.is_allowed(allowed, None).await?);
+        assert!(!policy.is_allowed(other, None).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_auth_header() {
+        assert_eq!(parse_auth_header("Bearer abc"), Some("abc"));
+        assert_eq!(parse_auth_header("bearer  abc "), Some("abc"));
+        assert_eq!(parse_auth_header("Basic abc"), None);
+        assert_eq!(parse_auth_header("Bearer "), None);
+        assert_eq!(parse_auth_header("abc"), None);
+    }
+}
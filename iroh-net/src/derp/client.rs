@@ -10,13 +10,14 @@ use futures::{Sink, SinkExt, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, info_span, Instrument};
+use tracing::{debug, info_span, warn, Instrument};
+use url::Url;
 
 use super::codec::PER_CLIENT_READ_QUEUE_DEPTH;
 use super::{
     codec::{
         recv_frame, write_frame, DerpCodec, Frame, FrameType, MAX_PACKET_SIZE,
-        PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
     },
     types::{ClientInfo, MeshKey, RateLimiter, ServerInfo},
 };
@@ -221,18 +222,41 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
         Frame::Restarting {
             reconnect_in,
             try_for,
+            redirect,
         } => {
             let reconnect_in = Duration::from_millis(reconnect_in as u64);
             let try_for = Duration::from_millis(try_for as u64);
+            let redirect = parse_redirect(&redirect);
             Ok(ReceivedMessage::ServerRestarting {
                 reconnect_in,
                 try_for,
+                redirect,
             })
         }
         _ => bail!("unexpected packet: {:?}", frame.typ()),
     }
 }
 
+/// Parses the redirect URL of a `Restarting` frame.
+///
+/// An invalid redirect is ignored rather than failing the connection, the rest of the
+/// restart notice is still useful.
+fn parse_redirect(redirect: &[u8]) -> Option<Url> {
+    if redirect.is_empty() {
+        return None;
+    }
+    match std::str::from_utf8(redirect)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(Url::parse(s)?))
+    {
+        Ok(url) => Some(url),
+        Err(err) => {
+            warn!("ignoring invalid redirect in restarting frame: {err:?}");
+            None
+        }
+    }
+}
+
 /// The kinds of messages we can send to the [`super::server::Server`]
 #[derive(Debug)]
 enum ClientWriterMessage {
@@ -405,9 +429,9 @@ impl ClientBuilder {
         let mut buf = encrypted_message.to_vec();
         shared_secret.open(&mut buf)?;
         let info: ServerInfo = postcard::from_bytes(&buf)?;
-        if info.version != PROTOCOL_VERSION {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&info.version) {
             bail!(
-                "incompatiable protocol version, expected {PROTOCOL_VERSION}, got {}",
+                "incompatiable protocol version {}, expected {MIN_PROTOCOL_VERSION}..={PROTOCOL_VERSION}",
                 info.version
             );
         }
@@ -564,6 +588,9 @@ pub enum ReceivedMessage {
         /// between retries is undefined for now. A server should not send a TryFor duration more
         /// than a few seconds.
         try_for: Duration,
+        /// Another derp server the client should move to, typically one from the same
+        /// [`super::DerpMap`].
+        redirect: Option<Url>,
     },
 }
 
@@ -582,7 +609,7 @@ pub(crate) async fn send_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
     let frame = Frame::SendPacket { dst_key, packet };
     if let Some(rate_limiter) = rate_limiter {
         if rate_limiter.check_n(frame.len()).is_err() {
-            tracing::warn!("dropping send: rate limit reached");
+            tracing::debug!("dropping send: rate limit reached");
             return Ok(());
         }
     }
@@ -1,6 +1,7 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{ensure, Context, Result};
 use bytes::Bytes;
@@ -19,9 +20,12 @@ use iroh_metrics::{inc, inc_by};
 use super::codec::{DerpCodec, Frame};
 use super::server::MaybeTlsStream;
 use super::{
-    codec::{write_frame, KEEP_ALIVE},
+    codec::{write_frame, KEEP_ALIVE, PER_CLIENT_SEND_QUEUE_DEPTH, PER_SOURCE_SEND_QUEUE_DEPTH},
     metrics::Metrics,
-    types::{Packet, PacketForwarder, PeerConnState, ServerMessage},
+    types::{
+        ClientOrigin, ClientRateLimit, ConnectedClient, Packet, PacketForwarder, PeerConnState,
+        RateLimiter, RestartNotice, ServerMessage,
+    },
 };
 
 /// The [`super::server::Server`] side representation of a [`super::client::Client`]'s connection
@@ -51,6 +55,24 @@ pub(crate) struct ClientConnManager {
     /// the client messages. These `Senders` correspond to `Receivers` on the
     /// [`ClientConnIo`].
     pub(crate) client_channels: ClientChannels,
+
+    /// When the connection was accepted
+    connected_at: Instant,
+    /// Whether the client is a mesh peer
+    can_mesh: bool,
+    /// Whether the client considers this its preferred connection, set by the [`ClientConnIo`]
+    preferred: Arc<AtomicBool>,
+    /// Traffic of this connection, counted by the [`ClientConnIo`]
+    stats: Arc<ConnStats>,
+}
+
+/// Bytes relayed for a single client connection.
+#[derive(Debug, Default)]
+pub(crate) struct ConnStats {
+    /// Bytes of packets the client sent through the server
+    bytes_recv: AtomicU64,
+    /// Bytes of packets delivered to the client
+    bytes_sent: AtomicU64,
 }
 
 /// Channels that the [`ClientConnManager`] uses to communicate with the
@@ -71,6 +93,8 @@ pub(crate) struct ClientChannels {
     /// allow the client to update their map of who's connected
     /// to this node
     pub(crate) mesh_update: mpsc::Sender<Vec<PeerConnState>>,
+    /// Announce to the client that the server is restarting
+    pub(crate) restarting: mpsc::Sender<RestartNotice>,
 }
 
 pub trait Io: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug {}
@@ -86,8 +110,11 @@ where
     pub(crate) conn_num: usize,
     pub(crate) io: Framed<MaybeTlsStream, DerpCodec>,
     pub(crate) can_mesh: bool,
+    /// The protocol version agreed on with the client
+    pub(crate) version: usize,
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
+    pub(crate) rate_limit: Option<ClientRateLimit>,
     pub(crate) server_channel: mpsc::Sender<ServerMessage<P>>,
 }
 
@@ -103,8 +130,10 @@ where
             self.conn_num,
             self.io,
             self.can_mesh,
+            self.version,
             self.write_timeout,
             self.channel_capacity,
+            self.rate_limit,
             self.server_channel,
         )
     }
@@ -120,8 +149,10 @@ impl ClientConnManager {
         conn_num: usize,
         io: Framed<MaybeTlsStream, DerpCodec>,
         can_mesh: bool,
+        version: usize,
         write_timeout: Option<Duration>,
         channel_capacity: usize,
+        rate_limit: Option<ClientRateLimit>,
         server_channel: mpsc::Sender<ServerMessage<P>>,
     ) -> ClientConnManager
     where
@@ -134,21 +165,33 @@ impl ClientConnManager {
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(channel_capacity);
+        let (restarting_s, restarting_r) = mpsc::channel(1);
 
         let preferred = Arc::from(AtomicBool::from(false));
+        let stats = Arc::new(ConnStats::default());
+        // mesh peers are trusted and not rate limited
+        let rate_limiter = match rate_limit {
+            Some(limit) if !can_mesh => Some(RateLimiter::from_limit(limit)),
+            _ => None,
+        };
 
         let conn_io = ClientConnIo {
             can_mesh,
+            version,
             io,
             timeout: write_timeout,
+            rate_limiter,
             send_queue: send_queue_r,
+            fair_queue: FairQueue::new(PER_SOURCE_SEND_QUEUE_DEPTH, PER_CLIENT_SEND_QUEUE_DEPTH),
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
             mesh_update_s: mesh_update_s.clone(),
+            restarting: restarting_r,
 
             key,
             preferred: Arc::clone(&preferred),
+            stats: Arc::clone(&stats),
             server_channel: server_channel.clone(),
         };
 
@@ -189,7 +232,28 @@ impl ClientConnManager {
                 disco_send_queue: disco_send_queue_s,
                 peer_gone: peer_gone_s,
                 mesh_update: mesh_update_s,
+                restarting: restarting_s,
             },
+            connected_at: Instant::now(),
+            can_mesh,
+            preferred,
+            stats,
+        }
+    }
+
+    /// Describes this connection for the admin of the server.
+    pub(crate) fn connected_client(&self) -> ConnectedClient {
+        ConnectedClient {
+            node_id: self.key,
+            origin: if self.can_mesh {
+                ClientOrigin::MeshPeer
+            } else {
+                ClientOrigin::Local
+            },
+            connected_for: Some(self.connected_at.elapsed()),
+            bytes_recv: self.stats.bytes_recv.load(Ordering::Relaxed),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            preferred: self.preferred.load(Ordering::Relaxed),
         }
     }
 
@@ -221,6 +285,7 @@ impl ClientConnManager {
 ///  - a PEER_GONE frame to inform the client that a peer they have previously sent messages to
 ///  is gone from the network
 ///  - packets from other peers
+///  - a RESTARTING frame when the server is about to restart
 ///
 /// If the client is a mesh client, it can also send updates about peers in the mesh.
 ///
@@ -242,12 +307,18 @@ impl ClientConnManager {
 pub(crate) struct ClientConnIo<P: PacketForwarder> {
     /// Indicates whether this client can mesh
     can_mesh: bool,
+    /// The protocol version agreed on with the client
+    version: usize,
     /// Io to talk to the client
     io: Framed<MaybeTlsStream, DerpCodec>,
     /// Max time we wait to complete a write to the client
     timeout: Option<Duration>,
+    /// Limits the bytes this client may send through the server
+    rate_limiter: Option<RateLimiter>,
     /// Packets queued to send to the client
     send_queue: mpsc::Receiver<Packet>,
+    /// Packets taken off the `send_queue`, waiting to be written to the client in a fair order
+    fair_queue: FairQueue,
     /// Important packets queued to send to the client
     disco_send_queue: mpsc::Receiver<Packet>,
     /// Notify the client that a previous sender has disconnected (not used by mesh peers)
@@ -259,6 +330,8 @@ pub(crate) struct ClientConnIo<P: PacketForwarder> {
     mesh_update_r: mpsc::Receiver<Vec<PeerConnState>>,
     /// Used by `reschedule_mesh_update` to reschedule additional mesh_updates
     mesh_update_s: mpsc::Sender<Vec<PeerConnState>>,
+    /// Notice that the server is restarting
+    restarting: mpsc::Receiver<RestartNotice>,
 
     /// [`PublicKey`] of this client
     key: PublicKey,
@@ -274,6 +347,8 @@ pub(crate) struct ClientConnIo<P: PacketForwarder> {
     // might find that the alternative is better, once I have a better idea of how this is supposed
     // to be read.
     preferred: Arc<AtomicBool>,
+    /// Counts the bytes relayed for this client
+    stats: Arc<ConnStats>,
 }
 
 impl<P> ClientConnIo<P>
@@ -317,17 +392,30 @@ where
                     trace!("peer gone: {:?}", peer);
                     self.send_peer_gone(peer).await?;
                 }
+                notice = self.restarting.recv() => {
+                    let notice = notice.context("Server.restarting dropped")?;
+                    trace!("send restarting");
+                    self.send_restarting(notice).await?;
+                }
                 updates = self.mesh_update_r.recv() => {
                     let updates = updates.context("Server.mesh_update dropped")?;
                     trace!("mesh updates");
                     self.send_mesh_updates(updates).await?;
                 }
-                packet = self.send_queue.recv() => {
+                // once the fair queue is full, packets back up in the channel again
+                packet = self.send_queue.recv(), if !self.fair_queue.is_full() => {
                     let packet = packet.context("Server.send_queue dropped")?;
-                    trace!("send packet");
-                    self.send_packet(packet).await.context("send packet")?;
-                    // TODO: stats
-                    // record `packet.enqueuedAt`
+                    trace!("queue packet");
+                    self.enqueue_fair(packet);
+                    // drain the channel, so per source limits apply rather than the
+                    // shared channel capacity
+                    while !self.fair_queue.is_full() {
+                        let Ok(packet) = self.send_queue.try_recv() else {
+                            break;
+                        };
+                        self.enqueue_fair(packet);
+                    }
+                    self.send_next_fair().await?;
                 }
                 packet = self.disco_send_queue.recv() => {
                     let packet = packet.context("Server.disco_send_queue dropped")?;
@@ -340,6 +428,9 @@ where
                     trace!("keep alive");
                     self.send_keep_alive().await.context("send keep alive")?;
                 }
+                _ = std::future::ready(()), if !self.fair_queue.is_empty() => {
+                    self.send_next_fair().await?;
+                }
             }
             // TODO: golang batches as many writes as are in all the channels
             // & then flushes when there is no more work to be done at the moment.
@@ -373,6 +464,28 @@ where
         write_frame(&mut self.io, Frame::PeerGone { peer }, self.timeout).await
     }
 
+    /// Sends a restarting frame, does not flush
+    ///
+    /// Errors if the send does not happen within the `timeout` duration
+    async fn send_restarting(&mut self, notice: RestartNotice) -> Result<()> {
+        let millis = |d: Duration| u32::try_from(d.as_millis()).unwrap_or(u32::MAX);
+        // clients before version 3 reject restarting frames carrying a redirect
+        let redirect = match notice.redirect {
+            Some(url) if self.version >= 3 => Bytes::from(url.to_string()),
+            _ => Bytes::new(),
+        };
+        write_frame(
+            &mut self.io,
+            Frame::Restarting {
+                reconnect_in: millis(notice.reconnect_in),
+                try_for: millis(notice.try_for),
+                redirect,
+            },
+            self.timeout,
+        )
+        .await
+    }
+
     /// Sends a peer present frame, does not flush
     ///
     /// Errors if the send does not happen within the `timeout` duration
@@ -421,6 +534,26 @@ where
         Ok(())
     }
 
+    /// Adds a packet to the [`FairQueue`], dropping it if its source already used up its share.
+    fn enqueue_fair(&mut self, packet: Packet) {
+        if let Err(packet) = self.fair_queue.push(packet) {
+            trace!("dropping packet from {:?}: over fair share", packet.src);
+            inc!(Metrics, send_packets_dropped);
+            inc_by!(Metrics, bytes_dropped_fair_share, packet.bytes.len() as u64);
+        }
+    }
+
+    /// Sends the next packet from the [`FairQueue`], does not flush.
+    async fn send_next_fair(&mut self) -> Result<()> {
+        if let Some(packet) = self.fair_queue.pop() {
+            trace!("send packet");
+            self.send_packet(packet).await.context("send packet")?;
+            // TODO: stats
+            // record `packet.enqueuedAt`
+        }
+        Ok(())
+    }
+
     /// Writes contents to the client in a `RECV_PACKET` frame. If `srcKey.is_zero`, it uses the
     /// old DERPv1 framing format, otherwise uses the DERPv2 framing format. The bytes of contents
     /// are only valid until this function returns, do not retain the slices.
@@ -429,6 +562,9 @@ where
         let src_key = packet.src;
         let content = packet.bytes;
         inc_by!(Metrics, bytes_sent, content.len().try_into().unwrap());
+        self.stats
+            .bytes_sent
+            .fetch_add(content.len() as u64, Ordering::Relaxed);
         write_frame(
             &mut self.io,
             Frame::RecvPacket { src_key, content },
@@ -450,8 +586,17 @@ where
             }
             Frame::SendPacket { dst_key, packet } => {
                 let packet_len = packet.len();
-                self.handle_frame_send_packet(dst_key, packet).await?;
                 inc_by!(Metrics, bytes_recv, packet_len as u64);
+                self.stats
+                    .bytes_recv
+                    .fetch_add(packet_len as u64, Ordering::Relaxed);
+                if self.is_rate_limited(packet_len) {
+                    trace!("dropping packet to {dst_key:?}: rate limit reached");
+                    inc!(Metrics, send_packets_dropped);
+                    inc_by!(Metrics, bytes_dropped_rate_limit, packet_len as u64);
+                    return Ok(());
+                }
+                self.handle_frame_send_packet(dst_key, packet).await?;
             }
             Frame::ForwardPacket {
                 src_key,
@@ -484,6 +629,14 @@ where
         Ok(())
     }
 
+    /// Whether sending `len` bytes exceeds this client's rate limit.
+    fn is_rate_limited(&self, len: usize) -> bool {
+        match self.rate_limiter {
+            Some(ref limiter) => len > 0 && limiter.check_n(len).is_err(),
+            None => false,
+        }
+    }
+
     /// Preferred indicates if this is the preferred connection to the client with
     /// this public key.
     fn set_preferred(&mut self, v: bool) -> Result<()> {
@@ -607,11 +760,83 @@ where
     }
 }
 
+/// Queues packets per source and hands them out round-robin.
+///
+/// The server's send queue to a client is shared by all sources, so one busy sender could
+/// otherwise fill it up and starve everybody else sending to the same client. Each source may
+/// have at most `per_source_depth` packets queued, further packets from it are dropped. All
+/// sources together may have at most `total_depth` packets queued, callers should stop taking
+/// packets off the send queue while [`FairQueue::is_full`].
+#[derive(Debug)]
+struct FairQueue {
+    queues: HashMap<PublicKey, VecDeque<Packet>>,
+    /// Sources with queued packets, in the order they will be served.
+    order: VecDeque<PublicKey>,
+    per_source_depth: usize,
+    total_depth: usize,
+    /// Number of packets queued over all sources.
+    len: usize,
+}
+
+impl FairQueue {
+    fn new(per_source_depth: usize, total_depth: usize) -> Self {
+        Self {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+            per_source_depth,
+            total_depth,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.total_depth
+    }
+
+    /// Queues the packet, returning it if its source or the whole queue has no room left.
+    fn push(&mut self, packet: Packet) -> Result<(), Packet> {
+        if self.is_full() {
+            return Err(packet);
+        }
+        let queue = self.queues.entry(packet.src).or_default();
+        if queue.len() >= self.per_source_depth {
+            return Err(packet);
+        }
+        if queue.is_empty() {
+            self.order.push_back(packet.src);
+        }
+        queue.push_back(packet);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Takes the next packet, rotating through the sources.
+    fn pop(&mut self) -> Option<Packet> {
+        let src = self.order.pop_front()?;
+        let queue = self
+            .queues
+            .get_mut(&src)
+            .expect("ordered sources are queued");
+        let packet = queue.pop_front();
+        self.len -= 1;
+        if queue.is_empty() {
+            self.queues.remove(&src);
+        } else {
+            self.order.push_back(src);
+        }
+        packet
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
-    use crate::derp::codec::{recv_frame, FrameType};
+    use crate::derp::codec::{recv_frame, FrameType, MAX_PACKET_SIZE, PROTOCOL_VERSION};
     use crate::key::SecretKey;
 
     use super::*;
@@ -631,6 +856,7 @@ mod tests {
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (restarting_s, restarting_r) = mpsc::channel(1);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
@@ -640,17 +866,22 @@ mod tests {
 
         let conn_io = ClientConnIo::<MockPacketForwarder> {
             can_mesh: true,
+            version: PROTOCOL_VERSION,
             io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
             timeout: None,
+            rate_limiter: None,
             send_queue: send_queue_r,
+            fair_queue: FairQueue::new(PER_SOURCE_SEND_QUEUE_DEPTH, PER_CLIENT_SEND_QUEUE_DEPTH),
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
             mesh_update_s: mesh_update_s.clone(),
+            restarting: restarting_r,
 
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            stats: Default::default(),
         };
 
         let done = CancellationToken::new();
@@ -714,6 +945,24 @@ mod tests {
         let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
         assert_eq!(frame, Frame::PeerGone { peer: key });
 
+        // send restarting
+        println!("send restarting");
+        let notice = RestartNotice {
+            reconnect_in: Duration::from_millis(500),
+            try_for: Duration::from_secs(5),
+            redirect: Some("https://derp.example.com".parse()?),
+        };
+        restarting_s.send(notice).await?;
+        let frame = recv_frame(FrameType::Restarting, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::Restarting {
+                reconnect_in: 500,
+                try_for: 5000,
+                redirect: Bytes::from_static(b"https://derp.example.com/"),
+            }
+        );
+
         // Read tests
         println!("--read");
 
@@ -835,12 +1084,116 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fair_queue() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let packet = |src, n: u8| Packet {
+            src,
+            bytes: Bytes::from(vec![n]),
+        };
+
+        let mut queue = FairQueue::new(2, 3);
+        assert!(queue.is_empty());
+        assert!(queue.push(packet(a, 1)).is_ok());
+        assert!(queue.push(packet(a, 2)).is_ok());
+        // `a` used up its share
+        let dropped = queue.push(packet(a, 3)).unwrap_err();
+        assert_eq!(&dropped.bytes[..], &[3]);
+        assert!(queue.push(packet(b, 4)).is_ok());
+
+        // sources are served in turns
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|p| (p.src, p.bytes[0]))
+            .collect();
+        assert_eq!(order, vec![(a, 1), (b, 4), (a, 2)]);
+        assert!(queue.is_empty());
+
+        // `a` has room again
+        assert!(queue.push(packet(a, 5)).is_ok());
+
+        // no source may exceed the total depth
+        let c = SecretKey::generate().public();
+        assert!(queue.push(packet(b, 6)).is_ok());
+        assert!(queue.push(packet(c, 7)).is_ok());
+        assert!(queue.is_full());
+        let dropped = queue.push(packet(c, 8)).unwrap_err();
+        assert_eq!(&dropped.bytes[..], &[8]);
+        assert!(queue.pop().is_some());
+        assert!(!queue.is_full());
+    }
+
+    #[tokio::test]
+    async fn test_client_conn_rate_limit() -> Result<()> {
+        let (_send_queue_s, send_queue_r) = mpsc::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
+        let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (_restarting_s, restarting_r) = mpsc::channel(1);
+
+        let key = SecretKey::generate().public();
+        let (io, io_rw) = tokio::io::duplex(1024);
+        let mut io_rw = Framed::new(io_rw, DerpCodec);
+        let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
+
+        // the burst fits exactly one full sized packet, then nothing for a long time
+        let burst = (MAX_PACKET_SIZE + crate::key::PUBLIC_KEY_LENGTH) as u32;
+        let limit = ClientRateLimit::new(1, burst)?;
+        let conn_io = ClientConnIo::<MockPacketForwarder> {
+            can_mesh: false,
+            version: PROTOCOL_VERSION,
+            io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
+            timeout: None,
+            rate_limiter: Some(RateLimiter::from_limit(limit)),
+            send_queue: send_queue_r,
+            fair_queue: FairQueue::new(PER_SOURCE_SEND_QUEUE_DEPTH, PER_CLIENT_SEND_QUEUE_DEPTH),
+            disco_send_queue: disco_send_queue_r,
+            peer_gone: peer_gone_r,
+            mesh_update_r,
+            mesh_update_s,
+            restarting: restarting_r,
+
+            key,
+            server_channel: server_channel_s,
+            preferred: Default::default(),
+            stats: Default::default(),
+        };
+
+        let done = CancellationToken::new();
+        let io_done = done.clone();
+        let io_handle = tokio::task::spawn(async move { conn_io.run(io_done).await });
+
+        let target = SecretKey::generate().public();
+        let data = Bytes::from(vec![1u8; MAX_PACKET_SIZE]);
+        for _ in 0..3 {
+            crate::derp::client::send_packet(&mut io_rw, &None, target, data.clone()).await?;
+        }
+        // frames are handled in order, once the pong arrives all packets were processed
+        write_frame(&mut io_rw, Frame::Ping { data: [0u8; 8] }, None).await?;
+        recv_frame(FrameType::Pong, &mut io_rw).await?;
+
+        // only the first packet made it to the server, the others were over the limit
+        match server_channel_r.try_recv()? {
+            ServerMessage::SendPacket((got_target, packet)) => {
+                assert_eq!(target, got_target);
+                assert_eq!(data, packet.bytes);
+            }
+            m => bail!("expected ServerMessage::SendPacket, got {m:?}"),
+        }
+        assert!(server_channel_r.try_recv().is_err());
+
+        done.cancel();
+        io_handle.await??;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_conn_read_err() -> Result<()> {
         let (_send_queue_s, send_queue_r) = mpsc::channel(10);
         let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
         let (mesh_update_s, mesh_update_r) = mpsc::channel(10);
+        let (_restarting_s, restarting_r) = mpsc::channel(1);
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
@@ -851,17 +1204,22 @@ mod tests {
         println!("-- create client conn");
         let conn_io = ClientConnIo::<MockPacketForwarder> {
             can_mesh: true,
+            version: PROTOCOL_VERSION,
             io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
             timeout: None,
+            rate_limiter: None,
             send_queue: send_queue_r,
+            fair_queue: FairQueue::new(PER_SOURCE_SEND_QUEUE_DEPTH, PER_CLIENT_SEND_QUEUE_DEPTH),
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
             mesh_update_r,
             mesh_update_s: mesh_update_s.clone(),
+            restarting: restarting_r,
 
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            stats: Default::default(),
         };
 
         let done = CancellationToken::new();
@@ -13,7 +13,7 @@ use tracing::{Instrument, Span};
 use super::{
     client_conn::{ClientConnBuilder, ClientConnManager},
     metrics::Metrics,
-    types::{Packet, PeerConnState},
+    types::{ConnectedClient, Packet, PeerConnState, RestartNotice},
     PacketForwarder,
 };
 
@@ -117,6 +117,19 @@ impl Client {
         }
         res
     }
+
+    pub fn send_restarting(&self, notice: RestartNotice) -> Result<(), SendError> {
+        let res = try_send(&self.conn.client_channels.restarting, notice);
+        match res {
+            Ok(_) => {
+                inc!(Metrics, other_packets_sent);
+            }
+            Err(_) => {
+                inc!(Metrics, other_packets_dropped);
+            }
+        }
+        res
+    }
 }
 
 // TODO: in the goimpl, it also tries 3 times to send a packet. But, in go we can clone receiver
@@ -172,13 +185,6 @@ impl Clients {
         join_all(handles).await;
     }
 
-    pub fn close_conn(&mut self, key: &PublicKey) {
-        tracing::info!("closing conn {:?}", key);
-        if let Some(client) = self.inner.remove(key) {
-            client.shutdown();
-        }
-    }
-
     /// Record that `src` sent or forwarded a packet to `dst`
     pub fn record_send(&mut self, src: &PublicKey, dst: PublicKey) {
         if let Some(client) = self.inner.get_mut(src) {
@@ -190,6 +196,13 @@ impl Clients {
         self.inner.keys()
     }
 
+    /// Describes all client connections.
+    pub fn connected(&self) -> impl Iterator<Item = ConnectedClient> + '_ {
+        self.inner
+            .values()
+            .map(|client| client.conn.connected_client())
+    }
+
     pub fn contains_key(&self, key: &PublicKey) -> bool {
         self.inner.contains_key(key)
     }
@@ -277,6 +290,16 @@ impl Clients {
         tracing::warn!("Could not find client for {key:?}, dropping mesh update packet",);
     }
 
+    /// Announce to the client that the server is restarting, smearing `reconnect_in` per client.
+    pub fn send_restarting(&mut self, key: &PublicKey, notice: &RestartNotice) {
+        if let Some(client) = self.inner.get(key) {
+            let res = client.send_restarting(notice.smeared());
+            let _ = self.process_result(key, res);
+            return;
+        };
+        tracing::warn!("Could not find client for {key:?}, dropping restarting packet");
+    }
+
     fn process_result(
         &mut self,
         key: &PublicKey,
@@ -336,8 +359,10 @@ mod tests {
                 conn_num,
                 io: Framed::new(crate::derp::server::MaybeTlsStream::Test(io), DerpCodec),
                 can_mesh: true,
+                version: crate::derp::codec::PROTOCOL_VERSION,
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limit: None,
                 server_channel,
             },
             FramedRead::new(test_io, DerpCodec),
@@ -1,9 +1,14 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context as TaskContext, Poll};
 use std::time::Duration;
 
 use anyhow::{bail, ensure, Context};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use ed25519_dalek::PUBLIC_KEY_LENGTH;
 use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_util::codec::{Decoder, Encoder};
 
 use super::types::ClientInfo;
@@ -28,11 +33,22 @@ pub(super) const SERVER_CHANNEL_SIZE: usize = 1024 * 100;
 /// The number of packets buffered for sending per client
 pub(super) const PER_CLIENT_SEND_QUEUE_DEPTH: usize = 512; //32;
 pub(super) const PER_CLIENT_READ_QUEUE_DEPTH: usize = 512;
+/// The number of packets from a single source buffered for sending to a client, see the
+/// `FairQueue` in `client_conn`
+pub(super) const PER_SOURCE_SEND_QUEUE_DEPTH: usize = 64;
 
 /// ProtocolVersion is bumped whenever there's a wire-incompatiable change.
 ///  - version 1 (zero on wire): consistent box headers, in use by employee dev nodes a bit
 ///  - version 2: received packets have src addrs in FrameType::RecvPacket at beginning
-pub(super) const PROTOCOL_VERSION: usize = 2;
+///  - version 3: FrameType::Restarting may carry a redirect url
+///
+/// Clients and servers agree on the lower of their two versions, see [`MIN_PROTOCOL_VERSION`].
+pub(super) const PROTOCOL_VERSION: usize = 3;
+
+/// The oldest protocol version we still talk to.
+///
+/// Version 2 peers only miss out on the redirect of `FrameType::Restarting`.
+pub(super) const MIN_PROTOCOL_VERSION: usize = 2;
 
 ///
 /// Protocol flow:
@@ -112,9 +128,8 @@ pub(crate) enum FrameType {
 
     /// Sent from server to client for the server to declare that it's restarting.
     /// Payload is two big endian u32 durations in milliseconds: when to reconnect,
-    /// and how long to try total.
-    ///
-    /// Handled on the `[derp::Client]`, but currently never sent on the `[derp::Server]`
+    /// and how long to try total. Since protocol version 3 optionally followed by the
+    /// UTF-8 encoded URL of another derp server the client should move to.
     Restarting = 15,
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
@@ -128,6 +143,84 @@ impl std::fmt::Display for FrameType {
     }
 }
 
+/// Whether a frame observed by a [`FrameTap`] was read from or written to the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameDirection {
+    Read,
+    Written,
+}
+
+/// Observes every frame of a connection, including the frame header.
+pub(crate) type FrameTap = Arc<dyn Fn(FrameDirection, FrameType, &[u8]) + Send + Sync + 'static>;
+
+/// Wraps one side of a connection, reporting the frames passing through to a [`FrameTap`].
+///
+/// The frames are reassembled from the bytes read or written, `io` is not affected.
+pub(crate) struct TappedIo<T> {
+    io: T,
+    tap: FrameTap,
+    direction: FrameDirection,
+    /// Bytes of frames which did not fully pass through yet.
+    partial: BytesMut,
+}
+
+impl<T> TappedIo<T> {
+    pub(crate) fn new(io: T, tap: FrameTap, direction: FrameDirection) -> Self {
+        Self {
+            io,
+            tap,
+            direction,
+            partial: BytesMut::new(),
+        }
+    }
+
+    /// Adds bytes which passed through, reporting the frames they complete.
+    fn observe(&mut self, data: &[u8]) {
+        self.partial.extend_from_slice(data);
+        while self.partial.len() >= HEADER_LEN {
+            let frame_len = u32::from_be_bytes(self.partial[1..5].try_into().unwrap()) as usize;
+            if self.partial.len() < HEADER_LEN + frame_len {
+                break;
+            }
+            let frame = self.partial.split_to(HEADER_LEN + frame_len);
+            (self.tap)(self.direction, frame[0].into(), &frame);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TappedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut self.io).poll_read(cx, buf))?;
+        self.observe(&buf.filled()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TappedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.io).poll_write(cx, buf))?;
+        self.observe(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
 /// Writes complete frame, errors if it is unable to write within the given `timeout`.
 /// Ignores the timeout if `timeout.is_zero()`
 ///
@@ -253,6 +346,8 @@ pub(crate) enum Frame {
     Restarting {
         reconnect_in: u32,
         try_for: u32,
+        /// URL of the derp server to move to, empty if there is none.
+        redirect: Bytes,
     },
     ForwardPacket {
         src_key: PublicKey,
@@ -306,7 +401,7 @@ impl Frame {
             Frame::Ping { .. } => 8,
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
-            Frame::Restarting { .. } => 4 + 4,
+            Frame::Restarting { redirect, .. } => 4 + 4 + redirect.len(),
             Frame::ForwardPacket {
                 src_key: _,
                 dst_key: _,
@@ -370,9 +465,11 @@ impl Frame {
             Frame::Restarting {
                 reconnect_in,
                 try_for,
+                redirect,
             } => {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
+                dst.put(redirect.as_ref());
             }
             Frame::ForwardPacket {
                 src_key,
@@ -502,15 +599,17 @@ impl Frame {
             FrameType::Health => Self::Health { problem: content },
             FrameType::Restarting => {
                 ensure!(
-                    content.len() == 4 + 4,
+                    content.len() >= 4 + 4,
                     "invalid restarting frame length: {}",
                     content.len()
                 );
                 let reconnect_in = u32::from_be_bytes(content[..4].try_into().unwrap());
-                let try_for = u32::from_be_bytes(content[4..].try_into().unwrap());
+                let try_for = u32::from_be_bytes(content[4..8].try_into().unwrap());
+                let redirect = content.slice(8..);
                 Self::Restarting {
                     reconnect_in,
                     try_for,
+                    redirect,
                 }
             }
             FrameType::ForwardPacket => {
@@ -680,6 +779,56 @@ mod tests {
         assert_eq!(client_info, got_client_info);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_tapped_io() -> anyhow::Result<()> {
+        let (reader, writer) = tokio::io::duplex(16);
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let tap: FrameTap = {
+            let frames = frames.clone();
+            Arc::new(move |direction, typ, frame: &[u8]| {
+                frames.lock().unwrap().push((direction, typ, frame.len()))
+            })
+        };
+        let reader = TappedIo::new(reader, tap.clone(), FrameDirection::Read);
+        let writer = TappedIo::new(writer, tap, FrameDirection::Written);
+        let mut reader = FramedRead::new(reader, DerpCodec);
+        let mut writer = FramedWrite::new(writer, DerpCodec);
+
+        // Larger than the pipe, so the frame passes through in pieces.
+        let packet = Frame::SendPacket {
+            dst_key: SecretKey::generate().public(),
+            packet: vec![7u8; 100].into(),
+        };
+        let write = async {
+            write_frame(&mut writer, Frame::KeepAlive, None).await?;
+            write_frame(&mut writer, packet.clone(), None).await?;
+            writer.flush().await?;
+            anyhow::Ok(())
+        };
+        let read = async {
+            let a = reader.next().await.unwrap()?;
+            let b = reader.next().await.unwrap()?;
+            anyhow::Ok((a, b))
+        };
+        let (written, read) = tokio::join!(write, read);
+        written?;
+        assert_eq!(read?, (Frame::KeepAlive, packet));
+
+        let mut frames = frames.lock().unwrap().clone();
+        frames.sort_by_key(|(direction, _, _)| *direction == FrameDirection::Read);
+        let send_len = HEADER_LEN + PUBLIC_KEY_LENGTH + 100;
+        assert_eq!(
+            frames,
+            [
+                (FrameDirection::Written, FrameType::KeepAlive, HEADER_LEN),
+                (FrameDirection::Written, FrameType::SendPacket, send_len),
+                (FrameDirection::Read, FrameType::KeepAlive, HEADER_LEN),
+                (FrameDirection::Read, FrameType::SendPacket, send_len),
+            ]
+        );
+        Ok(())
+    }
 }
 
 /// these test are slow in debug mode, so only run them in release mode
@@ -728,9 +877,12 @@ mod proptests {
         let pong = prop::array::uniform8(any::<u8>()).prop_map(|data| Frame::Pong { data });
         let health = data(0).prop_map(|problem| Frame::Health { problem });
         let restarting =
-            (any::<u32>(), any::<u32>()).prop_map(|(reconnect_in, try_for)| Frame::Restarting {
-                reconnect_in,
-                try_for,
+            (any::<u32>(), any::<u32>(), data(8)).prop_map(|(reconnect_in, try_for, redirect)| {
+                Frame::Restarting {
+                    reconnect_in,
+                    try_for,
+                    redirect,
+                }
             });
         let forward_packet =
             (key(), key(), data(64)).prop_map(|(src_key, dst_key, packet)| Frame::ForwardPacket {
@@ -767,13 +919,13 @@ mod proptests {
                 | FrameType::WatchConns
                 | FrameType::Ping
                 | FrameType::Pong
-                | FrameType::Restarting
                 | FrameType::PeerGone
                 | FrameType::PeerPresent
                 | FrameType::ClosePeer => true,
                 FrameType::ClientInfo
                 | FrameType::ServerInfo
                 | FrameType::Health
+                | FrameType::Restarting
                 | FrameType::ForwardPacket
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
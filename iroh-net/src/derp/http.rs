@@ -10,7 +10,9 @@ mod mesh_clients;
 mod server;
 
 pub use self::client::{Client, ClientBuilder, ClientError, ClientReceiver};
-pub use self::mesh_clients::MeshAddrs;
+pub use self::mesh_clients::{
+    MeshAddrs, MeshHealth, MeshMemberStatus, MeshStatus, MESH_PARTITION_TIMEOUT,
+};
 pub use self::server::{Server, ServerBuilder, TlsAcceptor, TlsConfig};
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
@@ -9,7 +9,7 @@ use anyhow::bail;
 use bytes::Bytes;
 use futures::future::BoxFuture;
 use hyper::body::Incoming;
-use hyper::header::UPGRADE;
+use hyper::header::{AUTHORIZATION, UPGRADE};
 use hyper::upgrade::{Parts, Upgraded};
 use hyper::Request;
 use iroh_metrics::inc;
@@ -24,8 +24,12 @@ use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use url::Url;
 
 use crate::derp::{
-    client::Client as DerpClient, client::ClientBuilder as DerpClientBuilder,
-    client::ClientReceiver as DerpClientReceiver, metrics::Metrics, server::PacketForwarderHandler,
+    client::Client as DerpClient,
+    client::ClientBuilder as DerpClientBuilder,
+    client::ClientReceiver as DerpClientReceiver,
+    codec::{FrameDirection, FrameTap, TappedIo},
+    metrics::Metrics,
+    server::PacketForwarderHandler,
     MeshKey, PacketForwarder, ReceivedMessage,
 };
 use crate::dns::DNS_RESOLVER;
@@ -110,6 +114,9 @@ pub enum ClientError {
     /// The inner actor is gone, likely means things are shutdown.
     #[error("actor gone")]
     ActorGone,
+    /// Reconnecting is paused, see [`Client::close_for_reconnect_after`].
+    #[error("reconnect paused")]
+    ReconnectPaused,
 }
 
 /// An HTTP DERP client.
@@ -133,6 +140,7 @@ enum ActorMessage {
     Send(PublicKey, Bytes, oneshot::Sender<Result<(), ClientError>>),
     Close(oneshot::Sender<Result<(), ClientError>>),
     CloseForReconnect(oneshot::Sender<Result<(), ClientError>>),
+    CloseForReconnectAfter(Duration, oneshot::Sender<Result<(), ClientError>>),
     IsConnected(oneshot::Sender<Result<bool, ClientError>>),
     WatchConnectionChanges(oneshot::Sender<Result<(PublicKey, usize), ClientError>>),
     ClosePeer(PublicKey, oneshot::Sender<Result<(), ClientError>>),
@@ -151,6 +159,8 @@ struct Actor {
     is_preferred: bool,
     derp_client: Option<(DerpClient, DerpClientReceiver)>,
     is_closed: bool,
+    /// While set, we do not connect again before this time, unless asked to explicitly.
+    reconnect_paused_until: Option<Instant>,
     #[debug("address family selector callback")]
     address_family_selector:
         Option<Box<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync + 'static>>,
@@ -159,6 +169,10 @@ struct Actor {
     is_prober: bool,
     server_public_key: Option<PublicKey>,
     url: Url,
+    #[debug("{}", auth_token.as_ref().map_or("None", |_| "Some(<redacted>)"))]
+    auth_token: Option<String>,
+    #[debug("{}", frame_tap.as_ref().map_or("None", |_| "Some(FrameTap)"))]
+    frame_tap: Option<FrameTap>,
     #[debug("TlsConnector")]
     tls_connector: tokio_rustls::TlsConnector,
     pings: PingTracker,
@@ -204,6 +218,10 @@ pub struct ClientBuilder {
     server_public_key: Option<PublicKey>,
     /// Server url.
     url: Url,
+    /// Default is None
+    auth_token: Option<String>,
+    /// Default is None
+    frame_tap: Option<FrameTap>,
 }
 
 impl std::fmt::Debug for ClientBuilder {
@@ -227,6 +245,8 @@ impl ClientBuilder {
             is_prober: false,
             server_public_key: None,
             url: url.into(),
+            auth_token: None,
+            frame_tap: None,
         }
     }
 
@@ -275,6 +295,19 @@ impl ClientBuilder {
         self
     }
 
+    /// Present this access token to the server, for servers restricting access with an
+    /// [`crate::derp::AccessPolicy::Token`].
+    pub fn auth_token(mut self, token: Option<String>) -> Self {
+        self.auth_token = token;
+        self
+    }
+
+    /// Reports every frame of the connections to the server to `tap`, used for packet captures.
+    pub(crate) fn frame_tap(mut self, tap: Option<FrameTap>) -> Self {
+        self.frame_tap = tap;
+        self
+    }
+
     /// Build the [`Client`]
     pub fn build(self, key: SecretKey) -> (Client, ClientReceiver) {
         // TODO: review TLS config
@@ -306,6 +339,7 @@ impl ClientBuilder {
             is_preferred: self.is_preferred,
             derp_client: None,
             is_closed: false,
+            reconnect_paused_until: None,
             address_family_selector: self.address_family_selector,
             conn_gen: 0,
             pings: PingTracker::default(),
@@ -314,6 +348,8 @@ impl ClientBuilder {
             is_prober: self.is_prober,
             server_public_key: self.server_public_key,
             url: self.url,
+            auth_token: self.auth_token,
+            frame_tap: self.frame_tap,
             tls_connector,
         };
 
@@ -344,8 +380,14 @@ impl ClientBuilder {
 #[allow(dead_code)]
 pub(crate) enum MeshClientEvent {
     Meshed,
-    PeerPresent { peer: PublicKey },
-    PeerGone { peer: PublicKey },
+    PeerPresent {
+        peer: PublicKey,
+    },
+    PeerGone {
+        peer: PublicKey,
+    },
+    /// Lost the connection to the remote server, we will try to mesh again.
+    Disconnected,
 }
 
 impl ClientReceiver {
@@ -443,6 +485,15 @@ impl Client {
         self.send_actor(ActorMessage::CloseForReconnect).await
     }
 
+    /// Disconnect the http derp connection and do not reconnect for `delay`.
+    ///
+    /// Until then, receiving does not reconnect and actions requiring a connection fail with
+    /// [`ClientError::ReconnectPaused`]. An explicit [`Client::connect`] ends the pause early.
+    pub async fn close_for_reconnect_after(&self, delay: Duration) -> Result<(), ClientError> {
+        self.send_actor(|s| ActorMessage::CloseForReconnectAfter(delay, s))
+            .await
+    }
+
     /// Returns `true` if the underyling derp connection is established.
     pub async fn is_connected(&self) -> Result<bool, ClientError> {
         self.send_actor(ActorMessage::IsConnected).await
@@ -492,6 +543,7 @@ impl Client {
     /// track network changes.
     /// When peers connect and disconnect, [`MeshClientEvent::PeerPresent`] and  [`MeshClientEvent::PeerGone`]
     /// are sent respectively.
+    /// [`MeshClientEvent::Disconnected`] is sent when we lose the connection to the remote server.
     ///
     /// This sender is typically used for aligning the mesh network during tests.
     pub(crate) async fn run_mesh_client(
@@ -532,11 +584,17 @@ impl Client {
                     Some(Ok(res)) => res,
                     Some(Err(e)) => {
                         warn!("recv error: {e:?}");
+                        if let Some(ref chan) = mesh_events {
+                            chan.send(MeshClientEvent::Disconnected).await.ok();
+                        }
                         tokio::time::sleep(MESH_CLIENT_REDIAL_DELAY).await;
                         break;
                     }
                     None => {
                         warn!("recv nothing");
+                        if let Some(ref chan) = mesh_events {
+                            chan.send(MeshClientEvent::Disconnected).await.ok();
+                        }
                         tokio::time::sleep(MESH_CLIENT_REDIAL_DELAY).await;
                         break;
                     }
@@ -592,13 +650,19 @@ impl Actor {
         msg_sender: mpsc::Sender<Result<(ReceivedMessage, usize), ClientError>>,
     ) {
         loop {
+            let reconnect_paused_until = self.reconnect_paused_until;
             tokio::select! {
-                res = self.recv_detail() => {
+                res = self.recv_detail(), if reconnect_paused_until.is_none() => {
                     msg_sender.send(res).await.ok();
                 }
+                _ = reconnect_pause_done(reconnect_paused_until) => {
+                    trace!("reconnect pause done");
+                    self.reconnect_paused_until = None;
+                }
                 Some(msg) = inbox.recv() => {
                     match msg {
                         ActorMessage::Connect(s) => {
+                            self.reconnect_paused_until = None;
                             let res = self.connect().await.map(|(client, _, count)| (client, count));
                             s.send(res).ok();
                         },
@@ -630,6 +694,11 @@ impl Actor {
                             let res = self.close_for_reconnect().await;
                             s.send(Ok(res)).ok();
                         },
+                        ActorMessage::CloseForReconnectAfter(delay, s) => {
+                            self.close_for_reconnect().await;
+                            self.reconnect_paused_until = Some(Instant::now() + delay);
+                            s.send(Ok(())).ok();
+                        },
                         ActorMessage::IsConnected(s) => {
                             let res = self.is_connected();
                             s.send(Ok(res)).ok();
@@ -659,6 +728,9 @@ impl Actor {
         if self.is_closed {
             return Err(ClientError::Closed);
         }
+        if self.derp_client.is_none() && self.reconnect_paused_until.is_some() {
+            return Err(ClientError::ReconnectPaused);
+        }
         async move {
             if self.derp_client.is_none() {
                 trace!("no connection, trying to connect");
@@ -700,10 +772,10 @@ impl Actor {
                 .ok_or_else(|| ClientError::InvalidUrl("No tls servername".into()))?;
             let tls_stream = self.tls_connector.connect(hostname, tcp_stream).await?;
             debug!("tls_connector connect success");
-            Self::start_upgrade(tls_stream).await?
+            Self::start_upgrade(tls_stream, self.auth_token.as_deref()).await?
         } else {
             debug!("Starting handshake");
-            Self::start_upgrade(tcp_stream).await?
+            Self::start_upgrade(tcp_stream, self.auth_token.as_deref()).await?
         };
 
         if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
@@ -729,6 +801,13 @@ impl Actor {
         debug!("connection upgraded");
         let (reader, writer) =
             downcast_upgrade(upgraded).map_err(|e| ClientError::Upgrade(e.to_string()))?;
+        let (reader, writer) = match self.frame_tap {
+            Some(ref tap) => (
+                Box::new(TappedIo::new(reader, tap.clone(), FrameDirection::Read)) as Box<_>,
+                Box::new(TappedIo::new(writer, tap.clone(), FrameDirection::Written)) as Box<_>,
+            ),
+            None => (reader, writer),
+        };
 
         let (derp_client, receiver) =
             DerpClientBuilder::new(self.secret_key.clone(), local_addr, reader, writer)
@@ -750,7 +829,10 @@ impl Actor {
     }
 
     /// Sends the HTTP upgrade request to the derper.
-    async fn start_upgrade<T>(io: T) -> Result<hyper::Response<Incoming>, hyper::Error>
+    async fn start_upgrade<T>(
+        io: T,
+        auth_token: Option<&str>,
+    ) -> Result<hyper::Response<Incoming>, hyper::Error>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
@@ -770,9 +852,16 @@ impl Actor {
             .instrument(info_span!("http-driver")),
         );
         debug!("Sending upgrade request");
-        let req = Request::builder()
+        let mut req = Request::builder()
             .uri("/derp")
-            .header(UPGRADE, super::HTTP_UPGRADE_PROTOCOL)
+            .header(UPGRADE, super::HTTP_UPGRADE_PROTOCOL);
+        if let Some(token) = auth_token {
+            req = req.header(
+                AUTHORIZATION,
+                format!("{} {token}", crate::derp::access::AUTH_SCHEME),
+            );
+        }
+        let req = req
             .body(http_body_util::Empty::<hyper::body::Bytes>::new())
             .unwrap();
         request_sender.send_request(req).await
@@ -1011,6 +1100,14 @@ impl Actor {
     }
 }
 
+/// Resolves once a reconnect pause is over, never if reconnecting is not paused.
+async fn reconnect_pause_done(paused_until: Option<Instant>) {
+    match paused_until {
+        Some(until) => tokio::time::sleep_until(until).await,
+        None => std::future::pending().await,
+    }
+}
+
 async fn resolve_host(url: &Url, prefer_ipv6: bool) -> Result<IpAddr, ClientError> {
     let host = url
         .host()
@@ -1222,6 +1319,47 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_close_for_reconnect_after() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let url: Url = format!("http://{}", server.addr()).parse().unwrap();
+        let (client, _client_receiver) = ClientBuilder::new(url).build(SecretKey::generate());
+        let (_, conn_gen) = client.connect().await?;
+
+        let delay = Duration::from_millis(500);
+        client.close_for_reconnect_after(delay).await?;
+        assert!(!client.is_connected().await?);
+
+        // neither receiving nor sending reconnects before the delay is over
+        tokio::time::sleep(delay / 2).await;
+        assert!(!client.is_connected().await?);
+        let res = client
+            .send(SecretKey::generate().public(), Bytes::from_static(b"hello"))
+            .await;
+        assert!(matches!(res, Err(ClientError::ReconnectPaused)));
+        assert!(!client.is_connected().await?);
+
+        // afterwards the client reconnects on its own
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.is_connected().await? {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        let (_, new_conn_gen) = client.connect().await?;
+        assert_eq!(new_conn_gen, conn_gen + 1);
+
+        client.close().await?;
+        server.shutdown().await;
+        Ok(())
+    }
+
     /// In the real world, when we create a local server, that server spawn a [`MeshClient`] for every remote server it was instructed to mesh with (we give it a list of derp servers to connect to via a config file). The connection with the local server and its [`MeshClient`] is managed by the [`PacketForwarderHandler`].
     ///
     /// The [`MeshClient`] receives a message from the remote server every time that server connects to a new iroh node. This message includes the [`PublicKey`] of the node it has just connected to.
@@ -1,29 +1,41 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
 use reqwest::Url;
-use tokio::task::JoinSet;
-use tokio_util::sync::CancellationToken;
+use tokio::sync::mpsc;
 use tracing::{info_span, Instrument};
 
 use crate::{
     derp::{http::ClientBuilder, DerpMap, MeshKey, PacketForwarderHandler},
-    key::SecretKey,
+    key::{PublicKey, SecretKey},
+    util::AbortingJoinHandle,
 };
 
 use super::{client::MeshClientEvent, Client};
 
+/// How long we may fail to mesh with a member before we consider it partitioned from us.
+pub const MESH_PARTITION_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Spawns, connects, and manages special [`crate::derp::http::Client`].
 ///
 /// These clients handled incoming network update notifications from remote
 /// [`super::Server`]s. These servers are used as [`crate::derp::PacketForwarder`]s for
 /// peers to which we are not directly connected.
 /// A [`crate::derp::MeshKey`] is used to ensure the remote server belongs to the same mesh network.
+///
+/// The members of the mesh can be changed at runtime using [`MeshClients::set_members`].
 #[derive(Debug)]
 pub(crate) struct MeshClients {
-    tasks: JoinSet<()>,
+    /// The tasks running a mesh client, per member
+    members: HashMap<Url, AbortingJoinHandle<()>>,
     mesh_key: MeshKey,
     server_key: SecretKey,
     mesh_addrs: MeshAddrs,
     packet_fwd: PacketForwarderHandler<Client>,
-    cancel: CancellationToken,
+    status: MeshStatus,
 }
 
 impl MeshClients {
@@ -34,19 +46,130 @@ impl MeshClients {
         packet_fwd: PacketForwarderHandler<Client>,
     ) -> Self {
         Self {
-            tasks: JoinSet::new(),
-            cancel: CancellationToken::new(),
+            members: HashMap::new(),
             mesh_key,
             server_key,
             mesh_addrs,
             packet_fwd,
+            status: MeshStatus::default(),
+        }
+    }
+
+    /// The [`MeshStatus`] of the members of this mesh.
+    pub(crate) fn status(&self) -> MeshStatus {
+        self.status.clone()
+    }
+
+    /// Starts meshing with all configured members.
+    ///
+    /// Returns a receiver of [`MeshClientEvent`]s for every member we were not yet meshing
+    /// with.
+    pub(crate) fn mesh(&mut self) -> anyhow::Result<Vec<mpsc::Receiver<MeshClientEvent>>> {
+        let mut meshed_once_recvs = Vec::new();
+        for addr in self.mesh_addrs.urls() {
+            if self.members.contains_key(&addr) {
+                continue;
+            }
+            let (sender, recv) = mpsc::channel(32);
+            self.add_member(addr, Some(sender));
+            meshed_once_recvs.push(recv);
+        }
+        Ok(meshed_once_recvs)
+    }
+
+    /// Replaces the members of the mesh.
+    ///
+    /// Starts meshing with new members and stops meshing with members that are no longer
+    /// listed, connections to members present before and after are kept. Packets for the
+    /// clients of a member we leave are no longer forwarded to it.
+    pub(crate) fn set_members(&mut self, mesh_addrs: MeshAddrs) {
+        let urls: HashSet<Url> = mesh_addrs.urls().into_iter().collect();
+        let mut orphaned = Vec::new();
+        self.members.retain(|url, _| {
+            let keep = urls.contains(url);
+            if !keep {
+                tracing::info!("leaving mesh member {url}");
+                orphaned.extend(self.status.remove(url));
+            }
+            keep
+        });
+        for peer in orphaned {
+            // the client may have moved on to a member we keep meshing with
+            if self.status.member_for(&peer).is_some() {
+                continue;
+            }
+            if let Err(err) = self.packet_fwd.remove_packet_forwarder(peer) {
+                tracing::warn!("unable to remove packet forwarder for {peer:?}: {err:?}");
+            }
+        }
+        for url in urls {
+            if !self.members.contains_key(&url) {
+                tracing::info!("joining mesh member {url}");
+                self.add_member(url, None);
+            }
         }
+        self.mesh_addrs = mesh_addrs;
     }
 
-    pub(crate) fn mesh(
-        &mut self,
-    ) -> anyhow::Result<Vec<tokio::sync::mpsc::Receiver<MeshClientEvent>>> {
-        let addrs = match &self.mesh_addrs {
+    fn add_member(&mut self, url: Url, events: Option<mpsc::Sender<MeshClientEvent>>) {
+        let (client, client_receiver) = ClientBuilder::new(url.clone())
+            .mesh_key(Some(self.mesh_key))
+            .build(self.server_key.clone());
+        let packet_forwarder_handler = self.packet_fwd.clone();
+        let status = self.status.clone();
+        status.connecting(&url);
+        let task_url = url.clone();
+        let task = tokio::spawn(
+            async move {
+                let (sender, mut recv) = mpsc::channel(32);
+                let run =
+                    client.run_mesh_client(packet_forwarder_handler, Some(sender), client_receiver);
+                tokio::pin!(run);
+                loop {
+                    tokio::select! {
+                        res = &mut run => {
+                            if let Err(e) = res {
+                                tracing::warn!("{e:?}");
+                            }
+                            break;
+                        }
+                        Some(event) = recv.recv() => {
+                            status.handle_event(&task_url, &event);
+                            if let Some(ref events) = events {
+                                events.send(event).await.ok();
+                            }
+                        }
+                    }
+                }
+                // e.g. we detected we are connected to ourselves, this is not a member to watch
+                status.remove(&task_url);
+            }
+            .instrument(info_span!("mesh-client", %url)),
+        );
+        self.members.insert(url, task.into());
+    }
+
+    pub(crate) async fn shutdown(mut self) {
+        for (_, task) in self.members.drain() {
+            task.0.abort();
+            task.await.ok();
+        }
+    }
+}
+
+/// The different ways to express the mesh network you want to join.
+#[derive(Debug, Clone)]
+pub enum MeshAddrs {
+    /// Supply a [`DerpMap`] of all the derp servers you want to mesh with.
+    DerpMap(DerpMap),
+    /// Supply a list of [`Url`]s of all the derp server you want to mesh with.
+    Addrs(Vec<Url>),
+}
+
+impl MeshAddrs {
+    /// The derp endpoint urls of all members.
+    fn urls(&self) -> Vec<Url> {
+        match self {
             MeshAddrs::Addrs(urls) => urls.to_owned(),
             MeshAddrs::DerpMap(derp_map) => {
                 let mut urls = Vec::new();
@@ -58,44 +181,174 @@ impl MeshClients {
                 }
                 urls
             }
+        }
+    }
+}
+
+/// Tracks the state of our connections to the other members of the mesh.
+///
+/// Cheaply cloneable.
+#[derive(Debug, Clone, Default)]
+pub struct MeshStatus {
+    members: Arc<Mutex<BTreeMap<Url, MemberState>>>,
+}
+
+#[derive(Debug)]
+struct MemberState {
+    meshed: bool,
+    since: Instant,
+    peers: HashSet<PublicKey>,
+}
+
+impl MeshStatus {
+    /// Returns a snapshot of the health of the mesh.
+    pub fn health(&self) -> MeshHealth {
+        let members = self
+            .members
+            .lock()
+            .iter()
+            .map(|(url, state)| MeshMemberStatus {
+                url: url.clone(),
+                meshed: state.meshed,
+                since: state.since,
+                peers: state.peers.len(),
+            })
+            .collect();
+        MeshHealth { members }
+    }
+
+    /// The mesh member the client `peer` is connected to, if any.
+    pub fn member_for(&self, peer: &PublicKey) -> Option<Url> {
+        self.members
+            .lock()
+            .iter()
+            .find(|(_, state)| state.peers.contains(peer))
+            .map(|(url, _)| url.clone())
+    }
+
+    fn connecting(&self, url: &Url) {
+        self.members.lock().insert(
+            url.clone(),
+            MemberState {
+                meshed: false,
+                since: Instant::now(),
+                peers: HashSet::new(),
+            },
+        );
+    }
+
+    fn handle_event(&self, url: &Url, event: &MeshClientEvent) {
+        let mut members = self.members.lock();
+        let Some(state) = members.get_mut(url) else {
+            return;
         };
-        let mut meshed_once_recvs = Vec::new();
-        for addr in addrs {
-            let (client, client_receiver) = ClientBuilder::new(addr)
-                .mesh_key(Some(self.mesh_key))
-                .build(self.server_key.clone());
-
-            let packet_forwarder_handler = self.packet_fwd.clone();
-            let (sender, recv) = tokio::sync::mpsc::channel(32);
-            self.tasks.spawn(
-                async move {
-                    if let Err(e) = client
-                        .run_mesh_client(packet_forwarder_handler, Some(sender), client_receiver)
-                        .await
-                    {
-                        tracing::warn!("{e:?}");
-                    }
+        match event {
+            MeshClientEvent::Meshed => {
+                if !state.meshed {
+                    state.meshed = true;
+                    state.since = Instant::now();
                 }
-                .instrument(info_span!("mesh-client")),
-            );
-            meshed_once_recvs.push(recv);
+            }
+            MeshClientEvent::PeerPresent { peer } => {
+                state.peers.insert(*peer);
+            }
+            MeshClientEvent::PeerGone { peer } => {
+                state.peers.remove(peer);
+            }
+            MeshClientEvent::Disconnected => {
+                if state.meshed {
+                    tracing::warn!("lost connection to mesh member {url}");
+                    state.meshed = false;
+                    state.since = Instant::now();
+                }
+                state.peers.clear();
+            }
         }
-        Ok(meshed_once_recvs)
     }
 
-    pub(crate) async fn shutdown(mut self) {
-        self.cancel.cancel();
-        self.tasks.shutdown().await
+    /// Stops tracking the member, returning the clients that were connected to it.
+    fn remove(&self, url: &Url) -> HashSet<PublicKey> {
+        self.members
+            .lock()
+            .remove(url)
+            .map(|state| state.peers)
+            .unwrap_or_default()
     }
 }
 
-/// The different ways to express the mesh network you want to join.
+/// The health of the mesh, as seen from one derp server.
 #[derive(Debug, Clone)]
-pub enum MeshAddrs {
-    /// Supply a [`DerpMap`] of all the derp servers you want to mesh with.
-    DerpMap(DerpMap),
-    /// Supply a list of [`Url`]s of all the derp server you want to mesh with.
-    Addrs(Vec<Url>),
+pub struct MeshHealth {
+    /// The status of every mesh member.
+    pub members: Vec<MeshMemberStatus>,
+}
+
+impl MeshHealth {
+    /// The members we are partitioned from.
+    pub fn partitioned(&self) -> impl Iterator<Item = &MeshMemberStatus> {
+        self.members.iter().filter(|m| m.is_partitioned())
+    }
+
+    /// Whether we are meshed with all members, or are still within the
+    /// [`MESH_PARTITION_TIMEOUT`] of (re)connecting to them.
+    pub fn is_healthy(&self) -> bool {
+        self.partitioned().next().is_none()
+    }
+}
+
+impl fmt::Display for MeshHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.is_healthy() {
+            "healthy"
+        } else {
+            "partitioned"
+        };
+        writeln!(f, "mesh: {state}")?;
+        for member in &self.members {
+            writeln!(f, "{member}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The status of our connection to a single mesh member.
+#[derive(Debug, Clone)]
+pub struct MeshMemberStatus {
+    /// The derp url of the member.
+    pub url: Url,
+    /// Whether we are currently meshed with the member.
+    pub meshed: bool,
+    /// When we last became meshed, or stopped being meshed.
+    pub since: Instant,
+    /// The number of clients connected to the member.
+    pub peers: usize,
+}
+
+impl MeshMemberStatus {
+    /// Whether we failed to mesh with this member for longer than the
+    /// [`MESH_PARTITION_TIMEOUT`].
+    pub fn is_partitioned(&self) -> bool {
+        !self.meshed && self.since.elapsed() > MESH_PARTITION_TIMEOUT
+    }
+}
+
+impl fmt::Display for MeshMemberStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.meshed {
+            "meshed"
+        } else if self.is_partitioned() {
+            "partitioned"
+        } else {
+            "connecting"
+        };
+        write!(
+            f,
+            "{} {state} for {}s, {} peers",
+            self.url,
+            self.since.elapsed().as_secs(),
+            self.peers
+        )
+    }
 }
 
 #[cfg(test)]
@@ -107,6 +360,110 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_mesh_status() {
+        let status = MeshStatus::default();
+        let url: Url = "https://derp.example.com/derp".parse().unwrap();
+        status.connecting(&url);
+        let health = status.health();
+        assert_eq!(health.members.len(), 1);
+        assert!(!health.members[0].meshed);
+        assert!(health.is_healthy());
+
+        let peer = SecretKey::generate().public();
+        status.handle_event(&url, &MeshClientEvent::Meshed);
+        status.handle_event(&url, &MeshClientEvent::PeerPresent { peer });
+        let health = status.health();
+        assert!(health.members[0].meshed);
+        assert_eq!(health.members[0].peers, 1);
+
+        // not meshed for too long
+        status.handle_event(&url, &MeshClientEvent::Disconnected);
+        status.members.lock().get_mut(&url).unwrap().since -= MESH_PARTITION_TIMEOUT * 2;
+        let health = status.health();
+        assert_eq!(health.members[0].peers, 0);
+        assert!(!health.is_healthy());
+        assert_eq!(health.partitioned().count(), 1);
+
+        status.remove(&url);
+        assert!(status.health().members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_mesh_members() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let mesh_key: MeshKey = [1; 32];
+        let mut derp_server_a = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .mesh_key(Some(mesh_key))
+            .mesh_derpers(Some(MeshAddrs::Addrs(Vec::new())))
+            .spawn()
+            .await?;
+        let derp_server_b = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .mesh_key(Some(mesh_key))
+            .spawn()
+            .await?;
+        let b_url: Url = format!("http://{}/derp", derp_server_b.addr()).parse()?;
+
+        let status = derp_server_a.mesh_status().expect("meshed server");
+        assert!(status.health().members.is_empty());
+
+        // join b
+        derp_server_a.set_mesh_members(MeshAddrs::Addrs(vec![b_url.clone()]))?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let health = status.health();
+                if health.members.iter().any(|m| m.url == b_url && m.meshed) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+
+        let health = status.health();
+        assert!(health.is_healthy());
+        assert!(health.to_string().contains(b_url.as_str()));
+
+        // a client of b is reachable through a
+        let client_key = SecretKey::generate();
+        let client_id = client_key.public();
+        let (client, _client_receiver) = ClientBuilder::new(b_url.clone()).build(client_key);
+        client.connect().await?;
+        let admin = derp_server_a.admin_handler().expect("derp enabled");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let clients = admin.clients().await.unwrap();
+                if clients.iter().any(|c| c.node_id == client_id) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+
+        // leave b again, its clients are no longer forwarded to
+        derp_server_a.set_mesh_members(MeshAddrs::Addrs(Vec::new()))?;
+        assert!(status.health().members.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let clients = admin.clients().await.unwrap();
+                if !clients.iter().any(|c| c.node_id == client_id) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        client.close().await?;
+
+        derp_server_a.shutdown().await;
+        derp_server_b.shutdown().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_mesh_network() -> Result<()> {
         let _guard = iroh_test::logging::setup();
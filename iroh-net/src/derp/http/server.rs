@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{bail, ensure, Context as _, Result};
 use bytes::Bytes;
@@ -9,7 +10,7 @@ use derive_more::Debug;
 use futures::future::{Future, FutureExt};
 use http::response::Builder as ResponseBuilder;
 use hyper::body::Incoming;
-use hyper::header::{HeaderValue, UPGRADE};
+use hyper::header::{HeaderValue, AUTHORIZATION, UPGRADE};
 use hyper::service::Service;
 use hyper::upgrade::Upgraded;
 use hyper::{HeaderMap, Method, Request, Response, StatusCode};
@@ -19,11 +20,12 @@ use tokio_rustls_acme::AcmeAcceptor;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
+use crate::derp::access::{parse_auth_header, AccessPolicy};
 use crate::derp::http::client::Client as HttpClient;
-use crate::derp::http::mesh_clients::{MeshAddrs, MeshClients};
+use crate::derp::http::mesh_clients::{MeshAddrs, MeshClients, MeshStatus};
 use crate::derp::http::HTTP_UPGRADE_PROTOCOL;
-use crate::derp::server::{ClientConnHandler, MaybeTlsStream};
-use crate::derp::types::{MeshKey, PacketForwarder};
+use crate::derp::server::{AdminHandler, ClientConnHandler, MaybeTlsStream};
+use crate::derp::types::{ClientRateLimit, MeshKey, PacketForwarder, RestartNotice};
 use crate::derp::MaybeTlsStreamServer;
 use crate::key::SecretKey;
 
@@ -61,6 +63,7 @@ fn downcast_upgrade(upgraded: Upgraded) -> Result<(MaybeTlsStream, Bytes)> {
 async fn derp_connection_handler<P>(
     conn_handler: &ClientConnHandler<P>,
     upgraded: Upgraded,
+    auth_token: Option<String>,
 ) -> Result<()>
 where
     P: PacketForwarder,
@@ -73,7 +76,7 @@ where
         read_buf
     );
 
-    conn_handler.accept(io).await
+    conn_handler.accept(io, auth_token).await
 }
 
 /// A Derp Server handler. Created using [`ServerBuilder::spawn`], it starts a derp server
@@ -90,8 +93,25 @@ pub struct Server {
 impl Server {
     /// Close the underlying derp server and the HTTP(S) server task
     pub async fn shutdown(self) {
+        self.shutdown_with(None).await
+    }
+
+    /// Announce the restart to all clients, then close the underlying derp server and the
+    /// HTTP(S) server task.
+    ///
+    /// See [`crate::derp::server::Server::close_for_restart`].
+    pub async fn shutdown_for_restart(self, notice: RestartNotice, drain_timeout: Duration) {
+        self.shutdown_with(Some((notice, drain_timeout))).await
+    }
+
+    async fn shutdown_with(self, restart: Option<(RestartNotice, Duration)>) {
         if let Some(server) = self.server {
-            server.close().await;
+            match restart {
+                Some((notice, drain_timeout)) => {
+                    server.close_for_restart(notice, drain_timeout).await
+                }
+                None => server.close().await,
+            }
         }
 
         if let Some(mesh_clients) = self.mesh_clients {
@@ -109,6 +129,30 @@ impl Server {
         self.addr
     }
 
+    /// An [`AdminHandler`] to inspect and manage the clients, unless derp is disabled.
+    pub fn admin_handler(&self) -> Option<AdminHandler<HttpClient>> {
+        self.server.as_ref().map(|server| server.admin_handler())
+    }
+
+    /// The [`MeshStatus`] of this server's mesh, if it is part of one.
+    pub fn mesh_status(&self) -> Option<MeshStatus> {
+        self.mesh_clients.as_ref().map(MeshClients::status)
+    }
+
+    /// Changes the derp servers this server meshes with.
+    ///
+    /// Connections to servers that remain members are kept. Errors if this server was not
+    /// configured to be part of a mesh.
+    pub fn set_mesh_members(&mut self, mesh_addrs: MeshAddrs) -> Result<()> {
+        let Some(mesh_clients) = self.mesh_clients.as_mut() else {
+            bail!(
+                "not part of a mesh, configure a mesh key and mesh derpers on the `ServerBuilder`"
+            );
+        };
+        mesh_clients.set_members(mesh_addrs);
+        Ok(())
+    }
+
     /// Mesh this server to a new list of derp servers.
     #[cfg(test)]
     pub(crate) async fn re_mesh(
@@ -178,6 +222,10 @@ pub struct ServerBuilder {
     ///
     /// When `None`, the server will serve HTTP, otherwise it will serve HTTPS.
     tls_config: Option<TlsConfig>,
+    /// Decides which clients may connect. Defaults to [`AccessPolicy::Everyone`].
+    access_policy: AccessPolicy,
+    /// Limits how many bytes each client may send. Defaults to no limit.
+    client_rate_limit: Option<ClientRateLimit>,
     /// A map of request handlers to routes. Used when certain routes in your server should be made
     /// available at the same port as the derp server, and so must be handled along side requests
     /// to the derp endpoint.
@@ -205,6 +253,8 @@ impl ServerBuilder {
             mesh_key: None,
             mesh_derpers: None,
             tls_config: None,
+            access_policy: AccessPolicy::Everyone,
+            client_rate_limit: None,
             handlers: Default::default(),
             derp_endpoint: "/derp",
             derp_override: None,
@@ -240,6 +290,23 @@ impl ServerBuilder {
         self
     }
 
+    /// The [`AccessPolicy`] deciding which clients may use this derp server.
+    ///
+    /// Clients present their access token in the `Authorization: Bearer <token>` header of
+    /// the upgrade request. Mesh peers are not subject to the policy.
+    pub fn access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.access_policy = policy;
+        self
+    }
+
+    /// The [`ClientRateLimit`] applied to every client of this derp server.
+    ///
+    /// Packets from clients exceeding the limit are dropped. Mesh peers are not limited.
+    pub fn client_rate_limit(mut self, limit: Option<ClientRateLimit>) -> Self {
+        self.client_rate_limit = limit;
+        self
+    }
+
     /// Add a custom handler for a specific Method & URI.
     pub fn request_handler(
         mut self,
@@ -280,7 +347,9 @@ impl ServerBuilder {
     pub async fn spawn(self) -> Result<Server> {
         ensure!(self.secret_key.is_some() || self.derp_override.is_some(), "Must provide a `SecretKey` for the derp server OR pass in an override function for the 'derp' endpoint");
         let (derp_handler, derp_server, mesh_clients) = if let Some(secret_key) = self.secret_key {
-            let server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            let mut server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            server.set_access_policy(self.access_policy);
+            server.set_client_rate_limit(self.client_rate_limit);
             let header_map: HeaderMap = HeaderMap::from_iter(
                 self.headers
                     .iter()
@@ -452,6 +521,15 @@ where
                     return Ok(res);
                 }
 
+                // The token is only checked against the access policy once the client has
+                // proven its identity in the derp handshake.
+                let auth_token = req
+                    .headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_auth_header)
+                    .map(ToOwned::to_owned);
+
                 // Setup a future that will eventually receive the upgraded
                 // connection and talk a new protocol, and spawn the future
                 // into the runtime.
@@ -463,8 +541,12 @@ where
                     async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                if let Err(e) =
-                                    derp_connection_handler(&closure_conn_handler, upgraded).await
+                                if let Err(e) = derp_connection_handler(
+                                    &closure_conn_handler,
+                                    upgraded,
+                                    auth_token,
+                                )
+                                .await
                                 {
                                     tracing::warn!(
                                         "upgrade to \"{HTTP_UPGRADE_PROTOCOL}\": io error: {:?}",
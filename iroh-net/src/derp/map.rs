@@ -76,6 +76,8 @@ impl DerpMap {
                 url,
                 stun_only: false,
                 stun_port,
+                stun_alt_port: None,
+                auth_token: None,
             }
             .into(),
         );
@@ -127,6 +129,18 @@ pub struct DerpNode {
     ///
     /// Setting this to `0` means the default STUN port is used.
     pub stun_port: u16,
+    /// A second STUN port used to classify the NAT behaviour.
+    ///
+    /// A server with this port replies to requests on it from [`DerpNode::stun_port`],
+    /// letting netcheck tell how the NAT maps and filters traffic.
+    #[serde(default)]
+    pub stun_alt_port: Option<u16>,
+    /// Access token presented when connecting to this derp server.
+    ///
+    /// Only needed for servers restricting access with a shared-secret token.
+    #[serde(default)]
+    #[debug("{}", auth_token.as_ref().map_or("None", |_| "Some(<redacted>)"))]
+    pub auth_token: Option<String>,
 }
 
 impl fmt::Display for DerpNode {
@@ -21,6 +21,11 @@ pub struct Metrics {
     pub send_packets_recv: Counter,
     /// `FrameType::SendPacket` dropped, that are not disco messages
     pub send_packets_dropped: Counter,
+    /// Bytes of `FrameType::SendPacket` dropped because the sender exceeded its rate limit
+    pub bytes_dropped_rate_limit: Counter,
+    /// Bytes of `FrameType::SendPacket` dropped because the sender exceeded its fair share of
+    /// the receiver's send queue
+    pub bytes_dropped_fair_share: Counter,
 
     /// `FrameType::SendPacket` sent that are disco messages
     pub disco_packets_sent: Counter,
@@ -58,6 +63,8 @@ pub struct Metrics {
 
     /// Number of connections we have accepted
     pub accepts: Counter,
+    /// Number of connections rejected by the access policy, bans or a restart
+    pub rejected_accepts: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
     // TODO: enable when we can have multiple connections for one node id
@@ -78,6 +85,12 @@ impl Default for Metrics {
             send_packets_recv: Counter::new("Number of 'send' packets received."),
             bytes_recv: Counter::new("Number of bytes received."),
             send_packets_dropped: Counter::new("Number of 'send' packets dropped."),
+            bytes_dropped_rate_limit: Counter::new(
+                "Number of bytes dropped because a client exceeded its rate limit.",
+            ),
+            bytes_dropped_fair_share: Counter::new(
+                "Number of bytes dropped because a sender exceeded its share of a client's queue.",
+            ),
             disco_packets_sent: Counter::new("Number of disco packets sent."),
             disco_packets_recv: Counter::new("Number of disco packets received."),
             disco_packets_dropped: Counter::new("Number of disco packets dropped."),
@@ -114,6 +127,9 @@ impl Default for Metrics {
             ),
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
+            rejected_accepts: Counter::new(
+                "Number of connections rejected by the access policy, bans or a restart.",
+            ),
             disconnects: Counter::new("Number of clients that have then disconnected."),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of dupliate client keys."),
@@ -11,8 +11,9 @@ use futures::SinkExt;
 use hyper::HeaderMap;
 use iroh_metrics::core::UsageStatsReport;
 use iroh_metrics::{inc, report_usage_stats};
+use parking_lot::RwLock;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
 use tokio_util::sync::CancellationToken;
@@ -21,6 +22,7 @@ use tracing::{info_span, trace, Instrument};
 use crate::key::{PublicKey, SecretKey, SharedSecret};
 
 use super::{
+    access::AccessPolicy,
     client_conn::ClientConnBuilder,
     clients::Clients,
     codec::{
@@ -28,7 +30,7 @@ use super::{
         PROTOCOL_VERSION, SERVER_CHANNEL_SIZE,
     },
     metrics::Metrics,
-    types::ServerInfo,
+    types::{ClientOrigin, ClientRateLimit, ConnectedClient, RestartNotice, ServerInfo},
     types::{PacketForwarder, PeerConnState, ServerMessage},
     MeshKey,
 };
@@ -42,6 +44,9 @@ fn new_conn_num() -> usize {
 
 pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// Clients banned from a [`Server`], shared by the [`ClientConnHandler`]s and the [`ServerActor`].
+type Bans = Arc<RwLock<HashSet<PublicKey>>>;
+
 /// A DERP server.
 ///
 /// Responsible for managing connections to derp [`super::client::Client`]s, sending/forwarding packets
@@ -77,6 +82,12 @@ where
     loop_handler: JoinHandle<Result<()>>,
     /// Done token, forces a hard shutdown. To gracefully shutdown, use [`Server::close`]
     cancel: CancellationToken,
+    /// Decides which clients are allowed to connect.
+    access_policy: Arc<AccessPolicy>,
+    /// Limits how many bytes each client may send through the server.
+    client_rate_limit: Option<ClientRateLimit>,
+    /// Clients banned by the [`AdminHandler`], shared with the [`ServerActor`].
+    banned: Bans,
     // TODO: stats collection
     // Counters:
     // 	packetsSent, bytesSent       expvar.Int
@@ -120,7 +131,8 @@ where
     /// TODO: replace with builder
     pub fn new(key: SecretKey, mesh_key: Option<MeshKey>) -> Self {
         let (server_channel_s, server_channel_r) = mpsc::channel(SERVER_CHANNEL_SIZE);
-        let server_actor = ServerActor::new(key.public(), server_channel_r);
+        let banned = Bans::default();
+        let server_actor = ServerActor::new(key.public(), server_channel_r, banned.clone());
         let cancel_token = CancellationToken::new();
         let done = cancel_token.clone();
         let server_task = tokio::spawn(
@@ -139,9 +151,31 @@ where
             server_info: ServerInfo::no_rate_limit(),
             loop_handler: server_task,
             cancel: cancel_token,
+            access_policy: Default::default(),
+            client_rate_limit: None,
+            banned,
         }
     }
 
+    /// Sets the [`AccessPolicy`] deciding which clients may connect.
+    ///
+    /// Only affects [`ClientConnHandler`]s created afterwards.
+    pub fn set_access_policy(&mut self, policy: AccessPolicy) {
+        self.access_policy = Arc::new(policy);
+    }
+
+    /// Sets the [`ClientRateLimit`] applied to each client, `None` disables rate limiting.
+    ///
+    /// The limit is announced to clients in the server info. Only affects
+    /// [`ClientConnHandler`]s created afterwards.
+    pub fn set_client_rate_limit(&mut self, limit: Option<ClientRateLimit>) {
+        self.server_info = match limit {
+            Some(limit) => ServerInfo::with_rate_limit(limit),
+            None => ServerInfo::no_rate_limit(),
+        };
+        self.client_rate_limit = limit;
+    }
+
     /// Reports whether the server is configured with a mesh key.
     pub fn has_mesh_key(&self) -> bool {
         self.mesh_key.is_some()
@@ -163,9 +197,26 @@ where
     }
 
     /// Closes the server and waits for the connections to disconnect.
-    pub async fn close(mut self) {
+    pub async fn close(self) {
+        self.close_with(ServerMessage::Shutdown).await
+    }
+
+    /// Announces the restart to all clients, then closes the server.
+    ///
+    /// Clients are sent the [`RestartNotice`], the server keeps relaying their packets until
+    /// they have all disconnected or `drain_timeout` elapsed. New clients are refused in the
+    /// meantime.
+    pub async fn close_for_restart(self, notice: RestartNotice, drain_timeout: Duration) {
+        self.close_with(ServerMessage::Restart {
+            notice,
+            drain_timeout,
+        })
+        .await
+    }
+
+    async fn close_with(mut self, msg: ServerMessage<P>) {
         if !self.closed {
-            if let Err(err) = self.server_channel.send(ServerMessage::Shutdown).await {
+            if let Err(err) = self.server_channel.send(msg).await {
                 tracing::warn!(
                     "could not shutdown the server gracefully, doing a forced shutdown: {:?}",
                     err
@@ -202,6 +253,16 @@ where
             write_timeout: self.write_timeout,
             server_info: self.server_info.clone(),
             default_headers: Arc::new(default_headers),
+            access_policy: Arc::clone(&self.access_policy),
+            client_rate_limit: self.client_rate_limit,
+            banned: self.banned.clone(),
+        }
+    }
+
+    /// Create an [`AdminHandler`], which can inspect and manage the clients of the [`Server`].
+    pub fn admin_handler(&self) -> AdminHandler<P> {
+        AdminHandler {
+            server_channel: self.server_channel.clone(),
         }
     }
 
@@ -265,6 +326,69 @@ where
     }
 }
 
+/// Inspects and manages the clients of a [`Server`].
+///
+/// Created by the [`Server`] by calling [`Server::admin_handler`].
+///
+/// Can be cheaply cloned.
+#[derive(Debug, Clone)]
+pub struct AdminHandler<P>
+where
+    P: PacketForwarder,
+{
+    server_channel: mpsc::Sender<ServerMessage<P>>,
+}
+
+impl<P> AdminHandler<P>
+where
+    P: PacketForwarder,
+{
+    /// Lists the clients connected to the server, and those reachable through the mesh.
+    pub async fn clients(&self) -> Result<Vec<ConnectedClient>> {
+        let (s, r) = oneshot::channel();
+        self.send(ServerMessage::ListClients(s)).await?;
+        r.await.context("server gone")
+    }
+
+    /// Closes the connection of the client, it is free to reconnect.
+    ///
+    /// The client is removed from the server like on a regular disconnect: its peers and the
+    /// mesh are told it is gone. Does nothing for clients reached through the mesh.
+    pub async fn disconnect(&self, key: PublicKey) -> Result<()> {
+        self.send(ServerMessage::ClosePeer(key)).await
+    }
+
+    /// Closes the connection of the client and refuses its connections until
+    /// [`AdminHandler::unban`]ned.
+    ///
+    /// Banned clients are rejected during the handshake. Packets from and to a banned client
+    /// are dropped, which also cuts off clients reached through the mesh.
+    ///
+    /// Bans are not persisted, they are lost when the server restarts.
+    pub async fn ban(&self, key: PublicKey) -> Result<()> {
+        self.send(ServerMessage::Ban(key)).await
+    }
+
+    /// Accepts connections from the client again.
+    pub async fn unban(&self, key: PublicKey) -> Result<()> {
+        self.send(ServerMessage::Unban(key)).await
+    }
+
+    /// Lists the banned clients.
+    pub async fn banned(&self) -> Result<Vec<PublicKey>> {
+        let (s, r) = oneshot::channel();
+        self.send(ServerMessage::ListBanned(s)).await?;
+        r.await.context("server gone")
+    }
+
+    async fn send(&self, msg: ServerMessage<P>) -> Result<()> {
+        self.server_channel
+            .send(msg)
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))
+    }
+}
+
 /// Handle incoming connections to the Server.
 ///
 /// Created by the [`Server`] by calling [`Server::client_conn_handler`].
@@ -281,6 +405,9 @@ where
     write_timeout: Option<Duration>,
     server_info: ServerInfo,
     pub(super) default_headers: Arc<HeaderMap>,
+    access_policy: Arc<AccessPolicy>,
+    client_rate_limit: Option<ClientRateLimit>,
+    banned: Bans,
 }
 
 impl<P> Clone for ClientConnHandler<P>
@@ -295,6 +422,9 @@ where
             write_timeout: self.write_timeout,
             server_info: self.server_info.clone(),
             default_headers: Arc::clone(&self.default_headers),
+            access_policy: Arc::clone(&self.access_policy),
+            client_rate_limit: self.client_rate_limit,
+            banned: self.banned.clone(),
         }
     }
 }
@@ -309,8 +439,12 @@ where
     /// some read or write error to the connection,  if the server is meant to verify clients,
     /// and is unable to verify this one, or if there is some issue communicating with the server.
     ///
+    /// The `auth_token` is the access token the client presented, if any. Banned clients are
+    /// rejected, clients that are not part of our mesh are checked against the server's
+    /// [`AccessPolicy`] and the connection is closed if they are rejected.
+    ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
-    pub async fn accept(&self, io: MaybeTlsStream) -> Result<()> {
+    pub async fn accept(&self, io: MaybeTlsStream, auth_token: Option<String>) -> Result<()> {
         let mut io = Framed::new(io, DerpCodec);
         trace!("accept: start");
         self.send_server_key(&mut io)
@@ -321,8 +455,33 @@ where
             recv_client_key(self.secret_key.clone(), &mut io)
                 .await
                 .context("unable to receive client information")?;
+        if self.banned.read().contains(&client_key) {
+            inc!(Metrics, rejected_accepts);
+            anyhow::bail!("client {client_key:?} is banned");
+        }
+        let can_mesh = self.can_mesh(client_info.mesh_key);
+        if !can_mesh {
+            trace!("accept: check access policy");
+            let allowed = match self
+                .access_policy
+                .is_allowed(client_key, auth_token.as_deref())
+                .await
+            {
+                Ok(allowed) => allowed,
+                Err(err) => {
+                    tracing::warn!("access check for client {client_key:?} failed: {err:?}");
+                    false
+                }
+            };
+            if !allowed {
+                inc!(Metrics, rejected_accepts);
+                anyhow::bail!("client {client_key:?} rejected by the access policy");
+            }
+        }
+        // talk the older protocol version of the two, old clients insist on their own
+        let version = client_info.version.min(PROTOCOL_VERSION);
         trace!("accept: send server info");
-        self.send_server_info(&mut io, &shared_secret)
+        self.send_server_info(&mut io, &shared_secret, version)
             .await
             .context("unable to sent server info to client {client_key}")?;
         trace!("accept: build client conn");
@@ -330,9 +489,11 @@ where
             key: client_key,
             conn_num: new_conn_num(),
             io,
-            can_mesh: self.can_mesh(client_info.mesh_key),
+            can_mesh,
+            version,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
+            rate_limit: self.client_rate_limit,
             server_channel: self.server_channel.clone(),
         };
         trace!("accept: create client");
@@ -365,11 +526,16 @@ where
         &self,
         mut writer: &mut Framed<T, DerpCodec>,
         shared_secret: &SharedSecret,
+        version: usize,
     ) -> Result<()>
     where
         T: AsyncWrite + Unpin,
     {
-        let mut msg = postcard::to_stdvec(&self.server_info)?;
+        let server_info = ServerInfo {
+            version,
+            ..self.server_info.clone()
+        };
+        let mut msg = postcard::to_stdvec(&server_info)?;
         shared_secret.seal(&mut msg);
         write_frame(
             &mut writer,
@@ -405,19 +571,30 @@ where
     client_mesh: HashMap<PublicKey, Option<P>>,
     /// Mesh clients that need to be appraised on the state of the network
     watchers: HashSet<PublicKey>,
+    /// When restarting, the time at which we stop waiting for clients to leave, no new
+    /// clients are accepted until then
+    drain_until: Option<tokio::time::Instant>,
+    /// Clients whose connections are refused and whose packets are dropped
+    banned: Bans,
 }
 
 impl<P> ServerActor<P>
 where
     P: PacketForwarder,
 {
-    pub(crate) fn new(key: PublicKey, receiver: mpsc::Receiver<ServerMessage<P>>) -> Self {
+    pub(crate) fn new(
+        key: PublicKey,
+        receiver: mpsc::Receiver<ServerMessage<P>>,
+        banned: Bans,
+    ) -> Self {
         Self {
             key,
             receiver,
             clients: Clients::new(),
             client_mesh: HashMap::default(),
             watchers: HashSet::default(),
+            drain_until: None,
+            banned,
         }
     }
 
@@ -432,6 +609,11 @@ where
                     self.clients.shutdown().await;
                     return Ok(());
                 }
+                _ = tokio::time::sleep_until(self.drain_until.unwrap_or_else(tokio::time::Instant::now)), if self.drain_until.is_some() => {
+                    tracing::info!("restart drain timeout elapsed, closing remaining client connections");
+                    self.clients.shutdown().await;
+                    return Ok(());
+                }
                 msg = self.receiver.recv() => {
                     let msg = match msg {
                         Some(m) => m,
@@ -458,14 +640,15 @@ where
                        },
                        ServerMessage::ClosePeer(key) => {
                            tracing::trace!("close peer: {:?}", key);
-                           // close the actual underlying connection to the client, but don't remove it from
-                           // the list of clients
-                           self.clients.close_conn(&key);
+                           self.close_client(key);
                        },
                         ServerMessage::SendPacket((key, packet)) => {
                            tracing::trace!("send packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src;
-                            if self.clients.contains_key(&key) {
+                            if self.is_banned(&src, &key) {
+                                tracing::debug!("send packet: {src:?} or {key:?} is banned, dropped packet");
+                                inc!(Metrics, send_packets_dropped);
+                            } else if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_packet(&key, packet).is_ok() {
@@ -483,7 +666,10 @@ where
                        ServerMessage::SendDiscoPacket((key, packet)) => {
                            tracing::trace!("send disco packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src;
-                            if self.clients.contains_key(&key) {
+                            if self.is_banned(&src, &key) {
+                                tracing::debug!("send disco packet: {src:?} or {key:?} is banned, dropped packet");
+                                inc!(Metrics, disco_packets_dropped);
+                            } else if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_disco_packet(&key, packet).is_ok() {
@@ -504,6 +690,19 @@ where
 
                            tracing::trace!("create client: {:?}", client_builder.key);
                            let key = client_builder.key;
+                           if self.banned.read().contains(&key) {
+                               // banned while in the handshake, dropping the builder closes the
+                               // connection
+                               tracing::info!("refusing banned client {key:?}");
+                               inc!(Metrics, rejected_accepts);
+                               continue;
+                           }
+                           if self.drain_until.is_some() {
+                               // we are about to go away, the client should try again later
+                               tracing::info!("refusing client {key:?} while restarting");
+                               inc!(Metrics, rejected_accepts);
+                               continue;
+                           }
 
                            report_usage_stats(&UsageStatsReport::new(
                                 "derp_accepts".to_string(),
@@ -521,7 +720,6 @@ where
                            self.clients.register(client_builder);
                            // broadcast to watchers that a new peer has joined the network
                            self.broadcast_peer_state_change(key, true);
-
                        }
                        ServerMessage::RemoveClient((key, conn_num)) => {
                            inc!(Metrics, disconnects);
@@ -536,6 +734,11 @@ where
                                // broadcast to watchers that this peer has left the network
                                self.broadcast_peer_state_change(key, false);
                             }
+                           if self.is_drained() {
+                               tracing::info!("all clients left, finishing restart");
+                               self.clients.shutdown().await;
+                               return Ok(());
+                           }
                        }
                        ServerMessage::AddPacketForwarder { key, forwarder } => {
                            tracing::trace!("add packet forwarder: {:?}", key);
@@ -556,6 +759,53 @@ where
                            }
                            inc!(Metrics, removed_pkt_fwder);
                        },
+                       ServerMessage::Restart { notice, drain_timeout } => {
+                           tracing::info!("server restarting, draining clients for up to {drain_timeout:?}");
+                           // mesh peers keep their connection until we close it
+                           let keys: Vec<_> = self
+                               .clients
+                               .all_clients()
+                               .filter(|key| !self.watchers.contains(*key))
+                               .copied()
+                               .collect();
+                           for key in keys {
+                               self.clients.send_restarting(&key, &notice);
+                           }
+                           self.drain_until = Some(tokio::time::Instant::now() + drain_timeout);
+                           if self.is_drained() {
+                               self.clients.shutdown().await;
+                               return Ok(());
+                           }
+                       }
+                       ServerMessage::ListClients(reply) => {
+                           let mut clients: Vec<_> = self.clients.connected().collect();
+                           let mesh = self
+                               .client_mesh
+                               .iter()
+                               .filter(|(key, fwd)| fwd.is_some() && !self.clients.contains_key(key))
+                               .map(|(key, _)| ConnectedClient {
+                                   node_id: *key,
+                                   origin: ClientOrigin::Mesh,
+                                   connected_for: None,
+                                   bytes_recv: 0,
+                                   bytes_sent: 0,
+                                   preferred: false,
+                               });
+                           clients.extend(mesh);
+                           reply.send(clients).ok();
+                       }
+                       ServerMessage::Ban(key) => {
+                           tracing::info!("ban client: {:?}", key);
+                           self.banned.write().insert(key);
+                           self.close_client(key);
+                       }
+                       ServerMessage::Unban(key) => {
+                           tracing::info!("unban client: {:?}", key);
+                           self.banned.write().remove(&key);
+                       }
+                       ServerMessage::ListBanned(reply) => {
+                           reply.send(self.banned.read().iter().copied().collect()).ok();
+                       }
                        ServerMessage::Shutdown => {
                         tracing::info!("server gracefully shutting down...");
                         // close all client connections and client read/write loops
@@ -568,6 +818,31 @@ where
         }
     }
 
+    /// Whether the sender or the receiver of a packet is banned.
+    fn is_banned(&self, src: &PublicKey, dst: &PublicKey) -> bool {
+        let banned = self.banned.read();
+        banned.contains(src) || banned.contains(dst)
+    }
+
+    /// Closes the connection to the client, telling its peers and the watchers it is gone.
+    fn close_client(&mut self, key: PublicKey) {
+        if !self.clients.contains_key(&key) {
+            return;
+        }
+        self.clients.unregister(&key);
+        self.client_mesh.remove(&key);
+        self.broadcast_peer_state_change(key, false);
+    }
+
+    /// Whether we are restarting and only mesh peers are left connected.
+    fn is_drained(&self) -> bool {
+        self.drain_until.is_some()
+            && self
+                .clients
+                .all_clients()
+                .all(|key| self.watchers.contains(key))
+    }
+
     pub(crate) fn broadcast_peer_state_change(&mut self, peer: PublicKey, present: bool) {
         let keys = self.watchers.iter();
         self.clients
@@ -694,7 +969,7 @@ mod tests {
     use crate::derp::{
         client::ClientBuilder,
         client_conn::ClientConnBuilder,
-        codec::{recv_frame, DerpCodec, FrameType},
+        codec::{recv_frame, DerpCodec, FrameType, MIN_PROTOCOL_VERSION},
         types::ClientInfo,
         ReceivedMessage,
     };
@@ -737,8 +1012,10 @@ mod tests {
                 conn_num,
                 io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
                 can_mesh: true,
+                version: PROTOCOL_VERSION,
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limit: None,
                 server_channel,
             },
             Framed::new(test_io, DerpCodec),
@@ -752,7 +1029,7 @@ mod tests {
         // make server actor
         let (server_channel, server_channel_r) = mpsc::channel(20);
         let server_actor: ServerActor<MockPacketForwarder> =
-            ServerActor::new(server_key, server_channel_r);
+            ServerActor::new(server_key, server_channel_r, Default::default());
         let done = CancellationToken::new();
         let server_done = done.clone();
 
@@ -910,6 +1187,9 @@ mod tests {
             server_info: ServerInfo::no_rate_limit(),
             server_channel: server_channel_s,
             default_headers: Default::default(),
+            access_policy: Default::default(),
+            client_rate_limit: None,
+            banned: Default::default(),
         };
 
         // create the parts needed for a client
@@ -926,9 +1206,9 @@ mod tests {
             let got_server_key = crate::derp::client::recv_server_key(&mut client_reader).await?;
             assert_eq!(expect_server_key, got_server_key);
 
-            // send the client info
+            // send the client info of an older client
             let client_info = ClientInfo {
-                version: PROTOCOL_VERSION,
+                version: MIN_PROTOCOL_VERSION,
                 mesh_key: Some([1u8; 32]),
                 can_ack_pings: true,
                 is_prober: true,
@@ -950,18 +1230,23 @@ mod tests {
             };
             let mut buf = encrypted_message.to_vec();
             shared_secret.open(&mut buf)?;
-            let _info: ServerInfo = postcard::from_bytes(&buf)?;
+            let info: ServerInfo = postcard::from_bytes(&buf)?;
+            // the server talks the older version
+            assert_eq!(info.version, MIN_PROTOCOL_VERSION);
             Ok(())
         });
 
         // attempt to add the connection to the server
-        handler.accept(MaybeTlsStream::Test(server_io)).await?;
+        handler
+            .accept(MaybeTlsStream::Test(server_io), None)
+            .await?;
         client_task.await??;
 
         // ensure we inform the server to create the client from the connection!
         match server_channel_r.recv().await.unwrap() {
             ServerMessage::CreateClient(builder) => {
                 assert_eq!(pub_client_key, builder.key);
+                assert_eq!(MIN_PROTOCOL_VERSION, builder.version);
             }
             _ => anyhow::bail!("unexpected server message"),
         }
@@ -997,7 +1282,7 @@ mod tests {
         let (rw_a, client_a_builder) = make_test_client(key_a);
         let handler = server.client_conn_handler(Default::default());
         let handler_task =
-            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a), None).await });
         let (client_a, mut client_receiver_a) = client_a_builder.build().await?;
         handler_task.await??;
 
@@ -1007,7 +1292,7 @@ mod tests {
         let (rw_b, client_b_builder) = make_test_client(key_b);
         let handler = server.client_conn_handler(Default::default());
         let handler_task =
-            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b), None).await });
         let (client_b, mut client_receiver_b) = client_b_builder.build().await?;
         handler_task.await??;
 
@@ -1071,6 +1356,132 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_access_policy() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let key_allowed = SecretKey::generate();
+        let key_denied = SecretKey::generate();
+        let mut server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        server.set_access_policy(AccessPolicy::allow_list([key_allowed.public()]));
+
+        // an allow-listed client connects
+        let (rw, client_builder) = make_test_client(key_allowed);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let (_client, _client_receiver) = client_builder.build().await?;
+        handler_task.await??;
+
+        // any other client is rejected before receiving the server info
+        let (rw, client_builder) = make_test_client(key_denied);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let client_res = client_builder.build().await;
+        assert!(handler_task.await?.is_err());
+        assert!(client_res.is_err());
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_ban() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        let admin = server.admin_handler();
+        let key_banned = SecretKey::generate();
+        admin.ban(key_banned.public()).await?;
+
+        // the banned client is rejected before receiving the server info
+        let (rw, client_builder) = make_test_client(key_banned);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let client_res = client_builder.build().await;
+        assert!(handler_task.await?.is_err());
+        assert!(client_res.is_err());
+
+        // a client reached through the mesh is cut off once banned
+        let (rw, client_builder) = make_test_client(SecretKey::generate());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let (client, _client_receiver) = client_builder.build().await?;
+        handler_task.await??;
+
+        let key_mesh = SecretKey::generate().public();
+        let (mut forwarded, forwarder) = MockPacketForwarder::new();
+        server
+            .packet_forwarder_handler()
+            .add_packet_forwarder(key_mesh, forwarder)?;
+        client.send(key_mesh, Bytes::from_static(b"hello")).await?;
+        let (_, dst, packet) = forwarded.recv().await.context("forwarder gone")?;
+        assert_eq!((dst, packet), (key_mesh, Bytes::from_static(b"hello")));
+
+        admin.ban(key_mesh).await?;
+        client.send(key_mesh, Bytes::from_static(b"hello")).await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), forwarded.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(admin.banned().await?.len(), 2);
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_close_for_restart() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        let (rw, client_builder) = make_test_client(SecretKey::generate());
+        let handler = server.client_conn_handler(Default::default());
+        let late_handler = handler.clone();
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let (client, mut client_receiver) = client_builder.build().await?;
+        handler_task.await??;
+
+        let redirect: url::Url = "https://other-derp.example.com".parse()?;
+        let notice = RestartNotice {
+            reconnect_in: Duration::from_millis(100),
+            try_for: Duration::from_secs(2),
+            redirect: Some(redirect.clone()),
+        };
+        let close_task = tokio::spawn(server.close_for_restart(notice, Duration::from_secs(10)));
+
+        match client_receiver.recv().await? {
+            ReceivedMessage::ServerRestarting {
+                reconnect_in,
+                try_for,
+                redirect: got_redirect,
+            } => {
+                assert!(reconnect_in <= Duration::from_millis(100));
+                assert_eq!(try_for, Duration::from_secs(2));
+                assert_eq!(got_redirect, Some(redirect));
+            }
+            msg => anyhow::bail!("expected ServerRestarting, got {msg:?}"),
+        }
+
+        // clients connecting while the server drains are refused
+        let (rw, late_builder) = make_test_client(SecretKey::generate());
+        let late_task =
+            tokio::spawn(async move { late_handler.accept(MaybeTlsStream::Test(rw), None).await });
+        let (_late_client, mut late_receiver) = late_builder.build().await?;
+        late_task.await??;
+        assert!(late_receiver.recv().await.is_err());
+
+        // the server finishes as soon as the client leaves, well before the drain timeout
+        client.close().await;
+        tokio::time::timeout(Duration::from_secs(5), close_task).await??;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_server_replace_client() -> Result<()> {
         tracing_subscriber::registry()
@@ -1090,7 +1501,7 @@ mod tests {
         let (rw_a, client_a_builder) = make_test_client(key_a);
         let handler = server.client_conn_handler(Default::default());
         let handler_task =
-            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a), None).await });
         let (client_a, mut client_receiver_a) = client_a_builder.build().await?;
         handler_task.await??;
 
@@ -1100,7 +1511,7 @@ mod tests {
         let (rw_b, client_b_builder) = make_test_client(key_b.clone());
         let handler = server.client_conn_handler(Default::default());
         let handler_task =
-            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b), None).await });
         let (client_b, mut client_receiver_b) = client_b_builder.build().await?;
         handler_task.await??;
 
@@ -1134,7 +1545,7 @@ mod tests {
         let (new_rw_b, new_client_b_builder) = make_test_client(key_b);
         let handler = server.client_conn_handler(Default::default());
         let handler_task =
-            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(new_rw_b)).await });
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(new_rw_b), None).await });
         let (new_client_b, mut new_client_receiver_b) = new_client_b_builder.build().await?;
         handler_task.await??;
 
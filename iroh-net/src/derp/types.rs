@@ -1,12 +1,19 @@
 use std::num::NonZeroU32;
+use std::time::Duration;
 
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
 use postcard::experimental::max_size::MaxSize;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use url::Url;
 
-use super::{client_conn::ClientConnBuilder, codec::PROTOCOL_VERSION};
-use crate::key::PublicKey;
+use super::{
+    client_conn::ClientConnBuilder,
+    codec::{MAX_PACKET_SIZE, PROTOCOL_VERSION},
+};
+use crate::key::{PublicKey, PUBLIC_KEY_LENGTH};
 
 /// A key to identify if a node belongs in a mesh
 pub type MeshKey = [u8; 32];
@@ -20,6 +27,12 @@ pub(crate) struct RateLimiter {
     >,
 }
 
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter").finish_non_exhaustive()
+    }
+}
+
 impl RateLimiter {
     pub(crate) fn new(bytes_per_second: usize, bytes_burst: usize) -> Result<Option<Self>> {
         if bytes_per_second == 0 || bytes_burst == 0 {
@@ -34,16 +47,120 @@ impl RateLimiter {
         }))
     }
 
+    pub(crate) fn from_limit(limit: ClientRateLimit) -> Self {
+        Self {
+            inner: governor::RateLimiter::direct(
+                governor::Quota::per_second(limit.bytes_per_second).allow_burst(limit.bytes_burst),
+            ),
+        }
+    }
+
     pub(crate) fn check_n(&self, n: usize) -> Result<()> {
         ensure!(n != 0);
         let n = NonZeroU32::new(u32::try_from(n)?).unwrap();
         match self.inner.check_n(n) {
-            Ok(_) => Ok(()),
-            Err(_) => bail!("batch cannot go through"),
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) | Err(_) => bail!("batch cannot go through"),
         }
     }
 }
 
+/// Limits how many bytes a single client may send through a derp [`super::Server`].
+///
+/// The limit is announced to clients in the server info, so well-behaved clients throttle
+/// themselves. Packets from clients exceeding it are dropped by the server. Mesh peers are not
+/// limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientRateLimit {
+    bytes_per_second: NonZeroU32,
+    bytes_burst: NonZeroU32,
+}
+
+impl ClientRateLimit {
+    /// Creates a new rate limit of `bytes_per_second`, allowing bursts of `bytes_burst`.
+    ///
+    /// The burst must be large enough to fit the largest frame a client can send.
+    pub fn new(bytes_per_second: u32, bytes_burst: u32) -> Result<Self> {
+        let min_burst = MAX_PACKET_SIZE + PUBLIC_KEY_LENGTH;
+        ensure!(
+            bytes_burst as usize >= min_burst,
+            "burst of {bytes_burst} bytes is too small, must be at least {min_burst} bytes"
+        );
+        let bytes_per_second =
+            NonZeroU32::new(bytes_per_second).context("bytes per second must not be zero")?;
+        let bytes_burst = NonZeroU32::new(bytes_burst).expect("checked");
+        Ok(Self {
+            bytes_per_second,
+            bytes_burst,
+        })
+    }
+
+    /// The sustained rate in bytes per second.
+    pub fn bytes_per_second(&self) -> u32 {
+        self.bytes_per_second.get()
+    }
+
+    /// The maximum burst size in bytes.
+    pub fn bytes_burst(&self) -> u32 {
+        self.bytes_burst.get()
+    }
+}
+
+/// Announcement sent to the clients of a derp [`super::Server`] that is about to restart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestartNotice {
+    /// How long clients wait before reconnecting, at most.
+    ///
+    /// Each client is told a random delay up to this, to smear out the reconnects.
+    pub reconnect_in: Duration,
+    /// How long clients keep trying to reconnect before falling back to their usual
+    /// connection failure handling.
+    pub try_for: Duration,
+    /// Another derp server clients should move to, typically one from the same
+    /// [`super::DerpMap`].
+    pub redirect: Option<Url>,
+}
+
+impl RestartNotice {
+    /// The notice for a single client, with a random `reconnect_in` up to ours.
+    pub(crate) fn smeared(&self) -> Self {
+        let reconnect_in = rand::thread_rng().gen_range(Duration::ZERO..=self.reconnect_in);
+        Self {
+            reconnect_in,
+            ..self.clone()
+        }
+    }
+}
+
+/// How a client is connected to a derp [`super::Server`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOrigin {
+    /// A client connected to this server.
+    Local,
+    /// Another derp server of our mesh, connected to this server with the mesh key.
+    MeshPeer,
+    /// A client connected to another server of our mesh, reached through that server.
+    Mesh,
+}
+
+/// A client known to a derp [`super::Server`], as listed by [`super::AdminHandler::clients`].
+#[derive(Debug, Clone)]
+pub struct ConnectedClient {
+    /// The node id of the client.
+    pub node_id: PublicKey,
+    /// How the client is connected.
+    pub origin: ClientOrigin,
+    /// How long the client has been connected to this server, `None` for
+    /// [`ClientOrigin::Mesh`] clients.
+    pub connected_for: Option<Duration>,
+    /// Bytes of packets the client sent through this server.
+    pub bytes_recv: u64,
+    /// Bytes of packets this server delivered to the client.
+    pub bytes_sent: u64,
+    /// Whether the client announced this server as its home server.
+    pub preferred: bool,
+}
+
 /// A request to write a dataframe to a Client
 #[derive(Debug, Clone)]
 pub(crate) struct Packet {
@@ -96,6 +213,15 @@ impl ServerInfo {
             token_bucket_bytes_per_second: 0,
         }
     }
+
+    /// Specifies the server requires clients to stay within `limit`
+    pub fn with_rate_limit(limit: ClientRateLimit) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            token_bucket_bytes_burst: limit.bytes_burst() as usize,
+            token_bucket_bytes_per_second: limit.bytes_per_second() as usize,
+        }
+    }
 }
 
 /// A `PacketForwarder` can forward a packet to the `dstkey` from the `srckey`.
@@ -114,6 +240,10 @@ where
     P: PacketForwarder,
 {
     AddWatcher(PublicKey),
+    /// Close the connection of a local client, sent by a mesh peer or the admin.
+    ///
+    /// The client is removed like on a regular disconnect: its peers and the watchers are
+    /// told it is gone. It is free to reconnect.
     ClosePeer(PublicKey),
     SendPacket((PublicKey, Packet)),
     SendDiscoPacket((PublicKey, Packet)),
@@ -126,5 +256,19 @@ where
         forwarder: P,
     },
     RemovePacketForwarder(PublicKey),
+    /// Announce the restart to all clients, then shut down once they left or the
+    /// `drain_timeout` elapsed.
+    Restart {
+        notice: RestartNotice,
+        drain_timeout: Duration,
+    },
+    /// Reply with all clients we can reach, locally or through the mesh.
+    ListClients(oneshot::Sender<Vec<ConnectedClient>>),
+    /// Close the connection of the client and refuse it from now on.
+    Ban(PublicKey),
+    /// Accept the client again.
+    Unban(PublicKey),
+    /// Reply with the banned clients.
+    ListBanned(oneshot::Sender<Vec<PublicKey>>),
     Shutdown,
 }
@@ -1,9 +1,17 @@
 //! An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 
-use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::IntoFuture,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
 
 use anyhow::{anyhow, ensure, Context, Result};
 use derive_more::Debug;
+use futures::{future::BoxFuture, Stream};
 use quinn_proto::VarInt;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
@@ -14,10 +22,18 @@ use crate::{
     defaults::default_derp_map,
     derp::{DerpMap, DerpMode},
     key::{PublicKey, SecretKey},
-    magicsock::{self, Discovery, MagicSock},
-    tls,
+    magicsock::{self, Discovery, MagicSock, UdpBinder},
+    netcheck, portmapper, tls,
 };
 
+use self::transport::AlpnTransports;
+
+mod accept_policy;
+mod transport;
+
+pub use self::accept_policy::{AcceptCallback, AcceptPolicy};
+pub use self::transport::{CongestionControl, TransportProfile, TransportSettings};
+pub use super::magicsock::BindConfig;
 pub use super::magicsock::EndpointInfo as ConnectionInfo;
 
 /// A peer and it's addressing information.
@@ -117,11 +133,18 @@ pub struct MagicEndpointBuilder {
     derp_mode: DerpMode,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: Option<quinn::TransportConfig>,
+    transport_settings: Option<TransportSettings>,
+    alpn_transport_settings: HashMap<Vec<u8>, TransportSettings>,
     concurrent_connections: Option<u32>,
     keylog: bool,
     discovery: Option<Box<dyn Discovery>>,
     /// Path for known peers. See [`MagicEndpointBuilder::peers_data_path`].
     peers_path: Option<PathBuf>,
+    accept_policy: AcceptPolicy,
+    bind_config: BindConfig,
+    udp_binder: Option<Arc<dyn UdpBinder>>,
+    capture_path: Option<PathBuf>,
+    portmapper_config: portmapper::Config,
 }
 
 impl Default for MagicEndpointBuilder {
@@ -131,10 +154,17 @@ impl Default for MagicEndpointBuilder {
             derp_mode: DerpMode::Default,
             alpn_protocols: Default::default(),
             transport_config: Default::default(),
+            transport_settings: Default::default(),
+            alpn_transport_settings: Default::default(),
             concurrent_connections: Default::default(),
             keylog: Default::default(),
             discovery: Default::default(),
             peers_path: None,
+            accept_policy: Default::default(),
+            bind_config: Default::default(),
+            udp_binder: None,
+            capture_path: None,
+            portmapper_config: Default::default(),
         }
     }
 }
@@ -192,6 +222,29 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Tune the QUIC transport of all connections, see [`TransportProfile`] for presets.
+    ///
+    /// If unset, dialed connections use [`TransportSettings::default`] and incoming connections
+    /// the default [quinn::TransportConfig]. Incoming connections use a
+    /// [`MagicEndpointBuilder::transport_config`] instead, if set.
+    pub fn transport_settings(mut self, settings: TransportSettings) -> Self {
+        self.transport_settings = Some(settings);
+        self
+    }
+
+    /// Tune the QUIC transport of connections dialed with the `alpn` protocol.
+    ///
+    /// Only applies to dialed connections: incoming connections always use the
+    /// [`MagicEndpointBuilder::transport_settings`], see the [`TransportSettings`] docs.
+    pub fn alpn_transport_settings(
+        mut self,
+        alpn: impl Into<Vec<u8>>,
+        settings: TransportSettings,
+    ) -> Self {
+        self.alpn_transport_settings.insert(alpn.into(), settings);
+        self
+    }
+
     /// Maximum number of simultaneous connections to accept.
     ///
     /// New incoming connections are only accepted if the total number of incoming or outgoing
@@ -216,12 +269,59 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Set which nodes may connect to this endpoint.
+    ///
+    /// By default all nodes are accepted. See [`AcceptPolicy`] for where the policy is
+    /// enforced.
+    pub fn accept_policy(mut self, accept_policy: AcceptPolicy) -> Self {
+        self.accept_policy = accept_policy;
+        self
+    }
+
+    /// Set the addresses or interface to bind to, and interfaces not to advertise.
+    ///
+    /// By default the endpoint listens on all interfaces. See [`BindConfig`] for details.
+    pub fn bind_config(mut self, bind_config: BindConfig) -> Self {
+        self.bind_config = bind_config;
+        self
+    }
+
+    /// Use sockets created by `udp_binder` instead of those of the operating system.
+    ///
+    /// This is used to run endpoints on a simulated network in tests.
+    #[cfg(test)]
+    pub(crate) fn udp_binder(mut self, udp_binder: Arc<dyn UdpBinder>) -> Self {
+        self.udp_binder = Some(udp_binder);
+        self
+    }
+
+    /// Configure the port mapping protocols and manually forwarded addresses.
+    ///
+    /// Addresses in [`portmapper::Config::static_external_addrs`] are advertised as direct
+    /// addresses of this endpoint. Use [`MagicEndpointBuilder::bind`] with the forwarded port.
+    pub fn portmapper_config(mut self, config: portmapper::Config) -> Self {
+        self.portmapper_config = config;
+        self
+    }
+
+    /// Write all packets sent and received by the endpoint to a pcapng file at `path`.
+    ///
+    /// The capture contains disco messages, STUN packets and QUIC datagrams, whether sent
+    /// directly or relayed by a derp server, each annotated with the node and the path used.
+    /// The frames exchanged with the derp servers are recorded as well, on a separate
+    /// interface.  Combined with [`MagicEndpointBuilder::keylog`] the QUIC packets can be
+    /// decrypted in Wireshark.  This is meant for debugging and slows down the endpoint.
+    pub fn packet_capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.capture_path = Some(path.into());
+        self
+    }
+
     /// Bind the magic endpoint on the specified socket address.
     ///
     /// The *bind_port* is the port that should be bound locally.
     /// The port will be used to bind an IPv4 and, if supported, and IPv6 socket.
     /// You can pass `0` to let the operating system choose a free port for you.
-    /// NOTE: This will be improved soon to add support for binding on specific addresses.
+    /// The addresses bound to are set by [`MagicEndpointBuilder::bind_config`].
     pub async fn bind(self, bind_port: u16) -> Result<MagicEndpoint> {
         let derp_map = match self.derp_mode {
             DerpMode::Disabled => DerpMap::empty(),
@@ -232,35 +332,58 @@ impl MagicEndpointBuilder {
             }
         };
         let secret_key = self.secret_key.unwrap_or_else(SecretKey::generate);
+        let transport_config = self.transport_config.unwrap_or_else(|| {
+            self.transport_settings
+                .as_ref()
+                .map(TransportSettings::transport_config)
+                .unwrap_or_default()
+        });
         let mut server_config = make_server_config(
             &secret_key,
             self.alpn_protocols,
-            self.transport_config,
+            transport_config,
             self.keylog,
+            self.accept_policy.clone(),
         )?;
         if let Some(c) = self.concurrent_connections {
             server_config.concurrent_connections(c);
         }
         let msock_opts = magicsock::Options {
             port: bind_port,
+            bind_config: self.bind_config,
             secret_key,
             derp_map,
             nodes_path: self.peers_path,
             discovery: self.discovery,
+            udp_binder: self.udp_binder,
+            capture_path: self.capture_path,
+            portmapper_config: self.portmapper_config,
         };
-        MagicEndpoint::bind(Some(server_config), msock_opts, self.keylog).await
+        MagicEndpoint::bind(
+            Some(server_config),
+            msock_opts,
+            self.keylog,
+            AlpnTransports {
+                default: self.transport_settings.unwrap_or_default(),
+                alpns: self.alpn_transport_settings,
+            },
+            self.accept_policy,
+        )
+        .await
     }
 }
 
 fn make_server_config(
     secret_key: &SecretKey,
     alpn_protocols: Vec<Vec<u8>>,
-    transport_config: Option<quinn::TransportConfig>,
+    transport_config: quinn::TransportConfig,
     keylog: bool,
+    accept_policy: AcceptPolicy,
 ) -> Result<quinn::ServerConfig> {
-    let tls_server_config = tls::make_server_config(secret_key, alpn_protocols, keylog)?;
+    let tls_server_config =
+        tls::make_server_config_with_policy(secret_key, alpn_protocols, keylog, accept_policy)?;
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
-    server_config.transport_config(Arc::new(transport_config.unwrap_or_default()));
+    server_config.transport_config(Arc::new(transport_config));
     Ok(server_config)
 }
 
@@ -271,6 +394,8 @@ pub struct MagicEndpoint {
     msock: MagicSock,
     endpoint: quinn::Endpoint,
     keylog: bool,
+    transports: Arc<AlpnTransports>,
+    accept_policy: AcceptPolicy,
 }
 
 impl MagicEndpoint {
@@ -287,6 +412,8 @@ impl MagicEndpoint {
         server_config: Option<quinn::ServerConfig>,
         msock_opts: magicsock::Options,
         keylog: bool,
+        transports: AlpnTransports,
+        accept_policy: AcceptPolicy,
     ) -> Result<Self> {
         let secret_key = msock_opts.secret_key.clone();
         let msock = magicsock::MagicSock::new(msock_opts).await?;
@@ -313,12 +440,21 @@ impl MagicEndpoint {
             msock,
             endpoint,
             keylog,
+            transports: Arc::new(transports),
+            accept_policy,
         })
     }
 
     /// Accept an incoming connection on the socket.
-    pub fn accept(&self) -> quinn::Accept<'_> {
-        self.endpoint.accept()
+    ///
+    /// Nodes refused by the [`AcceptPolicy`] fail the handshake, or, for an
+    /// [`AcceptCallback`], are closed when the returned [`Connecting`] is awaited.
+    pub async fn accept(&self) -> Option<Connecting> {
+        let inner = self.endpoint.accept().await?;
+        Some(Connecting {
+            inner,
+            accept_policy: self.accept_policy.clone(),
+        })
     }
 
     /// Get the node id of this endpoint.
@@ -407,6 +543,33 @@ impl MagicEndpoint {
         self.msock.tracked_endpoint(node_id).await
     }
 
+    /// Watch the [`magicsock::ConnectionType`] to a specific node.
+    ///
+    /// The stream starts with the current connection type and yields a new item every time
+    /// it changes, e.g. when a relayed connection upgrades to a direct one.
+    pub fn watch_connection_type(
+        &self,
+        node_id: PublicKey,
+    ) -> impl Stream<Item = magicsock::ConnectionType> + Send + Unpin + 'static {
+        self.msock.watch_connection_type(node_id)
+    }
+
+    /// Get a stream of [`magicsock::PathEvent`]s about how we can reach other nodes.
+    ///
+    /// Subscribers which fall too far behind miss events, they can catch up using
+    /// [`MagicEndpoint::connection_infos`].
+    pub fn path_events(&self) -> impl Stream<Item = magicsock::PathEvent> + Send + Unpin + 'static {
+        self.msock.path_events()
+    }
+
+    /// Get the most recent netcheck reports with the time they were generated, oldest first.
+    ///
+    /// Besides latencies to the DERP nodes the reports tell how the NAT we are behind, if
+    /// any, maps and filters traffic, see [`netcheck::Report::nat_mapping`].
+    pub async fn netcheck_history(&self) -> Result<Vec<(SystemTime, Arc<netcheck::Report>)>> {
+        self.msock.netcheck_history().await
+    }
+
     async fn resolve(&self, node_id: &PublicKey) -> Result<AddrInfo> {
         if let Some(discovery) = self.msock.discovery() {
             debug!("no mapping address for {node_id}, resolving via {discovery:?}");
@@ -488,8 +651,7 @@ impl MagicEndpoint {
                 self.keylog,
             )?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
-            let mut transport_config = quinn::TransportConfig::default();
-            transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
+            let transport_config = self.transports.for_alpn(alpn).transport_config();
             client_config.transport_config(Arc::new(transport_config));
             client_config
         };
@@ -557,11 +719,57 @@ impl MagicEndpoint {
     }
 }
 
+/// An incoming connection accepted by [`MagicEndpoint::accept`].
+///
+/// Awaiting it finishes the handshake and runs the [`AcceptCallback`] of the endpoint, if it
+/// has one.  A connection refused by the callback is closed and fails with
+/// [`quinn::ConnectionError::LocallyClosed`].
+#[derive(Debug)]
+pub struct Connecting {
+    inner: quinn::Connecting,
+    accept_policy: AcceptPolicy,
+}
+
+impl Connecting {
+    /// The ALPN protocol negotiated for this connection.
+    pub async fn alpn(&mut self) -> Result<String> {
+        get_alpn(&mut self.inner).await
+    }
+
+    /// The peer's UDP address.
+    pub fn remote_address(&self) -> SocketAddr {
+        self.inner.remote_address()
+    }
+
+    async fn finish(self) -> Result<quinn::Connection, quinn::ConnectionError> {
+        let conn = self.inner.await?;
+        if let AcceptPolicy::Callback(ref cb) = self.accept_policy {
+            let allowed = match (get_remote_node_id(&conn), get_connection_alpn(&conn)) {
+                (Ok(node_id), Ok(alpn)) => cb(node_id, alpn.into_bytes()).await,
+                _ => false,
+            };
+            if !allowed {
+                debug!("refusing connection not accepted by policy");
+                conn.close(0u32.into(), b"refused");
+                return Err(quinn::ConnectionError::LocallyClosed);
+            }
+        }
+        Ok(conn)
+    }
+}
+
+impl IntoFuture for Connecting {
+    type Output = Result<quinn::Connection, quinn::ConnectionError>;
+    type IntoFuture = BoxFuture<'static, Self::Output>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.finish())
+    }
+}
+
 /// Accept an incoming connection and extract the client-provided [`PublicKey`] and ALPN protocol.
-pub async fn accept_conn(
-    mut conn: quinn::Connecting,
-) -> Result<(PublicKey, String, quinn::Connection)> {
-    let alpn = get_alpn(&mut conn).await?;
+pub async fn accept_conn(mut conn: Connecting) -> Result<(PublicKey, String, quinn::Connection)> {
+    let alpn = conn.alpn().await?;
     let conn = conn.await?;
     let peer_id = get_remote_node_id(&conn)?;
     Ok((peer_id, alpn, conn))
@@ -570,6 +778,18 @@ pub async fn accept_conn(
 /// Extract the ALPN protocol from the peer's TLS certificate.
 pub async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
     let data = connecting.handshake_data().await?;
+    alpn_from_handshake_data(data)
+}
+
+/// Extract the ALPN protocol of an established connection.
+pub fn get_connection_alpn(connection: &quinn::Connection) -> Result<String> {
+    let data = connection
+        .handshake_data()
+        .context("no handshake data available")?;
+    alpn_from_handshake_data(data)
+}
+
+fn alpn_from_handshake_data(data: Box<dyn std::any::Any>) -> Result<String> {
     match data.downcast::<quinn::crypto::rustls::HandshakeData>() {
         Ok(data) => match data.protocol {
             Some(protocol) => std::string::String::from_utf8(protocol).map_err(Into::into),
@@ -605,8 +825,12 @@ pub fn get_remote_node_id(connection: &quinn::Connection) -> Result<PublicKey> {
 #[cfg(test)]
 mod tests {
 
-    use std::time::Instant;
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
 
+    use futures::{FutureExt, StreamExt};
     use rand_core::SeedableRng;
     use tracing::{error_span, info, info_span, Instrument};
 
@@ -756,6 +980,230 @@ mod tests {
         assert_eq!(conn_addr, direct_addr);
     }
 
+    #[tokio::test]
+    async fn watch_connection_type() {
+        let _guard = iroh_test::logging::setup();
+
+        let ep = MagicEndpoint::builder()
+            .derp_mode(DerpMode::Disabled)
+            .bind(0)
+            .await
+            .unwrap();
+        let node_id = SecretKey::generate().public();
+        let mut conn_types = ep.watch_connection_type(node_id);
+        let mut events = ep.path_events();
+
+        let timeout = Duration::from_secs(5);
+        let next = tokio::time::timeout(timeout, conn_types.next())
+            .await
+            .unwrap();
+        assert_eq!(next, Some(magicsock::ConnectionType::None));
+
+        let derp_url: Url = "https://derp.example.com".parse().unwrap();
+        ep.add_node_addr(NodeAddr::new(node_id).with_derp_url(derp_url.clone()))
+            .unwrap();
+
+        let next = tokio::time::timeout(timeout, conn_types.next())
+            .await
+            .unwrap();
+        assert_eq!(
+            next,
+            Some(magicsock::ConnectionType::Relay(derp_url.clone()))
+        );
+
+        let event = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert_eq!(
+            event,
+            Some(magicsock::PathEvent::DerpUrlChanged {
+                node_id,
+                url: Some(derp_url.clone()),
+            })
+        );
+        let event = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert_eq!(
+            event,
+            Some(magicsock::PathEvent::ConnectionTypeChanged {
+                node_id,
+                conn_type: magicsock::ConnectionType::Relay(derp_url),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_accept_policy() {
+        let _guard = iroh_test::logging::setup();
+        let allowed = SecretKey::generate();
+        check_accept_policy(
+            AcceptPolicy::allow_list([allowed.public()]),
+            allowed.clone(),
+        )
+        .await;
+        let allowed_id = allowed.public();
+        check_accept_policy(
+            AcceptPolicy::Callback(Arc::new(move |node_id, _alpn| {
+                async move { node_id == allowed_id }.boxed()
+            })),
+            allowed,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_accept_callback_alpn() {
+        let _guard = iroh_test::logging::setup();
+        let (derp_map, derp_url, _guard) = run_derper().await.unwrap();
+        const OTHER_ALPN: &[u8] = b"n0/iroh/test-other";
+        let client_key = SecretKey::generate();
+        let client_id = client_key.public();
+
+        // The client may only use TEST_ALPN.
+        let policy = AcceptPolicy::Callback(Arc::new(move |node_id, alpn| {
+            async move { node_id != client_id || alpn == TEST_ALPN }.boxed()
+        }));
+        let server = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec(), OTHER_ALPN.to_vec()])
+            .derp_mode(DerpMode::Custom(derp_map.clone()))
+            .accept_policy(policy)
+            .bind(0)
+            .await
+            .unwrap();
+        let server_addr = NodeAddr::new(server.node_id()).with_derp_url(derp_url);
+        let server_task = tokio::spawn(
+            async move {
+                loop {
+                    let conn = server.accept().await.unwrap();
+                    match accept_conn(conn).await {
+                        Ok((node_id, alpn, conn)) => return (node_id, alpn, conn),
+                        Err(err) => info!("refused connection: {err:#}"),
+                    }
+                }
+            }
+            .instrument(error_span!("server")),
+        );
+
+        let ep = MagicEndpoint::builder()
+            .secret_key(client_key)
+            .alpns(vec![TEST_ALPN.to_vec(), OTHER_ALPN.to_vec()])
+            .derp_mode(DerpMode::Custom(derp_map))
+            .bind(0)
+            .await
+            .unwrap();
+
+        // The handshake succeeds, the refused connection is closed right after.
+        let conn = ep.connect(server_addr.clone(), OTHER_ALPN).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), conn.closed())
+            .await
+            .expect("refused connection not closed");
+
+        let _conn = ep.connect(server_addr, TEST_ALPN).await.unwrap();
+        let (node_id, alpn, _conn) = tokio::time::timeout(Duration::from_secs(10), server_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(node_id, client_id);
+        assert_eq!(alpn.as_bytes(), TEST_ALPN);
+    }
+
+    async fn check_accept_policy(policy: AcceptPolicy, allowed: SecretKey) {
+        let (derp_map, derp_url, _guard) = run_derper().await.unwrap();
+        let refused = SecretKey::generate();
+
+        let server = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .derp_mode(DerpMode::Custom(derp_map.clone()))
+            .accept_policy(policy)
+            .bind(0)
+            .await
+            .unwrap();
+        let server_addr = NodeAddr::new(server.node_id()).with_derp_url(derp_url);
+        let server_task = tokio::spawn(
+            async move {
+                loop {
+                    let conn = server.accept().await.unwrap();
+                    match accept_conn(conn).await {
+                        Ok((node_id, _alpn, conn)) => return (node_id, conn),
+                        Err(err) => info!("refused connection: {err:#}"),
+                    }
+                }
+            }
+            .instrument(error_span!("server")),
+        );
+
+        let client = |secret_key: SecretKey| {
+            MagicEndpoint::builder()
+                .secret_key(secret_key)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .derp_mode(DerpMode::Custom(derp_map.clone()))
+                .bind(0)
+        };
+
+        // The client may finish its side of the handshake before the server verifies the
+        // client certificate, so the refused node either fails to connect or sees the
+        // connection closed.
+        let ep = client(refused).await.unwrap();
+        if let Ok(conn) = ep.connect(server_addr.clone(), TEST_ALPN).await {
+            tokio::time::timeout(Duration::from_secs(10), conn.closed())
+                .await
+                .expect("refused connection not closed");
+        }
+
+        let ep = client(allowed.clone()).await.unwrap();
+        let _conn = ep.connect(server_addr, TEST_ALPN).await.unwrap();
+        let (node_id, _conn) = tokio::time::timeout(Duration::from_secs(10), server_task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(node_id, allowed.public());
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_bind_config() {
+        let _guard = iroh_test::logging::setup();
+        let ep = MagicEndpoint::builder()
+            .derp_mode(DerpMode::Disabled)
+            .bind_config(BindConfig::addrs([Ipv4Addr::LOCALHOST.into()]))
+            .bind(0)
+            .await
+            .unwrap();
+        let (v4, v6) = ep.local_addr().unwrap();
+        assert_eq!(v4.ip(), Ipv4Addr::LOCALHOST);
+        assert!(v6.is_none());
+
+        let endpoints = tokio::time::timeout(Duration::from_secs(10), ep.local_endpoints())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(endpoints.iter().all(|ep| ep.addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_static_external_addrs() {
+        let _guard = iroh_test::logging::setup();
+        let forwarded: SocketAddr = "203.0.113.7:4433".parse().unwrap();
+        let ep = MagicEndpoint::builder()
+            .derp_mode(DerpMode::Disabled)
+            .portmapper_config(portmapper::Config {
+                enable_upnp: false,
+                enable_pcp: false,
+                enable_nat_pmp: false,
+                static_external_addrs: vec![forwarded],
+                ..Default::default()
+            })
+            .bind(0)
+            .await
+            .unwrap();
+
+        let endpoints = tokio::time::timeout(Duration::from_secs(10), ep.local_endpoints())
+            .await
+            .unwrap()
+            .unwrap();
+        let static_ep = endpoints
+            .iter()
+            .find(|ep| ep.addr == forwarded)
+            .expect("static address advertised");
+        assert_eq!(static_ep.typ, config::EndpointType::Static);
+    }
+
     #[tokio::test]
     async fn magic_endpoint_derp_connect_loop() {
         let _guard = iroh_test::logging::setup();
@@ -0,0 +1,112 @@
+//! Deciding which nodes may connect to a [`super::MagicEndpoint`].
+//!
+//! By default a [`super::MagicEndpoint`] accepts connections from any node completing the TLS
+//! handshake.  An [`AcceptPolicy`] restricts this to a known set of nodes, refuses a known
+//! set of nodes, or leaves the decision to a callback.
+//!
+//! The allow and deny lists are enforced while verifying the client certificate, so refused
+//! nodes fail the TLS handshake.  An [`AcceptCallback`] needs the negotiated ALPN and may take
+//! its time, so it runs once the handshake finished, when the [`super::Connecting`] returned
+//! by [`super::MagicEndpoint::accept`] is awaited.  Either way, a refused connection never
+//! reaches the application.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::key::PublicKey;
+
+/// Callback deciding whether an incoming connection is accepted.
+///
+/// Receives the [`PublicKey`] the remote proved ownership of during the TLS handshake and
+/// the negotiated ALPN.  Resolving to `false` refuses the connection.
+pub type AcceptCallback =
+    Arc<dyn Fn(PublicKey, Vec<u8>) -> BoxFuture<'static, bool> + Send + Sync + 'static>;
+
+/// Decides which nodes may connect to a [`super::MagicEndpoint`].
+#[derive(derive_more::Debug, Clone, Default)]
+pub enum AcceptPolicy {
+    /// Accept every node. The default.
+    #[default]
+    Everyone,
+    /// Only accept nodes whose [`PublicKey`] is in the set.
+    AllowList(Arc<HashSet<PublicKey>>),
+    /// Accept every node except those whose [`PublicKey`] is in the set.
+    DenyList(Arc<HashSet<PublicKey>>),
+    /// Ask the [`AcceptCallback`] for every connection.
+    Callback(#[debug("AcceptCallback")] AcceptCallback),
+}
+
+impl AcceptPolicy {
+    /// Creates an [`AcceptPolicy::AllowList`] from the given keys.
+    pub fn allow_list(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        AcceptPolicy::AllowList(Arc::new(keys.into_iter().collect()))
+    }
+
+    /// Creates an [`AcceptPolicy::DenyList`] from the given keys.
+    pub fn deny_list(keys: impl IntoIterator<Item = PublicKey>) -> Self {
+        AcceptPolicy::DenyList(Arc::new(keys.into_iter().collect()))
+    }
+
+    /// Whether this policy accepts every node.
+    pub fn is_open(&self) -> bool {
+        matches!(self, AcceptPolicy::Everyone)
+    }
+
+    /// Checks whether a connection from `node_id` using `alpn` is accepted.
+    pub async fn is_allowed(&self, node_id: PublicKey, alpn: &[u8]) -> bool {
+        match self {
+            AcceptPolicy::Callback(cb) => cb(node_id, alpn.to_vec()).await,
+            _ => self.verify_node(&node_id),
+        }
+    }
+
+    /// Checks the parts of the policy which are enforced during the TLS handshake.
+    ///
+    /// Callbacks do not run there and accept every node at this stage.
+    pub(crate) fn verify_node(&self, node_id: &PublicKey) -> bool {
+        match self {
+            AcceptPolicy::Everyone | AcceptPolicy::Callback(_) => true,
+            AcceptPolicy::AllowList(keys) => keys.contains(node_id),
+            AcceptPolicy::DenyList(keys) => !keys.contains(node_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[tokio::test]
+    async fn test_accept_policy() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+
+        let policy = AcceptPolicy::default();
+        assert!(policy.is_open());
+        assert!(policy.is_allowed(a, b"alpn").await);
+
+        let policy = AcceptPolicy::allow_list([a]);
+        assert!(policy.is_allowed(a, b"alpn").await);
+        assert!(!policy.is_allowed(b, b"alpn").await);
+        assert!(!policy.verify_node(&b));
+
+        let policy = AcceptPolicy::deny_list([a]);
+        assert!(!policy.is_allowed(a, b"alpn").await);
+        assert!(policy.is_allowed(b, b"alpn").await);
+
+        let policy = AcceptPolicy::Callback(Arc::new(move |node_id, alpn| {
+            async move { node_id == a && alpn == b"alpn" }.boxed()
+        }));
+        assert!(!policy.is_open());
+        assert!(policy.is_allowed(a, b"alpn").await);
+        assert!(!policy.is_allowed(a, b"other").await);
+        assert!(!policy.is_allowed(b, b"alpn").await);
+        // callbacks are not consulted during the handshake
+        assert!(policy.verify_node(&b));
+    }
+}
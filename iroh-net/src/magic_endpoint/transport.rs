@@ -0,0 +1,180 @@
+//! Tuning the QUIC transport of a [`super::MagicEndpoint`].
+//!
+//! The defaults of [`quinn::TransportConfig`] are a compromise for typical internet
+//! connections.  Transfers over links with a large bandwidth-delay product are limited by the
+//! flow control windows long before the link is saturated, while mobile devices rather want
+//! to keep their radio idle.  A [`TransportProfile`] bundles [`TransportSettings`] for these
+//! use cases, individual knobs can be adjusted afterwards.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::congestion;
+use quinn_proto::VarInt;
+use serde::{Deserialize, Serialize};
+
+/// Named sets of [`TransportSettings`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportProfile {
+    /// The quinn defaults, with a keep alive to hold on to NAT mappings.
+    #[default]
+    Default,
+    /// Large flow control windows and BBR congestion control, for transfers over links with a
+    /// high bandwidth or latency.
+    Bulk,
+    /// Infrequent keep alives and a short idle timeout, for interactive use and mobile
+    /// devices.
+    Interactive,
+}
+
+impl TransportProfile {
+    /// The [`TransportSettings`] of this profile.
+    pub fn settings(self) -> TransportSettings {
+        match self {
+            TransportProfile::Default => TransportSettings::default(),
+            TransportProfile::Bulk => TransportSettings {
+                max_idle_timeout: Some(Duration::from_secs(60)),
+                receive_window: 64 * 1024 * 1024,
+                stream_receive_window: 16 * 1024 * 1024,
+                send_window: 64 * 1024 * 1024,
+                congestion_control: CongestionControl::Bbr,
+                ..Default::default()
+            },
+            TransportProfile::Interactive => TransportSettings {
+                keep_alive_interval: Some(Duration::from_secs(5)),
+                max_idle_timeout: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The congestion control algorithm used for connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionControl {
+    /// CUBIC, the quinn default.
+    #[default]
+    Cubic,
+    /// NewReno.
+    NewReno,
+    /// BBR, which keeps links with some packet loss saturated better than the loss based
+    /// algorithms.  Experimental in quinn.
+    Bbr,
+}
+
+/// The knobs of the QUIC transport, see [`TransportProfile`] for presets.
+///
+/// Settings can differ per ALPN only for the connections we dial. An incoming connection is
+/// configured when its first packet arrives, before the ALPN is negotiated in the handshake,
+/// so all incoming connections share the endpoint wide settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportSettings {
+    /// How often to send a keep alive when the connection is otherwise idle, `None` disables
+    /// keep alives.
+    pub keep_alive_interval: Option<Duration>,
+    /// After how long without any traffic a connection is closed, `None` never closes idle
+    /// connections.
+    ///
+    /// The smaller value of both peers is used.
+    pub max_idle_timeout: Option<Duration>,
+    /// Bytes the peer may send on all streams of a connection before being acknowledged.
+    pub receive_window: u64,
+    /// Bytes the peer may send on a single stream before being acknowledged.
+    pub stream_receive_window: u64,
+    /// Bytes we send on all streams of a connection before waiting for acknowledgements.
+    pub send_window: u64,
+    /// The congestion control algorithm.
+    pub congestion_control: CongestionControl,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            keep_alive_interval: Some(Duration::from_secs(1)),
+            max_idle_timeout: Some(Duration::from_secs(30)),
+            receive_window: u64::from(VarInt::MAX),
+            stream_receive_window: 1_250_000,
+            send_window: 10_000_000,
+            congestion_control: CongestionControl::Cubic,
+        }
+    }
+}
+
+impl TransportSettings {
+    /// Builds a [`quinn::TransportConfig`] with these settings, other parameters keep the
+    /// quinn defaults.
+    pub fn transport_config(&self) -> quinn::TransportConfig {
+        let mut config = quinn::TransportConfig::default();
+        config
+            .keep_alive_interval(self.keep_alive_interval)
+            // timeouts too large for QUIC are as good as none
+            .max_idle_timeout(self.max_idle_timeout.and_then(|t| t.try_into().ok()))
+            .receive_window(varint(self.receive_window))
+            .stream_receive_window(varint(self.stream_receive_window))
+            .send_window(self.send_window);
+        match self.congestion_control {
+            CongestionControl::Cubic => {
+                config.congestion_controller_factory(Arc::new(congestion::CubicConfig::default()))
+            }
+            CongestionControl::NewReno => {
+                config.congestion_controller_factory(Arc::new(congestion::NewRenoConfig::default()))
+            }
+            CongestionControl::Bbr => {
+                config.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()))
+            }
+        };
+        config
+    }
+}
+
+/// The [`TransportSettings`] of an endpoint, optionally differing per ALPN.
+#[derive(Debug, Clone, Default)]
+pub(super) struct AlpnTransports {
+    pub(super) default: TransportSettings,
+    pub(super) alpns: HashMap<Vec<u8>, TransportSettings>,
+}
+
+impl AlpnTransports {
+    /// The settings for connections using the `alpn` protocol.
+    pub(super) fn for_alpn(&self, alpn: &[u8]) -> &TransportSettings {
+        self.alpns.get(alpn).unwrap_or(&self.default)
+    }
+}
+
+fn varint(value: u64) -> VarInt {
+    VarInt::from_u64(value).unwrap_or(VarInt::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_profiles() {
+        assert_eq!(
+            TransportProfile::Default.settings(),
+            TransportSettings::default()
+        );
+        let bulk = TransportProfile::Bulk.settings();
+        assert_eq!(bulk.congestion_control, CongestionControl::Bbr);
+        assert!(bulk.stream_receive_window > TransportSettings::default().stream_receive_window);
+        let interactive = TransportProfile::Interactive.settings();
+        assert!(interactive.max_idle_timeout < TransportSettings::default().max_idle_timeout);
+
+        // out of range values are clamped rather than rejected
+        let settings = TransportSettings {
+            receive_window: u64::MAX,
+            max_idle_timeout: Some(Duration::MAX),
+            ..bulk.clone()
+        };
+        settings.transport_config();
+
+        let mut transports = AlpnTransports::default();
+        transports.alpns.insert(b"bulk".to_vec(), bulk.clone());
+        assert_eq!(transports.for_alpn(b"bulk"), &bulk);
+        assert_eq!(transports.for_alpn(b"other"), &TransportSettings::default());
+    }
+}
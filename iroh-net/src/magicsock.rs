@@ -29,12 +29,12 @@ use std::{
         Arc,
     },
     task::{ready, Context, Poll, Waker},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
-use futures::{future::BoxFuture, FutureExt};
+use futures::{future::BoxFuture, FutureExt, Stream};
 use iroh_metrics::{inc, inc_by};
 use quinn::AsyncUdpSocket;
 use rand::{seq::SliceRandom, Rng, SeedableRng};
@@ -62,12 +62,15 @@ use crate::{
 };
 
 use self::{
+    capture::{CapturePath, Direction},
     derp_actor::{DerpActor, DerpActorMessage, DerpReadResult},
     metrics::Metrics as MagicsockMetrics,
     peer_map::{NodeMap, PingAction, SendPing},
     rebinding_conn::RebindingUdpConn,
 };
 
+mod bind_config;
+mod capture;
 mod derp_actor;
 mod metrics;
 mod peer_map;
@@ -76,8 +79,13 @@ mod timer;
 
 pub use crate::net::UdpSocket;
 
+pub use self::bind_config::BindConfig;
+pub(crate) use self::capture::PacketCapture;
 pub use self::metrics::Metrics;
-pub use self::peer_map::{ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo};
+pub use self::peer_map::{
+    ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo, PathEvent, PathTraffic, TrafficInfo,
+};
+pub(crate) use self::rebinding_conn::{CustomUdpSocket, UdpBinder};
 pub use self::timer::Timer;
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
@@ -102,6 +110,9 @@ pub struct Options {
     /// Zero means to pick one automatically.
     pub port: u16,
 
+    /// Addresses and interfaces to bind to and advertise.
+    pub bind_config: BindConfig,
+
     /// Secret key for this node.
     pub secret_key: SecretKey,
 
@@ -113,6 +124,17 @@ pub struct Options {
 
     /// Optional node discovery mechanism.
     pub discovery: Option<Box<dyn Discovery>>,
+
+    /// Creates the UDP sockets instead of the operating system if set.
+    ///
+    /// Port mapping is disabled for custom sockets.
+    pub(crate) udp_binder: Option<Arc<dyn UdpBinder>>,
+
+    /// Records all sent and received packets into a pcapng file at this path if set.
+    pub capture_path: Option<std::path::PathBuf>,
+
+    /// Configuration of the port mapping protocols and manually forwarded addresses.
+    pub portmapper_config: portmapper::Config,
 }
 
 /// Node discovery for [`super::MagicEndpoint`].
@@ -148,10 +170,14 @@ impl Default for Options {
     fn default() -> Self {
         Options {
             port: 0,
+            bind_config: BindConfig::default(),
             secret_key: SecretKey::generate(),
             derp_map: DerpMap::empty(),
             nodes_path: None,
             discovery: None,
+            udp_binder: None,
+            capture_path: None,
+            portmapper_config: Default::default(),
         }
     }
 }
@@ -205,6 +231,8 @@ struct Inner {
     closed: AtomicBool,
     /// If the last netcheck report, reports IPv6 to be available.
     ipv6_reported: Arc<AtomicBool>,
+    /// If the last netcheck report which checked for it found a captive portal.
+    captive_portal: sync::watch::Sender<bool>,
 
     /// None (or zero nodes) means DERP is disabled.
     derp_map: DerpMap,
@@ -239,6 +267,9 @@ struct Inner {
 
     /// Indicates the update endpoint state.
     endpoints_update_state: EndpointUpdateState,
+
+    /// Packet capture, if enabled by [`Options::capture_path`].
+    capture: Option<PacketCapture>,
 }
 
 impl Inner {
@@ -361,7 +392,7 @@ impl Inner {
                     for t in transmits.iter_mut() {
                         t.destination = addr;
                     }
-                    match ready!(self.poll_send_udp(addr, &transmits, cx)) {
+                    match ready!(self.poll_send_udp(public_key, addr, &transmits, cx)) {
                         Ok(n) => {
                             trace!(node = %public_key.fmt_short(), dst = %addr, transmit_count=n, "sent transmits over UDP");
                             // truncate the transmits vec to `n`. these transmits will be sent to
@@ -372,7 +403,13 @@ impl Inner {
                             // Poll::Ready.
                             transmits.truncate(n);
                             udp_sent = true;
-                            // record metrics.
+                            let packets = split_packets(&transmits);
+                            self.node_map.record_sent(
+                                &public_key,
+                                &SendAddr::Udp(addr),
+                                packets.len() as u64,
+                                packets.iter().map(|p| p.len() as u64).sum(),
+                            );
                         }
                         Err(err) => {
                             error!(node = %public_key.fmt_short(), ?addr, "failed to send udp: {err:?}");
@@ -385,6 +422,7 @@ impl Inner {
 
                 // send derp
                 if let Some(ref derp_url) = derp_url {
+                    // traffic is recorded by the derp actor once the packets are sent
                     self.try_send_derp(derp_url, public_key, split_packets(&transmits));
                     derp_sent = true;
                 }
@@ -423,12 +461,22 @@ impl Inner {
 
     fn poll_send_udp(
         &self,
+        node: PublicKey,
         addr: SocketAddr,
         transmits: &[quinn_udp::Transmit],
         cx: &mut Context<'_>,
     ) -> Poll<io::Result<usize>> {
         let conn = self.conn_for_addr(addr)?;
         let n = ready!(conn.poll_send(&self.udp_state, cx, transmits))?;
+        if self.capture.is_some() {
+            let packets = split_packets(&transmits[..n]);
+            self.capture_udp(
+                Direction::Outgoing,
+                addr,
+                Some(node),
+                packets.iter().map(|p| &p[..]),
+            );
+        }
         let total_bytes: u64 = transmits
             .iter()
             .take(n)
@@ -442,6 +490,47 @@ impl Inner {
         Poll::Ready(Ok(n))
     }
 
+    /// Records packets sent to or received from `remote` over UDP in the packet capture.
+    fn capture_udp<'a>(
+        &self,
+        direction: Direction,
+        remote: SocketAddr,
+        node: Option<PublicKey>,
+        packets: impl IntoIterator<Item = &'a [u8]>,
+    ) {
+        let Some(ref capture) = self.capture else {
+            return;
+        };
+        let local = self
+            .conn_for_addr(remote)
+            .and_then(|conn| conn.local_addr())
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        capture.record(direction, CapturePath::Udp { local, remote }, node, packets);
+    }
+
+    /// Records packets relayed to or from `node` by a derp server in the packet capture.
+    fn capture_derp<'a>(
+        &self,
+        direction: Direction,
+        url: &Url,
+        node: PublicKey,
+        packets: impl IntoIterator<Item = &'a [u8]>,
+    ) {
+        let Some(ref capture) = self.capture else {
+            return;
+        };
+        let mapped_addr = self
+            .node_map
+            .get_quic_mapped_addr_for_node_key(&node)
+            .map(|addr| addr.0);
+        capture.record(
+            direction,
+            CapturePath::Derp { url, mapped_addr },
+            Some(node),
+            packets,
+        );
+    }
+
     fn conn_for_addr(&self, addr: SocketAddr) -> io::Result<&RebindingUdpConn> {
         if addr.is_ipv6() && self.pconn6.is_none() {
             return Err(io::Error::new(io::ErrorKind::Other, "no IPv6 connection"));
@@ -493,6 +582,10 @@ impl Inner {
             let mut start = 0;
             let mut is_quic = false;
             let mut quic_packets_count = 0;
+            let mut quic_bytes = 0;
+            // Only filled when capturing, the node of QUIC packets is known after the loop.
+            let mut captured_quic_packets = SmallVec::<[std::ops::Range<usize>; 4]>::new();
+            let src = meta.addr;
 
             // find disco and stun packets and forward them to the actor
             loop {
@@ -503,12 +596,14 @@ impl Inner {
                 let packet = &buf[start..end];
                 let packet_is_quic = if stun::is(packet) {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: stun packet");
+                    self.capture_udp(Direction::Incoming, src, None, [packet]);
                     let packet2 = Bytes::copy_from_slice(packet);
                     self.net_checker.receive_stun_packet(packet2, meta.addr);
                     false
                 } else if let Some((sender, sealed_box)) = disco::source_and_box(packet) {
                     // Disco?
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: disco packet");
+                    self.capture_udp(Direction::Incoming, src, Some(sender), [packet]);
                     self.handle_disco_message(
                         sender,
                         sealed_box,
@@ -522,7 +617,11 @@ impl Inner {
 
                 if packet_is_quic {
                     quic_packets_count += 1;
+                    quic_bytes += (end - start) as u64;
                     is_quic = true;
+                    if self.capture.is_some() {
+                        captured_quic_packets.push(start..end);
+                    }
                 } else {
                     // overwrite the first byte of the packets with zero.
                     // this makes quinn reliably and quickly ignore the packet as long as
@@ -534,18 +633,34 @@ impl Inner {
 
             if is_quic {
                 // remap addr
-                match self.node_map.receive_udp(meta.addr) {
+                let node = match self.node_map.receive_udp(meta.addr) {
                     None => {
                         warn!(src = ?meta.addr, count = %quic_packets_count, len = meta.len, "UDP recv quic packets: no node state found, skipping");
                         // if we have no node state for the from addr, set len to 0 to make quinn skip the buf completely.
                         meta.len = 0;
+                        None
                     }
                     Some((node_id, quic_mapped_addr)) => {
                         trace!(src = ?meta.addr, node = %node_id.fmt_short(), count = %quic_packets_count, len = meta.len, "UDP recv quic packets");
                         quic_packets_total += quic_packets_count;
                         meta.addr = quic_mapped_addr.0;
+                        self.node_map.record_recv(
+                            &node_id,
+                            &SendAddr::Udp(src),
+                            quic_packets_count,
+                            quic_bytes,
+                        );
+                        Some(node_id)
                     }
-                }
+                };
+                self.capture_udp(
+                    Direction::Incoming,
+                    src,
+                    node,
+                    captured_quic_packets
+                        .iter()
+                        .map(|range| &buf[range.clone()]),
+                );
             } else {
                 // if there is no non-stun,non-disco packet in the chunk, set len to zero to make
                 // quinn skip the buf completely.
@@ -848,7 +963,7 @@ impl Inner {
             segment_size: None,
             src_ip: None, // TODO
         }];
-        let sent = ready!(self.poll_send_udp(dst, &transmits, cx));
+        let sent = ready!(self.poll_send_udp(dst_key, dst, &transmits, cx));
         Poll::Ready(match sent {
             Ok(0) => {
                 // Can't send. (e.g. no IPv6 locally)
@@ -912,6 +1027,7 @@ impl Inner {
 
     fn try_send_derp(&self, url: &Url, node: PublicKey, contents: DerpContents) -> bool {
         trace!(node = %node.fmt_short(), derp_url = %url, count = contents.len(), len = contents.iter().map(|c| c.len()).sum::<usize>(), "send derp");
+        let captured = self.capture.is_some().then(|| contents.clone());
         let msg = DerpActorMessage::Send {
             url: url.clone(),
             contents,
@@ -920,6 +1036,14 @@ impl Inner {
         match self.derp_actor_sender.try_send(msg) {
             Ok(_) => {
                 trace!(node = %node.fmt_short(), derp_url = %url, "send derp: message queued");
+                if let Some(packets) = captured {
+                    self.capture_derp(
+                        Direction::Outgoing,
+                        url,
+                        node,
+                        packets.iter().map(|p| &p[..]),
+                    );
+                }
                 true
             }
             Err(mpsc::error::TrySendError::Closed(_)) => {
@@ -1079,15 +1203,18 @@ impl MagicSock {
     }
 
     async fn with_name(me: String, opts: Options) -> Result<Self> {
-        let port_mapper = portmapper::Client::default();
-
         let Options {
             port,
+            bind_config,
             secret_key,
             derp_map,
             discovery,
             nodes_path,
+            udp_binder,
+            capture_path,
+            portmapper_config,
         } = opts;
+        let port_mapper = portmapper::Client::new(portmapper_config);
 
         let nodes_path = match nodes_path {
             Some(path) => {
@@ -1103,11 +1230,17 @@ impl MagicSock {
 
         let (derp_recv_sender, derp_recv_receiver) = flume::bounded(128);
 
-        let (pconn4, pconn6) = bind(port)?;
+        let capture = capture_path
+            .as_deref()
+            .map(PacketCapture::create)
+            .transpose()?;
+        bind_config.validate()?;
+        let (pconn4, pconn6) = bind(&bind_config, udp_binder.as_ref(), port)?;
         let port = pconn4.port();
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
         match port.try_into() {
+            Ok(_) if udp_binder.is_some() => debug!("Skipping port mapping for custom sockets"),
             Ok(non_zero_port) => {
                 port_mapper.update_local_port(non_zero_port);
             }
@@ -1116,7 +1249,8 @@ impl MagicSock {
         let ipv4_addr = pconn4.local_addr()?;
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
-        let net_checker = netcheck::Client::new(Some(port_mapper.clone()))?;
+        let net_checker =
+            netcheck::Client::with_udp_binder(Some(port_mapper.clone()), udp_binder.clone())?;
 
         let (actor_sender, actor_receiver) = mpsc::channel(256);
         let (derp_actor_sender, derp_actor_receiver) = mpsc::channel(256);
@@ -1151,6 +1285,7 @@ impl MagicSock {
             network_send_wakers: parking_lot::Mutex::new(None),
             actor_sender: actor_sender.clone(),
             ipv6_reported: Arc::new(AtomicBool::new(false)),
+            captive_portal: sync::watch::channel(false).0,
             derp_map,
             my_derp: Default::default(),
             pconn4: pconn4.clone(),
@@ -1166,6 +1301,7 @@ impl MagicSock {
             endpoints: Watchable::new(Default::default()),
             pending_call_me_maybes: Default::default(),
             endpoints_update_state: EndpointUpdateState::new(),
+            capture,
         });
 
         let mut actor_tasks = JoinSet::default();
@@ -1205,6 +1341,7 @@ impl MagicSock {
                     port_mapper,
                     pconn4,
                     pconn6,
+                    bind_config,
                     no_v4_send: false,
                     net_checker,
                     network_monitor,
@@ -1236,6 +1373,72 @@ impl MagicSock {
         Ok(res)
     }
 
+    /// Returns a stream of [`PathEvent`]s for all nodes and our own home DERP.
+    ///
+    /// Subscribers which fall too far behind miss events, they can catch up using
+    /// [`MagicSock::tracked_endpoints`].
+    pub fn path_events(&self) -> impl Stream<Item = PathEvent> + Send + Unpin + 'static {
+        let events = self.inner.node_map.subscribe_path_events();
+        Box::pin(futures::stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(sync::broadcast::error::RecvError::Lagged(n)) => {
+                        debug!("path events subscriber lagged, missed {n} events");
+                    }
+                    Err(sync::broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Returns a stream of the [`ConnectionType`]s to a node.
+    ///
+    /// The stream starts with the current connection type, [`ConnectionType::None`] if the
+    /// node is unknown, and yields a new item every time it changes.
+    pub fn watch_connection_type(
+        &self,
+        node_id: PublicKey,
+    ) -> impl Stream<Item = ConnectionType> + Send + Unpin + 'static {
+        // Subscribe before reading the current value, so no change is missed.
+        let events = self.inner.node_map.subscribe_path_events();
+        let inner = self.inner.clone();
+        let current_conn_type = move |inner: &Arc<Inner>| {
+            inner
+                .node_map
+                .endpoint_info(&node_id)
+                .map(|info| info.conn_type)
+                .unwrap_or(ConnectionType::None)
+        };
+        let initial = current_conn_type(&inner);
+        let state = (events, inner, None::<ConnectionType>, Some(initial));
+        Box::pin(futures::stream::unfold(
+            state,
+            move |(mut events, inner, mut last, mut next)| async move {
+                loop {
+                    if let Some(conn_type) = next.take() {
+                        if last.as_ref() != Some(&conn_type) {
+                            last = Some(conn_type.clone());
+                            return Some((conn_type, (events, inner, last, next)));
+                        }
+                    }
+                    match events.recv().await {
+                        Ok(PathEvent::ConnectionTypeChanged {
+                            node_id: id,
+                            conn_type,
+                        }) if id == node_id => next = Some(conn_type),
+                        Ok(_) => {}
+                        Err(sync::broadcast::error::RecvError::Lagged(_)) => {
+                            // We might have missed a change, read the current value instead.
+                            next = Some(current_conn_type(&inner));
+                        }
+                        Err(sync::broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+
     /// Retrieve connection information about a node in the network.
     pub async fn tracked_endpoint(&self, node_key: PublicKey) -> Result<Option<EndpointInfo>> {
         let (s, r) = sync::oneshot::channel();
@@ -1309,6 +1512,13 @@ impl MagicSock {
         r.await.unwrap();
     }
 
+    /// Returns the most recent netcheck reports with the time they were generated.
+    ///
+    /// The reports are ordered oldest first.
+    pub async fn netcheck_history(&self) -> Result<Vec<(SystemTime, Arc<netcheck::Report>)>> {
+        self.inner.net_checker.history().await
+    }
+
     /// Returns the DERP node with the best latency.
     ///
     /// If `None`, then we currently have no verified connection to a DERP node.
@@ -1354,6 +1564,9 @@ impl MagicSock {
             debug!("aborting remaining {}/3 tasks", tasks.len());
             tasks.shutdown().await;
         }
+        if let Some(ref capture) = self.inner.capture {
+            capture.close().await;
+        }
 
         Ok(())
     }
@@ -1520,6 +1733,11 @@ enum ActorMessage {
     EndpointPingExpired(usize, stun::TransactionId),
     NetcheckReport(Result<Option<Arc<netcheck::Report>>>, &'static str),
     NetworkChange,
+    /// A restarting derp server at `from` asked us to move to `to`.
+    DerpRedirect {
+        from: Url,
+        to: Url,
+    },
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -1542,6 +1760,8 @@ struct Actor {
     // The underlying UDP sockets used to send/rcv packets.
     pconn4: RebindingUdpConn,
     pconn6: Option<RebindingUdpConn>,
+    /// Constraints on the addresses the sockets are bound to, applied again on rebind.
+    bind_config: BindConfig,
 
     /// The NAT-PMP/PCP/UPnP prober/client, for requesting port mappings from NAT devices.
     port_mapper: portmapper::Client,
@@ -1579,6 +1799,7 @@ impl Actor {
         );
         let mut endpoints_update_receiver = self.inner.endpoints_update_state.running.subscribe();
         let mut portmap_watcher = self.port_mapper.watch_external_address();
+        let mut pinhole_watcher = self.port_mapper.watch_pinhole_address();
         let mut save_nodes_timer = if self.nodes_path.is_some() {
             tokio::time::interval_at(
                 time::Instant::now() + SAVE_NODES_INTERVAL,
@@ -1606,6 +1827,12 @@ impl Actor {
                     debug!("external address updated: {new_external_address:?}");
                     self.inner.re_stun("portmap_updated");
                 },
+                Ok(()) = pinhole_watcher.changed() => {
+                    trace!("tick: pinhole changed");
+                    let new_pinhole_address = *pinhole_watcher.borrow();
+                    debug!("pinhole address updated: {new_pinhole_address:?}");
+                    self.inner.re_stun("pinhole_updated");
+                },
                 _ = endpoint_heartbeat_timer.tick() => {
                     trace!("tick: endpoint heartbeat {} endpoints", self.inner.node_map.node_count());
                     // TODO: this might trigger too many packets at once, pace this
@@ -1749,6 +1976,15 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::DerpRedirect { from, to } => {
+                // Only move if the restarting server is our home, and we know the new one.
+                if self.inner.my_derp().as_ref() == Some(&from)
+                    && self.inner.derp_map.get_node(&to).is_some()
+                {
+                    info!(%from, %to, "home derp restarting, moving to redirect");
+                    self.set_nearest_derp(Some(to));
+                }
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
@@ -1796,9 +2032,13 @@ impl Actor {
         let dst_ip = self.normalized_local_addr().ok().map(|addr| addr.ip());
 
         let mut out = Vec::new();
+        let mut quic_packets_count = 0;
+        let mut quic_bytes = 0;
         for part in parts {
             match part {
                 Ok(part) => {
+                    self.inner
+                        .capture_derp(Direction::Incoming, url, dm.src, [&part[..]]);
                     if self.handle_derp_disco_message(&part, url, dm.src) {
                         // Message was internal, do not bubble up.
                         debug!(node = %dm.src.fmt_short(), "handled disco message from derp");
@@ -1812,6 +2052,8 @@ impl Actor {
                         dst_ip,
                         ecn: None,
                     };
+                    quic_packets_count += 1;
+                    quic_bytes += part.len() as u64;
                     out.push(Ok((dm.src, meta, part)));
                 }
                 Err(e) => {
@@ -1819,6 +2061,14 @@ impl Actor {
                 }
             }
         }
+        if quic_packets_count > 0 {
+            self.inner.node_map.record_recv(
+                &dm.src,
+                &SendAddr::Derp(url.clone()),
+                quic_packets_count,
+                quic_bytes,
+            );
+        }
 
         out
     }
@@ -1870,6 +2120,15 @@ impl Actor {
             self.set_net_info_have_port_map().await;
         }
 
+        let maybe_pinhole = *self.port_mapper.watch_pinhole_address().borrow();
+        if let Some(pinhole) = maybe_pinhole.map(SocketAddr::V6) {
+            add_addr!(already, eps, pinhole, config::EndpointType::Portmapped);
+        }
+
+        for addr in self.port_mapper.static_external_addrs() {
+            add_addr!(already, eps, *addr, config::EndpointType::Static);
+        }
+
         if let Some(nr) = nr {
             if let Some(global_v4) = nr.global_v4 {
                 add_addr!(already, eps, global_v4, config::EndpointType::Stun);
@@ -1902,7 +2161,7 @@ impl Actor {
         let LocalAddresses {
             regular: mut ips,
             loopback,
-        } = LocalAddresses::new();
+        } = LocalAddresses::with_filter(|name| !self.bind_config.is_excluded(name));
 
         if is_unspecified_v4 || is_unspecified_v6 {
             if ips.is_empty() && eps.is_empty() {
@@ -2053,13 +2312,18 @@ impl Actor {
         }
 
         let derp_map = self.inner.derp_map.clone();
-        let pconn4 = Some(self.pconn4.as_socket());
-        let pconn6 = self.pconn6.as_ref().map(|p| p.as_socket());
+        // STUN responses are recorded on receive, the requests when netcheck sends them.
+        let capture = self.inner.capture.clone();
+        let pconn4 = self.pconn4.stun_socket().with_capture(capture.clone());
+        let pconn6 = self
+            .pconn6
+            .as_ref()
+            .map(|p| p.stun_socket().with_capture(capture));
 
         debug!("requesting netcheck report");
         match self
             .net_checker
-            .get_report_channel(derp_map, pconn4, pconn6)
+            .run_check(derp_map, Some(pconn4), pconn6)
             .await
         {
             Ok(rx) => {
@@ -2090,6 +2354,16 @@ impl Actor {
             self.inner
                 .ipv6_reported
                 .store(report.ipv6, Ordering::Relaxed);
+            if let Some(captive_portal) = report.captive_portal {
+                let changed = self.inner.captive_portal.send_if_modified(|active| {
+                    std::mem::replace(active, captive_portal) != captive_portal
+                });
+                if changed && captive_portal {
+                    warn!("captive portal detected, backing off derp reconnects");
+                } else if changed {
+                    info!("captive portal cleared");
+                }
+            }
             let r = &report;
             debug!(
                 "setting no_v4_send {} -> {}",
@@ -2140,6 +2414,11 @@ impl Actor {
             return true;
         }
         self.inner.set_my_derp(derp_url.clone());
+        self.inner
+            .node_map
+            .send_path_event(PathEvent::HomeDerpChanged {
+                url: derp_url.clone(),
+            });
 
         if let Some(ref derp_url) = derp_url {
             inc!(MagicsockMetrics, derp_home_change);
@@ -2217,21 +2496,31 @@ impl Actor {
             let port = conn.port();
             trace!("IPv6 rebind {} {:?}", port, cur_port_fate);
             // If we were not able to bind ipv6 at program start, dont retry
-            if let Err(err) = conn.rebind(port, IpFamily::V6, cur_port_fate) {
-                info!("rebind ignoring IPv6 bind failure: {:?}", err);
-            } else {
-                ipv6_addr = conn.local_addr().ok();
+            match self.bind_config.resolve(IpFamily::V6) {
+                Ok(Some(ip)) => {
+                    if let Err(err) = conn.rebind(SocketAddr::new(ip, port), cur_port_fate) {
+                        info!("rebind ignoring IPv6 bind failure: {:?}", err);
+                    } else {
+                        ipv6_addr = conn.local_addr().ok();
+                    }
+                }
+                Ok(None) => info!("rebind ignoring IPv6: no address to bind to"),
+                Err(err) => info!("rebind ignoring IPv6 bind failure: {:?}", err),
             }
         }
 
         let port = self.local_port_v4();
+        let ip = self.bind_config.resolve_rebind_v4();
         self.pconn4
-            .rebind(port, IpFamily::V4, cur_port_fate)
+            .rebind(SocketAddr::new(ip, port), cur_port_fate)
             .context("rebind IPv4 failed")?;
 
         // reread, as it might have changed
         // we can end up with a zero port if std::net::UdpSocket::socket_addr fails
         match self.local_port_v4().try_into() {
+            Ok(_) if self.pconn4.as_socket().is_none() => {
+                debug!("Skipping port mapping for custom sockets")
+            }
             Ok(non_zero_port) => self.port_mapper.update_local_port(non_zero_port),
             Err(_zero_port) => {
                 // since the local port might still be the same, don't deactivate port mapping
@@ -2321,13 +2610,33 @@ fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
 }
 
 /// Initial connection setup.
-fn bind(port: u16) -> Result<(RebindingUdpConn, Option<RebindingUdpConn>)> {
-    let pconn4 = RebindingUdpConn::bind(port, IpFamily::V4).context("bind IPv4 failed")?;
+fn bind(
+    bind_config: &BindConfig,
+    udp_binder: Option<&Arc<dyn UdpBinder>>,
+    port: u16,
+) -> Result<(RebindingUdpConn, Option<RebindingUdpConn>)> {
+    let ip4 = bind_config
+        .resolve(IpFamily::V4)?
+        .context("no IPv4 address to bind to")?;
+    let pconn4 = RebindingUdpConn::bind(SocketAddr::new(ip4, port), udp_binder.cloned())
+        .context("bind IPv4 failed")?;
     let ip4_port = pconn4.local_addr()?.port();
     let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
 
-    let pconn6 = match RebindingUdpConn::bind(ip6_port, IpFamily::V6) {
-        Ok(conn) => Some(conn),
+    let pconn6 = match bind_config.resolve(IpFamily::V6) {
+        Ok(Some(ip6)) => {
+            match RebindingUdpConn::bind(SocketAddr::new(ip6, ip6_port), udp_binder.cloned()) {
+                Ok(conn) => Some(conn),
+                Err(err) => {
+                    info!("bind ignoring IPv6 bind failure: {:?}", err);
+                    None
+                }
+            }
+        }
+        Ok(None) => {
+            info!("bind ignoring IPv6: no address to bind to");
+            None
+        }
         Err(err) => {
             info!("bind ignoring IPv6 bind failure: {:?}", err);
             None
@@ -3321,7 +3630,7 @@ pub(crate) mod tests {
 
         fn make_conn(addr: SocketAddr) -> anyhow::Result<quinn::Endpoint> {
             let key = SecretKey::generate();
-            let conn = RebindingUdpConn::bind(addr.port(), addr.ip().into())?;
+            let conn = RebindingUdpConn::bind(addr, None)?;
 
             let tls_server_config = tls::make_server_config(&key, vec![ALPN.to_vec()], false)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
@@ -0,0 +1,181 @@
+//! Constraints on the local addresses the magicsock binds to and advertises.
+
+use std::net::IpAddr;
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::net::{ip::is_link_local, IpFamily};
+
+/// Which local addresses and interfaces a [`super::MagicSock`] uses.
+///
+/// By default the sockets listen on the unspecified address and every local interface is
+/// advertised as a direct address.  The constraints are resolved again whenever the sockets
+/// are rebound after a network change, so an interface which changed its address is followed.
+/// If the interface has no IPv4 address left at that point the IPv4 socket falls back to the
+/// unspecified address until a later rebind finds one again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindConfig {
+    /// Explicit IP addresses to bind to, at most one per address family.
+    ///
+    /// An IPv4 address is required when this is not empty, IPv6 is only bound if an IPv6
+    /// address is given.
+    pub addrs: Vec<IpAddr>,
+    /// Name of the interface to bind to, ignored if [`BindConfig::addrs`] is not empty.
+    ///
+    /// The sockets are bound to the first address of each family of the interface.
+    pub interface: Option<String>,
+    /// Interfaces whose addresses are never advertised as local endpoints.
+    ///
+    /// A pattern ending in `*` matches all interfaces starting with the prefix before it,
+    /// e.g. `docker*`.
+    pub exclude_interfaces: Vec<String>,
+}
+
+impl BindConfig {
+    /// Binds to the given IP addresses.
+    pub fn addrs(addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self {
+            addrs: addrs.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Binds to the addresses of the named interface.
+    pub fn interface(name: impl Into<String>) -> Self {
+        Self {
+            interface: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Excludes interfaces matching `pattern` from the advertised local endpoints.
+    pub fn exclude_interface(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude_interfaces.push(pattern.into());
+        self
+    }
+
+    /// Whether the addresses of the interface called `name` are excluded from advertising.
+    pub fn is_excluded(&self, name: &str) -> bool {
+        self.exclude_interfaces
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// Checks the configuration without looking at the interfaces of this machine.
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.addrs.is_empty() {
+            return Ok(());
+        }
+        let v4 = self.addrs.iter().filter(|ip| ip.is_ipv4()).count();
+        let v6 = self.addrs.len() - v4;
+        if v4 != 1 || v6 > 1 {
+            bail!(
+                "expected one IPv4 and at most one IPv6 bind address, got {:?}",
+                self.addrs
+            );
+        }
+        Ok(())
+    }
+
+    /// Resolves the IP address to bind to for the `network` family.
+    ///
+    /// Returns `None` if the constraints leave no address of this family to bind to.
+    pub(super) fn resolve(&self, network: IpFamily) -> Result<Option<IpAddr>> {
+        if !self.addrs.is_empty() {
+            return Ok(self
+                .addrs
+                .iter()
+                .find(|ip| IpFamily::from(**ip) == network)
+                .copied());
+        }
+        let Some(ref name) = self.interface else {
+            return Ok(Some(network.unspecified_addr()));
+        };
+        let Some(iface) = default_net::interface::get_interfaces()
+            .into_iter()
+            .find(|iface| &iface.name == name)
+        else {
+            bail!("interface {name} not found");
+        };
+        let addrs = iface
+            .ipv4
+            .iter()
+            .map(|a| IpAddr::V4(a.addr))
+            .chain(iface.ipv6.iter().map(|a| IpAddr::V6(a.addr)))
+            .filter(|ip| IpFamily::from(*ip) == network)
+            .collect::<Vec<_>>();
+        // Prefer routable addresses, IPv6 link local addresses need a scope id to be bound.
+        let ip = addrs
+            .iter()
+            .find(|ip| !is_link_local(**ip))
+            .or_else(|| addrs.iter().find(|ip| ip.is_ipv4()))
+            .copied();
+        Ok(ip)
+    }
+
+    /// Resolves the IPv4 address to rebind to after a network change.
+    ///
+    /// Unlike the initial bind this never fails: if the interface is gone or lost its IPv4
+    /// address the unspecified address is used, so the node stays reachable.
+    pub(super) fn resolve_rebind_v4(&self) -> IpAddr {
+        match self.resolve(IpFamily::V4) {
+            Ok(Some(ip)) => ip,
+            Ok(None) => {
+                warn!("no IPv4 address to bind to, falling back to the unspecified address");
+                IpFamily::V4.unspecified_addr()
+            }
+            Err(err) => {
+                warn!("{err:#}, falling back to the unspecified IPv4 address");
+                IpFamily::V4.unspecified_addr()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_bind_config() {
+        let config = BindConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.resolve(IpFamily::V4).unwrap(),
+            Some(Ipv4Addr::UNSPECIFIED.into())
+        );
+        assert_eq!(
+            config.resolve(IpFamily::V6).unwrap(),
+            Some(Ipv6Addr::UNSPECIFIED.into())
+        );
+
+        let config = BindConfig::addrs([Ipv4Addr::LOCALHOST.into()]);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.resolve(IpFamily::V4).unwrap(),
+            Some(Ipv4Addr::LOCALHOST.into())
+        );
+        assert_eq!(config.resolve(IpFamily::V6).unwrap(), None);
+
+        let config = BindConfig::addrs([Ipv6Addr::LOCALHOST.into()]);
+        assert!(config.validate().is_err());
+
+        let config = BindConfig::interface("does-not-exist");
+        assert!(config.resolve(IpFamily::V4).is_err());
+        assert_eq!(config.resolve_rebind_v4(), Ipv4Addr::UNSPECIFIED);
+
+        let config = BindConfig::default()
+            .exclude_interface("docker*")
+            .exclude_interface("wg0");
+        assert!(config.is_excluded("docker0"));
+        assert!(config.is_excluded("wg0"));
+        assert!(!config.is_excluded("wg1"));
+        assert!(!config.is_excluded("eth0"));
+    }
+}
@@ -0,0 +1,509 @@
+//! Records the packets a [`super::MagicSock`] sends and receives into a pcapng file.
+//!
+//! Every packet is written as a raw IP packet with synthesized IP and UDP headers, so
+//! Wireshark dissects the QUIC packets inside and can decrypt them using the TLS keylog,
+//! see [`crate::magic_endpoint::MagicEndpointBuilder::keylog`].  Packets relayed by a derp
+//! server have no real addresses, they are written between the unspecified IPv6 address and
+//! the address QUIC uses for the node.  Each packet carries a comment naming the packet
+//! kind, the node and the path it took.
+//!
+//! The frames exchanged with derp servers, including the relayed packets, are recorded as
+//! they are sent on the connection (before TLS) on a second interface named `derp`.
+//!
+//! Packets are handed to a writer thread, if it can not keep up packets are dropped rather
+//! than slowing down the magicsock.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::derp::{FrameDirection, FrameTap, FrameType};
+use crate::{disco, key::PublicKey, stun};
+
+/// How many packets may be queued for the writer thread before packets are dropped.
+const CAPTURE_QUEUE_LEN: usize = 4096;
+
+/// `LINKTYPE_RAW`, packets start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u16 = 101;
+/// `LINKTYPE_USER0`, used for the derp frames which have no registered link type.
+const LINKTYPE_USER0: u16 = 147;
+
+/// Interface of the UDP packets, with synthesized IP and UDP headers.
+const INTERFACE_UDP: u32 = 0;
+/// Interface of the derp frames.
+const INTERFACE_DERP: u32 = 1;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Whether a packet was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// The path a captured packet took.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum CapturePath<'a> {
+    /// Sent directly over one of our UDP sockets.
+    Udp {
+        /// The address of our socket.
+        local: SocketAddr,
+        /// The address of the other side.
+        remote: SocketAddr,
+    },
+    /// Relayed by a derp server.
+    Derp {
+        /// The derp server.
+        url: &'a Url,
+        /// The address QUIC uses for the node, if known.
+        mapped_addr: Option<SocketAddr>,
+    },
+}
+
+impl fmt::Display for CapturePath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapturePath::Udp { remote, .. } => write!(f, "udp {remote}"),
+            CapturePath::Derp { url, .. } => write!(f, "derp {url}"),
+        }
+    }
+}
+
+/// Writes captured packets into a pcapng file.
+///
+/// Clones write into the same file.
+#[derive(Debug, Clone)]
+pub(crate) struct PacketCapture {
+    sender: flume::Sender<Message>,
+}
+
+#[derive(Debug)]
+enum Message {
+    Packet(Record),
+    Close(oneshot::Sender<()>),
+}
+
+/// A single captured packet.
+#[derive(Debug)]
+struct Record {
+    time: SystemTime,
+    direction: Direction,
+    /// The addresses of a UDP packet, `None` for a derp frame.
+    addrs: Option<(SocketAddr, SocketAddr)>,
+    comment: String,
+    payload: Bytes,
+}
+
+impl PacketCapture {
+    /// Creates the capture file at `path` and starts the writer thread.
+    pub(super) fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create capture file {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        write_section_header(&mut writer)?;
+        write_interface_description(&mut writer, LINKTYPE_RAW, "magicsock")?;
+        write_interface_description(&mut writer, LINKTYPE_USER0, "derp")?;
+        writer.flush()?;
+
+        let (sender, receiver) = flume::bounded(CAPTURE_QUEUE_LEN);
+        let path = path.to_owned();
+        std::thread::Builder::new()
+            .name("magicsock-capture".into())
+            .spawn(move || {
+                if let Err(err) = run_writer(writer, receiver) {
+                    warn!(path = %path.display(), "packet capture failed: {err:#}");
+                }
+            })?;
+        Ok(Self { sender })
+    }
+
+    /// Records packets sent or received by the magicsock.
+    ///
+    /// The packets are classified as STUN, disco or QUIC by their contents.
+    pub(super) fn record<'a>(
+        &self,
+        direction: Direction,
+        path: CapturePath<'_>,
+        node: Option<PublicKey>,
+        packets: impl IntoIterator<Item = &'a [u8]>,
+    ) {
+        let time = SystemTime::now();
+        let (local, remote) = match path {
+            CapturePath::Udp { local, remote } => (local, remote),
+            CapturePath::Derp { mapped_addr, .. } => {
+                let unspecified = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+                (unspecified, mapped_addr.unwrap_or(unspecified))
+            }
+        };
+        let direction_name = match direction {
+            Direction::Incoming => "from",
+            Direction::Outgoing => "to",
+        };
+        let node = node.map_or_else(String::new, |node| {
+            format!(" {direction_name} node {}", node.fmt_short())
+        });
+        for packet in packets {
+            let kind = if stun::is(packet) {
+                "stun"
+            } else if disco::looks_like_disco_wrapper(packet) {
+                "disco"
+            } else {
+                "quic"
+            };
+            self.send(Record {
+                time,
+                direction,
+                addrs: Some((local, remote)),
+                comment: format!("{kind}{node} via {path}"),
+                payload: Bytes::copy_from_slice(packet),
+            });
+        }
+    }
+
+    /// Records a STUN request netcheck sent on one of the sockets of the magicsock.
+    pub(crate) fn record_stun_sent(&self, local: SocketAddr, remote: SocketAddr, packet: &[u8]) {
+        let path = CapturePath::Udp { local, remote };
+        self.record(Direction::Outgoing, path, None, [packet]);
+    }
+
+    /// Returns a [`FrameTap`] recording the frames exchanged with the derp server at `url`.
+    pub(super) fn derp_frame_tap(&self, url: &Url) -> FrameTap {
+        let capture = self.clone();
+        let url = url.clone();
+        Arc::new(move |direction, typ, frame| {
+            let (direction, direction_name) = match direction {
+                FrameDirection::Read => (Direction::Incoming, "from"),
+                FrameDirection::Written => (Direction::Outgoing, "to"),
+            };
+            // The node a packet is relayed to or from follows the frame header.
+            let node = match typ {
+                FrameType::SendPacket | FrameType::RecvPacket => frame
+                    .get(5..37)
+                    .and_then(|key| PublicKey::try_from(key).ok())
+                    .map(|node| format!(" node {}", node.fmt_short())),
+                _ => None,
+            };
+            capture.send(Record {
+                time: SystemTime::now(),
+                direction,
+                addrs: None,
+                comment: format!(
+                    "derp {typ}{} {direction_name} {url}",
+                    node.unwrap_or_default()
+                ),
+                payload: Bytes::copy_from_slice(frame),
+            });
+        })
+    }
+
+    fn send(&self, record: Record) {
+        if self.sender.try_send(Message::Packet(record)).is_err() {
+            debug!("packet capture queue full, dropping packet");
+        }
+    }
+
+    /// Writes all queued packets and closes the capture file.
+    ///
+    /// Packets recorded afterwards are ignored.
+    pub(super) async fn close(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.sender.send_async(Message::Close(tx)).await.is_ok() {
+            rx.await.ok();
+        }
+    }
+}
+
+fn run_writer(mut writer: BufWriter<File>, receiver: flume::Receiver<Message>) -> io::Result<()> {
+    while let Ok(msg) = receiver.recv() {
+        match msg {
+            Message::Packet(record) => {
+                write_enhanced_packet(&mut writer, &record)?;
+                // Keep the file usable while capturing, without flushing every packet.
+                if receiver.is_empty() {
+                    writer.flush()?;
+                }
+            }
+            Message::Close(done) => {
+                writer.flush()?;
+                done.send(()).ok();
+                break;
+            }
+        }
+    }
+    writer.flush()
+}
+
+fn write_section_header(w: &mut impl Write) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // The section length is not known in advance.
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    let user_appl = format!("iroh-net {}", env!("CARGO_PKG_VERSION"));
+    push_option(&mut body, OPT_SHB_USERAPPL, user_appl.as_bytes());
+    push_option(&mut body, OPT_END, &[]);
+    write_block(w, BLOCK_SECTION_HEADER, &body)
+}
+
+fn write_interface_description(w: &mut impl Write, link_type: u16, name: &str) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&link_type.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snap length limit.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+    push_option(&mut body, OPT_END, &[]);
+    write_block(w, BLOCK_INTERFACE_DESCRIPTION, &body)
+}
+
+fn write_enhanced_packet(w: &mut impl Write, record: &Record) -> io::Result<()> {
+    let (interface, packet) = match record.addrs {
+        Some((local, remote)) => {
+            let (src, dst) = match record.direction {
+                Direction::Incoming => (remote, local),
+                Direction::Outgoing => (local, remote),
+            };
+            (INTERFACE_UDP, ip_udp_packet(src, dst, &record.payload))
+        }
+        None => (INTERFACE_DERP, record.payload.to_vec()),
+    };
+    // Default timestamp resolution of pcapng is microseconds.
+    let micros = record
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let flags: u32 = match record.direction {
+        Direction::Incoming => 0b01,
+        Direction::Outgoing => 0b10,
+    };
+
+    let mut body = Vec::with_capacity(packet.len() + record.comment.len() + 48);
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&packet);
+    pad(&mut body);
+    push_option(&mut body, OPT_COMMENT, record.comment.as_bytes());
+    push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    push_option(&mut body, OPT_END, &[]);
+    write_block(w, BLOCK_ENHANCED_PACKET, &body)
+}
+
+/// Writes a block, `body` must already be padded to 32 bits.
+fn write_block(w: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    w.write_all(&block_type.to_le_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&len.to_le_bytes())
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+/// Wraps `payload` in UDP and IP headers.
+///
+/// If the addresses are of different families both are written as IPv6.  The UDP checksum
+/// is left empty, which Wireshark does not verify by default.
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = payload.len() + 8;
+    let mut packet = Vec::with_capacity(udp_len + 40);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = u16::try_from(udp_len + 20).unwrap_or(u16::MAX);
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            // Identification, don't fragment flag, TTL 64 and protocol UDP.
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let payload_len = u16::try_from(udp_len).unwrap_or(u16::MAX);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&payload_len.to_be_bytes());
+            // Next header UDP and hop limit 64.
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&to_v6(src_ip).octets());
+            packet.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&u16::try_from(udp_len).unwrap_or(u16::MAX).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Splits a pcapng file into its blocks.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&rest[4..8], &rest[len - 4..len]);
+            blocks.push((block_type, &rest[8..len - 4]));
+            rest = &rest[len..];
+        }
+        blocks
+    }
+
+    #[tokio::test]
+    async fn test_packet_capture() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("capture.pcapng");
+        let capture = PacketCapture::create(&path)?;
+
+        let local: SocketAddr = (Ipv4Addr::new(192, 168, 1, 2), 1234).into();
+        let remote: SocketAddr = (Ipv4Addr::new(10, 0, 0, 1), 4321).into();
+        let node = crate::key::SecretKey::generate().public();
+        capture.record(
+            Direction::Outgoing,
+            CapturePath::Udp { local, remote },
+            Some(node),
+            [&b"hello"[..], &b"world"[..]],
+        );
+        let url: Url = "https://derp.example.com".parse()?;
+        let mapped_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 12345);
+        capture.record(
+            Direction::Incoming,
+            CapturePath::Derp {
+                url: &url,
+                mapped_addr: Some(mapped_addr),
+            },
+            Some(node),
+            [&b"relayed"[..]],
+        );
+        let tap = capture.derp_frame_tap(&url);
+        tap(
+            FrameDirection::Written,
+            FrameType::KeepAlive,
+            &[6, 0, 0, 0, 0],
+        );
+        let stun_request = stun::request(stun::TransactionId::default());
+        capture.record_stun_sent(local, remote, &stun_request);
+        capture.close().await;
+
+        let data = std::fs::read(&path)?;
+        let blocks = blocks(&data);
+        let types: Vec<_> = blocks.iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            types,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+            ]
+        );
+
+        // An outgoing IPv4 packet, from the local address.
+        let body = blocks[3].1;
+        let cap_len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let packet = &body[20..20 + cap_len];
+        assert_eq!(cap_len, 20 + 8 + 5);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[12..16], &[192, 168, 1, 2]);
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 1234);
+        assert_eq!(&packet[28..], b"hello");
+        let comment = format!("quic to node {} via udp {remote}", node.fmt_short());
+        assert!(body.windows(comment.len()).any(|w| w == comment.as_bytes()));
+
+        // An incoming relayed packet, from the mapped address of the node.
+        let body = blocks[5].1;
+        let cap_len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        let packet = &body[20..20 + cap_len];
+        assert_eq!(cap_len, 40 + 8 + 7);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[8..24], &Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(&packet[48..], b"relayed");
+        let comment = format!("quic from node {} via derp {url}", node.fmt_short());
+        assert!(body.windows(comment.len()).any(|w| w == comment.as_bytes()));
+
+        // A derp frame, as is on the derp interface.
+        let body = blocks[6].1;
+        assert_eq!(
+            u32::from_le_bytes(body[0..4].try_into().unwrap()),
+            INTERFACE_DERP
+        );
+        let cap_len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        assert_eq!(&body[20..20 + cap_len], &[6, 0, 0, 0, 0]);
+        let comment = format!("derp KeepAlive to {url}");
+        assert!(body.windows(comment.len()).any(|w| w == comment.as_bytes()));
+
+        // A STUN request sent by netcheck.
+        let body = blocks[7].1;
+        assert_eq!(
+            u32::from_le_bytes(body[0..4].try_into().unwrap()),
+            INTERFACE_UDP
+        );
+        let comment = format!("stun via udp {remote}");
+        assert!(body.windows(comment.len()).any(|w| w == comment.as_bytes()));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{BTreeMap, HashSet},
     net::{IpAddr, SocketAddr},
+    pin::Pin,
     sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
@@ -11,7 +12,7 @@ use bytes::{Bytes, BytesMut};
 use futures::Future;
 use iroh_metrics::{inc, inc_by};
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{mpsc, oneshot, watch},
     task::{JoinHandle, JoinSet},
     time,
 };
@@ -21,15 +22,25 @@ use url::Url;
 
 use crate::{
     derp::{self, http::ClientError, ReceivedMessage, MAX_PACKET_SIZE},
+    disco::{self, SendAddr},
     key::{PublicKey, PUBLIC_KEY_LENGTH},
 };
 
-use super::{ActorMessage, Inner};
+use super::{ActorMessage, Inner, PacketSplitIter};
 use super::{DerpContents, Metrics as MagicsockMetrics};
 
 /// How long a non-home DERP connection needs to be idle (last written to) before we close it.
 const DERP_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
 
+/// How long to wait before reconnecting a DERP connection while behind a captive portal.
+const CAPTIVE_PORTAL_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The longest a restarting DERP server can make us wait before reconnecting.
+const MAX_RESTART_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The longest a restarting DERP server can make us keep trying to reconnect.
+const MAX_RESTART_RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+
 /// How often `clean_stale_derp` runs when there are potentially-stale DERP connections to close.
 const DERP_CLEAN_STALE_INTERVAL: Duration = Duration::from_secs(15);
 
@@ -67,8 +78,16 @@ struct ActiveDerp {
     /// messages we've received from the server.
     peer_present: HashSet<PublicKey>,
     backoff: backoff::exponential::ExponentialBackoff<backoff::SystemClock>,
+    /// Whether we are behind a captive portal, as found by netcheck.
+    captive_portal: watch::Receiver<bool>,
+    /// While set, we do not read from (and so do not reconnect) the derp client, because a
+    /// captive portal is in the way.
+    captive_portal_backoff: Option<Pin<Box<time::Sleep>>>,
     last_packet_time: Option<Instant>,
     last_packet_src: Option<PublicKey>,
+    /// Reconnect scheduled after the server announced a restart, and the time until which
+    /// we keep retrying it. While set, we do not read from the derp client either.
+    restart_reconnect: Option<(Pin<Box<time::Sleep>>, Instant)>,
 }
 
 #[derive(Debug)]
@@ -89,6 +108,7 @@ impl ActiveDerp {
         derp_client: derp::http::Client,
         derp_client_receiver: derp::http::ClientReceiver,
         msg_sender: mpsc::Sender<ActorMessage>,
+        captive_portal: watch::Receiver<bool>,
     ) -> Self {
         ActiveDerp {
             last_write: Instant::now(),
@@ -100,8 +120,11 @@ impl ActiveDerp {
                 .with_initial_interval(Duration::from_millis(10))
                 .with_max_interval(Duration::from_secs(5))
                 .build(),
+            captive_portal,
+            captive_portal_backoff: None,
             last_packet_time: None,
             last_packet_src: None,
+            restart_reconnect: None,
             derp_client,
             derp_client_receiver,
         }
@@ -148,7 +171,7 @@ impl ActiveDerp {
                         }
                     }
                 }
-                msg = self.derp_client_receiver.recv() => {
+                msg = self.derp_client_receiver.recv(), if self.captive_portal_backoff.is_none() && self.restart_reconnect.is_none() => {
                     trace!("tick: derp_client_receiver");
                     if let Some(msg) = msg {
                         if self.handle_derp_msg(msg).await == ReadResult::Break {
@@ -158,6 +181,14 @@ impl ActiveDerp {
                         }
                     }
                 }
+                _ = captive_portal_backoff_done(&mut self.captive_portal_backoff, &mut self.captive_portal) => {
+                    trace!("tick: captive portal backoff done");
+                    self.captive_portal_backoff = None;
+                }
+                _ = restart_reconnect_due(&mut self.restart_reconnect) => {
+                    trace!("tick: restart reconnect");
+                    self.reconnect_after_restart().await;
+                }
                 else => {
                     break;
                 }
@@ -173,7 +204,12 @@ impl ActiveDerp {
     ) -> ReadResult {
         match msg {
             Err(err) => {
-                warn!("recv error {:?}", err);
+                let captive_portal = *self.captive_portal.borrow();
+                if captive_portal {
+                    debug!("recv error behind captive portal {:?}", err);
+                } else {
+                    warn!("recv error {:?}", err);
+                }
 
                 // Forget that all these peers have routes.
                 let peers: Vec<_> = self.peer_present.drain().collect();
@@ -192,6 +228,16 @@ impl ActiveDerp {
                 // TODO:
                 // self.re_stun("derp-recv-error").await;
 
+                if captive_portal {
+                    // Reconnecting is pointless until the portal is cleared, so wait for
+                    // netcheck to tell us it is gone, or check again after a while.
+                    inc!(MagicsockMetrics, derp_captive_portal_backoffs);
+                    debug!("captive portal backoff: {CAPTIVE_PORTAL_RECONNECT_DELAY:?}");
+                    self.captive_portal_backoff =
+                        Some(Box::pin(time::sleep(CAPTIVE_PORTAL_RECONNECT_DELAY)));
+                    return ReadResult::Continue;
+                }
+
                 // Back off a bit before reconnecting.
                 match self.backoff.next_backoff() {
                     Some(t) => {
@@ -258,6 +304,15 @@ impl ActiveDerp {
                         self.derp_routes.retain(|peer| peer != &key);
                         ReadResult::Continue
                     }
+                    derp::ReceivedMessage::ServerRestarting {
+                        reconnect_in,
+                        try_for,
+                        redirect,
+                    } => {
+                        self.handle_server_restarting(reconnect_in, try_for, redirect)
+                            .await;
+                        ReadResult::Continue
+                    }
                     other => {
                         trace!("ignoring: {:?}", other);
                         // Ignore.
@@ -267,6 +322,100 @@ impl ActiveDerp {
             }
         }
     }
+
+    /// Follows the hints of a restarting derp server.
+    ///
+    /// Moves our home to the `redirect` if the server gave one, then schedules a reconnect
+    /// after `reconnect_in`, trying for at most `try_for` before leaving it to the usual
+    /// reconnect logic. Both are capped, the server does not get to park us for long.
+    async fn handle_server_restarting(
+        &mut self,
+        reconnect_in: Duration,
+        try_for: Duration,
+        redirect: Option<Url>,
+    ) {
+        info!(?reconnect_in, ?try_for, ?redirect, "derp server restarting");
+        inc!(MagicsockMetrics, derp_server_restarts);
+
+        let reconnect_in = reconnect_in.min(MAX_RESTART_RECONNECT_DELAY);
+        let try_for = try_for.min(MAX_RESTART_RECONNECT_WINDOW);
+
+        // The server forgets about all peers, so do we.
+        let peers: Vec<_> = self.peer_present.drain().collect();
+        self.derp_routes.retain(|peer| !peers.contains(peer));
+        // Keep the client from reconnecting on its own before the smeared delay is over.
+        self.derp_client
+            .close_for_reconnect_after(reconnect_in)
+            .await
+            .ok();
+
+        if let Some(to) = redirect {
+            if to != self.url {
+                let msg = ActorMessage::DerpRedirect {
+                    from: self.url.clone(),
+                    to,
+                };
+                if let Err(err) = self.msg_sender.send(msg).await {
+                    warn!("unable to redirect derp home: {:?}", err);
+                }
+            }
+        }
+
+        let deadline = Instant::now() + reconnect_in + try_for;
+        self.backoff.reset();
+        self.restart_reconnect = Some((Box::pin(time::sleep(reconnect_in)), deadline));
+    }
+
+    /// Tries to reconnect once after a server restart, scheduling the next attempt if it
+    /// fails and there is time left.
+    async fn reconnect_after_restart(&mut self) {
+        let Some((_, deadline)) = self.restart_reconnect.take() else {
+            return;
+        };
+        match self.derp_client.connect().await {
+            Ok(_) => {
+                debug!("reconnected after server restart");
+                self.backoff.reset();
+            }
+            Err(err) => {
+                let wait = self
+                    .backoff
+                    .next_backoff()
+                    .unwrap_or(MAX_RESTART_RECONNECT_WINDOW);
+                if Instant::now() + wait >= deadline {
+                    debug!("could not reconnect after server restart: {:?}", err);
+                    self.backoff.reset();
+                } else {
+                    self.restart_reconnect = Some((Box::pin(time::sleep(wait)), deadline));
+                }
+            }
+        }
+    }
+}
+
+/// Resolves once the captive portal backoff elapsed or netcheck found the portal gone, never if
+/// we are not backing off.
+async fn captive_portal_backoff_done(
+    backoff: &mut Option<Pin<Box<time::Sleep>>>,
+    captive_portal: &mut watch::Receiver<bool>,
+) {
+    match backoff {
+        Some(timer) => {
+            tokio::select! {
+                _ = timer => {}
+                _ = captive_portal.wait_for(|active| !active) => {}
+            }
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the reconnect scheduled after a server restart is due, never if there is none.
+async fn restart_reconnect_due(restart_reconnect: &mut Option<(Pin<Box<time::Sleep>>, Instant)>) {
+    match restart_reconnect {
+        Some((timer, _)) => timer.await,
+        None => std::future::pending().await,
+    }
 }
 
 pub(super) struct DerpActor {
@@ -381,9 +530,10 @@ impl DerpActor {
         // But we have no guarantee that the total size of the contents including
         // length prefix will be smaller than the payload size.
         for packet in PacketizeIter::<_, PAYLAOD_SIZE>::new(contents) {
-            match derp_client.send(peer, packet).await {
+            match derp_client.send(peer, packet.clone()).await {
                 Ok(_) => {
                     inc_by!(MagicsockMetrics, send_derp, total_bytes);
+                    self.record_sent_quic(url, peer, packet);
                 }
                 Err(err) => {
                     warn!(%url, "send: failed {:?}", err);
@@ -399,6 +549,23 @@ impl DerpActor {
         }
     }
 
+    /// Records the QUIC packets in a sent derp `packet` in the traffic of the `peer`.
+    ///
+    /// Disco messages share the derp packets with QUIC but are not counted.
+    fn record_sent_quic(&self, url: &Url, peer: PublicKey, packet: Bytes) {
+        let (packets, bytes) = PacketSplitIter::new(packet)
+            .filter_map(Result::ok)
+            .filter(|part| !disco::looks_like_disco_wrapper(part))
+            .fold((0, 0), |(packets, bytes), part| {
+                (packets + 1, bytes + part.len() as u64)
+            });
+        if packets > 0 {
+            self.conn
+                .node_map
+                .record_sent(&peer, &SendAddr::Derp(url.clone()), packets, bytes);
+        }
+    }
+
     /// Returns `true`if the message was sent successfully.
     async fn send_to_active(&mut self, url: &Url, msg: ActiveDerpMessage) -> bool {
         match self.active_derp.get(url) {
@@ -466,6 +633,11 @@ impl DerpActor {
 
         let my_derp = self.conn.my_derp();
         let ipv6_reported = self.conn.ipv6_reported.clone();
+        let auth_token = self
+            .conn
+            .derp_map
+            .get_node(url)
+            .and_then(|node| node.auth_token.clone());
         let url = url.clone();
         let url1 = url.clone();
 
@@ -477,16 +649,24 @@ impl DerpActor {
             })
             .can_ack_pings(true)
             .is_preferred(my_derp.as_ref() == Some(&url1))
+            .auth_token(auth_token)
+            .frame_tap(
+                self.conn
+                    .capture
+                    .as_ref()
+                    .map(|capture| capture.derp_frame_tap(&url1)),
+            )
             .build(self.conn.secret_key.clone());
 
         let (s, r) = mpsc::channel(64);
 
         let c = dc.clone();
         let msg_sender = self.msg_sender.clone();
+        let captive_portal = self.conn.captive_portal.subscribe();
         let url1 = url.clone();
         let handle = tokio::task::spawn(
             async move {
-                let ad = ActiveDerp::new(url1, c, dc_receiver, msg_sender);
+                let ad = ActiveDerp::new(url1, c, dc_receiver, msg_sender, captive_portal);
 
                 if let Err(err) = ad.run(r).await {
                     warn!("connection error: {:?}", err);
@@ -755,4 +935,34 @@ mod tests {
         assert_eq!(&[5, 0, b'H', b'e', b'l', b'l', b'o'], &result[0][..7]);
         assert_eq!(&[5, 0, b'W', b'o', b'r', b'l', b'd'], &result[1][..]);
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_captive_portal_backoff() {
+        let (portal_s, mut portal_r) = watch::channel(true);
+
+        // not backing off, never done
+        let mut backoff = None;
+        let res = time::timeout(
+            Duration::from_secs(60),
+            captive_portal_backoff_done(&mut backoff, &mut portal_r),
+        )
+        .await;
+        assert!(res.is_err());
+
+        // done once the delay elapsed
+        let mut backoff = Some(Box::pin(time::sleep(CAPTIVE_PORTAL_RECONNECT_DELAY)));
+        let start = time::Instant::now();
+        captive_portal_backoff_done(&mut backoff, &mut portal_r).await;
+        assert_eq!(start.elapsed(), CAPTIVE_PORTAL_RECONNECT_DELAY);
+
+        // done early once the portal is gone
+        let mut backoff = Some(Box::pin(time::sleep(CAPTIVE_PORTAL_RECONNECT_DELAY)));
+        let start = time::Instant::now();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_secs(1)).await;
+            portal_s.send(false).ok();
+        });
+        captive_portal_backoff_done(&mut backoff, &mut portal_r).await;
+        assert!(start.elapsed() < CAPTIVE_PORTAL_RECONNECT_DELAY);
+    }
 }
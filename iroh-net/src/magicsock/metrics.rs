@@ -57,6 +57,10 @@ pub struct Metrics {
 
     // How many times our DERP home node DI has changed from non-zero to a different non-zero.
     pub derp_home_change: Counter,
+    /// Number of times a DERP server announced it is restarting.
+    pub derp_server_restarts: Counter,
+    /// Number of derp reconnects delayed because of a captive portal.
+    pub derp_captive_portal_backoffs: Counter,
 
     /*
      * Connection Metrics
@@ -123,6 +127,8 @@ impl Default for Metrics {
 
             // How many times our DERP home node DI has changed from non-zero to a different non-zero.
             derp_home_change: Counter::new("derp_home_change"),
+            derp_server_restarts: Counter::new("derp_server_restarts"),
+            derp_captive_portal_backoffs: Counter::new("derp_captive_portal_backoffs"),
 
             num_direct_conns_added: Counter::new(
                 "number of direct connections to a peer we have added",
@@ -10,7 +10,7 @@ use anyhow::{ensure, Context};
 use iroh_metrics::inc;
 use parking_lot::Mutex;
 use stun_rs::TransactionId;
-use tokio::io::AsyncWriteExt;
+use tokio::{io::AsyncWriteExt, sync::broadcast};
 use tracing::{debug, info, instrument, trace, warn};
 use url::Url;
 
@@ -27,13 +27,19 @@ use crate::{
 mod best_addr;
 mod endpoint;
 
-pub use endpoint::{ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo};
+use endpoint::Traffic;
+pub use endpoint::{
+    ConnectionType, ControlMsg, DirectAddrInfo, EndpointInfo, PathEvent, PathTraffic, TrafficInfo,
+};
 pub(super) use endpoint::{DiscoPingPurpose, PingAction, PingRole, SendPing};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
 const MAX_INACTIVE_NODES: usize = 30;
 
+/// Number of [`PathEvent`]s buffered for slow subscribers before they start missing events.
+const PATH_EVENTS_CAPACITY: usize = 256;
+
 /// Map of the [`Endpoint`] information for all the known nodes.
 ///
 /// The nodes can be looked up by:
@@ -51,9 +57,18 @@ const MAX_INACTIVE_NODES: usize = 30;
 ///   These come and go as the node moves around on the internet
 ///
 /// An index of nodeInfos by node key, QuicMappedAddr, and discovered ip:port endpoints.
-#[derive(Default, Debug)]
+///
+/// Changes of the paths to the nodes are broadcast as [`PathEvent`]s.
+#[derive(Debug)]
 pub(super) struct NodeMap {
     inner: Mutex<NodeMapInner>,
+    path_events: broadcast::Sender<PathEvent>,
+}
+
+impl Default for NodeMap {
+    fn default() -> Self {
+        Self::from_inner(NodeMapInner::default())
+    }
 }
 
 #[derive(Default, Debug)]
@@ -63,6 +78,8 @@ pub(super) struct NodeMapInner {
     by_quic_mapped_addr: HashMap<QuicMappedAddr, usize>,
     by_id: HashMap<usize, Endpoint>,
     next_id: usize,
+    /// Traffic of pruned nodes, restored when they are inserted again.
+    pruned_traffic: HashMap<PublicKey, Traffic>,
 }
 
 enum EndpointId<'a> {
@@ -79,8 +96,28 @@ impl NodeMap {
     }
 
     fn from_inner(inner: NodeMapInner) -> Self {
+        let (path_events, _) = broadcast::channel(PATH_EVENTS_CAPACITY);
         Self {
             inner: Mutex::new(inner),
+            path_events,
+        }
+    }
+
+    /// Subscribe to the [`PathEvent`]s of all nodes.
+    pub fn subscribe_path_events(&self) -> broadcast::Receiver<PathEvent> {
+        self.path_events.subscribe()
+    }
+
+    /// Broadcast a [`PathEvent`] which did not originate from the node map.
+    pub fn send_path_event(&self, event: PathEvent) {
+        self.send_path_events(vec![event]);
+    }
+
+    fn send_path_events(&self, events: Vec<PathEvent>) {
+        for event in events {
+            trace!(?event, "path event");
+            // No subscribers is fine.
+            self.path_events.send(event).ok();
         }
     }
 
@@ -93,7 +130,10 @@ impl NodeMap {
 
     /// Add the contact information for a node.
     pub fn add_node_addr(&self, node_addr: NodeAddr) {
-        self.inner.lock().add_node_addr(node_addr)
+        let mut inner = self.inner.lock();
+        let node_id = node_addr.node_id;
+        inner.add_node_addr(node_addr);
+        self.send_path_events(inner.path_events(EndpointId::NodeKey(&node_id)));
     }
 
     /// Number of nodes currently listed.
@@ -102,11 +142,33 @@ impl NodeMap {
     }
 
     pub fn receive_udp(&self, udp_addr: SocketAddr) -> Option<(PublicKey, QuicMappedAddr)> {
-        self.inner.lock().receive_udp(udp_addr)
+        let mut inner = self.inner.lock();
+        let res = inner.receive_udp(udp_addr);
+        if let Some((node_id, _)) = res {
+            self.send_path_events(inner.path_events(EndpointId::NodeKey(&node_id)));
+        }
+        res
     }
 
     pub fn receive_derp(&self, derp_url: &Url, src: PublicKey) -> QuicMappedAddr {
-        self.inner.lock().receive_derp(derp_url, &src)
+        let mut inner = self.inner.lock();
+        let addr = inner.receive_derp(derp_url, &src);
+        self.send_path_events(inner.path_events(EndpointId::NodeKey(&src)));
+        addr
+    }
+
+    /// Records QUIC packets sent to `node` over `path`.
+    pub fn record_sent(&self, node: &PublicKey, path: &SendAddr, packets: u64, bytes: u64) {
+        if let Some(ep) = self.inner.lock().get_mut(EndpointId::NodeKey(node)) {
+            ep.record_sent(path, packets, bytes, Instant::now());
+        }
+    }
+
+    /// Records QUIC packets received from `node` over `path`.
+    pub fn record_recv(&self, node: &PublicKey, path: &SendAddr, packets: u64, bytes: u64) {
+        if let Some(ep) = self.inner.lock().get_mut(EndpointId::NodeKey(node)) {
+            ep.record_recv(path, packets, bytes, Instant::now());
+        }
     }
 
     pub fn notify_ping_sent(
@@ -123,9 +185,11 @@ impl NodeMap {
     }
 
     pub fn notify_ping_timeout(&self, id: usize, tx_id: stun::TransactionId) {
-        if let Some(ep) = self.inner.lock().get_mut(EndpointId::Id(&id)) {
+        let mut inner = self.inner.lock();
+        if let Some(ep) = inner.get_mut(EndpointId::Id(&id)) {
             ep.ping_timeout(tx_id);
         }
+        self.send_path_events(inner.path_events(EndpointId::Id(&id)));
     }
 
     pub fn get_quic_mapped_addr_for_node_key(
@@ -141,16 +205,24 @@ impl NodeMap {
     /// Insert a received ping into the node map, and return whether a ping with this tx_id was already
     /// received.
     pub fn handle_ping(&self, sender: PublicKey, src: SendAddr, tx_id: TransactionId) -> PingRole {
-        self.inner.lock().handle_ping(sender, src, tx_id)
+        let mut inner = self.inner.lock();
+        let role = inner.handle_ping(sender, src, tx_id);
+        self.send_path_events(inner.path_events(EndpointId::NodeKey(&sender)));
+        role
     }
 
     pub fn handle_pong(&self, sender: PublicKey, src: &DiscoMessageSource, pong: Pong) {
-        self.inner.lock().handle_pong(sender, src, pong)
+        let mut inner = self.inner.lock();
+        inner.handle_pong(sender, src, pong);
+        self.send_path_events(inner.path_events(EndpointId::NodeKey(&sender)));
     }
 
     #[must_use = "actions must be handled"]
     pub fn handle_call_me_maybe(&self, sender: PublicKey, cm: CallMeMaybe) -> Vec<PingAction> {
-        self.inner.lock().handle_call_me_maybe(sender, cm)
+        let mut inner = self.inner.lock();
+        let actions = inner.handle_call_me_maybe(sender, cm);
+        self.send_path_events(inner.path_events(EndpointId::NodeKey(&sender)));
+        actions
     }
 
     #[allow(clippy::type_complexity)]
@@ -162,6 +234,8 @@ impl NodeMap {
         let ep = inner.get_mut(EndpointId::QuicMappedAddr(addr))?;
         let public_key = *ep.public_key();
         let (udp_addr, derp_url, msgs) = ep.get_send_addrs();
+        let events = ep.path_events(Instant::now());
+        self.send_path_events(events);
         Some((public_key, udp_addr, derp_url, msgs))
     }
 
@@ -170,6 +244,7 @@ impl NodeMap {
         for (_, ep) in inner.endpoints_mut() {
             ep.reset();
         }
+        self.send_path_events(inner.all_path_events());
     }
 
     pub fn reset_endpoint_states(&self) {
@@ -177,6 +252,7 @@ impl NodeMap {
         for (_, ep) in inner.endpoints_mut() {
             ep.note_connectivity_change();
         }
+        self.send_path_events(inner.all_path_events());
     }
 
     pub fn endpoints_stayin_alive(&self) -> Vec<PingAction> {
@@ -185,6 +261,8 @@ impl NodeMap {
         for (_, ep) in inner.endpoints_mut() {
             msgs.extend(ep.stayin_alive());
         }
+        // This runs periodically, which also catches paths going stale without any traffic.
+        self.send_path_events(inner.all_path_events());
         msgs
     }
 
@@ -361,6 +439,21 @@ impl NodeMapInner {
         self.by_id.iter_mut()
     }
 
+    /// Returns the [`PathEvent`]s of an endpoint since they were last reported.
+    fn path_events(&mut self, id: EndpointId) -> Vec<PathEvent> {
+        self.get_mut(id)
+            .map(|ep| ep.path_events(Instant::now()))
+            .unwrap_or_default()
+    }
+
+    /// Returns the [`PathEvent`]s of all endpoints since they were last reported.
+    fn all_path_events(&mut self) -> Vec<PathEvent> {
+        let now = Instant::now();
+        self.endpoints_mut()
+            .flat_map(|(_, ep)| ep.path_events(now))
+            .collect()
+    }
+
     /// Get the [`EndpointInfo`]s for each endpoint
     fn endpoint_infos(&self, now: Instant) -> Vec<EndpointInfo> {
         self.endpoints().map(|(_, ep)| ep.info(now)).collect()
@@ -423,7 +516,10 @@ impl NodeMapInner {
         info!(node = %options.public_key.fmt_short(), derp_url = ?options.derp_url, "inserting new node endpoint in NodeMap");
         let id = self.next_id;
         self.next_id = self.next_id.wrapping_add(1);
-        let ep = Endpoint::new(id, options);
+        let mut ep = Endpoint::new(id, options);
+        if let Some(traffic) = self.pruned_traffic.remove(ep.public_key()) {
+            ep.restore_traffic(traffic);
+        }
 
         // update indices
         self.by_quic_mapped_addr.insert(*ep.quic_mapped_addr(), id);
@@ -488,10 +584,11 @@ impl NodeMapInner {
                 continue;
             };
 
-            let Some(ep) = self.by_id.remove(&id) else {
+            let Some(mut ep) = self.by_id.remove(&id) else {
                 debug_assert!(false, "missing by_id entry for id in by_node_key");
                 continue;
             };
+            self.pruned_traffic.insert(public_key, ep.take_traffic());
 
             for ip_port in ep.direct_addresses() {
                 self.by_ip_port.remove(&ip_port);
@@ -687,4 +784,30 @@ mod tests {
             .get(EndpointId::NodeKey(&active_node))
             .expect("should not be pruned");
     }
+
+    #[test]
+    fn test_prune_inactive_keeps_traffic() {
+        let node_map = NodeMap::default();
+        let nodes: Vec<_> = (0..MAX_INACTIVE_NODES + 1)
+            .map(|_| SecretKey::generate().public())
+            .collect();
+        let path = SendAddr::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 167));
+        for node in &nodes {
+            node_map.add_node_addr(NodeAddr::new(*node));
+            node_map.record_sent(node, &path, 1, 100);
+        }
+
+        node_map.prune_inactive();
+        let pruned = nodes
+            .iter()
+            .find(|node| node_map.endpoint_info(node).is_none())
+            .expect("one node pruned");
+
+        // the counters continue where they were when the node is back
+        node_map.add_node_addr(NodeAddr::new(*pruned));
+        node_map.record_sent(pruned, &path, 1, 100);
+        let traffic = node_map.endpoint_info(pruned).unwrap().traffic;
+        assert_eq!(traffic.ipv4.packets_sent, 2);
+        assert_eq!(traffic.ipv4.bytes_sent, 200);
+    }
 }
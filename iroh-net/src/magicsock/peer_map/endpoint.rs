@@ -1,5 +1,5 @@
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     hash::Hash,
     net::{IpAddr, SocketAddr},
     time::{Duration, Instant},
@@ -100,6 +100,77 @@ pub(super) struct Endpoint {
     /// A node is marked as in use when an endpoint to contact them is requested or if UDP activity
     /// is registered.
     last_used: Option<Instant>,
+    /// The paths to this node as last reported by [`Endpoint::path_events`].
+    reported_path: ReportedPath,
+    /// QUIC traffic exchanged with this node, per path.
+    traffic: Traffic,
+}
+
+/// Counts the QUIC packets exchanged with a node over a single path.
+#[derive(Debug, Default)]
+struct TrafficCounter {
+    bytes_sent: u64,
+    bytes_recv: u64,
+    packets_sent: u64,
+    packets_recv: u64,
+    last_sent: Option<Instant>,
+    last_recv: Option<Instant>,
+}
+
+impl TrafficCounter {
+    fn info(&self, now: Instant) -> PathTraffic {
+        PathTraffic {
+            bytes_sent: self.bytes_sent,
+            bytes_recv: self.bytes_recv,
+            packets_sent: self.packets_sent,
+            packets_recv: self.packets_recv,
+            last_sent: self.last_sent.map(|instant| now.duration_since(instant)),
+            last_recv: self.last_recv.map(|instant| now.duration_since(instant)),
+        }
+    }
+}
+
+/// The [`TrafficCounter`]s of a node.
+///
+/// Counters outlive the [`Endpoint`]: the [`super::NodeMap`] keeps them when the endpoint is
+/// pruned and hands them to the next endpoint of the node. A DERP url stays listed after the
+/// node moved to another one.
+#[derive(Debug, Default)]
+pub(super) struct Traffic {
+    ipv4: TrafficCounter,
+    ipv6: TrafficCounter,
+    derp: HashMap<Url, TrafficCounter>,
+}
+
+impl Traffic {
+    fn path_mut(&mut self, path: &SendAddr) -> &mut TrafficCounter {
+        match path {
+            SendAddr::Udp(addr) if addr.is_ipv6() => &mut self.ipv6,
+            SendAddr::Udp(_) => &mut self.ipv4,
+            SendAddr::Derp(url) => self.derp.entry(url.clone()).or_default(),
+        }
+    }
+
+    fn info(&self, now: Instant) -> TrafficInfo {
+        TrafficInfo {
+            ipv4: self.ipv4.info(now),
+            ipv6: self.ipv6.info(now),
+            derp: self
+                .derp
+                .iter()
+                .map(|(url, counter)| (url.clone(), counter.info(now)))
+                .collect(),
+        }
+    }
+}
+
+/// The paths to a node as last reported in [`PathEvent`]s.
+#[derive(Debug, Default)]
+struct ReportedPath {
+    derp_url: Option<Url>,
+    best_addr: Option<SocketAddr>,
+    best_addr_valid: bool,
+    conn_type: Option<ConnectionType>,
 }
 
 #[derive(Debug)]
@@ -129,6 +200,8 @@ impl Endpoint {
             sent_ping: HashMap::new(),
             direct_addr_state: HashMap::new(),
             last_used: options.active.then(Instant::now),
+            reported_path: Default::default(),
+            traffic: Default::default(),
         }
     }
 
@@ -146,25 +219,7 @@ impl Endpoint {
 
     /// Returns info about this endpoint
     pub(super) fn info(&self, now: Instant) -> EndpointInfo {
-        use best_addr::State::*;
-        // Report our active connection. This replicates the logic of [`Endpoint::addr_for_send`]
-        // without chosing a random candidate address if no best_addr is set.
-        let (conn_type, latency) = match (self.best_addr.state(now), self.derp_url.as_ref()) {
-            (Valid(addr), _) | (Outdated(addr), None) => {
-                (ConnectionType::Direct(addr.addr), Some(addr.latency))
-            }
-            (Outdated(addr), Some((url, relay_state))) => {
-                let latency = relay_state
-                    .latency()
-                    .map(|l| l.min(addr.latency))
-                    .unwrap_or(addr.latency);
-                (ConnectionType::Mixed(addr.addr, url.clone()), Some(latency))
-            }
-            (Empty, Some((url, relay_state))) => {
-                (ConnectionType::Relay(url.clone()), relay_state.latency())
-            }
-            (Empty, None) => (ConnectionType::None, None),
-        };
+        let (conn_type, latency) = self.connection_type(now);
         let addrs = self
             .direct_addr_state
             .iter()
@@ -187,9 +242,111 @@ impl Endpoint {
             conn_type,
             latency,
             last_used: self.last_used.map(|instant| now.duration_since(instant)),
+            traffic: self.traffic.info(now),
         }
     }
 
+    /// Takes the traffic counters, leaving them empty.
+    pub(super) fn take_traffic(&mut self) -> Traffic {
+        std::mem::take(&mut self.traffic)
+    }
+
+    /// Continues counting from the counters of a previous endpoint of this node.
+    pub(super) fn restore_traffic(&mut self, traffic: Traffic) {
+        self.traffic = traffic;
+    }
+
+    /// Records `packets` QUIC packets with a total of `bytes` sent to this node over `path`.
+    pub(super) fn record_sent(&mut self, path: &SendAddr, packets: u64, bytes: u64, now: Instant) {
+        let counter = self.traffic.path_mut(path);
+        counter.packets_sent += packets;
+        counter.bytes_sent += bytes;
+        counter.last_sent = Some(now);
+    }
+
+    /// Records `packets` QUIC packets with a total of `bytes` received from this node over
+    /// `path`.
+    pub(super) fn record_recv(&mut self, path: &SendAddr, packets: u64, bytes: u64, now: Instant) {
+        let counter = self.traffic.path_mut(path);
+        counter.packets_recv += packets;
+        counter.bytes_recv += bytes;
+        counter.last_recv = Some(now);
+    }
+
+    /// Returns the type of our active connection to this node and its latency.
+    pub(super) fn connection_type(&self, now: Instant) -> (ConnectionType, Option<Duration>) {
+        use best_addr::State::*;
+        // Report our active connection. This replicates the logic of [`Endpoint::addr_for_send`]
+        // without chosing a random candidate address if no best_addr is set.
+        match (self.best_addr.state(now), self.derp_url.as_ref()) {
+            (Valid(addr), _) | (Outdated(addr), None) => {
+                (ConnectionType::Direct(addr.addr), Some(addr.latency))
+            }
+            (Outdated(addr), Some((url, relay_state))) => {
+                let latency = relay_state
+                    .latency()
+                    .map(|l| l.min(addr.latency))
+                    .unwrap_or(addr.latency);
+                (ConnectionType::Mixed(addr.addr, url.clone()), Some(latency))
+            }
+            (Empty, Some((url, relay_state))) => {
+                (ConnectionType::Relay(url.clone()), relay_state.latency())
+            }
+            (Empty, None) => (ConnectionType::None, None),
+        }
+    }
+
+    /// Returns the events for the paths to this node which changed since the last call.
+    ///
+    /// This is called for every packet sent, so it does not allocate unless something changed.
+    pub(super) fn path_events(&mut self, now: Instant) -> Vec<PathEvent> {
+        let node_id = self.public_key;
+        let mut events = Vec::new();
+
+        let best_addr_valid = matches!(self.best_addr.state(now), best_addr::State::Valid(_));
+        let unchanged = self.reported_path.conn_type.is_some()
+            && self.reported_path.best_addr_valid == best_addr_valid
+            && self.reported_path.best_addr == self.best_addr.addr()
+            && self.reported_path.derp_url.as_ref() == self.derp_url.as_ref().map(|(url, _)| url);
+        if unchanged {
+            return events;
+        }
+        self.reported_path.best_addr_valid = best_addr_valid;
+
+        let derp_url = self.derp_url();
+        if derp_url != self.reported_path.derp_url {
+            events.push(PathEvent::DerpUrlChanged {
+                node_id,
+                url: derp_url.clone(),
+            });
+            self.reported_path.derp_url = derp_url;
+        }
+
+        let best_addr = self.best_addr.addr();
+        if best_addr != self.reported_path.best_addr {
+            events.push(PathEvent::BestAddrChanged {
+                node_id,
+                addr: best_addr,
+            });
+            self.reported_path.best_addr = best_addr;
+        }
+
+        let (conn_type, _latency) = self.connection_type(now);
+        if self.reported_path.conn_type.as_ref() != Some(&conn_type) {
+            // A node we never had a path to did not become unreachable.
+            if conn_type == ConnectionType::None && self.reported_path.conn_type.is_some() {
+                events.push(PathEvent::Unreachable { node_id });
+            }
+            events.push(PathEvent::ConnectionTypeChanged {
+                node_id,
+                conn_type: conn_type.clone(),
+            });
+            self.reported_path.conn_type = Some(conn_type);
+        }
+
+        events
+    }
+
     /// Returns the derp url of this endpoint
     pub(super) fn derp_url(&self) -> Option<Url> {
         self.derp_url.as_ref().map(|(url, _state)| url.clone())
@@ -1149,6 +1306,81 @@ pub struct EndpointInfo {
     pub latency: Option<Duration>,
     /// Duration since the last time this node was used.
     pub last_used: Option<Duration>,
+    /// QUIC traffic exchanged with this node, per path.
+    pub traffic: TrafficInfo,
+}
+
+/// QUIC traffic exchanged with a node over a single path.
+///
+/// Only QUIC packets are counted, the disco and STUN messages used to find paths are not.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PathTraffic {
+    /// Bytes sent to the node.
+    pub bytes_sent: u64,
+    /// Bytes received from the node.
+    pub bytes_recv: u64,
+    /// Packets sent to the node.
+    pub packets_sent: u64,
+    /// Packets received from the node.
+    pub packets_recv: u64,
+    /// How long ago a packet was last sent to the node.
+    pub last_sent: Option<Duration>,
+    /// How long ago a packet was last received from the node.
+    pub last_recv: Option<Duration>,
+}
+
+impl PathTraffic {
+    fn add(&mut self, other: &PathTraffic) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_recv += other.bytes_recv;
+        self.packets_sent += other.packets_sent;
+        self.packets_recv += other.packets_recv;
+        self.last_sent = min_some(self.last_sent, other.last_sent);
+        self.last_recv = min_some(self.last_recv, other.last_recv);
+    }
+}
+
+fn min_some(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// QUIC traffic exchanged with a node, split by path.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TrafficInfo {
+    /// Traffic sent directly over IPv4.
+    pub ipv4: PathTraffic,
+    /// Traffic sent directly over IPv6.
+    pub ipv6: PathTraffic,
+    /// Traffic relayed by each DERP server.
+    pub derp: BTreeMap<Url, PathTraffic>,
+}
+
+impl TrafficInfo {
+    /// The traffic relayed by all DERP servers together.
+    pub fn relayed(&self) -> PathTraffic {
+        let mut total = PathTraffic::default();
+        for traffic in self.derp.values() {
+            total.add(traffic);
+        }
+        total
+    }
+
+    /// The traffic sent directly over IPv4 and IPv6 together.
+    pub fn direct(&self) -> PathTraffic {
+        let mut total = self.ipv4.clone();
+        total.add(&self.ipv6);
+        total
+    }
+
+    /// The traffic over all paths together.
+    pub fn total(&self) -> PathTraffic {
+        let mut total = self.relayed();
+        total.add(&self.direct());
+        total
+    }
 }
 
 /// The type of connection we have to the endpoint.
@@ -1171,6 +1403,56 @@ pub enum ConnectionType {
     None,
 }
 
+/// A change in how we can reach other nodes.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PathEvent {
+    /// The [`ConnectionType`] to a node changed, e.g. because it upgraded from relay to
+    /// direct.
+    ConnectionTypeChanged {
+        /// The node whose connection changed.
+        node_id: PublicKey,
+        /// The new connection type.
+        conn_type: ConnectionType,
+    },
+    /// The best direct address of a node changed.
+    BestAddrChanged {
+        /// The node whose best address changed.
+        node_id: PublicKey,
+        /// The new best address, `None` if there is no usable direct address anymore.
+        addr: Option<SocketAddr>,
+    },
+    /// The DERP url through which we can reach a node changed.
+    DerpUrlChanged {
+        /// The node whose DERP url changed.
+        node_id: PublicKey,
+        /// The new DERP url.
+        url: Option<Url>,
+    },
+    /// Our own home DERP changed.
+    HomeDerpChanged {
+        /// The new home DERP url.
+        url: Option<Url>,
+    },
+    /// A node we used to have a path to can no longer be reached.
+    Unreachable {
+        /// The node which is unreachable.
+        node_id: PublicKey,
+    },
+}
+
+impl PathEvent {
+    /// The node this event is about, `None` for events about ourselves.
+    pub fn node_id(&self) -> Option<&PublicKey> {
+        match self {
+            PathEvent::ConnectionTypeChanged { node_id, .. }
+            | PathEvent::BestAddrChanged { node_id, .. }
+            | PathEvent::DerpUrlChanged { node_id, .. }
+            | PathEvent::Unreachable { node_id } => Some(node_id),
+            PathEvent::HomeDerpChanged { .. } => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
@@ -1225,6 +1507,8 @@ mod tests {
                     direct_addr_state: endpoint_state,
                     sent_ping: HashMap::new(),
                     last_used: Some(now),
+                    reported_path: Default::default(),
+                    traffic: Default::default(),
                 },
                 ip_port.into(),
             )
@@ -1249,6 +1533,8 @@ mod tests {
                 direct_addr_state: HashMap::default(),
                 sent_ping: HashMap::new(),
                 last_used: Some(now),
+                reported_path: Default::default(),
+                traffic: Default::default(),
             }
         };
 
@@ -1267,6 +1553,8 @@ mod tests {
                 direct_addr_state: endpoint_state,
                 sent_ping: HashMap::new(),
                 last_used: Some(now),
+                reported_path: Default::default(),
+                traffic: Default::default(),
             }
         };
 
@@ -1306,6 +1594,8 @@ mod tests {
                     direct_addr_state: endpoint_state,
                     sent_ping: HashMap::new(),
                     last_used: Some(now),
+                    reported_path: Default::default(),
+                    traffic: Default::default(),
                 },
                 socket_addr,
             )
@@ -1324,6 +1614,7 @@ mod tests {
                 conn_type: ConnectionType::Direct(a_socket_addr),
                 latency: Some(latency),
                 last_used: Some(elapsed),
+                traffic: Default::default(),
             },
             EndpointInfo {
                 id: b_endpoint.id,
@@ -1333,6 +1624,7 @@ mod tests {
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: Some(latency),
                 last_used: Some(elapsed),
+                traffic: Default::default(),
             },
             EndpointInfo {
                 id: c_endpoint.id,
@@ -1342,6 +1634,7 @@ mod tests {
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: None,
                 last_used: Some(elapsed),
+                traffic: Default::default(),
             },
             EndpointInfo {
                 id: d_endpoint.id,
@@ -1356,6 +1649,7 @@ mod tests {
                 conn_type: ConnectionType::Mixed(d_socket_addr, send_addr.clone()),
                 latency: Some(Duration::from_millis(50)),
                 last_used: Some(elapsed),
+                traffic: Default::default(),
             },
         ]);
 
@@ -1383,9 +1677,62 @@ mod tests {
                 (d_endpoint.id, d_endpoint),
             ]),
             next_id: 5,
+            pruned_traffic: HashMap::new(),
         });
         let mut got = node_map.endpoint_infos(later);
         got.sort_by_key(|p| p.id);
         assert_eq!(expect, got);
     }
+
+    #[test]
+    fn test_traffic() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(2);
+        let mut ep = Endpoint::new(
+            0,
+            Options {
+                public_key: SecretKey::generate().public(),
+                derp_url: None,
+                active: true,
+            },
+        );
+        let v4 = SendAddr::Udp("1.2.3.4:5".parse().unwrap());
+        let v6 = SendAddr::Udp("[::1]:5".parse().unwrap());
+        let derp_a: Url = "https://derp-a.example".parse().unwrap();
+        let derp_b: Url = "https://derp-b.example".parse().unwrap();
+
+        ep.record_sent(&v4, 2, 2400, now);
+        ep.record_recv(&v4, 1, 1200, later);
+        ep.record_recv(&v6, 1, 100, now);
+        ep.record_sent(&SendAddr::Derp(derp_a.clone()), 3, 300, now);
+        ep.record_sent(&SendAddr::Derp(derp_b.clone()), 1, 50, later);
+
+        let traffic = ep.info(later).traffic;
+        assert_eq!(
+            traffic.ipv4,
+            PathTraffic {
+                bytes_sent: 2400,
+                bytes_recv: 1200,
+                packets_sent: 2,
+                packets_recv: 1,
+                last_sent: Some(Duration::from_secs(2)),
+                last_recv: Some(Duration::ZERO),
+            }
+        );
+        assert_eq!(traffic.ipv6.bytes_recv, 100);
+        assert_eq!(traffic.ipv6.last_sent, None);
+        assert_eq!(traffic.derp.len(), 2);
+        assert_eq!(traffic.derp[&derp_a].packets_sent, 3);
+
+        let relayed = traffic.relayed();
+        assert_eq!(relayed.bytes_sent, 350);
+        assert_eq!(relayed.packets_sent, 4);
+        assert_eq!(relayed.last_sent, Some(Duration::ZERO));
+        assert_eq!(relayed.last_recv, None);
+
+        assert_eq!(traffic.direct().bytes_recv, 1300);
+        let total = traffic.total();
+        assert_eq!(total.bytes_sent, 2750);
+        assert_eq!(total.bytes_recv, 1300);
+    }
 }
@@ -14,52 +14,94 @@ use tracing::{debug, trace, warn};
 
 use crate::net::IpFamily;
 use crate::net::UdpSocket;
+use crate::netcheck::StunSocket;
 
 use super::CurrentPortFate;
 
+/// A UDP socket provided by a [`UdpBinder`].
+pub(crate) type CustomUdpSocket = Arc<dyn AsyncUdpSocket + Sync>;
+
+/// Creates the UDP sockets of a [`super::MagicSock`].
+///
+/// By default the sockets of the operating system are used.  A custom binder replaces the
+/// network stack, e.g. with a simulated network to test several endpoints in one process.
+/// It is asked again for sockets whenever the magicsock rebinds.
+pub(crate) trait UdpBinder: Debug + Send + Sync + 'static {
+    /// Binds a socket to `addr`, a port of `0` lets the binder pick a free port.
+    fn bind(&self, addr: SocketAddr) -> io::Result<CustomUdpSocket>;
+}
+
 /// A UDP socket that can be re-bound. Unix has no notion of re-binding a socket, so we swap it out for a new one.
 #[derive(Clone, Debug)]
 pub struct RebindingUdpConn {
-    io: Arc<UdpSocket>,
-    state: Arc<quinn_udp::UdpSocketState>,
+    io: Io,
+    binder: Option<Arc<dyn UdpBinder>>,
+}
+
+#[derive(Clone, Debug)]
+enum Io {
+    Os {
+        io: Arc<UdpSocket>,
+        state: Arc<quinn_udp::UdpSocketState>,
+    },
+    Custom(CustomUdpSocket),
+}
+
+impl Io {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Io::Os { io, .. } => io.local_addr(),
+            Io::Custom(io) => io.local_addr(),
+        }
+    }
 }
 
 impl RebindingUdpConn {
-    pub(super) fn as_socket(&self) -> Arc<UdpSocket> {
-        self.io.clone()
+    /// The socket of the operating system, `None` if a custom [`UdpBinder`] is used.
+    pub(super) fn as_socket(&self) -> Option<Arc<UdpSocket>> {
+        match self.io {
+            Io::Os { ref io, .. } => Some(io.clone()),
+            Io::Custom(_) => None,
+        }
+    }
+
+    /// The current socket, to send netcheck STUN probes from.
+    pub(super) fn stun_socket(&self) -> StunSocket {
+        match self.io {
+            Io::Os { ref io, .. } => StunSocket::os(io.clone()),
+            Io::Custom(ref io) => StunSocket::custom(io.clone()),
+        }
     }
 
     pub(super) fn rebind(
         &mut self,
-        port: u16,
-        network: IpFamily,
+        addr: SocketAddr,
         cur_port_fate: CurrentPortFate,
     ) -> anyhow::Result<()> {
         trace!(
-            "rebinding from {} to {} ({:?})",
-            self.port(),
-            port,
+            "rebinding from {:?} to {} ({:?})",
+            self.local_addr().ok(),
+            addr,
             cur_port_fate
         );
 
-        // Do not bother rebinding if we are keeping the port.
-        if self.port() == port && cur_port_fate == CurrentPortFate::Keep {
+        // Do not bother rebinding if we are keeping the address.
+        if self.local_addr().ok() == Some(addr) && cur_port_fate == CurrentPortFate::Keep {
             return Ok(());
         }
 
-        let sock = bind(Some(&self.io), port, network, cur_port_fate)?;
-        self.io = Arc::new(sock);
-        self.state = Default::default();
+        self.io = bind(self.binder.as_ref(), Some(&self.io), addr, cur_port_fate)?;
 
         Ok(())
     }
 
-    pub(super) fn bind(port: u16, network: IpFamily) -> anyhow::Result<Self> {
-        let sock = bind(None, port, network, CurrentPortFate::Keep)?;
-        Ok(Self {
-            io: Arc::new(sock),
-            state: Default::default(),
-        })
+    /// Binds using the `binder`, or the operating system if it is `None`.
+    pub(super) fn bind(
+        addr: SocketAddr,
+        binder: Option<Arc<dyn UdpBinder>>,
+    ) -> anyhow::Result<Self> {
+        let io = bind(binder.as_ref(), None, addr, CurrentPortFate::Keep)?;
+        Ok(Self { io, binder })
     }
 
     pub fn port(&self) -> u16 {
@@ -80,26 +122,31 @@ impl AsyncUdpSocket for RebindingUdpConn {
         cx: &mut Context,
         transmits: &[quinn_udp::Transmit],
     ) -> Poll<io::Result<usize>> {
-        let inner = &self.state;
-        let io = &self.io;
-        loop {
-            ready!(io.poll_send_ready(cx))?;
-            if let Ok(res) = io.try_io(Interest::WRITABLE, || {
-                inner.send(Arc::as_ref(io).into(), state, transmits)
-            }) {
-                for t in transmits.iter().take(res) {
-                    trace!(
-                        dst = %t.destination,
-                        len = t.contents.len(),
-                        count = t.segment_size.map(|ss| t.contents.len() / ss).unwrap_or(1),
-                        src = %t.src_ip.map(|x| x.to_string()).unwrap_or_default(),
-                        "UDP send"
-                    );
+        let res = match self.io {
+            Io::Os {
+                ref io,
+                state: ref inner,
+            } => loop {
+                ready!(io.poll_send_ready(cx))?;
+                if let Ok(res) = io.try_io(Interest::WRITABLE, || {
+                    inner.send(Arc::as_ref(io).into(), state, transmits)
+                }) {
+                    break res;
                 }
-
-                return Poll::Ready(Ok(res));
-            }
+            },
+            Io::Custom(ref io) => ready!(io.poll_send(state, cx, transmits))?,
+        };
+        for t in transmits.iter().take(res) {
+            trace!(
+                dst = %t.destination,
+                len = t.contents.len(),
+                count = t.segment_size.map(|ss| t.contents.len() / ss).unwrap_or(1),
+                src = %t.src_ip.map(|x| x.to_string()).unwrap_or_default(),
+                "UDP send"
+            );
         }
+
+        Poll::Ready(Ok(res))
     }
 
     fn poll_recv(
@@ -108,24 +155,28 @@ impl AsyncUdpSocket for RebindingUdpConn {
         bufs: &mut [io::IoSliceMut<'_>],
         meta: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        loop {
-            ready!(self.io.poll_recv_ready(cx))?;
-            if let Ok(res) = self.io.try_io(Interest::READABLE, || {
-                self.state.recv(Arc::as_ref(&self.io).into(), bufs, meta)
-            }) {
-                for meta in meta.iter().take(res) {
-                    trace!(
-                        src = %meta.addr,
-                        len = meta.len,
-                        count = meta.len / meta.stride,
-                        dst = %meta.dst_ip.map(|x| x.to_string()).unwrap_or_default(),
-                        "UDP recv"
-                    );
+        let res = match self.io {
+            Io::Os { ref io, ref state } => loop {
+                ready!(io.poll_recv_ready(cx))?;
+                if let Ok(res) = io.try_io(Interest::READABLE, || {
+                    state.recv(Arc::as_ref(io).into(), bufs, meta)
+                }) {
+                    break res;
                 }
-
-                return Poll::Ready(Ok(res));
-            }
+            },
+            Io::Custom(ref io) => ready!(io.poll_recv(cx, bufs, meta))?,
+        };
+        for meta in meta.iter().take(res) {
+            trace!(
+                src = %meta.addr,
+                len = meta.len,
+                count = meta.len / meta.stride,
+                dst = %meta.dst_ip.map(|x| x.to_string()).unwrap_or_default(),
+                "UDP recv"
+            );
         }
+
+        Poll::Ready(Ok(res))
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -134,12 +185,13 @@ impl AsyncUdpSocket for RebindingUdpConn {
 }
 
 fn bind(
-    inner: Option<&UdpSocket>,
-    port: u16,
-    network: IpFamily,
+    binder: Option<&Arc<dyn UdpBinder>>,
+    inner: Option<&Io>,
+    addr: SocketAddr,
     cur_port_fate: CurrentPortFate,
-) -> anyhow::Result<UdpSocket> {
-    debug!(?network, %port, ?cur_port_fate, "binding");
+) -> anyhow::Result<Io> {
+    let network = IpFamily::from(addr.ip());
+    debug!(%addr, ?cur_port_fate, "binding");
 
     // Build a list of preferred ports.
     // - Best is the port that the user requested.
@@ -147,8 +199,8 @@ fn bind(
     // - If those fail, fall back to 0.
 
     let mut ports = Vec::new();
-    if port != 0 {
-        ports.push(port);
+    if addr.port() != 0 {
+        ports.push(addr.port());
     }
     if cur_port_fate == CurrentPortFate::Keep {
         if let Some(cur_addr) = inner.as_ref().and_then(|i| i.local_addr().ok()) {
@@ -167,10 +219,21 @@ fn bind(
             // TODO: inner.close()
         }
         // Open a new one with the desired port.
-        match UdpSocket::bind(network, *port) {
+        let addr = SocketAddr::new(addr.ip(), *port);
+        let res = match binder {
+            Some(binder) => binder
+                .bind(addr)
+                .map(Io::Custom)
+                .with_context(|| format!("{addr:?}")),
+            None => UdpSocket::bind_addr(addr).map(|io| Io::Os {
+                io: Arc::new(io),
+                state: Default::default(),
+            }),
+        };
+        match res {
             Ok(pconn) => {
                 let local_addr = pconn.local_addr().context("UDP socket not bound")?;
-                debug!(?network, %local_addr, "successfully bound");
+                debug!(%local_addr, "successfully bound");
                 return Ok(pconn);
             }
             Err(err) => {
@@ -182,8 +245,9 @@ fn bind(
 
     // Failed to bind, including on port 0 (!).
     bail!(
-        "failed to bind any ports on {:?} (tried {:?})",
+        "failed to bind any ports on {:?} {} (tried {:?})",
         network,
+        addr.ip(),
         ports
     );
 }
@@ -228,10 +292,10 @@ mod tests {
     }
 
     async fn rebinding_conn_send_recv(network: IpFamily) -> Result<()> {
-        let m1 = RebindingUdpConn::bind(0, network)?;
+        let m1 = RebindingUdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0), None)?;
         let (m1, _m1_key) = wrap_socket(m1)?;
 
-        let m2 = RebindingUdpConn::bind(0, network)?;
+        let m2 = RebindingUdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0), None)?;
         let (m2, _m2_key) = wrap_socket(m2)?;
 
         let m1_addr = SocketAddr::new(network.local_addr(), m1.local_addr()?.port());
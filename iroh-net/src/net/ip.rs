@@ -25,6 +25,13 @@ impl LocalAddresses {
     /// If there are no regular addresses it will return any IPv4 linklocal or IPv6 unique local
     /// addresses because we know of environments where these are used with NAT to provide connectivity.
     pub fn new() -> Self {
+        Self::with_filter(|_| true)
+    }
+
+    /// Returns the IP addresses of the interfaces for which `filter` returns `true`.
+    ///
+    /// The filter receives the name of the interface.
+    pub fn with_filter(filter: impl Fn(&str) -> bool) -> Self {
         let ifaces = default_net::interface::get_interfaces();
 
         let mut loopback = Vec::new();
@@ -38,6 +45,9 @@ impl LocalAddresses {
                 // Skip down interfaces
                 continue;
             }
+            if !filter(&iface.name) {
+                continue;
+            }
             let ifc_is_loopback = is_loopback(&iface);
             let addrs = iface
                 .ipv4
@@ -121,7 +131,7 @@ pub(crate) fn is_private_v6(ip: &Ipv6Addr) -> bool {
     ip.octets()[0] & 0xfe == 0xfc
 }
 
-pub(super) fn is_link_local(ip: IpAddr) -> bool {
+pub(crate) fn is_link_local(ip: IpAddr) -> bool {
     match ip {
         IpAddr::V4(ip) => ip.is_link_local(),
         IpAddr::V6(ip) => is_unicast_link_local(ip),
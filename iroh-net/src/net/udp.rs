@@ -36,13 +36,16 @@ impl UdpSocket {
 
     /// Bind to the given port only on localhost.
     pub fn bind_local(network: IpFamily, port: u16) -> Result<Self> {
-        let addr = SocketAddr::new(network.local_addr(), port);
-        Self::bind_raw(addr, true).with_context(|| format!("{addr:?}"))
+        Self::bind_addr(SocketAddr::new(network.local_addr(), port))
     }
 
     /// Bind to the given port and listen on all interfaces.
     pub fn bind(network: IpFamily, port: u16) -> Result<Self> {
-        let addr = SocketAddr::new(network.unspecified_addr(), port);
+        Self::bind_addr(SocketAddr::new(network.unspecified_addr(), port))
+    }
+
+    /// Bind to the given address, which may be a specific local address or unspecified.
+    pub fn bind_addr(addr: SocketAddr) -> Result<Self> {
         Self::bind_raw(addr, true).with_context(|| format!("{addr:?}"))
     }
 
@@ -2,25 +2,30 @@
 //!
 //! Based on <https://github.com/tailscale/tailscale/blob/main/net/netcheck/netcheck.go>
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::{self, Debug};
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
+use futures::future::poll_fn;
 use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{self, mpsc, oneshot};
 use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info_span, trace, warn, Instrument};
 use url::Url;
 
+use crate::magicsock::{CustomUdpSocket, PacketCapture, UdpBinder};
 use crate::net::ip::to_canonical;
 use crate::net::{IpFamily, UdpSocket};
-use crate::util::CancelOnDrop;
+use crate::util::{AbortingJoinHandle, CancelOnDrop};
 
-use super::derp::DerpMap;
+use super::derp::{DerpMap, DerpNode};
 use super::portmapper;
 use super::stun;
 
@@ -32,6 +37,9 @@ use Metrics as NetcheckMetrics;
 
 const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
+/// The number of reports kept for [`Client::history`].
+pub const REPORT_HISTORY_LEN: usize = 32;
+
 /// The maximum latency of all nodes, if none are found yet.
 ///
 /// Normally the max latency of all nodes is computed, but if we don't yet know any nodes
@@ -62,6 +70,16 @@ pub struct Report {
     pub icmpv4: bool,
     /// Whether STUN results depend which STUN server you're talking to (on IPv4).
     pub mapping_varies_by_dest_ip: Option<bool>,
+    /// Whether STUN results depend on the port of the STUN server you're talking to (on
+    /// IPv4).
+    ///
+    /// Probed in the background on full reports using a derp node with a
+    /// [`crate::derp::DerpNode::stun_alt_port`], so reports finished before the first probe
+    /// completed do not include this.
+    pub mapping_varies_by_dest_port: Option<bool>,
+    /// How the NAT filters incoming packets (on IPv4), probed like
+    /// [`Report::mapping_varies_by_dest_port`].
+    pub nat_filtering: Option<NatFiltering>,
     /// Whether the router supports communicating between two local devices through the NATted
     /// public IP address (on IPv4).
     pub hair_pinning: Option<bool>,
@@ -81,15 +99,74 @@ pub struct Report {
     pub global_v6: Option<SocketAddr>,
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
+    ///
+    /// This is only checked on full reports, `None` means the check was not run or did not
+    /// complete.
     pub captive_portal: Option<bool>,
 }
 
+impl Report {
+    /// Classifies how the NAT maps outgoing IPv4 traffic to public addresses.
+    ///
+    /// Returns `None` if there were not enough STUN results to tell.
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        match (
+            self.mapping_varies_by_dest_ip,
+            self.mapping_varies_by_dest_port,
+        ) {
+            (_, Some(true)) => Some(NatMapping::AddressAndPortDependent),
+            (Some(true), _) => Some(NatMapping::AddressDependent),
+            (Some(false), _) | (_, Some(false)) => Some(NatMapping::EndpointIndependent),
+            (None, None) => None,
+        }
+    }
+}
+
 impl fmt::Display for Report {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&self, f)
     }
 }
 
+/// How a NAT chooses the public address for outgoing traffic, as described in RFC 4787.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum NatMapping {
+    /// The same public address is used for all destinations.
+    ///
+    /// This is also reported when there is no NAT at all.
+    #[display("endpoint-independent")]
+    EndpointIndependent,
+    /// The public address changes with the destination IP address.
+    #[display("address-dependent")]
+    AddressDependent,
+    /// The public address changes with the destination IP address and port.
+    #[display("address and port-dependent")]
+    AddressAndPortDependent,
+}
+
+/// Which incoming traffic a NAT lets through to a public address, as described in RFC 4787.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum NatFiltering {
+    /// Packets from any port of an address which was sent to are let through.
+    ///
+    /// NATs which do not filter at all, or only filter on the endpoint, can not be told
+    /// apart from this by a single STUN server and are reported like this as well.
+    #[display("address-dependent")]
+    AddressDependent,
+    /// Only packets from the exact addresses which were sent to are let through.
+    #[display("address and port-dependent")]
+    AddressAndPortDependent,
+}
+
+/// How the NAT maps and filters IPv4 traffic, the result of [`Client::probe_nat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatProbeReport {
+    /// How the NAT filters incoming packets.
+    pub filtering: NatFiltering,
+    /// Whether the mapped address changed with the destination port.
+    pub mapping_varies_by_dest_port: Option<bool>,
+}
+
 /// Latencies per DERP node.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct DerpLatencies(BTreeMap<Url, Duration>);
@@ -177,6 +254,12 @@ struct Reports {
     last: Option<Arc<Report>>,
     /// Time of last full (non-incremental) report.
     last_full: Instant,
+    /// The most recent reports with the time they were finished, oldest first.
+    ///
+    /// Bounded by [`REPORT_HISTORY_LEN`].
+    history: VecDeque<(SystemTime, Arc<Report>)>,
+    /// The most recent NAT classification, added to every report.
+    nat: Option<NatProbeReport>,
 }
 
 impl Default for Reports {
@@ -186,6 +269,8 @@ impl Default for Reports {
             prev: Default::default(),
             last: Default::default(),
             last_full: Instant::now(),
+            history: Default::default(),
+            nat: None,
         }
     }
 }
@@ -196,7 +281,17 @@ impl Client {
     /// This starts a connected actor in the background.  Once the client is dropped it will
     /// stop running.
     pub fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
-        let mut actor = Actor::new(port_mapper)?;
+        Self::with_udp_binder(port_mapper, None)
+    }
+
+    /// Creates a new netcheck client binding the sockets of its probes with `udp_binder`.
+    ///
+    /// Without a binder the sockets of the operating system are used.
+    pub(crate) fn with_udp_binder(
+        port_mapper: Option<portmapper::Client>,
+        udp_binder: Option<Arc<dyn UdpBinder>>,
+    ) -> Result<Self> {
+        let mut actor = Actor::new(port_mapper, udp_binder)?;
         let addr = actor.addr();
         let task =
             tokio::spawn(async move { actor.run().await }.instrument(info_span!("netcheck.actor")));
@@ -262,6 +357,21 @@ impl Client {
         dm: DerpMap,
         stun_conn4: Option<Arc<UdpSocket>>,
         stun_conn6: Option<Arc<UdpSocket>>,
+    ) -> Result<oneshot::Receiver<Result<Arc<Report>>>> {
+        self.run_check(
+            dm,
+            stun_conn4.map(StunSocket::os),
+            stun_conn6.map(StunSocket::os),
+        )
+        .await
+    }
+
+    /// Like [`Client::get_report_channel`], also accepting sockets of a [`UdpBinder`].
+    pub(crate) async fn run_check(
+        &mut self,
+        dm: DerpMap,
+        stun_conn4: Option<StunSocket>,
+        stun_conn6: Option<StunSocket>,
     ) -> Result<oneshot::Receiver<Result<Arc<Report>>>> {
         // TODO: consider if DerpMap should be made to easily clone?  It seems expensive
         // right now.
@@ -276,6 +386,32 @@ impl Client {
             .await?;
         Ok(rx)
     }
+
+    /// Returns the most recent reports with the time they were generated, oldest first.
+    ///
+    /// At most the last [`REPORT_HISTORY_LEN`] reports are kept, failed report runs are not
+    /// included.
+    pub async fn history(&self) -> Result<Vec<(SystemTime, Arc<Report>)>> {
+        let (tx, rx) = oneshot::channel();
+        self.addr.send(Message::History(tx)).await?;
+        rx.await.context("channel closed, actor awol")
+    }
+
+    /// Classifies how the NAT maps and filters IPv4 traffic.
+    ///
+    /// Full reports run this in the background and include the result in the following
+    /// reports.  This runs it right away and returns the error if it fails, using the first
+    /// derp node with a [`crate::derp::DerpNode::stun_alt_port`].
+    pub async fn probe_nat(&self, derp_map: DerpMap) -> Result<NatProbeReport> {
+        let (tx, rx) = oneshot::channel();
+        self.addr
+            .send(Message::ProbeNat {
+                derp_map,
+                response_tx: tx,
+            })
+            .await?;
+        rx.await.context("channel closed, actor awol")?
+    }
 }
 
 #[derive(Debug)]
@@ -305,11 +441,11 @@ pub(crate) enum Message {
         /// other packets from in the magicsocket (`MagicSock`).
         ///
         /// If not provided this will attempt to bind a suitable socket itself.
-        stun_sock_v4: Option<Arc<UdpSocket>>,
+        stun_sock_v4: Option<StunSocket>,
         /// Socket to send IPv6 STUN probes from.
         ///
         /// Like `stun_sock_v4` but for IPv6.
-        stun_sock_v6: Option<Arc<UdpSocket>>,
+        stun_sock_v6: Option<StunSocket>,
         /// Channel to receive the response.
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     },
@@ -332,6 +468,17 @@ pub(crate) enum Message {
     /// The sender is signalled once the STUN packet is registered with the actor and will
     /// correctly accept the STUN response.
     InFlightStun(Inflight, oneshot::Sender<()>),
+    /// Requests the recent report history.
+    History(oneshot::Sender<Vec<(SystemTime, Arc<Report>)>>),
+    /// Classifies the NAT now, see [`Client::probe_nat`].
+    ProbeNat {
+        /// The derp configuration.
+        derp_map: DerpMap,
+        /// Channel to receive the response.
+        response_tx: oneshot::Sender<Result<NatProbeReport>>,
+    },
+    /// The background NAT classification of a full report finished.
+    NatProbeDone(NatProbeReport),
 }
 
 /// Sender to the [`Actor`].
@@ -395,6 +542,8 @@ struct Actor {
     /// The port mapper is responsible for talking to routers via UPnP and the like to try
     /// and open ports.
     port_mapper: Option<portmapper::Client>,
+    /// Binds the sockets of the probes instead of the operating system, if set.
+    udp_binder: Option<Arc<dyn UdpBinder>>,
 
     // Actor state.
     /// Information about the currently in-flight STUN requests.
@@ -403,6 +552,8 @@ struct Actor {
     in_flight_stun_requests: HashMap<stun::TransactionId, Inflight>,
     /// The [`reportgen`] actor currently generating a report.
     current_report_run: Option<ReportRun>,
+    /// The background NAT classification started by the last full report.
+    nat_probe: Option<AbortingJoinHandle<()>>,
 }
 
 impl Actor {
@@ -410,7 +561,10 @@ impl Actor {
     ///
     /// This does not start the actor, see [`Actor::run`] for this.  You should not
     /// normally create this directly but rather create a [`Client`].
-    fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
+    fn new(
+        port_mapper: Option<portmapper::Client>,
+        udp_binder: Option<Arc<dyn UdpBinder>>,
+    ) -> Result<Self> {
         // TODO: consider an instrumented flume channel so we have metrics.
         let (sender, receiver) = mpsc::channel(32);
         Ok(Self {
@@ -419,8 +573,10 @@ impl Actor {
             reports: Default::default(),
             skip_external_network: false,
             port_mapper,
+            udp_binder,
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
+            nat_probe: None,
         })
     }
 
@@ -460,6 +616,20 @@ impl Actor {
                 Message::InFlightStun(inflight, response_tx) => {
                     self.handle_in_flight_stun(inflight, response_tx);
                 }
+                Message::History(response_tx) => {
+                    let history = self.reports.history.iter().cloned().collect();
+                    response_tx.send(history).ok();
+                }
+                Message::ProbeNat {
+                    derp_map,
+                    response_tx,
+                } => {
+                    self.handle_probe_nat(derp_map, response_tx);
+                }
+                Message::NatProbeDone(nat) => {
+                    self.nat_probe = None;
+                    self.reports.nat = Some(nat);
+                }
             }
         }
     }
@@ -472,8 +642,8 @@ impl Actor {
     fn handle_run_check(
         &mut self,
         derp_map: DerpMap,
-        stun_sock_v4: Option<Arc<UdpSocket>>,
-        stun_sock_v6: Option<Arc<UdpSocket>>,
+        stun_sock_v4: Option<StunSocket>,
+        stun_sock_v6: Option<StunSocket>,
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     ) {
         if self.current_report_run.is_some() {
@@ -490,11 +660,21 @@ impl Actor {
         let cancel_token = CancellationToken::new();
         let stun_sock_v4 = match stun_sock_v4 {
             Some(sock) => Some(sock),
-            None => bind_local_stun_socket(IpFamily::V4, self.addr(), cancel_token.clone()),
+            None => bind_local_stun_socket(
+                self.udp_binder.as_ref(),
+                IpFamily::V4,
+                self.addr(),
+                cancel_token.clone(),
+            ),
         };
         let stun_sock_v6 = match stun_sock_v6 {
             Some(sock) => Some(sock),
-            None => bind_local_stun_socket(IpFamily::V6, self.addr(), cancel_token.clone()),
+            None => bind_local_stun_socket(
+                self.udp_binder.as_ref(),
+                IpFamily::V6,
+                self.addr(),
+                cancel_token.clone(),
+            ),
         };
         let mut do_full = self.reports.next_full
             || now.duration_since(self.reports.last_full) > FULL_REPORT_INTERVAL;
@@ -512,6 +692,7 @@ impl Actor {
             self.reports.next_full = false;
             self.reports.last_full = now;
             inc!(NetcheckMetrics, reports_full);
+            self.start_nat_probe(&derp_map);
         }
         inc!(NetcheckMetrics, reports);
 
@@ -519,6 +700,7 @@ impl Actor {
             self.addr(),
             self.reports.last.clone(),
             self.port_mapper.clone(),
+            self.udp_binder.clone(),
             self.skip_external_network,
             derp_map,
             stun_sock_v4,
@@ -532,6 +714,58 @@ impl Actor {
         });
     }
 
+    /// Classifies the NAT in the background, so the report does not wait for it.
+    ///
+    /// The result is added to all reports finished after it arrived.
+    fn start_nat_probe(&mut self, derp_map: &DerpMap) {
+        if self.nat_probe.is_some() {
+            return;
+        }
+        let Some(derp_node) = nat_probe_node(derp_map) else {
+            return;
+        };
+        let udp_binder = self.udp_binder.clone();
+        let addr = self.addr();
+        let task = tokio::spawn(
+            async move {
+                match reportgen::probe_nat(&derp_node, udp_binder.as_ref()).await {
+                    Ok(nat) => {
+                        addr.send(Message::NatProbeDone(nat)).await.ok();
+                    }
+                    Err(err) => debug!("nat probe failed: {err:#}"),
+                }
+            }
+            .instrument(info_span!("nat-probe")),
+        );
+        self.nat_probe = Some(task.into());
+    }
+
+    /// Handles [`Message::ProbeNat`].
+    fn handle_probe_nat(
+        &mut self,
+        derp_map: DerpMap,
+        response_tx: oneshot::Sender<Result<NatProbeReport>>,
+    ) {
+        let Some(derp_node) = nat_probe_node(&derp_map) else {
+            response_tx
+                .send(Err(anyhow!("no derp node with an alternate STUN port")))
+                .ok();
+            return;
+        };
+        let udp_binder = self.udp_binder.clone();
+        let addr = self.addr();
+        tokio::spawn(
+            async move {
+                let res = reportgen::probe_nat(&derp_node, udp_binder.as_ref()).await;
+                if let Ok(nat) = res {
+                    addr.send(Message::NatProbeDone(nat)).await.ok();
+                }
+                response_tx.send(res).ok();
+            }
+            .instrument(info_span!("nat-probe")),
+        );
+    }
+
     fn handle_report_ready(&mut self, report: Box<Report>, derp_map: DerpMap) {
         let report = self.finish_and_store_report(*report, &derp_map);
         self.in_flight_stun_requests.clear();
@@ -621,6 +855,10 @@ impl Actor {
     /// Adds `r` to the set of recent Reports and mutates `r.preferred_derp` to contain the best recent one.
     /// `r` is stored ref counted and a reference is returned.
     fn add_report_history_and_set_preferred_derp(&mut self, mut r: Report) -> Arc<Report> {
+        if let Some(nat) = self.reports.nat {
+            r.mapping_varies_by_dest_port = nat.mapping_varies_by_dest_port;
+            r.nat_filtering = Some(nat.filtering);
+        }
         let mut prev_derp = None;
         if let Some(ref last) = self.reports.last {
             prev_derp = last.preferred_derp.clone();
@@ -683,6 +921,12 @@ impl Actor {
         let r = Arc::new(r);
         self.reports.prev.insert(now, r.clone());
         self.reports.last = Some(r.clone());
+        if self.reports.history.len() == REPORT_HISTORY_LEN {
+            self.reports.history.pop_front();
+        }
+        self.reports
+            .history
+            .push_back((SystemTime::now(), r.clone()));
 
         r
     }
@@ -706,6 +950,12 @@ impl Actor {
             write!(log, " v6os={}", r.os_has_ipv6).ok();
         }
         write!(log, " mapvarydest={:?}", r.mapping_varies_by_dest_ip).ok();
+        if let Some(mapping) = r.nat_mapping() {
+            write!(log, " natmap={mapping}").ok();
+        }
+        if let Some(filtering) = r.nat_filtering {
+            write!(log, " natfilter={filtering}").ok();
+        }
         write!(log, " hair={:?}", r.hair_pinning).ok();
         if let Some(probe) = &r.portmap_probe {
             write!(log, " {}", probe).ok();
@@ -757,18 +1007,28 @@ struct ReportRun {
     report_tx: oneshot::Sender<Result<Arc<Report>>>,
 }
 
+/// Returns the first derp node which can classify the NAT.
+fn nat_probe_node(derp_map: &DerpMap) -> Option<Arc<DerpNode>> {
+    derp_map
+        .nodes()
+        .map(|(_, node)| node)
+        .find(|node| node.stun_alt_port.is_some())
+        .cloned()
+}
+
 /// Attempts to bind a local socket to send STUN packets from.
 ///
 /// If successfull this returns the bound socket and will forward STUN responses to the
 /// provided *actor_addr*.  The *cancel_token* serves to stop the packet forwarding when the
 /// socket is no longer needed.
 fn bind_local_stun_socket(
+    udp_binder: Option<&Arc<dyn UdpBinder>>,
     network: IpFamily,
     actor_addr: Addr,
     cancel_token: CancellationToken,
-) -> Option<Arc<UdpSocket>> {
-    let sock = match UdpSocket::bind(network, 0) {
-        Ok(sock) => Arc::new(sock),
+) -> Option<StunSocket> {
+    let sock = match StunSocket::bind(udp_binder, network) {
+        Ok(sock) => sock,
         Err(err) => {
             debug!("failed to bind STUN socket: {}", err);
             return None;
@@ -809,7 +1069,7 @@ fn bind_local_stun_socket(
 }
 
 /// Receive STUN response from a UDP socket, pass it to the actor.
-async fn recv_stun_once(sock: &UdpSocket, buf: &mut [u8], actor_addr: &Addr) -> Result<()> {
+async fn recv_stun_once(sock: &StunSocket, buf: &mut [u8], actor_addr: &Addr) -> Result<()> {
     let (count, mut from_addr) = sock
         .recv_from(buf)
         .await
@@ -823,6 +1083,108 @@ async fn recv_stun_once(sock: &UdpSocket, buf: &mut [u8], actor_addr: &Addr) ->
     actor_addr.send(msg).await.context("actor stopped")
 }
 
+/// A socket STUN probes are sent from.
+///
+/// This is either a socket of the operating system or one created by the [`UdpBinder`] of
+/// the magicsock, so netcheck runs over the same network as the rest of the magicsock.
+#[derive(Debug, Clone)]
+pub(crate) struct StunSocket {
+    io: StunIo,
+    /// Records the sent packets, for the sockets of a magicsock with a packet capture.
+    capture: Option<PacketCapture>,
+}
+
+#[derive(Debug, Clone)]
+enum StunIo {
+    /// A socket of the operating system.
+    Os(Arc<UdpSocket>),
+    /// A socket created by a [`UdpBinder`].
+    Custom(CustomUdpSocket),
+}
+
+impl StunSocket {
+    /// Uses a socket of the operating system.
+    pub(crate) fn os(sock: Arc<UdpSocket>) -> Self {
+        Self {
+            io: StunIo::Os(sock),
+            capture: None,
+        }
+    }
+
+    /// Uses a socket created by a [`UdpBinder`].
+    pub(crate) fn custom(sock: CustomUdpSocket) -> Self {
+        Self {
+            io: StunIo::Custom(sock),
+            capture: None,
+        }
+    }
+
+    /// Records the packets sent on this socket in `capture`.
+    pub(crate) fn with_capture(mut self, capture: Option<PacketCapture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Binds a socket on the unspecified address of `network` with a random port.
+    ///
+    /// Uses `udp_binder` if given, otherwise the operating system.
+    pub(crate) fn bind(udp_binder: Option<&Arc<dyn UdpBinder>>, network: IpFamily) -> Result<Self> {
+        match udp_binder {
+            Some(binder) => {
+                let addr = SocketAddr::new(network.unspecified_addr(), 0);
+                Ok(Self::custom(binder.bind(addr)?))
+            }
+            None => Ok(Self::os(Arc::new(UdpSocket::bind(network, 0)?))),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.io {
+            StunIo::Os(ref sock) => sock.local_addr(),
+            StunIo::Custom(ref sock) => sock.local_addr(),
+        }
+    }
+
+    /// Sends `buf` to `dst`, returning the number of bytes sent.
+    pub(crate) async fn send_to(&self, buf: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        let n = match self.io {
+            StunIo::Os(ref sock) => sock.send_to(buf, dst).await?,
+            StunIo::Custom(ref sock) => {
+                let transmit = quinn_udp::Transmit {
+                    destination: dst,
+                    ecn: None,
+                    contents: Bytes::copy_from_slice(buf),
+                    segment_size: None,
+                    src_ip: None,
+                };
+                let state = quinn_udp::UdpState::default();
+                poll_fn(|cx| sock.poll_send(&state, cx, std::slice::from_ref(&transmit))).await?;
+                buf.len()
+            }
+        };
+        if let Some(ref capture) = self.capture {
+            capture.record_stun_sent(self.local_addr()?, dst, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    /// Receives a single datagram, returning its length and source address.
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.io {
+            StunIo::Os(ref sock) => sock.recv_from(buf).await,
+            StunIo::Custom(ref sock) => {
+                let mut meta = quinn_udp::RecvMeta::default();
+                poll_fn(|cx| {
+                    let mut bufs = [io::IoSliceMut::new(buf)];
+                    sock.poll_recv(cx, &mut bufs, std::slice::from_mut(&mut meta))
+                })
+                .await?;
+                Ok((meta.len, meta.addr))
+            }
+        }
+    }
+}
+
 /// Test if IPv6 works at all, or if it's been hard disabled at the OS level.
 pub(crate) fn os_has_ipv6() -> bool {
     UdpSocket::bind_local_v6(0).is_ok()
@@ -880,6 +1242,36 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_nat_probe() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let (node, _server) = reportgen::tests::serve_stun_alt().await?;
+        let dm = DerpMap::from_nodes([node])?;
+        let mut client = Client::new(None)?;
+
+        // The first report is full and starts the probe, but does not wait for it.
+        let r = client.get_report(dm.clone(), None, None).await?;
+        assert!(r.udp);
+
+        let want = NatProbeReport {
+            filtering: NatFiltering::AddressDependent,
+            mapping_varies_by_dest_port: Some(false),
+        };
+        assert_eq!(client.probe_nat(dm.clone()).await?, want);
+
+        // Later reports include the classification.
+        let r = client.get_report(dm, None, None).await?;
+        assert_eq!(r.nat_filtering, Some(want.filtering));
+        assert_eq!(
+            r.mapping_varies_by_dest_port,
+            want.mapping_varies_by_dest_port
+        );
+
+        let err = client.probe_nat(DerpMap::empty()).await.unwrap_err();
+        assert!(err.to_string().contains("alternate STUN port"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_iroh_computer_stun() -> Result<()> {
         let _guard = iroh_test::logging::setup();
@@ -891,6 +1283,8 @@ mod tests {
             url: url.clone(),
             stun_only: true,
             stun_port: DEFAULT_DERP_STUN_PORT,
+            stun_alt_port: None,
+            auth_token: None,
         }])
         .expect("hardcoded");
 
@@ -1147,7 +1541,7 @@ mod tests {
         ];
         for mut tt in tests {
             println!("test: {}", tt.name);
-            let mut actor = Actor::new(None).unwrap();
+            let mut actor = Actor::new(None, None).unwrap();
             for s in &mut tt.steps {
                 // trigger the timer
                 time::advance(Duration::from_secs(s.after)).await;
@@ -1166,6 +1560,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_report_history_bounded() {
+        let mut actor = Actor::new(None, None).unwrap();
+        for _ in 0..REPORT_HISTORY_LEN + 3 {
+            actor.add_report_history_and_set_preferred_derp(Report::default());
+        }
+        assert_eq!(actor.reports.history.len(), REPORT_HISTORY_LEN);
+        let times: Vec<_> = actor.reports.history.iter().map(|(t, _)| *t).collect();
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+        let (_, newest) = actor.reports.history.back().unwrap();
+        assert!(Arc::ptr_eq(newest, actor.reports.last.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_nat_mapping() {
+        let mut r = Report::default();
+        assert_eq!(r.nat_mapping(), None);
+        r.mapping_varies_by_dest_ip = Some(false);
+        assert_eq!(r.nat_mapping(), Some(NatMapping::EndpointIndependent));
+        r.mapping_varies_by_dest_port = Some(false);
+        assert_eq!(r.nat_mapping(), Some(NatMapping::EndpointIndependent));
+        r.mapping_varies_by_dest_ip = Some(true);
+        assert_eq!(r.nat_mapping(), Some(NatMapping::AddressDependent));
+        r.mapping_varies_by_dest_port = Some(true);
+        assert_eq!(r.nat_mapping(), Some(NatMapping::AddressAndPortDependent));
+    }
+
     #[tokio::test]
     async fn test_hairpin() -> Result<()> {
         // Hairpinning is initiated after we discover our own IPv4 socket address (IP +
@@ -15,6 +15,7 @@ pub struct Metrics {
     pub reports: Counter,
     pub reports_full: Counter,
     pub reports_error: Counter,
+    pub captive_portals_detected: Counter,
 }
 
 impl Default for Metrics {
@@ -30,6 +31,9 @@ impl Default for Metrics {
             reports: Counter::new("Number of reports executed by netcheck, including full reports"),
             reports_full: Counter::new("Number of full reports executed by netcheck"),
             reports_error: Counter::new("Number of executed reports resulting in an error"),
+            captive_portals_detected: Counter::new(
+                "Number of captive portal checks which found a captive portal",
+            ),
         }
     }
 }
@@ -35,10 +35,11 @@ use super::NetcheckMetrics;
 use crate::defaults::DEFAULT_DERP_STUN_PORT;
 use crate::derp::{DerpMap, DerpNode};
 use crate::dns::DNS_RESOLVER;
+use crate::magicsock::UdpBinder;
 use crate::net::interfaces;
 use crate::net::ip;
-use crate::net::UdpSocket;
-use crate::netcheck::{self, Report};
+use crate::net::IpFamily;
+use crate::netcheck::{self, NatFiltering, NatProbeReport, Report, StunSocket};
 use crate::ping::Pinger;
 use crate::util::{CancelOnDrop, MaybeFuture};
 use crate::{portmapper, stun};
@@ -64,6 +65,12 @@ const CAPTIVE_PORTAL_DELAY: Duration = Duration::from_millis(200);
 /// Timeout for captive portal checks, must be lower than OVERALL_PROBE_TIMEOUT
 const CAPTIVE_PORTAL_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// Timeout for classifying the NAT.
+const NAT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for each STUN response while classifying the NAT.
+const NAT_PROBE_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
 const ENOUGH_NODES: usize = 3;
 
 /// Holds the state for a single invocation of [`netcheck::Client::get_report`].
@@ -80,14 +87,16 @@ impl Client {
     ///
     /// The actor starts running immediately and only generates a single report, after which
     /// it shuts down.  Dropping this handle will abort the actor.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         netcheck: netcheck::Addr,
         last_report: Option<Arc<Report>>,
         port_mapper: Option<portmapper::Client>,
+        udp_binder: Option<Arc<dyn UdpBinder>>,
         skip_external_network: bool,
         derp_map: DerpMap,
-        stun_sock4: Option<Arc<UdpSocket>>,
-        stun_sock6: Option<Arc<UdpSocket>>,
+        stun_sock4: Option<StunSocket>,
+        stun_sock6: Option<StunSocket>,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
@@ -106,7 +115,7 @@ impl Client {
             stun_sock4,
             stun_sock6,
             report: Report::default(),
-            hairpin_actor: hairpin::Client::new(netcheck, addr),
+            hairpin_actor: hairpin::Client::new(netcheck, addr, udp_binder),
             outstanding_tasks: OutstandingTasks::default(),
         };
         let task = tokio::spawn(
@@ -175,9 +184,9 @@ struct Actor {
     /// The DERP configuration.
     derp_map: DerpMap,
     /// Socket to send IPv4 STUN requests from.
-    stun_sock4: Option<Arc<UdpSocket>>,
+    stun_sock4: Option<StunSocket>,
     /// Socket so send IPv6 STUN requests from.
-    stun_sock6: Option<Arc<UdpSocket>>,
+    stun_sock6: Option<StunSocket>,
 
     // Internal state.
     /// Whether we're doing an incremental report.
@@ -732,8 +741,8 @@ enum ProbeError {
 #[instrument(level = "debug", skip_all, fields(probe = %probe))]
 async fn run_probe(
     reportstate: Addr,
-    stun_sock4: Option<Arc<UdpSocket>>,
-    stun_sock6: Option<Arc<UdpSocket>>,
+    stun_sock4: Option<StunSocket>,
+    stun_sock6: Option<StunSocket>,
     derp_node: Arc<DerpNode>,
     probe: Probe,
     netcheck: netcheck::Addr,
@@ -911,13 +920,8 @@ async fn check_captive_portal(dm: &DerpMap, preferred_derp: Option<Url>) -> Resu
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
 
-    // Note: the set of valid characters in a challenge and the total
-    // length is limited; see is_challenge_char in bin/derper for more
-    // details.
-
-    let host_name = url.host_str().unwrap_or_default();
-    let challenge = format!("ts_{}", host_name);
-    let portal_url = format!("http://{}/generate_204", host_name);
+    let portal_url = captive_portal_url(&url)?;
+    let challenge = captive_portal_challenge(&url);
     let res = client
         .request(reqwest::Method::GET, portal_url)
         .header("X-Tailscale-Challenge", &challenge)
@@ -938,10 +942,125 @@ async fn check_captive_portal(dm: &DerpMap, preferred_derp: Option<Url>) -> Resu
         is_valid_response,
     );
     let has_captive = res.status() != 204 || !is_valid_response;
+    if has_captive {
+        inc!(NetcheckMetrics, captive_portals_detected);
+    }
 
     Ok(has_captive)
 }
 
+/// Returns the url of the no-content endpoint of the derp server at `url`.
+///
+/// The endpoint is always served over plain HTTP, as a portal can not intercept TLS
+/// without being noticed.  Derpers serving HTTPS serve it on the default HTTP port, while
+/// plain HTTP derpers serve it on their usual port.
+fn captive_portal_url(url: &Url) -> Result<Url> {
+    let host = url.host_str().context("derp url has no host")?;
+    let mut portal_url: Url = format!("http://{host}/generate_204").parse()?;
+    if url.scheme() == "http" {
+        portal_url
+            .set_port(url.port())
+            .map_err(|_| anyhow!("invalid port"))?;
+    }
+    Ok(portal_url)
+}
+
+/// Returns the challenge to send to the derp server at `url`.
+///
+/// The set of valid characters in a challenge and the total length is limited; see
+/// `is_challenge_char` in bin/derper for more details.  Any other character, like the
+/// brackets and colons of an IPv6 host, is replaced so the derper does not ignore the
+/// challenge and make us think we are behind a captive portal.
+fn captive_portal_challenge(url: &Url) -> String {
+    let host_name = url.host_str().unwrap_or_default();
+    format!("ts_{host_name}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(63)
+        .collect()
+}
+
+/// Classifies how the NAT maps and filters IPv4 traffic.
+///
+/// A fresh socket is used so the existing mappings of the magicsock sockets do not affect
+/// the result.  The alternate STUN port of `derp_node` answers from the primary port, so
+/// the response to a first request to the alternate port only arrives if the NAT lets in
+/// packets from a port it never sent to.  A request to the primary port then opens the NAT
+/// towards it, after which the response to a second request to the alternate port only
+/// arrives if the NAT used the same mapping for both destination ports.
+///
+/// Not getting a response is the answer, so apart from the request to the primary port
+/// missing responses are not errors.  A lost packet is reported as the more restrictive
+/// behaviour.
+pub(super) async fn probe_nat(
+    derp_node: &DerpNode,
+    udp_binder: Option<&Arc<dyn UdpBinder>>,
+) -> Result<NatProbeReport> {
+    let alt_port = derp_node
+        .stun_alt_port
+        .context("derp node has no alternate STUN port")?;
+    let probe = async {
+        let primary_addr = get_derp_addr(derp_node, ProbeProto::StunIpv4).await?;
+        let alt_addr = SocketAddr::new(primary_addr.ip(), alt_port);
+        let sock = StunSocket::bind(udp_binder, IpFamily::V4)?;
+        let mut buf = vec![0u8; 64 << 10];
+
+        let alt_mapped = stun_request(&sock, alt_addr, &mut buf).await?;
+        let primary_mapped = stun_request(&sock, primary_addr, &mut buf)
+            .await?
+            .with_context(|| format!("no STUN response from {primary_addr}"))?;
+        let (filtering, alt_mapped) = match alt_mapped {
+            Some(mapped) => (NatFiltering::AddressDependent, Some(mapped)),
+            None => (
+                NatFiltering::AddressAndPortDependent,
+                stun_request(&sock, alt_addr, &mut buf).await?,
+            ),
+        };
+        let mapping_varies_by_dest_port = match alt_mapped {
+            Some(mapped) => mapped != primary_mapped,
+            None => true,
+        };
+        Ok(NatProbeReport {
+            filtering,
+            mapping_varies_by_dest_port: Some(mapping_varies_by_dest_port),
+        })
+    };
+    time::timeout(NAT_PROBE_TIMEOUT, probe)
+        .await
+        .context("NAT probe timed out")?
+}
+
+/// Sends a STUN binding request to `dst` and waits for the response, from any port.
+///
+/// Returns the mapped address from the response, `None` if it did not arrive in time.
+async fn stun_request(
+    sock: &StunSocket,
+    dst: SocketAddr,
+    buf: &mut [u8],
+) -> Result<Option<SocketAddr>> {
+    let txid = stun::TransactionId::default();
+    sock.send_to(&stun::request(txid), dst).await?;
+    let recv = async {
+        loop {
+            let (len, from) = sock.recv_from(buf).await?;
+            match stun::parse_response(&buf[..len]) {
+                Ok((tx, mapped)) if tx == txid => return Ok::<_, anyhow::Error>(mapped),
+                _ => trace!(%from, "ignoring unexpected packet"),
+            }
+        }
+    };
+    match time::timeout(NAT_PROBE_REPLY_TIMEOUT, recv).await {
+        Ok(res) => res.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
 /// Returns the IP address to use to communicate to this derp node.
 ///
 /// *proto* specifies the protocol we want to use to talk to the node.
@@ -1041,3 +1160,195 @@ async fn measure_https_latency(_node: &DerpNode) -> Result<(Duration, IpAddr)> {
     // // Maybe the server should return the tcpinfo_rtt?
     // return result.ServerProcessing, ip, nil
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_utils::sim::{NatType, SimHost, SimNetwork};
+
+    /// Serves a single HTTP request with the given response.
+    async fn serve_once(response: String) -> Result<(Url, tokio::task::JoinHandle<()>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?).parse()?;
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        Ok((url, task))
+    }
+
+    /// Serves STUN on two ports of localhost, like a derper with an alternate STUN port.
+    ///
+    /// Requests on the alternate port are answered from the primary port.
+    pub(in crate::netcheck) async fn serve_stun_alt(
+    ) -> Result<(DerpNode, tokio::task::JoinHandle<()>)> {
+        let primary = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let alt = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let primary_addr = primary.local_addr()?;
+        let node = DerpNode {
+            url: format!("http://{primary_addr}").parse()?,
+            stun_only: true,
+            stun_port: primary_addr.port(),
+            stun_alt_port: Some(alt.local_addr()?.port()),
+            auth_token: None,
+        };
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let mut alt_buf = vec![0u8; 1024];
+            loop {
+                tokio::select! {
+                    res = primary.recv_from(&mut buf) => {
+                        let (n, from) = res.unwrap();
+                        let txid = stun::parse_binding_request(&buf[..n]).unwrap();
+                        primary.send_to(&stun::response(txid, from), from).await.unwrap();
+                    }
+                    res = alt.recv_from(&mut alt_buf) => {
+                        let (n, from) = res.unwrap();
+                        let txid = stun::parse_binding_request(&alt_buf[..n]).unwrap();
+                        primary.send_to(&stun::response(txid, from), from).await.unwrap();
+                    }
+                }
+            }
+        });
+        Ok((node, task))
+    }
+
+    #[test]
+    fn test_captive_portal_url() {
+        let url: Url = "https://derp.example.com".parse().unwrap();
+        assert_eq!(
+            captive_portal_url(&url).unwrap().as_str(),
+            "http://derp.example.com/generate_204"
+        );
+        let url: Url = "http://127.0.0.1:3340".parse().unwrap();
+        assert_eq!(
+            captive_portal_url(&url).unwrap().as_str(),
+            "http://127.0.0.1:3340/generate_204"
+        );
+    }
+
+    #[test]
+    fn test_captive_portal_challenge() {
+        let url: Url = "https://derp.example.com".parse().unwrap();
+        assert_eq!(captive_portal_challenge(&url), "ts_derp.example.com");
+        let url: Url = "http://[::1]:3340".parse().unwrap();
+        assert_eq!(captive_portal_challenge(&url), "ts____1_");
+    }
+
+    #[tokio::test]
+    async fn test_check_captive_portal() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        // A derper answers the challenge with no content.
+        let (url, task) = serve_once(
+            "HTTP/1.1 204 No Content\r\n\
+             X-Tailscale-Response: response ts_127.0.0.1\r\n\
+             Connection: close\r\n\r\n"
+                .to_string(),
+        )
+        .await?;
+        let found = check_captive_portal(&DerpMap::empty(), Some(url)).await?;
+        assert!(!found);
+        task.await?;
+
+        // A portal intercepts the request with its login page.
+        let body = "<html>please log in</html>";
+        let (url, task) = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        ))
+        .await?;
+        let found = check_captive_portal(&DerpMap::empty(), Some(url)).await?;
+        assert!(found);
+        task.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probe_nat() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        // There is no NAT on localhost.
+        let (node, task) = serve_stun_alt().await?;
+        let report = probe_nat(&node, None).await?;
+        assert_eq!(report.filtering, NatFiltering::AddressDependent);
+        assert_eq!(report.mapping_varies_by_dest_port, Some(false));
+        task.abort();
+
+        Ok(())
+    }
+
+    /// Serves STUN on a primary and an alternate port of a simulated host, answering
+    /// requests on the alternate port from the primary port.
+    fn serve_sim_stun_alt(host: &SimHost) -> Result<(DerpNode, tokio::task::JoinHandle<()>)> {
+        let primary_addr = SocketAddr::new(host.ip(), 3478);
+        let primary = StunSocket::custom(host.bind(primary_addr)?);
+        let alt = StunSocket::custom(host.bind(SocketAddr::new(host.ip(), 3479))?);
+        let node = DerpNode {
+            url: format!("http://{primary_addr}").parse()?,
+            stun_only: true,
+            stun_port: primary_addr.port(),
+            stun_alt_port: Some(3479),
+            auth_token: None,
+        };
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let mut alt_buf = vec![0u8; 1024];
+            loop {
+                tokio::select! {
+                    res = primary.recv_from(&mut buf) => {
+                        let (n, from) = res.unwrap();
+                        let txid = stun::parse_binding_request(&buf[..n]).unwrap();
+                        primary.send_to(&stun::response(txid, from), from).await.unwrap();
+                    }
+                    res = alt.recv_from(&mut alt_buf) => {
+                        let (n, from) = res.unwrap();
+                        let txid = stun::parse_binding_request(&alt_buf[..n]).unwrap();
+                        primary.send_to(&stun::response(txid, from), from).await.unwrap();
+                    }
+                }
+            }
+        });
+        Ok((node, task))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_nat_udp_binder() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let net = SimNetwork::new(0);
+        let (node, task) = serve_sim_stun_alt(&net.add_host())?;
+        for (kind, filtering, varies) in [
+            (
+                NatType::AddressDependent,
+                NatFiltering::AddressDependent,
+                false,
+            ),
+            (
+                NatType::AddressAndPortDependent,
+                NatFiltering::AddressAndPortDependent,
+                false,
+            ),
+            (
+                NatType::Symmetric,
+                NatFiltering::AddressAndPortDependent,
+                true,
+            ),
+        ] {
+            let binder = net.add_host_behind_nat(kind).binder();
+            let report = probe_nat(&node, Some(&binder)).await?;
+            assert_eq!(report.filtering, filtering, "{kind:?}");
+            assert_eq!(report.mapping_varies_by_dest_port, Some(varies), "{kind:?}");
+        }
+        task.abort();
+
+        Ok(())
+    }
+}
@@ -13,6 +13,7 @@
 //! requests to it will fail which is intentional.
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
@@ -20,8 +21,9 @@ use tokio::sync::oneshot;
 use tokio::time::Instant;
 use tracing::{debug, error, info_span, trace, warn, Instrument};
 
-use crate::net::UdpSocket;
-use crate::netcheck::{self, reportgen, Inflight};
+use crate::magicsock::UdpBinder;
+use crate::net::IpFamily;
+use crate::netcheck::{self, reportgen, Inflight, StunSocket};
 use crate::stun;
 use crate::util::CancelOnDrop;
 
@@ -38,13 +40,18 @@ pub(super) struct Client {
 }
 
 impl Client {
-    pub(super) fn new(netcheck: netcheck::Addr, reportgen: reportgen::Addr) -> Self {
+    pub(super) fn new(
+        netcheck: netcheck::Addr,
+        reportgen: reportgen::Addr,
+        udp_binder: Option<Arc<dyn UdpBinder>>,
+    ) -> Self {
         let (addr, msg_rx) = oneshot::channel();
 
         let actor = Actor {
             msg_rx,
             netcheck,
             reportgen,
+            udp_binder,
         };
 
         let task =
@@ -88,6 +95,8 @@ struct Actor {
     msg_rx: oneshot::Receiver<Message>,
     netcheck: netcheck::Addr,
     reportgen: reportgen::Addr,
+    /// Binds the hairpin socket instead of the operating system, if set.
+    udp_binder: Option<Arc<dyn UdpBinder>>,
 }
 
 impl Actor {
@@ -99,7 +108,8 @@ impl Actor {
     }
 
     async fn run_inner(self) -> Result<()> {
-        let socket = UdpSocket::bind_v4(0).context("Failed to bind hairpin socket on 0.0.0.0:0")?;
+        let socket = StunSocket::bind(self.udp_binder.as_ref(), IpFamily::V4)
+            .context("Failed to bind hairpin socket on 0.0.0.0:0")?;
 
         if let Err(err) = Self::prepare_hairpin(&socket).await {
             warn!("unable to send hairpin prep: {err:#}");
@@ -153,7 +163,7 @@ impl Actor {
         Ok(())
     }
 
-    async fn prepare_hairpin(socket: &UdpSocket) -> Result<()> {
+    async fn prepare_hairpin(socket: &StunSocket) -> Result<()> {
         // At least the Apple Airport Extreme doesn't allow hairpin
         // sends from a private socket until it's seen traffic from
         // that src IP:port to something else out on the internet.
@@ -185,6 +195,7 @@ mod tests {
     use tracing::info;
 
     use super::*;
+    use crate::net::UdpSocket;
 
     #[tokio::test]
     async fn test_hairpin_success() {
@@ -214,7 +225,7 @@ mod tests {
         };
 
         // Create hairpin actor
-        let mut actor = Client::new(netcheck_addr, reportstate_addr);
+        let mut actor = Client::new(netcheck_addr, reportstate_addr, None);
 
         // Hairpinning works by asking the hairpin actor to send a STUN request to our
         // discovered public address.  If the router returns it hairpinning works.  We
@@ -285,7 +296,7 @@ mod tests {
         };
 
         // Create hairpin actor
-        let mut client = Client::new(netcheck_addr, reportstate_addr);
+        let mut client = Client::new(netcheck_addr, reportstate_addr, None);
 
         // Save the addr, drop the client
         let addr = client.addr.take();
@@ -595,6 +595,8 @@ mod tests {
                 os_has_ipv6: true,
                 icmpv4: true,
                 mapping_varies_by_dest_ip: Some(false),
+                mapping_varies_by_dest_port: None,
+                nat_filtering: None,
                 hair_pinning: Some(true),
                 portmap_probe: None,
                 preferred_derp: Some(u1.clone()),
@@ -748,6 +750,8 @@ mod tests {
             os_has_ipv6: true,
             icmpv4: true,
             mapping_varies_by_dest_ip: Some(false),
+            mapping_varies_by_dest_port: None,
+            nat_filtering: None,
             hair_pinning: Some(true),
             portmap_probe: None,
             preferred_derp: Some(url_1.clone()),
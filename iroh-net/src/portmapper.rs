@@ -1,7 +1,7 @@
 //! Port mapping client and service.
 
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU16,
     time::{Duration, Instant},
 };
@@ -13,7 +13,13 @@ use tracing::{debug, info_span, trace, Instrument};
 
 use iroh_metrics::inc;
 
-use crate::{net::interfaces::HomeRouter, util};
+use crate::{
+    net::{
+        interfaces::HomeRouter,
+        ip::{is_link_local, is_private_v6},
+    },
+    util,
+};
 
 use current_mapping::CurrentMapping;
 
@@ -86,6 +92,16 @@ pub struct Config {
     pub enable_pcp: bool,
     /// Whether PMP is enabled.
     pub enable_nat_pmp: bool,
+    /// Whether to open an IPv6 firewall pinhole using PCP.
+    ///
+    /// Independent of [`Config::enable_pcp`], which only covers IPv4 mappings. The PCP server
+    /// must be reachable over IPv6 at the default gateway.  Disabled by default.
+    pub enable_pcp_ipv6: bool,
+    /// External addresses which were forwarded to the local port manually.
+    ///
+    /// These are reported by [`Client::static_external_addrs`] as is, without asking the
+    /// router. The local port must match the one forwarded by the router.
+    pub static_external_addrs: Vec<SocketAddr>,
 }
 
 impl Default for Config {
@@ -95,6 +111,8 @@ impl Default for Config {
             enable_upnp: true,
             enable_pcp: true,
             enable_nat_pmp: true,
+            enable_pcp_ipv6: false,
+            static_external_addrs: Vec::new(),
         }
     }
 }
@@ -106,6 +124,10 @@ pub struct Client {
     ///
     /// See [`watch::Receiver`].
     port_mapping: watch::Receiver<Option<SocketAddrV4>>,
+    /// A watcher over the most recent IPv6 pinhole obtained from PCP.
+    pinhole: watch::Receiver<Option<SocketAddrV6>>,
+    /// Manually forwarded external addresses, see [`Config::static_external_addrs`].
+    static_external_addrs: std::sync::Arc<[SocketAddr]>,
     /// Channel used to communicate with the port mapping service.
     service_tx: mpsc::Sender<Message>,
     /// A handle to the service that will cancel the spawned task once the client is dropped.
@@ -123,7 +145,8 @@ impl Client {
     pub fn new(config: Config) -> Self {
         let (service_tx, service_rx) = mpsc::channel(SERVICE_CHANNEL_CAPACITY);
 
-        let (service, watcher) = Service::new(config, service_rx);
+        let static_external_addrs = config.static_external_addrs.clone().into();
+        let (service, watcher, pinhole) = Service::new(config, service_rx);
 
         let handle = util::CancelOnDrop::new(
             "portmap_service",
@@ -135,6 +158,8 @@ impl Client {
 
         Client {
             port_mapping: watcher,
+            pinhole,
+            static_external_addrs,
             service_tx,
             _service_handle: std::sync::Arc::new(handle),
         }
@@ -203,6 +228,18 @@ impl Client {
     pub fn watch_external_address(&self) -> watch::Receiver<Option<SocketAddrV4>> {
         self.port_mapping.clone()
     }
+
+    /// Watch the IPv6 address of the firewall pinhole for changes.
+    ///
+    /// See [`Config::enable_pcp_ipv6`].
+    pub fn watch_pinhole_address(&self) -> watch::Receiver<Option<SocketAddrV6>> {
+        self.pinhole.clone()
+    }
+
+    /// The external addresses forwarded manually, see [`Config::static_external_addrs`].
+    pub fn static_external_addrs(&self) -> &[SocketAddr] {
+        &self.static_external_addrs
+    }
 }
 
 /// Port mapping protocol information obtained during a probe.
@@ -240,6 +277,7 @@ impl Probe {
             enable_upnp,
             enable_pcp,
             enable_nat_pmp,
+            ..
         } = config;
         let mut upnp_probing_task = util::MaybeFuture {
             inner: (enable_upnp && !upnp).then(|| {
@@ -389,6 +427,8 @@ pub struct Service {
     rx: mpsc::Receiver<Message>,
     /// Currently active mapping.
     current_mapping: CurrentMapping,
+    /// Currently open IPv6 pinhole.
+    current_pinhole: CurrentMapping<pcp::Pinhole>,
     /// Last updated probe.
     full_probe: Probe,
     /// Task attempting to get a port mapping.
@@ -396,6 +436,10 @@ pub struct Service {
     /// This task will be cancelled if a request to set the local port arrives before it's
     /// finished.
     mapping_task: Option<util::AbortingJoinHandle<Result<mapping::Mapping>>>,
+    /// Task attempting to open an IPv6 pinhole.
+    pinhole_task: Option<util::AbortingJoinHandle<Result<pcp::Pinhole>>>,
+    /// Nonce of the current pinhole, renewals must reuse it.
+    pinhole_nonce: Option<[u8; 12]>,
     /// Task probing the necessary protocols.
     ///
     /// Requests for a probe that arrive while this task is still in progress will receive the same
@@ -410,8 +454,13 @@ impl Service {
     fn new(
         config: Config,
         rx: mpsc::Receiver<Message>,
-    ) -> (Self, watch::Receiver<Option<SocketAddrV4>>) {
+    ) -> (
+        Self,
+        watch::Receiver<Option<SocketAddrV4>>,
+        watch::Receiver<Option<SocketAddrV6>>,
+    ) {
         let (current_mapping, watcher) = CurrentMapping::new();
+        let (current_pinhole, pinhole_watcher) = CurrentMapping::new();
         let mut full_probe = Probe::empty();
         if let Some(in_the_past) = full_probe
             .last_probe
@@ -425,12 +474,15 @@ impl Service {
             local_port: None,
             rx,
             current_mapping,
+            current_pinhole,
             full_probe,
             mapping_task: None,
+            pinhole_task: None,
+            pinhole_nonce: None,
             probing_task: None,
         };
 
-        (service, watcher)
+        (service, watcher, pinhole_watcher)
     }
 
     /// Clears the current mapping and releases it.
@@ -442,6 +494,16 @@ impl Service {
         }
     }
 
+    /// Closes the current IPv6 pinhole.
+    async fn invalidate_pinhole(&mut self) {
+        self.pinhole_nonce = None;
+        if let Some(old_pinhole) = self.current_pinhole.update(None) {
+            if let Err(e) = old_pinhole.release().await {
+                debug!("failed to release pinhole {e}");
+            }
+        }
+    }
+
     async fn run(mut self) -> Result<()> {
         debug!("portmap starting");
         loop {
@@ -470,6 +532,15 @@ impl Service {
                     };
                     self.on_mapping_result(result);
                 }
+                pinhole_result = util::MaybeFuture{ inner: self.pinhole_task.as_mut() } => {
+                    trace!("tick: pinhole ready");
+                    self.pinhole_task = None;
+                    let result = match pinhole_result {
+                        Ok(result) => result,
+                        Err(join_err) => Err(anyhow!("Failed to obtain a result {join_err}"))
+                    };
+                    self.on_pinhole_result(result);
+                }
                 probe_result = util::MaybeFuture{ inner: self.probing_task.as_mut().map(|(fut, _rec)| fut) } => {
                     trace!("tick: probe ready");
                     // retrieve the receivers and clear the task
@@ -486,6 +557,17 @@ impl Service {
                     }
 
                 }
+                Some(event) = self.current_pinhole.next() => {
+                    trace!("tick: pinhole event {event:?}");
+                    match event {
+                        // renewing with the nonce of the pinhole extends its lifetime, a
+                        // request with a new nonce would be rejected as a conflict
+                        current_mapping::Event::Renew { .. } => {},
+                        // the server forgot the pinhole, open a new one
+                        current_mapping::Event::Expired { .. } => self.pinhole_nonce = None,
+                    }
+                    self.get_pinhole();
+                }
             }
         }
         Ok(())
@@ -526,6 +608,19 @@ impl Service {
         }
     }
 
+    fn on_pinhole_result(&mut self, result: Result<pcp::Pinhole>) {
+        match result {
+            Ok(pinhole) => {
+                self.pinhole_nonce = Some(pinhole.nonce());
+                self.current_pinhole.update(Some(pinhole));
+            }
+            Err(e) => {
+                debug!("failed to open an ipv6 pinhole {e}");
+                inc!(Metrics, pinhole_failures);
+            }
+        }
+    }
+
     async fn handle_msg(&mut self, msg: Message) {
         match msg {
             Message::ProcureMapping => self.update_local_port(self.local_port).await,
@@ -568,15 +663,43 @@ impl Service {
             if external_addr.is_some() {
                 self.invalidate_mapping().await;
             }
+            self.pinhole_task = None;
+            if self.current_pinhole.external().is_some() {
+                self.invalidate_pinhole().await;
+            }
 
             // start a new mapping task to account for the new port if necessary
             self.get_mapping(external_addr);
-        } else if self.current_mapping.external().is_none() {
-            // if the local port has not changed, but there is no active mapping try to get one
-            self.get_mapping(None)
+            self.get_pinhole();
+        } else {
+            if self.current_mapping.external().is_none() {
+                // if the local port has not changed, but there is no active mapping try to get one
+                self.get_mapping(None)
+            }
+            if self.current_pinhole.external().is_none() && self.pinhole_task.is_none() {
+                self.get_pinhole();
+            }
         }
     }
 
+    /// Starts a task opening or renewing an IPv6 pinhole for the local port, if enabled.
+    fn get_pinhole(&mut self) {
+        if !self.config.enable_pcp_ipv6 {
+            return;
+        }
+        let Some(local_port) = self.local_port else {
+            return;
+        };
+        let (local_ip, gateway) = match ipv6_and_gateway() {
+            Ok(ip_and_gw) => ip_and_gw,
+            Err(e) => return trace!("can't open pinhole: {e}"),
+        };
+        inc!(Metrics, pinhole_attempts);
+        debug!("opening an ipv6 pinhole for [{local_ip}]:{local_port}");
+        let task = pcp::Pinhole::new(local_ip, local_port, gateway, self.pinhole_nonce);
+        self.pinhole_task = Some(tokio::spawn(task.instrument(info_span!("pcp6"))).into());
+    }
+
     fn get_mapping(&mut self, external_addr: Option<(Ipv4Addr, NonZeroU16)>) {
         if let Some(local_port) = self.local_port {
             inc!(Metrics, mapping_attempts);
@@ -698,3 +821,21 @@ fn ip_and_gateway() -> Result<(Ipv4Addr, Ipv4Addr)> {
 
     Ok((local_ip, gateway))
 }
+
+/// Gets the global IPv6 address and the IPv6 gateway to open a pinhole with.
+fn ipv6_and_gateway() -> Result<(Ipv6Addr, Ipv6Addr)> {
+    let Some(HomeRouter { gateway, .. }) = HomeRouter::new() else {
+        anyhow::bail!("no gateway found");
+    };
+    let IpAddr::V6(gateway) = gateway else {
+        anyhow::bail!("gateway found is ipv4, no ipv6 pinhole possible");
+    };
+    let interface = default_net::get_default_interface().map_err(|e| anyhow!(e))?;
+    let local_ip = interface
+        .ipv6
+        .iter()
+        .map(|net| net.addr)
+        .find(|ip| !ip.is_loopback() && !is_link_local(IpAddr::V6(*ip)) && !is_private_v6(ip))
+        .ok_or_else(|| anyhow!("no global ipv6 address found"))?;
+    Ok((local_ip, gateway))
+}
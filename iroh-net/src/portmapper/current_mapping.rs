@@ -1,7 +1,7 @@
 //! Holds the current mapping value and ensures that any change is reported accordingly.
 
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU16,
     pin::Pin,
     task::Poll,
@@ -13,14 +13,45 @@ use std::time::Duration;
 use tokio::{sync::watch, time};
 use tracing::{debug, trace};
 
+/// The external IP address of a mapping, either IPv4 or IPv6.
+pub(super) trait ExternalIp: Copy + Eq + std::fmt::Debug {
+    /// How the external address is reported to subscribers.
+    type SocketAddr: Copy + Eq + std::fmt::Debug + Send + Sync + 'static;
+
+    fn socket_addr(self, port: NonZeroU16) -> Self::SocketAddr;
+}
+
+impl ExternalIp for Ipv4Addr {
+    type SocketAddr = SocketAddrV4;
+
+    fn socket_addr(self, port: NonZeroU16) -> SocketAddrV4 {
+        SocketAddrV4::new(self, port.into())
+    }
+}
+
+impl ExternalIp for Ipv6Addr {
+    type SocketAddr = SocketAddrV6;
+
+    fn socket_addr(self, port: NonZeroU16) -> SocketAddrV6 {
+        SocketAddrV6::new(self, port.into(), 0, 0)
+    }
+}
+
+/// The external address reported for a [`Mapping`].
+pub(super) type ExternalAddr<M> = <<M as Mapping>::Ip as ExternalIp>::SocketAddr;
+
 /// This is an implementation detail to facilitate testing.
 pub(super) trait Mapping: std::fmt::Debug + Unpin {
-    fn external(&self) -> (Ipv4Addr, NonZeroU16);
+    type Ip: ExternalIp;
+
+    fn external(&self) -> (Self::Ip, NonZeroU16);
     /// Half the lifetime of a mapping. This is used to calculate when a mapping should be renewed.
     fn half_lifetime(&self) -> Duration;
 }
 
 impl Mapping for super::mapping::Mapping {
+    type Ip = Ipv4Addr;
+
     fn external(&self) -> (Ipv4Addr, NonZeroU16) {
         super::mapping::PortMapped::external(self)
     }
@@ -29,6 +60,17 @@ impl Mapping for super::mapping::Mapping {
     }
 }
 
+impl Mapping for super::pcp::Pinhole {
+    type Ip = Ipv6Addr;
+
+    fn external(&self) -> (Ipv6Addr, NonZeroU16) {
+        super::pcp::Pinhole::external(self)
+    }
+    fn half_lifetime(&self) -> Duration {
+        super::pcp::Pinhole::half_lifetime(self)
+    }
+}
+
 /// Models the lifetime of an active mapping.
 #[derive(Debug)]
 struct ActiveMapping<M> {
@@ -50,26 +92,26 @@ impl<M: Mapping> ActiveMapping<M> {
 
 /// Events in the lifetime of the mapping.
 #[derive(Debug, PartialEq, Eq)]
-pub(super) enum Event {
+pub(super) enum Event<Ip = Ipv4Addr> {
     /// On this event, the mapping is halfway through its lifetime and should be renewed.
     Renew {
-        external_ip: Ipv4Addr,
+        external_ip: Ip,
         external_port: NonZeroU16,
     },
     /// Mapping has expired.
     Expired {
-        external_ip: Ipv4Addr,
+        external_ip: Ip,
         external_port: NonZeroU16,
     },
 }
 
 /// Holds the current mapping value and ensures that any change is reported accordingly.
 #[derive(derive_more::Debug)]
-pub(super) struct CurrentMapping<M = super::mapping::Mapping> {
+pub(super) struct CurrentMapping<M: Mapping = super::mapping::Mapping> {
     /// Active port mapping.
     mapping: Option<ActiveMapping<M>>,
     /// A [`watch::Sender`] that keeps the latest external address for subscribers to changes.
-    address_tx: watch::Sender<Option<SocketAddrV4>>,
+    address_tx: watch::Sender<Option<ExternalAddr<M>>>,
     /// Waker to ensure this is polled when needed.
     #[debug(skip)]
     waker: Option<std::task::Waker>,
@@ -77,7 +119,7 @@ pub(super) struct CurrentMapping<M = super::mapping::Mapping> {
 
 impl<M: Mapping> CurrentMapping<M> {
     /// Creates a new [`CurrentMapping`] and returns the watcher over its external address.
-    pub(super) fn new() -> (Self, watch::Receiver<Option<SocketAddrV4>>) {
+    pub(super) fn new() -> (Self, watch::Receiver<Option<ExternalAddr<M>>>) {
         let (address_tx, address_rx) = watch::channel(None);
         let wrapper = CurrentMapping {
             mapping: None,
@@ -93,7 +135,7 @@ impl<M: Mapping> CurrentMapping<M> {
         debug!("new port mapping {mapping:?}");
         let maybe_external_addr = mapping.as_ref().map(|mapping| {
             let (ip, port) = mapping.external();
-            SocketAddrV4::new(ip, port.into())
+            ip.socket_addr(port)
         });
         let old_mapping = std::mem::replace(&mut self.mapping, mapping.map(ActiveMapping::new))
             .map(|mapping| mapping.mapping);
@@ -115,7 +157,7 @@ impl<M: Mapping> CurrentMapping<M> {
         old_mapping
     }
 
-    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Event> {
+    fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Event<M::Ip>> {
         // grab the waker if needed
         if let Some(waker) = &self.waker {
             if waker.will_wake(cx.waker()) {
@@ -157,7 +199,7 @@ impl<M: Mapping> CurrentMapping<M> {
         Poll::Pending
     }
 
-    pub(crate) fn external(&self) -> Option<(Ipv4Addr, NonZeroU16)> {
+    pub(crate) fn external(&self) -> Option<(M::Ip, NonZeroU16)> {
         self.mapping
             .as_ref()
             .map(|mapping| mapping.mapping.external())
@@ -165,7 +207,7 @@ impl<M: Mapping> CurrentMapping<M> {
 }
 
 impl<M: Mapping> futures::Stream for CurrentMapping<M> {
-    type Item = Event;
+    type Item = Event<M::Ip>;
 
     fn poll_next(
         mut self: Pin<&mut Self>,
@@ -189,6 +231,8 @@ mod tests {
     const HALF_LIFETIME_SECS: u64 = 1;
 
     impl Mapping for M {
+        type Ip = Ipv4Addr;
+
         fn external(&self) -> M {
             *self
         }
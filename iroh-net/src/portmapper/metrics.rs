@@ -29,6 +29,8 @@ pub struct Metrics {
      */
     pub pcp_probes: Counter,
     pub pcp_available: Counter,
+    pub pinhole_attempts: Counter,
+    pub pinhole_failures: Counter,
 }
 
 impl Default for Metrics {
@@ -57,6 +59,8 @@ impl Default for Metrics {
              */
             pcp_probes: Counter::new("Number of PCP probes executed."),
             pcp_available: Counter::new("Number of PCP probes that found it available."),
+            pinhole_attempts: Counter::new("Number of IPv6 pinhole tasks started."),
+            pinhole_failures: Counter::new("Number of failed IPv6 pinhole tasks."),
         }
     }
 }
@@ -1,6 +1,10 @@
 //! Definitions and utilities to interact with a PCP server.
 
-use std::{net::Ipv4Addr, num::NonZeroU16, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroU16,
+    time::Duration,
+};
 
 use rand::RngCore;
 use tracing::{debug, trace};
@@ -54,10 +58,6 @@ impl Mapping {
         gateway: Ipv4Addr,
         preferred_external_address: Option<(Ipv4Addr, NonZeroU16)>,
     ) -> anyhow::Result<Self> {
-        // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT)).await?;
-
         let mut nonce = [0u8; 12];
         rand::thread_rng().fill_bytes(&mut nonce);
 
@@ -75,64 +75,23 @@ impl Mapping {
             MAPPING_REQUESTED_LIFETIME_SECONDS,
         );
 
-        socket.send(&req.encode()).await?;
-
-        // wait for the response and decode it
-        let mut buffer = vec![0; protocol::Response::MAX_SIZE];
-        let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer)).await??;
-        let response = protocol::Response::decode(&buffer[..read])?;
-
-        // verify that the response is correct and matches the request
-        let protocol::Response {
-            lifetime_seconds,
-            epoch_time: _,
-            data,
-        } = response;
-
-        match data {
-            protocol::OpcodeData::MapData(map_data) => {
-                let protocol::MapData {
-                    nonce: received_nonce,
-                    protocol,
-                    local_port: received_local_port,
-                    external_port,
-                    external_address,
-                } = map_data;
-
-                if nonce != received_nonce {
-                    anyhow::bail!("received nonce does not match sent request");
-                }
+        let server = (gateway, protocol::SERVER_PORT).into();
+        let granted = request_map(local_ip.into(), server, req, nonce, local_port).await?;
 
-                if protocol != protocol::MapProtocol::Udp {
-                    anyhow::bail!("received mapping is not for UDP");
-                }
+        let external_address = granted
+            .external_address
+            .to_ipv4_mapped()
+            .ok_or(anyhow::anyhow!("received external address is not ipv4"))?;
 
-                let sent_port: u16 = local_port.into();
-                if received_local_port != sent_port {
-                    anyhow::bail!("received mapping is for a local port that does not match the requested one");
-                }
-                let external_port = external_port
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("received 0 external port for mapping"))?;
-
-                let external_address = external_address
-                    .to_ipv4_mapped()
-                    .ok_or(anyhow::anyhow!("received external address is not ipv4"))?;
-
-                Ok(Mapping {
-                    external_port,
-                    external_address,
-                    lifetime_seconds,
-                    nonce,
-                    local_ip,
-                    local_port,
-                    gateway,
-                })
-            }
-            protocol::OpcodeData::Announce => {
-                anyhow::bail!("received an announce response for a map request")
-            }
-        }
+        Ok(Mapping {
+            external_port: granted.external_port,
+            external_address,
+            lifetime_seconds: granted.lifetime_seconds,
+            nonce,
+            local_ip,
+            local_port,
+            gateway,
+        })
     }
 
     pub async fn release(self) -> anyhow::Result<()> {
@@ -144,20 +103,203 @@ impl Mapping {
             ..
         } = self;
 
-        // create the socket and send the request
-        let socket = UdpSocket::bind_full((local_ip, 0))?;
-        socket.connect((gateway, protocol::SERVER_PORT)).await?;
-
         let local_port = local_port.into();
         let req = protocol::Request::mapping(nonce, local_port, local_ip, None, None, 0);
+        let server = (gateway, protocol::SERVER_PORT).into();
+        send_release(local_ip.into(), server, req).await
+    }
+}
 
-        socket.send(&req.encode()).await?;
+/// An IPv6 firewall pinhole sucessfully opened with a PCP server.
+///
+/// IPv6 traffic is not translated, but the firewall of the router might drop unsolicited
+/// incoming packets. The pinhole allows them to reach the local address and port.
+#[derive(Debug)]
+pub struct Pinhole {
+    /// Local ip the pinhole allows traffic to.
+    local_ip: Ipv6Addr,
+    /// Local port the pinhole allows traffic to.
+    local_port: NonZeroU16,
+    /// Address of the PCP server which opened this pinhole.
+    server: SocketAddr,
+    /// External port of the pinhole, usually the local port.
+    external_port: NonZeroU16,
+    /// External address of the pinhole, usually the local address.
+    external_address: Ipv6Addr,
+    /// Allowed time for this pinhole as informed by the server.
+    lifetime_seconds: u32,
+    /// The nonce of the pinhole, identifying it to the PCP server when renewing or releasing
+    /// it.
+    nonce: [u8; 12],
+}
 
-        // mapping deletion is a notification, no point in waiting for the response
-        Ok(())
+impl Pinhole {
+    /// Attempt to open a pinhole for the local address with the PCP server on the gateway.
+    ///
+    /// To renew a pinhole pass the [`Pinhole::nonce`] of the previous one, the server only
+    /// extends the lifetime of the pinhole with the same nonce.
+    pub async fn new(
+        local_ip: Ipv6Addr,
+        local_port: NonZeroU16,
+        gateway: Ipv6Addr,
+        nonce: Option<[u8; 12]>,
+    ) -> anyhow::Result<Self> {
+        let server = (gateway, protocol::SERVER_PORT).into();
+        Self::with_server(local_ip, local_port, server, nonce).await
+    }
+
+    async fn with_server(
+        local_ip: Ipv6Addr,
+        local_port: NonZeroU16,
+        server: SocketAddr,
+        nonce: Option<[u8; 12]>,
+    ) -> anyhow::Result<Self> {
+        let nonce = nonce.unwrap_or_else(|| {
+            let mut nonce = [0u8; 12];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            nonce
+        });
+
+        let req = protocol::Request::pinhole(
+            nonce,
+            local_port.into(),
+            local_ip,
+            MAPPING_REQUESTED_LIFETIME_SECONDS,
+        );
+        let granted = request_map(local_ip.into(), server, req, nonce, local_port).await?;
+
+        if granted.external_address.to_ipv4_mapped().is_some() {
+            anyhow::bail!("received external address is not ipv6");
+        }
+
+        Ok(Pinhole {
+            local_ip,
+            local_port,
+            server,
+            external_port: granted.external_port,
+            external_address: granted.external_address,
+            lifetime_seconds: granted.lifetime_seconds,
+            nonce,
+        })
+    }
+
+    /// The address other nodes can reach us on.
+    pub fn external(&self) -> (Ipv6Addr, NonZeroU16) {
+        (self.external_address, self.external_port)
+    }
+
+    /// Half the lifetime of the pinhole, when it should be renewed.
+    pub fn half_lifetime(&self) -> Duration {
+        Duration::from_secs((self.lifetime_seconds / 2).into())
+    }
+
+    /// The nonce identifying this pinhole to the PCP server.
+    pub fn nonce(&self) -> [u8; 12] {
+        self.nonce
+    }
+
+    pub async fn release(self) -> anyhow::Result<()> {
+        let Pinhole {
+            nonce,
+            local_ip,
+            local_port,
+            server,
+            ..
+        } = self;
+
+        let req = protocol::Request::pinhole(nonce, local_port.into(), local_ip, 0);
+        send_release(local_ip.into(), server, req).await
+    }
+}
+
+/// A mapping granted by the PCP server in response to a MAP request.
+#[derive(Debug)]
+struct GrantedMap {
+    external_port: NonZeroU16,
+    external_address: Ipv6Addr,
+    lifetime_seconds: u32,
+}
+
+/// Sends a MAP request to the PCP server and verifies the response matches it.
+async fn request_map(
+    local_ip: IpAddr,
+    server: SocketAddr,
+    req: protocol::Request,
+    nonce: [u8; 12],
+    local_port: NonZeroU16,
+) -> anyhow::Result<GrantedMap> {
+    // create the socket and send the request
+    let socket = UdpSocket::bind_full((local_ip, 0))?;
+    socket.connect(server).await?;
+    socket.send(&req.encode()).await?;
+
+    // wait for the response and decode it
+    let mut buffer = vec![0; protocol::Response::MAX_SIZE];
+    let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer)).await??;
+    let response = protocol::Response::decode(&buffer[..read])?;
+
+    // verify that the response is correct and matches the request
+    let protocol::Response {
+        lifetime_seconds,
+        epoch_time: _,
+        data,
+    } = response;
+
+    match data {
+        protocol::OpcodeData::MapData(map_data) => {
+            let protocol::MapData {
+                nonce: received_nonce,
+                protocol,
+                local_port: received_local_port,
+                external_port,
+                external_address,
+            } = map_data;
+
+            if nonce != received_nonce {
+                anyhow::bail!("received nonce does not match sent request");
+            }
+
+            if protocol != protocol::MapProtocol::Udp {
+                anyhow::bail!("received mapping is not for UDP");
+            }
+
+            let sent_port: u16 = local_port.into();
+            if received_local_port != sent_port {
+                anyhow::bail!(
+                    "received mapping is for a local port that does not match the requested one"
+                );
+            }
+            let external_port = external_port
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("received 0 external port for mapping"))?;
+
+            Ok(GrantedMap {
+                external_port,
+                external_address,
+                lifetime_seconds,
+            })
+        }
+        protocol::OpcodeData::Announce => {
+            anyhow::bail!("received an announce response for a map request")
+        }
     }
 }
 
+/// Sends a MAP request with a zero lifetime, deleting the mapping.
+async fn send_release(
+    local_ip: IpAddr,
+    server: SocketAddr,
+    req: protocol::Request,
+) -> anyhow::Result<()> {
+    // create the socket and send the request
+    let socket = UdpSocket::bind_full((local_ip, 0))?;
+    socket.connect(server).await?;
+    socket.send(&req.encode()).await?;
+
+    // mapping deletion is a notification, no point in waiting for the response
+    Ok(())
+}
+
 /// Probes the local gateway for PCP support.
 pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> bool {
     match probe_available_fallible(local_ip, gateway).await {
@@ -201,3 +343,75 @@ async fn probe_available_fallible(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers PCP MAP requests like a router, forwarding every received request.
+    async fn serve_pcp(
+        lifetime_seconds: u32,
+    ) -> anyhow::Result<(
+        SocketAddr,
+        tokio::sync::mpsc::Receiver<protocol::Request>,
+        tokio::task::JoinHandle<()>,
+    )> {
+        let socket = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1100];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = protocol::Request::decode(&buf[..n]);
+                let protocol::OpcodeData::MapData(ref map) = request.opcode_data else {
+                    panic!("expected a map request");
+                };
+                let response = protocol::Response {
+                    lifetime_seconds,
+                    epoch_time: 0,
+                    data: protocol::OpcodeData::MapData(protocol::MapData {
+                        nonce: map.nonce,
+                        protocol: protocol::MapProtocol::Udp,
+                        local_port: map.local_port,
+                        external_port: map.external_port,
+                        external_address: map.external_address,
+                    }),
+                };
+                socket.send_to(&response.encode(), from).await.unwrap();
+                tx.send(request).await.unwrap();
+            }
+        });
+        Ok((addr, rx, task))
+    }
+
+    #[tokio::test]
+    async fn test_pinhole_lifecycle() -> anyhow::Result<()> {
+        let (server, mut requests, _server_task) = serve_pcp(120).await?;
+        let local_ip = Ipv6Addr::LOCALHOST;
+        let local_port = NonZeroU16::new(4433).unwrap();
+
+        let pinhole = Pinhole::with_server(local_ip, local_port, server, None).await?;
+        assert_eq!(pinhole.external(), (local_ip, local_port));
+        assert_eq!(pinhole.half_lifetime(), Duration::from_secs(60));
+        let nonce = pinhole.nonce();
+        let expected = |lifetime| protocol::Request::pinhole(nonce, 4433, local_ip, lifetime);
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            expected(MAPPING_REQUESTED_LIFETIME_SECONDS)
+        );
+
+        // Renewing reuses the nonce, so the server extends the same pinhole.
+        let renewed = Pinhole::with_server(local_ip, local_port, server, Some(nonce)).await?;
+        assert_eq!(renewed.nonce(), nonce);
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            expected(MAPPING_REQUESTED_LIFETIME_SECONDS)
+        );
+
+        // Releasing asks for a zero lifetime, again with the same nonce.
+        renewed.release().await?;
+        assert_eq!(requests.recv().await.unwrap(), expected(0));
+        Ok(())
+    }
+}
@@ -23,7 +23,7 @@ pub struct Request {
     /// If the IP is an IpV4 address, is represented as a IpV4-mapped IpV6 address.
     pub(super) client_addr: Ipv6Addr,
     /// Data associated to the [`super::Opcode`] in this request.
-    pub(super) opcode_data: OpcodeData,
+    pub(crate) opcode_data: OpcodeData,
 }
 
 impl Request {
@@ -101,6 +101,30 @@ impl Request {
         }
     }
 
+    /// Create a request to open an IPv6 firewall pinhole.
+    ///
+    /// There is no translation for IPv6, the server is asked to allow incoming traffic to
+    /// `local_ip` and `local_port`.
+    pub fn pinhole(
+        nonce: [u8; 12],
+        local_port: u16,
+        local_ip: Ipv6Addr,
+        lifetime_seconds: u32,
+    ) -> Request {
+        Request {
+            version: Version::Pcp,
+            lifetime_seconds,
+            client_addr: local_ip,
+            opcode_data: OpcodeData::MapData(MapData {
+                nonce,
+                protocol: MapProtocol::Udp,
+                local_port,
+                external_port: local_port,
+                external_address: local_ip,
+            }),
+        }
+    }
+
     #[cfg(test)]
     fn random<R: rand::Rng>(opcode: super::Opcode, rng: &mut R) -> Self {
         let opcode_data = OpcodeData::random(opcode, rng);
@@ -115,7 +139,7 @@ impl Request {
 
     #[cfg(test)]
     #[track_caller]
-    fn decode(buf: &[u8]) -> Self {
+    pub(crate) fn decode(buf: &[u8]) -> Self {
         let version: Version = buf[0].try_into().unwrap();
         let opcode: super::Opcode = buf[1].try_into().unwrap();
         // buf[2] reserved
@@ -159,4 +183,19 @@ mod tests {
         let encoded = request.encode();
         assert_eq!(request, Request::decode(&encoded));
     }
+
+    #[test]
+    fn test_pinhole_request() {
+        let local_ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let request = Request::pinhole([7; 12], 4433, local_ip, 120);
+        let decoded = Request::decode(&request.encode());
+        assert_eq!(decoded.client_addr, local_ip);
+        assert_eq!(decoded.lifetime_seconds, 120);
+        let OpcodeData::MapData(map_data) = decoded.opcode_data else {
+            panic!("expected map data");
+        };
+        assert_eq!(map_data.local_port, 4433);
+        assert_eq!(map_data.external_port, 4433);
+        assert_eq!(map_data.external_address, local_ip);
+    }
 }
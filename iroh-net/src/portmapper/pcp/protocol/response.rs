@@ -225,7 +225,7 @@ impl Response {
     }
 
     #[cfg(test)]
-    fn encode(&self) -> Vec<u8> {
+    pub(crate) fn encode(&self) -> Vec<u8> {
         let Response {
             lifetime_seconds,
             epoch_time,
@@ -190,7 +190,9 @@ pub mod test {
             DerpNode {
                 url: url.clone(),
                 stun_port: port,
+                stun_alt_port: None,
                 stun_only,
+                auth_token: None,
             }
         });
         DerpMap::from_nodes(nodes).expect("generated invalid nodes")
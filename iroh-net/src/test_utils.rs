@@ -8,6 +8,8 @@ use url::Url;
 use crate::derp::{DerpMap, DerpNode};
 use crate::key::SecretKey;
 
+pub(crate) mod sim;
+
 /// A drop guard to clean up test infrastructure.
 ///
 /// After dropping the test infrastructure will asynchronously shutdown and release its
@@ -48,6 +50,8 @@ pub(crate) async fn run_derper() -> Result<(DerpMap, Url, CleanupDropGuard)> {
         url: url.clone(),
         stun_only: false,
         stun_port: stun_addr.port(),
+        stun_alt_port: None,
+        auth_token: None,
     }])
     .expect("hardcoded");
 
@@ -0,0 +1,718 @@
+//! A deterministic in-memory network to run several [`MagicEndpoint`]s in one process.
+//!
+//! Hosts are attached to a single simulated internet, optionally behind a NAT.  Packet loss,
+//! latency, partitions and address changes are controlled by the test.  Packet loss is
+//! decided by a seeded RNG and latency uses [`tokio::time`], so a test running with a paused
+//! clock sees the same packet fates on every run.
+//!
+//! [`MagicEndpoint`]: crate::MagicEndpoint
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quinn::AsyncUdpSocket;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::{Instant, Sleep};
+use tracing::trace;
+
+use crate::magicsock::{CustomUdpSocket, UdpBinder};
+
+/// First port handed out to sockets bound to port `0`.
+const FIRST_EPHEMERAL_PORT: u16 = 10000;
+
+/// First public port handed out by a NAT.
+const FIRST_NAT_PORT: u16 = 40000;
+
+/// How a simulated NAT maps and filters traffic, named after RFC 4787.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NatType {
+    /// One mapping per local address which anyone may send to, a "full cone" NAT.
+    EndpointIndependent,
+    /// One mapping per local address, only IPs which were sent to may reply.
+    AddressDependent,
+    /// One mapping per local address, only addresses which were sent to may reply.
+    AddressAndPortDependent,
+    /// One mapping per pair of local and remote address, defeats most holepunching.
+    Symmetric,
+}
+
+type HostId = usize;
+
+/// A simulated network, cloning it gives another handle to the same network.
+#[derive(Debug, Clone)]
+pub(crate) struct SimNetwork(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    rng: StdRng,
+    hosts: Vec<Host>,
+    sockets: HashMap<SocketAddr, Inbox>,
+    loss: f64,
+    latency: Duration,
+    partitions: HashSet<(HostId, HostId)>,
+    public_ips: u32,
+}
+
+#[derive(Debug)]
+struct Host {
+    ip: Ipv4Addr,
+    nat: Option<Nat>,
+    next_port: u16,
+}
+
+#[derive(Debug)]
+struct Nat {
+    kind: NatType,
+    public_ip: Ipv4Addr,
+    next_port: u16,
+    /// Public port for a local address, and for the remote address on symmetric NATs.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// Local address behind a public port and the remote addresses it sent to.
+    ports: HashMap<u16, (SocketAddr, HashSet<SocketAddr>)>,
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    datagrams: VecDeque<Datagram>,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    src: SocketAddr,
+    data: Bytes,
+    deliver_at: Instant,
+}
+
+impl Nat {
+    fn new(kind: NatType, public_ip: Ipv4Addr) -> Self {
+        Self {
+            kind,
+            public_ip,
+            next_port: FIRST_NAT_PORT,
+            mappings: Default::default(),
+            ports: Default::default(),
+        }
+    }
+
+    /// Returns the public port for a packet from `local` to `dst`.
+    fn map_outgoing(&mut self, local: SocketAddr, dst: SocketAddr) -> u16 {
+        let remote = match self.kind {
+            NatType::Symmetric => Some(dst),
+            _ => None,
+        };
+        let port = match self.mappings.get(&(local, remote)) {
+            Some(port) => *port,
+            None => {
+                let port = self.next_port;
+                self.next_port += 1;
+                self.mappings.insert((local, remote), port);
+                self.ports.insert(port, (local, HashSet::new()));
+                port
+            }
+        };
+        self.ports
+            .get_mut(&port)
+            .expect("just mapped")
+            .1
+            .insert(dst);
+        port
+    }
+
+    /// Returns the local address a packet from `src` to the public `port` is let through to.
+    fn map_incoming(&self, src: SocketAddr, port: u16) -> Option<SocketAddr> {
+        let (local, remotes) = self.ports.get(&port)?;
+        let allowed = match self.kind {
+            NatType::EndpointIndependent => true,
+            NatType::AddressDependent => remotes.iter().any(|r| r.ip() == src.ip()),
+            NatType::AddressAndPortDependent | NatType::Symmetric => remotes.contains(&src),
+        };
+        allowed.then_some(*local)
+    }
+}
+
+impl State {
+    /// Hands out addresses from 198.18.0.0/15, which is reserved for benchmarking.
+    fn next_public_ip(&mut self) -> Ipv4Addr {
+        self.public_ips += 1;
+        Ipv4Addr::from(u32::from(Ipv4Addr::new(198, 18, 0, 0)) + self.public_ips)
+    }
+
+    fn send(&mut self, from: HostId, src: SocketAddr, dst: SocketAddr, data: Bytes) {
+        let src = match self.hosts[from].nat {
+            Some(ref mut nat) => SocketAddr::new(nat.public_ip.into(), nat.map_outgoing(src, dst)),
+            None => src,
+        };
+        let Some((to, local)) = self.route(src, dst) else {
+            trace!(%src, %dst, "sim: dropped, no route");
+            return;
+        };
+        if self.partitions.contains(&partition_key(from, to)) {
+            trace!(%src, %dst, "sim: dropped, partitioned");
+            return;
+        }
+        if self.loss > 0.0 && self.rng.gen_bool(self.loss) {
+            trace!(%src, %dst, "sim: dropped, lost");
+            return;
+        }
+        let deliver_at = Instant::now() + self.latency;
+        let Some(inbox) = self.sockets.get_mut(&local) else {
+            trace!(%src, %dst, "sim: dropped, no socket");
+            return;
+        };
+        inbox.datagrams.push_back(Datagram {
+            src,
+            data,
+            deliver_at,
+        });
+        if let Some(waker) = inbox.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Finds the host and local address a packet to `dst` is delivered to.
+    fn route(&self, src: SocketAddr, dst: SocketAddr) -> Option<(HostId, SocketAddr)> {
+        self.hosts
+            .iter()
+            .enumerate()
+            .find_map(|(id, host)| match host.nat {
+                Some(ref nat) if IpAddr::from(nat.public_ip) == dst.ip() => {
+                    nat.map_incoming(src, dst.port()).map(|local| (id, local))
+                }
+                None if IpAddr::from(host.ip) == dst.ip() => Some((id, dst)),
+                _ => None,
+            })
+    }
+}
+
+fn partition_key(a: HostId, b: HostId) -> (HostId, HostId) {
+    (a.min(b), a.max(b))
+}
+
+impl SimNetwork {
+    /// Creates an empty network, `seed` determines which packets are lost.
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(State {
+            rng: StdRng::seed_from_u64(seed),
+            hosts: Vec::new(),
+            sockets: HashMap::new(),
+            loss: 0.0,
+            latency: Duration::ZERO,
+            partitions: HashSet::new(),
+            public_ips: 0,
+        })))
+    }
+
+    /// Adds a host with a public IPv4 address.
+    pub(crate) fn add_host(&self) -> SimHost {
+        let mut state = self.0.lock();
+        let ip = state.next_public_ip();
+        self.push_host(&mut state, ip, None)
+    }
+
+    /// Adds a host with a private IPv4 address behind its own NAT.
+    pub(crate) fn add_host_behind_nat(&self, kind: NatType) -> SimHost {
+        let mut state = self.0.lock();
+        let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 1)) + state.hosts.len() as u32);
+        let public_ip = state.next_public_ip();
+        self.push_host(&mut state, ip, Some(Nat::new(kind, public_ip)))
+    }
+
+    fn push_host(&self, state: &mut State, ip: Ipv4Addr, nat: Option<Nat>) -> SimHost {
+        state.hosts.push(Host {
+            ip,
+            nat,
+            next_port: FIRST_EPHEMERAL_PORT,
+        });
+        SimHost {
+            net: self.clone(),
+            id: state.hosts.len() - 1,
+        }
+    }
+
+    /// Sets the probability with which each packet is dropped.
+    pub(crate) fn set_loss(&self, loss: f64) {
+        self.0.lock().loss = loss;
+    }
+
+    /// Sets the one way delay of every packet.
+    pub(crate) fn set_latency(&self, latency: Duration) {
+        self.0.lock().latency = latency;
+    }
+
+    /// Drops all packets between the two hosts until [`SimNetwork::heal`] is called.
+    pub(crate) fn partition(&self, a: &SimHost, b: &SimHost) {
+        self.0.lock().partitions.insert(partition_key(a.id, b.id));
+    }
+
+    /// Lets packets between two partitioned hosts through again.
+    pub(crate) fn heal(&self, a: &SimHost, b: &SimHost) {
+        self.0.lock().partitions.remove(&partition_key(a.id, b.id));
+    }
+}
+
+/// A host on a [`SimNetwork`], binds the sockets of a magicsock as [`UdpBinder`].
+#[derive(Debug, Clone)]
+pub(crate) struct SimHost {
+    net: SimNetwork,
+    id: HostId,
+}
+
+impl SimHost {
+    /// The address of the host's interface, private if it is behind a NAT.
+    pub(crate) fn ip(&self) -> IpAddr {
+        self.net.0.lock().hosts[self.id].ip.into()
+    }
+
+    /// The address other hosts see packets from this host coming from.
+    pub(crate) fn public_ip(&self) -> IpAddr {
+        let state = self.net.0.lock();
+        let host = &state.hosts[self.id];
+        match host.nat {
+            Some(ref nat) => nat.public_ip.into(),
+            None => host.ip.into(),
+        }
+    }
+
+    /// Returns this host as the [`UdpBinder`] of a magicsock.
+    pub(crate) fn binder(&self) -> Arc<dyn UdpBinder> {
+        Arc::new(self.clone())
+    }
+
+    /// Moves the host to a new public address, like switching to another uplink.
+    ///
+    /// A NAT loses all its mappings.  Sockets of a host without NAT keep their old address
+    /// and stop receiving packets until they are rebound.
+    pub(crate) fn change_address(&self) -> IpAddr {
+        let mut state = self.net.0.lock();
+        let ip = state.next_public_ip();
+        let host = &mut state.hosts[self.id];
+        match host.nat {
+            Some(ref mut nat) => *nat = Nat::new(nat.kind, ip),
+            None => host.ip = ip,
+        }
+        ip.into()
+    }
+}
+
+impl UdpBinder for SimHost {
+    fn bind(&self, addr: SocketAddr) -> io::Result<CustomUdpSocket> {
+        let mut state = self.net.0.lock();
+        let host_ip = IpAddr::from(state.hosts[self.id].ip);
+        let ip = match addr.ip() {
+            IpAddr::V6(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "IPv6 is not simulated",
+                ))
+            }
+            ip if ip.is_unspecified() => host_ip,
+            ip if ip == host_ip => ip,
+            ip => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{ip} is not an address of this host"),
+                ))
+            }
+        };
+        let port = match addr.port() {
+            0 => loop {
+                let host = &mut state.hosts[self.id];
+                let port = host.next_port;
+                host.next_port = host
+                    .next_port
+                    .checked_add(1)
+                    .unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !state.sockets.contains_key(&SocketAddr::new(ip, port)) {
+                    break port;
+                }
+            },
+            port => port,
+        };
+        let local_addr = SocketAddr::new(ip, port);
+        if state.sockets.contains_key(&local_addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{local_addr} is already bound"),
+            ));
+        }
+        state.sockets.insert(local_addr, Inbox::default());
+        Ok(Arc::new(SimSocket {
+            net: self.net.clone(),
+            host: self.id,
+            local_addr,
+            timer: Mutex::new(None),
+        }))
+    }
+}
+
+/// A socket bound on a [`SimHost`], unbound on drop.
+#[derive(Debug)]
+struct SimSocket {
+    net: SimNetwork,
+    host: HostId,
+    local_addr: SocketAddr,
+    /// Wakes the receiver when the next datagram in the inbox is due.
+    timer: Mutex<Option<Pin<Box<Sleep>>>>,
+}
+
+impl AsyncUdpSocket for SimSocket {
+    fn poll_send(
+        &self,
+        _state: &quinn_udp::UdpState,
+        _cx: &mut Context,
+        transmits: &[quinn_udp::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.net.0.lock();
+        for transmit in transmits {
+            let segment_size = transmit
+                .segment_size
+                .unwrap_or(transmit.contents.len())
+                .max(1);
+            for segment in transmit.contents.chunks(segment_size) {
+                state.send(
+                    self.host,
+                    self.local_addr,
+                    transmit.destination,
+                    transmit.contents.slice_ref(segment),
+                );
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.net.0.lock();
+        let inbox = state
+            .sockets
+            .get_mut(&self.local_addr)
+            .expect("bound while the socket lives");
+        let now = Instant::now();
+        let mut n = 0;
+        for (buf, meta) in bufs.iter_mut().zip(metas.iter_mut()) {
+            if !matches!(inbox.datagrams.front(), Some(d) if d.deliver_at <= now) {
+                break;
+            }
+            let datagram = inbox.datagrams.pop_front().expect("checked");
+            let len = datagram.data.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram.data[..len]);
+            *meta = quinn_udp::RecvMeta {
+                len,
+                stride: len,
+                addr: datagram.src,
+                dst_ip: Some(self.local_addr.ip()),
+                ecn: None,
+            };
+            n += 1;
+        }
+        if n > 0 {
+            return Poll::Ready(Ok(n));
+        }
+        inbox.waker = Some(cx.waker().clone());
+        let next = inbox.datagrams.front().map(|d| d.deliver_at);
+        drop(state);
+
+        if let Some(deliver_at) = next {
+            let mut timer = self.timer.lock();
+            let timer = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deliver_at)));
+            timer.as_mut().reset(deliver_at);
+            if timer.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.net.0.lock().sockets.remove(&self.local_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::future::poll_fn;
+
+    use super::*;
+    use crate::{derp::DerpMode, key::SecretKey, MagicEndpoint, NodeAddr};
+
+    const ALPN: &[u8] = b"n0/test/sim";
+
+    async fn send(sock: &CustomUdpSocket, dst: SocketAddr, data: &'static [u8]) {
+        let transmit = quinn_udp::Transmit {
+            destination: dst,
+            ecn: None,
+            contents: Bytes::from_static(data),
+            segment_size: None,
+            src_ip: None,
+        };
+        let state = quinn_udp::UdpState::default();
+        poll_fn(|cx| sock.poll_send(&state, cx, std::slice::from_ref(&transmit)))
+            .await
+            .unwrap();
+    }
+
+    /// Receives the next datagram, `None` if nothing arrives within a second.
+    async fn recv(sock: &CustomUdpSocket) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut buf = [0u8; 1500];
+        let mut meta = quinn_udp::RecvMeta::default();
+        let n = tokio::time::timeout(
+            Duration::from_secs(1),
+            poll_fn(|cx| {
+                let mut bufs = [io::IoSliceMut::new(&mut buf)];
+                sock.poll_recv(cx, &mut bufs, std::slice::from_mut(&mut meta))
+            }),
+        )
+        .await
+        .ok()?
+        .unwrap();
+        assert_eq!(n, 1);
+        Some((meta.addr, buf[..meta.len].to_vec()))
+    }
+
+    fn bind(host: &SimHost) -> CustomUdpSocket {
+        host.bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_nat() {
+        let net = SimNetwork::new(0);
+        let public = net.add_host();
+        let public_sock = bind(&public);
+        let public_addr = public_sock.local_addr().unwrap();
+        let other = net.add_host();
+        let other_sock = bind(&other);
+        let other_addr = other_sock.local_addr().unwrap();
+
+        for (kind, other_port_allowed, other_host_allowed) in [
+            (NatType::EndpointIndependent, true, true),
+            (NatType::AddressDependent, true, false),
+            (NatType::AddressAndPortDependent, false, false),
+            (NatType::Symmetric, false, false),
+        ] {
+            let natted = net.add_host_behind_nat(kind);
+            let sock = bind(&natted);
+            assert_eq!(sock.local_addr().unwrap().ip(), natted.ip());
+
+            // Nothing gets in before a mapping exists.
+            let mapped = SocketAddr::new(natted.public_ip(), FIRST_NAT_PORT);
+            send(&public_sock, mapped, b"early").await;
+            assert!(recv(&sock).await.is_none());
+
+            send(&sock, public_addr, b"out").await;
+            let (src, data) = recv(&public_sock).await.unwrap();
+            assert_eq!(src, mapped);
+            assert_eq!(data, b"out");
+
+            send(&public_sock, src, b"reply").await;
+            assert_eq!(recv(&sock).await.unwrap(), (public_addr, b"reply".to_vec()));
+
+            // Another port on the host we sent to.
+            let public_sock2 = bind(&public);
+            send(&public_sock2, src, b"port").await;
+            assert_eq!(recv(&sock).await.is_some(), other_port_allowed, "{kind:?}");
+
+            send(&other_sock, src, b"host").await;
+            assert_eq!(recv(&sock).await.is_some(), other_host_allowed, "{kind:?}");
+
+            // Symmetric NATs use a new mapping per destination.
+            send(&sock, other_addr, b"out").await;
+            let (src2, _) = recv(&other_sock).await.unwrap();
+            assert_eq!(src2 == src, kind != NatType::Symmetric, "{kind:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_network_conditions() {
+        let net = SimNetwork::new(42);
+        let a = net.add_host();
+        let b = net.add_host();
+        let sock_a = bind(&a);
+        let sock_b = bind(&b);
+        let addr_b = sock_b.local_addr().unwrap();
+
+        net.set_latency(Duration::from_millis(300));
+        let start = Instant::now();
+        send(&sock_a, addr_b, b"slow").await;
+        recv(&sock_b).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        net.set_latency(Duration::ZERO);
+
+        net.partition(&a, &b);
+        send(&sock_a, addr_b, b"partitioned").await;
+        assert!(recv(&sock_b).await.is_none());
+        net.heal(&a, &b);
+        send(&sock_a, addr_b, b"healed").await;
+        assert!(recv(&sock_b).await.is_some());
+
+        net.set_loss(1.0);
+        send(&sock_a, addr_b, b"lost").await;
+        assert!(recv(&sock_b).await.is_none());
+        net.set_loss(0.0);
+
+        // A host without NAT needs to rebind after its address changed.
+        let new_ip = b.change_address();
+        assert_eq!(b.ip(), new_ip);
+        send(&sock_a, SocketAddr::new(new_ip, addr_b.port()), b"moved").await;
+        assert!(recv(&sock_b).await.is_none());
+        drop(sock_b);
+        let sock_b = bind(&b);
+        assert_eq!(sock_b.local_addr().unwrap().ip(), new_ip);
+        send(&sock_a, sock_b.local_addr().unwrap(), b"rebound").await;
+        assert!(recv(&sock_b).await.is_some());
+    }
+
+    /// Sends packets over a lossy network, returning which arrived.
+    async fn delivered(seed: u64) -> Vec<bool> {
+        let net = SimNetwork::new(seed);
+        net.set_loss(0.5);
+        let sock_a = bind(&net.add_host());
+        let sock_b = bind(&net.add_host());
+        let addr_b = sock_b.local_addr().unwrap();
+        let mut delivered = Vec::new();
+        for _ in 0..32 {
+            send(&sock_a, addr_b, b"maybe").await;
+            delivered.push(recv(&sock_b).await.is_some());
+        }
+        delivered
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_deterministic_loss() {
+        let run = delivered(7).await;
+        assert!(run.contains(&true) && run.contains(&false));
+        assert_eq!(run, delivered(7).await);
+    }
+
+    async fn endpoint(host: &SimHost) -> Result<MagicEndpoint> {
+        MagicEndpoint::builder()
+            .secret_key(SecretKey::generate())
+            .alpns(vec![ALPN.to_vec()])
+            .derp_mode(DerpMode::Disabled)
+            .udp_binder(host.binder())
+            .bind(0)
+            .await
+    }
+
+    async fn roundtrip(server: &MagicEndpoint, client: &MagicEndpoint) -> Result<()> {
+        let server_addr =
+            NodeAddr::from_parts(server.node_id(), None, vec![server.local_addr()?.0]);
+        let accept = async {
+            let conn = server.accept().await.expect("endpoint closed").await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(1024).await?;
+            send.write_all(&data).await?;
+            send.finish().await?;
+            anyhow::Ok(())
+        };
+        let connect = async {
+            let conn = client.connect(server_addr, ALPN).await?;
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(b"hello").await?;
+            send.finish().await?;
+            let data = recv.read_to_end(1024).await?;
+            anyhow::ensure!(data == b"hello");
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::try_join!(accept, connect)
+        })
+        .await??;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_magic_endpoints() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let net = SimNetwork::new(0);
+        let server_host = net.add_host();
+        let server = endpoint(&server_host).await?;
+        assert_eq!(server.local_addr()?.0.ip(), server_host.ip());
+        assert!(server.local_addr()?.1.is_none());
+
+        let client = endpoint(&net.add_host()).await?;
+        roundtrip(&server, &client).await?;
+
+        // The server's reply passes the NAT of the client.
+        let natted_client = endpoint(&net.add_host_behind_nat(NatType::Symmetric)).await?;
+        roundtrip(&server, &natted_client).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_holepunch() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let net = SimNetwork::new(0);
+        net.set_latency(Duration::from_millis(20));
+        let host_a = net.add_host_behind_nat(NatType::AddressAndPortDependent);
+        let host_b = net.add_host_behind_nat(NatType::AddressAndPortDependent);
+        let a = endpoint(&host_a).await?;
+        let b = endpoint(&host_b).await?;
+
+        // Both NATs map all traffic of the only socket to the first public port, so each
+        // node knows where to find the other.  Packets are only let in once the receiving
+        // side sent to the sender itself, so the connection needs both sides to dial.
+        let addr_a = NodeAddr::from_parts(
+            a.node_id(),
+            None,
+            vec![SocketAddr::new(host_a.public_ip(), FIRST_NAT_PORT)],
+        );
+        let addr_b = NodeAddr::from_parts(
+            b.node_id(),
+            None,
+            vec![SocketAddr::new(host_b.public_ip(), FIRST_NAT_PORT)],
+        );
+        let punch = async {
+            b.connect(addr_a, ALPN).await?;
+            anyhow::Ok(())
+        };
+
+        let accept = async {
+            let conn = b.accept().await.expect("endpoint closed").await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(1024).await?;
+            send.write_all(&data).await?;
+            send.finish().await?;
+            anyhow::Ok(())
+        };
+        // Derp is disabled, so data only arrives over the punched hole.
+        let connect = async {
+            let conn = a.connect(addr_b, ALPN).await?;
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(b"punched").await?;
+            send.finish().await?;
+            let data = recv.read_to_end(1024).await?;
+            anyhow::ensure!(data == b"punched");
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::try_join!(accept, connect, punch)
+        })
+        .await??;
+
+        Ok(())
+    }
+}
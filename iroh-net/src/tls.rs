@@ -5,7 +5,10 @@
 
 use std::sync::Arc;
 
-use crate::key::{PublicKey, SecretKey};
+use crate::{
+    key::{PublicKey, SecretKey},
+    magic_endpoint::AcceptPolicy,
+};
 
 pub mod certificate;
 mod verifier;
@@ -50,6 +53,18 @@ pub fn make_server_config(
     secret_key: &SecretKey,
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
+) -> Result<rustls::ServerConfig, certificate::GenError> {
+    make_server_config_with_policy(secret_key, alpn_protocols, keylog, AcceptPolicy::default())
+}
+
+/// Create a TLS server configuration refusing clients not accepted by `accept_policy`.
+///
+/// See [`make_server_config`] for the other parameters.
+pub(crate) fn make_server_config_with_policy(
+    secret_key: &SecretKey,
+    alpn_protocols: Vec<Vec<u8>>,
+    keylog: bool,
+    accept_policy: AcceptPolicy,
 ) -> Result<rustls::ServerConfig, certificate::GenError> {
     let (certificate, secret_key) = certificate::generate(secret_key)?;
 
@@ -58,7 +73,9 @@ pub fn make_server_config(
         .with_safe_default_kx_groups()
         .with_protocol_versions(verifier::PROTOCOL_VERSIONS)
         .expect("Cipher suites and kx groups are configured; qed")
-        .with_client_cert_verifier(Arc::new(verifier::Libp2pCertificateVerifier::new()))
+        .with_client_cert_verifier(Arc::new(
+            verifier::Libp2pCertificateVerifier::with_accept_policy(accept_policy),
+        ))
         .with_single_cert(vec![certificate], secret_key)
         .expect("Server cert key DER is valid; qed");
     crypto.alpn_protocols = alpn_protocols;
@@ -17,7 +17,9 @@ use rustls::{
     SignatureScheme, SupportedCipherSuite, SupportedProtocolVersion,
 };
 
-use crate::key::PublicKey;
+use tracing::debug;
+
+use crate::{key::PublicKey, magic_endpoint::AcceptPolicy};
 
 use super::certificate;
 
@@ -45,6 +47,8 @@ pub static CIPHERSUITES: &[SupportedCipherSuite] = &[
 pub struct Libp2pCertificateVerifier {
     /// The peer ID we intend to connect to
     remote_peer_id: Option<PublicKey>,
+    /// Which clients to accept, only used when verifying client certificates.
+    accept_policy: AcceptPolicy,
 }
 
 /// libp2p requires the following of X.509 server certificate chains:
@@ -54,13 +58,17 @@ pub struct Libp2pCertificateVerifier {
 /// - The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 impl Libp2pCertificateVerifier {
-    pub fn new() -> Self {
+    pub fn with_remote_peer_id(remote_peer_id: Option<PublicKey>) -> Self {
         Self {
-            remote_peer_id: None,
+            remote_peer_id,
+            accept_policy: AcceptPolicy::default(),
         }
     }
-    pub fn with_remote_peer_id(remote_peer_id: Option<PublicKey>) -> Self {
-        Self { remote_peer_id }
+    pub fn with_accept_policy(accept_policy: AcceptPolicy) -> Self {
+        Self {
+            remote_peer_id: None,
+            accept_policy,
+        }
     }
 
     /// Return the list of SignatureSchemes that this verifier will handle,
@@ -151,7 +159,14 @@ impl ClientCertVerifier for Libp2pCertificateVerifier {
         intermediates: &[Certificate],
         _now: std::time::SystemTime,
     ) -> Result<ClientCertVerified, rustls::Error> {
-        verify_presented_certs(end_entity, intermediates)?;
+        let peer_id = verify_presented_certs(end_entity, intermediates)?;
+
+        if !self.accept_policy.verify_node(&peer_id) {
+            debug!(node = %peer_id.fmt_short(), "refusing client not accepted by policy");
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
 
         Ok(ClientCertVerified::assertion())
     }
@@ -6,7 +6,7 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use bytes::Bytes;
 use iroh_base::hash::Hash;
 use serde::{Deserialize, Serialize};
@@ -15,10 +15,11 @@ use tracing::{debug, error, error_span, trace, warn};
 
 use crate::{
     ranger::Message,
-    store::{self, DownloadPolicy, ImportNamespaceOutcome, Query},
-    Author, AuthorHeads, AuthorId, Capability, CapabilityKind, ContentStatus,
-    ContentStatusCallback, Event, NamespaceId, NamespaceSecret, PeerIdBytes, Replica, SignedEntry,
-    SyncOutcome,
+    store::{self, DownloadPolicy, HistoryPolicy, ImportNamespaceOutcome, Query},
+    sync::system_time_now,
+    Author, AuthorHeads, AuthorId, Capability, CapabilityKind, ClockSkew, Compaction,
+    ContentStatus, ContentStatusCallback, Delegation, DocumentKey, Event, EventFilter, NamespaceId,
+    NamespaceSecret, PeerIdBytes, Replica, Revocation, SignedEntry, SyncOutcome,
 };
 
 #[derive(derive_more::Debug, derive_more::Display)]
@@ -35,6 +36,18 @@ enum Action {
         #[debug("reply")]
         reply: oneshot::Sender<Result<NamespaceId>>,
     },
+    #[display("ImportDelegations")]
+    ImportDelegations {
+        delegations: Vec<Delegation>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
+    #[display("ImportRevocations")]
+    ImportRevocations {
+        revocations: Vec<Revocation>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
     #[display("ListAuthors")]
     ListAuthors {
         #[debug("reply")]
@@ -73,6 +86,8 @@ enum ReplicaAction {
     },
     Subscribe {
         sender: flume::Sender<Event>,
+        #[debug(skip)]
+        filter: Option<EventFilter>,
         #[debug("reply")]
         reply: oneshot::Sender<Result<()>>,
     },
@@ -89,6 +104,12 @@ enum ReplicaAction {
         #[debug("reply")]
         reply: oneshot::Sender<Result<()>>,
     },
+    InsertLocalMany {
+        author: AuthorId,
+        entries: Vec<(Bytes, Hash, u64)>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Vec<SignedEntry>>>,
+    },
     DeletePrefix {
         author: AuthorId,
         key: Bytes,
@@ -102,6 +123,11 @@ enum ReplicaAction {
         #[debug("reply")]
         reply: oneshot::Sender<Result<()>>,
     },
+    ImportEntries {
+        entries: Vec<SignedEntry>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<usize>>,
+    },
     SyncInitialMessage {
         #[debug("reply")]
         reply: oneshot::Sender<Result<Message<SignedEntry>>>,
@@ -122,6 +148,10 @@ enum ReplicaAction {
         #[debug("reply")]
         reply: oneshot::Sender<Result<()>>,
     },
+    RegisterSyncFinished {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
     GetExact {
         author: AuthorId,
         key: Bytes,
@@ -143,6 +173,12 @@ enum ReplicaAction {
         #[debug("reply")]
         reply: oneshot::Sender<Result<Option<NonZeroU64>>>,
     },
+    ObservePeerHeads {
+        from: PeerIdBytes,
+        heads: AuthorHeads,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
     SetDownloadPolicy {
         policy: DownloadPolicy,
         #[debug("reply")]
@@ -152,6 +188,68 @@ enum ReplicaAction {
         #[debug("reply")]
         reply: oneshot::Sender<Result<DownloadPolicy>>,
     },
+    Delegate {
+        author: AuthorId,
+        prefix: Bytes,
+        expires: Option<u64>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Delegation>>,
+    },
+    GetDelegations {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Vec<Delegation>>>,
+    },
+    Revoke {
+        author: AuthorId,
+        prefix: Option<Bytes>,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<usize>>,
+    },
+    GetRevocations {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Vec<Revocation>>>,
+    },
+    SetDocumentKey {
+        key: DocumentKey,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
+    GetDocumentKey {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Option<DocumentKey>>>,
+    },
+    SetHistoryPolicy {
+        policy: HistoryPolicy,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<()>>,
+    },
+    GetHistoryPolicy {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<HistoryPolicy>>,
+    },
+    GetHistory {
+        key: Bytes,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Vec<SignedEntry>>>,
+    },
+    Compact {
+        horizon: u64,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<(Compaction, usize)>>,
+    },
+    GetCompaction {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Option<Compaction>>>,
+    },
+    GetClockSkew {
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Vec<(PeerIdBytes, ClockSkew)>>>,
+    },
+    ImportCompaction {
+        compaction: Compaction,
+        #[debug("reply")]
+        reply: oneshot::Sender<Result<Option<usize>>>,
+    },
 }
 
 /// The state for an open replica.
@@ -246,8 +344,28 @@ impl SyncHandle {
         sender: flume::Sender<Event>,
     ) -> Result<()> {
         let (reply, rx) = oneshot::channel();
-        self.send_replica(namespace, ReplicaAction::Subscribe { sender, reply })
-            .await?;
+        let action = ReplicaAction::Subscribe {
+            sender,
+            filter: None,
+            reply,
+        };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn subscribe_filtered(
+        &self,
+        namespace: NamespaceId,
+        sender: flume::Sender<Event>,
+        filter: EventFilter,
+    ) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::Subscribe {
+            sender,
+            filter: Some(filter),
+            reply,
+        };
+        self.send_replica(namespace, action).await?;
         rx.await?
     }
 
@@ -289,6 +407,22 @@ impl SyncHandle {
         rx.await?
     }
 
+    pub async fn insert_local_many(
+        &self,
+        namespace: NamespaceId,
+        author: AuthorId,
+        entries: Vec<(Bytes, Hash, u64)>,
+    ) -> Result<Vec<SignedEntry>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::InsertLocalMany {
+            author,
+            entries,
+            reply,
+        };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
     pub async fn delete_prefix(
         &self,
         namespace: NamespaceId,
@@ -319,6 +453,17 @@ impl SyncHandle {
         rx.await?
     }
 
+    pub async fn import_entries(
+        &self,
+        namespace: NamespaceId,
+        entries: Vec<SignedEntry>,
+    ) -> Result<usize> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::ImportEntries { entries, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
     pub async fn sync_initial_message(
         &self,
         namespace: NamespaceId,
@@ -365,6 +510,13 @@ impl SyncHandle {
         rx.await?
     }
 
+    pub async fn register_sync_finished(&self, namespace: NamespaceId) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::RegisterSyncFinished { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
     pub async fn has_news_for_us(
         &self,
         namespace: NamespaceId,
@@ -376,6 +528,18 @@ impl SyncHandle {
         rx.await?
     }
 
+    pub async fn observe_peer_heads(
+        &self,
+        namespace: NamespaceId,
+        from: PeerIdBytes,
+        heads: AuthorHeads,
+    ) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::ObservePeerHeads { from, heads, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
     pub async fn get_many(
         &self,
         namespace: NamespaceId,
@@ -472,6 +636,153 @@ impl SyncHandle {
         rx.await?
     }
 
+    pub async fn delegate(
+        &self,
+        namespace: NamespaceId,
+        author: AuthorId,
+        prefix: Bytes,
+        expires: Option<u64>,
+    ) -> Result<Delegation> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::Delegate {
+            author,
+            prefix,
+            expires,
+            reply,
+        };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_delegations(&self, namespace: NamespaceId) -> Result<Vec<Delegation>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetDelegations { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    /// Revoke the delegations for `author`, or only the one for `prefix` if set.
+    ///
+    /// Returns the number of revoked delegations.
+    pub async fn revoke(
+        &self,
+        namespace: NamespaceId,
+        author: AuthorId,
+        prefix: Option<Bytes>,
+    ) -> Result<usize> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::Revoke {
+            author,
+            prefix,
+            reply,
+        };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_revocations(&self, namespace: NamespaceId) -> Result<Vec<Revocation>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetRevocations { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn set_document_key(&self, namespace: NamespaceId, key: DocumentKey) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::SetDocumentKey { key, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_document_key(&self, namespace: NamespaceId) -> Result<Option<DocumentKey>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetDocumentKey { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn set_history_policy(
+        &self,
+        namespace: NamespaceId,
+        policy: HistoryPolicy,
+    ) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::SetHistoryPolicy { policy, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_history_policy(&self, namespace: NamespaceId) -> Result<HistoryPolicy> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetHistoryPolicy { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_history(
+        &self,
+        namespace: NamespaceId,
+        key: Bytes,
+    ) -> Result<Vec<SignedEntry>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetHistory { key, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn compact(
+        &self,
+        namespace: NamespaceId,
+        horizon: u64,
+    ) -> Result<(Compaction, usize)> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::Compact { horizon, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_compaction(&self, namespace: NamespaceId) -> Result<Option<Compaction>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetCompaction { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn get_clock_skew(
+        &self,
+        namespace: NamespaceId,
+    ) -> Result<Vec<(PeerIdBytes, ClockSkew)>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::GetClockSkew { reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn import_compaction(
+        &self,
+        namespace: NamespaceId,
+        compaction: Compaction,
+    ) -> Result<Option<usize>> {
+        let (reply, rx) = oneshot::channel();
+        let action = ReplicaAction::ImportCompaction { compaction, reply };
+        self.send_replica(namespace, action).await?;
+        rx.await?
+    }
+
+    pub async fn import_delegations(&self, delegations: Vec<Delegation>) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Action::ImportDelegations { delegations, reply })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn import_revocations(&self, revocations: Vec<Revocation>) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Action::ImportRevocations { revocations, reply })
+            .await?;
+        rx.await?
+    }
+
     async fn send(&self, action: Action) -> Result<()> {
         self.tx
             .send_async(action)
@@ -528,6 +839,22 @@ impl<S: store::Store> Actor<S> {
                 }
                 Ok(id)
             }),
+            Action::ImportDelegations { delegations, reply } => {
+                send_reply_with(reply, self, |this| {
+                    for delegation in delegations {
+                        this.store.import_delegation(delegation)?;
+                    }
+                    Ok(())
+                })
+            }
+            Action::ImportRevocations { revocations, reply } => {
+                send_reply_with(reply, self, |this| {
+                    for revocation in revocations {
+                        this.store.import_revocation(revocation)?;
+                    }
+                    Ok(())
+                })
+            }
             Action::ListAuthors { reply } => iter_to_channel(
                 reply,
                 self.store
@@ -555,9 +882,16 @@ impl<S: store::Store> Actor<S> {
                 reply.send(Ok(res)).ok();
                 Ok(())
             }
-            ReplicaAction::Subscribe { sender, reply } => send_reply_with(reply, self, |this| {
+            ReplicaAction::Subscribe {
+                sender,
+                filter,
+                reply,
+            } => send_reply_with(reply, self, |this| {
                 let replica = this.states.replica(&namespace)?;
-                replica.subscribe(sender);
+                match filter {
+                    Some(filter) => replica.subscribe_filtered(sender, filter),
+                    None => replica.subscribe(sender),
+                }
                 Ok(())
             }),
             ReplicaAction::Unsubscribe { sender, reply } => send_reply_with(reply, self, |this| {
@@ -583,6 +917,16 @@ impl<S: store::Store> Actor<S> {
                 replica.insert(&key, &author, hash, len)?;
                 Ok(())
             }),
+            ReplicaAction::InsertLocalMany {
+                author,
+                entries,
+                reply,
+            } => send_reply_with(reply, self, |this| {
+                let author = get_author(&this.store, &author)?;
+                let replica = this.states.replica(&namespace)?;
+                let inserted = replica.insert_many(&author, entries)?;
+                Ok(inserted)
+            }),
             ReplicaAction::DeletePrefix { author, key, reply } => {
                 send_reply_with(reply, self, |this| {
                     let author = get_author(&this.store, &author)?;
@@ -601,6 +945,13 @@ impl<S: store::Store> Actor<S> {
                 replica.insert_remote_entry(entry, from, content_status)?;
                 Ok(())
             }),
+            ReplicaAction::ImportEntries { entries, reply } => {
+                send_reply_with(reply, self, move |this| {
+                    let replica = this.states.replica(&namespace)?;
+                    let inserted = replica.import_entries(entries)?;
+                    Ok(inserted)
+                })
+            }
 
             ReplicaAction::SyncInitialMessage { reply } => {
                 send_reply_with(reply, self, move |this| {
@@ -628,6 +979,10 @@ impl<S: store::Store> Actor<S> {
                 let res = self.store.register_useful_peer(namespace, peer);
                 send_reply(reply, res)
             }
+            ReplicaAction::RegisterSyncFinished { reply } => {
+                let res = self.store.register_sync_finished(&namespace);
+                send_reply(reply, res)
+            }
             ReplicaAction::GetExact {
                 author,
                 key,
@@ -667,12 +1022,127 @@ impl<S: store::Store> Actor<S> {
                 let res = self.store.has_news_for_us(namespace, &heads);
                 send_reply(reply, res)
             }
+            ReplicaAction::ObservePeerHeads { from, heads, reply } => {
+                send_reply_with(reply, self, move |this| {
+                    let replica = this.states.replica(&namespace)?;
+                    replica.observe_peer_heads(from, &heads);
+                    Ok(())
+                })
+            }
             ReplicaAction::SetDownloadPolicy { policy, reply } => {
                 send_reply(reply, self.store.set_download_policy(&namespace, policy))
             }
             ReplicaAction::GetDownloadPolicy { reply } => {
                 send_reply(reply, self.store.get_download_policy(&namespace))
             }
+            ReplicaAction::Delegate {
+                author,
+                prefix,
+                expires,
+                reply,
+            } => send_reply_with(reply, self, move |this| {
+                let replica = this.states.replica(&namespace)?;
+                let delegation = Delegation::new(replica.secret_key()?, author, prefix, expires);
+                this.store.import_delegation(delegation.clone())?;
+                Ok(delegation)
+            }),
+            ReplicaAction::GetDelegations { reply } => {
+                send_reply(reply, self.store.get_delegations(&namespace))
+            }
+            ReplicaAction::Revoke {
+                author,
+                prefix,
+                reply,
+            } => send_reply_with(reply, self, move |this| {
+                let replica = this.states.replica(&namespace)?;
+                let secret = replica.secret_key()?;
+                let mut revoked = 0;
+                for delegation in this.store.get_delegations(&namespace)? {
+                    if delegation.author() != author
+                        || prefix
+                            .as_ref()
+                            .is_some_and(|p| p.as_ref() != delegation.prefix())
+                    {
+                        continue;
+                    }
+                    let revocation = Revocation::new(secret, &delegation);
+                    this.store.import_revocation(revocation)?;
+                    revoked += 1;
+                }
+                Ok(revoked)
+            }),
+            ReplicaAction::GetRevocations { reply } => {
+                send_reply(reply, self.store.get_revocations(&namespace))
+            }
+            ReplicaAction::SetDocumentKey { key, reply } => {
+                send_reply(reply, self.store.set_document_key(&namespace, key))
+            }
+            ReplicaAction::GetDocumentKey { reply } => {
+                send_reply(reply, self.store.get_document_key(&namespace))
+            }
+            ReplicaAction::SetHistoryPolicy { policy, reply } => {
+                send_reply(reply, self.store.set_history_policy(&namespace, policy))
+            }
+            ReplicaAction::GetHistoryPolicy { reply } => {
+                send_reply(reply, self.store.get_history_policy(&namespace))
+            }
+            ReplicaAction::GetHistory { key, reply } => send_reply_with(reply, self, move |this| {
+                this.states.ensure_open(&namespace)?;
+                this.store.get_history(namespace, key)
+            }),
+            ReplicaAction::Compact { horizon, reply } => {
+                send_reply_with(reply, self, move |this| {
+                    let replica = this.states.replica(&namespace)?;
+                    let compaction = Compaction::new(replica.secret_key()?, horizon);
+                    ensure!(
+                        compaction.is_valid_at(system_time_now()),
+                        "compaction horizon is too far in the future"
+                    );
+                    let removed = this
+                        .store
+                        .apply_compaction(compaction.clone())?
+                        .ok_or_else(|| {
+                            anyhow!("document is already compacted to a later horizon")
+                        })?;
+                    Ok((compaction, removed))
+                })
+            }
+            ReplicaAction::GetCompaction { reply } => {
+                send_reply(reply, self.store.get_compaction(&namespace))
+            }
+            ReplicaAction::GetClockSkew { reply } => send_reply_with(reply, self, move |this| {
+                let replica = this.states.replica(&namespace)?;
+                Ok(replica.clock_skew_report())
+            }),
+            ReplicaAction::ImportCompaction { compaction, reply } => {
+                send_reply_with(reply, self, move |this| {
+                    this.states.replica_if_syncing(&namespace)?;
+                    ensure!(
+                        compaction.namespace() == namespace,
+                        "compaction is for another document"
+                    );
+                    if !compaction.is_valid_at(system_time_now()) {
+                        warn!(
+                            ?compaction,
+                            "ignore compaction with a horizon in the future"
+                        );
+                        return Ok(None);
+                    }
+                    let removed = this.store.apply_compaction(compaction)?;
+                    // Without a sync since the horizon we may have missed deletions whose
+                    // tombstones are gone. Our entries before the horizon are discarded instead of
+                    // offered to the remote, the sync session takes them from the remote again.
+                    if removed.is_some() && this.store.needs_resync(&namespace)? {
+                        let discarded = this.store.discard_before_horizon(&namespace)?;
+                        debug!(
+                            discarded,
+                            "document was not synced since the compaction horizon, resync"
+                        );
+                    }
+                    debug!(?removed, "imported compaction");
+                    Ok(removed)
+                })
+            }
         }
     }
 
@@ -692,6 +1162,11 @@ impl<S: store::Store> Actor<S> {
             if let Some(cb) = &self.content_status_callback {
                 replica.set_content_status_callback(Arc::clone(cb));
             }
+            // new entries are written after the latest entries in the store
+            for latest in self.store.get_latest_for_each_author(namespace)? {
+                let (_author, timestamp, _key) = latest?;
+                replica.observe_timestamp(timestamp);
+            }
             Ok(replica)
         };
         self.states.open_with(namespace, opts, open_cb)
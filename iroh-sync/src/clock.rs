@@ -0,0 +1,148 @@
+//! Hybrid logical clock for the timestamps of entries.
+
+use std::{cmp::Reverse, collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sync::{system_time_now, MAX_TIMESTAMP_FUTURE_SHIFT},
+    AuthorHeads, PeerIdBytes,
+};
+
+/// Skew from our wall clock above which a peer is included in the clock skew report.
+/// Value is 1 second.
+pub const CLOCK_SKEW_REPORT_THRESHOLD: u64 = Duration::from_secs(1).as_micros() as u64;
+
+/// Hybrid logical clock of a replica.
+///
+/// Timestamps are microseconds since the unix epoch, like wall-clock timestamps, so that entries
+/// written by peers with and without the clock sort together. The clock tracks the largest
+/// timestamp it issued or observed, and a new timestamp is always larger than that. An entry
+/// written after another entry was observed therefore sorts after it, even if our wall clock is
+/// behind the clock of the peer which wrote it.
+///
+/// Only the timestamps of entries which passed validation are observed, so that a peer cannot
+/// push our clock further ahead than [`MAX_TIMESTAMP_FUTURE_SHIFT`].
+#[derive(Debug, Default)]
+pub(crate) struct HybridClock {
+    /// The largest timestamp issued or observed.
+    last: u64,
+    /// Skew observed for each peer.
+    skew: HashMap<PeerIdBytes, ClockSkew>,
+}
+
+impl HybridClock {
+    /// Get a timestamp for a new local entry.
+    pub fn now(&mut self) -> u64 {
+        self.tick(system_time_now())
+    }
+
+    /// Advance the clock for the wall-clock time `physical` and return the new timestamp.
+    fn tick(&mut self, physical: u64) -> u64 {
+        self.last = physical.max(self.last.saturating_add(1));
+        self.last
+    }
+
+    /// Observe the timestamp of an entry which is stored in or was inserted into the replica.
+    pub fn observe(&mut self, timestamp: u64) {
+        self.last = self.last.max(timestamp);
+    }
+
+    /// Record the skew from our wall clock `now` of the timestamps received from a peer.
+    ///
+    /// This does not advance the clock, the entries are observed once they are inserted.
+    pub fn observe_peer(&mut self, peer: PeerIdBytes, heads: &AuthorHeads, now: u64) {
+        if heads.is_empty() {
+            return;
+        }
+        let skew = self.skew.entry(peer).or_default();
+        skew.last_observed = now;
+        for (_author, timestamp) in heads.iter() {
+            if *timestamp > now + MAX_TIMESTAMP_FUTURE_SHIFT {
+                skew.rejected += 1;
+                continue;
+            }
+            skew.max_ahead = skew.max_ahead.max(timestamp.saturating_sub(now));
+        }
+    }
+
+    /// Get the peers whose entries were ahead of our wall clock by more than
+    /// [`CLOCK_SKEW_REPORT_THRESHOLD`], or were rejected for being too far in the future.
+    pub fn skew_report(&self) -> Vec<(PeerIdBytes, ClockSkew)> {
+        let mut report = self
+            .skew
+            .iter()
+            .filter(|(_peer, skew)| skew.is_excessive())
+            .map(|(peer, skew)| (*peer, *skew))
+            .collect::<Vec<_>>();
+        report.sort_by_key(|(_, skew)| Reverse(skew.max_ahead));
+        report
+    }
+}
+
+/// Clock skew observed for a peer of a replica.
+///
+/// Only a clock which is ahead of ours can be detected. A peer whose clock is behind writes
+/// entries with timestamps after the ones it observed, so its entries look like they were written
+/// at the time of the latest entry it synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockSkew {
+    /// Largest amount in microseconds by which the timestamp of an entry received from the peer
+    /// was ahead of our wall clock.
+    pub max_ahead: u64,
+    /// Number of authors whose latest entry received from the peer was too far in the future to
+    /// be accepted.
+    pub rejected: u64,
+    /// Wall-clock time at which entries were last received from the peer, in microseconds since
+    /// the unix epoch.
+    pub last_observed: u64,
+}
+
+impl ClockSkew {
+    /// Whether the skew is above [`CLOCK_SKEW_REPORT_THRESHOLD`] or entries were rejected.
+    pub fn is_excessive(&self) -> bool {
+        self.max_ahead > CLOCK_SKEW_REPORT_THRESHOLD || self.rejected > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AuthorId;
+
+    use super::*;
+
+    #[test]
+    fn test_hybrid_clock() {
+        let mut clock = HybridClock::default();
+        assert_eq!(clock.tick(100), 100);
+        // a wall clock going backwards does not move the clock backwards
+        assert_eq!(clock.tick(50), 101);
+        // observed timestamps are passed
+        clock.observe(200);
+        assert_eq!(clock.tick(150), 201);
+        assert_eq!(clock.tick(300), 300);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let now = system_time_now();
+        let mut clock = HybridClock::default();
+        let mut heads = AuthorHeads::default();
+        heads.insert(AuthorId::from(&[1u8; 32]), now + 10);
+        clock.observe_peer([1u8; 32], &heads, now);
+        assert!(clock.skew_report().is_empty());
+
+        let ahead = now + 2 * CLOCK_SKEW_REPORT_THRESHOLD;
+        heads.insert(AuthorId::from(&[2u8; 32]), ahead);
+        heads.insert(
+            AuthorId::from(&[3u8; 32]),
+            now + 2 * MAX_TIMESTAMP_FUTURE_SHIFT,
+        );
+        clock.observe_peer([2u8; 32], &heads, now);
+        let report = clock.skew_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].0, [2u8; 32]);
+        assert_eq!(report[0].1.max_ahead, ahead - now);
+        assert_eq!(report[0].1.rejected, 1);
+    }
+}
@@ -0,0 +1,136 @@
+//! Compaction of old tombstones, agreed on by the namespace key.
+
+use std::fmt;
+
+use ed25519_dalek::{Signature, SignatureError};
+use iroh_base::base32;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    keys::NamespaceSecret,
+    sync::{SignedEntry, MAX_TIMESTAMP_FUTURE_SHIFT},
+    NamespaceId,
+};
+
+/// Domain separation for the signed bytes of a [`Compaction`], so that they can never be
+/// mistaken for an entry or a delegation.
+const COMPACTION_DOMAIN: &[u8] = b"iroh-sync-compaction-1";
+
+/// Setting which allows peers to drop the tombstones of a document up to a horizon.
+///
+/// Deleting a key or prefix inserts an empty entry, a tombstone, which has to be kept so that the
+/// deletion reaches all peers. A compaction is signed with the [`NamespaceSecret`] of the
+/// document and declares that all tombstones with a timestamp before its horizon have
+/// propagated, so they are removed from the store.
+///
+/// Peers exchange their compaction during sync and keep the one with the latest horizon. Only
+/// tombstones are removed, so entries which were never synced are not lost. A peer which did not
+/// sync since the new horizon may have missed deletions whose tombstones are gone, so it needs a
+/// full resync (see [`crate::store::Store::needs_resync`]) instead: it discards all its entries
+/// before the horizon and takes them from the remote peer, so that the deleted entries do not come
+/// back.
+///
+/// The horizon must not be further in the future than [`MAX_TIMESTAMP_FUTURE_SHIFT`], otherwise
+/// the compaction is rejected.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Compaction {
+    namespace: NamespaceId,
+    horizon: u64,
+    signature: Signature,
+}
+
+impl Compaction {
+    /// Create a compaction of all tombstones with a timestamp before `horizon`.
+    ///
+    /// The timestamp is in microseconds since the unix epoch, like the timestamps of entries.
+    pub fn new(namespace: &NamespaceSecret, horizon: u64) -> Self {
+        let mut compaction = Compaction {
+            namespace: namespace.id(),
+            horizon,
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        compaction.signature = namespace.sign(&compaction.to_signed_bytes());
+        compaction
+    }
+
+    /// Verify that this compaction was signed by the key of its namespace.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        self.namespace
+            .into_public_key()?
+            .verify(&self.to_signed_bytes(), &self.signature)
+    }
+
+    /// Get the namespace this compaction is for.
+    pub fn namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+
+    /// Get the timestamp before which tombstones are removed.
+    pub fn horizon(&self) -> u64 {
+        self.horizon
+    }
+
+    /// Whether the horizon is at most [`MAX_TIMESTAMP_FUTURE_SHIFT`] after `now`.
+    ///
+    /// A horizon far in the future would reject all tombstones which are created until then.
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.horizon <= now.saturating_add(MAX_TIMESTAMP_FUTURE_SHIFT)
+    }
+
+    /// Whether `entry` is a tombstone which is removed by this compaction.
+    pub(crate) fn removes(&self, entry: &SignedEntry) -> bool {
+        entry.timestamp() < self.horizon && entry.is_empty()
+    }
+
+    /// Get the namespace signature of this compaction.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn to_signed_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(COMPACTION_DOMAIN.len() + 32 + 8);
+        out.extend_from_slice(COMPACTION_DOMAIN);
+        out.extend_from_slice(self.namespace.as_bytes());
+        out.extend_from_slice(&self.horizon.to_be_bytes());
+        out
+    }
+}
+
+impl fmt::Debug for Compaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compaction")
+            .field("namespace", &self.namespace)
+            .field("horizon", &self.horizon)
+            .field("signature", &base32::fmt_short(self.signature.to_bytes()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compaction() {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let compaction = Compaction::new(&namespace, 1000);
+        compaction.verify().unwrap();
+        assert_eq!(compaction.namespace(), namespace.id());
+        assert_eq!(compaction.horizon(), 1000);
+
+        let mut forged = compaction.clone();
+        forged.horizon = 2000;
+        assert!(forged.verify().is_err());
+
+        assert!(compaction.is_valid_at(0));
+        let future = Compaction::new(&namespace, 1000 + MAX_TIMESTAMP_FUTURE_SHIFT + 1);
+        assert!(!future.is_valid_at(0));
+        assert!(future.is_valid_at(1001));
+
+        let other_namespace = NamespaceSecret::new(&mut rng);
+        let mut forged = Compaction::new(&other_namespace, 1000);
+        forged.namespace = namespace.id();
+        assert!(forged.verify().is_err());
+    }
+}
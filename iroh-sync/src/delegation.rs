@@ -0,0 +1,265 @@
+//! Write access to parts of a document, delegated by the namespace key.
+
+use std::fmt;
+
+use bytes::Bytes;
+use ed25519_dalek::{Signature, SignatureError};
+use iroh_base::base32;
+use serde::{Deserialize, Serialize};
+
+use crate::{keys::NamespaceSecret, AuthorId, Entry, NamespaceId};
+
+/// Domain separation for the signed bytes of a [`Delegation`], so that they can never be
+/// mistaken for an entry.
+const DELEGATION_DOMAIN: &[u8] = b"iroh-sync-delegation-1";
+
+/// Domain separation for the signed bytes of a [`Revocation`].
+const REVOCATION_DOMAIN: &[u8] = b"iroh-sync-revocation-1";
+
+/// Certificate which allows an author to write entries below a key prefix of a document.
+///
+/// A delegation is signed with the [`NamespaceSecret`] of the document. Entries written by the
+/// delegated author carry the signature of the delegation in place of the namespace signature,
+/// so the author can write to the document without knowing the namespace secret. Peers accept
+/// such entries only if they know the delegation, see
+/// [`Store::import_delegation`](crate::store::Store::import_delegation). Peers exchange their
+/// delegations when they sync.
+///
+/// A delegation can be limited in time and withdrawn with a [`Revocation`]. Both only stop new
+/// entries from being accepted: entries which a peer stored before are kept.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Delegation {
+    namespace: NamespaceId,
+    author: AuthorId,
+    prefix: Bytes,
+    expires: Option<u64>,
+    signature: Signature,
+}
+
+impl Delegation {
+    /// Create a delegation which allows `author` to write all keys starting with `prefix`.
+    ///
+    /// If `expires` is set, the delegation is only valid until then, in microseconds since the
+    /// unix epoch. Expiry is checked against the local clock of each peer when an entry is
+    /// written or received, not against the timestamp of the entry, which the author chooses
+    /// freely. An entry written before the expiry is thus rejected by peers which receive it
+    /// afterwards.
+    pub fn new(
+        namespace: &NamespaceSecret,
+        author: AuthorId,
+        prefix: impl Into<Bytes>,
+        expires: Option<u64>,
+    ) -> Self {
+        let mut delegation = Delegation {
+            namespace: namespace.id(),
+            author,
+            prefix: prefix.into(),
+            expires,
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        delegation.signature = namespace.sign(&delegation.to_signed_bytes());
+        delegation
+    }
+
+    /// Verify that this delegation was signed by the key of its namespace.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        self.namespace
+            .into_public_key()?
+            .verify(&self.to_signed_bytes(), &self.signature)
+    }
+
+    /// Whether this delegation allows to write `entry`.
+    ///
+    /// This checks the namespace, author and key prefix, but neither the signatures nor the
+    /// expiry, see [`Delegation::is_expired`].
+    pub fn allows(&self, entry: &Entry) -> bool {
+        entry.namespace() == self.namespace
+            && entry.author() == self.author
+            && entry.key().starts_with(&self.prefix)
+    }
+
+    /// Whether this delegation expired at `now`, in microseconds since the unix epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| now > expires)
+    }
+
+    /// Get the namespace this delegation is for.
+    pub fn namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+
+    /// Get the author this delegation is for.
+    pub fn author(&self) -> AuthorId {
+        self.author
+    }
+
+    /// Get the key prefix the author may write to.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Get the time after which no entries are allowed, if any.
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    /// Get the namespace signature of this delegation.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn to_signed_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(DELEGATION_DOMAIN.len() + 32 + 32 + 9 + self.prefix.len());
+        out.extend_from_slice(DELEGATION_DOMAIN);
+        out.extend_from_slice(self.namespace.as_bytes());
+        out.extend_from_slice(self.author.as_bytes());
+        match self.expires {
+            Some(expires) => {
+                out.push(1);
+                out.extend_from_slice(&expires.to_be_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&self.prefix);
+        out
+    }
+}
+
+impl fmt::Debug for Delegation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delegation")
+            .field("namespace", &self.namespace)
+            .field("author", &self.author)
+            .field("prefix", &self.prefix)
+            .field("expires", &self.expires)
+            .field("signature", &base32::fmt_short(self.signature.to_bytes()))
+            .finish()
+    }
+}
+
+/// Withdrawal of a [`Delegation`], signed by the namespace key.
+///
+/// Once a peer imports a revocation it forgets the delegation and rejects all entries signed
+/// with it that it did not store yet. Peers exchange their revocations when they sync.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Revocation {
+    namespace: NamespaceId,
+    delegation: Signature,
+    signature: Signature,
+}
+
+impl Revocation {
+    /// Create a revocation of `delegation`.
+    pub fn new(namespace: &NamespaceSecret, delegation: &Delegation) -> Self {
+        let mut revocation = Revocation {
+            namespace: namespace.id(),
+            delegation: *delegation.signature(),
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        revocation.signature = namespace.sign(&revocation.to_signed_bytes());
+        revocation
+    }
+
+    /// Verify that this revocation was signed by the key of its namespace.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        self.namespace
+            .into_public_key()?
+            .verify(&self.to_signed_bytes(), &self.signature)
+    }
+
+    /// Whether this revocation withdraws `delegation`.
+    pub fn revokes(&self, delegation: &Delegation) -> bool {
+        self.namespace == delegation.namespace && &self.delegation == delegation.signature()
+    }
+
+    /// Get the namespace this revocation is for.
+    pub fn namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+
+    /// Get the namespace signature of this revocation.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn to_signed_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(REVOCATION_DOMAIN.len() + 32 + 64);
+        out.extend_from_slice(REVOCATION_DOMAIN);
+        out.extend_from_slice(self.namespace.as_bytes());
+        out.extend_from_slice(&self.delegation.to_bytes());
+        out
+    }
+}
+
+impl fmt::Debug for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Revocation")
+            .field("namespace", &self.namespace)
+            .field("delegation", &base32::fmt_short(self.delegation.to_bytes()))
+            .field("signature", &base32::fmt_short(self.signature.to_bytes()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keys::Author, Record, RecordIdentifier};
+    use iroh_base::hash::Hash;
+
+    #[test]
+    fn test_delegation() {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = Author::new(&mut rng).id();
+        let delegation = Delegation::new(&namespace, author, "photos/", Some(1000));
+        delegation.verify().unwrap();
+
+        let entry = |author: AuthorId, key: &str, timestamp: u64| {
+            let id = RecordIdentifier::new(namespace.id(), author, key);
+            Entry::new(id, Record::new(Hash::new(key), 1, timestamp))
+        };
+        assert!(delegation.allows(&entry(author, "photos/cat.jpg", 1000)));
+        // the entry timestamp is chosen by the author, expiry only depends on the local clock
+        assert!(delegation.allows(&entry(author, "photos/cat.jpg", 1001)));
+        assert!(!delegation.is_expired(1000));
+        assert!(delegation.is_expired(1001));
+        assert!(!Delegation::new(&namespace, author, "", None).is_expired(u64::MAX));
+        assert!(!delegation.allows(&entry(author, "notes", 10)));
+        let other = Author::new(&mut rng).id();
+        assert!(!delegation.allows(&entry(other, "photos/cat.jpg", 10)));
+
+        // changing any part invalidates the signature
+        let mut forged = delegation.clone();
+        forged.prefix = Bytes::from_static(b"");
+        assert!(forged.verify().is_err());
+        let mut forged = delegation.clone();
+        forged.expires = None;
+        assert!(forged.verify().is_err());
+
+        // signed by another namespace
+        let other_namespace = NamespaceSecret::new(&mut rng);
+        let mut forged = Delegation::new(&other_namespace, author, "photos/", None);
+        forged.namespace = namespace.id();
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn test_revocation() {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = Author::new(&mut rng).id();
+        let delegation = Delegation::new(&namespace, author, "photos/", None);
+        let other = Delegation::new(&namespace, author, "notes/", None);
+        let revocation = Revocation::new(&namespace, &delegation);
+        revocation.verify().unwrap();
+        assert!(revocation.revokes(&delegation));
+        assert!(!revocation.revokes(&other));
+
+        // only the namespace key can revoke
+        let other_namespace = NamespaceSecret::new(&mut rng);
+        let mut forged = Revocation::new(&other_namespace, &delegation);
+        forged.namespace = namespace.id();
+        assert!(forged.verify().is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Symmetric encryption of the keys and content of a document.
+
+use std::{fmt, str::FromStr};
+
+use crypto_secretbox::{
+    aead::{generic_array::GenericArray, Aead, KeyInit},
+    XChaCha20Poly1305,
+};
+use iroh_base::base32;
+use rand_core::CryptoRngCore;
+use serde::{Deserialize, Serialize};
+
+const NONCE_LEN: usize = 24;
+
+const CIPHER_CONTEXT: &str = "iroh-sync document encryption 2024-01 cipher";
+const KEY_NONCE_CONTEXT: &str = "iroh-sync document encryption 2024-01 entry keys";
+const CONTENT_NONCE_CONTEXT: &str = "iroh-sync document encryption 2024-01 content";
+
+/// Symmetric key of an encrypted document, shared by all nodes which may read the document.
+///
+/// The keys of entries and the content they refer to are encrypted by the writer, so nodes
+/// without the key store and sync the document without being able to read it.
+///
+/// Encryption is deterministic: the nonce is derived from the plaintext, so equal plaintexts
+/// have equal ciphertexts. This allows to reconcile, look up and overwrite entries by their
+/// encrypted keys, and leaks nothing but whether two keys or contents are equal. Because the
+/// ciphertext of a key does not start with the ciphertext of its prefixes, prefix queries and
+/// deletions only match the exact key in encrypted documents.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DocumentKey([u8; 32]);
+
+/// Error when decrypting data which was not encrypted with the [`DocumentKey`].
+#[derive(Debug, thiserror::Error)]
+#[error("failed to decrypt with the document key")]
+pub struct DecryptionError;
+
+impl DocumentKey {
+    /// Create a new random document key.
+    pub fn new<R: CryptoRngCore + ?Sized>(rng: &mut R) -> Self {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Create a document key from a byte array.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        Self(*bytes)
+    }
+
+    /// Convert to byte array.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Encrypt the key of an entry.
+    pub fn encrypt_key(&self, key: &[u8]) -> Vec<u8> {
+        self.seal(KEY_NONCE_CONTEXT, key)
+    }
+
+    /// Decrypt the key of an entry.
+    pub fn decrypt_key(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        self.open(ciphertext)
+    }
+
+    /// Encrypt the content referred to by an entry.
+    pub fn encrypt_content(&self, content: &[u8]) -> Vec<u8> {
+        self.seal(CONTENT_NONCE_CONTEXT, content)
+    }
+
+    /// Decrypt the content referred to by an entry.
+    pub fn decrypt_content(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        self.open(ciphertext)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        let key = blake3::derive_key(CIPHER_CONTEXT, &self.0);
+        XChaCha20Poly1305::new(GenericArray::from_slice(&key))
+    }
+
+    /// Encrypts `plaintext` with a nonce derived from it, and prepends the nonce.
+    fn seal(&self, nonce_context: &str, plaintext: &[u8]) -> Vec<u8> {
+        let nonce_key = blake3::derive_key(nonce_context, &self.0);
+        let nonce = blake3::keyed_hash(&nonce_key, plaintext);
+        let nonce = &nonce.as_bytes()[..NONCE_LEN];
+        let ciphertext = self
+            .cipher()
+            .encrypt(GenericArray::from_slice(nonce), plaintext)
+            .expect("encryption failed");
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(nonce);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    fn open(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if ciphertext.len() < NONCE_LEN {
+            return Err(DecryptionError);
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptionError)
+    }
+}
+
+impl fmt::Display for DocumentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base32::fmt(self.0))
+    }
+}
+
+impl fmt::Debug for DocumentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DocumentKey({})", base32::fmt_short(self.0))
+    }
+}
+
+impl FromStr for DocumentKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_bytes(&base32::parse_array(s)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_key() {
+        let mut rng = rand::thread_rng();
+        let key = DocumentKey::new(&mut rng);
+
+        let ciphertext = key.encrypt_key(b"photos/cat.jpg");
+        assert_eq!(key.decrypt_key(&ciphertext).unwrap(), b"photos/cat.jpg");
+        // deterministic, so that entries can be looked up by their encrypted key
+        assert_eq!(ciphertext, key.encrypt_key(b"photos/cat.jpg"));
+        assert_ne!(ciphertext, key.encrypt_key(b"photos/dog.jpg"));
+        // keys and content with equal plaintext are not linkable
+        assert_ne!(ciphertext, key.encrypt_content(b"photos/cat.jpg"));
+
+        let ciphertext = key.encrypt_content(b"meow");
+        assert_eq!(key.decrypt_content(&ciphertext).unwrap(), b"meow");
+
+        let other = DocumentKey::new(&mut rng);
+        assert!(other.decrypt_content(&ciphertext).is_err());
+        assert!(key.decrypt_key(b"too short").is_err());
+
+        assert_eq!(key, key.to_string().parse().unwrap());
+    }
+}
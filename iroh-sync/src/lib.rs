@@ -13,6 +13,13 @@
 //! * The [Author] key, as a proof of authorship. Any number of authors may be created, and
 //!   their semantic meaning is application-specific. The public key of an author is the [AuthorId].
 //!
+//! Write access to the keys below a prefix can be granted to a single author without handing out
+//! the namespace key, with a [`Delegation`] signed by the namespace key.
+//!
+//! Entries only carry keys and hashes, which are visible to every peer that syncs the replica.
+//! Applications can encrypt both with a [`DocumentKey`], which the store keeps alongside the
+//! replica, so that only the holders of the key can read the document.
+//!
 //! Replicas can be synchronized between peers by exchanging messages. The synchronization algorithm
 //! is based on a technique called *range-based set reconciliation*, based on [this paper][paper] by
 //! Aljoscha Meyer:
@@ -30,6 +37,10 @@
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
 pub mod actor;
+mod clock;
+mod compaction;
+mod delegation;
+mod encryption;
 mod heads;
 mod keys;
 #[cfg(feature = "metrics")]
@@ -40,6 +51,10 @@ mod ranger;
 pub mod store;
 pub mod sync;
 
+pub use self::clock::*;
+pub use self::compaction::*;
+pub use self::delegation::*;
+pub use self::encryption::*;
 pub use self::heads::*;
 pub use self::keys::*;
 pub use self::sync::*;
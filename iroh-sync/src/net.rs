@@ -5,13 +5,17 @@ use std::{
     time::{Duration, Instant},
 };
 
-use iroh_net::{key::PublicKey, magic_endpoint::get_remote_node_id, MagicEndpoint, NodeAddr};
+use iroh_net::{
+    key::PublicKey,
+    magic_endpoint::{get_connection_alpn, get_remote_node_id},
+    MagicEndpoint, NodeAddr,
+};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error_span, trace, Instrument};
 
 use crate::{
     actor::SyncHandle,
-    net::codec::{run_alice, BobState},
+    net::codec::{run_alice, BobState, ProtocolVersion},
     NamespaceId, SyncOutcome,
 };
 
@@ -21,7 +25,18 @@ use crate::metrics::Metrics;
 use iroh_metrics::inc;
 
 /// The ALPN identifier for the iroh-sync protocol
-pub const SYNC_ALPN: &[u8] = b"/iroh-sync/1";
+///
+/// Version 2 exchanges the compaction and the delegations of a document before the entries.
+pub const SYNC_ALPN: &[u8] = b"/iroh-sync/2";
+
+/// The ALPN identifier for version 1 of the iroh-sync protocol
+///
+/// Incoming connections with this ALPN are still accepted, and it is dialed if the remote does
+/// not support [`SYNC_ALPN`].
+pub const SYNC_ALPN_V1: &[u8] = b"/iroh-sync/1";
+
+/// TLS alert sent by a remote which supports none of the offered ALPNs, as a QUIC error code.
+const NO_APPLICATION_PROTOCOL: u64 = 0x100 + 120;
 
 mod codec;
 
@@ -35,10 +50,18 @@ pub async fn connect_and_sync(
     let t_start = Instant::now();
     let peer_id = peer.node_id;
     trace!("connect");
-    let connection = endpoint
-        .connect(peer, SYNC_ALPN)
-        .await
-        .map_err(ConnectError::connect)?;
+    let (connection, version) = match endpoint.connect(peer.clone(), SYNC_ALPN).await {
+        Ok(connection) => (connection, ProtocolVersion::V2),
+        Err(err) if is_unsupported_alpn(&err) => {
+            debug!("remote does not support sync v2, fall back to v1");
+            let connection = endpoint
+                .connect(peer, SYNC_ALPN_V1)
+                .await
+                .map_err(ConnectError::connect)?;
+            (connection, ProtocolVersion::V1)
+        }
+        Err(err) => return Err(ConnectError::connect(err)),
+    };
 
     let (mut send_stream, mut recv_stream) =
         connection.open_bi().await.map_err(ConnectError::connect)?;
@@ -46,7 +69,15 @@ pub async fn connect_and_sync(
     let t_connect = t_start.elapsed();
     debug!(?t_connect, "connected");
 
-    let res = run_alice(&mut send_stream, &mut recv_stream, sync, namespace, peer_id).await;
+    let res = run_alice(
+        &mut send_stream,
+        &mut recv_stream,
+        sync,
+        namespace,
+        peer_id,
+        version,
+    )
+    .await;
 
     send_stream.finish().await.map_err(ConnectError::close)?;
     recv_stream
@@ -94,6 +125,16 @@ pub async fn connect_and_sync(
     Ok(res)
 }
 
+/// Whether dialing failed because the remote does not support the ALPN.
+fn is_unsupported_alpn(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<quinn::ConnectionError>() {
+        Some(quinn::ConnectionError::ConnectionClosed(close)) => {
+            u64::from(close.error_code) == NO_APPLICATION_PROTOCOL
+        }
+        _ => false,
+    }
+}
+
 /// Whether we want to accept or reject an incoming sync request.
 #[derive(Debug, Clone)]
 pub enum AcceptOutcome {
@@ -104,9 +145,11 @@ pub enum AcceptOutcome {
 }
 
 /// Handle an iroh-sync connection and sync all shared documents in the replica store.
+///
+/// Both [`SYNC_ALPN`] and [`SYNC_ALPN_V1`] connections are handled.
 pub async fn handle_connection<F, Fut>(
     sync: SyncHandle,
-    connecting: quinn::Connecting,
+    connection: quinn::Connection,
     accept_cb: F,
 ) -> Result<SyncFinished, AcceptError>
 where
@@ -114,7 +157,11 @@ where
     Fut: Future<Output = AcceptOutcome>,
 {
     let t_start = Instant::now();
-    let connection = connecting.await.map_err(AcceptError::connect)?;
+    let alpn = get_connection_alpn(&connection).map_err(AcceptError::connect)?;
+    let version = match alpn.as_bytes() {
+        SYNC_ALPN_V1 => ProtocolVersion::V1,
+        _ => ProtocolVersion::V2,
+    };
     let peer = get_remote_node_id(&connection).map_err(AcceptError::connect)?;
     let (mut send_stream, mut recv_stream) = connection
         .accept_bi()
@@ -127,7 +174,7 @@ where
         debug!(?t_connect, "connection established");
     });
 
-    let mut state = BobState::new(peer);
+    let mut state = BobState::new(peer, version);
     let res = state
         .run(&mut send_stream, &mut recv_stream, sync, accept_cb)
         .instrument(span.clone())
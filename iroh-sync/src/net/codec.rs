@@ -13,7 +13,7 @@ use tracing::{debug, trace, Span};
 use crate::{
     actor::SyncHandle,
     net::{AbortReason, AcceptError, AcceptOutcome, ConnectError},
-    NamespaceId, SyncOutcome,
+    Compaction, Delegation, NamespaceId, Revocation, SyncOutcome,
 };
 
 #[derive(Debug, Default)]
@@ -67,11 +67,29 @@ impl Encoder<Message> for SyncCodec {
     }
 }
 
+/// Version of the sync protocol, negotiated with the ALPN of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ProtocolVersion {
+    /// Only init, sync and abort messages, see [`super::SYNC_ALPN_V1`].
+    V1,
+    /// Adds the compaction and delegations messages, see [`super::SYNC_ALPN`].
+    V2,
+}
+
 /// Sync Protocol
 ///
+/// - Compaction message: the [`Compaction`] of the namespace, if the dialing peer has one
+/// - Delegations message: the delegations and revocations of the dialing peer, if any
 /// - Init message: signals which namespace is being synced
+/// - Compaction message: the [`Compaction`] of the accepting peer, if it is newer
+/// - Delegations message: the delegations and revocations of the accepting peer, if any
 /// - N Sync messages
 ///
+/// The compactions are exchanged before any entries, so that the tombstones before the horizon
+/// are removed before reconciling. Likewise the delegations are exchanged first, so that entries
+/// of delegated authors are accepted. The compaction and delegations messages are only sent with
+/// [`ProtocolVersion::V2`].
+///
 /// On any error and on success the substream is closed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum Message {
@@ -86,6 +104,45 @@ enum Message {
     Sync(crate::sync::ProtocolMessage),
     /// Abort message (sent by the accepting peer to decline a request)
     Abort { reason: AbortReason },
+    /// Compaction of the namespace (sent by both peers before the sync messages)
+    Compaction(Compaction),
+    /// Delegations and revocations of the namespace (sent by both peers before the sync
+    /// messages)
+    Delegations {
+        delegations: Vec<Delegation>,
+        revocations: Vec<Revocation>,
+    },
+}
+
+/// Import the delegations and revocations received from a peer, ignoring those of other
+/// namespaces.
+async fn import_delegations(
+    handle: &SyncHandle,
+    namespace: NamespaceId,
+    mut delegations: Vec<Delegation>,
+    mut revocations: Vec<Revocation>,
+) -> anyhow::Result<()> {
+    delegations.retain(|d| d.namespace() == namespace);
+    revocations.retain(|r| r.namespace() == namespace);
+    // revocations first, so that revoked delegations are not imported again
+    handle.import_revocations(revocations).await?;
+    handle.import_delegations(delegations).await
+}
+
+/// Get our delegations and revocations for `namespace`, if we have any.
+async fn delegations_message(
+    handle: &SyncHandle,
+    namespace: NamespaceId,
+) -> anyhow::Result<Option<Message>> {
+    let delegations = handle.get_delegations(namespace).await?;
+    let revocations = handle.get_revocations(namespace).await?;
+    if delegations.is_empty() && revocations.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Message::Delegations {
+        delegations,
+        revocations,
+    }))
 }
 
 /// Runs the initiator side of the sync protocol.
@@ -95,6 +152,7 @@ pub(super) async fn run_alice<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     handle: &SyncHandle,
     namespace: NamespaceId,
     peer: PublicKey,
+    version: ProtocolVersion,
 ) -> Result<SyncOutcome, ConnectError> {
     let peer_bytes = *peer.as_bytes();
     let mut reader = FramedRead::new(reader, SyncCodec);
@@ -102,6 +160,32 @@ pub(super) async fn run_alice<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
 
     let mut progress = Some(SyncOutcome::default());
 
+    if version == ProtocolVersion::V2 {
+        // Compaction message, so that the remote can apply it before processing the init message
+
+        let compaction = handle
+            .get_compaction(namespace)
+            .await
+            .map_err(ConnectError::sync)?;
+        if let Some(compaction) = compaction {
+            trace!("send compaction message");
+            writer
+                .send(Message::Compaction(compaction))
+                .await
+                .map_err(ConnectError::sync)?;
+        }
+
+        // Delegations message, so that the remote accepts the entries of delegated authors
+
+        let delegations = delegations_message(handle, namespace)
+            .await
+            .map_err(ConnectError::sync)?;
+        if let Some(message) = delegations {
+            trace!("send delegations message");
+            writer.send(message).await.map_err(ConnectError::sync)?;
+        }
+    }
+
     // Init message
 
     let message = handle
@@ -122,6 +206,27 @@ pub(super) async fn run_alice<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
             Message::Init { .. } => {
                 return Err(ConnectError::sync(anyhow!("unexpected init message")));
             }
+            Message::Compaction(_) | Message::Delegations { .. }
+                if version == ProtocolVersion::V1 =>
+            {
+                return Err(ConnectError::sync(anyhow!("unexpected v2 message")));
+            }
+            Message::Compaction(compaction) => {
+                trace!("recv compaction message");
+                handle
+                    .import_compaction(namespace, compaction)
+                    .await
+                    .map_err(ConnectError::sync)?;
+            }
+            Message::Delegations {
+                delegations,
+                revocations,
+            } => {
+                trace!("recv delegations message");
+                import_delegations(handle, namespace, delegations, revocations)
+                    .await
+                    .map_err(ConnectError::sync)?;
+            }
             Message::Sync(msg) => {
                 trace!("recv process message");
                 let current_progress = progress.take().unwrap();
@@ -146,6 +251,11 @@ pub(super) async fn run_alice<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
         }
     }
 
+    handle
+        .register_sync_finished(namespace)
+        .await
+        .map_err(ConnectError::sync)?;
+
     trace!("done");
     Ok(progress.unwrap())
 }
@@ -158,6 +268,7 @@ pub(super) async fn run_bob<R, W, F, Fut>(
     handle: SyncHandle,
     accept_cb: F,
     peer: PublicKey,
+    version: ProtocolVersion,
 ) -> Result<(NamespaceId, SyncOutcome), AcceptError>
 where
     R: AsyncRead + Unpin,
@@ -165,7 +276,7 @@ where
     F: Fn(NamespaceId, PublicKey) -> Fut,
     Fut: Future<Output = AcceptOutcome>,
 {
-    let mut state = BobState::new(peer);
+    let mut state = BobState::new(peer, version);
     let namespace = state.run(writer, reader, handle, accept_cb).await?;
     Ok((namespace, state.into_outcome()))
 }
@@ -175,15 +286,17 @@ pub struct BobState {
     namespace: Option<NamespaceId>,
     peer: PublicKey,
     progress: Option<SyncOutcome>,
+    version: ProtocolVersion,
 }
 
 impl BobState {
     /// Create a new state for a single connection.
-    pub fn new(peer: PublicKey) -> Self {
+    pub(super) fn new(peer: PublicKey, version: ProtocolVersion) -> Self {
         Self {
             peer,
             namespace: None,
             progress: Some(Default::default()),
+            version,
         }
     }
 
@@ -207,9 +320,39 @@ impl BobState {
     {
         let mut reader = FramedRead::new(reader, SyncCodec);
         let mut writer = FramedWrite::new(writer, SyncCodec);
+        let mut remote_compaction = None;
+        let mut remote_delegations = None;
         while let Some(msg) = reader.next().await {
             let msg = msg.map_err(|e| self.fail(e))?;
             let next = match (msg, self.namespace.as_ref()) {
+                (Message::Compaction(_) | Message::Delegations { .. }, _)
+                    if self.version == ProtocolVersion::V1 =>
+                {
+                    return Err(self.fail(anyhow!("unexpected v2 message")))
+                }
+                (Message::Compaction(compaction), None) => {
+                    trace!("recv compaction message");
+                    if remote_compaction.replace(compaction).is_some() {
+                        return Err(self.fail(anyhow!("double compaction message")));
+                    }
+                    continue;
+                }
+                (
+                    Message::Delegations {
+                        delegations,
+                        revocations,
+                    },
+                    None,
+                ) => {
+                    trace!("recv delegations message");
+                    if remote_delegations
+                        .replace((delegations, revocations))
+                        .is_some()
+                    {
+                        return Err(self.fail(anyhow!("double delegations message")));
+                    }
+                    continue;
+                }
                 (Message::Init { namespace, message }, None) => {
                     Span::current()
                         .record("namespace", tracing::field::display(&namespace.fmt_short()));
@@ -232,6 +375,39 @@ impl BobState {
                             });
                         }
                     }
+                    if self.version == ProtocolVersion::V2 {
+                        let remote_horizon = remote_compaction.as_ref().map(Compaction::horizon);
+                        if let Some(compaction) = remote_compaction.take() {
+                            sync.import_compaction(namespace, compaction)
+                                .await
+                                .map_err(|e| self.fail(e))?;
+                        }
+                        let compaction = sync
+                            .get_compaction(namespace)
+                            .await
+                            .map_err(|e| self.fail(e))?;
+                        if let Some(compaction) = compaction.filter(|c| {
+                            remote_horizon.map_or(true, |horizon| c.horizon() > horizon)
+                        }) {
+                            trace!("send compaction message");
+                            writer
+                                .send(Message::Compaction(compaction))
+                                .await
+                                .map_err(|e| self.fail(e))?;
+                        }
+                        if let Some((delegations, revocations)) = remote_delegations.take() {
+                            import_delegations(&sync, namespace, delegations, revocations)
+                                .await
+                                .map_err(|e| self.fail(e))?;
+                        }
+                        let delegations = delegations_message(&sync, namespace)
+                            .await
+                            .map_err(|e| self.fail(e))?;
+                        if let Some(message) = delegations {
+                            trace!("send delegations message");
+                            writer.send(message).await.map_err(|e| self.fail(e))?;
+                        }
+                    }
                     let last_progress = self.progress.take().unwrap();
                     let next = sync
                         .sync_process_message(
@@ -259,6 +435,12 @@ impl BobState {
                 (Message::Abort { .. }, _) => {
                     return Err(self.fail(anyhow!("unexpected sync abort message")))
                 }
+                (Message::Compaction(_), Some(_)) => {
+                    return Err(self.fail(anyhow!("unexpected compaction message after init")))
+                }
+                (Message::Delegations { .. }, Some(_)) => {
+                    return Err(self.fail(anyhow!("unexpected delegations message after init")))
+                }
             };
             let (reply, progress) = next.map_err(|e| self.fail(e))?;
             self.progress = Some(progress);
@@ -274,10 +456,15 @@ impl BobState {
             }
         }
 
-        trace!("done");
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| self.fail(anyhow!("Stream closed before init message")))?;
+        sync.register_sync_finished(namespace)
+            .await
+            .map_err(|e| self.fail(e))?;
 
-        self.namespace()
-            .ok_or_else(|| self.fail(anyhow!("Stream closed before init message")))
+        trace!("done");
+        Ok(namespace)
     }
 
     /// Get the namespace that is synced, if available.
@@ -296,7 +483,8 @@ mod tests {
     use crate::{
         actor::OpenOpts,
         store::{self, Query, Store},
-        AuthorId, NamespaceSecret,
+        AuthorId, Capability, ContentStatus, Entry, NamespaceSecret, Record, RecordIdentifier,
+        SignedEntry,
     };
     use anyhow::Result;
     use iroh_base::hash::Hash;
@@ -367,6 +555,7 @@ mod tests {
                 &alice_handle2,
                 namespace_id,
                 bob_peer_id,
+                ProtocolVersion::V2,
             )
             .await
         });
@@ -384,6 +573,7 @@ mod tests {
                 bob_handle2,
                 |_namespace, _peer| futures::future::ready(AcceptOutcome::Allow),
                 alice_peer_id,
+                ProtocolVersion::V2,
             )
             .await
         });
@@ -575,6 +765,25 @@ mod tests {
         bob_handle: SyncHandle,
         bob_node_pubkey: PublicKey,
         namespace: NamespaceId,
+    ) -> Result<()> {
+        run_sync_with_version(
+            alice_handle,
+            alice_node_pubkey,
+            bob_handle,
+            bob_node_pubkey,
+            namespace,
+            ProtocolVersion::V2,
+        )
+        .await
+    }
+
+    async fn run_sync_with_version(
+        alice_handle: SyncHandle,
+        alice_node_pubkey: PublicKey,
+        bob_handle: SyncHandle,
+        bob_node_pubkey: PublicKey,
+        namespace: NamespaceId,
+        version: ProtocolVersion,
     ) -> Result<()> {
         alice_handle
             .open(namespace, OpenOpts::default().sync())
@@ -592,6 +801,7 @@ mod tests {
                 &alice_handle,
                 namespace,
                 bob_node_pubkey,
+                version,
             )
             .await
         });
@@ -604,6 +814,7 @@ mod tests {
                 bob_handle,
                 |_namespace, _peer| futures::future::ready(AcceptOutcome::Allow),
                 alice_node_pubkey,
+                version,
             )
             .await
         });
@@ -693,4 +904,345 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_sync_compaction_memory() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let alice_store = store::memory::Store::default();
+        let bob_store = store::memory::Store::default();
+        let carol_store = store::memory::Store::default();
+        test_sync_compaction(alice_store, bob_store, carol_store).await
+    }
+
+    #[tokio::test]
+    async fn test_sync_compaction_fs() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let tmpdir = tempfile::tempdir()?;
+        let alice_store = store::fs::Store::new(tmpdir.path().join("a.db"))?;
+        let bob_store = store::fs::Store::new(tmpdir.path().join("b.db"))?;
+        let carol_store = store::fs::Store::new(tmpdir.path().join("c.db"))?;
+        test_sync_compaction(alice_store, bob_store, carol_store).await
+    }
+
+    async fn test_sync_compaction<S: Store>(
+        alice_store: S,
+        bob_store: S,
+        carol_store: S,
+    ) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(99);
+        let alice_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let bob_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let carol_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = alice_store.new_author(&mut rng)?;
+
+        let entry = |key: &str, value: &str, timestamp: u64| {
+            let id = RecordIdentifier::new(namespace.id(), author.id(), key);
+            let record = match value {
+                "" => Record::empty(timestamp),
+                value => Record::new(Hash::new(value), value.len() as u64, timestamp),
+            };
+            Entry::new(id, record).sign(&namespace, &author)
+        };
+        let insert = |store: &S, entries: Vec<SignedEntry>| -> Result<()> {
+            let mut replica = store.new_replica(namespace.clone())?;
+            for entry in entries {
+                replica.insert_remote_entry(entry, [0u8; 32], ContentStatus::Complete)?;
+            }
+            store.close_replica(replica);
+            Ok(())
+        };
+
+        // bob deleted `old/` and compacted the tombstone, while alice and carol were offline
+        insert(
+            &bob_store,
+            vec![
+                entry("x", "x", 1),
+                entry("old/y", "y", 1),
+                entry("old/", "", 2),
+            ],
+        )?;
+        let compaction = Compaction::new(&namespace, 3);
+        bob_store.apply_compaction(compaction.clone())?;
+        // alice wrote `z` before the horizon, but never synced it
+        insert(
+            &alice_store,
+            vec![
+                entry("x", "x", 1),
+                entry("old/y", "y", 1),
+                entry("z", "z", 2),
+            ],
+        )?;
+        insert(&carol_store, vec![entry("x", "x", 1)])?;
+
+        let alice_handle = SyncHandle::spawn(alice_store.clone(), None, "alice".to_string());
+        let bob_handle = SyncHandle::spawn(bob_store.clone(), None, "bob".to_string());
+        let carol_handle = SyncHandle::spawn(carol_store.clone(), None, "carol".to_string());
+        // the stale peer discards its entries before the horizon and takes them from the remote,
+        // so the deleted `old/y` does not come back, but the unsynced `z` is lost as well
+        let expected = vec![(author.id(), b"x".to_vec(), Hash::new("x"))];
+
+        // the accepting peer sends its compaction to the stale dialing peer
+        assert_eq!(alice_store.last_sync(&namespace.id())?, None);
+        run_sync(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        assert_eq!(get_messages(&alice_store, namespace.id()), expected);
+        assert_eq!(get_messages(&bob_store, namespace.id()), expected);
+        assert_eq!(
+            alice_store.get_compaction(&namespace.id())?,
+            Some(compaction.clone())
+        );
+        // the finished sync clears the resync mark
+        assert!(alice_store.last_sync(&namespace.id())?.is_some());
+        assert!(!alice_store.needs_resync(&namespace.id())?);
+
+        // the compaction is not sent with the v1 protocol
+        run_sync_with_version(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            carol_handle.clone(),
+            carol_node_pubkey,
+            namespace.id(),
+            ProtocolVersion::V1,
+        )
+        .await?;
+        assert_eq!(get_messages(&carol_store, namespace.id()), expected);
+        assert_eq!(carol_store.get_compaction(&namespace.id())?, None);
+
+        // the dialing peer sends its compaction to the accepting peer
+        run_sync(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            carol_handle.clone(),
+            carol_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        assert_eq!(get_messages(&carol_store, namespace.id()), expected);
+        assert_eq!(
+            carol_store.get_compaction(&namespace.id())?,
+            Some(compaction)
+        );
+
+        alice_handle.shutdown().await;
+        bob_handle.shutdown().await;
+        carol_handle.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_compaction_offline_peer_memory() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let alice_store = store::memory::Store::default();
+        let bob_store = store::memory::Store::default();
+        test_sync_compaction_offline_peer(alice_store, bob_store).await
+    }
+
+    #[tokio::test]
+    async fn test_sync_compaction_offline_peer_fs() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let tmpdir = tempfile::tempdir()?;
+        let alice_store = store::fs::Store::new(tmpdir.path().join("a.db"))?;
+        let bob_store = store::fs::Store::new(tmpdir.path().join("b.db"))?;
+        test_sync_compaction_offline_peer(alice_store, bob_store).await
+    }
+
+    async fn test_sync_compaction_offline_peer<S: Store>(
+        alice_store: S,
+        bob_store: S,
+    ) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(99);
+        let alice_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let bob_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = bob_store.new_author(&mut rng)?;
+        for store in [&alice_store, &bob_store] {
+            let replica = store.new_replica(namespace.clone())?;
+            store.close_replica(replica);
+        }
+
+        let alice_handle = SyncHandle::spawn(alice_store.clone(), None, "alice".to_string());
+        let bob_handle = SyncHandle::spawn(bob_store.clone(), None, "bob".to_string());
+
+        // bob inserts two entries, which alice syncs before going offline
+        bob_handle
+            .open(namespace.id(), OpenOpts::default().sync())
+            .await?;
+        for (key, value) in [("old/y", "y"), ("x", "x")] {
+            bob_handle
+                .insert_local(
+                    namespace.id(),
+                    author.id(),
+                    key.into(),
+                    Hash::new(value),
+                    value.len() as u64,
+                )
+                .await?;
+        }
+        run_sync(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        let expected = vec![
+            (author.id(), b"old/y".to_vec(), Hash::new("y")),
+            (author.id(), b"x".to_vec(), Hash::new("x")),
+        ];
+        assert_eq!(get_messages(&alice_store, namespace.id()), expected);
+
+        // while alice is offline, bob deletes `old/` and compacts the tombstone
+        let deleted = bob_handle
+            .delete_prefix(namespace.id(), author.id(), "old/".into())
+            .await?;
+        assert_eq!(deleted, 1);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let horizon = crate::sync::system_time_now();
+        let (_compaction, removed) = bob_handle.compact(namespace.id(), horizon).await?;
+        assert_eq!(removed, 1);
+        assert!(alice_store.last_sync(&namespace.id())? < Some(horizon));
+
+        // alice comes back and syncs, the deleted entry does not come back for anyone
+        run_sync(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        let expected = vec![(author.id(), b"x".to_vec(), Hash::new("x"))];
+        assert_eq!(get_messages(&alice_store, namespace.id()), expected);
+        assert_eq!(get_messages(&bob_store, namespace.id()), expected);
+        assert!(!alice_store.needs_resync(&namespace.id())?);
+
+        alice_handle.shutdown().await;
+        bob_handle.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_delegations_memory() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let alice_store = store::memory::Store::default();
+        let bob_store = store::memory::Store::default();
+        let dave_store = store::memory::Store::default();
+        test_sync_delegations(alice_store, bob_store, dave_store).await
+    }
+
+    #[tokio::test]
+    async fn test_sync_delegations_fs() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let tmpdir = tempfile::tempdir()?;
+        let alice_store = store::fs::Store::new(tmpdir.path().join("a.db"))?;
+        let bob_store = store::fs::Store::new(tmpdir.path().join("b.db"))?;
+        let dave_store = store::fs::Store::new(tmpdir.path().join("d.db"))?;
+        test_sync_delegations(alice_store, bob_store, dave_store).await
+    }
+
+    async fn test_sync_delegations<S: Store>(
+        alice_store: S,
+        bob_store: S,
+        dave_store: S,
+    ) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(99);
+        let alice_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let bob_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let dave_node_pubkey = SecretKey::generate_with_rng(&mut rng).public();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = dave_store.new_author(&mut rng)?;
+
+        // alice owns the document and delegates `dave/` to dave
+        let replica = alice_store.new_replica(namespace.clone())?;
+        alice_store.close_replica(replica);
+        let delegation = Delegation::new(&namespace, author.id(), "dave/", None);
+        alice_store.import_delegation(delegation.clone())?;
+
+        // bob joined with read access before the delegation was made
+        bob_store.import_namespace(Capability::Read(namespace.id()))?;
+
+        dave_store.import_namespace(Capability::Read(namespace.id()))?;
+        dave_store.import_delegation(delegation.clone())?;
+        let mut replica = dave_store.open_replica(&namespace.id())?;
+        replica.hash_and_insert("dave/1", &author, "one")?;
+        dave_store.close_replica(replica);
+
+        let alice_handle = SyncHandle::spawn(alice_store.clone(), None, "alice".to_string());
+        let bob_handle = SyncHandle::spawn(bob_store.clone(), None, "bob".to_string());
+        let dave_handle = SyncHandle::spawn(dave_store.clone(), None, "dave".to_string());
+
+        // bob learns the delegation from dave, and accepts the entry of dave
+        run_sync(
+            dave_handle.clone(),
+            dave_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        assert_eq!(
+            bob_store.get_delegations(&namespace.id())?,
+            vec![delegation.clone()]
+        );
+        assert_eq!(get_messages(&bob_store, namespace.id()).len(), 1);
+
+        // alice revokes the delegation, and bob learns the revocation from alice
+        let revocation = Revocation::new(&namespace, &delegation);
+        alice_store.import_revocation(revocation.clone())?;
+        run_sync(
+            alice_handle.clone(),
+            alice_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        assert!(bob_store.get_delegations(&namespace.id())?.is_empty());
+        assert_eq!(
+            bob_store.get_revocations(&namespace.id())?,
+            vec![revocation.clone()]
+        );
+
+        // bob rejects new entries of dave, and dave learns the revocation from bob
+        dave_handle
+            .insert_local(
+                namespace.id(),
+                author.id(),
+                "dave/2".into(),
+                Hash::new("two"),
+                3,
+            )
+            .await?;
+        run_sync(
+            dave_handle.clone(),
+            dave_node_pubkey,
+            bob_handle.clone(),
+            bob_node_pubkey,
+            namespace.id(),
+        )
+        .await?;
+        assert_eq!(get_messages(&bob_store, namespace.id()).len(), 1);
+        assert!(bob_store.get_delegations(&namespace.id())?.is_empty());
+        assert!(dave_store.get_delegations(&namespace.id())?.is_empty());
+        assert_eq!(
+            dave_store.get_revocations(&namespace.id())?,
+            vec![revocation]
+        );
+
+        alice_handle.shutdown().await;
+        bob_handle.shutdown().await;
+        dave_handle.shutdown().await;
+
+        Ok(())
+    }
 }
@@ -253,6 +253,18 @@ pub trait Store<E: RangeEntry>: Sized {
     /// Insert the given key value pair.
     fn put(&mut self, entry: E) -> Result<(), Self::Error>;
 
+    /// Insert many entries, with the semantics of [`put_checked`] for each of them in order.
+    ///
+    /// Stores may override this to apply all modifications in a single transaction, so that
+    /// either all or none of them are applied. The default implementation inserts the entries
+    /// one by one.
+    fn put_many(&mut self, entries: Vec<E>) -> Result<Vec<InsertOutcome>, Self::Error> {
+        entries
+            .into_iter()
+            .map(|entry| put_checked(self, entry))
+            .collect()
+    }
+
     /// Returns all entries in the given range
     fn get_range(&self, range: Range<E::Key>) -> Result<Self::RangeIterator<'_>, Self::Error>;
 
@@ -370,6 +382,7 @@ where
         }
 
         // Process item messages
+        let mut to_insert = Vec::new();
         for RangeItem {
             range,
             values,
@@ -408,16 +421,10 @@ where
                 )
             };
 
-            // Store incoming values
-            for (entry, content_status) in values {
-                if validate_cb(&self.store, &entry, content_status) {
-                    // TODO: Get rid of the clone?
-                    let outcome = self.put(entry.clone())?;
-                    if let InsertOutcome::Inserted { .. } = outcome {
-                        on_insert_cb(&self.store, entry, content_status);
-                    }
-                }
-            }
+            // Collect incoming values
+            to_insert.extend(values.into_iter().filter(|(entry, content_status)| {
+                validate_cb(&self.store, entry, *content_status)
+            }));
 
             if let Some(diff) = diff {
                 if !diff.is_empty() {
@@ -430,6 +437,18 @@ where
             }
         }
 
+        // Store incoming values in a single batch, and notify about them once they are committed.
+        if !to_insert.is_empty() {
+            // TODO: Get rid of the clone?
+            let entries = to_insert.iter().map(|(entry, _)| entry.clone()).collect();
+            let outcomes = self.store.put_many(entries)?;
+            for ((entry, content_status), outcome) in to_insert.into_iter().zip(outcomes) {
+                if let InsertOutcome::Inserted { .. } = outcome {
+                    on_insert_cb(&self.store, entry, content_status);
+                }
+            }
+        }
+
         // Process fingerprint messages
         for RangeFingerprint { range, fingerprint } in fingerprints {
             let local_fingerprint = self.store.get_fingerprint(&range)?;
@@ -570,41 +589,16 @@ where
         }
     }
 
-    /// Insert a key value pair.
-    ///
-    /// Entries are inserted if they compare strictly greater than all entries in the set of
-    /// entries which have the same key as `entry` or have a key which is a prefix of `entry`.
-    ///
-    /// Additionally, entries that have a key which is a prefix of the entry's key and whose
-    /// timestamp is not strictly greater than that of the new entry are deleted
-    ///
-    /// Note: The deleted entries are simply dropped right now. We might want to make this return
-    /// an iterator, to potentially log or expose the deleted entries.
-    ///
-    /// Returns `true` if the entry was inserted.
-    /// Returns `false` if it was not inserted.
+    /// Insert a key value pair, see [`put_checked`].
+    // currently unused outside of tests
+    #[cfg(test)]
     pub fn put(&mut self, entry: E) -> Result<InsertOutcome, S::Error> {
-        let prefix_entry = self.store.prefixes_of(entry.key())?;
-        // First we check if our entry is strictly greater than all parent elements.
-        // From the willow spec:
-        // "Remove all entries whose timestamp is strictly less than the timestamp of any other entry [..]
-        // whose path is a prefix of p." and then "remove all but those whose record has the greatest hash component".
-        // This is the contract of the `Ord` impl for `E::Value`.
-        for prefix_entry in prefix_entry {
-            let prefix_entry = prefix_entry?;
-            if entry.value() <= prefix_entry.value() {
-                return Ok(InsertOutcome::NotInserted);
-            }
-        }
-
-        // Now we remove all entries that have our key as a prefix and are older than our entry.
-        let removed = self
-            .store
-            .remove_prefix_filtered(entry.key(), |value| entry.value() >= value)?;
+        put_checked(&mut self.store, entry)
+    }
 
-        // Insert our new entry.
-        self.store.put(entry)?;
-        Ok(InsertOutcome::Inserted { removed })
+    /// Insert many entries in a single batch, see [`Store::put_many`].
+    pub fn put_many(&mut self, entries: Vec<E>) -> Result<Vec<InsertOutcome>, S::Error> {
+        self.store.put_many(entries)
     }
 
     /// List all existing key value pairs.
@@ -620,6 +614,44 @@ where
     }
 }
 
+/// Insert a key value pair into `store`.
+///
+/// Entries are inserted if they compare strictly greater than all entries in the set of
+/// entries which have the same key as `entry` or have a key which is a prefix of `entry`.
+///
+/// Additionally, entries that have a key which is a prefix of the entry's key and whose
+/// timestamp is not strictly greater than that of the new entry are deleted
+///
+/// Note: The deleted entries are simply dropped right now. We might want to make this return
+/// an iterator, to potentially log or expose the deleted entries.
+///
+/// Returns `true` if the entry was inserted.
+/// Returns `false` if it was not inserted.
+fn put_checked<E: RangeEntry, S: Store<E>>(
+    store: &mut S,
+    entry: E,
+) -> Result<InsertOutcome, S::Error> {
+    let prefix_entry = store.prefixes_of(entry.key())?;
+    // First we check if our entry is strictly greater than all parent elements.
+    // From the willow spec:
+    // "Remove all entries whose timestamp is strictly less than the timestamp of any other entry [..]
+    // whose path is a prefix of p." and then "remove all but those whose record has the greatest hash component".
+    // This is the contract of the `Ord` impl for `E::Value`.
+    for prefix_entry in prefix_entry {
+        let prefix_entry = prefix_entry?;
+        if entry.value() <= prefix_entry.value() {
+            return Ok(InsertOutcome::NotInserted);
+        }
+    }
+
+    // Now we remove all entries that have our key as a prefix and are older than our entry.
+    let removed = store.remove_prefix_filtered(entry.key(), |value| entry.value() >= value)?;
+
+    // Insert our new entry.
+    store.put(entry)?;
+    Ok(InsertOutcome::Inserted { removed })
+}
+
 /// The outcome of a [`Store::put`] operation.
 #[derive(Debug)]
 pub enum InsertOutcome {
@@ -1,10 +1,14 @@
 //! Storage trait and implementation for iroh-sync documents
 
-use std::num::{NonZeroU64, NonZeroUsize};
+use std::{
+    cmp::Ordering,
+    num::{NonZeroU64, NonZeroUsize},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
-use iroh_base::hash::Hash;
+use iroh_base::{base32, hash::Hash};
 use rand_core::CryptoRngCore;
 use serde::{Deserialize, Serialize};
 
@@ -13,7 +17,8 @@ use crate::{
     keys::{Author, NamespaceSecret},
     ranger,
     sync::{Replica, SignedEntry},
-    AuthorId, Capability, CapabilityKind, Entry, NamespaceId, PeerIdBytes,
+    AuthorId, Capability, CapabilityKind, Compaction, Delegation, DocumentKey, Entry, NamespaceId,
+    PeerIdBytes, Revocation,
 };
 
 #[cfg(feature = "fs-store")]
@@ -49,6 +54,8 @@ pub trait Store: std::fmt::Debug + Clone + Send + Sync + 'static {
     type Instance: ranger::Store<SignedEntry>
         + PublicKeyStore
         + DownloadPolicyStore
+        + DelegationStore
+        + CompactionStore
         + Send
         + Sync
         + 'static
@@ -182,10 +189,113 @@ pub trait Store: std::fmt::Debug + Clone + Send + Sync + 'static {
     /// Get peers to use for syncing a document.
     fn get_sync_peers(&self, namespace: &NamespaceId) -> Result<Option<Self::PeersIter<'_>>>;
 
+    /// Register that a sync session of a document finished, after both peers reconciled their
+    /// entries.
+    fn register_sync_finished(&self, namespace: &NamespaceId) -> Result<()>;
+
+    /// Get the time of the last sync session of a document which was registered with
+    /// [`Self::register_sync_finished`], in microseconds since the unix epoch.
+    fn last_sync(&self, namespace: &NamespaceId) -> Result<Option<u64>>;
+
+    /// Whether a document needs a full resync because it was not synced since the horizon of
+    /// its [`Compaction`].
+    ///
+    /// The document may still hold entries whose tombstones were compacted by the other peers.
+    /// Such a document discards its entries before the horizon with
+    /// [`Self::discard_before_horizon`] and takes them from the remote peer again, the mark is
+    /// cleared by the next finished sync session.
+    fn needs_resync(&self, namespace: &NamespaceId) -> Result<bool> {
+        let Some(compaction) = <Self as Store>::get_compaction(self, namespace)? else {
+            return Ok(false);
+        };
+        let last_sync = self.last_sync(namespace)?;
+        Ok(last_sync.map_or(true, |last_sync| last_sync < compaction.horizon()))
+    }
+
     /// Set the download policy for a document.
     fn set_download_policy(&self, namespace: &NamespaceId, policy: DownloadPolicy) -> Result<()>;
     /// Get the download policy for a document.
     fn get_download_policy(&self, namespace: &NamespaceId) -> Result<DownloadPolicy>;
+
+    /// Import a [`Delegation`] after verifying its signature.
+    ///
+    /// Entries which are signed with an imported delegation are accepted from remote peers, and
+    /// may be written locally to read only replicas. Delegations which were revoked are ignored.
+    fn import_delegation(&self, delegation: Delegation) -> Result<()>;
+    /// Get the delegations imported for a document.
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>>;
+    /// Import a [`Revocation`] after verifying its signature.
+    ///
+    /// The revoked delegation is removed and will not be imported again. Entries which were
+    /// signed with it and are already in the store are kept.
+    fn import_revocation(&self, revocation: Revocation) -> Result<()>;
+    /// Get the revocations imported for a document.
+    fn get_revocations(&self, namespace: &NamespaceId) -> Result<Vec<Revocation>>;
+
+    /// Set the [`DocumentKey`] of an encrypted document.
+    fn set_document_key(&self, namespace: &NamespaceId, key: DocumentKey) -> Result<()>;
+    /// Get the [`DocumentKey`] of a document, if it is encrypted.
+    fn get_document_key(&self, namespace: &NamespaceId) -> Result<Option<DocumentKey>>;
+
+    /// Set the [`HistoryPolicy`] for a document.
+    ///
+    /// Previous versions which are already retained are pruned to the new policy by the next
+    /// call to [`Self::prune_history`].
+    fn set_history_policy(&self, namespace: &NamespaceId, policy: HistoryPolicy) -> Result<()>;
+    /// Get the [`HistoryPolicy`] for a document.
+    fn get_history_policy(&self, namespace: &NamespaceId) -> Result<HistoryPolicy>;
+
+    /// Get the retained previous versions of the entries for `key` of all authors, newest first.
+    fn get_history(
+        &self,
+        namespace: NamespaceId,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Vec<SignedEntry>>;
+    /// Remove the previous versions which are no longer retained by the [`HistoryPolicy`] of
+    /// their document.
+    ///
+    /// Returns the number of removed entries. The content of retained versions is included in
+    /// [`Self::content_hashes`], so this should be called before collecting garbage.
+    fn prune_history(&self) -> Result<usize>;
+
+    /// Apply a [`Compaction`] after verifying its signature.
+    ///
+    /// The tombstones with a timestamp before the horizon are removed from the document, other
+    /// entries are kept. Returns the number of removed entries, or `None` if the document already
+    /// has a compaction with the same or a later horizon, in which case nothing is changed.
+    fn apply_compaction(&self, compaction: Compaction) -> Result<Option<usize>>;
+    /// Get the [`Compaction`] with the latest horizon which was applied to a document.
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>>;
+    /// Remove all entries of a document with a timestamp before the horizon of its
+    /// [`Compaction`], not only the tombstones.
+    ///
+    /// This is used for documents which [need a resync](Self::needs_resync), so that they do not
+    /// offer entries whose deletion was compacted away. Returns the number of removed entries.
+    fn discard_before_horizon(&self, namespace: &NamespaceId) -> Result<usize>;
+}
+
+/// Store that gives read access to the delegations of a document.
+pub trait DelegationStore {
+    /// Get the delegations imported for a document.
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>>;
+}
+
+impl<T: Store> DelegationStore for T {
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>> {
+        <T as Store>::get_delegations(self, namespace)
+    }
+}
+
+/// Store that gives read access to the compaction of a document.
+pub trait CompactionStore {
+    /// Get the [`Compaction`] with the latest horizon which was applied to a document.
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>>;
+}
+
+impl<T: Store> CompactionStore for T {
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>> {
+        <T as Store>::get_compaction(self, namespace)
+    }
 }
 
 /// Store that gives read access to download policies for a document.
@@ -313,12 +423,66 @@ impl DownloadPolicy {
     }
 }
 
+/// Retention of the previous versions of entries, which were replaced by a newer entry for
+/// their key or a prefix of it.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HistoryPolicy {
+    /// Only keep the latest entry per author and key.
+    #[default]
+    Disabled,
+    /// Keep previous versions of entries.
+    Retain {
+        /// Number of previous versions to keep per author and key, `None` keeps all of them.
+        max_versions: Option<NonZeroU64>,
+        /// How long to keep a version after it was replaced, `None` keeps it forever.
+        max_age: Option<Duration>,
+    },
+}
+
+impl HistoryPolicy {
+    /// Whether previous versions are retained at all.
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, HistoryPolicy::Disabled)
+    }
+
+    /// Select the versions of a single author and key which are no longer retained.
+    ///
+    /// `replaced_at` holds the times at which the versions were replaced, ordered from the oldest
+    /// to the newest version. Times are in microseconds since the unix epoch. Returns the indices
+    /// of the versions to remove.
+    pub(crate) fn expired(&self, replaced_at: &[u64], now: u64) -> Vec<usize> {
+        let HistoryPolicy::Retain {
+            max_versions,
+            max_age,
+        } = self
+        else {
+            return (0..replaced_at.len()).collect();
+        };
+        let len = replaced_at.len() as u64;
+        replaced_at
+            .iter()
+            .enumerate()
+            .filter(|(i, replaced_at)| {
+                let newer = len - 1 - *i as u64;
+                let too_many = max_versions.is_some_and(|max| newer >= max.get());
+                let too_old = max_age.is_some_and(|max_age| {
+                    now.saturating_sub(**replaced_at) > max_age.as_micros() as u64
+                });
+                too_many || too_old
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
 /// A query builder for document queries.
 #[derive(Debug, Default)]
 pub struct QueryBuilder<K> {
     kind: K,
     filter_author: AuthorFilter,
     filter_key: KeyFilter,
+    filter_timestamp: TimestampFilter,
+    cursor: Option<Cursor>,
     limit: Option<u64>,
     offset: u64,
     include_empty: bool,
@@ -341,6 +505,46 @@ impl<K> QueryBuilder<K> {
         self.filter_key = KeyFilter::Prefix(key.as_ref().to_vec().into());
         self
     }
+    /// Filter by keys from `start` (inclusive) to `end` (exclusive).
+    pub fn key_range(mut self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Self {
+        self.filter_key = KeyFilter::Range {
+            start: start.as_ref().to_vec().into(),
+            end: Some(end.as_ref().to_vec().into()),
+        };
+        self
+    }
+    /// Filter by keys from `start` (inclusive) onwards.
+    pub fn key_from(mut self, start: impl AsRef<[u8]>) -> Self {
+        self.filter_key = KeyFilter::Range {
+            start: start.as_ref().to_vec().into(),
+            end: None,
+        };
+        self
+    }
+    /// Only include entries which were inserted into the local store at or after `timestamp`.
+    ///
+    /// This is the local insertion time, not the timestamp set by the author of the entry, so
+    /// entries which were written earlier but synced from another peer later are included.
+    /// Timestamps are in microseconds since the unix epoch.
+    pub fn changed_since(mut self, timestamp: u64) -> Self {
+        self.filter_timestamp.since = Some(timestamp);
+        self
+    }
+    /// Only include entries which were inserted into the local store before `timestamp`.
+    ///
+    /// Timestamps are in microseconds since the unix epoch.
+    pub fn changed_before(mut self, timestamp: u64) -> Self {
+        self.filter_timestamp.until = Some(timestamp);
+        self
+    }
+    /// Continue a previous query after the position of `cursor`.
+    ///
+    /// The query should otherwise be the same as the one which returned the entry the cursor
+    /// was created from.
+    pub fn cursor(mut self, cursor: Cursor) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
     /// Filter by author.
     pub fn author(mut self, author: AuthorId) -> Self {
         self.filter_author = AuthorFilter::Exact(author);
@@ -372,6 +576,9 @@ impl QueryBuilder<FlatQuery> {
     /// Set the sort for the query.
     ///
     /// The default is to sort by author, then by key, in ascending order.
+    ///
+    /// Filters on the insertion time are only served from an index when sorting by
+    /// [`SortBy::Timestamp`], otherwise all entries matching the other filters are scanned.
     pub fn sort_by(mut self, sort_by: SortBy, direction: SortDirection) -> Self {
         self.kind.sort_by = sort_by;
         self.sort_direction = direction;
@@ -406,6 +613,8 @@ impl From<QueryBuilder<SingleLatestPerKeyQuery>> for Query {
             kind: QueryKind::SingleLatestPerKey(builder.kind),
             filter_author: builder.filter_author,
             filter_key: builder.filter_key,
+            filter_timestamp: builder.filter_timestamp,
+            cursor: builder.cursor,
             limit: builder.limit,
             offset: builder.offset,
             include_empty: builder.include_empty,
@@ -420,6 +629,8 @@ impl From<QueryBuilder<FlatQuery>> for Query {
             kind: QueryKind::Flat(builder.kind),
             filter_author: builder.filter_author,
             filter_key: builder.filter_key,
+            filter_timestamp: builder.filter_timestamp,
+            cursor: builder.cursor,
             limit: builder.limit,
             offset: builder.offset,
             include_empty: builder.include_empty,
@@ -429,12 +640,14 @@ impl From<QueryBuilder<FlatQuery>> for Query {
 }
 
 /// Note: When using the `SingleLatestPerKey` query kind, the key filter is applied *before* the
-/// grouping, the author filter is applied *after* the grouping.
+/// grouping, the author and timestamp filters are applied *after* the grouping.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Query {
     kind: QueryKind,
     filter_author: AuthorFilter,
     filter_key: KeyFilter,
+    filter_timestamp: TimestampFilter,
+    cursor: Option<Cursor>,
     limit: Option<u64>,
     offset: u64,
     include_empty: bool,
@@ -476,6 +689,106 @@ impl Query {
     pub fn offset(&self) -> u64 {
         self.offset
     }
+
+    /// Get the key filter of this query.
+    pub fn key_filter(&self) -> &KeyFilter {
+        &self.filter_key
+    }
+
+    /// Replace the key filter of this query.
+    pub fn set_key_filter(&mut self, filter: KeyFilter) {
+        self.filter_key = filter;
+    }
+
+    /// Get the timestamp filter of this query.
+    pub fn timestamp_filter(&self) -> &TimestampFilter {
+        &self.filter_timestamp
+    }
+
+    /// Get the cursor after which this query continues.
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+
+    /// Whether an entry comes after the cursor of this query, in the sort order and direction of
+    /// the query.
+    ///
+    /// Returns true if the query has no cursor. For [`SortBy::Timestamp`] the position of the
+    /// cursor depends on the local insertion time, so stores start their range after the cursor
+    /// instead and this returns true as well.
+    pub(crate) fn is_after_cursor(&self, author: &AuthorId, key: &[u8]) -> bool {
+        let Some(cursor) = &self.cursor else {
+            return true;
+        };
+        let ordering = match &self.kind {
+            QueryKind::Flat(FlatQuery {
+                sort_by: SortBy::AuthorKey,
+            }) => (author, key).cmp(&(&cursor.author, &cursor.key[..])),
+            QueryKind::Flat(FlatQuery {
+                sort_by: SortBy::KeyAuthor,
+            }) => (key, author).cmp(&(&cursor.key[..], &cursor.author)),
+            QueryKind::Flat(FlatQuery {
+                sort_by: SortBy::Timestamp,
+            }) => return true,
+            QueryKind::SingleLatestPerKey(_) => key.cmp(&cursor.key[..]),
+        };
+        match self.sort_direction {
+            SortDirection::Asc => ordering == Ordering::Greater,
+            SortDirection::Desc => ordering == Ordering::Less,
+        }
+    }
+}
+
+/// Position in the results of a [`Query`], to continue the query after an entry.
+///
+/// Create a cursor from the last entry of a page of results with [`Cursor::after`] and pass it to
+/// [`QueryBuilder::cursor`] for the next page. Unlike an offset, a cursor stays valid if entries
+/// are inserted or removed before it. When sorting by [`SortBy::Timestamp`], the entry the cursor
+/// was created from must not have been replaced or removed since, otherwise the query fails.
+///
+/// The cursor is opaque; it can be sent to clients as a string with its [`std::fmt::Display`]
+/// and [`std::str::FromStr`] impls.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Cursor {
+    pub(crate) timestamp: u64,
+    pub(crate) author: AuthorId,
+    pub(crate) key: Bytes,
+}
+
+impl Cursor {
+    /// Create a cursor which continues a query after `entry`.
+    pub fn after(entry: &Entry) -> Self {
+        Self {
+            timestamp: entry.timestamp(),
+            author: entry.author(),
+            key: entry.key().to_vec().into(),
+        }
+    }
+
+    /// Encode the cursor to bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("cursor is always serializable")
+    }
+
+    /// Decode a cursor from bytes created with [`Cursor::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).context("invalid cursor")
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", base32::fmt(self.to_bytes()))
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bytes = base32::parse_vec(s).context("invalid cursor")?;
+        Self::from_bytes(&bytes)
+    }
 }
 
 /// Sort direction
@@ -504,6 +817,8 @@ pub enum SortBy {
     /// Sort by author, then key.
     #[default]
     AuthorKey,
+    /// Sort by the time the entries were inserted into the local store, then author, then key.
+    Timestamp,
 }
 
 /// Key matching.
@@ -516,6 +831,14 @@ pub enum KeyFilter {
     Exact(Bytes),
     /// All keys that start with the provided value.
     Prefix(Bytes),
+    /// All keys from `start` (inclusive) up to `end` (exclusive), or without an upper limit if
+    /// `end` is `None`.
+    Range {
+        /// First key of the range.
+        start: Bytes,
+        /// End of the range, not included.
+        end: Option<Bytes>,
+    },
 }
 
 impl<T: AsRef<[u8]>> From<T> for KeyFilter {
@@ -531,10 +854,32 @@ impl KeyFilter {
             Self::Any => true,
             Self::Exact(k) => &k[..] == key,
             Self::Prefix(p) => key.starts_with(p),
+            Self::Range { start, end } => {
+                key >= &start[..] && end.as_ref().map_or(true, |end| key < &end[..])
+            }
         }
     }
 }
 
+/// Matching on the time entries were inserted into the local store.
+///
+/// Timestamps are in microseconds since the unix epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TimestampFilter {
+    /// Only timestamps at or after this value.
+    pub since: Option<u64>,
+    /// Only timestamps before this value.
+    pub until: Option<u64>,
+}
+
+impl TimestampFilter {
+    /// Test if a timestamp is matched by this [`TimestampFilter`].
+    pub fn matches(&self, timestamp: u64) -> bool {
+        self.since.map_or(true, |since| timestamp >= since)
+            && self.until.map_or(true, |until| timestamp < until)
+    }
+}
+
 /// Author matching.
 #[derive(Debug, Serialize, Deserialize, Clone, Default, Eq, PartialEq)]
 pub enum AuthorFilter {
@@ -575,4 +920,28 @@ mod tests {
         );
         assert_eq!(filter.to_string(), REPR)
     }
+
+    #[test]
+    fn test_history_policy_expired() {
+        let replaced_at = [10, 20, 30, 40];
+        assert_eq!(
+            HistoryPolicy::Disabled.expired(&replaced_at, 50),
+            vec![0, 1, 2, 3]
+        );
+        let policy = HistoryPolicy::Retain {
+            max_versions: NonZeroU64::new(2),
+            max_age: None,
+        };
+        assert_eq!(policy.expired(&replaced_at, 50), vec![0, 1]);
+        let policy = HistoryPolicy::Retain {
+            max_versions: None,
+            max_age: Some(Duration::from_micros(25)),
+        };
+        assert_eq!(policy.expired(&replaced_at, 50), vec![0, 1]);
+        let policy = HistoryPolicy::Retain {
+            max_versions: None,
+            max_age: None,
+        };
+        assert!(policy.expired(&replaced_at, u64::MAX).is_empty());
+    }
 }
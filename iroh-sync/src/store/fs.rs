@@ -16,29 +16,35 @@ use ed25519_dalek::{SignatureError, VerifyingKey};
 use iroh_base::hash::Hash;
 use parking_lot::RwLock;
 use redb::{
-    Database, MultimapTableDefinition, ReadOnlyTable, ReadableMultimapTable, ReadableTable,
-    TableDefinition,
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, Table,
+    TableDefinition, WriteTransaction,
 };
 
 use crate::{
     keys::Author,
-    ranger::{Fingerprint, Range, RangeEntry},
+    ranger::{Fingerprint, InsertOutcome, Range, RangeEntry},
     store::Store as _,
-    sync::{Entry, EntrySignature, Record, RecordIdentifier, Replica, SignedEntry},
-    AuthorId, Capability, CapabilityKind, NamespaceId, PeerIdBytes,
+    sync::{
+        system_time_now, Entry, EntrySignature, Record, RecordIdentifier, Replica, SignedEntry,
+    },
+    AuthorId, Capability, CapabilityKind, Compaction, Delegation, DocumentKey, NamespaceId,
+    PeerIdBytes, Revocation,
 };
 
 use super::{
-    pubkeys::MemPublicKeyStore, DownloadPolicy, ImportNamespaceOutcome, OpenError, PublicKeyStore,
-    Query,
+    pubkeys::MemPublicKeyStore, util::InsertionClock, DownloadPolicy, HistoryPolicy,
+    ImportNamespaceOutcome, OpenError, PublicKeyStore, Query,
 };
 
 mod bounds;
+mod fingerprints;
+mod history;
 mod migrations;
 mod query;
 mod ranges;
 
-use self::bounds::{ByKeyBounds, RecordsBounds};
+use self::bounds::{ByInsertedAtBounds, ByKeyBounds, RecordsBounds};
+use self::fingerprints::FingerprintIndex;
 use self::query::QueryIterator;
 use self::ranges::{TableRange, TableReader};
 
@@ -72,7 +78,6 @@ const RECORDS_TABLE: TableDefinition<RecordsId, RecordsValue> = TableDefinition:
 type RecordsId<'a> = (&'a [u8; 32], &'a [u8; 32], &'a [u8]);
 type RecordsIdOwned = ([u8; 32], [u8; 32], Bytes);
 type RecordsValue<'a> = (u64, &'a [u8; 64], &'a [u8; 64], u64, &'a [u8; 32]);
-type RecordsTable<'a> = ReadOnlyTable<'a, RecordsId<'static>, RecordsValue<'static>>;
 
 /// Table: Latest per author
 /// Key:   `([u8; 32], [u8; 32])`    # (NamespaceId, AuthorId)
@@ -90,6 +95,29 @@ const RECORDS_BY_KEY_TABLE: TableDefinition<RecordsByKeyId, ()> =
 type RecordsByKeyId<'a> = (&'a [u8; 32], &'a [u8], &'a [u8; 32]);
 type RecordsByKeyIdOwned = ([u8; 32], Bytes, [u8; 32]);
 
+/// Table: Records by local insertion time
+/// Key:   `([u8; 32], u64, [u8; 32], Vec<u8>)` # (NamespaceId, InsertedAt, AuthorId, Key)
+/// Value: `()`
+const RECORDS_BY_INSERTED_AT_TABLE: TableDefinition<RecordsByInsertedAtId, ()> =
+    TableDefinition::new("records-by-inserted-at-1");
+type RecordsByInsertedAtId<'a> = (&'a [u8; 32], u64, &'a [u8; 32], &'a [u8]);
+type RecordsByInsertedAtIdOwned = ([u8; 32], u64, [u8; 32], Bytes);
+
+/// Table: Local insertion time of records
+/// Key:   `([u8; 32], [u8; 32], &[u8])` # (NamespaceId, AuthorId, Key)
+/// Value: `u64`                         # Microseconds since the unix epoch
+const RECORDS_INSERTED_AT_TABLE: TableDefinition<RecordsId, u64> =
+    TableDefinition::new("records-inserted-at-1");
+
+/// Table: Range fingerprints, see [`fingerprints`]
+/// Key:   `(u8, [u8; 32], [u8; 32], &[u8])`
+///      # (Level, NamespaceId, AuthorId, Key)
+/// Value: `[u8; 32]`
+///      # XOR of the record fingerprints up to the next key of the same level
+const FINGERPRINTS_TABLE: TableDefinition<FingerprintsId, &[u8; 32]> =
+    TableDefinition::new("fingerprints-1");
+type FingerprintsId<'a> = (u8, &'a [u8; 32], &'a [u8; 32], &'a [u8]);
+
 /// Table: Peers per document.
 /// Key:   `[u8; 32]`        # NamespaceId
 /// Value: `(u64, [u8; 32])` # ([`Nanos`], &[`PeerIdBytes`]) representing the last time a peer was used.
@@ -107,12 +135,56 @@ type Nanos = u64;
 const DOWNLOAD_POLICY_TABLE: TableDefinition<&[u8; 32], &[u8]> =
     TableDefinition::new("download-policy-1");
 
+/// Table: Delegations per document
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `Vec<u8>`         # Postcard encoded [`Delegation`]
+const DELEGATIONS_TABLE: MultimapTableDefinition<&[u8; 32], &[u8]> =
+    MultimapTableDefinition::new("delegations-1");
+
+/// Table: Revocations of delegations per document
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `Vec<u8>`         # Postcard encoded [`Revocation`]
+const REVOCATIONS_TABLE: MultimapTableDefinition<&[u8; 32], &[u8]> =
+    MultimapTableDefinition::new("revocations-1");
+
+/// Table: Document keys of encrypted documents
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `[u8; 32]`        # [`DocumentKey`]
+const DOCUMENT_KEYS_TABLE: TableDefinition<&[u8; 32], &[u8; 32]> =
+    TableDefinition::new("document-keys-1");
+
+/// Table: History policy
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `Vec<u8>`         # Postcard encoded [`HistoryPolicy`]
+const HISTORY_POLICY_TABLE: TableDefinition<&[u8; 32], &[u8]> =
+    TableDefinition::new("history-policy-1");
+
+/// Table: Compaction of tombstones per document
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `Vec<u8>`         # Postcard encoded [`Compaction`]
+const COMPACTIONS_TABLE: TableDefinition<&[u8; 32], &[u8]> = TableDefinition::new("compactions-1");
+
+/// Table: Time of the last finished sync session per document
+/// Key:   `[u8; 32]`        # NamespaceId
+/// Value: `u64`             # Microseconds since the unix epoch
+const LAST_SYNC_TABLE: TableDefinition<&[u8; 32], u64> = TableDefinition::new("last-sync-1");
+
+/// Table: Previous versions of records, see [`history`]
+/// Key:   `([u8; 32], &[u8], [u8; 32], u64)`
+///      # (NamespaceId, Key, AuthorId, timestamp)
+/// Value: `(u64, [u8; 64], [u8; 64], u64, [u8; 32])`
+///      # (replaced_at, signature_namespace, signature_author, len, hash)
+const HISTORY_TABLE: TableDefinition<HistoryId, HistoryValue> = TableDefinition::new("history-1");
+type HistoryId<'a> = (&'a [u8; 32], &'a [u8], &'a [u8; 32], u64);
+type HistoryValue<'a> = (u64, &'a [u8; 64], &'a [u8; 64], u64, &'a [u8; 32]);
+
 /// Manages the replicas and authors for an instance.
 #[derive(Debug, Clone)]
 pub struct Store {
     db: Arc<Database>,
     open_replicas: Arc<RwLock<HashSet<NamespaceId>>>,
     pubkeys: MemPublicKeyStore,
+    insertion_clock: Arc<InsertionClock>,
 }
 
 impl Store {
@@ -130,7 +202,17 @@ impl Store {
             let _table = write_tx.open_table(LATEST_PER_AUTHOR_TABLE)?;
             let _table = write_tx.open_multimap_table(NAMESPACE_PEERS_TABLE)?;
             let _table = write_tx.open_table(DOWNLOAD_POLICY_TABLE)?;
+            let _table = write_tx.open_multimap_table(DELEGATIONS_TABLE)?;
+            let _table = write_tx.open_multimap_table(REVOCATIONS_TABLE)?;
+            let _table = write_tx.open_table(DOCUMENT_KEYS_TABLE)?;
+            let _table = write_tx.open_table(HISTORY_POLICY_TABLE)?;
+            let _table = write_tx.open_table(HISTORY_TABLE)?;
+            let _table = write_tx.open_table(COMPACTIONS_TABLE)?;
+            let _table = write_tx.open_table(LAST_SYNC_TABLE)?;
             let _table = write_tx.open_table(AUTHORS_TABLE)?;
+            let _table = write_tx.open_table(FINGERPRINTS_TABLE)?;
+            let _table = write_tx.open_table(RECORDS_BY_INSERTED_AT_TABLE)?;
+            let _table = write_tx.open_table(RECORDS_INSERTED_AT_TABLE)?;
         }
         write_tx.commit()?;
 
@@ -141,6 +223,7 @@ impl Store {
             db: Arc::new(db),
             open_replicas: Default::default(),
             pubkeys: Default::default(),
+            insertion_clock: Default::default(),
         })
     }
 }
@@ -148,7 +231,7 @@ impl Store {
 impl super::Store for Store {
     type Instance = StoreInstance;
     type GetIter<'a> = QueryIterator<'a>;
-    type ContentHashesIter<'a> = ContentHashesIterator<'a>;
+    type ContentHashesIter<'a> = Chain<ContentHashesIterator<'a>, HistoryHashesIterator<'a>>;
     type LatestIter<'a> = LatestIterator<'a>;
     type AuthorsIter<'a> = std::vec::IntoIter<Result<Author>>;
     type NamespaceIter<'a> = std::vec::IntoIter<Result<(NamespaceId, CapabilityKind)>>;
@@ -276,6 +359,42 @@ impl super::Store for Store {
             let bounds = ByKeyBounds::namespace(*namespace);
             let _ = table.drain(bounds.as_ref());
         }
+        {
+            let mut table = write_tx.open_table(RECORDS_BY_INSERTED_AT_TABLE)?;
+            let bounds = ByInsertedAtBounds::namespace(*namespace);
+            table.drain(bounds.as_ref())?;
+            let mut table = write_tx.open_table(RECORDS_INSERTED_AT_TABLE)?;
+            let bounds = RecordsBounds::namespace(*namespace);
+            table.drain(bounds.as_ref())?;
+        }
+        {
+            let mut table = write_tx.open_table(FINGERPRINTS_TABLE)?;
+            FingerprintIndex::new(*namespace).clear(&mut table)?;
+        }
+        {
+            let mut table = write_tx.open_multimap_table(DELEGATIONS_TABLE)?;
+            table.remove_all(namespace.as_bytes())?;
+            let mut table = write_tx.open_multimap_table(REVOCATIONS_TABLE)?;
+            table.remove_all(namespace.as_bytes())?;
+        }
+        {
+            let mut table = write_tx.open_table(DOCUMENT_KEYS_TABLE)?;
+            table.remove(namespace.as_bytes())?;
+        }
+        {
+            let mut table = write_tx.open_table(HISTORY_TABLE)?;
+            history::clear(&mut table, namespace)?;
+            let mut table = write_tx.open_table(HISTORY_POLICY_TABLE)?;
+            table.remove(namespace.as_bytes())?;
+        }
+        {
+            let mut table = write_tx.open_table(COMPACTIONS_TABLE)?;
+            table.remove(namespace.as_bytes())?;
+        }
+        {
+            let mut table = write_tx.open_table(LAST_SYNC_TABLE)?;
+            table.remove(namespace.as_bytes())?;
+        }
         {
             let mut namespace_table = write_tx.open_table(NAMESPACES_TABLE)?;
             namespace_table.remove(namespace.as_bytes())?;
@@ -305,7 +424,8 @@ impl super::Store for Store {
     }
 
     fn content_hashes(&self) -> Result<Self::ContentHashesIter<'_>> {
-        ContentHashesIterator::new(&self.db)
+        let history = HistoryHashesIterator::new(&self.db)?;
+        Ok(ContentHashesIterator::new(&self.db)?.chain(history))
     }
 
     fn get_latest_for_each_author(&self, namespace: NamespaceId) -> Result<Self::LatestIter<'_>> {
@@ -401,6 +521,23 @@ impl super::Store for Store {
         }
     }
 
+    fn register_sync_finished(&self, namespace: &NamespaceId) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(LAST_SYNC_TABLE)?;
+            table.insert(namespace.as_bytes(), system_time_now())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn last_sync(&self, namespace: &NamespaceId) -> Result<Option<u64>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(LAST_SYNC_TABLE)?;
+        let last_sync = table.get(namespace.as_bytes())?.map(|value| value.value());
+        Ok(last_sync)
+    }
+
     fn set_download_policy(&self, namespace: &NamespaceId, policy: DownloadPolicy) -> Result<()> {
         let tx = self.db.begin_write()?;
         {
@@ -421,6 +558,158 @@ impl super::Store for Store {
             Some(value) => postcard::from_bytes(value.value())?,
         })
     }
+
+    fn import_delegation(&self, delegation: Delegation) -> Result<()> {
+        delegation.verify()?;
+        let revocations = self.get_revocations(&delegation.namespace())?;
+        if revocations.iter().any(|r| r.revokes(&delegation)) {
+            return Ok(());
+        }
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_multimap_table(DELEGATIONS_TABLE)?;
+            let value = postcard::to_stdvec(&delegation)?;
+            table.insert(delegation.namespace().as_bytes(), value.as_slice())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_multimap_table(DELEGATIONS_TABLE)?;
+        let mut delegations = Vec::new();
+        for value in table.get(namespace.as_bytes())? {
+            delegations.push(postcard::from_bytes(value?.value())?);
+        }
+        Ok(delegations)
+    }
+
+    fn import_revocation(&self, revocation: Revocation) -> Result<()> {
+        revocation.verify()?;
+        let namespace = revocation.namespace();
+        let revoked = self
+            .get_delegations(&namespace)?
+            .into_iter()
+            .filter(|delegation| revocation.revokes(delegation));
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_multimap_table(DELEGATIONS_TABLE)?;
+            for delegation in revoked {
+                let value = postcard::to_stdvec(&delegation)?;
+                table.remove(namespace.as_bytes(), value.as_slice())?;
+            }
+            let mut table = tx.open_multimap_table(REVOCATIONS_TABLE)?;
+            let value = postcard::to_stdvec(&revocation)?;
+            table.insert(namespace.as_bytes(), value.as_slice())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_revocations(&self, namespace: &NamespaceId) -> Result<Vec<Revocation>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_multimap_table(REVOCATIONS_TABLE)?;
+        let mut revocations = Vec::new();
+        for value in table.get(namespace.as_bytes())? {
+            revocations.push(postcard::from_bytes(value?.value())?);
+        }
+        Ok(revocations)
+    }
+
+    fn set_document_key(&self, namespace: &NamespaceId, key: DocumentKey) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(DOCUMENT_KEYS_TABLE)?;
+            table.insert(namespace.as_bytes(), &key.to_bytes())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_document_key(&self, namespace: &NamespaceId) -> Result<Option<DocumentKey>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(DOCUMENT_KEYS_TABLE)?;
+        let key = table.get(namespace.as_bytes())?;
+        Ok(key.map(|key| DocumentKey::from_bytes(key.value())))
+    }
+
+    fn set_history_policy(&self, namespace: &NamespaceId, policy: HistoryPolicy) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(HISTORY_POLICY_TABLE)?;
+            let value = postcard::to_stdvec(&policy)?;
+            table.insert(namespace.as_bytes(), value.as_slice())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_history_policy(&self, namespace: &NamespaceId) -> Result<HistoryPolicy> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(HISTORY_POLICY_TABLE)?;
+        history::read_policy(&table, namespace)
+    }
+
+    fn get_history(
+        &self,
+        namespace: NamespaceId,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Vec<SignedEntry>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(HISTORY_TABLE)?;
+        history::get(&table, &namespace, key.as_ref())
+    }
+
+    fn prune_history(&self) -> Result<usize> {
+        let tx = self.db.begin_write()?;
+        let removed = {
+            let mut table = tx.open_table(HISTORY_TABLE)?;
+            let policies = tx.open_table(HISTORY_POLICY_TABLE)?;
+            history::prune_all(&mut table, &policies, system_time_now())?
+        };
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    fn apply_compaction(&self, compaction: Compaction) -> Result<Option<usize>> {
+        compaction.verify()?;
+        let namespace = compaction.namespace();
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(COMPACTIONS_TABLE)?;
+            if let Some(current) = read_compaction(&table, &namespace)? {
+                if current.horizon() >= compaction.horizon() {
+                    return Ok(None);
+                }
+            }
+            let value = postcard::to_stdvec(&compaction)?;
+            table.insert(namespace.as_bytes(), value.as_slice())?;
+        }
+        let inserted_at = self.insertion_clock.next();
+        let removed = RecordsWriter::new(namespace, &tx, inserted_at)?
+            .remove_entries_filtered(|entry| compaction.removes(entry))?;
+        tx.commit()?;
+        Ok(Some(removed))
+    }
+
+    fn discard_before_horizon(&self, namespace: &NamespaceId) -> Result<usize> {
+        let Some(compaction) = super::Store::get_compaction(self, namespace)? else {
+            return Ok(0);
+        };
+        let tx = self.db.begin_write()?;
+        let inserted_at = self.insertion_clock.next();
+        let removed = RecordsWriter::new(*namespace, &tx, inserted_at)?
+            .remove_entries_filtered(|entry| entry.timestamp() < compaction.horizon())?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(COMPACTIONS_TABLE)?;
+        read_compaction(&table, namespace)
+    }
 }
 
 fn parse_capability((raw_kind, raw_bytes): (u8, &[u8; 32])) -> Result<Capability> {
@@ -428,7 +717,7 @@ fn parse_capability((raw_kind, raw_bytes): (u8, &[u8; 32])) -> Result<Capability
 }
 
 fn get_exact(
-    record_table: &RecordsTable,
+    record_table: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
     namespace: NamespaceId,
     author: AuthorId,
     key: impl AsRef<[u8]>,
@@ -441,6 +730,17 @@ fn get_exact(
         .filter(|entry| include_empty || !entry.is_empty()))
 }
 
+fn read_compaction(
+    table: &impl ReadableTable<&'static [u8; 32], &'static [u8]>,
+    namespace: &NamespaceId,
+) -> Result<Option<Compaction>> {
+    let value = table.get(namespace.as_bytes())?;
+    Ok(match value {
+        None => None,
+        Some(value) => Some(postcard::from_bytes(value.value())?),
+    })
+}
+
 /// A wrapper around [`Store`] for a specific [`NamespaceId`]
 #[derive(Debug, Clone)]
 pub struct StoreInstance {
@@ -452,6 +752,242 @@ impl StoreInstance {
     fn new(namespace: NamespaceId, store: Store) -> Self {
         StoreInstance { namespace, store }
     }
+
+    /// Runs `f` in a single write transaction, which is committed if `f` succeeds.
+    fn with_writer<T>(&self, f: impl FnOnce(&mut RecordsWriter) -> Result<T>) -> Result<T> {
+        let write_tx = self.store.db.begin_write()?;
+        let inserted_at = self.store.insertion_clock.next();
+        let res = f(&mut RecordsWriter::new(
+            self.namespace,
+            &write_tx,
+            inserted_at,
+        )?)?;
+        write_tx.commit()?;
+        Ok(res)
+    }
+}
+
+/// The tables modified by inserting and removing records, opened in a write transaction.
+struct RecordsWriter<'db, 'tx> {
+    namespace: NamespaceId,
+    records: Table<'db, 'tx, RecordsId<'static>, RecordsValue<'static>>,
+    records_by_key: Table<'db, 'tx, RecordsByKeyId<'static>, ()>,
+    records_by_inserted_at: Table<'db, 'tx, RecordsByInsertedAtId<'static>, ()>,
+    records_inserted_at: Table<'db, 'tx, RecordsId<'static>, u64>,
+    latest_per_author: Table<'db, 'tx, LatestPerAuthorKey<'static>, LatestPerAuthorValue<'static>>,
+    fingerprints: Table<'db, 'tx, FingerprintsId<'static>, &'static [u8; 32]>,
+    history: Table<'db, 'tx, HistoryId<'static>, HistoryValue<'static>>,
+    history_policy: HistoryPolicy,
+    /// Local insertion time of the records written in this transaction.
+    inserted_at: u64,
+}
+
+impl<'db, 'tx> RecordsWriter<'db, 'tx> {
+    fn new(
+        namespace: NamespaceId,
+        write_tx: &'tx WriteTransaction<'db>,
+        inserted_at: u64,
+    ) -> Result<Self> {
+        let history_policy =
+            history::read_policy(&write_tx.open_table(HISTORY_POLICY_TABLE)?, &namespace)?;
+        Ok(Self {
+            namespace,
+            records: write_tx.open_table(RECORDS_TABLE)?,
+            records_by_key: write_tx.open_table(RECORDS_BY_KEY_TABLE)?,
+            records_by_inserted_at: write_tx.open_table(RECORDS_BY_INSERTED_AT_TABLE)?,
+            records_inserted_at: write_tx.open_table(RECORDS_INSERTED_AT_TABLE)?,
+            latest_per_author: write_tx.open_table(LATEST_PER_AUTHOR_TABLE)?,
+            fingerprints: write_tx.open_table(FINGERPRINTS_TABLE)?,
+            history: write_tx.open_table(HISTORY_TABLE)?,
+            history_policy,
+            inserted_at,
+        })
+    }
+
+    /// Moves an entry which was replaced by a newer entry to the history, if the document
+    /// retains it.
+    fn retain_history(&mut self, entry: &SignedEntry) -> Result<()> {
+        history::retain(
+            &mut self.history,
+            &self.history_policy,
+            entry,
+            system_time_now(),
+        )
+    }
+
+    /// Inserts `entry` unless a newer entry exists for its key or a prefix of it, with the
+    /// semantics of `ranger::put_checked`.
+    fn put_checked(&mut self, entry: SignedEntry) -> Result<InsertOutcome> {
+        let id = entry.id();
+        let mut key = id.key().to_vec();
+        while !key.is_empty() {
+            let prefix_entry = get_exact(&self.records, id.namespace(), id.author(), &key, false)?;
+            if prefix_entry.is_some_and(|prefix_entry| entry.value() <= prefix_entry.value()) {
+                return Ok(InsertOutcome::NotInserted);
+            }
+            key.pop();
+        }
+        let removed = self.remove_prefix_filtered(id, |value| entry.value() >= value)?;
+        self.put(&entry)?;
+        Ok(InsertOutcome::Inserted { removed })
+    }
+
+    fn put(&mut self, e: &SignedEntry) -> Result<()> {
+        let id = e.id();
+        // insert into record table
+        let key = (
+            &id.namespace().to_bytes(),
+            &id.author().to_bytes(),
+            id.key(),
+        );
+        let hash = e.content_hash(); // let binding is needed
+        let value = (
+            e.timestamp(),
+            &e.signature().namespace().to_bytes(),
+            &e.signature().author().to_bytes(),
+            e.content_len(),
+            hash.as_bytes(),
+        );
+        let old = self
+            .records
+            .insert(key, value)?
+            .map(|old| into_entry(key, old.value()));
+
+        // update the fingerprint index
+        FingerprintIndex::new(self.namespace).insert(
+            &self.records,
+            &mut self.fingerprints,
+            &id.to_byte_tuple(),
+            e.as_fingerprint(),
+            old.as_ref().map(|old| old.as_fingerprint()),
+        )?;
+
+        // update the insertion time tables, unless the same entry was inserted again
+        if old.as_ref() != Some(e) {
+            let (namespace, author, key) = id.as_byte_tuple();
+            let old_inserted_at = self
+                .records_inserted_at
+                .insert((namespace, author, key), self.inserted_at)?
+                .map(|value| value.value());
+            if let Some(old_inserted_at) = old_inserted_at {
+                self.records_by_inserted_at
+                    .remove((namespace, old_inserted_at, author, key))?;
+            }
+            self.records_by_inserted_at
+                .insert((namespace, self.inserted_at, author, key), ())?;
+        }
+
+        if let Some(old) = old.filter(|old| old != e) {
+            self.retain_history(&old)?;
+        }
+
+        // insert into by key index table
+        let key = (
+            &id.namespace().to_bytes(),
+            id.key(),
+            &id.author().to_bytes(),
+        );
+        self.records_by_key.insert(key, ())?;
+
+        // insert into latest table
+        let key = (&e.id().namespace().to_bytes(), &e.id().author().to_bytes());
+        let value = (e.timestamp(), e.id().key());
+        self.latest_per_author.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &RecordIdentifier) -> Result<Option<SignedEntry>> {
+        let (namespace, author, key) = id.as_byte_tuple();
+        self.records_by_key.remove((namespace, key, author))?;
+        let entry = {
+            let id = (namespace, author, key);
+            let value = self.records.remove(id)?;
+            value.map(|value| into_entry(id, value.value()))
+        };
+        if let Some(ref entry) = entry {
+            FingerprintIndex::new(self.namespace).remove(
+                &self.records,
+                &mut self.fingerprints,
+                &id.to_byte_tuple(),
+                entry.as_fingerprint(),
+            )?;
+            self.remove_inserted_at(id)?;
+        }
+        Ok(entry)
+    }
+
+    fn remove_prefix_filtered(
+        &mut self,
+        id: &RecordIdentifier,
+        predicate: impl Fn(&Record) -> bool,
+    ) -> Result<usize> {
+        let bounds = RecordsBounds::author_prefix(id.namespace(), id.author(), id.key_bytes());
+        let cb = |_k: RecordsId, v: RecordsValue| {
+            let (timestamp, _namespace_sig, _author_sig, len, hash) = v;
+            let record = Record::new(hash.into(), len, timestamp);
+
+            predicate(&record)
+        };
+        let removed = self
+            .records
+            .drain_filter(bounds.as_ref(), cb)?
+            .map(|res| {
+                let (k, v) = res?;
+                Ok(into_entry(k.value(), v.value()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // update the fingerprint and index tables
+        let index = FingerprintIndex::new(self.namespace);
+        for entry in &removed {
+            let id = entry.id().to_byte_tuple();
+            index.remove(
+                &self.records,
+                &mut self.fingerprints,
+                &id,
+                entry.as_fingerprint(),
+            )?;
+            let (namespace, author, key) = entry.id().as_byte_tuple();
+            self.records_by_key.remove((namespace, key, author))?;
+            self.remove_inserted_at(entry.id())?;
+            self.retain_history(entry)?;
+        }
+        Ok(removed.len())
+    }
+
+    /// Remove the insertion time of a removed record.
+    fn remove_inserted_at(&mut self, id: &RecordIdentifier) -> Result<()> {
+        let (namespace, author, key) = id.as_byte_tuple();
+        let inserted_at = self
+            .records_inserted_at
+            .remove((namespace, author, key))?
+            .map(|value| value.value());
+        if let Some(inserted_at) = inserted_at {
+            self.records_by_inserted_at
+                .remove((namespace, inserted_at, author, key))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the entries of the namespace which match `predicate`.
+    fn remove_entries_filtered(
+        &mut self,
+        predicate: impl Fn(&SignedEntry) -> bool,
+    ) -> Result<usize> {
+        let bounds = RecordsBounds::namespace(self.namespace);
+        let mut ids = Vec::new();
+        for item in self.records.range(bounds.as_ref())? {
+            let (key, value) = item?;
+            let entry = into_entry(key.value(), value.value());
+            if predicate(&entry) {
+                ids.push(entry.id().clone());
+            }
+        }
+        for id in &ids {
+            self.remove(id)?;
+        }
+        Ok(ids.len())
+    }
 }
 
 impl PublicKeyStore for StoreInstance {
@@ -466,6 +1002,18 @@ impl super::DownloadPolicyStore for StoreInstance {
     }
 }
 
+impl super::DelegationStore for StoreInstance {
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>> {
+        super::Store::get_delegations(&self.store, namespace)
+    }
+}
+
+impl super::CompactionStore for StoreInstance {
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>> {
+        super::Store::get_compaction(&self.store, namespace)
+    }
+}
+
 impl crate::ranger::Store<SignedEntry> for StoreInstance {
     type Error = anyhow::Error;
     type RangeIterator<'a> =
@@ -511,56 +1059,28 @@ impl crate::ranger::Store<SignedEntry> for StoreInstance {
     }
 
     fn get_fingerprint(&self, range: &Range<RecordIdentifier>) -> Result<Fingerprint> {
-        // TODO: optimize
-        let elements = self.get_range(range.clone())?;
-
-        let mut fp = Fingerprint::empty();
-        for el in elements {
-            let el = el?;
-            fp ^= el.as_fingerprint();
-        }
-
-        Ok(fp)
+        let read_tx = self.store.db.begin_read()?;
+        let record_table = read_tx.open_table(RECORDS_TABLE)?;
+        let fingerprint_table = read_tx.open_table(FINGERPRINTS_TABLE)?;
+        FingerprintIndex::new(self.namespace).fingerprint(
+            &record_table,
+            &fingerprint_table,
+            &range.x().to_byte_tuple(),
+            &range.y().to_byte_tuple(),
+        )
     }
 
     fn put(&mut self, e: SignedEntry) -> Result<()> {
-        let id = e.id();
-        let write_tx = self.store.db.begin_write()?;
-        {
-            // insert into record table
-            let mut record_table = write_tx.open_table(RECORDS_TABLE)?;
-            let key = (
-                &id.namespace().to_bytes(),
-                &id.author().to_bytes(),
-                id.key(),
-            );
-            let hash = e.content_hash(); // let binding is needed
-            let value = (
-                e.timestamp(),
-                &e.signature().namespace().to_bytes(),
-                &e.signature().author().to_bytes(),
-                e.content_len(),
-                hash.as_bytes(),
-            );
-            record_table.insert(key, value)?;
-
-            // insert into by key index table
-            let mut idx_by_key = write_tx.open_table(RECORDS_BY_KEY_TABLE)?;
-            let key = (
-                &id.namespace().to_bytes(),
-                id.key(),
-                &id.author().to_bytes(),
-            );
-            idx_by_key.insert(key, ())?;
+        self.with_writer(|writer| writer.put(&e))
+    }
 
-            // insert into latest table
-            let mut latest_table = write_tx.open_table(LATEST_PER_AUTHOR_TABLE)?;
-            let key = (&e.id().namespace().to_bytes(), &e.id().author().to_bytes());
-            let value = (e.timestamp(), e.id().key());
-            latest_table.insert(key, value)?;
-        }
-        write_tx.commit()?;
-        Ok(())
+    fn put_many(&mut self, entries: Vec<SignedEntry>) -> Result<Vec<InsertOutcome>> {
+        self.with_writer(|writer| {
+            entries
+                .into_iter()
+                .map(|entry| writer.put_checked(entry))
+                .collect()
+        })
     }
 
     fn get_range(&self, range: Range<RecordIdentifier>) -> Result<Self::RangeIterator<'_>> {
@@ -600,21 +1120,7 @@ impl crate::ranger::Store<SignedEntry> for StoreInstance {
     }
 
     fn remove(&mut self, id: &RecordIdentifier) -> Result<Option<SignedEntry>> {
-        let write_tx = self.store.db.begin_write()?;
-        let (namespace, author, key) = id.as_byte_tuple();
-        {
-            let mut table = write_tx.open_table(RECORDS_BY_KEY_TABLE)?;
-            let id = (namespace, key, author);
-            table.remove(id)?;
-        }
-        let entry = {
-            let mut table = write_tx.open_table(RECORDS_TABLE)?;
-            let id = (namespace, author, key);
-            let value = table.remove(id)?;
-            value.map(|value| into_entry(id, value.value()))
-        };
-        write_tx.commit()?;
-        Ok(entry)
+        self.with_writer(|writer| writer.remove(id))
     }
 
     fn all(&self) -> Result<Self::RangeIterator<'_>> {
@@ -643,21 +1149,7 @@ impl crate::ranger::Store<SignedEntry> for StoreInstance {
         id: &RecordIdentifier,
         predicate: impl Fn(&Record) -> bool,
     ) -> Result<usize> {
-        let bounds = RecordsBounds::author_prefix(id.namespace(), id.author(), id.key_bytes());
-        let write_tx = self.store.db.begin_write()?;
-        let count = {
-            let mut table = write_tx.open_table(RECORDS_TABLE)?;
-            let cb = |_k: RecordsId, v: RecordsValue| {
-                let (timestamp, _namespace_sig, _author_sig, len, hash) = v;
-                let record = Record::new(hash.into(), len, timestamp);
-
-                predicate(&record)
-            };
-            let iter = table.drain_filter(bounds.as_ref(), cb)?;
-            iter.count()
-        };
-        write_tx.commit()?;
-        Ok(count)
+        self.with_writer(|writer| writer.remove_prefix_filtered(id, predicate))
     }
 }
 
@@ -734,6 +1226,31 @@ impl Iterator for ContentHashesIterator<'_> {
     }
 }
 
+/// Iterator over the content hashes of the retained previous versions of records.
+#[derive(Debug)]
+pub struct HistoryHashesIterator<'a>(TableRange<'a, HistoryId<'static>, HistoryValue<'static>>);
+
+impl<'a> HistoryHashesIterator<'a> {
+    fn new(db: &'a Arc<Database>) -> anyhow::Result<Self> {
+        Ok(Self(TableRange::new(
+            db,
+            |tx| tx.open_table(HISTORY_TABLE),
+            |table| table.iter(),
+        )?))
+    }
+}
+
+impl Iterator for HistoryHashesIterator<'_> {
+    type Item = Result<Hash>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_mapped(|_key, value| {
+            let (_replaced_at, _namespace_sig, _author_sig, _len, hash) = value;
+            Hash::from(hash)
+        })
+    }
+}
+
 /// Iterator over the latest entry per author.
 #[derive(Debug)]
 pub struct LatestIterator<'a>(
@@ -941,6 +1458,123 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fingerprint_index() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = Store::new(dbfile.path())?;
+        let authors = [
+            store.new_author(&mut rand::thread_rng())?,
+            store.new_author(&mut rand::thread_rng())?,
+        ];
+        let namespace = NamespaceSecret::new(&mut rand::thread_rng());
+        let other_namespace = NamespaceSecret::new(&mut rand::thread_rng());
+
+        let mut wrapper = StoreInstance::new(namespace.id(), store.clone());
+        let mut other_wrapper = StoreInstance::new(other_namespace.id(), store.clone());
+        let id =
+            |author: &Author, key: &str| RecordIdentifier::new(namespace.id(), author.id(), key);
+        let put = |wrapper: &mut StoreInstance,
+                   ns: &NamespaceSecret,
+                   author: &Author,
+                   key: &str,
+                   value: &str| {
+            let id = RecordIdentifier::new(ns.id(), author.id(), key);
+            let entry = Entry::new(id, Record::current_from_data(value));
+            wrapper.put(SignedEntry::from_entry(entry, ns, author))
+        };
+        let check = |wrapper: &StoreInstance| -> Result<()> {
+            let ranges = [
+                (id(&authors[0], ""), id(&authors[0], "")),
+                (id(&authors[0], "key-0042"), id(&authors[0], "key-0777")),
+                (id(&authors[0], "key-0500x"), id(&authors[1], "key-0300")),
+                (id(&authors[1], "key-0900"), id(&authors[0], "key-0100")),
+                (id(&authors[1], "key-0123"), id(&authors[1], "key-0124")),
+                (id(&authors[1], "key-0999"), id(&authors[1], "zzz")),
+            ];
+            for (x, y) in ranges {
+                let range = Range::from((x, y));
+                let mut expected = Fingerprint::empty();
+                for entry in wrapper.get_range(range.clone())? {
+                    expected ^= entry?.as_fingerprint();
+                }
+                assert_eq!(wrapper.get_fingerprint(&range)?, expected, "{range:?}");
+            }
+            Ok(())
+        };
+
+        for i in 0..1000 {
+            let author = &authors[i % 2];
+            put(
+                &mut wrapper,
+                &namespace,
+                author,
+                &format!("key-{i:04}"),
+                "v1",
+            )?;
+        }
+        for i in 0..50 {
+            put(
+                &mut other_wrapper,
+                &other_namespace,
+                &authors[0],
+                &format!("key-{i:04}"),
+                "v1",
+            )?;
+        }
+        check(&wrapper)?;
+
+        // replace, remove and remove by prefix
+        for i in (0..1000).step_by(7) {
+            let author = &authors[i % 2];
+            put(
+                &mut wrapper,
+                &namespace,
+                author,
+                &format!("key-{i:04}"),
+                "v2",
+            )?;
+        }
+        for i in (0..1000).step_by(5) {
+            wrapper.remove(&id(&authors[i % 2], &format!("key-{i:04}")))?;
+        }
+        wrapper.remove_prefix_filtered(&id(&authors[1], "key-03"), |_| true)?;
+        check(&wrapper)?;
+
+        // the index built by the migration matches the one maintained on updates
+        let read_index = |store: &Store| -> Result<Vec<_>> {
+            let read_tx = store.db.begin_read()?;
+            let table = read_tx.open_table(FINGERPRINTS_TABLE)?;
+            let entries = table
+                .iter()?
+                .map(|res| {
+                    let (k, v) = res?;
+                    let (level, ns, author, key) = k.value();
+                    Ok(((level, *ns, *author, key.to_vec()), *v.value()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(entries)
+        };
+        let expected = read_index(&store)?;
+        assert!(!expected.is_empty());
+        drop((wrapper, other_wrapper, store));
+        let dbfile_before_migration = copy_and_modify(dbfile.path(), |tx| {
+            tx.delete_table(FINGERPRINTS_TABLE)?;
+            Ok(())
+        })?;
+        let store = Store::new(dbfile_before_migration.path())?;
+        assert_eq!(read_index(&store)?, expected);
+        check(&StoreInstance::new(namespace.id(), store.clone()))?;
+
+        // removing the replica removes its part of the index
+        store.remove_replica(&namespace.id())?;
+        let remaining = read_index(&store)?;
+        assert!(remaining
+            .iter()
+            .all(|((_, ns, _, _), _)| ns == other_namespace.id().as_bytes()));
+
+        Ok(())
+    }
+
     #[test]
     fn test_migration_004_populate_by_key_index() -> Result<()> {
         let dbfile = tempfile::NamedTempFile::new()?;
@@ -958,4 +1592,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_delete_prefix_updates_by_key_index() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = Store::new(dbfile.path())?;
+
+        let author = store.new_author(&mut rand::thread_rng())?;
+        let namespace = NamespaceSecret::new(&mut rand::thread_rng());
+        let mut replica = store.new_replica(namespace)?;
+
+        replica.hash_and_insert("a/1", &author, b"v1")?;
+        replica.hash_and_insert("a/2", &author, b"v2")?;
+        replica.hash_and_insert("b", &author, b"v3")?;
+        replica.delete_prefix("a/", &author)?;
+
+        // only the tombstone for `a/` and `b` are left in the index
+        let read_tx = store.db.begin_read()?;
+        let record_by_key_table = read_tx.open_table(RECORDS_BY_KEY_TABLE)?;
+        let keys = record_by_key_table
+            .iter()?
+            .map(|item| Ok(item?.0.value().1.to_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![b"a/".to_vec(), b"b".to_vec()]);
+
+        Ok(())
+    }
 }
@@ -2,9 +2,15 @@ use std::ops::{Bound, RangeBounds};
 
 use bytes::Bytes;
 
-use crate::{store::KeyFilter, AuthorId, NamespaceId};
+use crate::{
+    store::{KeyFilter, SortDirection, TimestampFilter},
+    AuthorId, NamespaceId,
+};
 
-use super::{RecordsByKeyId, RecordsByKeyIdOwned, RecordsId, RecordsIdOwned};
+use super::{
+    RecordsByInsertedAtId, RecordsByInsertedAtIdOwned, RecordsByKeyId, RecordsByKeyIdOwned,
+    RecordsId, RecordsIdOwned,
+};
 
 /// Bounds on the records table.
 ///
@@ -18,23 +24,30 @@ impl RecordsBounds {
 
     pub fn author_key(ns: NamespaceId, author: AuthorId, key_matcher: KeyFilter) -> Self {
         let key_is_exact = matches!(key_matcher, KeyFilter::Exact(_));
-        let key = match key_matcher {
-            KeyFilter::Any => Bytes::new(),
-            KeyFilter::Exact(key) => key,
-            KeyFilter::Prefix(prefix) => prefix,
+        let (key, key_end) = match key_matcher {
+            KeyFilter::Any => (Bytes::new(), None),
+            KeyFilter::Exact(key) => (key, None),
+            KeyFilter::Prefix(prefix) => {
+                let mut key_end = prefix.to_vec();
+                let key_end = increment_by_one(&mut key_end).then(|| key_end.into());
+                (prefix, key_end)
+            }
+            KeyFilter::Range { start, end } => {
+                let end = end.map(|end| end.max(start.clone()));
+                (start, end)
+            }
         };
         let author = author.to_bytes();
         let ns = ns.to_bytes();
         let mut author_end = author;
         let mut ns_end = ns;
-        let mut key_end = key.to_vec();
 
         let start = (ns, author, key);
 
         let end = if key_is_exact {
             Bound::Included(start.clone())
-        } else if increment_by_one(&mut key_end) {
-            Bound::Excluded((ns, author, key_end.into()))
+        } else if let Some(key_end) = key_end {
+            Bound::Excluded((ns, author, key_end))
         } else if increment_by_one(&mut author_end) {
             Bound::Excluded((ns, author_end, Bytes::new()))
         } else if increment_by_one(&mut ns_end) {
@@ -62,6 +75,12 @@ impl RecordsBounds {
         Self::new(start, Self::namespace_end(ns))
     }
 
+    /// Narrow the bounds to the records after `position` in `direction`.
+    pub fn after(self, position: RecordsIdOwned, direction: &SortDirection) -> Self {
+        let (start, end) = narrow_after((self.0, self.1), position, direction);
+        Self(start, end)
+    }
+
     pub fn as_ref(&self) -> (Bound<RecordsId>, Bound<RecordsId>) {
         fn map(id: &RecordsIdOwned) -> RecordsId {
             (&id.0, &id.1, &id.2[..])
@@ -126,6 +145,18 @@ impl ByKeyBounds {
                 };
                 Self(start, end)
             }
+            KeyFilter::Range { start, end } => {
+                let end = match end {
+                    Some(end) => {
+                        Bound::Excluded((ns.to_bytes(), end.clone().max(start.clone()), [0u8; 32]))
+                    }
+                    None => Self::namespace(ns).1,
+                };
+                Self(
+                    Bound::Included((ns.to_bytes(), start.clone(), [0u8; 32])),
+                    end,
+                )
+            }
         }
     }
 
@@ -140,6 +171,12 @@ impl ByKeyBounds {
         Self(start, end)
     }
 
+    /// Narrow the bounds to the rows after `position` in `direction`.
+    pub fn after(self, position: RecordsByKeyIdOwned, direction: &SortDirection) -> Self {
+        let (start, end) = narrow_after((self.0, self.1), position, direction);
+        Self(start, end)
+    }
+
     pub fn as_ref(&self) -> (Bound<RecordsByKeyId>, Bound<RecordsByKeyId>) {
         fn map(id: &RecordsByKeyIdOwned) -> RecordsByKeyId {
             (&id.0, &id.1[..], &id.2)
@@ -158,10 +195,85 @@ impl RangeBounds<RecordsByKeyIdOwned> for ByKeyBounds {
     }
 }
 
+/// Bounds for the by-insertion-time index table.
+///
+/// Supports bounds by the local insertion time.
+pub struct ByInsertedAtBounds(
+    Bound<RecordsByInsertedAtIdOwned>,
+    Bound<RecordsByInsertedAtIdOwned>,
+);
+
+impl ByInsertedAtBounds {
+    pub fn new(ns: NamespaceId, filter: &TimestampFilter) -> Self {
+        let since = filter.since.unwrap_or(0);
+        let start = Bound::Included((ns.to_bytes(), since, [0u8; 32], Bytes::new()));
+        let end = match filter.until {
+            Some(until) => {
+                Bound::Excluded((ns.to_bytes(), until.max(since), [0u8; 32], Bytes::new()))
+            }
+            None => Self::namespace(ns).1,
+        };
+        Self(start, end)
+    }
+
+    pub fn namespace(ns: NamespaceId) -> Self {
+        let start = Bound::Included((ns.to_bytes(), 0, [0u8; 32], Bytes::new()));
+        let mut ns_end = ns.to_bytes();
+        let end = if increment_by_one(&mut ns_end) {
+            Bound::Excluded((ns_end, 0, [0u8; 32], Bytes::new()))
+        } else {
+            Bound::Unbounded
+        };
+        Self(start, end)
+    }
+
+    /// Narrow the bounds to the rows after `position` in `direction`.
+    pub fn after(self, position: RecordsByInsertedAtIdOwned, direction: &SortDirection) -> Self {
+        let (start, end) = narrow_after((self.0, self.1), position, direction);
+        Self(start, end)
+    }
+
+    pub fn as_ref(&self) -> (Bound<RecordsByInsertedAtId>, Bound<RecordsByInsertedAtId>) {
+        fn map(id: &RecordsByInsertedAtIdOwned) -> RecordsByInsertedAtId {
+            (&id.0, id.1, &id.2, &id.3[..])
+        }
+        (map_bound(&self.0, map), map_bound(&self.1, map))
+    }
+}
+
+impl RangeBounds<RecordsByInsertedAtIdOwned> for ByInsertedAtBounds {
+    fn start_bound(&self) -> Bound<&RecordsByInsertedAtIdOwned> {
+        map_bound(&self.0, |s| s)
+    }
+
+    fn end_bound(&self) -> Bound<&RecordsByInsertedAtIdOwned> {
+        map_bound(&self.1, |s| s)
+    }
+}
+
+/// Narrow `bounds` to the part after `position`, in `direction`.
+///
+/// The bounds are returned unchanged if they do not contain `position`, so that the range never
+/// becomes inverted. Callers still have to filter the rows in that case.
+fn narrow_after<T: Ord>(
+    bounds: (Bound<T>, Bound<T>),
+    position: T,
+    direction: &SortDirection,
+) -> (Bound<T>, Bound<T>) {
+    if !bounds.contains(&position) {
+        return bounds;
+    }
+    let (start, end) = bounds;
+    match direction {
+        SortDirection::Asc => (Bound::Excluded(position), end),
+        SortDirection::Desc => (start, Bound::Excluded(position)),
+    }
+}
+
 /// Increment a byte string by one, by incrementing the last byte that is not 255 by one.
 ///
 /// Returns false if all bytes are 255.
-fn increment_by_one(value: &mut [u8]) -> bool {
+pub fn increment_by_one(value: &mut [u8]) -> bool {
     for char in value.iter_mut().rev() {
         if *char != 255 {
             *char += 1;
@@ -173,7 +285,7 @@ fn increment_by_one(value: &mut [u8]) -> bool {
     false
 }
 
-fn map_bound<'a, T, U: 'a>(bound: &'a Bound<T>, f: impl Fn(&'a T) -> U) -> Bound<U> {
+pub fn map_bound<'a, T, U: 'a>(bound: &'a Bound<T>, f: impl Fn(&'a T) -> U) -> Bound<U> {
     match bound {
         Bound::Unbounded => Bound::Unbounded,
         Bound::Included(t) => Bound::Included(f(t)),
@@ -233,6 +345,47 @@ mod tests {
             bounds.end_bound(),
             Bound::Included(&(ns.to_bytes(), a.to_bytes(), vec![1u8].into()))
         );
+
+        let range = KeyFilter::Range {
+            start: vec![1u8].into(),
+            end: Some(vec![3u8].into()),
+        };
+        let bounds = RecordsBounds::author_key(ns, a, range);
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), a.to_bytes(), vec![1u8].into()))
+        );
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), a.to_bytes(), vec![3u8].into()))
+        );
+
+        let range = KeyFilter::Range {
+            start: vec![1u8].into(),
+            end: None,
+        };
+        let bounds = RecordsBounds::author_key(ns, a, range);
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), a_end, Default::default()))
+        );
+
+        let bounds = bounds.after(
+            (ns.to_bytes(), a.to_bytes(), vec![2u8].into()),
+            &SortDirection::Asc,
+        );
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Excluded(&(ns.to_bytes(), a.to_bytes(), vec![2u8].into()))
+        );
+        let bounds = bounds.after(
+            (ns.to_bytes(), a.to_bytes(), vec![0u8].into()),
+            &SortDirection::Asc,
+        );
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Excluded(&(ns.to_bytes(), a.to_bytes(), vec![2u8].into()))
+        );
     }
 
     #[test]
@@ -292,5 +445,84 @@ mod tests {
             bounds.end_bound(),
             Bound::Included(&(ns.to_bytes(), vec![1u8].into(), [255u8; 32]))
         );
+
+        let range = KeyFilter::Range {
+            start: vec![1u8].into(),
+            end: Some(vec![3u8].into()),
+        };
+        let bounds = ByKeyBounds::new(ns, &range);
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), vec![1u8].into(), [0u8; 32]))
+        );
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), vec![3u8].into(), [0u8; 32]))
+        );
+
+        let bounds = bounds.after(
+            (ns.to_bytes(), vec![2u8].into(), [0u8; 32]),
+            &SortDirection::Desc,
+        );
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), vec![1u8].into(), [0u8; 32]))
+        );
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), vec![2u8].into(), [0u8; 32]))
+        );
+
+        // an empty range must not be inverted
+        let range = KeyFilter::Range {
+            start: vec![3u8].into(),
+            end: Some(vec![1u8].into()),
+        };
+        let bounds = ByKeyBounds::new(ns, &range);
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), vec![3u8].into(), [0u8; 32]))
+        );
+    }
+
+    #[test]
+    fn by_inserted_at_bounds() {
+        let ns = NamespaceId::from(&[2u8; 32]);
+        let mut ns_end = ns.to_bytes();
+        ns_end[31] = 3u8;
+
+        let bounds = ByInsertedAtBounds::new(ns, &TimestampFilter::default());
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), 0, [0u8; 32], Bytes::new()))
+        );
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns_end, 0, [0u8; 32], Bytes::new()))
+        );
+
+        let filter = TimestampFilter {
+            since: Some(10),
+            until: Some(20),
+        };
+        let bounds = ByInsertedAtBounds::new(ns, &filter);
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), 10, [0u8; 32], Bytes::new()))
+        );
+        assert_eq!(
+            bounds.end_bound(),
+            Bound::Excluded(&(ns.to_bytes(), 20, [0u8; 32], Bytes::new()))
+        );
+
+        // a position outside of the bounds leaves them unchanged
+        let bounds = bounds.after(
+            (ns.to_bytes(), 30, [0u8; 32], Bytes::new()),
+            &SortDirection::Asc,
+        );
+        assert_eq!(
+            bounds.start_bound(),
+            Bound::Included(&(ns.to_bytes(), 10, [0u8; 32], Bytes::new()))
+        );
     }
 }
@@ -0,0 +1,409 @@
+//! An index of XOR fingerprints over ranges of the records table.
+//!
+//! Every record is assigned a level derived from the hash of its id, one in `16^l` records
+//! has a level of at least `l`.  Such a record is a boundary on all levels up to its own, and
+//! for each level `l >= 1` the [`super::FINGERPRINTS_TABLE`] stores the combined fingerprint
+//! of the segment from every boundary up to the next boundary of the same level.  Level 0 are
+//! the records themselves.
+//!
+//! The fingerprint of an arbitrary range is combined by walking up the levels from the start
+//! of the range, taking the largest segments that fit, and down again towards the end of the
+//! range, so only a few segments per level are read instead of every record in the range.
+//! Since the levels only depend on the record ids, the index does not depend on the order in
+//! which records were inserted.
+
+use std::{cmp::Ordering, ops::Bound};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use redb::{ReadableTable, Table};
+
+use crate::{
+    ranger::{Fingerprint, RangeEntry},
+    NamespaceId,
+};
+
+use super::{
+    bounds::{increment_by_one, map_bound, RecordsBounds},
+    into_entry, FingerprintsId, RecordsId, RecordsIdOwned, RecordsValue,
+};
+
+/// Number of bits of the id hash which have to be zero per level, so a segment contains
+/// 16 segments of the level below on average.
+const LEVEL_BITS: u32 = 4;
+
+/// The highest level, which gives segments of about four billion records.
+const MAX_LEVEL: u8 = 8;
+
+type FingerprintsIdOwned = (u8, [u8; 32], [u8; 32], Bytes);
+
+/// The neutral element of XOR, segments store the plain XOR of their records.
+const ZERO: Fingerprint = Fingerprint([0u8; 32]);
+
+/// The level of the record with the id `(namespace, author, key)`.
+fn level_of(id: &RecordsIdOwned) -> u8 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&id.0);
+    hasher.update(&id.1);
+    hasher.update(&id.2);
+    let hash = hasher.finalize();
+    let prefix = u64::from_be_bytes(hash.as_bytes()[..8].try_into().expect("8 bytes"));
+    let level = prefix.leading_zeros() / LEVEL_BITS;
+    level.min(MAX_LEVEL as u32) as u8
+}
+
+fn fingerprint_key(level: u8, id: &RecordsIdOwned) -> FingerprintsId {
+    (level, &id.0, &id.1, &id.2[..])
+}
+
+fn to_owned_id((namespace, author, key): RecordsId) -> RecordsIdOwned {
+    (*namespace, *author, Bytes::copy_from_slice(key))
+}
+
+fn record_fingerprint(id: RecordsId, value: RecordsValue) -> Fingerprint {
+    into_entry(id, value).as_fingerprint()
+}
+
+/// The fingerprint index of a single namespace.
+#[derive(Debug, Clone, Copy)]
+pub struct FingerprintIndex {
+    namespace: NamespaceId,
+}
+
+impl FingerprintIndex {
+    pub fn new(namespace: NamespaceId) -> Self {
+        Self { namespace }
+    }
+
+    /// The [`Fingerprint`] of the range from `x` to `y`, following the semantics of
+    /// [`crate::ranger::Range`].
+    pub fn fingerprint(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &impl ReadableTable<FingerprintsId<'static>, &'static [u8; 32]>,
+        x: &RecordsIdOwned,
+        y: &RecordsIdOwned,
+    ) -> Result<Fingerprint> {
+        let mut fp = Fingerprint::empty();
+        match x.cmp(y) {
+            // identity range: all records of the namespace
+            Ordering::Equal => {
+                fp ^= self.xor(records, fingerprints, None, None, MAX_LEVEL)?;
+            }
+            // regular range: x <= t < y
+            Ordering::Less => {
+                fp ^= self.xor(records, fingerprints, Some(x), Some(y), MAX_LEVEL)?;
+            }
+            // split range: start <= t < y and x <= t <= end
+            Ordering::Greater => {
+                fp ^= self.xor(records, fingerprints, None, Some(y), MAX_LEVEL)?;
+                fp ^= self.xor(records, fingerprints, Some(x), None, MAX_LEVEL)?;
+            }
+        }
+        Ok(fp)
+    }
+
+    /// The XOR of the fingerprints of the records from `start` (inclusive) to `end`
+    /// (exclusive), using only the segments up to `max_level`.
+    ///
+    /// `None` stands for the start and end of the namespace respectively.
+    fn xor(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &impl ReadableTable<FingerprintsId<'static>, &'static [u8; 32]>,
+        start: Option<&RecordsIdOwned>,
+        end: Option<&RecordsIdOwned>,
+        max_level: u8,
+    ) -> Result<Fingerprint> {
+        let mut fp = ZERO;
+        let start = start.map_or(Bound::Unbounded, Bound::Included);
+        let Some((mut pos, mut segment)) = self.next_at(records, fingerprints, 0, start)? else {
+            return Ok(fp);
+        };
+        if end.is_some_and(|end| &pos >= end) {
+            return Ok(fp);
+        }
+
+        // `pos` is always a boundary of `level`, and `segment` the fingerprint of its segment.
+        let mut level = 0;
+        let mut descending = false;
+        loop {
+            if !descending && level < max_level && level_of(&pos) > level {
+                level += 1;
+                segment = self.segment(records, fingerprints, level, &pos)?;
+                continue;
+            }
+            let next = self.next_at(records, fingerprints, level, Bound::Excluded(&pos))?;
+            // A record on level 0 always fits, larger segments must end before `end`.
+            let fits = level == 0
+                || match (&next, end) {
+                    (_, None) => true,
+                    (Some((next, _)), Some(end)) => next <= end,
+                    (None, Some(_)) => false,
+                };
+            if !fits {
+                // Continue with the smaller segments of the level below.
+                descending = true;
+                level -= 1;
+                segment = self.segment(records, fingerprints, level, &pos)?;
+                continue;
+            }
+            fp ^= segment;
+            match next {
+                Some((next, next_segment)) if end.map_or(true, |end| &next < end) => {
+                    pos = next;
+                    segment = next_segment;
+                }
+                _ => return Ok(fp),
+            }
+        }
+    }
+
+    /// Updates the index after the record `id` was inserted into the records table.
+    ///
+    /// `new` is the fingerprint of the inserted record, `old` the one of the record it
+    /// replaced, if any.
+    pub fn insert(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &mut Table<FingerprintsId<'static>, &'static [u8; 32]>,
+        id: &RecordsIdOwned,
+        new: Fingerprint,
+        old: Option<Fingerprint>,
+    ) -> Result<()> {
+        let mut delta = new;
+        if let Some(old) = old {
+            if old == new {
+                return Ok(());
+            }
+            delta ^= old;
+        }
+        let id_level = level_of(id);
+        for level in 1..=MAX_LEVEL {
+            if old.is_none() && level <= id_level {
+                // The record is a new boundary, which splits the segment it falls into.
+                let next = self
+                    .next_at(records, &*fingerprints, level, Bound::Excluded(id))?
+                    .map(|(next, _)| next);
+                let segment =
+                    self.xor(records, &*fingerprints, Some(id), next.as_ref(), level - 1)?;
+                if let Some(prev) = self.prev_at(&*fingerprints, level, Bound::Excluded(id))? {
+                    let mut prev_segment = self.segment(records, &*fingerprints, level, &prev)?;
+                    prev_segment ^= segment;
+                    prev_segment ^= new;
+                    fingerprints.insert(fingerprint_key(level, &prev), &prev_segment.0)?;
+                }
+                fingerprints.insert(fingerprint_key(level, id), &segment.0)?;
+            } else {
+                self.update_owner(records, fingerprints, level, id, delta)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the index after the record `id` with the fingerprint `old` was removed from the
+    /// records table.
+    pub fn remove(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &mut Table<FingerprintsId<'static>, &'static [u8; 32]>,
+        id: &RecordsIdOwned,
+        old: Fingerprint,
+    ) -> Result<()> {
+        let id_level = level_of(id);
+        for level in 1..=MAX_LEVEL {
+            if level <= id_level {
+                // The segment of the record is merged into the previous one.
+                let segment = self.segment(records, &*fingerprints, level, id)?;
+                fingerprints.remove(fingerprint_key(level, id))?;
+                if let Some(prev) = self.prev_at(&*fingerprints, level, Bound::Excluded(id))? {
+                    let mut prev_segment = self.segment(records, &*fingerprints, level, &prev)?;
+                    prev_segment ^= segment;
+                    prev_segment ^= old;
+                    fingerprints.insert(fingerprint_key(level, &prev), &prev_segment.0)?;
+                }
+            } else {
+                self.update_owner(records, fingerprints, level, id, old)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the index of the whole namespace.
+    pub fn clear(
+        &self,
+        fingerprints: &mut Table<FingerprintsId<'static>, &'static [u8; 32]>,
+    ) -> Result<()> {
+        for level in 1..=MAX_LEVEL {
+            let start = self.level_start(level);
+            let end = self.level_end(level);
+            fingerprints.drain((Bound::Included(as_ref(&start)), map_bound(&end, as_ref)))?;
+        }
+        Ok(())
+    }
+
+    /// XORs `delta` into the segment of `level` which contains `id`.
+    fn update_owner(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &mut Table<FingerprintsId<'static>, &'static [u8; 32]>,
+        level: u8,
+        id: &RecordsIdOwned,
+        delta: Fingerprint,
+    ) -> Result<()> {
+        // Records before the first boundary of a level are not part of any of its segments.
+        if let Some(owner) = self.prev_at(&*fingerprints, level, Bound::Included(id))? {
+            let mut segment = self.segment(records, &*fingerprints, level, &owner)?;
+            segment ^= delta;
+            fingerprints.insert(fingerprint_key(level, &owner), &segment.0)?;
+        }
+        Ok(())
+    }
+
+    /// The fingerprint of the segment of `level` starting at the boundary `id`.
+    fn segment(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &impl ReadableTable<FingerprintsId<'static>, &'static [u8; 32]>,
+        level: u8,
+        id: &RecordsIdOwned,
+    ) -> Result<Fingerprint> {
+        let fp = if level == 0 {
+            let key = (&id.0, &id.1, &id.2[..]);
+            records
+                .get(key)?
+                .map(|value| record_fingerprint(key, value.value()))
+        } else {
+            fingerprints
+                .get(fingerprint_key(level, id))?
+                .map(|value| Fingerprint(*value.value()))
+        };
+        fp.ok_or_else(|| anyhow!("fingerprint index is missing a segment on level {level}"))
+    }
+
+    /// The first boundary of `level` after `start` and the fingerprint of its segment.
+    fn next_at(
+        &self,
+        records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+        fingerprints: &impl ReadableTable<FingerprintsId<'static>, &'static [u8; 32]>,
+        level: u8,
+        start: Bound<&RecordsIdOwned>,
+    ) -> Result<Option<(RecordsIdOwned, Fingerprint)>> {
+        if level == 0 {
+            let bounds = match start {
+                Bound::Unbounded => RecordsBounds::namespace(self.namespace),
+                start => RecordsBounds::to_end(&self.namespace, start.cloned()),
+            };
+            let Some(next) = records.range(bounds.as_ref())?.next() else {
+                return Ok(None);
+            };
+            let (id, value) = next?;
+            let fp = record_fingerprint(id.value(), value.value());
+            Ok(Some((to_owned_id(id.value()), fp)))
+        } else {
+            let level_start = self.level_start(level);
+            let start = match start {
+                Bound::Unbounded => Bound::Included(as_ref(&level_start)),
+                Bound::Included(id) => Bound::Included(fingerprint_key(level, id)),
+                Bound::Excluded(id) => Bound::Excluded(fingerprint_key(level, id)),
+            };
+            let end = self.level_end(level);
+            let Some(next) = fingerprints.range((start, map_bound(&end, as_ref)))?.next() else {
+                return Ok(None);
+            };
+            let (id, value) = next?;
+            let (_level, namespace, author, key) = id.value();
+            let fp = Fingerprint(*value.value());
+            Ok(Some((to_owned_id((namespace, author, key)), fp)))
+        }
+    }
+
+    /// The last boundary of `level` before `end`, `level` must be at least 1.
+    fn prev_at(
+        &self,
+        fingerprints: &impl ReadableTable<FingerprintsId<'static>, &'static [u8; 32]>,
+        level: u8,
+        end: Bound<&RecordsIdOwned>,
+    ) -> Result<Option<RecordsIdOwned>> {
+        let level_start = self.level_start(level);
+        let level_end = self.level_end(level);
+        let end = match end {
+            Bound::Unbounded => map_bound(&level_end, as_ref),
+            Bound::Included(id) => Bound::Included(fingerprint_key(level, id)),
+            Bound::Excluded(id) => Bound::Excluded(fingerprint_key(level, id)),
+        };
+        let Some(prev) = fingerprints
+            .range((Bound::Included(as_ref(&level_start)), end))?
+            .next_back()
+        else {
+            return Ok(None);
+        };
+        let (id, _value) = prev?;
+        let (_level, namespace, author, key) = id.value();
+        Ok(Some(to_owned_id((namespace, author, key))))
+    }
+
+    fn level_start(&self, level: u8) -> FingerprintsIdOwned {
+        (level, self.namespace.to_bytes(), [0u8; 32], Bytes::new())
+    }
+
+    fn level_end(&self, level: u8) -> Bound<FingerprintsIdOwned> {
+        let mut namespace_end = self.namespace.to_bytes();
+        if increment_by_one(&mut namespace_end) {
+            Bound::Excluded((level, namespace_end, [0u8; 32], Bytes::new()))
+        } else {
+            Bound::Excluded((level + 1, [0u8; 32], [0u8; 32], Bytes::new()))
+        }
+    }
+}
+
+fn as_ref(id: &FingerprintsIdOwned) -> FingerprintsId {
+    (id.0, &id.1, &id.2, &id.3[..])
+}
+
+/// Builds the fingerprint index from all records in the records table, returning the number of
+/// segments written.
+///
+/// The fingerprints table must be empty.
+pub fn build_index(
+    records: &impl ReadableTable<RecordsId<'static>, RecordsValue<'static>>,
+    fingerprints: &mut Table<FingerprintsId<'static>, &'static [u8; 32]>,
+) -> Result<usize> {
+    // The segment currently being built on each level, by its first record.
+    let mut open: [Option<(RecordsIdOwned, Fingerprint)>; MAX_LEVEL as usize] = Default::default();
+    let mut count = 0;
+    let mut flush = |level: usize, segment: Option<(RecordsIdOwned, Fingerprint)>| -> Result<()> {
+        if let Some((id, fp)) = segment {
+            fingerprints.insert(fingerprint_key(level as u8 + 1, &id), &fp.0)?;
+            count += 1;
+        }
+        Ok(())
+    };
+    for next in records.iter()? {
+        let (id, value) = next?;
+        let fp = record_fingerprint(id.value(), value.value());
+        let id = to_owned_id(id.value());
+        // segments never span namespaces
+        let new_namespace = open
+            .iter()
+            .flatten()
+            .next()
+            .is_some_and(|(start, _)| start.0 != id.0);
+        if new_namespace {
+            for (level, segment) in open.iter_mut().enumerate() {
+                flush(level, segment.take())?;
+            }
+        }
+        for (level, segment) in open.iter_mut().enumerate().take(level_of(&id) as usize) {
+            flush(level, segment.take())?;
+            *segment = Some((id.clone(), ZERO));
+        }
+        for (_start, segment_fp) in open.iter_mut().flatten() {
+            *segment_fp ^= fp;
+        }
+    }
+    for (level, segment) in open.iter_mut().enumerate() {
+        flush(level, segment.take())?;
+    }
+    Ok(count)
+}
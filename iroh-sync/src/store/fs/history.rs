@@ -0,0 +1,172 @@
+//! Previous versions of records, retained according to the [`HistoryPolicy`] of their document.
+//!
+//! When a record is replaced by a newer entry for its key or a prefix of it, the replaced entry
+//! is moved to the [`super::HISTORY_TABLE`] if the document retains its history.  The versions
+//! of a key are pruned to the policy whenever a version is added, and for all documents by
+//! [`prune_all`], which also applies changed policies and the maximum age.
+
+use std::{collections::HashMap, ops::Bound};
+
+use anyhow::Result;
+use bytes::Bytes;
+use redb::{ReadableTable, Table};
+
+use crate::{
+    store::HistoryPolicy,
+    sync::{Entry, EntrySignature, Record, RecordIdentifier, SignedEntry},
+    NamespaceId,
+};
+
+use super::{bounds::increment_by_one, HistoryId, HistoryValue};
+
+type HistoryIdOwned = ([u8; 32], Bytes, [u8; 32], u64);
+
+/// Read the [`HistoryPolicy`] of `namespace`.
+pub fn read_policy(
+    policies: &impl ReadableTable<&'static [u8; 32], &'static [u8]>,
+    namespace: &NamespaceId,
+) -> Result<HistoryPolicy> {
+    Ok(match policies.get(namespace.as_bytes())? {
+        None => HistoryPolicy::default(),
+        Some(value) => postcard::from_bytes(value.value())?,
+    })
+}
+
+/// Add `entry`, which was replaced at `now`, and prune the older versions of its key.
+pub fn retain(
+    history: &mut Table<HistoryId<'static>, HistoryValue<'static>>,
+    policy: &HistoryPolicy,
+    entry: &SignedEntry,
+    now: u64,
+) -> Result<()> {
+    if !policy.is_enabled() {
+        return Ok(());
+    }
+    let id = entry.id();
+    let namespace = id.namespace().to_bytes();
+    let author = id.author().to_bytes();
+    let hash = entry.content_hash(); // let binding is needed
+    let key: HistoryId = (&namespace, id.key(), &author, entry.timestamp());
+    let value = (
+        now,
+        &entry.signature().namespace().to_bytes(),
+        &entry.signature().author().to_bytes(),
+        entry.content_len(),
+        hash.as_bytes(),
+    );
+    history.insert(key, value)?;
+
+    let start: HistoryId = (&namespace, id.key(), &author, 0);
+    let end: HistoryId = (&namespace, id.key(), &author, u64::MAX);
+    let mut versions = Vec::new();
+    for item in history.range(start..=end)? {
+        let (key, value) = item?;
+        versions.push((key.value().3, value.value().0));
+    }
+    let replaced_at = versions.iter().map(|(_, at)| *at).collect::<Vec<_>>();
+    for i in policy.expired(&replaced_at, now) {
+        history.remove((&namespace, id.key(), &author, versions[i].0))?;
+    }
+    Ok(())
+}
+
+/// Get the versions of `key` of all authors in `namespace`, newest first.
+pub fn get(
+    history: &impl ReadableTable<HistoryId<'static>, HistoryValue<'static>>,
+    namespace: &NamespaceId,
+    key: &[u8],
+) -> Result<Vec<SignedEntry>> {
+    let namespace = namespace.to_bytes();
+    let start: HistoryId = (&namespace, key, &[0u8; 32], 0);
+    let end: HistoryId = (&namespace, key, &[255u8; 32], u64::MAX);
+    let mut entries = Vec::new();
+    for item in history.range(start..=end)? {
+        let (key, value) = item?;
+        entries.push(into_entry(key.value(), value.value()));
+    }
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp()));
+    Ok(entries)
+}
+
+/// Remove the versions which are no longer retained by the policy of their document.
+///
+/// The table is walked one author and key at a time, so only the versions of a single key are
+/// held in memory. Returns the number of removed versions.
+pub fn prune_all(
+    history: &mut Table<HistoryId<'static>, HistoryValue<'static>>,
+    policies: &impl ReadableTable<&'static [u8; 32], &'static [u8]>,
+    now: u64,
+) -> Result<usize> {
+    let mut policies_by_namespace = HashMap::new();
+    let mut removed = 0;
+    let mut last: Option<HistoryIdOwned> = None;
+    loop {
+        // the versions of a single author and key are adjacent
+        let mut group: Option<([u8; 32], Bytes, [u8; 32])> = None;
+        let mut versions = Vec::new();
+        let range = match &last {
+            None => history.iter()?,
+            Some((namespace, key, author, timestamp)) => {
+                let start: HistoryId = (namespace, &key[..], author, *timestamp);
+                history.range::<HistoryId>((Bound::Excluded(start), Bound::Unbounded))?
+            }
+        };
+        for item in range {
+            let (key, value) = item?;
+            let (namespace, key, author, timestamp) = key.value();
+            match &group {
+                None => group = Some((*namespace, Bytes::copy_from_slice(key), *author)),
+                Some((n, k, a)) if (n, &k[..], a) != (namespace, key, author) => break,
+                Some(_) => {}
+            }
+            versions.push((timestamp, value.value().0));
+        }
+        let Some((namespace, key, author)) = group else {
+            break;
+        };
+
+        let policy = match policies_by_namespace.get(&namespace) {
+            Some(policy) => *policy,
+            None => {
+                let policy = read_policy(policies, &NamespaceId::from(&namespace))?;
+                policies_by_namespace.insert(namespace, policy);
+                policy
+            }
+        };
+        let replaced_at = versions.iter().map(|(_, at)| *at).collect::<Vec<_>>();
+        for i in policy.expired(&replaced_at, now) {
+            history.remove((&namespace, &key[..], &author, versions[i].0))?;
+            removed += 1;
+        }
+        let (timestamp, _) = versions.last().expect("group is not empty");
+        last = Some((namespace, key, author, *timestamp));
+    }
+    Ok(removed)
+}
+
+/// Remove all versions of `namespace`.
+pub fn clear(
+    history: &mut Table<HistoryId<'static>, HistoryValue<'static>>,
+    namespace: &NamespaceId,
+) -> Result<()> {
+    let namespace = namespace.to_bytes();
+    let start: HistoryId = (&namespace, &[], &[0u8; 32], 0);
+    let mut namespace_end = namespace;
+    if increment_by_one(&mut namespace_end) {
+        let end: HistoryId = (&namespace_end, &[], &[0u8; 32], 0);
+        history.drain(start..end)?;
+    } else {
+        history.drain(start..)?;
+    }
+    Ok(())
+}
+
+fn into_entry(key: HistoryId, value: HistoryValue) -> SignedEntry {
+    let (namespace, key, author, timestamp) = key;
+    let (_replaced_at, namespace_sig, author_sig, len, hash) = value;
+    let id = RecordIdentifier::new(namespace, author, key);
+    let record = Record::new(hash.into(), len, timestamp);
+    let entry = Entry::new(id, record);
+    let entry_signature = EntrySignature::from_parts(namespace_sig, author_sig);
+    SignedEntry::new(entry_signature, entry)
+}
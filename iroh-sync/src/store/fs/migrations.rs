@@ -7,8 +7,9 @@ use tracing::{debug, info};
 use crate::{Capability, NamespaceSecret};
 
 use super::{
-    LATEST_PER_AUTHOR_TABLE, NAMESPACES_TABLE, NAMESPACES_TABLE_V1, RECORDS_BY_KEY_TABLE,
-    RECORDS_TABLE,
+    fingerprints, FINGERPRINTS_TABLE, LATEST_PER_AUTHOR_TABLE, NAMESPACES_TABLE,
+    NAMESPACES_TABLE_V1, RECORDS_BY_INSERTED_AT_TABLE, RECORDS_BY_KEY_TABLE,
+    RECORDS_INSERTED_AT_TABLE, RECORDS_TABLE,
 };
 
 /// Run all database migrations, if needed.
@@ -17,6 +18,8 @@ pub fn run_migrations(db: &Database) -> Result<()> {
     run_migration(db, migration_002_namespaces_populate_v2)?;
     run_migration(db, migration_003_namespaces_delete_v1)?;
     run_migration(db, migration_004_populate_by_key_index)?;
+    run_migration(db, migration_005_populate_fingerprint_index)?;
+    run_migration(db, migration_006_populate_inserted_at_tables)?;
     Ok(())
 }
 
@@ -137,3 +140,41 @@ fn migration_004_populate_by_key_index(tx: &WriteTransaction) -> Result<MigrateO
     }
     Ok(MigrateOutcome::Execute(len))
 }
+
+/// migration 005: populate the fingerprint index table (which did not exist before)
+///
+/// A small store may have no records with a level above zero, in which case the index is
+/// legitimately empty and this runs again, without writing anything, on the next start.
+fn migration_005_populate_fingerprint_index(tx: &WriteTransaction) -> Result<MigrateOutcome> {
+    let mut fingerprints_table = tx.open_table(FINGERPRINTS_TABLE)?;
+    let records_table = tx.open_table(RECORDS_TABLE)?;
+    if !fingerprints_table.is_empty()? || records_table.is_empty()? {
+        return Ok(MigrateOutcome::Skip);
+    }
+    let len = fingerprints::build_index(&records_table, &mut fingerprints_table)?;
+    Ok(MigrateOutcome::Execute(len))
+}
+
+/// migration 006: populate the insertion time tables (which did not exist before)
+///
+/// The insertion time of existing records is not known, so their entry timestamp is used instead.
+fn migration_006_populate_inserted_at_tables(tx: &WriteTransaction) -> Result<MigrateOutcome> {
+    let mut inserted_at_table = tx.open_table(RECORDS_INSERTED_AT_TABLE)?;
+    let mut by_inserted_at_table = tx.open_table(RECORDS_BY_INSERTED_AT_TABLE)?;
+    let records_table = tx.open_table(RECORDS_TABLE)?;
+    if !inserted_at_table.is_empty()? || records_table.is_empty()? {
+        return Ok(MigrateOutcome::Skip);
+    }
+
+    let iter = records_table.iter()?;
+    let mut len = 0;
+    for next in iter {
+        let (id, value) = next?;
+        let (namespace, author, key) = id.value();
+        let (timestamp, _namespace_sig, _author_sig, _len, _hash) = value.value();
+        inserted_at_table.insert((namespace, author, key), timestamp)?;
+        by_inserted_at_table.insert((namespace, timestamp, author, key), ())?;
+        len += 1;
+    }
+    Ok(MigrateOutcome::Execute(len))
+}
@@ -1,21 +1,23 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{ensure, Context, Result};
 use iroh_base::hash::Hash;
-use redb::Database;
+use redb::{Database, ReadableTable};
 
 use crate::{
     store::{
         util::{IndexKind, LatestPerKeySelector, SelectorRes},
-        AuthorFilter, KeyFilter, Query,
+        AuthorFilter, Cursor, KeyFilter, Query, SortDirection, TimestampFilter,
     },
     AuthorId, NamespaceId, SignedEntry,
 };
 
 use super::{
-    bounds::{ByKeyBounds, RecordsBounds},
-    ranges::{RecordsByKeyRange, RecordsRange},
-    RecordsValue,
+    bounds::{ByInsertedAtBounds, ByKeyBounds, RecordsBounds},
+    get_exact,
+    ranges::{RecordsByInsertedAtRange, RecordsByKeyRange, RecordsRange, TableReader},
+    RecordsByInsertedAtId, RecordsByInsertedAtIdOwned, RecordsId, RecordsValue,
+    RECORDS_INSERTED_AT_TABLE, RECORDS_TABLE,
 };
 
 /// A query iterator for entry queries.
@@ -23,6 +25,9 @@ use super::{
 pub struct QueryIterator<'a> {
     range: QueryRange<'a>,
     query: Query,
+    /// Reader for the insertion times of records, to apply the timestamp filter of queries which
+    /// are not served from the by-insertion-time index.
+    inserted_at: Option<TableReader<'a, RecordsId<'static>, u64>>,
     offset: u64,
     count: u64,
 }
@@ -38,11 +43,20 @@ enum QueryRange<'a> {
         author_filter: AuthorFilter,
         selector: Option<LatestPerKeySelector>,
     },
+    Timestamp {
+        range: RecordsByInsertedAtRange<'a>,
+        author_filter: AuthorFilter,
+        key_filter: KeyFilter,
+        /// Position of the cursor in the index, if any.
+        after: Option<RecordsByInsertedAtIdOwned>,
+    },
 }
 
 impl<'a> QueryIterator<'a> {
     pub fn new(db: &'a Arc<Database>, namespace: NamespaceId, query: Query) -> Result<Self> {
         let index_kind = IndexKind::from(&query);
+        let direction = &query.sort_direction;
+        let ns = namespace.to_bytes();
         let range = match index_kind {
             IndexKind::AuthorKey { range, key_filter } => {
                 let (bounds, filter) = match range {
@@ -55,6 +69,14 @@ impl<'a> QueryIterator<'a> {
                     // no author set => full table scan with the provided key filter
                     AuthorFilter::Any => (RecordsBounds::namespace(namespace), key_filter),
                 };
+                // start the range after the cursor, if any.
+                let bounds = match query.cursor() {
+                    Some(cursor) => bounds.after(
+                        (ns, cursor.author.to_bytes(), cursor.key.clone()),
+                        direction,
+                    ),
+                    None => bounds,
+                };
                 let range = RecordsRange::with_bounds(db, bounds)?;
                 QueryRange::AuthorKey {
                     range,
@@ -67,6 +89,19 @@ impl<'a> QueryIterator<'a> {
                 latest_per_key,
             } => {
                 let bounds = ByKeyBounds::new(namespace, &range);
+                // start the range after the cursor, if any. when selecting the latest entry per
+                // key, the cursor points to a key, so skip all authors of the cursor key.
+                let bounds = match query.cursor() {
+                    Some(cursor) => {
+                        let author = match (latest_per_key, direction) {
+                            (false, _) => cursor.author.to_bytes(),
+                            (true, SortDirection::Asc) => [255u8; 32],
+                            (true, SortDirection::Desc) => [0u8; 32],
+                        };
+                        bounds.after((ns, cursor.key.clone(), author), direction)
+                    }
+                    None => bounds,
+                };
                 let range = RecordsByKeyRange::with_bounds(db, bounds)?;
                 let selector = latest_per_key.then(LatestPerKeySelector::default);
                 QueryRange::KeyAuthor {
@@ -75,17 +110,112 @@ impl<'a> QueryIterator<'a> {
                     selector,
                 }
             }
+            IndexKind::Timestamp {
+                author_filter,
+                key_filter,
+            } => {
+                let bounds = ByInsertedAtBounds::new(namespace, query.timestamp_filter());
+                // start the range after the cursor, if any.
+                let after = match query.cursor() {
+                    Some(cursor) => Some(resolve_cursor(db, namespace, cursor)?),
+                    None => None,
+                };
+                let bounds = match &after {
+                    Some(after) => bounds.after(after.clone(), direction),
+                    None => bounds,
+                };
+                let range = RecordsByInsertedAtRange::with_bounds(db, bounds)?;
+                QueryRange::Timestamp {
+                    range,
+                    author_filter,
+                    key_filter,
+                    after,
+                }
+            }
+        };
+
+        let inserted_at = match &range {
+            QueryRange::Timestamp { .. } => None,
+            _ if *query.timestamp_filter() == TimestampFilter::default() => None,
+            _ => Some(TableReader::new(db, |tx| {
+                tx.open_table(RECORDS_INSERTED_AT_TABLE)
+            })?),
         };
 
         Ok(QueryIterator {
             range,
             query,
+            inserted_at,
             offset: 0,
             count: 0,
         })
     }
 }
 
+/// Find the position of the entry of `cursor` in the by-insertion-time index.
+///
+/// Fails if the entry was replaced or removed since the cursor was created.
+fn resolve_cursor(
+    db: &Arc<Database>,
+    namespace: NamespaceId,
+    cursor: &Cursor,
+) -> Result<RecordsByInsertedAtIdOwned> {
+    let read_tx = db.begin_read()?;
+    let records_table = read_tx.open_table(RECORDS_TABLE)?;
+    let entry = get_exact(&records_table, namespace, cursor.author, &cursor.key, true)?;
+    ensure!(
+        entry.is_some_and(|entry| entry.timestamp() == cursor.timestamp),
+        "the entry of the cursor was replaced or removed"
+    );
+    let inserted_at_table = read_tx.open_table(RECORDS_INSERTED_AT_TABLE)?;
+    let inserted_at = inserted_at_table
+        .get((
+            namespace.as_bytes(),
+            cursor.author.as_bytes(),
+            &cursor.key[..],
+        ))?
+        .context("missing insertion time of the cursor entry")?
+        .value();
+    Ok((
+        namespace.to_bytes(),
+        inserted_at,
+        cursor.author.to_bytes(),
+        cursor.key.clone(),
+    ))
+}
+
+/// Whether the index row `id` comes after `position` in `direction`.
+fn is_after_position(
+    id: RecordsByInsertedAtId,
+    position: &RecordsByInsertedAtIdOwned,
+    direction: &SortDirection,
+) -> bool {
+    let (_ns, inserted_at, author, key) = id;
+    let ordering = (inserted_at, author, key).cmp(&(position.1, &position.2, &position.3[..]));
+    match direction {
+        SortDirection::Asc => ordering == Ordering::Greater,
+        SortDirection::Desc => ordering == Ordering::Less,
+    }
+}
+
+/// Whether the local insertion time of `entry` matches `filter`.
+///
+/// Always true if there is no `reader`, which is only opened if the query has a timestamp filter.
+fn matches_inserted_at(
+    reader: &Option<TableReader<'_, RecordsId<'static>, u64>>,
+    filter: &TimestampFilter,
+    entry: &SignedEntry,
+) -> Result<bool> {
+    let Some(reader) = reader else {
+        return Ok(true);
+    };
+    let inserted_at = reader
+        .table()
+        .get(entry.id().as_byte_tuple())?
+        .map(|value| value.value());
+    Ok(inserted_at.is_some_and(|inserted_at| filter.matches(inserted_at)))
+}
+
 impl<'a> Iterator for QueryIterator<'a> {
     type Item = Result<SignedEntry>;
 
@@ -98,13 +228,30 @@ impl<'a> Iterator for QueryIterator<'a> {
         }
         loop {
             let next = match &mut self.range {
-                QueryRange::AuthorKey { range, key_filter } => {
-                    // get the next entry from the query range, filtered by the key and empty filters
-                    range.next_filtered(&self.query.sort_direction, |(_ns, _author, key), value| {
-                        key_filter.matches(key)
-                            && (self.query.include_empty || !value_is_empty(&value))
-                    })
-                }
+                QueryRange::AuthorKey { range, key_filter } => loop {
+                    // get the next entry from the query range, filtered by the key, cursor and
+                    // empty filters
+                    let next = range.next_filtered(
+                        &self.query.sort_direction,
+                        |(_ns, author, key), value| {
+                            key_filter.matches(key)
+                                && self.query.is_after_cursor(&AuthorId::from(author), key)
+                                && (self.query.include_empty || !value_is_empty(&value))
+                        },
+                    );
+
+                    // skip the entry if outside of the timestamp filter
+                    if let Some(Ok(e)) = &next {
+                        let filter = self.query.timestamp_filter();
+                        match matches_inserted_at(&self.inserted_at, filter, e) {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(err) => break Some(Err(err)),
+                        }
+                    }
+
+                    break next;
+                },
 
                 QueryRange::KeyAuthor {
                     range,
@@ -139,8 +286,47 @@ impl<'a> Iterator for QueryIterator<'a> {
                         continue;
                     }
 
+                    // skip the entry if outside of the timestamp filter or not after the cursor
+                    if let Some(e) = &next {
+                        if !self.query.is_after_cursor(&e.author(), e.key()) {
+                            continue;
+                        }
+                        let filter = self.query.timestamp_filter();
+                        match matches_inserted_at(&self.inserted_at, filter, e) {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(err) => break Some(Err(err)),
+                        }
+                    }
+
                     break next.map(Result::Ok);
                 },
+
+                QueryRange::Timestamp {
+                    range,
+                    author_filter,
+                    key_filter,
+                    after,
+                } => loop {
+                    // get the next entry from the query range, filtered by the author, key and
+                    // cursor filters
+                    let direction = &self.query.sort_direction;
+                    let next = range.next_filtered(direction, |id| {
+                        let (_ns, _inserted_at, author, key) = id;
+                        author_filter.matches(&AuthorId::from(author))
+                            && key_filter.matches(key)
+                            && after
+                                .as_ref()
+                                .map_or(true, |after| is_after_position(id, after, direction))
+                    });
+
+                    // skip the entry if empty and no empty entries requested
+                    if !self.query.include_empty && matches!(&next, Some(Ok(e)) if e.is_empty()) {
+                        continue;
+                    }
+
+                    break next;
+                },
             };
 
             // skip the entry if we didn't get past the requested offset yet.
@@ -16,8 +16,9 @@ use redb::{
 use crate::{store::SortDirection, SignedEntry};
 
 use super::{
-    bounds::{ByKeyBounds, RecordsBounds},
-    into_entry, RecordsByKeyId, RecordsId, RecordsValue, RECORDS_BY_KEY_TABLE, RECORDS_TABLE,
+    bounds::{ByInsertedAtBounds, ByKeyBounds, RecordsBounds},
+    into_entry, RecordsByInsertedAtId, RecordsByKeyId, RecordsId, RecordsValue,
+    RECORDS_BY_INSERTED_AT_TABLE, RECORDS_BY_KEY_TABLE, RECORDS_TABLE,
 };
 
 /// A [`ReadTransaction`] with a [`ReadOnlyTable`] that can be stored in a struct.
@@ -273,6 +274,85 @@ impl<'a> RecordsByKeyRange<'a> {
     }
 }
 
+#[derive(derive_more::Debug)]
+#[debug("RecordsByInsertedAtRange")]
+pub struct RecordsByInsertedAtRange<'a>(RecordsByInsertedAtRangeInner<'a>);
+
+#[self_referencing]
+struct RecordsByInsertedAtRangeInner<'a> {
+    read_tx: ReadTransaction<'a>,
+
+    #[covariant]
+    #[borrows(read_tx)]
+    records_table: ReadOnlyTable<'this, RecordsId<'static>, RecordsValue<'static>>,
+
+    #[covariant]
+    #[borrows(read_tx)]
+    by_inserted_at_table: ReadOnlyTable<'this, RecordsByInsertedAtId<'static>, ()>,
+
+    #[borrows(by_inserted_at_table)]
+    #[covariant]
+    by_inserted_at_range: Range<'this, RecordsByInsertedAtId<'static>, ()>,
+}
+
+impl<'a> RecordsByInsertedAtRange<'a> {
+    pub fn new<RF>(db: &'a Arc<Database>, range_fn: RF) -> anyhow::Result<Self>
+    where
+        RF: for<'s> FnOnce(
+            &'s ReadOnlyTable<'s, RecordsByInsertedAtId<'static>, ()>,
+        )
+            -> Result<Range<'s, RecordsByInsertedAtId<'static>, ()>, StorageError>,
+    {
+        let inner = RecordsByInsertedAtRangeInner::try_new(
+            db.begin_read()?,
+            |tx| tx.open_table(RECORDS_TABLE).map_err(anyhow_err),
+            |tx| {
+                tx.open_table(RECORDS_BY_INSERTED_AT_TABLE)
+                    .map_err(anyhow_err)
+            },
+            |table| range_fn(table).map_err(Into::into),
+        )?;
+        Ok(Self(inner))
+    }
+
+    pub fn with_bounds(db: &'a Arc<Database>, bounds: ByInsertedAtBounds) -> anyhow::Result<Self> {
+        Self::new(db, |table| table.range(bounds.as_ref()))
+    }
+
+    /// Get the next item in the range.
+    ///
+    /// Omit items for which the `matcher` function returns false. Index rows without a matching
+    /// record are skipped.
+    pub fn next_filtered(
+        &mut self,
+        direction: &SortDirection,
+        filter: impl for<'x> Fn(RecordsByInsertedAtId<'x>) -> bool,
+    ) -> Option<anyhow::Result<SignedEntry>> {
+        self.0.with_mut(|fields| loop {
+            let next = match direction {
+                SortDirection::Asc => fields.by_inserted_at_range.next(),
+                SortDirection::Desc => fields.by_inserted_at_range.next_back(),
+            };
+            let by_inserted_at_id = match next {
+                Some(Ok(res)) => match filter(res.0.value()) {
+                    false => continue,
+                    true => res.0,
+                },
+                Some(Err(err)) => return Some(Err(err.into())),
+                None => return None,
+            };
+
+            let (namespace, _inserted_at, author, key) = by_inserted_at_id.value();
+            let records_id = (namespace, author, key);
+            match fields.records_table.get(&records_id) {
+                Ok(Some(entry)) => return Some(Ok(into_entry(records_id, entry.value()))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err.into())),
+            }
+        })
+    }
+}
+
 fn anyhow_err(err: impl Into<anyhow::Error>) -> anyhow::Error {
     err.into()
 }
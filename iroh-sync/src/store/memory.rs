@@ -15,14 +15,16 @@ use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard};
 use crate::{
     keys::Author,
     ranger::{Fingerprint, Range, RangeEntry},
-    sync::{RecordIdentifier, Replica, SignedEntry},
-    AuthorId, Capability, CapabilityKind, NamespaceId, PeerIdBytes, Record,
+    sync::{system_time_now, RecordIdentifier, Replica, SignedEntry},
+    AuthorId, Capability, CapabilityKind, Compaction, Delegation, DocumentKey, NamespaceId,
+    PeerIdBytes, Record, Revocation,
 };
 
 use super::{
     pubkeys::MemPublicKeyStore,
-    util::{IndexKind, LatestPerKeySelector, SelectorRes},
-    DownloadPolicy, ImportNamespaceOutcome, OpenError, PublicKeyStore, Query, SortDirection,
+    util::{IndexKind, InsertionClock, LatestPerKeySelector, SelectorRes},
+    DownloadPolicy, HistoryPolicy, ImportNamespaceOutcome, OpenError, PublicKeyStore, Query,
+    SortDirection, TimestampFilter,
 };
 
 type SyncPeersCache = Arc<RwLock<HashMap<NamespaceId, lru::LruCache<PeerIdBytes, ()>>>>;
@@ -34,6 +36,15 @@ pub struct Store {
     namespaces: Arc<RwLock<HashMap<NamespaceId, Capability>>>,
     authors: Arc<RwLock<HashMap<AuthorId, Author>>>,
     download_policies: Arc<RwLock<HashMap<NamespaceId, DownloadPolicy>>>,
+    delegations: Arc<RwLock<HashMap<NamespaceId, Vec<Delegation>>>>,
+    revocations: Arc<RwLock<HashMap<NamespaceId, Vec<Revocation>>>>,
+    document_keys: Arc<RwLock<HashMap<NamespaceId, DocumentKey>>>,
+    history_policies: Arc<RwLock<HashMap<NamespaceId, HistoryPolicy>>>,
+    /// Stores previous versions of records by namespace -> key + author + timestamp
+    history: Arc<RwLock<HashMap<NamespaceId, HistoryMap>>>,
+    compactions: Arc<RwLock<HashMap<NamespaceId, Compaction>>>,
+    /// Time of the last finished sync session per document
+    last_syncs: Arc<RwLock<HashMap<NamespaceId, u64>>>,
     /// Stores records by namespace -> identifier + timestamp
     replica_records: Arc<RwLock<ReplicaRecordsOwned>>,
     /// Stores the latest entry for each author
@@ -41,6 +52,7 @@ pub struct Store {
     pubkeys: MemPublicKeyStore,
     /// Cache of peers that have been used for sync.
     peers_per_doc: SyncPeersCache,
+    insertion_clock: Arc<InsertionClock>,
 }
 
 type Key = Bytes;
@@ -50,34 +62,67 @@ type ReplicaRecordsOwned = BTreeMap<NamespaceId, RecordMap>;
 struct RecordMap {
     by_author: BTreeMap<(AuthorId, Key), SignedEntry>,
     by_key: BTreeMap<(Key, AuthorId), ()>,
+    /// Local insertion time of each record
+    inserted_at: BTreeMap<(AuthorId, Key), u64>,
+    by_inserted_at: BTreeMap<(u64, AuthorId, Key), ()>,
 }
 
 impl RecordMap {
-    fn insert(&mut self, entry: SignedEntry) {
-        self.by_key
-            .insert((entry.id().key_bytes(), entry.author()), ());
-        self.by_author
-            .insert((entry.author(), entry.id().key_bytes()), entry);
+    fn insert(&mut self, entry: SignedEntry, inserted_at: u64) -> Option<SignedEntry> {
+        let id = (entry.author(), entry.id().key_bytes());
+        self.by_key.insert((id.1.clone(), id.0), ());
+        let old = self.by_author.insert(id.clone(), entry.clone());
+        // keep the insertion time if the same entry was inserted again
+        if old.as_ref() != Some(&entry) {
+            if let Some(old_inserted_at) = self.inserted_at.insert(id.clone(), inserted_at) {
+                self.by_inserted_at
+                    .remove(&(old_inserted_at, id.0, id.1.clone()));
+            }
+            self.by_inserted_at.insert((inserted_at, id.0, id.1), ());
+        }
+        old
     }
     fn remove(&mut self, id: &RecordIdentifier) -> Option<SignedEntry> {
         let entry = self.by_author.remove(&(id.author(), id.key_bytes()));
         self.by_key.remove(&(id.key_bytes(), id.author()));
+        self.remove_inserted_at(&(id.author(), id.key_bytes()));
         entry
     }
-    fn len(&self) -> usize {
-        self.by_author.len()
+    fn matches_inserted_at(&self, filter: &TimestampFilter, entry: &SignedEntry) -> bool {
+        let id = (entry.author(), entry.id().key_bytes());
+        self.inserted_at
+            .get(&id)
+            .is_some_and(|inserted_at| filter.matches(*inserted_at))
+    }
+    fn remove_inserted_at(&mut self, id: &(AuthorId, Key)) {
+        if let Some(inserted_at) = self.inserted_at.remove(id) {
+            self.by_inserted_at
+                .remove(&(inserted_at, id.0, id.1.clone()));
+        }
     }
-    fn retain(&mut self, f: impl Fn(&(AuthorId, Key), &mut SignedEntry) -> bool) {
+    fn retain(
+        &mut self,
+        f: impl Fn(&(AuthorId, Key), &mut SignedEntry) -> bool,
+    ) -> Vec<SignedEntry> {
+        let mut removed = Vec::new();
         self.by_author.retain(|key, value| {
             let retain = f(key, value);
             if !retain {
                 self.by_key.remove(&(key.1.clone(), key.0));
+                removed.push(value.clone());
             }
             retain
-        })
+        });
+        for entry in &removed {
+            self.remove_inserted_at(&(entry.author(), entry.id().key_bytes()));
+        }
+        removed
     }
 }
 
+/// Previous versions of records with the time they were replaced at.
+type HistoryMap = BTreeMap<(Key, AuthorId, u64), (u64, SignedEntry)>;
+
 type LatestByAuthorMapOwned = BTreeMap<AuthorId, (u64, Vec<u8>)>;
 type LatestMapOwned = HashMap<NamespaceId, LatestByAuthorMapOwned>;
 type LatestByAuthorMap<'a> = MappedRwLockReadGuard<'a, LatestByAuthorMapOwned>;
@@ -85,7 +130,8 @@ type LatestByAuthorMap<'a> = MappedRwLockReadGuard<'a, LatestByAuthorMapOwned>;
 impl super::Store for Store {
     type Instance = ReplicaStoreInstance;
     type GetIter<'a> = QueryIterator<'a>;
-    type ContentHashesIter<'a> = ContentHashesIterator<'a>;
+    type ContentHashesIter<'a> =
+        std::iter::Chain<ContentHashesIterator<'a>, std::vec::IntoIter<Result<Hash>>>;
     type AuthorsIter<'a> = std::vec::IntoIter<Result<Author>>;
     type NamespaceIter<'a> = std::vec::IntoIter<Result<(NamespaceId, CapabilityKind)>>;
     type PeersIter<'a> = std::vec::IntoIter<PeerIdBytes>;
@@ -163,6 +209,13 @@ impl super::Store for Store {
             return Err(anyhow!("replica is not closed"));
         }
         self.replica_records.write().remove(namespace);
+        self.delegations.write().remove(namespace);
+        self.revocations.write().remove(namespace);
+        self.document_keys.write().remove(namespace);
+        self.history.write().remove(namespace);
+        self.history_policies.write().remove(namespace);
+        self.compactions.write().remove(namespace);
+        self.last_syncs.write().remove(namespace);
         self.namespaces.write().remove(namespace);
         Ok(())
     }
@@ -174,7 +227,7 @@ impl super::Store for Store {
     ) -> Result<Self::GetIter<'_>> {
         let query = query.into();
         let records = self.replica_records.read();
-        Ok(QueryIterator::new(records, namespace, query))
+        QueryIterator::new(records, namespace, query)
     }
 
     fn get_exact(
@@ -200,12 +253,20 @@ impl super::Store for Store {
 
     /// Get all content hashes of all replicas in the store.
     fn content_hashes(&self) -> Result<Self::ContentHashesIter<'_>> {
+        let history = self
+            .history
+            .read()
+            .values()
+            .flat_map(|versions| versions.values())
+            .map(|(_replaced_at, entry)| Ok(entry.content_hash()))
+            .collect::<Vec<_>>();
         let records = self.replica_records.read();
-        Ok(ContentHashesIterator {
+        let records = ContentHashesIterator {
             records,
             namespace_i: 0,
             record_i: 0,
-        })
+        };
+        Ok(records.chain(history))
     }
 
     fn get_latest_for_each_author(&self, namespace: NamespaceId) -> Result<LatestIterator<'_>> {
@@ -237,6 +298,17 @@ impl super::Store for Store {
         Ok(Some(peers.into_iter()))
     }
 
+    fn register_sync_finished(&self, namespace: &NamespaceId) -> Result<()> {
+        self.last_syncs
+            .write()
+            .insert(*namespace, system_time_now());
+        Ok(())
+    }
+
+    fn last_sync(&self, namespace: &NamespaceId) -> Result<Option<u64>> {
+        Ok(self.last_syncs.read().get(namespace).copied())
+    }
+
     fn set_download_policy(&self, namespace: &NamespaceId, policy: DownloadPolicy) -> Result<()> {
         self.download_policies.write().insert(*namespace, policy);
         Ok(())
@@ -250,6 +322,172 @@ impl super::Store for Store {
             .cloned()
             .unwrap_or_default())
     }
+
+    fn import_delegation(&self, delegation: Delegation) -> Result<()> {
+        delegation.verify()?;
+        let revocations = self.revocations.read();
+        if let Some(revocations) = revocations.get(&delegation.namespace()) {
+            if revocations.iter().any(|r| r.revokes(&delegation)) {
+                return Ok(());
+            }
+        }
+        let mut delegations = self.delegations.write();
+        let delegations = delegations.entry(delegation.namespace()).or_default();
+        if !delegations.contains(&delegation) {
+            delegations.push(delegation);
+        }
+        Ok(())
+    }
+
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>> {
+        Ok(self
+            .delegations
+            .read()
+            .get(namespace)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn import_revocation(&self, revocation: Revocation) -> Result<()> {
+        revocation.verify()?;
+        let mut revocations = self.revocations.write();
+        if let Some(delegations) = self.delegations.write().get_mut(&revocation.namespace()) {
+            delegations.retain(|delegation| !revocation.revokes(delegation));
+        }
+        let revocations = revocations.entry(revocation.namespace()).or_default();
+        if !revocations.contains(&revocation) {
+            revocations.push(revocation);
+        }
+        Ok(())
+    }
+
+    fn get_revocations(&self, namespace: &NamespaceId) -> Result<Vec<Revocation>> {
+        Ok(self
+            .revocations
+            .read()
+            .get(namespace)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn set_document_key(&self, namespace: &NamespaceId, key: DocumentKey) -> Result<()> {
+        self.document_keys.write().insert(*namespace, key);
+        Ok(())
+    }
+
+    fn get_document_key(&self, namespace: &NamespaceId) -> Result<Option<DocumentKey>> {
+        Ok(self.document_keys.read().get(namespace).cloned())
+    }
+
+    fn set_history_policy(&self, namespace: &NamespaceId, policy: HistoryPolicy) -> Result<()> {
+        self.history_policies.write().insert(*namespace, policy);
+        Ok(())
+    }
+
+    fn get_history_policy(&self, namespace: &NamespaceId) -> Result<HistoryPolicy> {
+        Ok(self
+            .history_policies
+            .read()
+            .get(namespace)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    fn get_history(
+        &self,
+        namespace: NamespaceId,
+        key: impl AsRef<[u8]>,
+    ) -> Result<Vec<SignedEntry>> {
+        let history = self.history.read();
+        let Some(versions) = history.get(&namespace) else {
+            return Ok(vec![]);
+        };
+        let key = Bytes::copy_from_slice(key.as_ref());
+        let start = (key.clone(), AuthorId::from(&[0u8; 32]), 0);
+        let end = (key, AuthorId::from(&[255u8; 32]), u64::MAX);
+        let mut entries = versions
+            .range(start..=end)
+            .map(|(_id, (_replaced_at, entry))| entry.clone())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp()));
+        Ok(entries)
+    }
+
+    fn prune_history(&self) -> Result<usize> {
+        let now = system_time_now();
+        let policies = self.history_policies.read();
+        let mut history = self.history.write();
+        let mut removed = 0;
+        for (namespace, versions) in history.iter_mut() {
+            let policy = policies.get(namespace).copied().unwrap_or_default();
+            let mut expired = Vec::new();
+            let mut entries = versions.iter().peekable();
+            let mut group = Vec::new();
+            while let Some((id, (replaced_at, _entry))) = entries.next() {
+                group.push((id.clone(), *replaced_at));
+                // the versions of a single author and key are adjacent
+                let group_ends = entries
+                    .peek()
+                    .map_or(true, |(next, _)| (&next.0, next.1) != (&id.0, id.1));
+                if group_ends {
+                    let replaced_at = group.iter().map(|(_, at)| *at).collect::<Vec<_>>();
+                    for i in policy.expired(&replaced_at, now) {
+                        expired.push(group[i].0.clone());
+                    }
+                    group.clear();
+                }
+            }
+            removed += expired.len();
+            for id in expired {
+                versions.remove(&id);
+            }
+        }
+        history.retain(|_namespace, versions| !versions.is_empty());
+        Ok(removed)
+    }
+
+    fn apply_compaction(&self, compaction: Compaction) -> Result<Option<usize>> {
+        compaction.verify()?;
+        let namespace = compaction.namespace();
+        {
+            let compactions = self.compactions.read();
+            if let Some(current) = compactions.get(&namespace) {
+                if current.horizon() >= compaction.horizon() {
+                    return Ok(None);
+                }
+            }
+        }
+        let removed = match self.replica_records.write().get_mut(&namespace) {
+            None => 0,
+            Some(records) => records
+                .retain(|_key, entry| !compaction.removes(entry))
+                .len(),
+        };
+        self.compactions.write().insert(namespace, compaction);
+        Ok(Some(removed))
+    }
+
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>> {
+        Ok(self.compactions.read().get(namespace).cloned())
+    }
+
+    fn discard_before_horizon(&self, namespace: &NamespaceId) -> Result<usize> {
+        let Some(horizon) = self
+            .compactions
+            .read()
+            .get(namespace)
+            .map(Compaction::horizon)
+        else {
+            return Ok(0);
+        };
+        let removed = match self.replica_records.write().get_mut(namespace) {
+            None => 0,
+            Some(records) => records
+                .retain(|_key, entry| entry.timestamp() >= horizon)
+                .len(),
+        };
+        Ok(removed)
+    }
 }
 
 /// Iterator over all content hashes in the memory store.
@@ -308,6 +546,8 @@ pub struct QueryIterator<'a> {
     query: Query,
     index: IndexKind,
     selector: Option<LatestPerKeySelector>,
+    // position of the cursor in the by insertion time index, if sorted by timestamp
+    after: Option<(u64, AuthorId, Key)>,
     // current iterator index
     position: usize,
     // number of entries returned from the iterator
@@ -317,7 +557,7 @@ pub struct QueryIterator<'a> {
 }
 
 impl<'a> QueryIterator<'a> {
-    fn new(records: ReplicaRecords<'a>, namespace: NamespaceId, query: Query) -> Self {
+    fn new(records: ReplicaRecords<'a>, namespace: NamespaceId, query: Query) -> Result<Self> {
         let index = IndexKind::from(&query);
         let selector = match index {
             IndexKind::KeyAuthor { latest_per_key, .. } if latest_per_key => {
@@ -325,17 +565,35 @@ impl<'a> QueryIterator<'a> {
             }
             _ => None,
         };
+        let after = match (&index, query.cursor()) {
+            (IndexKind::Timestamp { .. }, Some(cursor)) => {
+                let id = (cursor.author, cursor.key.clone());
+                let inserted_at = records
+                    .get(&namespace)
+                    .filter(|records| {
+                        records
+                            .by_author
+                            .get(&id)
+                            .is_some_and(|entry| entry.timestamp() == cursor.timestamp)
+                    })
+                    .and_then(|records| records.inserted_at.get(&id))
+                    .ok_or_else(|| anyhow!("the entry of the cursor was replaced or removed"))?;
+                Some((*inserted_at, id.0, id.1))
+            }
+            _ => None,
+        };
 
-        Self {
+        Ok(Self {
             records,
             namespace,
             query,
             index,
             selector,
+            after,
             position: 0,
             offset: 0,
             count: 0,
-        }
+        })
     }
 }
 
@@ -360,6 +618,8 @@ impl<'a> Iterator for QueryIterator<'a> {
                         .filter(|(_key, entry)| {
                             range.matches(&entry.author())
                                 && key_filter.matches(entry.key())
+                                && records.matches_inserted_at(self.query.timestamp_filter(), entry)
+                                && self.query.is_after_cursor(&entry.author(), entry.key())
                                 && (self.query.include_empty || !entry.is_empty())
                         })
                         .map(|(_key, entry)| entry);
@@ -405,14 +665,46 @@ impl<'a> Iterator for QueryIterator<'a> {
 
                     // final check for empty entries: if the selector is active, the latest
                     // entry for a key might be empty, so skip it if no empty entries were
-                    // requested
-                    if !self.query.include_empty && entry.is_empty() {
+                    // requested. the timestamp filter and cursor apply to the selected entry
+                    // as well.
+                    if (!self.query.include_empty && entry.is_empty())
+                        || !records.matches_inserted_at(self.query.timestamp_filter(), &entry)
+                        || !self.query.is_after_cursor(&entry.author(), entry.key())
+                    {
                         self.position += 1;
                         continue;
                     } else {
                         break Some(entry);
                     }
                 },
+                IndexKind::Timestamp {
+                    author_filter,
+                    key_filter,
+                } => {
+                    let direction = self.query.sort_direction;
+                    let mut iter = records
+                        .by_inserted_at
+                        .keys()
+                        .filter(|id| {
+                            let (inserted_at, author, key) = id;
+                            self.query.timestamp_filter().matches(*inserted_at)
+                                && author_filter.matches(author)
+                                && key_filter.matches(key)
+                                && self.after.as_ref().map_or(true, |after| match direction {
+                                    SortDirection::Asc => *id > after,
+                                    SortDirection::Desc => *id < after,
+                                })
+                        })
+                        .filter_map(|(_inserted_at, author, key)| {
+                            records.by_author.get(&(*author, key.clone()))
+                        })
+                        .filter(|entry| self.query.include_empty || !entry.is_empty());
+                    let next = match direction {
+                        SortDirection::Asc => iter.nth(self.position),
+                        SortDirection::Desc => iter.nth_back(self.position),
+                    };
+                    next.cloned()
+                }
             };
 
             self.position += 1;
@@ -439,6 +731,18 @@ impl super::DownloadPolicyStore for ReplicaStoreInstance {
     }
 }
 
+impl super::DelegationStore for ReplicaStoreInstance {
+    fn get_delegations(&self, namespace: &NamespaceId) -> Result<Vec<Delegation>> {
+        super::Store::get_delegations(&self.store, namespace)
+    }
+}
+
+impl super::CompactionStore for ReplicaStoreInstance {
+    fn get_compaction(&self, namespace: &NamespaceId) -> Result<Option<Compaction>> {
+        super::Store::get_compaction(&self.store, namespace)
+    }
+}
+
 impl PublicKeyStore for ReplicaStoreInstance {
     fn public_key(&self, id: &[u8; 32]) -> std::result::Result<VerifyingKey, SignatureError> {
         self.store.pubkeys.public_key(id)
@@ -477,6 +781,39 @@ impl ReplicaStoreInstance {
         f(value)
     }
 
+    /// Moves entries which were replaced by newer entries to the history, if the document
+    /// retains it.
+    // `Bytes` keys are never mutated
+    #[allow(clippy::mutable_key_type)]
+    fn retain_history(&self, replaced: impl IntoIterator<Item = SignedEntry>) {
+        let policy = self
+            .store
+            .history_policies
+            .read()
+            .get(&self.namespace)
+            .copied()
+            .unwrap_or_default();
+        if !policy.is_enabled() {
+            return;
+        }
+        let now = system_time_now();
+        let mut history = self.store.history.write();
+        let versions = history.entry(self.namespace).or_default();
+        for entry in replaced {
+            let (key, author) = (entry.id().key_bytes(), entry.author());
+            versions.insert((key.clone(), author, entry.timestamp()), (now, entry));
+            let range = (key.clone(), author, 0)..=(key, author, u64::MAX);
+            let keys = versions
+                .range(range)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            let replaced_at = keys.iter().map(|id| versions[id].0).collect::<Vec<_>>();
+            for i in policy.expired(&replaced_at, now) {
+                versions.remove(&keys[i]);
+            }
+        }
+    }
+
     fn with_latest_mut_with_default<F, T>(&self, f: F) -> T
     where
         F: FnOnce(&mut LatestByAuthorMapOwned) -> T,
@@ -565,9 +902,10 @@ impl crate::ranger::Store<SignedEntry> for ReplicaStoreInstance {
         self.with_latest_mut_with_default(|records| {
             records.insert(e.author_bytes(), (e.timestamp(), e.key().to_vec()));
         });
-        self.with_records_mut_with_default(|records| {
-            records.insert(e);
-        });
+        let inserted_at = self.store.insertion_clock.next();
+        let old =
+            self.with_records_mut_with_default(|records| records.insert(e.clone(), inserted_at));
+        self.retain_history(old.filter(|old| *old != e));
         Ok(())
     }
 
@@ -628,16 +966,17 @@ impl crate::ranger::Store<SignedEntry> for ReplicaStoreInstance {
         prefix: &RecordIdentifier,
         predicate: impl Fn(&Record) -> bool,
     ) -> Result<usize, Self::Error> {
-        self.with_records_mut(|records| {
+        let removed = self.with_records_mut(|records| {
             let Some(records) = records else {
-                return Ok(0);
+                return vec![];
             };
-            let old_len = records.by_author.len();
             records.retain(|(a, k), v| {
                 !(a == &prefix.author() && k.starts_with(prefix.key()) && predicate(v.entry()))
-            });
-            Ok(old_len - records.len())
-        })
+            })
+        });
+        let count = removed.len();
+        self.retain_history(removed);
+        Ok(count)
     }
 }
 
@@ -1,6 +1,8 @@
 //! Utilities useful across different store impls.
 
-use crate::SignedEntry;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{sync::system_time_now, SignedEntry};
 
 use super::{AuthorFilter, KeyFilter, Query, QueryKind, SortBy};
 
@@ -16,12 +18,20 @@ pub enum IndexKind {
         author_filter: AuthorFilter,
         latest_per_key: bool,
     },
+    Timestamp {
+        author_filter: AuthorFilter,
+        key_filter: KeyFilter,
+    },
 }
 
 impl From<&Query> for IndexKind {
     fn from(query: &Query) -> Self {
         match &query.kind {
             QueryKind::Flat(details) => match (&query.filter_author, details.sort_by) {
+                (_, SortBy::Timestamp) => IndexKind::Timestamp {
+                    author_filter: query.filter_author.clone(),
+                    key_filter: query.filter_key.clone(),
+                },
                 (AuthorFilter::Any, SortBy::KeyAuthor) => IndexKind::KeyAuthor {
                     range: query.filter_key.clone(),
                     author_filter: AuthorFilter::Any,
@@ -87,3 +97,24 @@ impl LatestPerKeySelector {
         }
     }
 }
+
+/// Source of the local insertion times of records.
+///
+/// Returns the current time in microseconds since the unix epoch, but never the same or an
+/// earlier value than before, so that the insertion order is kept if the system clock goes back.
+#[derive(Debug, Default)]
+pub struct InsertionClock(AtomicU64);
+
+impl InsertionClock {
+    /// Get the insertion time for the next write.
+    pub fn next(&self) -> u64 {
+        let now = system_time_now();
+        let last = self
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .expect("closure always returns Some");
+        now.max(last + 1)
+    }
+}
@@ -26,6 +26,8 @@ pub use crate::heads::AuthorHeads;
 #[cfg(feature = "metrics")]
 use crate::metrics::Metrics;
 use crate::{
+    clock::{ClockSkew, HybridClock},
+    delegation::Delegation,
     keys::{Author, AuthorId, AuthorPublicKey, NamespaceId, NamespacePublicKey, NamespaceSecret},
     ranger::{self, Fingerprint, InsertOutcome, Peer, RangeEntry, RangeKey, RangeValue},
     store::{self, PublicKeyStore},
@@ -42,11 +44,16 @@ pub type PeerIdBytes = [u8; 32];
 
 /// Max time in the future from our wall clock time that we accept entries for.
 /// Value is 10 minutes.
-pub const MAX_TIMESTAMP_FUTURE_SHIFT: u64 = 10 * 60 * Duration::from_secs(1).as_millis() as u64;
+///
+/// Entry timestamps are in microseconds since the unix epoch, so this is as well.
+pub const MAX_TIMESTAMP_FUTURE_SHIFT: u64 = 10 * 60 * Duration::from_secs(1).as_micros() as u64;
 
 /// Callback that may be set on a replica to determine the availability status for a content hash.
 pub type ContentStatusCallback = Arc<dyn Fn(Hash) -> ContentStatus + Send + Sync + 'static>;
 
+/// Filter for the events sent to a subscriber, see [`Replica::subscribe_filtered`].
+pub type EventFilter = Arc<dyn Fn(&Event) -> bool + Send + Sync + 'static>;
+
 /// Event emitted by sync when entries are added.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -84,6 +91,11 @@ pub enum InsertOrigin {
         /// Whether the peer claims to have the content blob for this entry.
         remote_content_status: ContentStatus,
     },
+    /// The entry was imported from a document archive.
+    ///
+    /// Imported entries do not emit insert events, so that they are not announced as local
+    /// writes.
+    Import,
 }
 
 /// Whether the content status is available on a node.
@@ -108,17 +120,23 @@ pub struct SyncOutcome {
     pub num_sent: usize,
 }
 
-#[derive(Debug, Default)]
-struct Subscribers(Vec<flume::Sender<Event>>);
+#[derive(derive_more::Debug, Default)]
+#[debug("Subscribers({})", _0.len())]
+struct Subscribers(Vec<(flume::Sender<Event>, Option<EventFilter>)>);
 impl Subscribers {
-    pub fn subscribe(&mut self, sender: flume::Sender<Event>) {
-        self.0.push(sender)
+    pub fn subscribe(&mut self, sender: flume::Sender<Event>, filter: Option<EventFilter>) {
+        self.0.push((sender, filter))
     }
     pub fn unsubscribe(&mut self, sender: &flume::Sender<Event>) {
-        self.0.retain(|s| !s.same_channel(sender));
+        self.0.retain(|(s, _filter)| !s.same_channel(sender));
     }
     pub fn send(&mut self, event: Event) {
-        self.0.retain(|sender| sender.send(event.clone()).is_ok())
+        self.0.retain(|(sender, filter)| {
+            if filter.as_ref().is_some_and(|filter| !filter(&event)) {
+                return true;
+            }
+            sender.send(event.clone()).is_ok()
+        })
     }
     pub fn len(&self) -> usize {
         self.0.len()
@@ -244,17 +262,30 @@ pub enum CapabilityError {
 
 /// Local representation of a mutable, synchronizable key-value store.
 #[derive(derive_more::Debug)]
-pub struct Replica<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore> {
+pub struct Replica<
+    S: ranger::Store<SignedEntry>
+        + PublicKeyStore
+        + store::DownloadPolicyStore
+        + store::DelegationStore
+        + store::CompactionStore,
+> {
     capability: Capability,
     peer: Peer<SignedEntry, S>,
     subscribers: Subscribers,
     #[debug("ContentStatusCallback")]
     content_status_cb: Option<ContentStatusCallback>,
+    clock: HybridClock,
     closed: bool,
 }
 
-impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore + 'static>
-    Replica<S>
+impl<
+        S: ranger::Store<SignedEntry>
+            + PublicKeyStore
+            + store::DownloadPolicyStore
+            + store::DelegationStore
+            + store::CompactionStore
+            + 'static,
+    > Replica<S>
 {
     /// Create a new replica.
     pub fn new(capability: Capability, store: S) -> Self {
@@ -264,6 +295,7 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
             subscribers: Default::default(),
             // on_insert_sender: RwLock::new(None),
             content_status_cb: None,
+            clock: HybridClock::default(),
             closed: false,
         }
     }
@@ -282,7 +314,15 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
     /// received from in a loop. If not receiving, local and remote inserts will hang waiting for
     /// the receiver to be received from.
     pub fn subscribe(&mut self, sender: flume::Sender<Event>) {
-        self.subscribers.subscribe(sender)
+        self.subscribers.subscribe(sender, None)
+    }
+
+    /// Subcribe to the insert events for which `filter` returns true.
+    ///
+    /// The filter is applied before sending, so events which do not match neither wait for nor
+    /// take up space in a bounded channel. See [`Self::subscribe`].
+    pub fn subscribe_filtered(&mut self, sender: flume::Sender<Event>, filter: EventFilter) {
+        self.subscribers.subscribe(sender, Some(filter))
     }
 
     /// Explicitly unsubscribe a sender.
@@ -341,7 +381,8 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
 
     /// Insert a new record at the given key.
     ///
-    /// The entry will by signed by the provided `author`.
+    /// The entry will by signed by the provided `author`. If the replica is read only, the entry
+    /// is signed with a [`Delegation`] for the author which allows to write the key instead.
     /// The `len` must be the byte length of the data identified by `hash`.
     ///
     /// Returns the number of entries removed as a consequence of this insertion,
@@ -358,13 +399,50 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
         }
         self.ensure_open()?;
         let id = RecordIdentifier::new(self.id(), author.id(), key);
-        let record = Record::new_current(hash, len);
+        let record = Record::new(hash, len, self.clock.now());
         let entry = Entry::new(id, record);
-        let secret = self.secret_key()?;
-        let signed_entry = entry.sign(secret, author);
+        let signed_entry = self.sign_entry(entry, author)?;
         self.insert_entry(signed_entry, InsertOrigin::Local)
     }
 
+    /// Insert many new records in a single transaction of the store.
+    ///
+    /// All entries are signed by the provided `author`, `entries` yields the key, hash and byte
+    /// length of each record. The insert events are emitted once all entries are stored.
+    ///
+    /// Entries for which a newer entry exists for their key or a prefix of it are skipped.
+    /// Returns the entries which were inserted, or an error if any of the entries is empty or a
+    /// store operation failed, in which case none of the entries are inserted.
+    pub fn insert_many<K: AsRef<[u8]>>(
+        &mut self,
+        author: &Author,
+        entries: impl IntoIterator<Item = (K, Hash, u64)>,
+    ) -> Result<Vec<SignedEntry>, InsertError<S>> {
+        self.ensure_open()?;
+        let namespace = self.id();
+        let timestamp = self.clock.now();
+        let entries = entries
+            .into_iter()
+            .map(|(key, hash, len)| {
+                if len == 0 || hash == Hash::EMPTY {
+                    return Err(InsertError::EntryIsEmpty);
+                }
+                let id = RecordIdentifier::new(namespace, author.id(), key);
+                let entry = Entry::new(id, Record::new(hash, len, timestamp));
+                self.sign_entry(entry, author)
+            })
+            .collect::<Result<Vec<_>, InsertError<S>>>()?;
+        let inserted = self
+            .insert_entries(entries, InsertOrigin::Local)?
+            .into_iter()
+            .filter_map(|(entry, outcome)| match outcome {
+                InsertOutcome::Inserted { .. } => Some(entry),
+                InsertOutcome::NotInserted => None,
+            })
+            .collect();
+        Ok(inserted)
+    }
+
     /// Delete entries that match the given `author` and key `prefix`.
     ///
     /// This inserts an empty entry with the key set to `prefix`, effectively clearing all other
@@ -378,11 +456,30 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
     ) -> Result<usize, InsertError<S>> {
         self.ensure_open()?;
         let id = RecordIdentifier::new(self.id(), author.id(), prefix);
-        let entry = Entry::new_empty(id);
-        let signed_entry = entry.sign(self.secret_key()?, author);
+        let entry = Entry::new(id, Record::empty(self.clock.now()));
+        let signed_entry = self.sign_entry(entry, author)?;
         self.insert_entry(signed_entry, InsertOrigin::Local)
     }
 
+    /// Sign a local entry with the namespace secret, or with a [`Delegation`] for the author
+    /// which allows the entry and is not expired if the replica is read only.
+    fn sign_entry(&self, entry: Entry, author: &Author) -> Result<SignedEntry, InsertError<S>> {
+        if let Ok(secret) = self.secret_key() {
+            return Ok(entry.sign(secret, author));
+        }
+        let delegations = self
+            .peer
+            .store()
+            .get_delegations(&self.id())
+            .map_err(|_| InsertError::ReadOnly)?;
+        let now = system_time_now();
+        let delegation = delegations
+            .iter()
+            .find(|delegation| delegation.allows(&entry) && !delegation.is_expired(now))
+            .ok_or(InsertError::ReadOnly)?;
+        Ok(SignedEntry::from_delegation(entry, delegation, author))
+    }
+
     /// Insert an entry into this replica which was received from a remote peer.
     ///
     /// This will verify both the namespace and author signatures of the entry, emit an `on_insert`
@@ -398,6 +495,10 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
     ) -> Result<usize, InsertError<S>> {
         self.ensure_open()?;
         entry.validate_empty()?;
+        let mut heads = AuthorHeads::default();
+        heads.insert(entry.author(), entry.timestamp());
+        self.clock
+            .observe_peer(received_from, &heads, system_time_now());
         let origin = InsertOrigin::Sync {
             from: received_from,
             remote_content_status: content_status,
@@ -405,6 +506,27 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
         self.insert_entry(entry, origin)
     }
 
+    /// Insert entries into this replica which were read from a document archive.
+    ///
+    /// The namespace or delegated signatures of all entries are verified before any of them is
+    /// stored. No insert events are emitted for the entries: they were not written on this node,
+    /// and reporting them as local inserts would make live sync broadcast them as such.
+    ///
+    /// Returns the number of entries which were inserted. Entries for which a newer entry exists
+    /// are skipped.
+    pub fn import_entries(&mut self, entries: Vec<SignedEntry>) -> Result<usize, InsertError<S>> {
+        self.ensure_open()?;
+        for entry in &entries {
+            entry.validate_empty()?;
+        }
+        let inserted = self
+            .insert_entries(entries, InsertOrigin::Import)?
+            .into_iter()
+            .filter(|(_entry, outcome)| matches!(outcome, InsertOutcome::Inserted { .. }))
+            .count();
+        Ok(inserted)
+    }
+
     /// Insert a signed entry into the database.
     ///
     /// Returns the number of entries removed as a consequence of this insertion.
@@ -413,59 +535,92 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
         entry: SignedEntry,
         origin: InsertOrigin,
     ) -> Result<usize, InsertError<S>> {
-        let namespace = self.id();
-
-        #[cfg(feature = "metrics")]
-        let len = entry.content_len();
+        let (_entry, outcome) = self
+            .insert_entries(vec![entry], origin)?
+            .pop()
+            .expect("one outcome per entry");
+        match outcome {
+            InsertOutcome::Inserted { removed } => Ok(removed),
+            InsertOutcome::NotInserted => Err(InsertError::NewerEntryExists),
+        }
+    }
 
+    /// Insert signed entries into the database in a single batch.
+    ///
+    /// All entries are validated before any of them is stored. The insert events are emitted
+    /// after the batch was committed, except for entries imported from an archive.
+    fn insert_entries(
+        &mut self,
+        entries: Vec<SignedEntry>,
+        origin: InsertOrigin,
+    ) -> Result<Vec<(SignedEntry, InsertOutcome)>, InsertError<S>> {
+        let namespace = self.id();
+        let now = system_time_now();
         let store = self.peer.store();
-        validate_entry(system_time_now(), store, namespace, &entry, &origin)?;
+        for entry in &entries {
+            validate_entry(now, store, namespace, entry, &origin)?;
+        }
+        for entry in &entries {
+            self.clock.observe(entry.timestamp());
+        }
 
-        let outcome = self.peer.put(entry.clone()).map_err(InsertError::Store)?;
+        let outcomes = self
+            .peer
+            .put_many(entries.clone())
+            .map_err(InsertError::Store)?;
 
-        let removed_count = match outcome {
-            InsertOutcome::Inserted { removed } => removed,
-            InsertOutcome::NotInserted => return Err(InsertError::NewerEntryExists),
+        let download_policy = match origin {
+            InsertOrigin::Local | InsertOrigin::Import => None,
+            InsertOrigin::Sync { .. } => Some(
+                self.peer
+                    .store
+                    .get_download_policy(&namespace)
+                    .unwrap_or_default(),
+            ),
         };
-
-        let insert_event = match origin {
-            InsertOrigin::Local => {
-                #[cfg(feature = "metrics")]
-                {
-                    inc!(Metrics, new_entries_local);
-                    inc_by!(Metrics, new_entries_local_size, len);
-                }
-                Event::LocalInsert { namespace, entry }
+        for (entry, outcome) in entries.iter().zip(&outcomes) {
+            if let InsertOutcome::NotInserted = outcome {
+                continue;
             }
-            InsertOrigin::Sync {
-                from,
-                remote_content_status,
-            } => {
-                #[cfg(feature = "metrics")]
-                {
-                    inc!(Metrics, new_entries_remote);
-                    inc_by!(Metrics, new_entries_remote_size, len);
+            #[cfg(feature = "metrics")]
+            let len = entry.content_len();
+            let entry = entry.clone();
+            let insert_event = match origin {
+                InsertOrigin::Import => continue,
+                InsertOrigin::Local => {
+                    #[cfg(feature = "metrics")]
+                    {
+                        inc!(Metrics, new_entries_local);
+                        inc_by!(Metrics, new_entries_local_size, len);
+                    }
+                    Event::LocalInsert { namespace, entry }
                 }
-
-                let download_policy = self
-                    .peer
-                    .store
-                    .get_download_policy(&self.capability().id())
-                    .unwrap_or_default();
-                let should_download = download_policy.matches(entry.entry());
-                Event::RemoteInsert {
-                    namespace,
-                    entry,
+                InsertOrigin::Sync {
                     from,
-                    should_download,
                     remote_content_status,
-                }
-            }
-        };
+                } => {
+                    #[cfg(feature = "metrics")]
+                    {
+                        inc!(Metrics, new_entries_remote);
+                        inc_by!(Metrics, new_entries_remote_size, len);
+                    }
 
-        self.subscribers.send(insert_event);
+                    let should_download = download_policy
+                        .as_ref()
+                        .is_some_and(|policy| policy.matches(entry.entry()));
+                    Event::RemoteInsert {
+                        namespace,
+                        entry,
+                        from,
+                        should_download,
+                        remote_content_status,
+                    }
+                }
+            };
+            self.subscribers.send(insert_event);
+        }
 
-        Ok(removed_count)
+        Ok(entries.into_iter().zip(outcomes).collect())
     }
 
     /// Hashes the given data and inserts it.
@@ -511,11 +666,12 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
 
         // update state with incoming data.
         state.num_recv += message.value_count();
+        let mut heads = AuthorHeads::default();
         for (entry, _content_status) in message.values() {
-            state
-                .heads_received
-                .insert(entry.author(), entry.timestamp());
+            heads.insert(entry.author(), entry.timestamp());
         }
+        self.clock.observe_peer(from_peer, &heads, now);
+        state.heads_received.merge(&heads);
 
         // let subscribers = std::rc::Rc::new(&mut self.subscribers);
         // l
@@ -533,6 +689,7 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
                 },
                 // on_insert callback: is called when an entry was actually inserted in the store
                 |store, entry, content_status| {
+                    self.clock.observe(entry.timestamp());
                     // We use `send_with` to only clone the entry if we have active subcriptions.
                     self.subscribers.send_with(|| {
                         let download_policy =
@@ -566,6 +723,32 @@ impl<S: ranger::Store<SignedEntry> + PublicKeyStore + store::DownloadPolicyStore
         Ok(reply)
     }
 
+    /// Observe the timestamp of an entry which is stored in this replica.
+    ///
+    /// Local entries are written with timestamps after all observed timestamps, see
+    /// [`Self::clock_skew_report`].
+    pub(crate) fn observe_timestamp(&mut self, timestamp: u64) {
+        self.clock.observe(timestamp);
+    }
+
+    /// Record the clock skew of a peer from the author heads it reported outside of a sync, e.g.
+    /// in a gossip sync report.
+    pub fn observe_peer_heads(&mut self, from: PeerIdBytes, heads: &AuthorHeads) {
+        self.clock.observe_peer(from, heads, system_time_now());
+    }
+
+    /// Get the peers whose entries were ahead of our wall clock by an excessive amount.
+    ///
+    /// The timestamps of local entries come from a hybrid logical clock: a new entry always has a
+    /// timestamp after the latest entry this replica has seen, even if our wall clock is behind.
+    /// This keeps local writes from losing against the entries they replace, but entries from a
+    /// peer with a clock that is ahead also move our timestamps ahead. The report lists the peers
+    /// whose entries were more than [`crate::CLOCK_SKEW_REPORT_THRESHOLD`] ahead of our wall clock,
+    /// or too far in the future to be accepted at all, since this replica was opened.
+    pub fn clock_skew_report(&self) -> Vec<(PeerIdBytes, ClockSkew)> {
+        self.clock.skew_report()
+    }
+
     /// Get the namespace identifier for this [`Replica`].
     pub fn id(&self) -> NamespaceId {
         self.capability.id()
@@ -591,11 +774,17 @@ pub struct ReadOnly;
 /// Validate a [`SignedEntry`] if it's fit to be inserted.
 ///
 /// This validates that
-/// * the entry's author and namespace signatures are correct
+/// * the entry's author and namespace signatures are correct, where the namespace signature may
+///   also be the signature of a [`Delegation`] in the store which allows the entry and is not
+///   expired at our system time
 /// * the entry's namespace matches the current replica
 /// * the entry's timestamp is not more than 10 minutes in the future of our system time
-/// * the entry is newer than an existing entry for the same key and author, if such exists.
-fn validate_entry<S: ranger::Store<SignedEntry> + PublicKeyStore>(
+/// * the entry is newer than an existing entry for the same key and author, if such exists
+/// * the entry is not a tombstone before the horizon of the [`crate::Compaction`] of the
+///   document.
+fn validate_entry<
+    S: ranger::Store<SignedEntry> + PublicKeyStore + store::DelegationStore + store::CompactionStore,
+>(
     now: u64,
     store: &S,
     expected_namespace: NamespaceId,
@@ -609,13 +798,28 @@ fn validate_entry<S: ranger::Store<SignedEntry> + PublicKeyStore>(
 
     // Verify signature for non-local entries.
     if !matches!(origin, InsertOrigin::Local) && entry.verify(store).is_err() {
-        return Err(ValidationFailure::BadSignature);
+        let mut delegations = store
+            .get_delegations(&expected_namespace)
+            .map_err(|_| ValidationFailure::BadSignature)?;
+        delegations.retain(|delegation| !delegation.is_expired(now));
+        if entry.verify_delegated(store, &delegations).is_err() {
+            return Err(ValidationFailure::BadSignature);
+        }
     }
 
     // Verify that the timestamp of the entry is not too far in the future.
     if entry.timestamp() > now + MAX_TIMESTAMP_FUTURE_SHIFT {
         return Err(ValidationFailure::TooFarInTheFuture);
     }
+
+    // Tombstones before the compaction horizon were removed by all peers.
+    if entry.is_empty() {
+        if let Ok(Some(compaction)) = store.get_compaction(&expected_namespace) {
+            if entry.timestamp() < compaction.horizon() {
+                return Err(ValidationFailure::Compacted);
+            }
+        }
+    }
     Ok(())
 }
 
@@ -658,6 +862,9 @@ pub enum ValidationFailure {
     /// Entry has length 0 but not the empty hash, or the empty hash but not length 0.
     #[error("Entry has length 0 but not the empty hash, or the empty hash but not length 0")]
     InvalidEmptyEntry,
+    /// Entry is a tombstone before the compaction horizon of the document.
+    #[error("Entry is a tombstone before the compaction horizon of the document")]
+    Compacted,
 }
 
 /// A signed entry.
@@ -711,6 +918,13 @@ impl SignedEntry {
         SignedEntry { signature, entry }
     }
 
+    /// Create a new signed entry by signing an entry with the `author` and a `delegation` which
+    /// allows the entry.
+    pub fn from_delegation(entry: Entry, delegation: &Delegation, author: &Author) -> Self {
+        let signature = EntrySignature::from_delegation(&entry, delegation, author);
+        SignedEntry { signature, entry }
+    }
+
     /// Create a new signed entries from its parts.
     pub fn from_parts(
         namespace: &NamespaceSecret,
@@ -732,6 +946,27 @@ impl SignedEntry {
         )
     }
 
+    /// Verify the signatures on this entry, where the namespace signature is the signature of
+    /// one of the `delegations`.
+    ///
+    /// Fails if none of the delegations allows this entry or has a valid signature.
+    pub fn verify_delegated<S: store::PublicKeyStore>(
+        &self,
+        store: &S,
+        delegations: &[Delegation],
+    ) -> Result<(), SignatureError> {
+        let delegation = delegations
+            .iter()
+            .find(|delegation| {
+                delegation.signature() == &self.signature.namespace_signature
+                    && delegation.allows(&self.entry)
+            })
+            .ok_or_else(SignatureError::new)?;
+        delegation.verify()?;
+        self.signature
+            .verify_author(&self.entry, &self.entry.author().public_key(store)?)
+    }
+
     /// Get the signature.
     pub fn signature(&self) -> &EntrySignature {
         &self.signature
@@ -833,6 +1068,15 @@ impl EntrySignature {
         }
     }
 
+    /// Create a new signature by signing an entry with the `author`, using the signature of the
+    /// `delegation` as namespace signature.
+    pub fn from_delegation(entry: &Entry, delegation: &Delegation, author: &Author) -> Self {
+        EntrySignature {
+            author_signature: author.sign(&entry.to_vec()),
+            namespace_signature: *delegation.signature(),
+        }
+    }
+
     /// Verify that this signature was created by signing the `entry` with the
     /// secret keys of the specified `author` and `namespace`.
     pub fn verify(
@@ -848,6 +1092,10 @@ impl EntrySignature {
         Ok(())
     }
 
+    fn verify_author(&self, entry: &Entry, author: &AuthorPublicKey) -> Result<(), SignatureError> {
+        author.verify(&entry.to_vec(), &self.author_signature)
+    }
+
     #[cfg(feature = "fs-store")]
     pub(crate) fn from_parts(namespace_sig: &[u8; 64], author_sig: &[u8; 64]) -> Self {
         let namespace_signature = Signature::from_bytes(namespace_sig);
@@ -979,7 +1227,7 @@ impl RangeKey for RecordIdentifier {
     }
 }
 
-fn system_time_now() -> u64 {
+pub(crate) fn system_time_now() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("time drift")
@@ -1175,7 +1423,7 @@ impl Record {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, num::NonZeroU64};
 
     use anyhow::Result;
     use rand_core::SeedableRng;
@@ -1183,7 +1431,11 @@ mod tests {
     use crate::{
         actor::SyncHandle,
         ranger::{Range, Store as _},
-        store::{self, OpenError, Query, SortBy, SortDirection, Store},
+        store::{
+            self, Cursor, FlatQuery, HistoryPolicy, OpenError, Query, QueryBuilder, SortBy,
+            SortDirection, Store,
+        },
+        Compaction, Revocation,
     };
 
     use super::*;
@@ -1422,6 +1674,175 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_history_memory() -> Result<()> {
+        let store = store::memory::Store::default();
+        test_history(store)
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_history_fs() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = store::fs::Store::new(dbfile.path())?;
+        test_history(store)
+    }
+
+    fn test_history<S: store::Store>(store: S) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = store.new_author(&mut rng)?;
+        let mut replica = store.new_replica(namespace.clone())?;
+        let mut insert = |key: &str, value: &str, timestamp: u64| {
+            let id = RecordIdentifier::new(namespace.id(), author.id(), key);
+            let record = Record::new(Hash::new(value), value.len() as u64, timestamp);
+            let entry = Entry::new(id, record).sign(&namespace, &author);
+            replica.insert_remote_entry(entry, [0u8; 32], ContentStatus::Complete)
+        };
+        let values = |entries: Vec<SignedEntry>| {
+            entries
+                .iter()
+                .map(|entry| entry.content_hash())
+                .collect::<Vec<_>>()
+        };
+
+        // without a history policy, replaced entries are dropped
+        insert("a", "v1", 1)?;
+        insert("a", "v2", 2)?;
+        assert!(store.get_history(namespace.id(), "a")?.is_empty());
+
+        let policy = HistoryPolicy::Retain {
+            max_versions: NonZeroU64::new(2),
+            max_age: None,
+        };
+        store.set_history_policy(&namespace.id(), policy)?;
+        assert_eq!(store.get_history_policy(&namespace.id())?, policy);
+        insert("a", "v3", 3)?;
+        assert_eq!(
+            values(store.get_history(namespace.id(), "a")?),
+            vec![Hash::new("v2")]
+        );
+        // stale entries are not inserted and thus not retained
+        insert("a", "v0", 0).ok();
+        insert("a", "v4", 4)?;
+        insert("a", "v5", 5)?;
+        assert_eq!(
+            values(store.get_history(namespace.id(), "a")?),
+            vec![Hash::new("v4"), Hash::new("v3")]
+        );
+
+        // entries replaced by an entry for a prefix of their key are retained too
+        insert("ab", "v6", 6)?;
+        insert("a", "v7", 7)?;
+        assert_eq!(
+            values(store.get_history(namespace.id(), "ab")?),
+            vec![Hash::new("v6")]
+        );
+        assert_eq!(
+            values(store.get_history(namespace.id(), "a")?),
+            vec![Hash::new("v5"), Hash::new("v4")]
+        );
+
+        // retained content is kept alive
+        let hashes = store.content_hashes()?.collect::<Result<HashSet<Hash>>>()?;
+        assert!(hashes.contains(&Hash::new("v4")));
+        assert!(hashes.contains(&Hash::new("v6")));
+
+        assert_eq!(store.prune_history()?, 0);
+        store.set_history_policy(&namespace.id(), HistoryPolicy::Disabled)?;
+        assert_eq!(store.prune_history()?, 3);
+        assert!(store.get_history(namespace.id(), "a")?.is_empty());
+        let hashes = store.content_hashes()?.collect::<Result<HashSet<Hash>>>()?;
+        assert!(!hashes.contains(&Hash::new("v4")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_memory() -> Result<()> {
+        let store = store::memory::Store::default();
+        test_compaction(store)
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_compaction_fs() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = store::fs::Store::new(dbfile.path())?;
+        test_compaction(store)
+    }
+
+    fn test_compaction<S: store::Store>(store: S) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = store.new_author(&mut rng)?;
+        let mut replica = store.new_replica(namespace.clone())?;
+        let mut insert = |key: &str, value: &str, timestamp: u64| {
+            let id = RecordIdentifier::new(namespace.id(), author.id(), key);
+            let record = match value {
+                "" => Record::empty(timestamp),
+                value => Record::new(Hash::new(value), value.len() as u64, timestamp),
+            };
+            let entry = Entry::new(id, record).sign(&namespace, &author);
+            replica.insert_remote_entry(entry, [0u8; 32], ContentStatus::Complete)
+        };
+        let keys = |store: &S| -> Result<Vec<Vec<u8>>> {
+            store
+                .get_many(namespace.id(), Query::all().include_empty())?
+                .map(|entry| entry.map(|entry| entry.key().to_vec()))
+                .collect()
+        };
+
+        insert("a/1", "v1", 1)?;
+        insert("a/2", "v2", 2)?;
+        insert("b", "v3", 3)?;
+        insert("a/", "", 4)?;
+        insert("c", "", 10)?;
+        assert_eq!(
+            keys(&store)?,
+            vec![b"a/".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+
+        // only the tombstones before the horizon are removed
+        let compaction = Compaction::new(&namespace, 5);
+        assert_eq!(store.apply_compaction(compaction.clone())?, Some(1));
+        assert_eq!(keys(&store)?, vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(
+            store.get_compaction(&namespace.id())?,
+            Some(compaction.clone())
+        );
+
+        // compactions up to the same or an earlier horizon are ignored
+        assert_eq!(store.apply_compaction(compaction)?, None);
+        let earlier = Compaction::new(&namespace, 4);
+        assert_eq!(store.apply_compaction(earlier)?, None);
+
+        // tombstones before the horizon are not accepted anymore
+        assert!(matches!(
+            insert("d", "", 4),
+            Err(InsertError::Validation(ValidationFailure::Compacted))
+        ));
+        insert("d", "", 6)?;
+
+        // without a finished sync since the horizon the document needs a resync, but only the
+        // tombstones are removed
+        assert!(store.needs_resync(&namespace.id())?);
+        let compaction = Compaction::new(&namespace, 8);
+        assert_eq!(store.apply_compaction(compaction)?, Some(1));
+        assert_eq!(keys(&store)?, vec![b"b".to_vec(), b"c".to_vec()]);
+
+        // a document which needs a resync discards all entries before the horizon
+        assert_eq!(store.discard_before_horizon(&namespace.id())?, 1);
+        assert_eq!(keys(&store)?, vec![b"c".to_vec()]);
+
+        // registering a useful peer is not a finished sync
+        store.register_useful_peer(namespace.id(), [1u8; 32])?;
+        assert_eq!(store.last_sync(&namespace.id())?, None);
+        store.register_sync_finished(&namespace.id())?;
+        assert!(store.last_sync(&namespace.id())?.is_some());
+        assert!(!store.needs_resync(&namespace.id())?);
+        Ok(())
+    }
+
     #[test]
     fn test_multikey() {
         let mut rng = rand::thread_rng();
@@ -1726,6 +2147,104 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_future_timestamp_tolerance() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let store = store::memory::Store::default();
+        let author = Author::new(&mut rng);
+        let namespace = NamespaceSecret::new(&mut rng);
+        let mut replica = store.new_replica(namespace.clone())?;
+
+        let minute = Duration::from_secs(60).as_micros() as u64;
+        assert_eq!(MAX_TIMESTAMP_FUTURE_SHIFT, 10 * minute);
+
+        // a peer whose clock is a few minutes ahead is still accepted
+        let key = b"hi";
+        let record = Record::from_data(b"1", system_time_now() + 5 * minute);
+        let entry = SignedEntry::from_parts(&namespace, &author, key, record);
+        replica.insert_entry(entry.clone(), InsertOrigin::Local)?;
+        assert_eq!(get_entry(&store, namespace.id(), author.id(), key)?, entry);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hybrid_clock_memory() -> Result<()> {
+        let store = store::memory::Store::default();
+        test_hybrid_clock(store)
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_hybrid_clock_fs() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = store::fs::Store::new(dbfile.path())?;
+        test_hybrid_clock(store)
+    }
+
+    fn test_hybrid_clock<S: store::Store>(store: S) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let alice = Author::new(&mut rng);
+        let bob = Author::new(&mut rng);
+        let namespace = NamespaceSecret::new(&mut rng);
+        let mut replica = store.new_replica(namespace.clone())?;
+
+        // an entry from a peer whose clock is a minute ahead
+        let ahead = system_time_now() + Duration::from_secs(60).as_micros() as u64;
+        let record = Record::from_data(b"remote", ahead);
+        let entry = SignedEntry::from_parts(&namespace, &bob, b"key", record);
+        replica.insert_remote_entry(entry, [1u8; 32], ContentStatus::Complete)?;
+
+        // a local write after observing the entry sorts after it
+        replica.hash_and_insert(b"key", &alice, b"local")?;
+        let local = get_entry(&store, namespace.id(), alice.id(), b"key")?;
+        assert!(local.timestamp() > ahead);
+        let latest = store
+            .get_many(namespace.id(), Query::single_latest_per_key())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].author(), alice.id());
+
+        // entries too far in the future are rejected and reported
+        let future = system_time_now() + 2 * MAX_TIMESTAMP_FUTURE_SHIFT;
+        let record = Record::from_data(b"future", future);
+        let entry = SignedEntry::from_parts(&namespace, &bob, b"key", record);
+        let res = replica.insert_remote_entry(entry, [2u8; 32], ContentStatus::Complete);
+        assert!(matches!(
+            res,
+            Err(InsertError::Validation(
+                ValidationFailure::TooFarInTheFuture
+            ))
+        ));
+        let report = replica.clock_skew_report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].0, [1u8; 32]);
+        assert!(report[0].1.max_ahead > Duration::from_secs(59).as_micros() as u64);
+        assert_eq!(report[1].0, [2u8; 32]);
+        assert_eq!(report[1].1.rejected, 1);
+
+        // heads reported by a peer outside of a sync are observed as well
+        let mut heads = AuthorHeads::default();
+        heads.insert(bob.id(), ahead);
+        replica.observe_peer_heads([3u8; 32], &heads);
+        let report = replica.clock_skew_report();
+        assert_eq!(report.len(), 3);
+        assert!(report.iter().any(|(peer, _skew)| *peer == [3u8; 32]));
+
+        // the clock is restored from the store when the replica is opened again
+        store.close_replica(replica);
+        let mut replica = store.open_replica(&namespace.id())?;
+        for latest in store.get_latest_for_each_author(namespace.id())? {
+            let (_author, timestamp, _key) = latest?;
+            replica.observe_timestamp(timestamp);
+        }
+        replica.hash_and_insert(b"other", &alice, b"local")?;
+        let other = get_entry(&store, namespace.id(), alice.id(), b"other")?;
+        assert!(other.timestamp() > local.timestamp());
+
+        Ok(())
+    }
+
     #[test]
     fn test_insert_empty() -> Result<()> {
         let store = store::memory::Store::default();
@@ -1792,6 +2311,182 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_insert_many_memory() -> Result<()> {
+        let store = store::memory::Store::default();
+        test_insert_many(store)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_insert_many_fs() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = store::fs::Store::new(dbfile.path())?;
+        test_insert_many(store)?;
+        Ok(())
+    }
+
+    fn test_insert_many<S: store::Store>(store: S) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let alice = Author::new(&mut rng);
+        let myspace = NamespaceSecret::new(&mut rng);
+        let mut replica = store.new_replica(myspace.clone())?;
+        let (events_sender, events) = flume::bounded(32);
+        replica.subscribe(events_sender);
+
+        let entries = [b"a", b"b", b"c"]
+            .into_iter()
+            .map(|key| (key, Hash::new(key), 1));
+        let inserted = replica.insert_many(&alice, entries)?;
+        assert_eq!(inserted.len(), 3);
+        let events = events.drain().collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, Event::LocalInsert { .. })));
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(
+                get_content_hash(&store, myspace.id(), alice.id(), key)?,
+                Some(Hash::new(key))
+            );
+        }
+
+        // a single empty entry fails the whole batch
+        let entries = vec![
+            (b"d".to_vec(), Hash::new(b"d"), 1),
+            (b"e".to_vec(), Hash::EMPTY, 0),
+        ];
+        let res = replica.insert_many(&alice, entries);
+        assert!(matches!(res, Err(InsertError::EntryIsEmpty)));
+        assert_eq!(
+            get_content_hash(&store, myspace.id(), alice.id(), b"d")?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegated_writes_memory() -> Result<()> {
+        let alice_store = store::memory::Store::default();
+        let bob_store = store::memory::Store::default();
+        let carol_store = store::memory::Store::default();
+        test_delegated_writes(alice_store, bob_store, carol_store)
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_delegated_writes_fs() -> Result<()> {
+        let alice_dbfile = tempfile::NamedTempFile::new()?;
+        let alice_store = store::fs::Store::new(alice_dbfile.path())?;
+        let bob_dbfile = tempfile::NamedTempFile::new()?;
+        let bob_store = store::fs::Store::new(bob_dbfile.path())?;
+        let carol_dbfile = tempfile::NamedTempFile::new()?;
+        let carol_store = store::fs::Store::new(carol_dbfile.path())?;
+        test_delegated_writes(alice_store, bob_store, carol_store)
+    }
+
+    fn test_delegated_writes<S: store::Store>(
+        alice_store: S,
+        bob_store: S,
+        carol_store: S,
+    ) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let alice = Author::new(&mut rng);
+        let bob = Author::new(&mut rng);
+        let myspace = NamespaceSecret::new(&mut rng);
+        let mut alice_replica = alice_store.new_replica(myspace.clone())?;
+        alice_replica.hash_and_insert("alice", &alice, "from alice")?;
+
+        // bob may only write below `bob/`
+        let delegation = Delegation::new(&myspace, bob.id(), "bob/", None);
+        alice_store.import_delegation(delegation.clone())?;
+        bob_store.import_namespace(Capability::Read(myspace.id()))?;
+        bob_store.import_delegation(delegation.clone())?;
+        let mut bob_replica = bob_store.open_replica(&myspace.id())?;
+        bob_replica.hash_and_insert("bob/1", &bob, "from bob")?;
+        bob_replica.delete_prefix("bob/", &bob)?;
+        bob_replica.hash_and_insert("bob/2", &bob, "from bob")?;
+        let res = bob_replica.hash_and_insert("other", &bob, "from bob");
+        assert!(matches!(res, Err(InsertError::ReadOnly)));
+        let res = bob_replica.hash_and_insert("bob/3", &alice, "from bob");
+        assert!(matches!(res, Err(InsertError::ReadOnly)));
+
+        sync::<S>(&mut alice_replica, &mut bob_replica)?;
+        assert!(bob_store
+            .get_exact(myspace.id(), alice.id(), "alice", false)?
+            .is_some());
+        let entry = alice_store
+            .get_exact(myspace.id(), bob.id(), "bob/2", false)?
+            .expect("synced from bob");
+
+        // carol does not know the delegation
+        carol_store.import_namespace(Capability::Read(myspace.id()))?;
+        let mut carol_replica = carol_store.open_replica(&myspace.id())?;
+        let res =
+            carol_replica.insert_remote_entry(entry.clone(), [1u8; 32], ContentStatus::Missing);
+        assert!(matches!(
+            res,
+            Err(InsertError::Validation(ValidationFailure::BadSignature))
+        ));
+        carol_store.import_delegation(delegation.clone())?;
+        carol_replica.insert_remote_entry(entry, [1u8; 32], ContentStatus::Missing)?;
+
+        // delegations of other documents and expired delegations do not allow writes
+        let other = Delegation::new(&NamespaceSecret::new(&mut rng), alice.id(), "", None);
+        bob_store.import_delegation(other)?;
+        let res = bob_replica.hash_and_insert("alice", &alice, "from bob");
+        assert!(matches!(res, Err(InsertError::ReadOnly)));
+        let expired = Delegation::new(&myspace, alice.id(), "", Some(1));
+        bob_store.import_delegation(expired.clone())?;
+        let res = bob_replica.hash_and_insert("alice", &alice, "from bob");
+        assert!(matches!(res, Err(InsertError::ReadOnly)));
+
+        // expiry is checked against our clock, whatever the timestamp of the entry
+        carol_store.import_delegation(expired.clone())?;
+        let entry = Entry::new(
+            RecordIdentifier::new(myspace.id(), alice.id(), "alice"),
+            Record::new(Hash::new("from bob"), 8, 1),
+        );
+        let entry = SignedEntry::from_delegation(entry, &expired, &alice);
+        let res = carol_replica.insert_remote_entry(entry, [1u8; 32], ContentStatus::Missing);
+        assert!(matches!(
+            res,
+            Err(InsertError::Validation(ValidationFailure::BadSignature))
+        ));
+
+        // after a revocation, entries which were already stored are kept but no new ones are
+        // accepted
+        let revocation = Revocation::new(&myspace, &delegation);
+        bob_replica.hash_and_insert("bob/3", &bob, "from bob")?;
+        let entry = bob_store
+            .get_exact(myspace.id(), bob.id(), "bob/3", false)?
+            .expect("written by bob");
+        carol_store.import_revocation(revocation.clone())?;
+        assert_eq!(
+            carol_store.get_revocations(&myspace.id())?,
+            vec![revocation.clone()]
+        );
+        carol_store.import_delegation(delegation.clone())?;
+        assert!(!carol_store
+            .get_delegations(&myspace.id())?
+            .contains(&delegation));
+        let res = carol_replica.insert_remote_entry(entry, [1u8; 32], ContentStatus::Missing);
+        assert!(matches!(
+            res,
+            Err(InsertError::Validation(ValidationFailure::BadSignature))
+        ));
+        assert!(carol_store
+            .get_exact(myspace.id(), bob.id(), "bob/2", false)?
+            .is_some());
+        bob_store.import_revocation(revocation)?;
+        let res = bob_replica.hash_and_insert("bob/4", &bob, "from bob");
+        assert!(matches!(res, Err(InsertError::ReadOnly)));
+
+        Ok(())
+    }
+
     #[test]
     fn test_replica_sync_delete_memory() -> Result<()> {
         let alice_store = store::memory::Store::default();
@@ -2191,6 +2886,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_replica_import_entries() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let store1 = store::memory::Store::default();
+        let store2 = store::memory::Store::default();
+
+        let alice = Author::new(&mut rng);
+        let mallory = Author::new(&mut rng);
+        let namespace = NamespaceSecret::new(&mut rng);
+        let mut replica1 = store1.new_replica(namespace.clone())?;
+        replica1.hash_and_insert(b"a", &alice, b"a")?;
+        replica1.hash_and_insert(b"b", &alice, b"b")?;
+        let entries = store1
+            .get_many(namespace.id(), Query::all())?
+            .collect::<Result<Vec<_>>>()?;
+
+        store2.import_namespace(Capability::Read(namespace.id()))?;
+        let mut replica2 = store2.open_replica(&namespace.id())?;
+        let (events_sender, events) = flume::bounded(32);
+        replica2.subscribe(events_sender);
+        assert_eq!(replica2.import_entries(entries.clone())?, 2);
+        assert_eq!(replica2.import_entries(entries)?, 0);
+        // imported entries are not reported as inserts
+        assert!(events.is_empty());
+
+        // a batch with a forged entry is rejected as a whole
+        let valid = SignedEntry::from_entry(
+            Entry::new(
+                RecordIdentifier::new(namespace.id(), alice.id(), b"c"),
+                Record::new_current(Hash::new(b"c"), 1),
+            ),
+            &namespace,
+            &alice,
+        );
+        let forged = SignedEntry::from_entry(
+            Entry::new(
+                RecordIdentifier::new(namespace.id(), alice.id(), b"d"),
+                Record::new_current(Hash::new(b"d"), 1),
+            ),
+            &namespace,
+            &mallory,
+        );
+        let res = replica2.import_entries(vec![valid, forged]);
+        assert!(matches!(
+            res,
+            Err(InsertError::Validation(ValidationFailure::BadSignature))
+        ));
+        assert!(store2
+            .get_exact(namespace.id(), alice.id(), b"c", false)?
+            .is_none());
+
+        Ok(())
+    }
+
     #[test]
     fn test_replica_queries_mem() -> Result<()> {
         let store = store::memory::Store::default();
@@ -2377,6 +3126,220 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_range_queries_memory() -> Result<()> {
+        let store = store::memory::Store::default();
+        test_range_queries(store)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "fs-store")]
+    #[test]
+    fn test_range_queries_fs() -> Result<()> {
+        let dbfile = tempfile::NamedTempFile::new()?;
+        let store = store::fs::Store::new(dbfile.path())?;
+        test_range_queries(store)?;
+        Ok(())
+    }
+
+    fn test_range_queries<S: store::Store>(store: S) -> Result<()> {
+        let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(1);
+        let namespace = NamespaceSecret::new(&mut rng);
+        let mut replica = store.new_replica(namespace.clone())?;
+        let a1 = store.new_author(&mut rng)?;
+        let a2 = store.new_author(&mut rng)?;
+
+        let mut insert =
+            |key: &str, author: &Author, timestamp: u64, origin: InsertOrigin| -> Result<()> {
+                let id = RecordIdentifier::new(namespace.id(), author.id(), key);
+                let record = Record::from_data(key, timestamp);
+                let entry = Entry::new(id, record).sign(&namespace, author);
+                replica.insert_entry(entry, origin)?;
+                Ok(())
+            };
+        // a point in time between two inserts, later than the insertion time of all previous
+        // entries.
+        let mark = || {
+            std::thread::sleep(Duration::from_millis(1));
+            system_time_now()
+        };
+        let synced = InsertOrigin::Sync {
+            from: [1u8; 32],
+            remote_content_status: ContentStatus::Complete,
+        };
+
+        // the entries are inserted in a different order than their timestamps.
+        insert("a", &a1, 10, InsertOrigin::Local)?;
+        insert("b", &a1, 30, InsertOrigin::Local)?;
+        let t1 = mark();
+        insert("c", &a1, 20, InsertOrigin::Local)?;
+        insert("b", &a2, 40, InsertOrigin::Local)?;
+        let t2 = mark();
+        // an old entry which arrives late from another peer
+        insert("d", &a2, 5, synced)?;
+        let t3 = mark();
+        // replacing an entry moves it to the end of the insertion order
+        insert("a", &a1, 50, InsertOrigin::Local)?;
+
+        let get = |query: Query| -> Result<Vec<(String, u64)>> {
+            store
+                .get_many(namespace.id(), query)?
+                .map(|e| e.map(|e| (String::from_utf8(e.key().to_vec()).unwrap(), e.timestamp())))
+                .collect()
+        };
+        let expected = |entries: &[(&str, u64)]| -> Vec<(String, u64)> {
+            entries
+                .iter()
+                .map(|(key, timestamp)| (key.to_string(), *timestamp))
+                .collect()
+        };
+
+        assert_eq!(
+            get(Query::single_latest_per_key().key_range("b", "d").build())?,
+            expected(&[("b", 40), ("c", 20)])
+        );
+        assert_eq!(
+            get(Query::author(a1.id()).key_from("b").build())?,
+            expected(&[("b", 30), ("c", 20)])
+        );
+        assert_eq!(
+            get(Query::author(a1.id()).changed_since(t1).build())?,
+            expected(&[("a", 50), ("c", 20)])
+        );
+        // the late entry is found by its insertion time, not by its timestamp
+        assert_eq!(
+            get(Query::all().changed_since(t2).build())?,
+            expected(&[("a", 50), ("d", 5)])
+        );
+        assert_eq!(
+            get(Query::single_latest_per_key().changed_since(t2).build())?,
+            expected(&[("a", 50), ("d", 5)])
+        );
+
+        let by_timestamp = |direction| Query::all().sort_by(SortBy::Timestamp, direction);
+        assert_eq!(
+            get(by_timestamp(SortDirection::Asc).build())?,
+            expected(&[("b", 30), ("c", 20), ("b", 40), ("d", 5), ("a", 50)])
+        );
+        assert_eq!(
+            get(by_timestamp(SortDirection::Asc).changed_since(t1).build())?,
+            expected(&[("c", 20), ("b", 40), ("d", 5), ("a", 50)])
+        );
+        assert_eq!(
+            get(by_timestamp(SortDirection::Asc)
+                .changed_since(t1)
+                .changed_before(t3)
+                .build())?,
+            expected(&[("c", 20), ("b", 40), ("d", 5)])
+        );
+        assert_eq!(
+            get(by_timestamp(SortDirection::Desc).limit(2).build())?,
+            expected(&[("a", 50), ("d", 5)])
+        );
+        assert_eq!(
+            get(by_timestamp(SortDirection::Asc).key_range("b", "c").build())?,
+            expected(&[("b", 30), ("b", 40)])
+        );
+
+        // page through the results with cursors
+        let paginate =
+            |query: &dyn Fn() -> QueryBuilder<FlatQuery>| -> Result<Vec<Vec<(String, u64)>>> {
+                let mut pages = vec![];
+                let mut cursor: Option<Cursor> = None;
+                loop {
+                    let mut query = query().limit(2);
+                    if let Some(cursor) = cursor.take() {
+                        query = query.cursor(cursor);
+                    }
+                    let entries = store
+                        .get_many(namespace.id(), query.build())?
+                        .collect::<Result<Vec<_>>>()?;
+                    let Some(last) = entries.last() else {
+                        break;
+                    };
+                    // cursors survive a round trip through their string encoding
+                    cursor = Some(Cursor::after(last).to_string().parse()?);
+                    pages.push(
+                        entries
+                            .iter()
+                            .map(|e| (String::from_utf8(e.key().to_vec()).unwrap(), e.timestamp()))
+                            .collect(),
+                    );
+                }
+                Ok(pages)
+            };
+        assert_eq!(
+            paginate(&|| by_timestamp(SortDirection::Asc))?,
+            vec![
+                expected(&[("b", 30), ("c", 20)]),
+                expected(&[("b", 40), ("d", 5)]),
+                expected(&[("a", 50)]),
+            ]
+        );
+        assert_eq!(
+            paginate(&|| by_timestamp(SortDirection::Desc).changed_before(t3))?,
+            vec![
+                expected(&[("d", 5), ("b", 40)]),
+                expected(&[("c", 20), ("b", 30)]),
+            ]
+        );
+        for direction in [SortDirection::Asc, SortDirection::Desc] {
+            for sort_by in [SortBy::AuthorKey, SortBy::KeyAuthor] {
+                let query = || Query::all().sort_by(sort_by, direction);
+                let pages = paginate(&query)?;
+                assert_eq!(pages.len(), 3);
+                assert_eq!(pages.concat(), get(query().build())?);
+            }
+        }
+
+        let mut cursor = None;
+        let mut keys = vec![];
+        loop {
+            let mut query = Query::single_latest_per_key().limit(1);
+            if let Some(cursor) = cursor.take() {
+                query = query.cursor(cursor);
+            }
+            let Some(entry) = store.get_many(namespace.id(), query.build())?.next() else {
+                break;
+            };
+            let entry = entry?;
+            keys.push(String::from_utf8(entry.key().to_vec())?);
+            cursor = Some(Cursor::after(&entry));
+        }
+        assert_eq!(keys, vec!["a", "b", "c", "d"]);
+
+        // a cursor on an entry which was replaced since is rejected
+        let cursor = Cursor::after(
+            &store
+                .get_exact(namespace.id(), a1.id(), "c", false)?
+                .unwrap(),
+        );
+        insert("c", &a1, 60, InsertOrigin::Local)?;
+        let query = by_timestamp(SortDirection::Asc).cursor(cursor).build();
+        assert!(store.get_many(namespace.id(), query).is_err());
+
+        // deleted entries leave the insertion time index
+        let t4 = mark();
+        replica.delete_prefix("b", &a2)?;
+        assert_eq!(
+            get(by_timestamp(SortDirection::Asc).build())?,
+            expected(&[("b", 30), ("d", 5), ("a", 50), ("c", 60)])
+        );
+        let tombstones = store
+            .get_many(
+                namespace.id(),
+                by_timestamp(SortDirection::Asc)
+                    .changed_since(t4)
+                    .include_empty()
+                    .build(),
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tombstones.len(), 1);
+        assert!(tombstones[0].is_empty());
+
+        Ok(())
+    }
+
     fn assert_keys<S: store::Store>(store: &S, namespace: NamespaceId, mut expected: Vec<Vec<u8>>) {
         expected.sort();
         assert_eq!(expected, get_keys_sorted(store, namespace));
@@ -28,7 +28,7 @@ pub fn setup_logging() {
 }
 
 const DEFAULT_RPC_PORT: u16 = 0x1337;
-const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/1";
+const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/2";
 
 /// Makes a an RPC endpoint that uses a QUIC transport
 fn make_rpc_endpoint(
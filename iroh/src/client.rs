@@ -3,13 +3,14 @@
 //! TODO: Contains only iroh sync related methods. Add other methods.
 
 use std::collections::BTreeMap;
-use std::io::{self, Cursor};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::result::Result as StdResult;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use bytes::Bytes;
@@ -20,11 +21,11 @@ use iroh_bytes::store::ValidateProgress;
 // use iroh_bytes::util::progress::FlumeProgressSender;
 use iroh_bytes::Hash;
 use iroh_bytes::{BlobFormat, Tag};
-use iroh_net::{key::PublicKey, magic_endpoint::ConnectionInfo, NodeAddr};
+use iroh_net::{key::PublicKey, magic_endpoint::ConnectionInfo, magicsock::PathEvent, NodeAddr};
 use iroh_sync::actor::OpenState;
-use iroh_sync::store::DownloadPolicy;
+use iroh_sync::store::{Cursor, DownloadPolicy, HistoryPolicy, KeyFilter};
 use iroh_sync::{store::Query, AuthorId, CapabilityKind, NamespaceId};
-use iroh_sync::{ContentStatus, RecordIdentifier};
+use iroh_sync::{ClockSkew, ContentStatus, DocumentKey, RecordIdentifier};
 use quic_rpc::message::RpcMsg;
 use quic_rpc::{RpcClient, ServiceConnection};
 use serde::{Deserialize, Serialize};
@@ -37,17 +38,21 @@ use crate::rpc_protocol::{
     BlobAddStreamUpdate, BlobDeleteBlobRequest, BlobDownloadRequest, BlobListCollectionsRequest,
     BlobListCollectionsResponse, BlobListIncompleteRequest, BlobListIncompleteResponse,
     BlobListRequest, BlobListResponse, BlobReadRequest, BlobReadResponse, BlobValidateRequest,
-    CounterStats, DeleteTagRequest, DocCloseRequest, DocCreateRequest, DocDelRequest,
-    DocDelResponse, DocDropRequest, DocExportFileRequest, DocExportProgress,
-    DocGetDownloadPolicyRequest, DocGetExactRequest, DocGetManyRequest, DocImportFileRequest,
-    DocImportProgress, DocImportRequest, DocLeaveRequest, DocListRequest, DocOpenRequest,
-    DocSetDownloadPolicyRequest, DocSetHashRequest, DocSetRequest, DocShareRequest,
-    DocStartSyncRequest, DocStatusRequest, DocSubscribeRequest, DocTicket, DownloadProgress,
-    ListTagsRequest, ListTagsResponse, NodeConnectionInfoRequest, NodeConnectionInfoResponse,
-    NodeConnectionsRequest, NodeShutdownRequest, NodeStatsRequest, NodeStatusRequest,
-    NodeStatusResponse, ProviderService, SetTagOption, ShareMode, WrapOption,
+    CounterStats, DeleteTagRequest, DocClockSkewRequest, DocCloseRequest, DocCompactRequest,
+    DocCreateRequest, DocDelRequest, DocDelResponse, DocDelegateRequest, DocDropRequest,
+    DocExportArchiveRequest, DocExportFileRequest, DocExportProgress, DocGetDownloadPolicyRequest,
+    DocGetExactRequest, DocGetHistoryPolicyRequest, DocGetKeyRequest, DocGetManyRequest,
+    DocHistoryRequest, DocImportArchiveRequest, DocImportFileRequest, DocImportProgress,
+    DocImportRequest, DocLeaveRequest, DocListRequest, DocOpenRequest, DocRevokeRequest,
+    DocSetDownloadPolicyRequest, DocSetHashRequest, DocSetHistoryPolicyRequest, DocSetManyRequest,
+    DocSetRequest, DocSetValue, DocShareRequest, DocStartSyncRequest, DocStatusRequest,
+    DocSubscribeRequest, DocTicket, DownloadProgress, ListTagsRequest, ListTagsResponse,
+    NetcheckReport, NodeConnectionInfoRequest, NodeConnectionInfoResponse, NodeConnectionsRequest,
+    NodeNetcheckRequest, NodeNetcheckResponse, NodeShutdownRequest, NodeStatsRequest,
+    NodeStatusRequest, NodeStatusResponse, NodeWatchRequest, NodeWatchResponse, ProviderService,
+    SetTagOption, ShareMode, WrapOption,
 };
-use crate::sync_engine::SyncEvent;
+use crate::sync_engine::{SubscribeOptions, SyncEvent};
 
 pub mod mem;
 #[cfg(feature = "cli")]
@@ -115,6 +120,29 @@ where
         Ok(conn_info)
     }
 
+    /// Watch for changes in how the node can reach other nodes.
+    ///
+    /// This includes connections upgrading from relay to direct, changes of the best
+    /// address, and nodes becoming unreachable.
+    pub async fn path_events(&self) -> Result<impl Stream<Item = Result<PathEvent>>> {
+        let stream = self.rpc.server_streaming(NodeWatchRequest).await?;
+        Ok(stream.filter_map(|res| async move {
+            match res {
+                Ok(NodeWatchResponse { event, .. }) => event.map(Ok),
+                Err(err) => Some(Err(err.into())),
+            }
+        }))
+    }
+
+    /// Get the most recent netcheck reports of the node, oldest first.
+    ///
+    /// The reports describe the network conditions of the node, including how the NAT it
+    /// is behind maps and filters traffic.
+    pub async fn netcheck(&self) -> Result<Vec<NetcheckReport>> {
+        let NodeNetcheckResponse { reports } = self.rpc.rpc(NodeNetcheckRequest).await??;
+        Ok(reports)
+    }
+
     /// Get status information about a node
     pub async fn status(&self) -> Result<NodeStatusResponse> {
         let response = self.rpc.rpc(NodeStatusRequest).await??;
@@ -143,8 +171,23 @@ where
 {
     /// Create a new document.
     pub async fn create(&self) -> Result<Doc<C>> {
-        let res = self.rpc.rpc(DocCreateRequest {}).await??;
-        let doc = Doc::new(self.rpc.clone(), res.id);
+        let res = self
+            .rpc
+            .rpc(DocCreateRequest { encrypted: false })
+            .await??;
+        let doc = Doc::new(self.rpc.clone(), res.id, None);
+        Ok(doc)
+    }
+
+    /// Create a new end-to-end encrypted document.
+    ///
+    /// The keys and content of entries are encrypted with a [`DocumentKey`] before they are
+    /// sent to the node, see [`Doc`] for the limitations this implies.
+    pub async fn create_encrypted(&self) -> Result<Doc<C>> {
+        let res = self.rpc.rpc(DocCreateRequest { encrypted: true }).await??;
+        let key = self.get_key(res.id).await?;
+        anyhow::ensure!(key.is_some(), "node did not create a document key");
+        let doc = Doc::new(self.rpc.clone(), res.id, key);
         Ok(doc)
     }
 
@@ -160,11 +203,39 @@ where
 
     /// Import a document from a ticket and join all peers in the ticket.
     pub async fn import(&self, ticket: DocTicket) -> Result<Doc<C>> {
+        let key = ticket.encryption_key.clone();
         let res = self.rpc.rpc(DocImportRequest(ticket)).await??;
-        let doc = Doc::new(self.rpc.clone(), res.doc_id);
+        let doc = Doc::new(self.rpc.clone(), res.doc_id, key);
         Ok(doc)
     }
 
+    /// Import a document from an archive file at the given absolute path.
+    ///
+    /// See [`Doc::export_archive`]. The signatures of all entries and the hashes of all content
+    /// blobs in the archive are verified before they are added. Entries are imported in batches
+    /// while the archive is read, so an invalid archive can leave some of its entries in the
+    /// document. A document which did not exist on the node before is added with a read
+    /// capability. The encryption key of an
+    /// encrypted document is not part of the archive and has to be imported separately.
+    pub async fn import_archive(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<(Doc<C>, DocArchiveOutcome)> {
+        let res = self
+            .rpc
+            .rpc(DocImportArchiveRequest {
+                path: path.as_ref().into(),
+            })
+            .await??;
+        let key = self.get_key(res.doc_id).await?;
+        let doc = Doc::new(self.rpc.clone(), res.doc_id, key);
+        let outcome = DocArchiveOutcome {
+            entries: res.entries,
+            blobs: res.blobs,
+        };
+        Ok((doc, outcome))
+    }
+
     /// List all documents.
     pub async fn list(&self) -> Result<impl Stream<Item = Result<(NamespaceId, CapabilityKind)>>> {
         let stream = self.rpc.server_streaming(DocListRequest {}).await?;
@@ -174,9 +245,15 @@ where
     /// Get a [`Doc`] client for a single document. Return None if the document cannot be found.
     pub async fn open(&self, id: NamespaceId) -> Result<Option<Doc<C>>> {
         self.rpc.rpc(DocOpenRequest { doc_id: id }).await??;
-        let doc = Doc::new(self.rpc.clone(), id);
+        let key = self.get_key(id).await?;
+        let doc = Doc::new(self.rpc.clone(), id, key);
         Ok(Some(doc))
     }
+
+    async fn get_key(&self, doc_id: NamespaceId) -> Result<Option<DocumentKey>> {
+        let res = self.rpc.rpc(DocGetKeyRequest { doc_id }).await??;
+        Ok(res.key)
+    }
 }
 
 /// Iroh authors client.
@@ -322,7 +399,7 @@ where
         bytes: Bytes,
         tag: SetTagOption,
     ) -> anyhow::Result<BlobAddOutcome> {
-        self.add_reader(Cursor::new(bytes), tag)
+        self.add_reader(io::Cursor::new(bytes), tag)
             .await?
             .finish()
             .await
@@ -528,6 +605,14 @@ impl AsyncRead for BlobReader {
 }
 
 /// Document handle
+///
+/// For encrypted documents, keys and content are encrypted and decrypted with the
+/// [`DocumentKey`] of the document here, so the node only ever sees ciphertext. Nodes which
+/// got the document from a [`ShareMode::Pin`] ticket do not have the key, and see the entries
+/// as they are stored.
+///
+/// Since equal keys encrypt to equal ciphertexts, entries of encrypted documents can be looked
+/// up and deleted by their exact key, but not by key prefix.
 #[derive(Debug, Clone)]
 pub struct Doc<C: ServiceConnection<ProviderService>>(Arc<DocInner<C>>);
 
@@ -542,6 +627,7 @@ impl<C: ServiceConnection<ProviderService>> Eq for Doc<C> {}
 #[derive(Debug)]
 struct DocInner<C: ServiceConnection<ProviderService>> {
     id: NamespaceId,
+    key: Option<DocumentKey>,
     rpc: RpcClient<ProviderService, C>,
     closed: AtomicBool,
     rt: tokio::runtime::Handle,
@@ -564,10 +650,11 @@ impl<C> Doc<C>
 where
     C: ServiceConnection<ProviderService>,
 {
-    fn new(rpc: RpcClient<ProviderService, C>, id: NamespaceId) -> Self {
+    fn new(rpc: RpcClient<ProviderService, C>, id: NamespaceId, key: Option<DocumentKey>) -> Self {
         Self(Arc::new(DocInner {
             rpc,
             id,
+            key,
             closed: AtomicBool::new(false),
             rt: tokio::runtime::Handle::current(),
         }))
@@ -586,6 +673,80 @@ where
         self.0.id
     }
 
+    /// Get the key of this doc, if it is encrypted and the key is known to the node.
+    pub fn document_key(&self) -> Option<&DocumentKey> {
+        self.0.key.as_ref()
+    }
+
+    fn encrypt_key(&self, key: Bytes) -> Bytes {
+        match &self.0.key {
+            Some(doc_key) => doc_key.encrypt_key(&key).into(),
+            None => key,
+        }
+    }
+
+    fn encrypt_content(&self, content: Bytes) -> Bytes {
+        match &self.0.key {
+            Some(doc_key) => doc_key.encrypt_content(&content).into(),
+            None => content,
+        }
+    }
+
+    fn decrypt_entry(&self, entry: Entry) -> Result<Entry> {
+        let Some(doc_key) = &self.0.key else {
+            return Ok(entry);
+        };
+        let key = doc_key.decrypt_key(entry.key())?;
+        let id = RecordIdentifier::new(entry.0.namespace(), entry.author(), key);
+        let entry = iroh_sync::Entry::new(id, entry.0.record().clone());
+        Ok(Entry(entry, Some(doc_key.clone())))
+    }
+
+    /// Encrypt the key of an exact key filter. Prefixes and ranges of plaintext keys cannot be
+    /// matched against encrypted keys.
+    fn encrypt_key_filter(&self, filter: KeyFilter) -> Result<KeyFilter> {
+        match filter {
+            KeyFilter::Exact(key) => Ok(KeyFilter::Exact(self.encrypt_key(key))),
+            KeyFilter::Prefix(prefix) if !prefix.is_empty() => Err(anyhow!(
+                "key prefix queries are not supported for encrypted documents"
+            )),
+            KeyFilter::Range { .. } => Err(anyhow!(
+                "key range queries are not supported for encrypted documents"
+            )),
+            filter => Ok(filter),
+        }
+    }
+
+    fn decrypt_event(&self, event: LiveEvent) -> Result<LiveEvent> {
+        let event = match event {
+            LiveEvent::ReplayEntry { entry } => LiveEvent::ReplayEntry {
+                entry: self.decrypt_entry(entry)?,
+            },
+            LiveEvent::InsertLocal { entry } => LiveEvent::InsertLocal {
+                entry: self.decrypt_entry(entry)?,
+            },
+            LiveEvent::InsertRemote {
+                from,
+                entry,
+                content_status,
+            } => LiveEvent::InsertRemote {
+                from,
+                entry: self.decrypt_entry(entry)?,
+                content_status,
+            },
+            event => event,
+        };
+        Ok(event)
+    }
+
+    fn ensure_unencrypted(&self) -> Result<()> {
+        if self.0.key.is_some() {
+            Err(anyhow!("not supported for encrypted documents"))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Close the document.
     pub async fn close(&self) -> Result<()> {
         self.0.closed.store(true, Ordering::Release);
@@ -613,14 +774,17 @@ where
             .rpc(DocSetRequest {
                 doc_id: self.id(),
                 author_id,
-                key: key.into(),
-                value: value.into(),
+                key: self.encrypt_key(key.into()),
+                value: self.encrypt_content(value.into()),
             })
             .await??;
         Ok(res.entry.content_hash())
     }
 
     /// Set an entries on the doc via its key, hash, and size.
+    ///
+    /// Not supported for encrypted documents, because the content could not be checked to be
+    /// encrypted. Use [`Self::set_bytes`] instead.
     pub async fn set_hash(
         &self,
         author_id: AuthorId,
@@ -629,10 +793,11 @@ where
         size: u64,
     ) -> Result<()> {
         self.ensure_open()?;
+        self.ensure_unencrypted()?;
         self.rpc(DocSetHashRequest {
             doc_id: self.id(),
             author_id,
-            key: key.into(),
+            key: self.encrypt_key(key.into()),
             hash,
             size,
         })
@@ -640,7 +805,20 @@ where
         Ok(())
     }
 
+    /// Start a batch of entries of `author_id`, which are set in a single transaction.
+    ///
+    /// Nothing is sent to the node before [`DocBatch::commit`] is called.
+    pub fn batch(&self, author_id: AuthorId) -> DocBatch<C> {
+        DocBatch {
+            doc: self.clone(),
+            author_id,
+            entries: Vec::new(),
+        }
+    }
+
     /// Add an entry from an absolute file path
+    ///
+    /// Not supported for encrypted documents, use [`Self::set_bytes`] instead.
     pub async fn import_file(
         &self,
         author: AuthorId,
@@ -649,6 +827,7 @@ where
         in_place: bool,
     ) -> Result<DocImportFileProgress> {
         self.ensure_open()?;
+        self.ensure_unencrypted()?;
         let stream = self
             .0
             .rpc
@@ -664,12 +843,15 @@ where
     }
 
     /// Export an entry as a file to a given absolute path.
+    ///
+    /// Not supported for encrypted documents, use [`Entry::content_bytes`] instead.
     pub async fn export_file(
         &self,
         entry: Entry,
         path: impl AsRef<Path>,
     ) -> Result<DocExportFileProgress> {
         self.ensure_open()?;
+        self.ensure_unencrypted()?;
         let stream = self
             .0
             .rpc
@@ -681,19 +863,47 @@ where
         Ok(DocExportFileProgress::new(stream))
     }
 
+    /// Export the document to an archive file at the given absolute path.
+    ///
+    /// The archive contains the latest entry of each key and author, including deletions, and
+    /// the delegations of the document. With `include_content`, it also contains the content
+    /// blobs of the entries which are complete on the node. The archive can be imported on
+    /// another node with [`DocsClient::import_archive`] without a connection to this node.
+    pub async fn export_archive(
+        &self,
+        path: impl AsRef<Path>,
+        include_content: bool,
+    ) -> Result<DocArchiveOutcome> {
+        self.ensure_open()?;
+        let res = self
+            .0
+            .rpc
+            .rpc(DocExportArchiveRequest {
+                doc_id: self.id(),
+                path: path.as_ref().into(),
+                include_content,
+            })
+            .await??;
+        Ok(DocArchiveOutcome {
+            entries: res.entries,
+            blobs: res.blobs,
+        })
+    }
+
     /// Delete entries that match the given `author` and key `prefix`.
     ///
     /// This inserts an empty entry with the key set to `prefix`, effectively clearing all other
     /// entries whose key starts with or is equal to the given `prefix`.
     ///
-    /// Returns the number of entries deleted.
+    /// Returns the number of entries deleted. In encrypted documents, only the entry with the
+    /// exact key `prefix` is deleted.
     pub async fn del(&self, author_id: AuthorId, prefix: impl Into<Bytes>) -> Result<usize> {
         self.ensure_open()?;
         let res = self
             .rpc(DocDelRequest {
                 doc_id: self.id(),
                 author_id,
-                prefix: prefix.into(),
+                prefix: self.encrypt_key(prefix.into()),
             })
             .await??;
         let DocDelResponse { removed } = res;
@@ -713,29 +923,46 @@ where
         let res = self
             .rpc(DocGetExactRequest {
                 author,
-                key: key.as_ref().to_vec().into(),
+                key: self.encrypt_key(key.as_ref().to_vec().into()),
                 doc_id: self.id(),
                 include_empty,
             })
             .await??;
-        Ok(res.entry.map(|entry| entry.into()))
+        res.entry
+            .map(|entry| self.decrypt_entry(entry.into()))
+            .transpose()
     }
 
     /// Get entries.
+    ///
+    /// The keys of encrypted documents are encrypted one by one, so queries of encrypted
+    /// documents can only filter by an exact key, not by a key prefix or range, and do not
+    /// support cursors.
     pub async fn get_many(
         &self,
         query: impl Into<Query>,
     ) -> Result<impl Stream<Item = Result<Entry>>> {
         self.ensure_open()?;
+        let mut query = query.into();
+        if self.0.key.is_some() {
+            let filter = self.encrypt_key_filter(query.key_filter().clone())?;
+            if query.cursor().is_some() {
+                return Err(anyhow!(
+                    "query cursors are not supported for encrypted documents"
+                ));
+            }
+            query.set_key_filter(filter);
+        }
         let stream = self
             .0
             .rpc
             .server_streaming(DocGetManyRequest {
                 doc_id: self.id(),
-                query: query.into(),
+                query,
             })
             .await?;
-        Ok(flatten(stream).map_ok(|res| res.entry.into()))
+        let doc = self.clone();
+        Ok(flatten(stream).map(move |res| doc.decrypt_entry(res?.entry.into())))
     }
 
     /// Get a single entry.
@@ -755,6 +982,45 @@ where
         Ok(res.0)
     }
 
+    /// Allow `author` to write all keys starting with `prefix`, and share this document with
+    /// read access over a ticket.
+    ///
+    /// If `expires` is set, the author may only write until then, in microseconds since the
+    /// unix epoch. Requires write access to the document. See [`iroh_sync::Delegation`].
+    pub async fn delegate(
+        &self,
+        author: AuthorId,
+        prefix: impl Into<Bytes>,
+        expires: Option<u64>,
+    ) -> anyhow::Result<DocTicket> {
+        self.ensure_open()?;
+        let res = self
+            .rpc(DocDelegateRequest {
+                doc_id: self.id(),
+                author,
+                prefix: prefix.into(),
+                expires,
+            })
+            .await??;
+        Ok(res.0)
+    }
+
+    /// Revoke the delegations for `author`, or only the one for `prefix` if set.
+    ///
+    /// Entries which were already written stay in the document. Returns the number of revoked
+    /// delegations.
+    pub async fn revoke(&self, author: AuthorId, prefix: Option<Bytes>) -> anyhow::Result<usize> {
+        self.ensure_open()?;
+        let res = self
+            .rpc(DocRevokeRequest {
+                doc_id: self.id(),
+                author,
+                prefix,
+            })
+            .await??;
+        Ok(res.revoked)
+    }
+
     /// Start to sync this document with a list of peers.
     pub async fn start_sync(&self, peers: Vec<NodeAddr>) -> Result<()> {
         self.ensure_open()?;
@@ -776,15 +1042,32 @@ where
 
     /// Subscribe to events for this document.
     pub async fn subscribe(&self) -> anyhow::Result<impl Stream<Item = anyhow::Result<LiveEvent>>> {
+        self.subscribe_with(SubscribeOptions::default()).await
+    }
+
+    /// Subscribe to the events for this document which match `options`.
+    ///
+    /// The events are filtered by the node. If `options` request a replay, the current matching
+    /// entries are emitted first, followed by [`LiveEvent::ReplayFinished`].
+    pub async fn subscribe_with(
+        &self,
+        mut options: SubscribeOptions,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<LiveEvent>>> {
         self.ensure_open()?;
+        if self.0.key.is_some() {
+            let filter = self.encrypt_key_filter(options.key_filter().clone())?;
+            options.set_key_filter(filter);
+        }
         let stream = self
             .0
             .rpc
-            .server_streaming(DocSubscribeRequest { doc_id: self.id() })
+            .server_streaming(DocSubscribeRequest {
+                doc_id: self.id(),
+                options,
+            })
             .await?;
-        Ok(flatten(stream)
-            .map_ok(|res| res.event.into())
-            .map_err(Into::into))
+        let doc = self.clone();
+        Ok(flatten(stream).map(move |res| doc.decrypt_event(res?.event.into())))
     }
 
     /// Get status info for this document
@@ -811,6 +1094,82 @@ where
             .await??;
         Ok(res.policy)
     }
+
+    /// Set the history policy for this document
+    pub async fn set_history_policy(&self, policy: HistoryPolicy) -> Result<()> {
+        self.rpc(DocSetHistoryPolicyRequest {
+            doc_id: self.id(),
+            policy,
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Get the history policy for this document
+    pub async fn get_history_policy(&self) -> Result<HistoryPolicy> {
+        let res = self
+            .rpc(DocGetHistoryPolicyRequest { doc_id: self.id() })
+            .await??;
+        Ok(res.policy)
+    }
+
+    /// Get the previous versions of the entries for `key` of all authors, newest first.
+    ///
+    /// Only versions which were replaced while the [`HistoryPolicy`] of this document retained
+    /// them are returned. To restore a version, set its content hash again.
+    pub async fn history(&self, key: impl Into<Bytes>) -> Result<Vec<Entry>> {
+        self.ensure_open()?;
+        let res = self
+            .rpc(DocHistoryRequest {
+                doc_id: self.id(),
+                key: self.encrypt_key(key.into()),
+            })
+            .await??;
+        res.entries
+            .into_iter()
+            .map(|entry| self.decrypt_entry(entry.into()))
+            .collect()
+    }
+
+    /// Remove the deletion markers of this document which are older than `older_than`.
+    ///
+    /// This requires write access to the document. The compaction is signed with the namespace
+    /// key and sent to the peers during sync, which remove their deletion markers as well. Other
+    /// entries are kept. A peer which did not sync since the horizon may have missed deletions, so
+    /// it replaces all its entries before the horizon with those of the peer it syncs with. Its
+    /// own entries before the horizon which it never synced are lost, so `older_than` should
+    /// exceed the time peers stay offline.
+    ///
+    /// Returns the number of removed deletion markers.
+    pub async fn compact(&self, older_than: Duration) -> Result<usize> {
+        self.ensure_open()?;
+        let horizon = SystemTime::now()
+            .checked_sub(older_than)
+            .context("horizon is before the unix epoch")?
+            .duration_since(SystemTime::UNIX_EPOCH)?;
+        let res = self
+            .rpc(DocCompactRequest {
+                doc_id: self.id(),
+                horizon: horizon.as_micros() as u64,
+            })
+            .await??;
+        Ok(res.removed)
+    }
+
+    /// Get the peers whose entries were ahead of our wall clock by an excessive amount.
+    ///
+    /// New entries are always written with a timestamp after the latest entry the node has seen
+    /// for this document, even if its wall clock is behind, so a peer with a clock that is ahead
+    /// moves the timestamps of all writers ahead. Entries of a peer which are too far in the
+    /// future are rejected. The report covers the time since the document was opened on the node,
+    /// largest skew first.
+    pub async fn clock_skew(&self) -> Result<Vec<(PublicKey, ClockSkew)>> {
+        self.ensure_open()?;
+        let res = self
+            .rpc(DocClockSkewRequest { doc_id: self.id() })
+            .await??;
+        Ok(res.peers)
+    }
 }
 
 impl<'a, C: ServiceConnection<ProviderService>> From<&'a Doc<C>>
@@ -829,19 +1188,89 @@ impl<'a, C: ServiceConnection<ProviderService>> From<&'a Iroh<C>>
     }
 }
 
+/// A batch of entries to set in a [`Doc`] in a single transaction, see [`Doc::batch`].
+#[derive(Debug)]
+pub struct DocBatch<C: ServiceConnection<ProviderService>> {
+    doc: Doc<C>,
+    author_id: AuthorId,
+    entries: Vec<(Bytes, DocSetValue)>,
+}
+
+impl<C> DocBatch<C>
+where
+    C: ServiceConnection<ProviderService>,
+{
+    /// Set the content of a key to a byte array.
+    pub fn set_bytes(&mut self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> &mut Self {
+        let key = self.doc.encrypt_key(key.into());
+        let value = self.doc.encrypt_content(value.into());
+        self.entries.push((key, DocSetValue::Bytes(value)));
+        self
+    }
+
+    /// Set an entry via its key, hash, and size.
+    ///
+    /// Not supported for encrypted documents, [`Self::commit`] fails if the batch contains such
+    /// entries.
+    pub fn set_hash(&mut self, key: impl Into<Bytes>, hash: Hash, size: u64) -> &mut Self {
+        let key = self.doc.encrypt_key(key.into());
+        self.entries.push((key, DocSetValue::Hash { hash, size }));
+        self
+    }
+
+    /// The number of entries in this batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether this batch contains no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Set all entries of this batch in a single transaction.
+    ///
+    /// Either all entries are stored or none. Entries for which a newer entry exists for their
+    /// key or a prefix of it are skipped, the other entries are returned.
+    pub async fn commit(self) -> Result<Vec<Entry>> {
+        self.doc.ensure_open()?;
+        let has_hashes = self
+            .entries
+            .iter()
+            .any(|(_, value)| matches!(value, DocSetValue::Hash { .. }));
+        if has_hashes {
+            self.doc.ensure_unencrypted()?;
+        }
+        let res = self
+            .doc
+            .rpc(DocSetManyRequest {
+                doc_id: self.doc.id(),
+                author_id: self.author_id,
+                entries: self.entries,
+            })
+            .await??;
+        res.entries
+            .into_iter()
+            .map(|entry| self.doc.decrypt_entry(entry.into()))
+            .collect()
+    }
+}
+
 /// A single entry in a [`Doc`].
+///
+/// Entries of encrypted documents hold the [`DocumentKey`] to decrypt their content.
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
-pub struct Entry(iroh_sync::Entry);
+pub struct Entry(iroh_sync::Entry, #[serde(skip)] Option<DocumentKey>);
 
 impl From<iroh_sync::Entry> for Entry {
     fn from(value: iroh_sync::Entry) -> Self {
-        Self(value)
+        Self(value, None)
     }
 }
 
 impl From<iroh_sync::SignedEntry> for Entry {
     fn from(value: iroh_sync::SignedEntry) -> Self {
-        Self(value.into())
+        Self(value.into(), None)
     }
 }
 
@@ -866,14 +1295,27 @@ impl Entry {
         self.0.content_len()
     }
 
+    /// Get the timestamp of this entry, in microseconds since the unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.0.timestamp()
+    }
+
     /// Get the key of this entry.
     pub fn key(&self) -> &[u8] {
         self.0.key()
     }
 
+    /// Get a [`Cursor`] to continue a query after this entry.
+    pub fn cursor(&self) -> Cursor {
+        Cursor::after(&self.0)
+    }
+
     /// Read the content of an [`Entry`] as a streaming [`BlobReader`].
     ///
     /// You can pass either a [`Doc`] or the [`Iroh`] client by reference as `client`.
+    ///
+    /// Fails for entries of encrypted documents, whose content can only be decrypted as a whole
+    /// with [`Self::content_bytes`].
     pub async fn content_reader<C>(
         &self,
         client: impl Into<&RpcClient<ProviderService, C>>,
@@ -881,10 +1323,16 @@ impl Entry {
     where
         C: ServiceConnection<ProviderService>,
     {
+        if self.1.is_some() {
+            return Err(anyhow!(
+                "content of encrypted documents can only be read with `content_bytes`"
+            ));
+        }
         BlobReader::from_rpc(client.into(), self.content_hash()).await
     }
 
-    /// Read all content of an [`Entry`] into a buffer.
+    /// Read all content of an [`Entry`] into a buffer, decrypted if the entry is of an encrypted
+    /// document.
     ///
     /// You can pass either a [`Doc`] or the [`Iroh`] client by reference as `client`.
     pub async fn content_bytes<C>(
@@ -894,10 +1342,16 @@ impl Entry {
     where
         C: ServiceConnection<ProviderService>,
     {
-        BlobReader::from_rpc(client.into(), self.content_hash())
+        let content = BlobReader::from_rpc(client.into(), self.content_hash())
             .await?
             .read_to_bytes()
-            .await
+            .await?;
+        match &self.1 {
+            // deletion markers have no content to decrypt
+            Some(_) if content.is_empty() => Ok(content),
+            Some(doc_key) => Ok(doc_key.decrypt_content(&content)?.into()),
+            None => Ok(content),
+        }
     }
 }
 
@@ -929,6 +1383,15 @@ pub enum LiveEvent {
     NeighborDown(PublicKey),
     /// A set-reconciliation sync finished.
     SyncFinished(SyncEvent),
+    /// An entry which existed when the subscription started.
+    ///
+    /// Only emitted for subscriptions which replay the current entries, before all other events.
+    ReplayEntry {
+        /// The existing entry.
+        entry: Entry,
+    },
+    /// All existing entries were replayed, the following events are live.
+    ReplayFinished,
 }
 
 impl From<crate::sync_engine::LiveEvent> for LiveEvent {
@@ -950,6 +1413,10 @@ impl From<crate::sync_engine::LiveEvent> for LiveEvent {
             crate::sync_engine::LiveEvent::NeighborUp(node) => Self::NeighborUp(node),
             crate::sync_engine::LiveEvent::NeighborDown(node) => Self::NeighborDown(node),
             crate::sync_engine::LiveEvent::SyncFinished(details) => Self::SyncFinished(details),
+            crate::sync_engine::LiveEvent::ReplayEntry { entry } => Self::ReplayEntry {
+                entry: entry.into(),
+            },
+            crate::sync_engine::LiveEvent::ReplayFinished => Self::ReplayFinished,
         }
     }
 }
@@ -1093,6 +1560,15 @@ impl Stream for DocExportFileProgress {
     }
 }
 
+/// Outcome of a [`Doc::export_archive`] or [`DocsClient::import_archive`] operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocArchiveOutcome {
+    /// The number of entries written to the archive, or inserted into the document on import
+    pub entries: u64,
+    /// The number of content blobs written to or imported from the archive
+    pub blobs: u64,
+}
+
 fn flatten<T, E1, E2>(
     s: impl Stream<Item = StdResult<StdResult<T, E1>, E2>>,
 ) -> impl Stream<Item = Result<T>>
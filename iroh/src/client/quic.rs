@@ -11,7 +11,7 @@ use quic_rpc::transport::quinn::QuinnConnection;
 use crate::rpc_protocol::{NodeStatusRequest, ProviderRequest, ProviderResponse, ProviderService};
 
 /// TODO: Change to "/iroh-rpc/1"
-pub const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/1";
+pub const RPC_ALPN: [u8; 17] = *b"n0/provider-rpc/2";
 
 /// RPC client to an iroh node running in a seperate process.
 pub type RpcClient =
@@ -1,9 +1,10 @@
 use std::{
     cell::RefCell,
     collections::BTreeMap,
+    num::NonZeroU64,
     path::{Path, PathBuf},
     rc::Rc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -19,12 +20,12 @@ use tokio::io::AsyncReadExt;
 use iroh::{
     client::{Doc, Entry, Iroh, LiveEvent},
     rpc_protocol::{DocTicket, ProviderService, SetTagOption, ShareMode, WrapOption},
-    sync_engine::Origin,
+    sync_engine::{Origin, SubscribeOptions},
     util::fs::{path_content_info, path_to_key, PathContent},
 };
 use iroh_bytes::{provider::AddProgress, Hash, Tag};
 use iroh_sync::{
-    store::{DownloadPolicy, FilterKind, Query, SortDirection},
+    store::{Cursor, DownloadPolicy, FilterKind, HistoryPolicy, Query, SortDirection},
     AuthorId, NamespaceId,
 };
 
@@ -83,6 +84,36 @@ pub enum DlPolicyCmd {
     },
 }
 
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum HistoryPolicyCmd {
+    /// Retain the previous versions of entries, or stop to retain them.
+    Set {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Number of previous versions to keep per author and key.
+        #[clap(long)]
+        max_versions: Option<NonZeroU64>,
+        /// Seconds to keep a previous version after it was replaced.
+        #[clap(long)]
+        max_age_secs: Option<u64>,
+        /// Do not retain previous versions, and drop those retained so far.
+        #[clap(long, conflicts_with_all = ["max_versions", "max_age_secs"])]
+        disable: bool,
+    },
+    Get {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+    },
+}
+
 #[derive(Debug, Clone, Parser)]
 pub enum DocCommands {
     /// Set the active document (only works within the Iroh console).
@@ -92,6 +123,9 @@ pub enum DocCommands {
         /// Switch to the created document (only in the Iroh console).
         #[clap(long)]
         switch: bool,
+        /// Encrypt the keys and content of the document end-to-end.
+        #[clap(long)]
+        encrypted: bool,
     },
     /// Join a document from a ticket.
     Join {
@@ -112,6 +146,44 @@ pub enum DocCommands {
         doc: Option<NamespaceId>,
         mode: ShareMode,
     },
+    /// Allow an author to write all keys starting with a prefix, and share the document with
+    /// read access.
+    ///
+    /// Requires write access to the document.
+    Delegate {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Author to delegate write access to.
+        #[clap(long)]
+        author: AuthorId,
+        /// Key prefix the author may write to (parsed as UTF-8 string).
+        #[clap(long, default_value = "")]
+        prefix: String,
+        /// How many seconds the author may write, unlimited if not set.
+        #[clap(long)]
+        valid_secs: Option<u64>,
+    },
+    /// Revoke write access delegated to an author.
+    ///
+    /// Entries the author already wrote stay in the document.
+    Revoke {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Author to revoke write access from.
+        #[clap(long)]
+        author: AuthorId,
+        /// Only revoke write access to this key prefix (parsed as UTF-8 string).
+        #[clap(long)]
+        prefix: Option<String>,
+    },
     /// Set an entry in a document.
     Set {
         /// Document to operate on.
@@ -156,6 +228,28 @@ pub enum DocCommands {
         #[clap(short, long, value_enum, default_value_t=DisplayContentMode::Auto)]
         mode: DisplayContentMode,
     },
+    /// Show the retained previous versions of the entries for a key, newest first.
+    ///
+    /// Versions are only retained if enabled with `doc history-policy set`.
+    History {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Key to the entry (parsed as UTF-8 string).
+        key: String,
+        /// Filter by author.
+        #[clap(long)]
+        author: Option<AuthorId>,
+        /// How to show the contents of the key.
+        #[clap(short, long, value_enum, default_value_t=DisplayContentMode::Auto)]
+        mode: DisplayContentMode,
+    },
+    /// Set the retention of previous versions of entries for a document.
+    #[clap(subcommand)]
+    HistoryPolicy(HistoryPolicyCmd),
     /// Delete all entries below a key prefix.
     Del {
         /// Document to operate on.
@@ -174,6 +268,33 @@ pub enum DocCommands {
         /// deleted.
         prefix: String,
     },
+    /// Remove the empty entries left by deletions which are older than a horizon.
+    ///
+    /// Requires write access to the document. Peers remove these entries as well when they
+    /// sync. Peers which were offline since the horizon may sync deleted entries back.
+    Compact {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Remove deletions older than this many seconds.
+        #[clap(long)]
+        older_than_secs: u64,
+    },
+    /// List the peers whose clocks are ahead of ours by an excessive amount.
+    ///
+    /// Entries are written with timestamps after the latest entry seen for the document, so a
+    /// peer with a clock that is ahead moves the timestamps of all writers ahead.
+    ClockSkew {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+    },
     /// List all keys in a document.
     #[clap(alias = "ls")]
     Keys {
@@ -188,12 +309,30 @@ pub enum DocCommands {
         author: Option<AuthorId>,
         /// Optional key prefix (parsed as UTF-8 string)
         prefix: Option<String>,
+        /// Only list keys from this key on (parsed as UTF-8 string)
+        #[clap(long, conflicts_with = "prefix")]
+        start: Option<String>,
+        /// Only list keys before this key (parsed as UTF-8 string)
+        #[clap(long, conflicts_with = "prefix")]
+        end: Option<String>,
+        /// Only list entries this node received at or after this time, in microseconds since the
+        /// unix epoch.
+        #[clap(long)]
+        since: Option<u64>,
         /// How to sort the entries
         #[clap(long, default_value_t=Sorting::Author)]
         sort: Sorting,
         /// Sort in descending order
         #[clap(long)]
         desc: bool,
+        /// Maximum number of entries to list.
+        ///
+        /// If the limit is reached, a cursor is printed to list the following entries.
+        #[clap(long)]
+        limit: Option<u64>,
+        /// Continue a previous listing after the entry this cursor was printed for.
+        #[clap(long)]
+        cursor: Option<Cursor>,
         /// How to show the contents of the keys.
         #[clap(short, long, value_enum, default_value_t=DisplayContentMode::ShortHash)]
         mode: DisplayContentMode,
@@ -244,6 +383,32 @@ pub enum DocCommands {
         #[clap(short, long)]
         out: String,
     },
+    /// Export a document with all its entries to a single archive file
+    ///
+    /// The archive can be imported on another node with `doc import-archive`, without a
+    /// connection between the nodes. All entries and content are verified on import.
+    ExportArchive {
+        /// Document to operate on.
+        ///
+        /// Required unless the document is set through the IROH_DOC environment variable.
+        /// Within the Iroh console, the active document can also be set with `doc switch`.
+        #[clap(short, long)]
+        doc: Option<NamespaceId>,
+        /// Path of the archive file to write
+        #[clap(short, long)]
+        out: String,
+        /// Also include the content of the entries which is available on this node
+        #[clap(long)]
+        content: bool,
+    },
+    /// Import a document from an archive file created with `doc export-archive`
+    ImportArchive {
+        /// Path of the archive file
+        path: String,
+        /// Switch to the imported document (only in the Iroh console).
+        #[clap(long)]
+        switch: bool,
+    },
     /// Watch for changes and events on a document
     Watch {
         /// Document to operate on.
@@ -252,6 +417,15 @@ pub enum DocCommands {
         /// Within the Iroh console, the active document can also set with `doc switch`.
         #[clap(short, long)]
         doc: Option<NamespaceId>,
+        /// Only watch entries with this key prefix (parsed as UTF-8 string).
+        #[clap(long)]
+        prefix: Option<String>,
+        /// Only watch entries by this author.
+        #[clap(long)]
+        author: Option<AuthorId>,
+        /// Print the current matching entries before watching for changes.
+        #[clap(long)]
+        replay: bool,
     },
     /// Stop syncing a document.
     Leave {
@@ -283,12 +457,15 @@ pub enum Sorting {
     Author,
     /// Sort by key, then author
     Key,
+    /// Sort by the time this node received the entries, then author, then key
+    Time,
 }
 impl From<Sorting> for iroh_sync::store::SortBy {
     fn from(value: Sorting) -> Self {
         match value {
             Sorting::Author => Self::AuthorKey,
             Sorting::Key => Self::KeyAuthor,
+            Sorting::Time => Self::Timestamp,
         }
     }
 }
@@ -303,12 +480,16 @@ impl DocCommands {
                 env.set_doc(doc)?;
                 println!("Active doc is now {}", fmt_short(doc.as_bytes()));
             }
-            Self::New { switch } => {
+            Self::New { switch, encrypted } => {
                 if switch && !env.is_console() {
                     bail!("The --switch flag is only supported within the Iroh console.");
                 }
 
-                let doc = iroh.docs.create().await?;
+                let doc = if encrypted {
+                    iroh.docs.create_encrypted().await?
+                } else {
+                    iroh.docs.create().await?
+                };
                 println!("{}", doc.id());
 
                 if switch {
@@ -340,6 +521,32 @@ impl DocCommands {
                 let ticket = doc.share(mode).await?;
                 println!("{}", ticket);
             }
+            Self::Delegate {
+                doc,
+                author,
+                prefix,
+                valid_secs,
+            } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let expires = valid_secs.map(|secs| {
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .expect("time drift");
+                    (now + Duration::from_secs(secs)).as_micros() as u64
+                });
+                let ticket = doc.delegate(author, prefix.into_bytes(), expires).await?;
+                println!("{}", ticket);
+            }
+            Self::Revoke {
+                doc,
+                author,
+                prefix,
+            } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let prefix = prefix.map(|prefix| prefix.into_bytes().into());
+                let revoked = doc.revoke(author, prefix).await?;
+                println!("Revoked {revoked} delegations");
+            }
             Self::Set {
                 doc,
                 author,
@@ -378,6 +585,32 @@ impl DocCommands {
                     println!("Aborted.")
                 }
             }
+            Self::Compact {
+                doc,
+                older_than_secs,
+            } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let removed = doc.compact(Duration::from_secs(older_than_secs)).await?;
+                println!("Removed {removed} deletion markers.");
+            }
+            Self::ClockSkew { doc } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let peers = doc.clock_skew().await?;
+                if peers.is_empty() {
+                    println!("No peers with excessive clock skew.");
+                }
+                for (peer, skew) in peers {
+                    let ahead = HumanDuration(Duration::from_micros(skew.max_ahead));
+                    if skew.rejected > 0 {
+                        println!(
+                            "{peer}: ahead by {ahead}, {} rejected entries",
+                            skew.rejected
+                        );
+                    } else {
+                        println!("{peer}: ahead by {ahead}");
+                    }
+                }
+            }
             Self::Get {
                 doc,
                 key,
@@ -400,13 +633,38 @@ impl DocCommands {
                     println!("{}", fmt_entry(&doc, &entry, mode).await);
                 }
             }
+            Self::History {
+                doc,
+                key,
+                author,
+                mode,
+            } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("time drift");
+                let entries = doc.history(key.into_bytes()).await?;
+                let entries = entries
+                    .iter()
+                    .filter(|entry| author.map_or(true, |author| entry.author() == author));
+                for entry in entries {
+                    let age = now.saturating_sub(Duration::from_micros(entry.timestamp()));
+                    let entry = fmt_entry(&doc, entry, mode).await;
+                    println!("{entry} ({} ago)", HumanDuration(age));
+                }
+            }
             Self::Keys {
                 doc,
                 prefix,
+                start,
+                end,
+                since,
                 author,
                 mode,
                 sort,
                 desc,
+                limit,
+                cursor,
             } => {
                 let doc = get_doc(iroh, env, doc).await?;
                 let mut query = Query::all();
@@ -416,14 +674,38 @@ impl DocCommands {
                 if let Some(prefix) = prefix {
                     query = query.key_prefix(prefix);
                 }
+                match (start, end) {
+                    (Some(start), Some(end)) => query = query.key_range(start, end),
+                    (Some(start), None) => query = query.key_from(start),
+                    (None, Some(end)) => query = query.key_range("", end),
+                    (None, None) => {}
+                }
+                if let Some(since) = since {
+                    query = query.changed_since(since);
+                }
+                if let Some(limit) = limit {
+                    query = query.limit(limit);
+                }
+                if let Some(cursor) = cursor {
+                    query = query.cursor(cursor);
+                }
                 let direction = match desc {
                     true => SortDirection::Desc,
                     false => SortDirection::Asc,
                 };
                 query = query.sort_by(sort.into(), direction);
                 let mut stream = doc.get_many(query).await?;
+                let mut count = 0;
+                let mut last = None;
                 while let Some(entry) = stream.try_next().await? {
                     println!("{}", fmt_entry(&doc, &entry, mode).await);
+                    count += 1;
+                    last = Some(entry);
+                }
+                if let (Some(limit), Some(last)) = (limit, last) {
+                    if count >= limit {
+                        println!("More entries with: --cursor {}", last.cursor());
+                    }
                 }
             }
             Self::Leave { doc } => {
@@ -499,6 +781,16 @@ impl DocCommands {
                     }
                     Some(e) => e,
                 };
+                if doc.document_key().is_some() {
+                    // encrypted content can only be decrypted as a whole
+                    let content = entry.content_bytes(&doc).await?;
+                    if let Some(dir) = path.parent() {
+                        tokio::fs::create_dir_all(dir).await?;
+                    }
+                    tokio::fs::write(&path, content).await?;
+                    println!("wrote '{key_str}' to {}", path.display());
+                    return Ok(());
+                }
                 match entry.content_reader(&doc).await {
                     Ok(mut content) => {
                         if let Some(dir) = path.parent() {
@@ -527,9 +819,54 @@ impl DocCommands {
                     Err(err) => println!("<failed to get content: {err}>"),
                 }
             }
-            Self::Watch { doc } => {
+            Self::ExportArchive { doc, out, content } => {
                 let doc = get_doc(iroh, env, doc).await?;
-                let mut stream = doc.subscribe().await?;
+                let path = std::env::current_dir()?.join(canonicalize_path(&out)?);
+                let outcome = doc.export_archive(&path, content).await?;
+                println!(
+                    "wrote {} entries and {} blobs to {}",
+                    outcome.entries,
+                    outcome.blobs,
+                    path.display()
+                );
+            }
+            Self::ImportArchive { path, switch } => {
+                if switch && !env.is_console() {
+                    bail!("The --switch flag is only supported within the Iroh console.");
+                }
+
+                let path = canonicalize_path(&path)?.canonicalize()?;
+                let (doc, outcome) = iroh.docs.import_archive(path).await?;
+                println!(
+                    "imported {} entries and {} blobs into {}",
+                    outcome.entries,
+                    outcome.blobs,
+                    doc.id()
+                );
+
+                if switch {
+                    env.set_doc(doc.id())?;
+                    println!("Active doc is now {}", fmt_short(doc.id().as_bytes()));
+                }
+            }
+            Self::Watch {
+                doc,
+                prefix,
+                author,
+                replay,
+            } => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let mut options = SubscribeOptions::default();
+                if let Some(prefix) = prefix {
+                    options = options.key_prefix(prefix);
+                }
+                if let Some(author) = author {
+                    options = options.author(author);
+                }
+                if replay {
+                    options = options.replay();
+                }
+                let mut stream = doc.subscribe_with(options).await?;
                 while let Some(event) = stream.next().await {
                     let event = event?;
                     match event {
@@ -591,6 +928,15 @@ impl DocCommands {
                         LiveEvent::NeighborDown(peer) => {
                             println!("neighbor peer down: {peer:?}");
                         }
+                        LiveEvent::ReplayEntry { entry } => {
+                            println!(
+                                "existing:      {}",
+                                fmt_entry(&doc, &entry, DisplayContentMode::Auto).await
+                            )
+                        }
+                        LiveEvent::ReplayFinished => {
+                            println!("watching for changes");
+                        }
                     }
                 }
             }
@@ -622,6 +968,45 @@ impl DocCommands {
                     println!("Could not set the document's download policy. {e}")
                 }
             }
+            Self::HistoryPolicy(HistoryPolicyCmd::Set {
+                doc,
+                max_versions,
+                max_age_secs,
+                disable,
+            }) => {
+                let doc = get_doc(iroh, env, doc).await?;
+                let policy = if disable {
+                    HistoryPolicy::Disabled
+                } else {
+                    HistoryPolicy::Retain {
+                        max_versions,
+                        max_age: max_age_secs.map(Duration::from_secs),
+                    }
+                };
+                doc.set_history_policy(policy).await?;
+            }
+            Self::HistoryPolicy(HistoryPolicyCmd::Get { doc }) => {
+                let doc = get_doc(iroh, env, doc).await?;
+                match doc.get_history_policy().await? {
+                    HistoryPolicy::Disabled => {
+                        println!("Previous versions are not retained in this document.")
+                    }
+                    HistoryPolicy::Retain {
+                        max_versions,
+                        max_age,
+                    } => {
+                        let versions = match max_versions {
+                            Some(max) => format!("up to {max} previous versions"),
+                            None => "all previous versions".to_string(),
+                        };
+                        let age = match max_age {
+                            Some(max_age) => format!("for {}", HumanDuration(max_age)),
+                            None => "forever".to_string(),
+                        };
+                        println!("Retaining {versions} per author and key {age}.");
+                    }
+                }
+            }
             Self::DlPolicy(DlPolicyCmd::Get { doc }) => {
                 let doc = get_doc(iroh, env, doc).await?;
                 match doc.get_download_policy().await {
@@ -681,7 +1066,8 @@ where
 
     match mode {
         DisplayContentMode::Auto => {
-            if entry.content_len() < MAX_DISPLAY_CONTENT_LEN {
+            // encrypted content can only be decrypted as a whole
+            if entry.content_len() < MAX_DISPLAY_CONTENT_LEN || doc.document_key().is_some() {
                 // small content: read fully as UTF-8
                 let bytes = entry.content_bytes(doc).await.map_err(read_failed)?;
                 Ok(as_utf8(bytes.into()).unwrap_or_else(encode_hex))
@@ -950,7 +1336,15 @@ mod tests {
         // run iroh node in the background, as if running `iroh start`
         std::env::set_var("IROH_DATA_DIR", data_dir.path().as_os_str());
         let lp = tokio_util::task::LocalPoolHandle::new(1);
-        let node = crate::commands::start::start_node(&lp, None).await?;
+        let node = crate::commands::start::start_node(
+            &lp,
+            None,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            &Default::default(),
+        )
+        .await?;
         let client = node.client();
         let doc = client.docs.create().await.context("doc create")?;
         let author = client.authors.create().await.context("author create")?;
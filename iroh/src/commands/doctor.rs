@@ -4,6 +4,7 @@ use std::{
     collections::HashMap,
     net::SocketAddr,
     num::NonZeroU16,
+    path::PathBuf,
     str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
@@ -18,7 +19,7 @@ use iroh::util::{path::IrohPaths, progress::ProgressWriter};
 use iroh_base::ticket::Ticket;
 use iroh_net::{
     defaults::DEFAULT_DERP_STUN_PORT,
-    derp::{DerpMap, DerpMode},
+    derp::{DerpMap, DerpMode, DerpNode},
     key::{PublicKey, SecretKey},
     magic_endpoint,
     magicsock::EndpointInfo,
@@ -71,6 +72,12 @@ pub enum Commands {
         /// The port of the STUN server.
         #[clap(long, default_value_t = DEFAULT_DERP_STUN_PORT)]
         stun_port: u16,
+        /// The alternate port of the STUN server, used to classify the NAT.
+        ///
+        /// Only used together with `--stun-host`, the server must answer requests on this
+        /// port from `--stun-port`.
+        #[clap(long)]
+        stun_alt_port: Option<u16>,
     },
     /// Wait for incoming requests from iroh doctor connect
     Accept {
@@ -89,6 +96,13 @@ pub enum Commands {
         /// Use a local derp relay
         #[clap(long)]
         local_derper: bool,
+
+        /// Record all packets into a pcapng file at this path.
+        ///
+        /// If the KEYLOGFILE environment variable is set, the TLS secrets are written to it
+        /// so Wireshark can decrypt the QUIC traffic.
+        #[clap(long)]
+        capture: Option<PathBuf>,
     },
     /// Connect to an iroh doctor accept node.
     Connect {
@@ -118,6 +132,13 @@ pub enum Commands {
         /// Default is `None`.
         #[clap(long)]
         derp_url: Option<Url>,
+
+        /// Record all packets into a pcapng file at this path.
+        ///
+        /// If the KEYLOGFILE environment variable is set, the TLS secrets are written to it
+        /// so Wireshark can decrypt the QUIC traffic.
+        #[clap(long)]
+        capture: Option<PathBuf>,
     },
     /// Probe the port mapping protocols.
     PortMapProbe {
@@ -133,7 +154,9 @@ pub enum Commands {
     },
     /// Attempt to get a port mapping to the given local port.
     PortMap {
-        /// Protocol to use for port mapping. One of ["upnp", "nat_pmp", "pcp"].
+        /// Protocol to use for port mapping. One of ["upnp", "nat_pmp", "pcp", "pcp6"].
+        ///
+        /// "pcp6" opens an IPv6 firewall pinhole instead of an IPv4 mapping.
         protocol: String,
         /// Local port to get a mapping.
         local_port: NonZeroU16,
@@ -255,6 +278,7 @@ async fn send_blocks(
 async fn report(
     stun_host: Option<String>,
     stun_port: u16,
+    stun_alt_port: Option<u16>,
     config: &NodeConfig,
 ) -> anyhow::Result<()> {
     let port_mapper = portmapper::Client::default();
@@ -263,15 +287,43 @@ async fn report(
     let dm = match stun_host {
         Some(host_name) => {
             let url = host_name.parse()?;
-            // creating a derp map from host name and stun port
-            DerpMap::default_from_node(url, stun_port)
+            // creating a derp map from host name and stun ports
+            DerpMap::from_nodes([DerpNode {
+                url,
+                stun_only: false,
+                stun_port,
+                stun_alt_port,
+                auth_token: None,
+            }])?
         }
         None => config.derp_map()?.unwrap_or_else(DerpMap::empty),
     };
     println!("getting report using derp map {dm:#?}");
 
-    let r = client.get_report(dm, None, None).await?;
+    let r = client.get_report(dm.clone(), None, None).await?;
     println!("{r:#?}");
+    match r.captive_portal {
+        Some(true) => {
+            println!("captive portal detected, log in to the network to reach the relays")
+        }
+        Some(false) => println!("no captive portal detected"),
+        None => println!("captive portal check did not complete"),
+    }
+    match client.probe_nat(dm).await {
+        Ok(nat) => {
+            let r = netcheck::Report {
+                mapping_varies_by_dest_port: nat.mapping_varies_by_dest_port,
+                nat_filtering: Some(nat.filtering),
+                ..(*r).clone()
+            };
+            match r.nat_mapping() {
+                Some(mapping) => println!("nat mapping: {mapping}"),
+                None => println!("nat mapping: unknown"),
+            }
+            println!("nat filtering: {}", nat.filtering);
+        }
+        Err(err) => println!("nat classification failed: {err:#}"),
+    }
     Ok(())
 }
 
@@ -564,6 +616,7 @@ const DR_DERP_ALPN: [u8; 11] = *b"n0/drderp/1";
 async fn make_endpoint(
     secret_key: SecretKey,
     derp_map: Option<DerpMap>,
+    capture_path: Option<PathBuf>,
 ) -> anyhow::Result<MagicEndpoint> {
     tracing::info!(
         "public key: {}",
@@ -571,19 +624,19 @@ async fn make_endpoint(
     );
     tracing::info!("derp map {:#?}", derp_map);
 
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
-    transport_config.max_idle_timeout(Some(Duration::from_secs(10).try_into().unwrap()));
-
     let endpoint = MagicEndpoint::builder()
         .secret_key(secret_key)
         .alpns(vec![DR_DERP_ALPN.to_vec()])
-        .transport_config(transport_config);
+        .transport_settings(magic_endpoint::TransportProfile::Interactive.settings());
 
     let endpoint = match derp_map {
         Some(derp_map) => endpoint.derp_mode(DerpMode::Custom(derp_map)),
         None => endpoint,
     };
+    let endpoint = match capture_path {
+        Some(path) => endpoint.packet_capture(path).keylog(true),
+        None => endpoint,
+    };
     let endpoint = endpoint.bind(0).await?;
 
     tokio::time::timeout(Duration::from_secs(10), endpoint.local_endpoints())
@@ -599,8 +652,9 @@ async fn connect(
     direct_addresses: Vec<SocketAddr>,
     derp_url: Option<Url>,
     derp_map: Option<DerpMap>,
+    capture_path: Option<PathBuf>,
 ) -> anyhow::Result<()> {
-    let endpoint = make_endpoint(secret_key, derp_map).await?;
+    let endpoint = make_endpoint(secret_key, derp_map, capture_path.clone()).await?;
 
     tracing::info!("dialing {:?}", node_id);
     let node_addr = NodeAddr::from_parts(node_id, derp_url, direct_addresses);
@@ -616,9 +670,28 @@ async fn connect(
         }
     }
 
+    if let Some(path) = capture_path {
+        // Closing the endpoint flushes the capture file.
+        endpoint.close(0u32.into(), b"capture done").await?;
+        println!("Packet capture written to {}", path.display());
+        print_capture_hints();
+    }
+
     Ok(())
 }
 
+/// Explains how to inspect a packet capture in Wireshark.
+fn print_capture_hints() {
+    match std::env::var_os("KEYLOGFILE") {
+        Some(keylog) => println!(
+            "To decrypt the QUIC packets in Wireshark, set the TLS \"(Pre)-Master-Secret log filename\" to {}",
+            PathBuf::from(keylog).display()
+        ),
+        None => println!("Set KEYLOGFILE to record the TLS secrets needed to decrypt the QUIC packets"),
+    }
+    println!("If the packets are not recognized as QUIC, use \"Decode As...\" on the UDP ports");
+}
+
 /// format a socket addr so that it does not have to be escaped on the console
 fn format_addr(addr: SocketAddr) -> String {
     if addr.is_ipv6() {
@@ -632,8 +705,13 @@ async fn accept(
     secret_key: SecretKey,
     config: TestConfig,
     derp_map: Option<DerpMap>,
+    capture_path: Option<PathBuf>,
 ) -> anyhow::Result<()> {
-    let endpoint = make_endpoint(secret_key.clone(), derp_map).await?;
+    let endpoint = make_endpoint(secret_key.clone(), derp_map, capture_path.clone()).await?;
+    if let Some(path) = capture_path {
+        println!("Recording packets to {}", path.display());
+        print_capture_hints();
+    }
     let endpoints = endpoint.local_endpoints().await?;
     let remote_addrs = endpoints
         .iter()
@@ -698,24 +776,39 @@ async fn port_map(protocol: &str, local_port: NonZeroU16, timeout: Duration) ->
     let mut enable_upnp = false;
     let mut enable_pcp = false;
     let mut enable_nat_pmp = false;
+    let mut enable_pcp_ipv6 = false;
     match protocol.to_ascii_lowercase().as_ref() {
         "upnp" => enable_upnp = true,
         "nat_pmp" => enable_nat_pmp = true,
         "pcp" => enable_pcp = true,
+        "pcp6" => enable_pcp_ipv6 = true,
         other => anyhow::bail!("Unknown port mapping protocol {other}"),
     }
     let config = portmapper::Config {
         enable_upnp,
         enable_pcp,
         enable_nat_pmp,
+        enable_pcp_ipv6,
+        static_external_addrs: Vec::new(),
     };
     let port_mapper = portmapper::Client::new(config);
     let mut watcher = port_mapper.watch_external_address();
+    let mut pinhole_watcher = port_mapper.watch_pinhole_address();
     port_mapper.update_local_port(local_port);
 
     // wait for the mapping to be ready, or timeout waiting for a change.
-    match tokio::time::timeout(timeout, watcher.changed()).await {
-        Ok(Ok(_)) => match *watcher.borrow() {
+    let changed = async {
+        let address = if enable_pcp_ipv6 {
+            pinhole_watcher.changed().await?;
+            pinhole_watcher.borrow().map(SocketAddr::V6)
+        } else {
+            watcher.changed().await?;
+            watcher.borrow().map(SocketAddr::V4)
+        };
+        Ok::<_, tokio::sync::watch::error::RecvError>(address)
+    };
+    match tokio::time::timeout(timeout, changed).await {
+        Ok(Ok(address)) => match address {
             Some(address) => {
                 println!("Port mapping ready: {address}");
                 // Ensure the port mapper remains alive until the end.
@@ -900,13 +993,15 @@ pub async fn run(command: Commands, config: &NodeConfig) -> anyhow::Result<()> {
         Commands::Report {
             stun_host,
             stun_port,
-        } => report(stun_host, stun_port, config).await,
+            stun_alt_port,
+        } => report(stun_host, stun_port, stun_alt_port, config).await,
         Commands::Connect {
             dial,
             secret_key,
             local_derper,
             derp_url,
             remote_endpoint,
+            capture,
         } => {
             let (derp_map, derp_url) = if local_derper {
                 let dm = configure_local_derp_map();
@@ -916,13 +1011,22 @@ pub async fn run(command: Commands, config: &NodeConfig) -> anyhow::Result<()> {
                 (config.derp_map()?, derp_url)
             };
             let secret_key = create_secret_key(secret_key)?;
-            connect(dial, secret_key, remote_endpoint, derp_url, derp_map).await
+            connect(
+                dial,
+                secret_key,
+                remote_endpoint,
+                derp_url,
+                derp_map,
+                capture,
+            )
+            .await
         }
         Commands::Accept {
             secret_key,
             local_derper,
             size,
             iterations,
+            capture,
         } => {
             let derp_map = if local_derper {
                 Some(configure_local_derp_map())
@@ -931,7 +1035,7 @@ pub async fn run(command: Commands, config: &NodeConfig) -> anyhow::Result<()> {
             };
             let secret_key = create_secret_key(secret_key)?;
             let config = TestConfig { size, iterations };
-            accept(secret_key, config, derp_map).await
+            accept(secret_key, config, derp_map, capture).await
         }
         Commands::PortMap {
             protocol,
@@ -947,6 +1051,8 @@ pub async fn run(command: Commands, config: &NodeConfig) -> anyhow::Result<()> {
                 enable_upnp,
                 enable_pcp,
                 enable_nat_pmp,
+                enable_pcp_ipv6: false,
+                static_external_addrs: Vec::new(),
             };
 
             port_map_probe(config).await
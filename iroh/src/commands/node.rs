@@ -7,9 +7,14 @@ use comfy_table::Table;
 use comfy_table::{presets::NOTHING, Cell};
 use futures::{Stream, StreamExt};
 use human_time::ToHumanTimeString;
+use indicatif::HumanBytes;
 use iroh::client::Iroh;
-use iroh::rpc_protocol::ProviderService;
-use iroh_net::{key::PublicKey, magic_endpoint::ConnectionInfo, magicsock::DirectAddrInfo};
+use iroh::rpc_protocol::{NetcheckReport, ProviderService};
+use iroh_net::{
+    key::PublicKey,
+    magic_endpoint::ConnectionInfo,
+    magicsock::{DirectAddrInfo, PathTraffic, TrafficInfo},
+};
 use quic_rpc::ServiceConnection;
 
 #[derive(Subcommand, Debug, Clone)]
@@ -23,6 +28,8 @@ pub enum NodeCommands {
     Status,
     /// Get statistics and metrics from the running node.
     Stats,
+    /// Get the recent netcheck reports of the running node, including its NAT type.
+    Netcheck,
     /// Shutdown the running node.
     Shutdown {
         /// Shutdown mode.
@@ -72,6 +79,10 @@ impl NodeCommands {
                     );
                 }
             }
+            Self::Netcheck => {
+                let reports = iroh.node.netcheck().await?;
+                println!("{}", fmt_netcheck_reports(reports));
+            }
             Self::Status => {
                 let response = iroh.node.status().await?;
                 println!("Listening addresses: {:#?}", response.listen_addrs);
@@ -88,9 +99,17 @@ async fn fmt_connections(
 ) -> String {
     let mut table = Table::new();
     table.load_preset(NOTHING).set_header(
-        ["node id", "derp", "conn type", "latency", "last used"]
-            .into_iter()
-            .map(bold_cell),
+        [
+            "node id",
+            "derp",
+            "conn type",
+            "latency",
+            "last used",
+            "direct sent/recv",
+            "relayed sent/recv",
+        ]
+        .into_iter()
+        .map(bold_cell),
     );
     while let Some(Ok(conn_info)) = infos.next().await {
         let node_id: Cell = conn_info.public_key.to_string().into();
@@ -109,7 +128,11 @@ async fn fmt_connections(
             .map(fmt_how_long_ago)
             .map(Cell::new)
             .unwrap_or_else(never);
-        table.add_row([node_id, derp_url, conn_type, latency, last_used]);
+        let direct = fmt_bytes_sent_recv(&conn_info.traffic.direct()).into();
+        let relayed = fmt_bytes_sent_recv(&conn_info.traffic.relayed()).into();
+        table.add_row([
+            node_id, derp_url, conn_type, latency, last_used, direct, relayed,
+        ]);
     }
     table.to_string()
 }
@@ -123,6 +146,7 @@ fn fmt_connection(info: ConnectionInfo) -> String {
         conn_type,
         latency,
         last_used,
+        traffic,
     } = info;
     let timestamp = time::OffsetDateTime::now_utc()
         .format(&time::format_description::well_known::Rfc2822)
@@ -149,7 +173,85 @@ fn fmt_connection(info: ConnectionInfo) -> String {
     let general_info = table.to_string();
 
     let addrs_info = fmt_addrs(addrs);
-    format!("{general_info}\n\n{addrs_info}",)
+    let traffic_info = fmt_traffic(traffic);
+    format!("{general_info}\n\n{addrs_info}\n\n{traffic_info}",)
+}
+
+fn fmt_bytes_sent_recv(traffic: &PathTraffic) -> String {
+    format!(
+        "{} / {}",
+        HumanBytes(traffic.bytes_sent),
+        HumanBytes(traffic.bytes_recv)
+    )
+}
+
+fn fmt_traffic(traffic: TrafficInfo) -> comfy_table::Table {
+    let mut table = Table::new();
+    table.load_preset(NOTHING).set_header(
+        [
+            "path",
+            "bytes sent",
+            "bytes recv",
+            "packets sent",
+            "packets recv",
+            "last sent",
+            "last recv",
+        ]
+        .into_iter()
+        .map(bold_cell),
+    );
+    let TrafficInfo { ipv4, ipv6, derp } = traffic;
+    let paths = [(String::from("ipv4"), ipv4), (String::from("ipv6"), ipv6)]
+        .into_iter()
+        .chain(
+            derp.into_iter()
+                .map(|(url, traffic)| (url.to_string(), traffic)),
+        );
+    for (path, traffic) in paths {
+        let last =
+            |d: Option<Duration>| d.map(fmt_how_long_ago).map(Cell::new).unwrap_or_else(never);
+        table.add_row([
+            path.into(),
+            HumanBytes(traffic.bytes_sent).to_string().into(),
+            HumanBytes(traffic.bytes_recv).to_string().into(),
+            traffic.packets_sent.into(),
+            traffic.packets_recv.into(),
+            last(traffic.last_sent),
+            last(traffic.last_recv),
+        ]);
+    }
+    table
+}
+
+fn fmt_netcheck_reports(reports: Vec<NetcheckReport>) -> String {
+    let mut table = Table::new();
+    table.load_preset(NOTHING).set_header(
+        [
+            "age",
+            "udp",
+            "public ipv4",
+            "public ipv6",
+            "nat mapping",
+            "nat filtering",
+            "derp",
+        ]
+        .into_iter()
+        .map(bold_cell),
+    );
+    let unknown = || Cell::new("unknown").add_attribute(comfy_table::Attribute::Dim);
+    for report in reports.into_iter().rev() {
+        let age = fmt_how_long_ago(report.time.elapsed().unwrap_or_default());
+        table.add_row([
+            age.into(),
+            report.udp.into(),
+            report.global_v4.map(Cell::new).unwrap_or_else(unknown),
+            report.global_v6.map(Cell::new).unwrap_or_else(unknown),
+            report.nat_mapping.map(Cell::new).unwrap_or_else(unknown),
+            report.nat_filtering.map(Cell::new).unwrap_or_else(unknown),
+            report.preferred_derp.map(Cell::new).unwrap_or_else(unknown),
+        ]);
+    }
+    table.to_string()
 }
 
 fn direct_addr_row(info: DirectAddrInfo) -> comfy_table::Row {
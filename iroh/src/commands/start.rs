@@ -17,12 +17,14 @@ use iroh::{
 use iroh_net::{
     derp::{DerpMap, DerpMode},
     key::SecretKey,
+    magic_endpoint::{AcceptPolicy, BindConfig},
+    portmapper,
 };
 use quic_rpc::{transport::quinn::QuinnServerEndpoint, ServiceEndpoint};
 use tokio_util::task::LocalPoolHandle;
 use tracing::{info_span, Instrument};
 
-use crate::config::{iroh_data_root, path_with_env, NodeConfig};
+use crate::config::{iroh_data_root, path_with_env, NodeConfig, TransportConfig};
 
 use super::rpc::RpcStatus;
 
@@ -77,9 +79,18 @@ where
     T: Future<Output = Result<()>> + 'static,
 {
     let derp_map = config.derp_map()?;
+    let accept_policy = config.accept_policy()?;
 
     let spinner = create_spinner("Iroh booting...");
-    let node = start_node(rt, derp_map).await?;
+    let node = start_node(
+        rt,
+        derp_map,
+        accept_policy,
+        config.bind_config(),
+        config.portmapper_config(),
+        &config.transport,
+    )
+    .await?;
     drop(spinner);
 
     eprintln!("{}", welcome_message(&node)?);
@@ -172,6 +183,10 @@ fn migrate_flat_store_v0_v1() -> anyhow::Result<()> {
 pub(crate) async fn start_node(
     rt: &LocalPoolHandle,
     derp_map: Option<DerpMap>,
+    accept_policy: AcceptPolicy,
+    bind_config: BindConfig,
+    portmapper_config: portmapper::Config,
+    transport: &TransportConfig,
 ) -> Result<Node<iroh_bytes::store::flat::Store>> {
     let rpc_status = RpcStatus::load(iroh_data_root()?).await?;
     match rpc_status {
@@ -200,8 +215,21 @@ pub(crate) async fn start_node(
         Some(derp_map) => DerpMode::Custom(derp_map),
     };
 
-    Node::builder(bao_store, doc_store)
+    let builder = Node::builder(bao_store, doc_store)
         .derp_mode(derp_mode)
+        .accept_policy(accept_policy)
+        .bind_config(bind_config)
+        .portmapper_config(portmapper_config);
+    let builder = match transport.settings() {
+        Some(settings) => builder.transport_settings(settings),
+        None => builder,
+    };
+    let builder = transport
+        .alpn_settings()
+        .fold(builder, |builder, (alpn, settings)| {
+            builder.alpn_transport_settings(alpn, settings)
+        });
+    builder
         .peers_data_path(peers_data_path)
         .local_pool(rt)
         .rpc_endpoint(rpc_endpoint)
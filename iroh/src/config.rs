@@ -1,12 +1,13 @@
 //! Configuration for the iroh CLI.
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env, fmt,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
@@ -15,6 +16,11 @@ use iroh::{node::GcPolicy, util::path::IrohPaths};
 use iroh_net::{
     defaults::{default_eu_derp_node, default_na_derp_node},
     derp::{DerpMap, DerpNode},
+    key::PublicKey,
+    magic_endpoint::{
+        AcceptPolicy, BindConfig, CongestionControl, TransportProfile, TransportSettings,
+    },
+    portmapper,
 };
 use iroh_sync::{AuthorId, NamespaceId};
 use parking_lot::RwLock;
@@ -118,6 +124,96 @@ pub struct NodeConfig {
     /// Bind address on which to serve Prometheus metrics
     #[cfg(feature = "metrics")]
     pub metrics_addr: Option<SocketAddr>,
+    /// Only accept connections from these nodes. If unset, any node can connect.
+    pub allow_nodes: Option<Vec<PublicKey>>,
+    /// Refuse connections from these nodes. Can not be combined with `allow_nodes`.
+    pub deny_nodes: Vec<PublicKey>,
+    /// IP addresses to bind to, one IPv4 and optionally one IPv6 address. If empty, all
+    /// interfaces are used.
+    pub bind_addrs: Vec<IpAddr>,
+    /// Interface to bind to, ignored if `bind_addrs` is set.
+    pub bind_interface: Option<String>,
+    /// Interfaces whose addresses are not advertised to other nodes, a trailing `*`
+    /// matches any suffix.
+    pub exclude_interfaces: Vec<String>,
+    /// External addresses forwarded manually to the bind port on the router, these are
+    /// advertised to other nodes.
+    pub static_external_addrs: Vec<SocketAddr>,
+    /// Tuning of the QUIC transport.
+    pub transport: TransportConfig,
+}
+
+/// Tuning of the QUIC transport of a node.
+///
+/// The settings of the `profile` are used, with the knobs which are set overriding them. If
+/// neither a profile nor any knob is set the node keeps its default transport.
+#[derive(PartialEq, Eq, Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TransportConfig {
+    /// The profile the settings start from.
+    pub profile: TransportProfile,
+    /// Keep alive interval in milliseconds, `0` disables keep alives.
+    pub keep_alive_interval_ms: Option<u64>,
+    /// Idle timeout in milliseconds, `0` never closes idle connections.
+    pub max_idle_timeout_ms: Option<u64>,
+    /// Bytes the peer may send on all streams of a connection before being acknowledged.
+    pub receive_window: Option<u64>,
+    /// Bytes the peer may send on a single stream before being acknowledged.
+    pub stream_receive_window: Option<u64>,
+    /// Bytes we send on all streams of a connection before waiting for acknowledgements.
+    pub send_window: Option<u64>,
+    /// The congestion control algorithm.
+    pub congestion_control: Option<CongestionControl>,
+    /// Profiles for the connections the node dials with specific ALPNs, by ALPN.
+    ///
+    /// Incoming connections always use the settings above.
+    pub alpns: BTreeMap<String, TransportProfile>,
+}
+
+impl TransportConfig {
+    /// The [`TransportSettings`] of the profile, with the configured overrides.
+    ///
+    /// `None` if nothing is configured.
+    pub fn settings(&self) -> Option<TransportSettings> {
+        let is_unset = self.profile == TransportProfile::Default
+            && self.keep_alive_interval_ms.is_none()
+            && self.max_idle_timeout_ms.is_none()
+            && self.receive_window.is_none()
+            && self.stream_receive_window.is_none()
+            && self.send_window.is_none()
+            && self.congestion_control.is_none();
+        if is_unset {
+            return None;
+        }
+        let mut settings = self.profile.settings();
+        let millis = |ms: u64| (ms != 0).then(|| Duration::from_millis(ms));
+        if let Some(ms) = self.keep_alive_interval_ms {
+            settings.keep_alive_interval = millis(ms);
+        }
+        if let Some(ms) = self.max_idle_timeout_ms {
+            settings.max_idle_timeout = millis(ms);
+        }
+        if let Some(window) = self.receive_window {
+            settings.receive_window = window;
+        }
+        if let Some(window) = self.stream_receive_window {
+            settings.stream_receive_window = window;
+        }
+        if let Some(window) = self.send_window {
+            settings.send_window = window;
+        }
+        if let Some(congestion_control) = self.congestion_control {
+            settings.congestion_control = congestion_control;
+        }
+        Some(settings)
+    }
+
+    /// The [`TransportSettings`] for connections dialed with specific ALPNs.
+    pub fn alpn_settings(&self) -> impl Iterator<Item = (Vec<u8>, TransportSettings)> + '_ {
+        self.alpns
+            .iter()
+            .map(|(alpn, profile)| (alpn.as_bytes().to_vec(), profile.settings()))
+    }
 }
 
 impl Default for NodeConfig {
@@ -128,6 +224,13 @@ impl Default for NodeConfig {
             gc_policy: GcPolicy::Disabled,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
+            allow_nodes: None,
+            deny_nodes: Vec::new(),
+            bind_addrs: Vec::new(),
+            bind_interface: None,
+            exclude_interfaces: Vec::new(),
+            static_external_addrs: Vec::new(),
+            transport: Default::default(),
         }
     }
 }
@@ -213,6 +316,39 @@ impl NodeConfig {
         }
         Some(DerpMap::from_nodes(self.derp_nodes.iter().cloned())).transpose()
     }
+
+    /// Constructs the [`AcceptPolicy`] for incoming connections based on the current
+    /// configuration.
+    pub fn accept_policy(&self) -> Result<AcceptPolicy> {
+        match self.allow_nodes {
+            Some(ref allow_nodes) => {
+                ensure!(
+                    self.deny_nodes.is_empty(),
+                    "only one of allow_nodes and deny_nodes can be set"
+                );
+                Ok(AcceptPolicy::allow_list(allow_nodes.iter().copied()))
+            }
+            None if self.deny_nodes.is_empty() => Ok(AcceptPolicy::Everyone),
+            None => Ok(AcceptPolicy::deny_list(self.deny_nodes.iter().copied())),
+        }
+    }
+
+    /// Constructs the [`BindConfig`] for the node's sockets based on the current configuration.
+    pub fn bind_config(&self) -> BindConfig {
+        BindConfig {
+            addrs: self.bind_addrs.clone(),
+            interface: self.bind_interface.clone(),
+            exclude_interfaces: self.exclude_interfaces.clone(),
+        }
+    }
+
+    /// Constructs the port mapping configuration, including the manually forwarded addresses.
+    pub fn portmapper_config(&self) -> portmapper::Config {
+        portmapper::Config {
+            static_external_addrs: self.static_external_addrs.clone(),
+            ..Default::default()
+        }
+    }
 }
 
 /// Environment for CLI and REPL
@@ -448,5 +584,69 @@ mod tests {
         let config = NodeConfig::load(&[][..], "__FOO", HashMap::<String, String>::new()).unwrap();
 
         assert_eq!(config.derp_nodes.len(), 2);
+        assert!(config.accept_policy().unwrap().is_open());
+        assert_eq!(config.bind_config(), BindConfig::default());
+    }
+
+    #[test]
+    fn test_accept_policy() {
+        let node = iroh_net::key::SecretKey::generate().public();
+        let config = NodeConfig {
+            allow_nodes: Some(vec![node]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.accept_policy().unwrap(),
+            AcceptPolicy::AllowList(nodes) if nodes.contains(&node)
+        ));
+
+        let config = NodeConfig {
+            deny_nodes: vec![node],
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.accept_policy().unwrap(),
+            AcceptPolicy::DenyList(_)
+        ));
+
+        let config = NodeConfig {
+            allow_nodes: Some(vec![node]),
+            deny_nodes: vec![node],
+            ..Default::default()
+        };
+        assert!(config.accept_policy().is_err());
+    }
+
+    #[test]
+    fn test_transport_config() {
+        let config: TransportConfig = toml::from_str(
+            r#"
+            profile = "bulk"
+            keep_alive_interval_ms = 0
+            stream_receive_window = 1024
+
+            [alpns]
+            "n0/iroh-gossip/0" = "interactive"
+            "#,
+        )
+        .unwrap();
+        let settings = config.settings().unwrap();
+        assert_eq!(settings.keep_alive_interval, None);
+        assert_eq!(settings.stream_receive_window, 1024);
+        assert_eq!(settings.congestion_control, CongestionControl::Bbr);
+        let alpns: Vec<_> = config.alpn_settings().collect();
+        assert_eq!(
+            alpns,
+            vec![(
+                b"n0/iroh-gossip/0".to_vec(),
+                TransportProfile::Interactive.settings()
+            )]
+        );
+        assert_eq!(TransportConfig::default().settings(), None);
+        let config = TransportConfig {
+            profile: TransportProfile::Default,
+            ..config
+        };
+        assert_eq!(config.settings().unwrap().keep_alive_interval, None);
     }
 }
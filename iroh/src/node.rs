@@ -15,7 +15,7 @@ use std::sync::{Arc, Mutex};
 use std::task::Poll;
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use futures::future::{BoxFuture, Shared};
 use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
 use iroh_base::rpc::RpcResult;
@@ -30,13 +30,13 @@ use iroh_bytes::util::progress::{FlumeProgressSender, IdGenerator, ProgressSende
 use iroh_bytes::{protocol::Closed, provider::AddProgress, BlobFormat, Hash, HashAndFormat};
 use iroh_gossip::net::{Gossip, GOSSIP_ALPN};
 use iroh_io::AsyncSliceReader;
-use iroh_net::magic_endpoint::get_alpn;
+use iroh_net::magic_endpoint::{AcceptPolicy, BindConfig, Connecting, TransportSettings};
 use iroh_net::util::AbortingJoinHandle;
 use iroh_net::{
     config::Endpoint,
     derp::DerpMode,
     key::{PublicKey, SecretKey},
-    tls, MagicEndpoint, NodeAddr,
+    portmapper, tls, MagicEndpoint, NodeAddr,
 };
 use iroh_sync::store::Store as DocStore;
 use quic_rpc::server::{RpcChannel, RpcServerError};
@@ -57,15 +57,16 @@ use crate::rpc_protocol::{
     BlobAddStreamUpdate, BlobDeleteBlobRequest, BlobDownloadRequest, BlobListCollectionsRequest,
     BlobListCollectionsResponse, BlobListIncompleteRequest, BlobListIncompleteResponse,
     BlobListRequest, BlobListResponse, BlobReadRequest, BlobReadResponse, BlobValidateRequest,
-    DeleteTagRequest, DocExportFileRequest, DocExportFileResponse, DocExportProgress,
+    DeleteTagRequest, DocExportArchiveRequest, DocExportArchiveResponse, DocExportFileRequest,
+    DocExportFileResponse, DocExportProgress, DocImportArchiveRequest, DocImportArchiveResponse,
     DocImportFileRequest, DocImportFileResponse, DocImportProgress, DocSetHashRequest,
-    DownloadLocation, ListTagsRequest, ListTagsResponse, NodeConnectionInfoRequest,
+    DownloadLocation, ListTagsRequest, ListTagsResponse, NetcheckReport, NodeConnectionInfoRequest,
     NodeConnectionInfoResponse, NodeConnectionsRequest, NodeConnectionsResponse,
-    NodeShutdownRequest, NodeStatsRequest, NodeStatsResponse, NodeStatusRequest,
-    NodeStatusResponse, NodeWatchRequest, NodeWatchResponse, ProviderRequest, ProviderResponse,
-    ProviderService, SetTagOption,
+    NodeNetcheckRequest, NodeNetcheckResponse, NodeShutdownRequest, NodeStatsRequest,
+    NodeStatsResponse, NodeStatusRequest, NodeStatusResponse, NodeWatchRequest, NodeWatchResponse,
+    ProviderRequest, ProviderResponse, ProviderService, SetTagOption,
 };
-use crate::sync_engine::{SyncEngine, SYNC_ALPN};
+use crate::sync_engine::{archive, SyncEngine, SYNC_ALPN, SYNC_ALPN_V1};
 use crate::ticket::BlobTicket;
 
 const MAX_CONNECTIONS: u32 = 1024;
@@ -132,9 +133,19 @@ where
     docs: S,
     /// Path to store peer data. If `None`, peer data will not be persisted.
     peers_data_path: Option<PathBuf>,
+    accept_policy: AcceptPolicy,
+    bind_config: BindConfig,
+    portmapper_config: portmapper::Config,
+    transport_settings: Option<TransportSettings>,
+    alpn_transport_settings: Vec<(Vec<u8>, TransportSettings)>,
 }
 
-const PROTOCOLS: [&[u8]; 3] = [&iroh_bytes::protocol::ALPN, GOSSIP_ALPN, SYNC_ALPN];
+const PROTOCOLS: [&[u8]; 4] = [
+    &iroh_bytes::protocol::ALPN,
+    GOSSIP_ALPN,
+    SYNC_ALPN,
+    SYNC_ALPN_V1,
+];
 
 impl<D: Map, S: DocStore> Builder<D, S> {
     /// Creates a new builder for [`Node`] using the given database.
@@ -150,6 +161,11 @@ impl<D: Map, S: DocStore> Builder<D, S> {
             rt: None,
             docs,
             peers_data_path: None,
+            accept_policy: AcceptPolicy::default(),
+            bind_config: BindConfig::default(),
+            portmapper_config: Default::default(),
+            transport_settings: None,
+            alpn_transport_settings: Vec::new(),
         }
     }
 }
@@ -177,6 +193,11 @@ where
             rt: self.rt,
             docs: self.docs,
             peers_data_path: self.peers_data_path,
+            accept_policy: self.accept_policy,
+            bind_config: self.bind_config,
+            portmapper_config: self.portmapper_config,
+            transport_settings: self.transport_settings,
+            alpn_transport_settings: self.alpn_transport_settings,
         }
     }
 
@@ -202,6 +223,16 @@ where
         self
     }
 
+    /// Sets which nodes may connect to this node.
+    ///
+    /// By default all nodes are accepted. The policy is enforced for every incoming connection
+    /// before it is dispatched to a protocol, an [`iroh_net::magic_endpoint::AcceptCallback`]
+    /// also receives the ALPN of the connection.
+    pub fn accept_policy(mut self, accept_policy: AcceptPolicy) -> Self {
+        self.accept_policy = accept_policy;
+        self
+    }
+
     /// Binds the node service to a different socket.
     ///
     /// By default it binds to `127.0.0.1:11204`.
@@ -210,6 +241,45 @@ where
         self
     }
 
+    /// Sets the addresses or interface the node binds to, and interfaces not to advertise.
+    ///
+    /// By default the node listens on all interfaces.
+    pub fn bind_config(mut self, bind_config: BindConfig) -> Self {
+        self.bind_config = bind_config;
+        self
+    }
+
+    /// Configures the port mapping protocols and manually forwarded external addresses.
+    ///
+    /// See [`portmapper::Config`], by default all protocols are enabled.
+    pub fn portmapper_config(mut self, config: portmapper::Config) -> Self {
+        self.portmapper_config = config;
+        self
+    }
+
+    /// Tunes the QUIC transport of all connections.
+    ///
+    /// See [`iroh_net::magic_endpoint::TransportProfile`] for presets. If unset, incoming
+    /// connections keep the quinn defaults.
+    pub fn transport_settings(mut self, settings: TransportSettings) -> Self {
+        self.transport_settings = Some(settings);
+        self
+    }
+
+    /// Tunes the QUIC transport of the connections the node dials with the `alpn` protocol.
+    ///
+    /// E.g. downloads can use a bulk profile while gossip keeps the default. Incoming
+    /// connections are not affected, they always use the
+    /// [`Builder::transport_settings`].
+    pub fn alpn_transport_settings(
+        mut self,
+        alpn: impl Into<Vec<u8>>,
+        settings: TransportSettings,
+    ) -> Self {
+        self.alpn_transport_settings.push((alpn.into(), settings));
+        self
+    }
+
     /// Uses the given [`SecretKey`] for the [`PublicKey`] instead of a newly generated one.
     pub fn secret_key(mut self, secret_key: SecretKey) -> Self {
         self.secret_key = secret_key;
@@ -259,7 +329,11 @@ where
         #[cfg(feature = "metrics")]
         crate::metrics::try_init_metrics_collection().ok();
 
-        let mut transport_config = quinn::TransportConfig::default();
+        let mut transport_config = self
+            .transport_settings
+            .as_ref()
+            .map(TransportSettings::transport_config)
+            .unwrap_or_default();
         transport_config
             .max_concurrent_bidi_streams(MAX_STREAMS.try_into()?)
             .max_concurrent_uni_streams(0u32.into());
@@ -270,7 +344,20 @@ where
             .keylog(self.keylog)
             .transport_config(transport_config)
             .concurrent_connections(MAX_CONNECTIONS)
+            .accept_policy(self.accept_policy)
+            .bind_config(self.bind_config)
+            .portmapper_config(self.portmapper_config)
             .derp_mode(self.derp_mode);
+        let endpoint = match self.transport_settings {
+            Some(settings) => endpoint.transport_settings(settings),
+            None => endpoint,
+        };
+        let endpoint = self
+            .alpn_transport_settings
+            .into_iter()
+            .fold(endpoint, |endpoint, (alpn, settings)| {
+                endpoint.alpn_transport_settings(alpn, settings)
+            });
         let endpoint = match self.peers_data_path {
             Some(path) => endpoint.peers_data_path(path),
             None => endpoint,
@@ -437,7 +524,7 @@ where
                 },
                 // handle incoming p2p connections
                 Some(mut connecting) = server.accept() => {
-                    let alpn = match get_alpn(&mut connecting).await {
+                    let alpn = match connecting.alpn().await {
                         Ok(alpn) => alpn,
                         Err(err) => {
                             error!("invalid handshake: {:?}", err);
@@ -482,6 +569,11 @@ where
                 .send(Event::Db(iroh_bytes::store::Event::GcStarted))
                 .await;
             db.clear_live();
+            // drop the previous versions of entries which are no longer retained, so their
+            // content can be collected
+            if let Err(err) = ds.prune_history() {
+                tracing::error!("Error pruning doc history: {}", err);
+            }
             let doc_hashes = match ds.content_hashes() {
                 Ok(hashes) => hashes,
                 Err(err) => {
@@ -547,18 +639,20 @@ where
 // TODO: Restructure this code to not take all these arguments.
 #[allow(clippy::too_many_arguments)]
 async fn handle_connection<D: BaoStore>(
-    connecting: quinn::Connecting,
+    connecting: Connecting,
     alpn: String,
     node: Arc<NodeInner<D>>,
     gossip: Gossip,
     sync: SyncEngine,
 ) -> Result<()> {
+    // Runs the accept callback of the node, if any, before the connection is dispatched.
+    let connection = connecting.await?;
     match alpn.as_bytes() {
-        GOSSIP_ALPN => gossip.handle_connection(connecting.await?).await?,
-        SYNC_ALPN => sync.handle_connection(connecting).await?,
+        GOSSIP_ALPN => gossip.handle_connection(connection).await?,
+        SYNC_ALPN | SYNC_ALPN_V1 => sync.handle_connection(connection).await?,
         alpn if alpn == iroh_bytes::protocol::ALPN => {
             iroh_bytes::provider::handle_connection(
-                connecting,
+                connection,
                 node.db.clone(),
                 node.callbacks.clone(),
                 node.rt.clone(),
@@ -1051,6 +1145,51 @@ impl<D: BaoStore> RpcHandler<D> {
         Ok(())
     }
 
+    async fn doc_export_archive(
+        self,
+        msg: DocExportArchiveRequest,
+    ) -> RpcResult<DocExportArchiveResponse> {
+        let DocExportArchiveRequest {
+            doc_id,
+            path,
+            include_content,
+        } = msg;
+        // reading blobs from the store needs to happen on a local task
+        let stats = self
+            .rt()
+            .spawn_pinned(move || async move {
+                ensure!(path.is_absolute(), "path must be absolute");
+                let sync = &self.inner.sync.sync;
+                archive::export(sync, &self.inner.db, doc_id, &path, include_content).await
+            })
+            .await
+            .map_err(|err| anyhow!("document archive export failed: {err}"))??;
+        Ok(DocExportArchiveResponse {
+            entries: stats.entries,
+            blobs: stats.blobs,
+        })
+    }
+
+    async fn doc_import_archive(
+        self,
+        msg: DocImportArchiveRequest,
+    ) -> RpcResult<DocImportArchiveResponse> {
+        let DocImportArchiveRequest { path } = msg;
+        let (doc_id, stats) = self
+            .rt()
+            .spawn_pinned(move || async move {
+                ensure!(path.is_absolute(), "path must be absolute");
+                archive::import(&self.inner.sync.sync, &self.inner.db, &path).await
+            })
+            .await
+            .map_err(|err| anyhow!("document archive import failed: {err}"))??;
+        Ok(DocImportArchiveResponse {
+            doc_id,
+            entries: stats.entries,
+            blobs: stats.blobs,
+        })
+    }
+
     async fn blob_export(
         self,
         out: PathBuf,
@@ -1354,15 +1493,25 @@ impl<D: BaoStore> RpcHandler<D> {
     }
 
     fn node_watch(self, _: NodeWatchRequest) -> impl Stream<Item = NodeWatchResponse> {
-        futures::stream::unfold((), |()| async move {
+        let heartbeats = futures::stream::unfold((), |()| async move {
             tokio::time::sleep(HEALTH_POLL_WAIT).await;
             Some((
                 NodeWatchResponse {
                     version: env!("CARGO_PKG_VERSION").to_string(),
+                    event: None,
                 },
                 (),
             ))
-        })
+        });
+        let path_events = self
+            .inner
+            .endpoint
+            .path_events()
+            .map(|event| NodeWatchResponse {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                event: Some(event),
+            });
+        futures::stream::select(heartbeats, path_events)
     }
 
     fn blob_add_stream(
@@ -1512,6 +1661,15 @@ impl<D: BaoStore> RpcHandler<D> {
         let conn_info = self.inner.endpoint.connection_info(node_id).await?;
         Ok(NodeConnectionInfoResponse { conn_info })
     }
+
+    async fn node_netcheck(self, _: NodeNetcheckRequest) -> RpcResult<NodeNetcheckResponse> {
+        let history = self.inner.endpoint.netcheck_history().await?;
+        let reports = history
+            .iter()
+            .map(|(time, report)| NetcheckReport::new(*time, report))
+            .collect();
+        Ok(NodeNetcheckResponse { reports })
+    }
 }
 
 fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
@@ -1539,6 +1697,7 @@ fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
                 chan.rpc(msg, handler, RpcHandler::node_connection_info)
                     .await
             }
+            NodeNetcheck(msg) => chan.rpc(msg, handler, RpcHandler::node_netcheck).await,
             BlobList(msg) => {
                 chan.server_streaming(msg, handler, RpcHandler::blob_list)
                     .await
@@ -1650,6 +1809,8 @@ fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
                 chan.server_streaming(msg, handler, RpcHandler::doc_export_file)
                     .await
             }
+            DocExportArchive(msg) => chan.rpc(msg, handler, RpcHandler::doc_export_archive).await,
+            DocImportArchive(msg) => chan.rpc(msg, handler, RpcHandler::doc_import_archive).await,
             DocDel(msg) => {
                 chan.rpc(msg, handler, |handler, req| async move {
                     handler.inner.sync.doc_del(req).await
@@ -1662,6 +1823,13 @@ fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
                 })
                 .await
             }
+            DocSetMany(msg) => {
+                let bao_store = handler.inner.db.clone();
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_set_many(&bao_store, req).await
+                })
+                .await
+            }
             DocGet(msg) => {
                 chan.server_streaming(msg, handler, |handler, req| {
                     handler.inner.sync.doc_get_many(req)
@@ -1692,6 +1860,18 @@ fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
                 })
                 .await
             }
+            DocDelegate(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_delegate(req).await
+                })
+                .await
+            }
+            DocRevoke(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_revoke(req).await
+                })
+                .await
+            }
             DocSubscribe(msg) => {
                 chan.server_streaming(msg, handler, |handler, req| {
                     async move { handler.inner.sync.doc_subscribe(req) }.flatten_stream()
@@ -1710,6 +1890,42 @@ fn handle_rpc_request<D: BaoStore, E: ServiceEndpoint<ProviderService>>(
                 })
                 .await
             }
+            DocGetKey(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_get_key(req).await
+                })
+                .await
+            }
+            DocSetHistoryPolicy(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_set_history_policy(req).await
+                })
+                .await
+            }
+            DocGetHistoryPolicy(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_get_history_policy(req).await
+                })
+                .await
+            }
+            DocHistory(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_history(req).await
+                })
+                .await
+            }
+            DocCompact(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_compact(req).await
+                })
+                .await
+            }
+            DocClockSkew(msg) => {
+                chan.rpc(msg, handler, |handler, req| async move {
+                    handler.inner.sync.doc_clock_skew(req).await
+                })
+                .await
+            }
         }
     });
 }
@@ -1849,4 +2065,61 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_node_accept_callback() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let allowed = SecretKey::generate();
+        let allowed_id = allowed.public();
+        let db = iroh_bytes::store::mem::Store::new();
+        let doc_store = iroh_sync::store::memory::Store::default();
+        let node = Node::builder(db, doc_store)
+            .bind_port(0)
+            .derp_mode(DerpMode::Disabled)
+            .accept_policy(AcceptPolicy::Callback(Arc::new(move |node_id, alpn| {
+                // the allowed node may only fetch blobs
+                async move { node_id == allowed_id && alpn == iroh_bytes::protocol::ALPN }.boxed()
+            })))
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let addr =
+            NodeAddr::from_parts(node.node_id(), None, node.local_endpoint_addresses().await?);
+
+        let connect = |secret_key: SecretKey, alpn: &'static [u8]| {
+            let addr = addr.clone();
+            async move {
+                let ep = MagicEndpoint::builder()
+                    .secret_key(secret_key)
+                    .derp_mode(DerpMode::Disabled)
+                    .bind(0)
+                    .await?;
+                let conn = ep.connect(addr, alpn).await;
+                anyhow::Ok((ep, conn))
+            }
+        };
+
+        // Refused connections are closed before they reach a protocol.
+        for (secret_key, alpn) in [
+            (SecretKey::generate(), iroh_bytes::protocol::ALPN),
+            (allowed.clone(), GOSSIP_ALPN),
+        ] {
+            let (_ep, conn) = connect(secret_key, alpn).await?;
+            tokio::time::timeout(Duration::from_secs(10), conn?.closed())
+                .await
+                .context("refused connection not closed")?;
+        }
+
+        let (_ep, conn) = connect(allowed, iroh_bytes::protocol::ALPN).await?;
+        let conn = conn?;
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), conn.closed())
+                .await
+                .is_err(),
+            "allowed connection closed"
+        );
+
+        Ok(())
+    }
 }
@@ -7,7 +7,7 @@
 //! response, while others like provide have a stream of responses.
 //!
 //! Note that this is subject to change. The RPC protocol is not yet stable.
-use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, time::SystemTime};
 
 use bytes::Bytes;
 use derive_more::{From, TryInto};
@@ -16,23 +16,26 @@ pub use iroh_bytes::{get::db::DownloadProgress, BlobFormat, Hash};
 use iroh_net::{
     key::PublicKey,
     magic_endpoint::{ConnectionInfo, NodeAddr},
+    magicsock::PathEvent,
+    netcheck::{self, NatFiltering, NatMapping},
 };
 
 use iroh_sync::{
     actor::OpenState,
-    store::{DownloadPolicy, Query},
-    {AuthorId, CapabilityKind, Entry, NamespaceId, SignedEntry},
+    store::{DownloadPolicy, HistoryPolicy, Query},
+    {AuthorId, CapabilityKind, ClockSkew, DocumentKey, Entry, NamespaceId, SignedEntry},
 };
 use quic_rpc::{
     message::{BidiStreaming, BidiStreamingMsg, Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
     Service,
 };
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 pub use iroh_base::rpc::{RpcError, RpcResult};
 pub use iroh_bytes::{provider::AddProgress, store::ValidateProgress};
 
-use crate::sync_engine::LiveEvent;
+use crate::sync_engine::{LiveEvent, SubscribeOptions};
 pub use crate::ticket::DocTicket;
 
 /// A 32-byte key or token
@@ -346,6 +349,69 @@ pub struct NodeStatusResponse {
     pub version: String,
 }
 
+/// A request for the recent netcheck reports of the node
+///
+/// See [`NodeNetcheckResponse`] for the response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeNetcheckRequest;
+
+impl RpcMsg<ProviderService> for NodeNetcheckRequest {
+    type Response = RpcResult<NodeNetcheckResponse>;
+}
+
+/// The response to a [`NodeNetcheckRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeNetcheckResponse {
+    /// The most recent reports, oldest first
+    pub reports: Vec<NetcheckReport>,
+}
+
+/// A summary of a netcheck report, describing the network conditions of the node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NetcheckReport {
+    /// When the report was generated
+    pub time: SystemTime,
+    /// Whether a UDP STUN round trip completed
+    pub udp: bool,
+    /// Whether an IPv4 STUN round trip completed
+    pub ipv4: bool,
+    /// Whether an IPv6 STUN round trip completed
+    pub ipv6: bool,
+    /// The public IPv4 address, as seen by the STUN servers
+    pub global_v4: Option<SocketAddr>,
+    /// The public IPv6 address, as seen by the STUN servers
+    pub global_v6: Option<SocketAddr>,
+    /// How the NAT maps outgoing traffic, `None` if unknown
+    pub nat_mapping: Option<NatMapping>,
+    /// How the NAT filters incoming traffic, `None` if unknown
+    pub nat_filtering: Option<NatFiltering>,
+    /// Whether the router lets local devices talk to each other using the public address
+    pub hair_pinning: Option<bool>,
+    /// Whether a captive portal intercepts HTTP traffic, `None` if not checked
+    pub captive_portal: Option<bool>,
+    /// The DERP server with the lowest latency
+    pub preferred_derp: Option<Url>,
+}
+
+impl NetcheckReport {
+    /// Summarizes a netcheck report generated at `time`
+    pub fn new(time: SystemTime, report: &netcheck::Report) -> Self {
+        Self {
+            time,
+            udp: report.udp,
+            ipv4: report.ipv4,
+            ipv6: report.ipv6,
+            global_v4: report.global_v4,
+            global_v6: report.global_v6,
+            nat_mapping: report.nat_mapping(),
+            nat_filtering: report.nat_filtering,
+            hair_pinning: report.hair_pinning,
+            captive_portal: report.captive_portal,
+            preferred_derp: report.preferred_derp.clone(),
+        }
+    }
+}
+
 /// A request to watch for the node status
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NodeWatchRequest;
@@ -363,6 +429,8 @@ impl ServerStreamingMsg<ProviderService> for NodeWatchRequest {
 pub struct NodeWatchResponse {
     /// The version of the node
     pub version: String,
+    /// A change in how the node can reach other nodes, `None` for periodic heartbeats.
+    pub event: Option<PathEvent>,
 }
 
 /// The response to a version request
@@ -434,6 +502,11 @@ pub enum ShareMode {
     Read,
     /// Write access
     Write,
+    /// Read access without the [`DocumentKey`] of an encrypted document.
+    ///
+    /// For nodes which store and forward the document without being able to read it. The same
+    /// as [`ShareMode::Read`] for documents which are not encrypted.
+    Pin,
 }
 
 /// Subscribe to events for a document.
@@ -441,6 +514,8 @@ pub enum ShareMode {
 pub struct DocSubscribeRequest {
     /// The document id
     pub doc_id: NamespaceId,
+    /// Filters and replay option for the subscription
+    pub options: SubscribeOptions,
 }
 
 impl Msg<ProviderService> for DocSubscribeRequest {
@@ -481,7 +556,10 @@ pub struct DocListResponse {
 
 /// Create a new document
 #[derive(Serialize, Deserialize, Debug)]
-pub struct DocCreateRequest {}
+pub struct DocCreateRequest {
+    /// Whether to encrypt the document with a new [`DocumentKey`].
+    pub encrypted: bool,
+}
 
 impl RpcMsg<ProviderService> for DocCreateRequest {
     type Response = RpcResult<DocCreateResponse>;
@@ -526,6 +604,54 @@ impl RpcMsg<ProviderService> for DocShareRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocShareResponse(pub DocTicket);
 
+/// Delegate write access to all keys starting with a prefix to an author, and share the document
+/// with read access over a ticket.
+///
+/// See [`iroh_sync::Delegation`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocDelegateRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// The author who may write
+    pub author: AuthorId,
+    /// The key prefix the author may write to
+    pub prefix: Bytes,
+    /// Time in microseconds since the unix epoch until which the author may write
+    pub expires: Option<u64>,
+}
+
+impl RpcMsg<ProviderService> for DocDelegateRequest {
+    type Response = RpcResult<DocDelegateResponse>;
+}
+
+/// The response to [`DocDelegateRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocDelegateResponse(pub DocTicket);
+
+/// Revoke delegated write access of an author.
+///
+/// See [`iroh_sync::Revocation`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocRevokeRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// The author whose delegations are revoked
+    pub author: AuthorId,
+    /// Only revoke the delegation for this key prefix
+    pub prefix: Option<Bytes>,
+}
+
+impl RpcMsg<ProviderService> for DocRevokeRequest {
+    type Response = RpcResult<DocRevokeResponse>;
+}
+
+/// The response to [`DocRevokeRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocRevokeResponse {
+    /// The number of revoked delegations
+    pub revoked: usize,
+}
+
 /// Get info on a document
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocStatusRequest {
@@ -783,6 +909,62 @@ pub enum DocExportProgress {
     Abort(RpcError),
 }
 
+/// A request to the node to write a document to an archive file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocExportArchiveRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// The filepath to where the archive should be saved
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs. Usually the cli will run on the same machine as the
+    /// node, so this should be an absolute path on the cli machine.
+    pub path: PathBuf,
+    /// Whether to include the content blobs of the entries in the archive.
+    pub include_content: bool,
+}
+
+impl RpcMsg<ProviderService> for DocExportArchiveRequest {
+    type Response = RpcResult<DocExportArchiveResponse>;
+}
+
+/// Response to [`DocExportArchiveRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocExportArchiveResponse {
+    /// The number of entries written to the archive.
+    pub entries: u64,
+    /// The number of content blobs written to the archive.
+    pub blobs: u64,
+}
+
+/// A request to the node to import a document from an archive file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocImportArchiveRequest {
+    /// The filepath of the archive
+    ///
+    /// This should be an absolute path valid for the file system on which
+    /// the node runs. Usually the cli will run on the same machine as the
+    /// node, so this should be an absolute path on the cli machine.
+    pub path: PathBuf,
+}
+
+impl RpcMsg<ProviderService> for DocImportArchiveRequest {
+    type Response = RpcResult<DocImportArchiveResponse>;
+}
+
+/// Response to [`DocImportArchiveRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocImportArchiveResponse {
+    /// The id of the imported document.
+    pub doc_id: NamespaceId,
+    /// The number of entries which were inserted.
+    ///
+    /// Entries for which the node already had the same or a newer entry are not counted.
+    pub entries: u64,
+    /// The number of content blobs which were imported.
+    pub blobs: u64,
+}
+
 /// Delete entries in a document
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocDelRequest {
@@ -828,6 +1010,42 @@ impl RpcMsg<ProviderService> for DocSetHashRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocSetHashResponse {}
 
+/// The content of an entry in a [`DocSetManyRequest`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DocSetValue {
+    /// The content, which is added to the blob store.
+    Bytes(Bytes),
+    /// The hash and size of content.
+    Hash {
+        /// Hash of the content.
+        hash: Hash,
+        /// Size of the content.
+        size: u64,
+    },
+}
+
+/// Set many entries of an author in a document in a single transaction
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocSetManyRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// Author of the entries.
+    pub author_id: AuthorId,
+    /// Keys and contents of the entries.
+    pub entries: Vec<(Bytes, DocSetValue)>,
+}
+
+impl RpcMsg<ProviderService> for DocSetManyRequest {
+    type Response = RpcResult<DocSetManyResponse>;
+}
+
+/// Response to [`DocSetManyRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocSetManyResponse {
+    /// The newly-created entries, without the ones for which a newer entry exists.
+    pub entries: Vec<SignedEntry>,
+}
+
 /// Get entries from a document
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocGetManyRequest {
@@ -911,6 +1129,117 @@ pub struct DocGetDownloadPolicyResponse {
     pub policy: DownloadPolicy,
 }
 
+/// Set the history policy of a document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocSetHistoryPolicyRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// History policy
+    pub policy: HistoryPolicy,
+}
+
+impl RpcMsg<ProviderService> for DocSetHistoryPolicyRequest {
+    type Response = RpcResult<DocSetHistoryPolicyResponse>;
+}
+
+/// Response to [`DocSetHistoryPolicyRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocSetHistoryPolicyResponse {}
+
+/// Get the history policy of a document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocGetHistoryPolicyRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+}
+
+impl RpcMsg<ProviderService> for DocGetHistoryPolicyRequest {
+    type Response = RpcResult<DocGetHistoryPolicyResponse>;
+}
+
+/// Response to [`DocGetHistoryPolicyRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocGetHistoryPolicyResponse {
+    /// The history policy
+    pub policy: HistoryPolicy,
+}
+
+/// Get the retained previous versions of the entries for a key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocHistoryRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// Key of the entries
+    pub key: Bytes,
+}
+
+impl RpcMsg<ProviderService> for DocHistoryRequest {
+    type Response = RpcResult<DocHistoryResponse>;
+}
+
+/// Response to [`DocHistoryRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocHistoryResponse {
+    /// The previous versions of all authors, newest first
+    pub entries: Vec<SignedEntry>,
+}
+
+/// Remove the deletion markers of a document which are older than a horizon
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocCompactRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+    /// Timestamp before which deletion markers are removed, in microseconds since the unix epoch
+    pub horizon: u64,
+}
+
+impl RpcMsg<ProviderService> for DocCompactRequest {
+    type Response = RpcResult<DocCompactResponse>;
+}
+
+/// Response to [`DocCompactRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocCompactResponse {
+    /// The number of removed deletion markers
+    pub removed: usize,
+}
+
+/// Get the peers of a document whose clocks are ahead of ours by an excessive amount
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocClockSkewRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+}
+
+impl RpcMsg<ProviderService> for DocClockSkewRequest {
+    type Response = RpcResult<DocClockSkewResponse>;
+}
+
+/// Response to [`DocClockSkewRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocClockSkewResponse {
+    /// The skew observed for each peer, largest skew first
+    pub peers: Vec<(PublicKey, ClockSkew)>,
+}
+
+/// Get the key of an encrypted document
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocGetKeyRequest {
+    /// The document id
+    pub doc_id: NamespaceId,
+}
+
+impl RpcMsg<ProviderService> for DocGetKeyRequest {
+    type Response = RpcResult<DocGetKeyResponse>;
+}
+
+/// Response to [`DocGetKeyRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocGetKeyResponse {
+    /// The document key, `None` if the document is not encrypted
+    pub key: Option<DocumentKey>,
+}
+
 /// Get the bytes for a hash
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BlobReadRequest {
@@ -1010,6 +1339,7 @@ pub enum ProviderRequest {
     NodeConnections(NodeConnectionsRequest),
     NodeConnectionInfo(NodeConnectionInfoRequest),
     NodeWatch(NodeWatchRequest),
+    NodeNetcheck(NodeNetcheckRequest),
 
     BlobRead(BlobReadRequest),
     BlobAddStream(BlobAddStreamRequest),
@@ -1034,17 +1364,28 @@ pub enum ProviderRequest {
     DocImport(DocImportRequest),
     DocSet(DocSetRequest),
     DocSetHash(DocSetHashRequest),
+    DocSetMany(DocSetManyRequest),
     DocGet(DocGetManyRequest),
     DocGetExact(DocGetExactRequest),
     DocImportFile(DocImportFileRequest),
     DocExportFile(DocExportFileRequest),
+    DocExportArchive(DocExportArchiveRequest),
+    DocImportArchive(DocImportArchiveRequest),
     DocDel(DocDelRequest),
     DocStartSync(DocStartSyncRequest),
     DocLeave(DocLeaveRequest),
     DocShare(DocShareRequest),
+    DocDelegate(DocDelegateRequest),
+    DocRevoke(DocRevokeRequest),
     DocSubscribe(DocSubscribeRequest),
     DocGetDownloadPolicy(DocGetDownloadPolicyRequest),
     DocSetDownloadPolicy(DocSetDownloadPolicyRequest),
+    DocGetKey(DocGetKeyRequest),
+    DocSetHistoryPolicy(DocSetHistoryPolicyRequest),
+    DocGetHistoryPolicy(DocGetHistoryPolicyRequest),
+    DocHistory(DocHistoryRequest),
+    DocCompact(DocCompactRequest),
+    DocClockSkew(DocClockSkewRequest),
 
     AuthorList(AuthorListRequest),
     AuthorCreate(AuthorCreateRequest),
@@ -1061,6 +1402,7 @@ pub enum ProviderResponse {
     NodeConnectionInfo(RpcResult<NodeConnectionInfoResponse>),
     NodeShutdown(()),
     NodeWatch(NodeWatchResponse),
+    NodeNetcheck(RpcResult<NodeNetcheckResponse>),
 
     BlobRead(RpcResult<BlobReadResponse>),
     BlobAddStream(BlobAddStreamResponse),
@@ -1083,17 +1425,28 @@ pub enum ProviderResponse {
     DocImport(RpcResult<DocImportResponse>),
     DocSet(RpcResult<DocSetResponse>),
     DocSetHash(RpcResult<DocSetHashResponse>),
+    DocSetMany(RpcResult<DocSetManyResponse>),
     DocGet(RpcResult<DocGetManyResponse>),
     DocGetExact(RpcResult<DocGetExactResponse>),
     DocImportFile(DocImportFileResponse),
     DocExportFile(DocExportFileResponse),
+    DocExportArchive(RpcResult<DocExportArchiveResponse>),
+    DocImportArchive(RpcResult<DocImportArchiveResponse>),
     DocDel(RpcResult<DocDelResponse>),
     DocShare(RpcResult<DocShareResponse>),
+    DocDelegate(RpcResult<DocDelegateResponse>),
+    DocRevoke(RpcResult<DocRevokeResponse>),
     DocStartSync(RpcResult<DocStartSyncResponse>),
     DocLeave(RpcResult<DocLeaveResponse>),
     DocSubscribe(RpcResult<DocSubscribeResponse>),
     DocGetDownloadPolicy(RpcResult<DocGetDownloadPolicyResponse>),
     DocSetDownloadPolicy(RpcResult<DocSetDownloadPolicyResponse>),
+    DocGetKey(RpcResult<DocGetKeyResponse>),
+    DocSetHistoryPolicy(RpcResult<DocSetHistoryPolicyResponse>),
+    DocGetHistoryPolicy(RpcResult<DocGetHistoryPolicyResponse>),
+    DocHistory(RpcResult<DocHistoryResponse>),
+    DocCompact(RpcResult<DocCompactResponse>),
+    DocClockSkew(RpcResult<DocClockSkewResponse>),
 
     AuthorList(RpcResult<AuthorListResponse>),
     AuthorCreate(RpcResult<AuthorCreateResponse>),
@@ -2,9 +2,10 @@
 //!
 //! [`iroh_sync::Replica`] is also called documents here.
 
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use futures::{
     future::{BoxFuture, FutureExt, Shared},
     Stream, TryStreamExt,
@@ -12,14 +13,18 @@ use futures::{
 use iroh_bytes::{store::EntryStatus, Hash};
 use iroh_gossip::net::Gossip;
 use iroh_net::{key::PublicKey, MagicEndpoint, NodeAddr};
-use iroh_sync::{actor::SyncHandle, ContentStatus, ContentStatusCallback, Entry, NamespaceId};
+use iroh_sync::{
+    actor::SyncHandle,
+    store::{AuthorFilter, KeyFilter, Query},
+    AuthorId, ContentStatus, ContentStatusCallback, Entry, NamespaceId, SignedEntry,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, oneshot};
-use tokio_stream::StreamExt;
 use tracing::{error, error_span, Instrument};
 
 use crate::downloader::Downloader;
 
+pub(crate) mod archive;
 mod gossip;
 mod live;
 pub mod rpc;
@@ -30,12 +35,14 @@ use live::{LiveActor, ToLiveActor};
 
 pub use self::live::SyncEvent;
 pub use self::state::{Origin, SyncReason};
-pub use iroh_sync::net::SYNC_ALPN;
+pub use iroh_sync::net::{SYNC_ALPN, SYNC_ALPN_V1};
 
 /// Capacity of the channel for the [`ToLiveActor`] messages.
 const ACTOR_CHANNEL_CAP: usize = 64;
 /// Capacity for the channels for [`SyncEngine::subscribe`].
 const SUBSCRIBE_CHANNEL_CAP: usize = 256;
+/// Maximum number of events buffered while a subscription replays the current entries.
+const REPLAY_BUFFER_CAP: usize = 1024;
 
 /// The sync engine coordinates actors that manage open documents, set-reconciliation syncs with
 /// peers and a gossip swarm for each syncing document.
@@ -183,9 +190,13 @@ impl SyncEngine {
     }
 
     /// Subscribe to replica and sync progress events.
+    ///
+    /// Only the events matching `options` are emitted. If the options request a replay, the
+    /// current matching entries are emitted first, see [`SubscribeOptions::replay`].
     pub fn subscribe(
         &self,
         namespace: NamespaceId,
+        options: SubscribeOptions,
     ) -> impl Stream<Item = Result<LiveEvent>> + Unpin + 'static {
         let content_status_cb = self.content_status_cb.clone();
 
@@ -193,33 +204,50 @@ impl SyncEngine {
         // We clone `self` so that the future does not capture any lifetimes.
         let this = self.clone();
         let fut = async move {
-            // Subscribe to insert events from the replica.
-            let replica_events = {
-                let (s, r) = flume::bounded(SUBSCRIBE_CHANNEL_CAP);
-                this.sync.subscribe(namespace, s).await?;
-                r.into_stream()
-                    .map(move |ev| LiveEvent::from_replica_event(ev, &content_status_cb))
-            };
+            // Subscribe to insert events from the replica. The events are filtered before they
+            // are sent, so that events which do not match never wait for a full channel.
+            let (s, replica_events) = flume::bounded(SUBSCRIBE_CHANNEL_CAP);
+            let filter = options.clone();
+            let filter =
+                Arc::new(move |event: &iroh_sync::Event| filter.matches_replica_event(event));
+            this.sync.subscribe_filtered(namespace, s, filter).await?;
 
             // Subscribe to events from the [`live::Actor`].
-            let sync_events = {
-                let (s, r) = flume::bounded(SUBSCRIBE_CHANNEL_CAP);
-                let (reply, reply_rx) = oneshot::channel();
-                this.to_live_actor
-                    .send(ToLiveActor::Subscribe {
-                        namespace,
-                        sender: s,
-                        reply,
-                    })
-                    .await?;
-                reply_rx.await??;
-                r.into_stream().map(|event| Ok(LiveEvent::from(event)))
-            };
+            let (s, sync_events) = flume::bounded(SUBSCRIBE_CHANNEL_CAP);
+            let (reply, reply_rx) = oneshot::channel();
+            this.to_live_actor
+                .send(ToLiveActor::Subscribe {
+                    namespace,
+                    sender: s,
+                    options: options.clone(),
+                    reply,
+                })
+                .await?;
+            reply_rx.await??;
 
-            // Merge the two receivers into a single stream.
-            let stream = replica_events.merge(sync_events);
+            // Read the current entries after subscribing, so that no insert is missed.
+            let entries = match options.replay {
+                true => {
+                    let (s, entries) = flume::bounded(SUBSCRIBE_CHANNEL_CAP);
+                    this.sync.get_many(namespace, options.query(), s).await?;
+                    Some(entries)
+                }
+                false => None,
+            };
+            let subscription = Subscription {
+                entries,
+                replica_events,
+                sync_events,
+                buffer: Default::default(),
+                closed: false,
+                content_status_cb,
+            };
+            let stream = futures::stream::unfold(subscription, |mut subscription| async move {
+                let event = subscription.next().await?;
+                Some((event, subscription))
+            });
             // We need type annotations for the error type here.
-            Result::<_, anyhow::Error>::Ok(stream)
+            Result::<_, anyhow::Error>::Ok(Box::pin(stream))
         };
 
         // Flatten the future into a single stream. If the future errors, the error will be
@@ -229,7 +257,7 @@ impl SyncEngine {
     }
 
     /// Handle an incoming iroh-sync connection.
-    pub async fn handle_connection(&self, conn: quinn::Connecting) -> anyhow::Result<()> {
+    pub async fn handle_connection(&self, conn: quinn::Connection) -> anyhow::Result<()> {
         self.to_live_actor
             .send(ToLiveActor::HandleConnection { conn })
             .await?;
@@ -244,6 +272,71 @@ impl SyncEngine {
     }
 }
 
+/// The state of a subscription to the events of a document.
+#[derive(derive_more::Debug)]
+struct Subscription {
+    /// The current entries, while they are replayed.
+    entries: Option<flume::Receiver<Result<SignedEntry>>>,
+    replica_events: flume::Receiver<iroh_sync::Event>,
+    sync_events: flume::Receiver<live::Event>,
+    /// The events which arrived while the entries were replayed.
+    buffer: VecDeque<Result<LiveEvent>>,
+    /// Whether the subscription ended because too many events arrived during the replay.
+    closed: bool,
+    #[debug("ContentStatusCallback")]
+    content_status_cb: ContentStatusCallback,
+}
+
+impl Subscription {
+    /// Get the next event: the replayed entries first, then the events in order of arrival.
+    ///
+    /// The events which arrive while the entries are replayed are buffered, so that the actors
+    /// do not block on a full subscriber channel, up to [`REPLAY_BUFFER_CAP`] events.
+    async fn next(&mut self) -> Option<Result<LiveEvent>> {
+        if self.closed {
+            return None;
+        }
+        if let Some(entries) = self.entries.clone() {
+            loop {
+                let event = tokio::select! {
+                    entry = entries.recv_async() => match entry {
+                        Ok(entry) => {
+                            return Some(entry.map(|entry| LiveEvent::ReplayEntry {
+                                entry: entry.into(),
+                            }))
+                        }
+                        Err(_) => {
+                            self.entries = None;
+                            return Some(Ok(LiveEvent::ReplayFinished));
+                        }
+                    },
+                    Ok(event) = self.replica_events.recv_async() => {
+                        LiveEvent::from_replica_event(event, &self.content_status_cb)
+                    }
+                    Ok(event) = self.sync_events.recv_async() => Ok(LiveEvent::from(event)),
+                };
+                if self.buffer.len() >= REPLAY_BUFFER_CAP {
+                    self.closed = true;
+                    return Some(Err(anyhow!(
+                        "more than {REPLAY_BUFFER_CAP} events arrived while replaying the entries"
+                    )));
+                }
+                self.buffer.push_back(event);
+            }
+        }
+        if let Some(event) = self.buffer.pop_front() {
+            return Some(event);
+        }
+        tokio::select! {
+            Ok(event) = self.replica_events.recv_async() => {
+                Some(LiveEvent::from_replica_event(event, &self.content_status_cb))
+            }
+            Ok(event) = self.sync_events.recv_async() => Some(Ok(LiveEvent::from(event))),
+            else => None,
+        }
+    }
+}
+
 pub(crate) fn entry_to_content_status(entry: EntryStatus) -> ContentStatus {
     match entry {
         EntryStatus::Complete => ContentStatus::Complete,
@@ -280,12 +373,161 @@ pub enum LiveEvent {
     NeighborDown(PublicKey),
     /// A set-reconciliation sync finished.
     SyncFinished(SyncEvent),
+    /// An entry which existed when the subscription started.
+    ///
+    /// Only emitted for subscriptions which replay the current entries, before all other events.
+    ReplayEntry {
+        /// The existing entry.
+        entry: Entry,
+    },
+    /// All existing entries were replayed, the following events are live.
+    ReplayFinished,
+}
+
+/// Kinds of [`LiveEvent`]s, to filter a subscription by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, strum::Display)]
+pub enum LiveEventKind {
+    /// [`LiveEvent::InsertLocal`]
+    InsertLocal,
+    /// [`LiveEvent::InsertRemote`]
+    InsertRemote,
+    /// [`LiveEvent::ContentReady`]
+    ContentReady,
+    /// [`LiveEvent::NeighborUp`]
+    NeighborUp,
+    /// [`LiveEvent::NeighborDown`]
+    NeighborDown,
+    /// [`LiveEvent::SyncFinished`]
+    SyncFinished,
+}
+
+/// Options for a subscription to the events of a document.
+///
+/// The filters are evaluated by the node before an event is sent to the subscription, so events
+/// which do not match never cross the RPC boundary. The key and author filters apply to events
+/// for entries, and to [`LiveEvent::ContentReady`] by the entry whose content was downloaded.
+/// Events without an entry, like [`LiveEvent::NeighborUp`], are only filtered by their kind.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubscribeOptions {
+    filter_key: KeyFilter,
+    filter_author: AuthorFilter,
+    filter_kinds: Option<Vec<LiveEventKind>>,
+    replay: bool,
+}
+
+impl SubscribeOptions {
+    /// Only emit events for entries with exactly this key.
+    pub fn key_exact(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.filter_key = KeyFilter::Exact(Bytes::copy_from_slice(key.as_ref()));
+        self
+    }
+
+    /// Only emit events for entries whose key starts with `prefix`.
+    pub fn key_prefix(mut self, prefix: impl AsRef<[u8]>) -> Self {
+        self.filter_key = KeyFilter::Prefix(Bytes::copy_from_slice(prefix.as_ref()));
+        self
+    }
+
+    /// Only emit events for entries by `author`.
+    pub fn author(mut self, author: AuthorId) -> Self {
+        self.filter_author = AuthorFilter::Exact(author);
+        self
+    }
+
+    /// Only emit events of these kinds.
+    pub fn kinds(mut self, kinds: impl IntoIterator<Item = LiveEventKind>) -> Self {
+        self.filter_kinds = Some(kinds.into_iter().collect());
+        self
+    }
+
+    /// Emit the current entries matching the key and author filters first, as
+    /// [`LiveEvent::ReplayEntry`] followed by [`LiveEvent::ReplayFinished`].
+    ///
+    /// Entries inserted while the replay is read may be emitted both as replayed entry and as
+    /// insert event. The other events are held back until the replay finished; if more than
+    /// 1024 events arrive meanwhile, the subscription fails.
+    pub fn replay(mut self) -> Self {
+        self.replay = true;
+        self
+    }
+
+    /// Get the key filter of these options.
+    pub fn key_filter(&self) -> &KeyFilter {
+        &self.filter_key
+    }
+
+    /// Replace the key filter of these options.
+    pub fn set_key_filter(&mut self, filter: KeyFilter) {
+        self.filter_key = filter;
+    }
+
+    /// Whether `event` passes the filters of these options.
+    pub fn matches(&self, event: &LiveEvent) -> bool {
+        match event {
+            LiveEvent::InsertLocal { entry } => {
+                self.matches_kind(LiveEventKind::InsertLocal)
+                    && self.matches_entry(&entry.author(), entry.key())
+            }
+            LiveEvent::InsertRemote { entry, .. } => {
+                self.matches_kind(LiveEventKind::InsertRemote)
+                    && self.matches_entry(&entry.author(), entry.key())
+            }
+            LiveEvent::ContentReady { .. } => self.matches_kind(LiveEventKind::ContentReady),
+            LiveEvent::NeighborUp(_) => self.matches_kind(LiveEventKind::NeighborUp),
+            LiveEvent::NeighborDown(_) => self.matches_kind(LiveEventKind::NeighborDown),
+            LiveEvent::SyncFinished(_) => self.matches_kind(LiveEventKind::SyncFinished),
+            LiveEvent::ReplayEntry { entry } => self.matches_entry(&entry.author(), entry.key()),
+            LiveEvent::ReplayFinished => true,
+        }
+    }
+
+    /// Whether an event of the replica passes the filters of these options.
+    fn matches_replica_event(&self, event: &iroh_sync::Event) -> bool {
+        let (kind, entry) = match event {
+            iroh_sync::Event::LocalInsert { entry, .. } => (LiveEventKind::InsertLocal, entry),
+            iroh_sync::Event::RemoteInsert { entry, .. } => (LiveEventKind::InsertRemote, entry),
+        };
+        self.matches_kind(kind) && self.matches_entry(&entry.author(), entry.key())
+    }
+
+    /// Whether an event of the live actor passes the filters of these options.
+    fn matches_sync_event(&self, event: &live::Event) -> bool {
+        match event {
+            live::Event::ContentReady { entry, .. } => {
+                self.matches_kind(LiveEventKind::ContentReady)
+                    && self.matches_entry(&entry.author(), entry.key())
+            }
+            live::Event::NeighborUp(_) => self.matches_kind(LiveEventKind::NeighborUp),
+            live::Event::NeighborDown(_) => self.matches_kind(LiveEventKind::NeighborDown),
+            live::Event::SyncFinished(_) => self.matches_kind(LiveEventKind::SyncFinished),
+        }
+    }
+
+    fn matches_kind(&self, kind: LiveEventKind) -> bool {
+        self.filter_kinds
+            .as_ref()
+            .map_or(true, |kinds| kinds.contains(&kind))
+    }
+
+    fn matches_entry(&self, author: &AuthorId, key: &[u8]) -> bool {
+        self.filter_key.matches(key) && self.filter_author.matches(author)
+    }
+
+    /// The query for the entries of a replay.
+    fn query(&self) -> Query {
+        let mut query = match self.filter_author {
+            AuthorFilter::Any => Query::all().build(),
+            AuthorFilter::Exact(author) => Query::author(author).build(),
+        };
+        query.set_key_filter(self.filter_key.clone());
+        query
+    }
 }
 
 impl From<live::Event> for LiveEvent {
     fn from(ev: live::Event) -> Self {
         match ev {
-            live::Event::ContentReady { hash } => Self::ContentReady { hash },
+            live::Event::ContentReady { hash, .. } => Self::ContentReady { hash },
             live::Event::NeighborUp(peer) => Self::NeighborUp(peer),
             live::Event::NeighborDown(peer) => Self::NeighborDown(peer),
             live::Event::SyncFinished(ev) => Self::SyncFinished(ev),
@@ -0,0 +1,350 @@
+//! Portable document archives.
+//!
+//! An archive is a single file with the signed entries of a document and, optionally, the content
+//! blobs they reference. It allows moving a document to a node which has no connection to the
+//! peers of the document.
+//!
+//! Nothing in an archive is trusted on import: the signatures of all entries are verified before
+//! they are inserted, and every blob is verified against its hash while it is written to the blob
+//! store. Blobs are stored in their bao encoding, which contains the outboard, so that it does not
+//! have to be computed again on import.
+//!
+//! The file starts with [`MAGIC`] and a big-endian `u16` format version, followed by frames of a
+//! big-endian `u32` length and a postcard encoded [`Frame`]. A [`Frame::Blob`] is followed by the
+//! bao encoding of the blob.
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bao_tree::{
+    io::{
+        fsm::{
+            encode_ranges_validated, BaoContentItem, OutboardMut, ResponseDecoderReadingNext,
+            ResponseDecoderStart,
+        },
+        Leaf, Parent,
+    },
+    ChunkRanges,
+};
+use iroh_bytes::{
+    store::{MapEntry, PartialMapEntry, Store as BaoStore},
+    Hash, HashAndFormat, IROH_BLOCK_SIZE,
+};
+use iroh_io::{AsyncSliceWriter, TokioStreamWriter};
+use iroh_sync::{
+    actor::SyncHandle, store::Query, Capability, Delegation, NamespaceId, SignedEntry,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tracing::warn;
+
+/// Magic bytes at the start of every archive.
+const MAGIC: &[u8; 16] = b"iroh-doc-archive";
+/// Version of the archive format.
+const VERSION: u16 = 2;
+/// Maximum size of an encoded frame, to not allocate arbitrary amounts of memory on import.
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Capacity of the channel for reading entries from the store.
+const CHANNEL_CAP: usize = 64;
+/// Number of entries which are verified and inserted together on import.
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// A frame of an archive.
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    /// The first frame, identifying the document.
+    Header {
+        namespace: NamespaceId,
+        delegations: Vec<Delegation>,
+    },
+    /// A signed entry of the document.
+    Entry(SignedEntry),
+    /// A blob of `size` bytes, followed by its bao encoding.
+    ///
+    /// Blob frames follow all entry frames, and only blobs referenced by an entry are allowed.
+    Blob { hash: Hash, size: u64 },
+    /// The last frame, to detect truncated archives.
+    End { entries: u64, blobs: u64 },
+}
+
+/// Number of items written to or read from an archive.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ArchiveStats {
+    /// Number of entries.
+    pub entries: u64,
+    /// Number of content blobs.
+    pub blobs: u64,
+}
+
+/// Write the entries of a document to an archive at `path`.
+///
+/// With `include_content`, all complete content blobs referenced by the entries are written as
+/// well. Blobs which are not completely available on this node are skipped.
+pub(crate) async fn export<B: BaoStore>(
+    sync: &SyncHandle,
+    bao_store: &B,
+    namespace: NamespaceId,
+    path: &Path,
+    include_content: bool,
+) -> Result<ArchiveStats> {
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    sync.open(namespace, Default::default()).await?;
+    let res = write_archive(sync, bao_store, namespace, file, include_content).await;
+    sync.close(namespace).await?;
+    res
+}
+
+async fn write_archive<B: BaoStore>(
+    sync: &SyncHandle,
+    bao_store: &B,
+    namespace: NamespaceId,
+    file: tokio::fs::File,
+    include_content: bool,
+) -> Result<ArchiveStats> {
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC).await?;
+    writer.write_u16(VERSION).await?;
+
+    let delegations = sync.get_delegations(namespace).await?;
+    write_frame(
+        &mut writer,
+        &Frame::Header {
+            namespace,
+            delegations,
+        },
+    )
+    .await?;
+
+    let mut stats = ArchiveStats::default();
+    let mut hashes = BTreeSet::new();
+    let (tx, rx) = flume::bounded(CHANNEL_CAP);
+    let query = Query::all().include_empty().build();
+    sync.get_many(namespace, query, tx).await?;
+    while let Ok(entry) = rx.recv_async().await {
+        let entry = entry?;
+        if !entry.is_empty() {
+            hashes.insert(entry.content_hash());
+        }
+        write_frame(&mut writer, &Frame::Entry(entry)).await?;
+        stats.entries += 1;
+    }
+
+    if include_content {
+        for hash in hashes {
+            let entry = match bao_store.get(&hash) {
+                Some(entry) if entry.is_complete() => entry,
+                _ => {
+                    warn!(%hash, "skipping blob which is not complete");
+                    continue;
+                }
+            };
+            let size = entry.size();
+            write_frame(&mut writer, &Frame::Blob { hash, size }).await?;
+            encode_ranges_validated(
+                entry.data_reader().await?,
+                entry.outboard().await?,
+                &ChunkRanges::all(),
+                TokioStreamWriter(&mut writer),
+            )
+            .await?;
+            stats.blobs += 1;
+        }
+    }
+
+    write_frame(
+        &mut writer,
+        &Frame::End {
+            entries: stats.entries,
+            blobs: stats.blobs,
+        },
+    )
+    .await?;
+    writer.flush().await?;
+    writer.into_inner().sync_all().await?;
+    Ok(stats)
+}
+
+/// Import a document from the archive at `path`.
+///
+/// The document is added with a read capability, unless the node already has one. Entries are
+/// verified and inserted in batches while the archive is read, so an archive which turns out to
+/// be invalid can leave some of its entries in the document. The document is left open like one
+/// imported from a ticket. Returns the id of the document and the number of entries and blobs
+/// which were added.
+pub(crate) async fn import<B: BaoStore>(
+    sync: &SyncHandle,
+    bao_store: &B,
+    path: &Path,
+) -> Result<(NamespaceId, ArchiveStats)> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 16];
+    reader.read_exact(&mut magic).await?;
+    ensure!(&magic == MAGIC, "not a document archive");
+    let version = reader.read_u16().await?;
+    ensure!(
+        version == VERSION,
+        "unsupported document archive version {version}"
+    );
+
+    let (namespace, delegations) = match read_frame(&mut reader).await? {
+        Frame::Header {
+            namespace,
+            delegations,
+        } => (namespace, delegations),
+        _ => bail!("document archive does not start with a header"),
+    };
+
+    sync.import_namespace(Capability::Read(namespace)).await?;
+    sync.import_delegations(delegations.clone()).await?;
+    sync.open(namespace, Default::default()).await?;
+    match read_archive(sync, bao_store, namespace, &delegations, reader).await {
+        Ok(stats) => Ok((namespace, stats)),
+        Err(err) => {
+            sync.close(namespace).await?;
+            Err(err)
+        }
+    }
+}
+
+async fn read_archive<B: BaoStore>(
+    sync: &SyncHandle,
+    bao_store: &B,
+    namespace: NamespaceId,
+    delegations: &[Delegation],
+    mut reader: impl AsyncRead + Unpin,
+) -> Result<ArchiveStats> {
+    let mut stats = ArchiveStats::default();
+    let mut read = ArchiveStats::default();
+    let mut hashes = BTreeSet::new();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    loop {
+        match read_frame(&mut reader).await? {
+            Frame::Header { .. } => bail!("unexpected header in document archive"),
+            Frame::Entry(entry) => {
+                ensure!(
+                    read.blobs == 0,
+                    "unexpected entry after blobs in document archive"
+                );
+                ensure!(
+                    entry.namespace() == namespace,
+                    "entry of another document in archive"
+                );
+                entry
+                    .verify(&())
+                    .or_else(|_| entry.verify_delegated(&(), delegations))
+                    .map_err(|_| anyhow!("invalid signature for entry in document archive"))?;
+                if !entry.is_empty() {
+                    hashes.insert(entry.content_hash());
+                }
+                batch.push(entry);
+                read.entries += 1;
+                if batch.len() == IMPORT_BATCH_SIZE {
+                    stats.entries += import_batch(sync, namespace, &mut batch).await?;
+                }
+            }
+            Frame::Blob { hash, size } => {
+                // The entries protect the content from garbage collection once it is complete.
+                stats.entries += import_batch(sync, namespace, &mut batch).await?;
+                ensure!(
+                    hashes.contains(&hash),
+                    "unreferenced blob {hash} in document archive"
+                );
+                import_blob(bao_store, &mut reader, hash, size).await?;
+                read.blobs += 1;
+            }
+            Frame::End { entries, blobs } => {
+                ensure!(
+                    entries == read.entries && blobs == read.blobs,
+                    "document archive is incomplete"
+                );
+                stats.entries += import_batch(sync, namespace, &mut batch).await?;
+                break;
+            }
+        }
+    }
+    stats.blobs = read.blobs;
+    Ok(stats)
+}
+
+/// Insert the entries of `batch` into the document and return how many of them were added.
+async fn import_batch(
+    sync: &SyncHandle,
+    namespace: NamespaceId,
+    batch: &mut Vec<SignedEntry>,
+) -> Result<u64> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let entries = std::mem::replace(batch, Vec::with_capacity(IMPORT_BATCH_SIZE));
+    let inserted = sync.import_entries(namespace, entries).await?;
+    Ok(inserted as u64)
+}
+
+/// Import the bao encoding of a blob from `reader` into the blob store.
+///
+/// The content and the outboard are verified against `hash` while they are written, like for a
+/// download.
+async fn import_blob<B: BaoStore>(
+    bao_store: &B,
+    reader: &mut (impl AsyncRead + Unpin),
+    hash: Hash,
+    size: u64,
+) -> Result<()> {
+    let _tag = bao_store.temp_tag(HashAndFormat::raw(hash));
+    let decoder =
+        ResponseDecoderStart::new(hash.into(), ChunkRanges::all(), IROH_BLOCK_SIZE, reader);
+    let (mut reading, encoded_size) = decoder.next().await?;
+    ensure!(
+        encoded_size == size,
+        "size of blob {hash} in document archive does not match its encoding"
+    );
+    let entry = bao_store.get_or_create_partial(hash, size)?;
+    let mut data_writer = entry.data_writer().await?;
+    // Small blobs have no outboard, so it is only created for the first parent.
+    let mut outboard = None;
+    while let ResponseDecoderReadingNext::More((next, item)) = reading.next().await {
+        match item.with_context(|| {
+            format!("content of blob {hash} in document archive does not match its hash")
+        })? {
+            BaoContentItem::Parent(Parent { node, pair }) => {
+                let outboard = match outboard.as_mut() {
+                    Some(outboard) => outboard,
+                    None => outboard.insert(entry.outboard_mut().await?),
+                };
+                outboard.save(node, &pair).await?;
+            }
+            BaoContentItem::Leaf(Leaf { offset, data }) => {
+                data_writer.write_bytes_at(offset.0, data).await?;
+            }
+        }
+        reading = next;
+    }
+    data_writer.sync().await?;
+    if let Some(mut outboard) = outboard {
+        outboard.sync().await?;
+    }
+    bao_store.insert_complete(entry).await?;
+    Ok(())
+}
+
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), frame: &Frame) -> Result<()> {
+    let bytes = postcard::to_stdvec(frame)?;
+    ensure!(bytes.len() <= MAX_FRAME_SIZE, "archive frame too large");
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Frame> {
+    let len = reader.read_u32().await? as usize;
+    ensure!(len <= MAX_FRAME_SIZE, "archive frame too large");
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    let frame = postcard::from_bytes(&bytes)?;
+    Ok(frame)
+}
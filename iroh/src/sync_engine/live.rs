@@ -14,7 +14,7 @@ use iroh_sync::{
         connect_and_sync, handle_connection, AbortReason, AcceptError, AcceptOutcome, ConnectError,
         SyncFinished,
     },
-    AuthorHeads, ContentStatus, NamespaceId, SignedEntry,
+    AuthorHeads, ContentStatus, NamespaceId, RecordIdentifier, SignedEntry,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -25,6 +25,7 @@ use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
 
 use super::gossip::ToGossipActor;
 use super::state::{NamespaceStates, Origin, SyncReason};
+use super::SubscribeOptions;
 
 /// An iroh-sync operation
 ///
@@ -73,11 +74,12 @@ pub enum ToLiveActor {
         namespace: NamespaceId,
         #[debug("sender")]
         sender: flume::Sender<Event>,
+        options: SubscribeOptions,
         #[debug("oneshot::Sender")]
         reply: sync::oneshot::Sender<Result<()>>,
     },
     HandleConnection {
-        conn: quinn::Connecting,
+        conn: quinn::Connection,
     },
     AcceptSyncRequest {
         namespace: NamespaceId,
@@ -107,6 +109,8 @@ pub enum Event {
     ContentReady {
         /// The content hash of the newly available entry content
         hash: Hash,
+        /// The entry for which the content was downloaded
+        entry: RecordIdentifier,
     },
     /// We have a new neighbor in the swarm.
     NeighborUp(PublicKey),
@@ -147,7 +151,7 @@ pub struct LiveActor<B: iroh_bytes::store::Store> {
     /// Running sync futures (from accept).
     running_sync_accept: JoinSet<SyncAcceptRes>,
     /// Runnning download futures.
-    pending_downloads: JoinSet<Option<(NamespaceId, Hash)>>,
+    pending_downloads: JoinSet<Option<(NamespaceId, Hash, RecordIdentifier)>>,
 
     /// Subscribers to actor events
     subscribers: SubscribersMap,
@@ -232,8 +236,8 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
                 Some(res) = self.pending_downloads.join_next(), if !self.pending_downloads.is_empty() => {
                     trace!(?i, "tick: pending_downloads");
                     let res = res.context("pending_downloads closed")?;
-                    if let Some((namespace, hash)) = res {
-                        self.subscribers.send(&namespace, Event::ContentReady { hash }).await;
+                    if let Some((namespace, hash, entry)) = res {
+                        self.subscribers.send(&namespace, Event::ContentReady { hash, entry }).await;
                         // Inform our neighbors that we have new content ready.
                         self.broadcast_neighbors(namespace, &Op::ContentReady(hash)).await;
                     }
@@ -293,9 +297,10 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
             ToLiveActor::Subscribe {
                 namespace,
                 sender,
+                options,
                 reply,
             } => {
-                self.subscribers.subscribe(namespace, sender);
+                self.subscribers.subscribe(namespace, sender, options);
                 reply.send(Ok(())).ok();
             }
             ToLiveActor::HandleConnection { conn } => {
@@ -595,6 +600,13 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
                 return;
             }
         };
+        if let Err(err) = self
+            .sync
+            .observe_peer_heads(namespace, *from.as_bytes(), heads.clone())
+            .await
+        {
+            warn!("sync actor error: {err:?}");
+        }
         match self.sync.has_news_for_us(report.namespace, heads).await {
             Ok(Some(updated_authors)) => {
                 info!(%updated_authors, "news reported: sync now");
@@ -645,10 +657,11 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
                         .queue(DownloadKind::Blob { hash }, vec![(from, role).into()])
                         .await;
 
+                    let id = entry.id().clone();
                     self.pending_downloads.spawn(async move {
                         // NOTE: this ignores the result for now, simply keeping the option
                         let res = handle.await.ok();
-                        res.map(|_| (namespace, hash))
+                        res.map(|_| (namespace, hash, id))
                     });
                 }
             }
@@ -658,7 +671,7 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
     }
 
     #[instrument("accept", skip_all)]
-    pub async fn handle_connection(&mut self, conn: quinn::Connecting) {
+    pub async fn handle_connection(&mut self, conn: quinn::Connection) {
         let to_actor_tx = self.sync_actor_tx.clone();
         let accept_request_cb = move |namespace, peer| {
             let to_actor_tx = to_actor_tx.clone();
@@ -719,8 +732,16 @@ pub struct SyncEvent {
 struct SubscribersMap(HashMap<NamespaceId, Subscribers>);
 
 impl SubscribersMap {
-    fn subscribe(&mut self, namespace: NamespaceId, sender: flume::Sender<Event>) {
-        self.0.entry(namespace).or_default().subscribe(sender);
+    fn subscribe(
+        &mut self,
+        namespace: NamespaceId,
+        sender: flume::Sender<Event>,
+        options: SubscribeOptions,
+    ) {
+        self.0
+            .entry(namespace)
+            .or_default()
+            .subscribe(sender, options);
     }
 
     async fn send(&mut self, namespace: &NamespaceId, event: Event) -> bool {
@@ -744,15 +765,25 @@ impl SubscribersMap {
 }
 
 #[derive(Debug, Default)]
-struct Subscribers(Vec<flume::Sender<Event>>);
+struct Subscribers(Vec<(flume::Sender<Event>, SubscribeOptions)>);
 
 impl Subscribers {
-    fn subscribe(&mut self, sender: flume::Sender<Event>) {
-        self.0.push(sender)
+    fn subscribe(&mut self, sender: flume::Sender<Event>, options: SubscribeOptions) {
+        self.0.push((sender, options))
     }
 
+    /// Send `event` to the subscribers whose options match it.
     async fn send(&mut self, event: Event) -> bool {
-        let futs = self.0.iter().map(|sender| sender.send_async(event.clone()));
+        let futs = self.0.iter().map(|(sender, options)| {
+            let matches = options.matches_sync_event(&event);
+            let event = event.clone();
+            async move {
+                match matches {
+                    true => sender.send_async(event).await,
+                    false => Ok(()),
+                }
+            }
+        });
         let res = futures::future::join_all(futs).await;
         // reverse the order so removing does not shift remaining indices
         for (i, res) in res.into_iter().enumerate().rev() {
@@ -787,6 +818,7 @@ fn fmt_accept_namespace(res: &Result<SyncFinished, AcceptError>) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sync_engine::LiveEventKind;
 
     #[tokio::test]
     async fn test_sync_remove() {
@@ -794,10 +826,33 @@ mod tests {
         let (a_tx, a_rx) = flume::unbounded();
         let (b_tx, b_rx) = flume::unbounded();
         let mut subscribers = Subscribers::default();
-        subscribers.subscribe(a_tx);
-        subscribers.subscribe(b_tx);
+        subscribers.subscribe(a_tx, Default::default());
+        subscribers.subscribe(b_tx, Default::default());
         drop(a_rx);
         drop(b_rx);
         subscribers.send(Event::NeighborUp(pk)).await;
     }
+
+    #[tokio::test]
+    async fn test_sync_filtered() {
+        let pk = PublicKey::from_bytes(&[1; 32]).unwrap();
+        let (tx, rx) = flume::bounded(1);
+        let mut subscribers = Subscribers::default();
+        let options = SubscribeOptions::default().kinds([LiveEventKind::NeighborDown]);
+        subscribers.subscribe(tx, options);
+        // events which do not match are not sent, so they never wait for the full channel
+        let send = async {
+            for _ in 0..3 {
+                subscribers.send(Event::NeighborUp(pk)).await;
+            }
+            subscribers.send(Event::NeighborDown(pk)).await;
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(1), send)
+            .await
+            .expect("sending to a full channel");
+        assert_eq!(
+            rx.drain().collect::<Vec<_>>(),
+            vec![Event::NeighborDown(pk)]
+        );
+    }
 }
@@ -3,21 +3,28 @@
 use anyhow::anyhow;
 use futures::Stream;
 use iroh_bytes::{store::Store as BaoStore, BlobFormat};
-use iroh_sync::{Author, NamespaceSecret};
+use iroh_net::key::PublicKey;
+use iroh_sync::{Author, DocumentKey, NamespaceSecret};
 use tokio_stream::StreamExt;
 
 use crate::{
     rpc_protocol::{
         AuthorCreateRequest, AuthorCreateResponse, AuthorListRequest, AuthorListResponse,
-        DocCloseRequest, DocCloseResponse, DocCreateRequest, DocCreateResponse, DocDelRequest,
-        DocDelResponse, DocDropRequest, DocDropResponse, DocGetDownloadPolicyRequest,
-        DocGetDownloadPolicyResponse, DocGetExactRequest, DocGetExactResponse, DocGetManyRequest,
-        DocGetManyResponse, DocImportRequest, DocImportResponse, DocLeaveRequest, DocLeaveResponse,
-        DocListRequest, DocListResponse, DocOpenRequest, DocOpenResponse,
-        DocSetDownloadPolicyRequest, DocSetDownloadPolicyResponse, DocSetHashRequest,
-        DocSetHashResponse, DocSetRequest, DocSetResponse, DocShareRequest, DocShareResponse,
-        DocStartSyncRequest, DocStartSyncResponse, DocStatusRequest, DocStatusResponse,
-        DocSubscribeRequest, DocSubscribeResponse, DocTicket, RpcResult, ShareMode,
+        DocClockSkewRequest, DocClockSkewResponse, DocCloseRequest, DocCloseResponse,
+        DocCompactRequest, DocCompactResponse, DocCreateRequest, DocCreateResponse, DocDelRequest,
+        DocDelResponse, DocDelegateRequest, DocDelegateResponse, DocDropRequest, DocDropResponse,
+        DocGetDownloadPolicyRequest, DocGetDownloadPolicyResponse, DocGetExactRequest,
+        DocGetExactResponse, DocGetHistoryPolicyRequest, DocGetHistoryPolicyResponse,
+        DocGetKeyRequest, DocGetKeyResponse, DocGetManyRequest, DocGetManyResponse,
+        DocHistoryRequest, DocHistoryResponse, DocImportRequest, DocImportResponse,
+        DocLeaveRequest, DocLeaveResponse, DocListRequest, DocListResponse, DocOpenRequest,
+        DocOpenResponse, DocRevokeRequest, DocRevokeResponse, DocSetDownloadPolicyRequest,
+        DocSetDownloadPolicyResponse, DocSetHashRequest, DocSetHashResponse,
+        DocSetHistoryPolicyRequest, DocSetHistoryPolicyResponse, DocSetManyRequest,
+        DocSetManyResponse, DocSetRequest, DocSetResponse, DocSetValue, DocShareRequest,
+        DocShareResponse, DocStartSyncRequest, DocStartSyncResponse, DocStatusRequest,
+        DocStatusResponse, DocSubscribeRequest, DocSubscribeResponse, DocTicket, RpcResult,
+        ShareMode,
     },
     sync_engine::SyncEngine,
 };
@@ -59,10 +66,14 @@ impl SyncEngine {
         })
     }
 
-    pub async fn doc_create(&self, _req: DocCreateRequest) -> RpcResult<DocCreateResponse> {
+    pub async fn doc_create(&self, req: DocCreateRequest) -> RpcResult<DocCreateResponse> {
         let namespace = NamespaceSecret::new(&mut rand::rngs::OsRng {});
         let id = namespace.id();
         self.sync.import_namespace(namespace.into()).await?;
+        if req.encrypted {
+            let key = DocumentKey::new(&mut rand::rngs::OsRng {});
+            self.sync.set_document_key(id, key).await?;
+        }
         self.sync.open(id, Default::default()).await?;
         Ok(DocCreateResponse { id })
     }
@@ -108,25 +119,55 @@ impl SyncEngine {
 
     pub async fn doc_share(&self, req: DocShareRequest) -> RpcResult<DocShareResponse> {
         let me = self.endpoint.my_addr().await?;
+        let encryption_key = match req.mode {
+            ShareMode::Pin => None,
+            _ => self.sync.get_document_key(req.doc_id).await?,
+        };
         let capability = match req.mode {
-            ShareMode::Read => iroh_sync::Capability::Read(req.doc_id),
+            ShareMode::Read | ShareMode::Pin => iroh_sync::Capability::Read(req.doc_id),
             ShareMode::Write => {
                 let secret = self.sync.export_secret_key(req.doc_id).await?;
                 iroh_sync::Capability::Write(secret)
             }
         };
+        // all known delegations are shared, so that the peers accept the delegated entries
+        let delegations = self.sync.get_delegations(req.doc_id).await?;
         self.start_sync(req.doc_id, vec![]).await?;
         Ok(DocShareResponse(DocTicket {
             capability,
             nodes: vec![me],
+            delegations,
+            encryption_key,
         }))
     }
 
+    pub async fn doc_delegate(&self, req: DocDelegateRequest) -> RpcResult<DocDelegateResponse> {
+        let DocDelegateRequest {
+            doc_id,
+            author,
+            prefix,
+            expires,
+        } = req;
+        self.sync.delegate(doc_id, author, prefix, expires).await?;
+        let DocShareResponse(ticket) = self
+            .doc_share(DocShareRequest {
+                doc_id,
+                mode: ShareMode::Read,
+            })
+            .await?;
+        Ok(DocDelegateResponse(ticket))
+    }
+
+    pub async fn doc_revoke(&self, req: DocRevokeRequest) -> RpcResult<DocRevokeResponse> {
+        let revoked = self.sync.revoke(req.doc_id, req.author, req.prefix).await?;
+        Ok(DocRevokeResponse { revoked })
+    }
+
     pub fn doc_subscribe(
         &self,
         req: DocSubscribeRequest,
     ) -> impl Stream<Item = RpcResult<DocSubscribeResponse>> {
-        let stream = self.subscribe(req.doc_id);
+        let stream = self.subscribe(req.doc_id, req.options);
         stream.map(|res| {
             res.map(|event| DocSubscribeResponse { event })
                 .map_err(Into::into)
@@ -137,8 +178,14 @@ impl SyncEngine {
         let DocImportRequest(DocTicket {
             capability,
             nodes: peers,
+            delegations,
+            encryption_key,
         }) = req;
         let doc_id = self.sync.import_namespace(capability).await?;
+        self.sync.import_delegations(delegations).await?;
+        if let Some(key) = encryption_key {
+            self.sync.set_document_key(doc_id, key).await?;
+        }
         self.sync.open(doc_id, Default::default()).await?;
         self.start_sync(doc_id, peers).await?;
         Ok(DocImportResponse { doc_id })
@@ -207,6 +254,40 @@ impl SyncEngine {
         Ok(DocSetHashResponse {})
     }
 
+    pub async fn doc_set_many<B: BaoStore>(
+        &self,
+        bao_store: &B,
+        req: DocSetManyRequest,
+    ) -> RpcResult<DocSetManyResponse> {
+        let DocSetManyRequest {
+            doc_id,
+            author_id,
+            entries,
+        } = req;
+        // Keep the temp tags of the imported contents alive until the entries are inserted.
+        let mut tags = Vec::new();
+        let mut records = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let (hash, len) = match value {
+                DocSetValue::Bytes(value) => {
+                    let len = value.len() as u64;
+                    let tag = bao_store.import_bytes(value, BlobFormat::Raw).await?;
+                    let hash = *tag.hash();
+                    tags.push(tag);
+                    (hash, len)
+                }
+                DocSetValue::Hash { hash, size } => (hash, size),
+            };
+            records.push((key, hash, len));
+        }
+        let entries = self
+            .sync
+            .insert_local_many(doc_id, author_id, records)
+            .await?;
+        drop(tags);
+        Ok(DocSetManyResponse { entries })
+    }
+
     pub fn doc_get_many(
         &self,
         req: DocGetManyRequest,
@@ -258,4 +339,49 @@ impl SyncEngine {
         let policy = self.sync.get_download_policy(req.doc_id).await?;
         Ok(DocGetDownloadPolicyResponse { policy })
     }
+
+    pub async fn doc_get_key(&self, req: DocGetKeyRequest) -> RpcResult<DocGetKeyResponse> {
+        let key = self.sync.get_document_key(req.doc_id).await?;
+        Ok(DocGetKeyResponse { key })
+    }
+
+    pub async fn doc_set_history_policy(
+        &self,
+        req: DocSetHistoryPolicyRequest,
+    ) -> RpcResult<DocSetHistoryPolicyResponse> {
+        self.sync.set_history_policy(req.doc_id, req.policy).await?;
+        Ok(DocSetHistoryPolicyResponse {})
+    }
+
+    pub async fn doc_get_history_policy(
+        &self,
+        req: DocGetHistoryPolicyRequest,
+    ) -> RpcResult<DocGetHistoryPolicyResponse> {
+        let policy = self.sync.get_history_policy(req.doc_id).await?;
+        Ok(DocGetHistoryPolicyResponse { policy })
+    }
+
+    pub async fn doc_history(&self, req: DocHistoryRequest) -> RpcResult<DocHistoryResponse> {
+        let entries = self.sync.get_history(req.doc_id, req.key).await?;
+        Ok(DocHistoryResponse { entries })
+    }
+
+    pub async fn doc_compact(&self, req: DocCompactRequest) -> RpcResult<DocCompactResponse> {
+        let (_compaction, removed) = self.sync.compact(req.doc_id, req.horizon).await?;
+        Ok(DocCompactResponse { removed })
+    }
+
+    pub async fn doc_clock_skew(
+        &self,
+        req: DocClockSkewRequest,
+    ) -> RpcResult<DocClockSkewResponse> {
+        let peers = self
+            .sync
+            .get_clock_skew(req.doc_id)
+            .await?
+            .into_iter()
+            .map(|(peer, skew)| anyhow::Ok((PublicKey::from_bytes(&peer)?, skew)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(DocClockSkewResponse { peers })
+    }
 }
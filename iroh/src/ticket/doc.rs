@@ -2,7 +2,7 @@
 
 use iroh_base::ticket;
 use iroh_net::NodeAddr;
-use iroh_sync::Capability;
+use iroh_sync::{Capability, Delegation, DocumentKey};
 use serde::{Deserialize, Serialize};
 
 /// Contains both a key (either secret or public) to a document, and a list of peers to join.
@@ -13,32 +13,89 @@ pub struct DocTicket {
     pub capability: Capability,
     /// A list of nodes to contact.
     pub nodes: Vec<NodeAddr>,
+    /// Delegations of write access to parts of the document.
+    pub delegations: Vec<Delegation>,
+    /// The key to decrypt the document, if it is encrypted.
+    pub encryption_key: Option<DocumentKey>,
 }
 
 /// Wire format for [`DocTicket`].
 ///
-/// In the future we might have multiple variants (not versions, since they
-/// might be both equally valid), so this is a single variant enum to force
-/// postcard to add a discriminator.
+/// Each ticket is encoded with the oldest variant which can hold it, so that it stays readable
+/// by nodes which do not know about the newer fields.
 #[derive(Serialize, Deserialize)]
 enum TicketWireFormat {
-    Variant0(DocTicket),
+    Variant0(Variant0DocTicket),
+    Variant1(Variant1DocTicket),
+    Variant2(DocTicket),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Variant0DocTicket {
+    capability: Capability,
+    nodes: Vec<NodeAddr>,
+}
+
+/// Ticket with delegations, but without an encryption key.
+#[derive(Serialize, Deserialize)]
+struct Variant1DocTicket {
+    capability: Capability,
+    nodes: Vec<NodeAddr>,
+    delegations: Vec<Delegation>,
 }
 
 impl ticket::Ticket for DocTicket {
     const KIND: &'static str = "doc";
 
     fn to_bytes(&self) -> Vec<u8> {
-        let data = TicketWireFormat::Variant0(self.clone());
+        let data = if self.encryption_key.is_some() {
+            TicketWireFormat::Variant2(self.clone())
+        } else if !self.delegations.is_empty() {
+            TicketWireFormat::Variant1(Variant1DocTicket {
+                capability: self.capability.clone(),
+                nodes: self.nodes.clone(),
+                delegations: self.delegations.clone(),
+            })
+        } else {
+            TicketWireFormat::Variant0(Variant0DocTicket {
+                capability: self.capability.clone(),
+                nodes: self.nodes.clone(),
+            })
+        };
         postcard::to_stdvec(&data).expect("postcard serialization failed")
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, ticket::Error> {
         let res: TicketWireFormat = postcard::from_bytes(bytes).map_err(ticket::Error::Postcard)?;
-        let TicketWireFormat::Variant0(res) = res;
+        let res = match res {
+            TicketWireFormat::Variant0(Variant0DocTicket { capability, nodes }) => DocTicket {
+                capability,
+                nodes,
+                delegations: vec![],
+                encryption_key: None,
+            },
+            TicketWireFormat::Variant1(Variant1DocTicket {
+                capability,
+                nodes,
+                delegations,
+            }) => DocTicket {
+                capability,
+                nodes,
+                delegations,
+                encryption_key: None,
+            },
+            TicketWireFormat::Variant2(res) => res,
+        };
         if res.nodes.is_empty() {
             return Err(ticket::Error::Verify("addressing info cannot be empty"));
         }
+        if res
+            .delegations
+            .iter()
+            .any(|d| d.namespace() != res.capability.id())
+        {
+            return Err(ticket::Error::Verify("delegation for another document"));
+        }
         Ok(res)
     }
 }
@@ -49,6 +106,8 @@ impl DocTicket {
         Self {
             capability,
             nodes: peers,
+            delegations: vec![],
+            encryption_key: None,
         }
     }
 }
@@ -66,8 +125,8 @@ mod tests {
 
     use super::*;
     use iroh_base::base32;
-    use iroh_net::key::PublicKey;
-    use iroh_sync::{Capability, NamespaceId};
+    use iroh_net::key::{PublicKey, SecretKey};
+    use iroh_sync::{Author, Capability, NamespaceId, NamespaceSecret};
     use iroh_test::{assert_eq_hex, hexdump::parse_hexdump};
 
     #[test]
@@ -86,6 +145,8 @@ mod tests {
         let ticket = DocTicket {
             capability: Capability::Read(namespace_id),
             nodes: vec![NodeAddr::from_parts(node_id, None, vec![])],
+            delegations: vec![],
+            encryption_key: None,
         };
         let base32 = base32::parse_vec(ticket.to_string().strip_prefix("doc").unwrap()).unwrap();
         let expected = parse_hexdump("
@@ -99,4 +160,47 @@ mod tests {
         ").unwrap();
         assert_eq_hex!(base32, expected);
     }
+    #[test]
+    fn test_ticket_delegations() {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let author = Author::new(&mut rng);
+        let node_id = SecretKey::generate().public();
+        let delegation = Delegation::new(&namespace, author.id(), "home/", None);
+        let ticket = DocTicket {
+            capability: Capability::Read(namespace.id()),
+            nodes: vec![NodeAddr::from_parts(node_id, None, vec![])],
+            delegations: vec![delegation.clone()],
+            encryption_key: None,
+        };
+        assert_eq!(ticket::Ticket::to_bytes(&ticket)[0], 1, "variant 1");
+        let parsed = DocTicket::from_str(&ticket.to_string()).unwrap();
+        assert_eq!(parsed.delegations, vec![delegation]);
+        assert_eq!(parsed.capability.id(), namespace.id());
+
+        // delegations must be for the document of the ticket
+        let other = NamespaceSecret::new(&mut rng);
+        let ticket = DocTicket {
+            capability: Capability::Read(other.id()),
+            ..ticket
+        };
+        assert!(DocTicket::from_str(&ticket.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_ticket_encryption_key() {
+        let mut rng = rand::thread_rng();
+        let namespace = NamespaceSecret::new(&mut rng);
+        let key = DocumentKey::new(&mut rng);
+        let node_id = SecretKey::generate().public();
+        let mut ticket = DocTicket::new(
+            Capability::Read(namespace.id()),
+            vec![NodeAddr::from_parts(node_id, None, vec![])],
+        );
+        ticket.encryption_key = Some(key.clone());
+        assert_eq!(ticket::Ticket::to_bytes(&ticket)[0], 2, "variant 2");
+        let parsed = DocTicket::from_str(&ticket.to_string()).unwrap();
+        assert_eq!(parsed.encryption_key, Some(key));
+        assert!(parsed.delegations.is_empty());
+    }
 }
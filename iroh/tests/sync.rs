@@ -12,6 +12,7 @@ use iroh::{
     client::{mem::Doc, Entry, LiveEvent},
     node::{Builder, Node},
     rpc_protocol::ShareMode,
+    sync_engine::{LiveEventKind, SubscribeOptions},
 };
 use iroh_net::key::{PublicKey, SecretKey};
 use quic_rpc::transport::misc::DummyServerEndpoint;
@@ -23,7 +24,7 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 use iroh_bytes::Hash;
 use iroh_net::derp::DerpMode;
 use iroh_sync::{
-    store::{self, DownloadPolicy, FilterKind, Query},
+    store::{self, DownloadPolicy, FilterKind, HistoryPolicy, Query},
     AuthorId, ContentStatus,
 };
 
@@ -146,6 +147,50 @@ async fn sync_subscribe_no_sync() -> Result<()> {
     Ok(())
 }
 
+/// Test filtered subscriptions with a replay of the existing entries
+#[tokio::test]
+async fn sync_subscribe_filtered() -> Result<()> {
+    let mut rng = test_rng(b"sync_subscribe_filtered");
+    setup_logging();
+    let node = spawn_node(0, &mut rng).await?;
+    let client = node.client();
+    let doc = client.docs.create().await?;
+    let author = client.authors.create().await?;
+    doc.set_bytes(author, b"a/1".to_vec(), b"v".to_vec())
+        .await?;
+    doc.set_bytes(author, b"b/1".to_vec(), b"v".to_vec())
+        .await?;
+    doc.set_bytes(author, b"a/2".to_vec(), b"v".to_vec())
+        .await?;
+
+    let options = SubscribeOptions::default()
+        .key_prefix("a/")
+        .kinds([LiveEventKind::InsertLocal])
+        .replay();
+    let mut sub = doc.subscribe_with(options).await?;
+    let mut replayed = vec![];
+    loop {
+        match next(&mut sub).await {
+            LiveEvent::ReplayEntry { entry } => replayed.push(entry.key().to_vec()),
+            LiveEvent::ReplayFinished => break,
+            event => bail!("expected replayed entry but got {event:?}"),
+        }
+    }
+    assert_eq!(replayed, vec![b"a/1".to_vec(), b"a/2".to_vec()]);
+
+    doc.set_bytes(author, b"b/2".to_vec(), b"v".to_vec())
+        .await?;
+    doc.set_bytes(author, b"a/3".to_vec(), b"v".to_vec())
+        .await?;
+    let event = next(&mut sub).await;
+    assert!(
+        matches!(&event, LiveEvent::InsertLocal { entry } if entry.key() == b"a/3"),
+        "expected InsertLocal for a/3 but got {event:?}"
+    );
+    node.shutdown();
+    Ok(())
+}
+
 #[tokio::test]
 async fn sync_gossip_bulk() -> Result<()> {
     let n_entries: usize = std::env::var("N_ENTRIES")
@@ -887,6 +932,262 @@ async fn doc_delete() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn doc_batch() -> Result<()> {
+    let db = iroh_bytes::store::mem::Store::new();
+    let store = iroh_sync::store::memory::Store::default();
+    let node = Node::builder(db, store).spawn().await?;
+    let client = node.client();
+    let doc = client.docs.create().await?;
+    let author = client.authors.create().await?;
+    let mut sub = doc.subscribe().await?;
+
+    let mut batch = doc.batch(author);
+    batch
+        .set_bytes(b"foo".to_vec(), b"hi".to_vec())
+        .set_bytes(b"bar".to_vec(), b"hello".to_vec())
+        .set_hash(b"baz".to_vec(), Hash::new(b"hi"), 2);
+    assert_eq!(batch.len(), 3);
+    let entries = batch.commit().await?;
+    assert_eq!(entries.len(), 3);
+
+    assert_latest(&doc, b"foo", b"hi").await;
+    assert_latest(&doc, b"bar", b"hello").await;
+    assert_latest(&doc, b"baz", b"hi").await;
+    for _ in 0..3 {
+        let event = tokio::time::timeout(Duration::from_millis(100), sub.next()).await?;
+        assert!(
+            matches!(event, Some(Ok(LiveEvent::InsertLocal { .. }))),
+            "expected InsertLocal but got {event:?}"
+        );
+    }
+    node.shutdown();
+    Ok(())
+}
+
+#[tokio::test]
+async fn doc_history() -> Result<()> {
+    let db = iroh_bytes::store::mem::Store::new();
+    let store = iroh_sync::store::memory::Store::default();
+    let node = Node::builder(db, store).spawn().await?;
+    let client = node.client();
+    let doc = client.docs.create().await?;
+    let author = client.authors.create().await?;
+
+    // no history by default
+    assert_eq!(doc.get_history_policy().await?, HistoryPolicy::Disabled);
+    doc.set_bytes(author, b"k".to_vec(), b"v0".to_vec()).await?;
+    doc.set_bytes(author, b"k".to_vec(), b"v1".to_vec()).await?;
+    assert!(doc.history(b"k".to_vec()).await?.is_empty());
+
+    let policy = HistoryPolicy::Retain {
+        max_versions: Some(2.try_into()?),
+        max_age: None,
+    };
+    doc.set_history_policy(policy).await?;
+    assert_eq!(doc.get_history_policy().await?, policy);
+    for value in ["v2", "v3", "v4"] {
+        doc.set_bytes(author, b"k".to_vec(), value.as_bytes().to_vec())
+            .await?;
+    }
+    let history = doc.history(b"k".to_vec()).await?;
+    let mut values = Vec::new();
+    for entry in history {
+        values.push(entry.content_bytes(&doc).await?);
+    }
+    assert_eq!(values, vec![&b"v3"[..], &b"v2"[..]]);
+    assert_latest(&doc, b"k", b"v4").await;
+
+    node.shutdown();
+    Ok(())
+}
+
+/// Test writing to a document below a key prefix delegated through a ticket.
+#[tokio::test]
+async fn sync_delegated_write() -> Result<()> {
+    setup_logging();
+    let mut rng = test_rng(b"sync_delegated_write");
+    let nodes = spawn_nodes(2, &mut rng).await?;
+    let clients = nodes.iter().map(|node| node.client()).collect::<Vec<_>>();
+    let peer1 = nodes[1].node_id();
+
+    let author0 = clients[0].authors.create().await?;
+    let author1 = clients[1].authors.create().await?;
+    let doc0 = clients[0].docs.create().await?;
+    doc0.set_bytes(author0, b"k0".to_vec(), b"v0".to_vec())
+        .await?;
+    let mut events0 = doc0.subscribe().await?;
+    let ticket = doc0.delegate(author1, "home/1/", None).await?;
+    assert_eq!(ticket.delegations.len(), 1);
+
+    info!("node1: join");
+    let doc1 = clients[1].docs.import(ticket).await?;
+    let res = doc1
+        .set_bytes(author1, b"k1".to_vec(), b"v1".to_vec())
+        .await;
+    assert!(res.is_err(), "write outside of the delegated prefix");
+    let hash1 = doc1
+        .set_bytes(author1, b"home/1/k1".to_vec(), b"v1".to_vec())
+        .await?;
+
+    info!("node0: wait for the delegated entry");
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = events0.next().await {
+            match event? {
+                LiveEvent::InsertRemote { from, entry, .. } if from == peer1 => {
+                    assert_eq!(entry.author(), author1);
+                }
+                LiveEvent::ContentReady { hash } if hash == hash1 => break,
+                _ => {}
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    assert_latest(&doc0, b"home/1/k1", b"v1").await;
+
+    for node in nodes {
+        node.shutdown();
+    }
+    Ok(())
+}
+
+/// Test that peers which joined before a delegation was made learn it when syncing, and that only
+/// writers can revoke it.
+#[tokio::test]
+async fn sync_delegation_after_join() -> Result<()> {
+    setup_logging();
+    let mut rng = test_rng(b"sync_delegation_after_join");
+    let nodes = spawn_nodes(3, &mut rng).await?;
+    let clients = nodes.iter().map(|node| node.client()).collect::<Vec<_>>();
+
+    let author0 = clients[0].authors.create().await?;
+    let author1 = clients[1].authors.create().await?;
+    let doc0 = clients[0].docs.create().await?;
+    doc0.set_bytes(author0, b"k0".to_vec(), b"v0".to_vec())
+        .await?;
+
+    info!("node2: join with read access");
+    let ticket = doc0.share(ShareMode::Read).await?;
+    let doc2 = clients[2].docs.import(ticket).await?;
+    let mut events2 = doc2.subscribe().await?;
+
+    info!("node1: join with a delegation");
+    let ticket = doc0.delegate(author1, "home/1/", None).await?;
+    let doc1 = clients[1].docs.import(ticket).await?;
+    let hash1 = doc1
+        .set_bytes(author1, b"home/1/k1".to_vec(), b"v1".to_vec())
+        .await?;
+
+    info!("node2: wait for the delegated entry");
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = events2.next().await {
+            if matches!(event?, LiveEvent::ContentReady { hash } if hash == hash1) {
+                break;
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    assert_latest(&doc2, b"home/1/k1", b"v1").await;
+
+    info!("node0: revoke the delegation");
+    assert_eq!(doc0.revoke(author1, None).await?, 1);
+    assert_eq!(doc0.revoke(author1, None).await?, 0);
+    let res = doc2.revoke(author1, None).await;
+    assert!(res.is_err(), "revoking requires write access");
+
+    for node in nodes {
+        node.shutdown();
+    }
+    Ok(())
+}
+
+/// Test that pinning nodes sync encrypted documents without being able to read them.
+#[tokio::test]
+async fn sync_encrypted_doc() -> Result<()> {
+    setup_logging();
+    let mut rng = test_rng(b"sync_encrypted_doc");
+    let nodes = spawn_nodes(3, &mut rng).await?;
+    let clients = nodes.iter().map(|node| node.client()).collect::<Vec<_>>();
+
+    let author0 = clients[0].authors.create().await?;
+    let doc0 = clients[0].docs.create_encrypted().await?;
+    let hash0 = doc0
+        .set_bytes(author0, b"k0".to_vec(), b"v0".to_vec())
+        .await?;
+    assert_exact_encrypted(&doc0, author0, b"k0", b"v0").await?;
+    // content set by hash could not be checked to be encrypted
+    let res = doc0.set_hash(author0, b"k1".to_vec(), hash0, 2).await;
+    assert!(res.is_err(), "set_hash in an encrypted document");
+    let mut batch = doc0.batch(author0);
+    batch.set_hash(b"k1".to_vec(), hash0, 2);
+    assert!(
+        batch.commit().await.is_err(),
+        "set_hash in an encrypted batch"
+    );
+
+    let pin_ticket = doc0.share(ShareMode::Pin).await?;
+    assert!(pin_ticket.encryption_key.is_none());
+    let read_ticket = doc0.share(ShareMode::Read).await?;
+    assert_eq!(read_ticket.encryption_key.as_ref(), doc0.document_key());
+
+    let join = |client: &iroh::client::mem::Iroh, mut ticket: iroh::ticket::DocTicket| {
+        let client = client.clone();
+        async move {
+            let peers = std::mem::take(&mut ticket.nodes);
+            let doc = client.docs.import(ticket).await?;
+            let mut events = doc.subscribe().await?;
+            doc.start_sync(peers).await?;
+            tokio::time::timeout(TIMEOUT, async {
+                while let Some(event) = events.next().await {
+                    if matches!(event?, LiveEvent::ContentReady { hash } if hash == hash0) {
+                        break;
+                    }
+                }
+                anyhow::Ok(())
+            })
+            .await??;
+            anyhow::Ok(doc)
+        }
+    };
+
+    info!("node1: pin");
+    let doc1 = join(&clients[1], pin_ticket).await?;
+    assert!(doc1.document_key().is_none());
+    assert!(doc1.get_exact(author0, b"k0", false).await?.is_none());
+    let entries = doc1.get_many(Query::all()).await?.collect::<Vec<_>>().await;
+    assert_eq!(entries.len(), 1);
+    let entry = entries.into_iter().next().unwrap()?;
+    assert_ne!(entry.key(), b"k0");
+    assert_ne!(entry.content_bytes(&doc1).await?.as_ref(), b"v0");
+
+    info!("node2: read");
+    let doc2 = join(&clients[2], read_ticket).await?;
+    assert_exact_encrypted(&doc2, author0, b"k0", b"v0").await?;
+
+    for node in nodes {
+        node.shutdown();
+    }
+    Ok(())
+}
+
+async fn assert_exact_encrypted(
+    doc: &Doc,
+    author: AuthorId,
+    key: &[u8],
+    value: &[u8],
+) -> Result<()> {
+    let entry = doc
+        .get_exact(author, key, false)
+        .await?
+        .context("entry not found")?;
+    assert_eq!(entry.key(), key);
+    assert_eq!(entry.content_bytes(doc).await?.as_ref(), value);
+    assert!(entry.content_reader(doc).await.is_err());
+    Ok(())
+}
+
 #[tokio::test]
 async fn sync_drop_doc() -> Result<()> {
     let mut rng = test_rng(b"sync_drop_doc");
@@ -918,6 +1219,62 @@ async fn sync_drop_doc() -> Result<()> {
     Ok(())
 }
 
+/// Test moving a document between nodes through an archive file.
+#[tokio::test]
+async fn doc_archive() -> Result<()> {
+    let mut rng = test_rng(b"doc_archive");
+    setup_logging();
+    let nodes = spawn_nodes(2, &mut rng).await?;
+    let clients = nodes.iter().map(|node| node.client()).collect::<Vec<_>>();
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("doc.archive");
+
+    let doc = clients[0].docs.create().await?;
+    let author = clients[0].authors.create().await?;
+    doc.set_bytes(author, b"foo".to_vec(), vec![1u8; 100 * 1024])
+        .await?;
+    doc.set_bytes(author, b"bar".to_vec(), vec![2u8; 64])
+        .await?;
+    doc.del(author, b"bar".to_vec()).await?;
+    let outcome = doc.export_archive(&path, true).await?;
+    assert_eq!(outcome.entries, 2);
+    assert_eq!(outcome.blobs, 1);
+
+    let (doc1, outcome) = clients[1].docs.import_archive(&path).await?;
+    assert_eq!(doc1.id(), doc.id());
+    assert_eq!(outcome.entries, 2);
+    assert_eq!(outcome.blobs, 1);
+    assert_latest(&doc1, b"foo", &[1u8; 100 * 1024]).await;
+    assert!(doc1
+        .get_exact(author, b"bar".to_vec(), false)
+        .await?
+        .is_none());
+    // the document was imported without write capability
+    let author1 = clients[1].authors.create().await?;
+    let res = doc1
+        .set_bytes(author1, b"baz".to_vec(), b"baz".to_vec())
+        .await;
+    assert!(res.is_err());
+
+    // importing again does not insert anything
+    let (_, outcome) = clients[1].docs.import_archive(&path).await?;
+    assert_eq!(outcome.entries, 0);
+
+    // tampered content is rejected
+    let mut bytes = std::fs::read(&path)?;
+    let len = bytes.len();
+    bytes[len - 10] ^= 1;
+    let tampered = dir.path().join("tampered.archive");
+    std::fs::write(&tampered, bytes)?;
+    let res = clients[1].docs.import_archive(&tampered).await;
+    assert!(res.is_err());
+
+    for node in nodes {
+        node.shutdown();
+    }
+    Ok(())
+}
+
 async fn assert_latest(doc: &Doc, key: &[u8], value: &[u8]) {
     let content = get_latest(doc, key).await.unwrap();
     assert_eq!(content, value.to_vec());